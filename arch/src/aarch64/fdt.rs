@@ -21,8 +21,7 @@ use super::super::DeviceType;
 use super::super::GuestMemoryMmap;
 use super::super::InitramfsConfig;
 use super::layout::{
-    IRQ_BASE, MEM_32BIT_DEVICES_SIZE, MEM_32BIT_DEVICES_START, MEM_PCI_IO_SIZE, MEM_PCI_IO_START,
-    PCI_HIGH_BASE, PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+    IRQ_BASE, MEM_PCI_IO_SIZE, MEM_PCI_IO_START, PCI_HIGH_BASE, PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
 };
 use std::fs;
 use std::path::Path;
@@ -84,6 +83,14 @@ pub trait DeviceInfoForFdt {
 pub enum Error {
     /// Failure in writing FDT in memory.
     WriteFdtToMemory(GuestMemoryError),
+    /// Failure in parsing a device tree blob.
+    ParseDtb(fdt_parser::FdtError),
+    /// Invalid device tree overlay.
+    InvalidDtOverlay(String),
+    /// Failure in generating the device tree blob.
+    WriteDtb(vm_fdt::Error),
+    /// The device tree blob doesn't fit in the space reserved for it.
+    FdtTooLarge(usize),
 }
 type Result<T> = result::Result<T, Error>;
 
@@ -270,6 +277,11 @@ pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHash
 }
 
 pub fn write_fdt_to_memory(fdt_final: Vec<u8>, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    // The overlays can grow it past the ACPI tables following it
+    if fdt_final.len() as u64 > super::layout::FDT_MAX_SIZE {
+        return Err(Error::FdtTooLarge(fdt_final.len()));
+    }
+
     // Write FDT to memory.
     guest_mem
         .write_slice(fdt_final.as_slice(), super::layout::FDT_START)
@@ -910,12 +922,8 @@ fn create_pci_nodes(
                     pci_device_info_elem.pci_device_space_size,
                 )
             };
-        // There is no specific requirement of the 32bit MMIO range, and
-        // therefore at least we can make these ranges 4K aligned.
-        let pci_device_size_32bit: u64 =
-            MEM_32BIT_DEVICES_SIZE / ((1 << 12) * pci_device_info.len() as u64) * (1 << 12);
-        let pci_device_base_32bit: u64 = MEM_32BIT_DEVICES_START.0
-            + pci_device_size_32bit * pci_device_info_elem.pci_segment_id as u64;
+        let pci_device_base_32bit = pci_device_info_elem.pci_device_space_start_32bit;
+        let pci_device_size_32bit = pci_device_info_elem.pci_device_space_size_32bit;
 
         let ranges = [
             // io addresses. Since AArch64 will not use IO address,
@@ -1066,6 +1074,214 @@ fn create_distance_map_node(fdt: &mut FdtWriter, numa_nodes: &NumaNodes) -> FdtW
     Ok(())
 }
 
+/// Node of a device tree, owning its content so that it can be modified.
+#[derive(Clone, Debug)]
+struct DtNode {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<DtNode>,
+}
+
+impl DtNode {
+    fn parse(node: fdt_parser::node::FdtNode<'_, '_>) -> Self {
+        DtNode {
+            name: node.name.to_string(),
+            properties: node
+                .properties()
+                .map(|p| (p.name.to_string(), p.value.to_vec()))
+                .collect(),
+            children: node.children().map(DtNode::parse).collect(),
+        }
+    }
+
+    fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.properties.push((name.to_string(), value)),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&DtNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    // The unit address can be left out of the path when it is unambiguous.
+    fn find_mut(&mut self, path: &str) -> Option<&mut DtNode> {
+        let mut node = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = node.children.iter_mut().find(|c| {
+                c.name == name || (!name.contains('@') && c.name.split('@').next() == Some(name))
+            })?;
+        }
+        Some(node)
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .filter(|value| value.len() == 4)
+            .map(BigEndian::read_u32)
+    }
+
+    fn find_phandle_mut(&mut self, phandle: u32) -> Option<&mut DtNode> {
+        if self.phandle() == Some(phandle) {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|c| c.find_phandle_mut(phandle))
+    }
+
+    fn max_phandle(&self) -> u32 {
+        self.children
+            .iter()
+            .map(DtNode::max_phandle)
+            .fold(self.phandle().unwrap_or(0), cmp::max)
+    }
+
+    fn shift_phandles(&mut self, delta: u32) -> Result<()> {
+        for (name, value) in self.properties.iter_mut() {
+            if (name == "phandle" || name == "linux,phandle") && value.len() == 4 {
+                let phandle = shift_phandle(BigEndian::read_u32(value), delta, &self.name)?;
+                BigEndian::write_u32(value, phandle);
+            }
+        }
+        for child in self.children.iter_mut() {
+            child.shift_phandles(delta)?;
+        }
+
+        Ok(())
+    }
+
+    // Updates the references to the phandles of the overlay, whose location
+    // is recorded in the __local_fixups__ node mirroring the overlay tree.
+    fn shift_phandle_references(&mut self, fixups: &DtNode, delta: u32) -> Result<()> {
+        let invalid = || Error::InvalidDtOverlay(format!("Invalid local fixup in {}", self.name));
+        for (name, offsets) in fixups.properties.iter() {
+            let Some((_, value)) = self.properties.iter_mut().find(|(n, _)| n == name) else {
+                return Err(invalid());
+            };
+            for offset in offsets.chunks_exact(4) {
+                let offset = BigEndian::read_u32(offset) as usize;
+                let cell = value.get_mut(offset..offset + 4).ok_or_else(invalid)?;
+                let phandle = shift_phandle(BigEndian::read_u32(cell), delta, &self.name)?;
+                BigEndian::write_u32(cell, phandle);
+            }
+        }
+        for child_fixups in fixups.children.iter() {
+            self.children
+                .iter_mut()
+                .find(|c| c.name == child_fixups.name)
+                .ok_or_else(invalid)?
+                .shift_phandle_references(child_fixups, delta)?;
+        }
+
+        Ok(())
+    }
+
+    fn merge(&mut self, overlay: &DtNode) {
+        for (name, value) in overlay.properties.iter() {
+            self.set_property(name, value.clone());
+        }
+        for child in overlay.children.iter() {
+            match self.children.iter_mut().find(|c| c.name == child.name) {
+                Some(existing) => existing.merge(child),
+                None => self.children.push(child.clone()),
+            }
+        }
+    }
+
+    fn write(&self, fdt: &mut FdtWriter, name: &str) -> FdtWriterResult<()> {
+        let node = fdt.begin_node(name)?;
+        for (name, value) in self.properties.iter() {
+            fdt.property(name, value)?;
+        }
+        for child in self.children.iter() {
+            child.write(fdt, &child.name)?;
+        }
+        fdt.end_node(node)
+    }
+}
+
+// Moves a phandle of an overlay past the ones of the base device tree, the
+// phandle 0xffffffff being reserved.
+fn shift_phandle(phandle: u32, delta: u32, node: &str) -> Result<u32> {
+    phandle
+        .checked_add(delta)
+        .filter(|phandle| *phandle != u32::MAX)
+        .ok_or_else(|| Error::InvalidDtOverlay(format!("Phandle overflow in {node}")))
+}
+
+fn parse_dtb(dtb: &[u8]) -> Result<DtNode> {
+    let fdt = fdt_parser::Fdt::new(dtb).map_err(Error::ParseDtb)?;
+    let root = fdt
+        .find_node("/")
+        .ok_or_else(|| Error::InvalidDtOverlay("Missing root node".to_string()))?;
+    Ok(DtNode::parse(root))
+}
+
+/// Merges a compiled device tree overlay (.dtbo) into the device tree blob.
+///
+/// The fragments must target a node of the device tree through either a
+/// `target-path` or the raw phandle of the node as `target`, since the
+/// generated device tree doesn't carry any label to resolve references from
+/// the overlay against.
+pub fn apply_overlay(dtb: &[u8], overlay: &[u8]) -> Result<Vec<u8>> {
+    let mut base = parse_dtb(dtb)?;
+    let mut overlay = parse_dtb(overlay)?;
+
+    if overlay.child("__fixups__").is_some() {
+        return Err(Error::InvalidDtOverlay(
+            "Labels of the base device tree can't be referenced".to_string(),
+        ));
+    }
+
+    // The phandles of the overlay are moved past the ones already in use.
+    let delta = base.max_phandle();
+    overlay.shift_phandles(delta)?;
+    if let Some(fixups) = overlay.child("__local_fixups__").cloned() {
+        overlay.shift_phandle_references(&fixups, delta)?;
+    }
+
+    for fragment in overlay.children.iter() {
+        let Some(content) = fragment.child("__overlay__") else {
+            continue;
+        };
+        let target = if let Some(path) = fragment.property("target-path") {
+            let path = CStr::from_bytes_until_nul(path)
+                .ok()
+                .and_then(|path| path.to_str().ok())
+                .ok_or_else(|| {
+                    Error::InvalidDtOverlay(format!("Invalid target-path in {}", fragment.name))
+                })?;
+            base.find_mut(path)
+        } else if let Some(phandle) = fragment.property("target").filter(|t| t.len() == 4) {
+            base.find_phandle_mut(BigEndian::read_u32(phandle))
+        } else {
+            return Err(Error::InvalidDtOverlay(format!(
+                "Missing target in {}",
+                fragment.name
+            )));
+        };
+        target
+            .ok_or_else(|| {
+                Error::InvalidDtOverlay(format!("Target of {} not found", fragment.name))
+            })?
+            .merge(content);
+    }
+
+    let mut fdt = FdtWriter::new().map_err(Error::WriteDtb)?;
+    base.write(&mut fdt, "").map_err(Error::WriteDtb)?;
+    fdt.finish().map_err(Error::WriteDtb)
+}
+
 // Parse the DTB binary and print for debugging
 pub fn print_fdt(dtb: &[u8]) {
     match fdt_parser::Fdt::new(dtb) {
@@ -1145,3 +1361,115 @@ fn print_node(node: fdt_parser::node::FdtNode<'_, '_>, n_spaces: usize) {
         print_node(child, n_spaces + 2);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_dtb() -> Vec<u8> {
+        let mut fdt = FdtWriter::new().unwrap();
+        let root = fdt.begin_node("").unwrap();
+        let gic = fdt.begin_node("intc@8000000").unwrap();
+        fdt.property_u32("phandle", GIC_PHANDLE).unwrap();
+        fdt.end_node(gic).unwrap();
+        let chosen = fdt.begin_node("chosen").unwrap();
+        fdt.property_string("bootargs", "console=ttyS0").unwrap();
+        fdt.end_node(chosen).unwrap();
+        fdt.end_node(root).unwrap();
+        fdt.finish().unwrap()
+    }
+
+    #[test]
+    fn test_apply_overlay() {
+        let mut fdt = FdtWriter::new().unwrap();
+        let root = fdt.begin_node("").unwrap();
+        let fragment = fdt.begin_node("fragment@0").unwrap();
+        fdt.property_string("target-path", "/chosen").unwrap();
+        let overlay = fdt.begin_node("__overlay__").unwrap();
+        fdt.property_string("bootargs", "console=hvc0").unwrap();
+        let device = fdt.begin_node("device@1000").unwrap();
+        fdt.property_string("compatible", "vendor,device").unwrap();
+        fdt.property_u32("phandle", 1).unwrap();
+        fdt.property_u32("interrupt-parent", 1).unwrap();
+        fdt.end_node(device).unwrap();
+        fdt.end_node(overlay).unwrap();
+        fdt.end_node(fragment).unwrap();
+        let fragment = fdt.begin_node("fragment@1").unwrap();
+        fdt.property_u32("target", GIC_PHANDLE).unwrap();
+        let overlay = fdt.begin_node("__overlay__").unwrap();
+        fdt.property_null("interrupt-controller").unwrap();
+        fdt.end_node(overlay).unwrap();
+        fdt.end_node(fragment).unwrap();
+        // interrupt-parent of the new device refers to its own phandle
+        let fixups = fdt.begin_node("__local_fixups__").unwrap();
+        let fixup_fragment = fdt.begin_node("fragment@0").unwrap();
+        let fixup_overlay = fdt.begin_node("__overlay__").unwrap();
+        let fixup_device = fdt.begin_node("device@1000").unwrap();
+        fdt.property_u32("interrupt-parent", 0).unwrap();
+        fdt.end_node(fixup_device).unwrap();
+        fdt.end_node(fixup_overlay).unwrap();
+        fdt.end_node(fixup_fragment).unwrap();
+        fdt.end_node(fixups).unwrap();
+        fdt.end_node(root).unwrap();
+        let overlay = fdt.finish().unwrap();
+
+        let dtb = apply_overlay(&base_dtb(), &overlay).unwrap();
+        let fdt = fdt_parser::Fdt::new(&dtb).unwrap();
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(
+            chosen.property("bootargs").unwrap().as_str(),
+            Some("console=hvc0")
+        );
+        let device = fdt.find_node("/chosen/device@1000").unwrap();
+        let phandle = GIC_PHANDLE + 1;
+        assert_eq!(
+            device.property("phandle").unwrap().as_usize(),
+            Some(phandle as usize)
+        );
+        assert_eq!(
+            device.property("interrupt-parent").unwrap().as_usize(),
+            Some(phandle as usize)
+        );
+        let gic = fdt.find_node("/intc@8000000").unwrap();
+        assert!(gic.property("interrupt-controller").is_some());
+    }
+
+    #[test]
+    fn test_apply_overlay_invalid_target() {
+        let mut fdt = FdtWriter::new().unwrap();
+        let root = fdt.begin_node("").unwrap();
+        let fragment = fdt.begin_node("fragment@0").unwrap();
+        fdt.property_string("target-path", "/soc").unwrap();
+        let overlay = fdt.begin_node("__overlay__").unwrap();
+        fdt.end_node(overlay).unwrap();
+        fdt.end_node(fragment).unwrap();
+        fdt.end_node(root).unwrap();
+        let overlay = fdt.finish().unwrap();
+
+        assert!(matches!(
+            apply_overlay(&base_dtb(), &overlay),
+            Err(Error::InvalidDtOverlay(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_overlay_phandle_overflow() {
+        let mut fdt = FdtWriter::new().unwrap();
+        let root = fdt.begin_node("").unwrap();
+        let fragment = fdt.begin_node("fragment@0").unwrap();
+        fdt.property_string("target-path", "/chosen").unwrap();
+        let overlay = fdt.begin_node("__overlay__").unwrap();
+        let device = fdt.begin_node("device@1000").unwrap();
+        fdt.property_u32("phandle", u32::MAX - GIC_PHANDLE).unwrap();
+        fdt.end_node(device).unwrap();
+        fdt.end_node(overlay).unwrap();
+        fdt.end_node(fragment).unwrap();
+        fdt.end_node(root).unwrap();
+        let overlay = fdt.finish().unwrap();
+
+        assert!(matches!(
+            apply_overlay(&base_dtb(), &overlay),
+            Err(Error::InvalidDtOverlay(_))
+        ));
+    }
+}
@@ -84,6 +84,9 @@ pub const MEM_PCI_IO_SIZE: u64 = 0x10000;
 /// Starting from 0x1000_0000 (256MiB) to 0x3000_0000 (768MiB) is used for PCIE MMIO
 pub const MEM_32BIT_DEVICES_START: GuestAddress = GuestAddress(0x1000_0000);
 pub const MEM_32BIT_DEVICES_SIZE: u64 = 0x2000_0000;
+/// The legacy devices space below and the PCI MMCONFIG space above leave no
+/// room to grow the PCIE MMIO range.
+pub const MEM_32BIT_DEVICES_MAX_SIZE: u64 = MEM_32BIT_DEVICES_SIZE;
 
 /// PCI MMCONFIG space (start: after the device space at 1 GiB, length: 256MiB)
 pub const PCI_MMCONFIG_START: GuestAddress = GuestAddress(0x3000_0000);
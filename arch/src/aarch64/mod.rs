@@ -45,6 +45,9 @@ pub enum Error {
 
     /// Error initializing PMU for vcpu
     VcpuInitPmu,
+
+    /// Failed to apply a device tree overlay.
+    ApplyDtOverlay(fdt::Error),
 }
 
 impl From<Error> for super::Error {
@@ -133,8 +136,9 @@ pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::Bui
     gic_device: &Arc<Mutex<dyn Vgic>>,
     numa_nodes: &NumaNodes,
     pmu_supported: bool,
+    dt_overlays: &[Vec<u8>],
 ) -> super::Result<()> {
-    let fdt_final = fdt::create_fdt(
+    let mut fdt_final = fdt::create_fdt(
         guest_mem,
         cmdline,
         vcpu_mpidr,
@@ -149,6 +153,10 @@ pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::Bui
     )
     .map_err(|_| Error::SetupFdt)?;
 
+    for dt_overlay in dt_overlays {
+        fdt_final = fdt::apply_overlay(&fdt_final, dt_overlay).map_err(Error::ApplyDtOverlay)?;
+    }
+
     if log_enabled!(Level::Debug) {
         fdt::print_fdt(&fdt_final);
     }
@@ -167,6 +167,8 @@ pub struct PciSpaceInfo {
     pub mmio_config_address: u64,
     pub pci_device_space_start: u64,
     pub pci_device_space_size: u64,
+    pub pci_device_space_start_32bit: u64,
+    pub pci_device_space_size_32bit: u64,
 }
 
 #[cfg(target_arch = "aarch64")]
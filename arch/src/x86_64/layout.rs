@@ -91,6 +91,10 @@ pub const MEM_32BIT_RESERVED_SIZE: u64 = PCI_MMCONFIG_SIZE + MEM_32BIT_DEVICES_S
 // Sub range: 32-bit PCI devices (start: 3GiB, length: 640Mib)
 pub const MEM_32BIT_DEVICES_START: GuestAddress = MEM_32BIT_RESERVED_START;
 pub const MEM_32BIT_DEVICES_SIZE: u64 = 640 << 20;
+// The sub range can be grown downwards, over the "High RAM" range the guest
+// RAM leaves unused.
+pub const MEM_32BIT_DEVICES_MAX_SIZE: u64 =
+    MEM_32BIT_DEVICES_START.0 + MEM_32BIT_DEVICES_SIZE - HIGH_RAM_START.0;
 
 // PCI MMCONFIG space (start: after the device space, length: 256MiB)
 pub const PCI_MMCONFIG_START: GuestAddress =
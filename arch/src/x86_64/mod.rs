@@ -37,6 +37,7 @@ const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
 const INVARIANT_TSC_EDX_BIT: u8 = 8; // Invariant TSC bit on 0x8000_0007 EDX
+const HYPERV_EVMCS_RECOMMENDED_EAX_BIT: u8 = 14; // Recommend enlightened VMCS on 0x4000_0004 EAX
 const AMX_BF16: u8 = 22; // AMX tile computation on bfloat16 numbers
 const AMX_TILE: u8 = 24; // AMX tile load/store instructions
 const AMX_INT8: u8 = 25; // AMX tile computation on 8-bit integers
@@ -125,6 +126,7 @@ pub struct CpuidConfig {
     #[cfg(feature = "tdx")]
     pub tdx: bool,
     pub amx: bool,
+    pub evmcs: bool,
 }
 
 #[derive(Debug)]
@@ -168,6 +170,9 @@ pub enum Error {
     /// Error populating CPUID with KVM HyperV emulation details
     CpuidKvmHyperV(vmm_sys_util::fam::Error),
 
+    /// Error enabling HyperV enlightened VMCS
+    EnableHyperVEvmcs(HypervisorCpuError),
+
     /// Error populating CPUID with CPU identification
     CpuidIdentification(vmm_sys_util::fam::Error),
 
@@ -756,26 +761,46 @@ pub fn generate_common_cpuid(
             ebx: 0xa0000, // "Version"
             ..Default::default()
         });
-        cpuid.push(CpuIdEntry {
-            function: 0x4000_0003,
-            eax: 1 << 1 // AccessPartitionReferenceCounter
+        let mut features = 1 << 1 // AccessPartitionReferenceCounter
                    | 1 << 2 // AccessSynicRegs
                    | 1 << 3 // AccessSyntheticTimerRegs
-                   | 1 << 9, // AccessPartitionReferenceTsc
+                   | 1 << 9; // AccessPartitionReferenceTsc
+        let mut recommendations = 1 << 5; // Recommend relaxed timing
+        if config.evmcs {
+            // The enlightened VMCS relies on the APIC being accessed through
+            // the synthetic MSRs.
+            features |= 1 << 4; // AccessApicMsrs
+            recommendations |= 1 << 3 // Recommend MSR APIC access
+                | 1 << HYPERV_EVMCS_RECOMMENDED_EAX_BIT;
+        }
+        cpuid.push(CpuIdEntry {
+            function: 0x4000_0003,
+            eax: features,
             edx: 1 << 3, // CPU dynamic partitioning
             ..Default::default()
         });
         cpuid.push(CpuIdEntry {
             function: 0x4000_0004,
-            eax: 1 << 5, // Recommend relaxed timing
+            eax: recommendations,
             ..Default::default()
         });
-        for i in 0x4000_0005..=0x4000_000a {
+        for i in 0x4000_0005..=0x4000_0009 {
             cpuid.push(CpuIdEntry {
                 function: i,
                 ..Default::default()
             });
         }
+        // The supported eVMCS versions are only known once enabled on the
+        // vCPU, see configure_vcpu().
+        cpuid.push(CpuIdEntry {
+            function: 0x4000_000a,
+            eax: if config.evmcs {
+                1 << 19 // Enlightened MSR bitmap
+            } else {
+                0
+            },
+            ..Default::default()
+        });
     }
 
     Ok(cpuid)
@@ -839,6 +864,21 @@ pub fn configure_vcpu(
         }
     }
 
+    // The enlightened VMCS must be enabled before setting the CPUID, as KVM
+    // filters the VMX capabilities exposed to the guest accordingly.
+    if kvm_hyperv
+        && cpuid.iter().any(|c| {
+            c.function == 0x4000_0004 && c.eax & (1 << HYPERV_EVMCS_RECOMMENDED_EAX_BIT) != 0
+        })
+    {
+        let evmcs_version = vcpu
+            .enable_hyperv_evmcs()
+            .map_err(Error::EnableHyperVEvmcs)?;
+        for entry in cpuid.iter_mut().filter(|c| c.function == 0x4000_000a) {
+            entry.eax = (entry.eax & !0xffff) | evmcs_version as u32;
+        }
+    }
+
     vcpu.set_cpuid2(&cpuid)
         .map_err(|e| Error::SetSupportedCpusFailed(e.into()))?;
 
@@ -0,0 +1,137 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Overlays of a base image only living as long as the disk they back, their
+//! data being scrubbed before they get removed.
+
+use crate::qcow::{self, QcowFile};
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use vmm_sys_util::write_zeroes::PunchHole;
+
+// Size of the buffer of zeroes the overlay gets overwritten with
+const ZERO_FILL_CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to create the ephemeral overlay: {0}")]
+    CreateOverlay(#[source] qcow::Error),
+    #[error("Failed to open the ephemeral overlay: {0}")]
+    OpenOverlay(#[source] io::Error),
+}
+
+/// Overlay of a base image, erased and removed when dropped.
+#[derive(Debug)]
+pub struct EphemeralDisk {
+    path: PathBuf,
+    // Kept open to erase the data even if the overlay got renamed
+    file: File,
+    zero_fill: bool,
+}
+
+impl EphemeralDisk {
+    /// Creates an overlay of `base` at `path`. When `zero_fill` is set, the
+    /// overlay is overwritten with zeroes before its blocks are deallocated.
+    pub fn create(path: PathBuf, base: &Path, zero_fill: bool) -> Result<Self, Error> {
+        QcowFile::create_overlay(&path, &base.to_string_lossy()).map_err(Error::CreateOverlay)?;
+        let file = match OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                return Err(Error::OpenOverlay(e));
+            }
+        };
+
+        Ok(EphemeralDisk {
+            path,
+            file,
+            zero_fill,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn erase(&mut self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+
+        if self.zero_fill {
+            let zeroes = vec![0u8; ZERO_FILL_CHUNK_SIZE];
+            let mut offset = 0;
+            while offset < len {
+                let count = cmp::min(len - offset, ZERO_FILL_CHUNK_SIZE as u64) as usize;
+                self.file.write_all_at(&zeroes[..count], offset)?;
+                offset += count as u64;
+            }
+            // Make sure the zeroes reach the storage before the blocks are
+            // handed back to the filesystem
+            self.file.sync_data()?;
+        }
+
+        self.file.punch_hole(0, len)?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for EphemeralDisk {
+    fn drop(&mut self) {
+        if let Err(e) = self.erase() {
+            error!(
+                "Failed to erase ephemeral overlay {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!(
+                "Failed to remove ephemeral overlay {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_ephemeral_disk() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x10_0000).unwrap();
+        let dir = TempDir::new().unwrap();
+
+        for zero_fill in [false, true] {
+            let path = dir.as_path().join("overlay.qcow2");
+            let disk = EphemeralDisk::create(path.clone(), base.as_path(), zero_fill).unwrap();
+            assert_eq!(disk.path(), path);
+
+            // Keep the overlay open to check it got erased once removed
+            let mut file = File::open(&path).unwrap();
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .write_all_at(b"secret", 0x1000)
+                .unwrap();
+
+            drop(disk);
+            assert!(!path.exists());
+
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).unwrap();
+            assert!(content.iter().all(|b| *b == 0));
+        }
+    }
+}
@@ -12,6 +12,7 @@
 extern crate log;
 
 pub mod async_io;
+pub mod ephemeral;
 pub mod fixed_vhd;
 #[cfg(feature = "io_uring")]
 /// Enabled with the `"io_uring"` feature
@@ -26,14 +27,18 @@ pub mod qcow_sync;
 pub mod raw_async;
 pub mod raw_async_aio;
 pub mod raw_sync;
+pub mod read_ahead;
 pub mod vhd;
 pub mod vhdx;
 pub mod vhdx_sync;
+pub mod zstd_seekable;
+pub mod zstd_seekable_sync;
 
 use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult};
 use crate::fixed_vhd::FixedVhd;
 use crate::qcow::{QcowFile, RawFile};
 use crate::vhdx::{Vhdx, VhdxError};
+use crate::zstd_seekable::{ZstdSeekable, ZstdSeekableError, DEFAULT_CACHE_SIZE, ZSTD_FRAME_MAGIC};
 #[cfg(feature = "io_uring")]
 use io_uring::{opcode, IoUring, Probe};
 use libc::{ioctl, S_IFBLK, S_IFMT};
@@ -99,6 +104,8 @@ pub enum Error {
     TooManyDescriptors,
     #[error("Failure in vhdx: {0}")]
     VhdxError(VhdxError),
+    #[error("Failure in zstd seekable image: {0}")]
+    ZstdSeekableError(ZstdSeekableError),
 }
 
 fn build_device_id(disk_path: &Path) -> result::Result<String, Error> {
@@ -743,6 +750,7 @@ pub enum ImageType {
     Qcow2,
     Raw,
     Vhdx,
+    ZstdSeekable,
 }
 
 const QCOW_MAGIC: u32 = 0x5146_49fb;
@@ -776,6 +784,8 @@ pub fn detect_image_type(f: &mut File) -> std::io::Result<ImageType> {
         ImageType::FixedVhd
     } else if u64::from_le_bytes(block[0..8].try_into().unwrap()) == VHDX_SIGN {
         ImageType::Vhdx
+    } else if u32::from_le_bytes(block[0..4].try_into().unwrap()) == ZSTD_FRAME_MAGIC {
+        ImageType::ZstdSeekable
     } else {
         ImageType::Raw
     };
@@ -803,6 +813,10 @@ pub fn create_disk_file(mut file: File, direct_io: bool) -> Result<Box<dyn Block
             Box::new(Vhdx::new(file).map_err(Error::VhdxError)?) as Box<dyn BlockBackend>
         }
         ImageType::Raw => Box::new(RawFile::new(file, direct_io)) as Box<dyn BlockBackend>,
+        ImageType::ZstdSeekable => {
+            Box::new(ZstdSeekable::new(file, DEFAULT_CACHE_SIZE).map_err(Error::ZstdSeekableError)?)
+                as Box<dyn BlockBackend>
+        }
     })
 }
 
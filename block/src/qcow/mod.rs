@@ -23,6 +23,7 @@ use std::fmt::{self, Display};
 use std::fs::OpenOptions;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::path::Path;
 use std::str;
 use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
@@ -651,6 +652,26 @@ impl QcowFile {
         Ok(result)
     }
 
+    /// Creates a new QcowFile at `path`, overlaying the image at
+    /// `backing_file_name` that the clusters not written yet are read from.
+    pub fn create_overlay(path: &Path, backing_file_name: &str) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(Error::OpeningFile)?;
+
+        let result = QcowFile::new_from_backing(RawFile::new(file, false), 3, backing_file_name)
+            .and_then(|mut overlay| overlay.flush().map_err(Error::WritingHeader));
+        if result.is_err() {
+            // Don't leave a corrupted image behind
+            let _ = std::fs::remove_file(path);
+        }
+
+        result
+    }
+
     fn new_from_header(mut file: RawFile, header: QcowHeader) -> Result<QcowFile> {
         file.rewind().map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
@@ -1917,6 +1938,39 @@ mod tests {
         testfn(qcow_file); // File closed when the function exits.
     }
 
+    #[test]
+    fn create_overlay() {
+        let backing = TempFile::new().unwrap();
+        backing.as_file().set_len(0x10_0000).unwrap();
+        backing.as_file().write_all(b"test first bytes").unwrap();
+        let backing_path = backing.as_path().to_str().unwrap().to_string();
+
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_path_buf();
+        // The overlay is never overwritten
+        assert!(matches!(
+            QcowFile::create_overlay(&overlay_path, &backing_path),
+            Err(Error::OpeningFile(_))
+        ));
+        drop(overlay);
+
+        QcowFile::create_overlay(&overlay_path, &backing_path).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&overlay_path)
+            .unwrap();
+        let mut overlay = QcowFile::from(RawFile::new(file, false)).unwrap();
+        assert_eq!(overlay.header().backing_file_path, Some(backing_path));
+        assert_eq!(overlay.virtual_size(), 0x10_0000);
+
+        let mut buf = [0u8; 4];
+        overlay.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"test");
+
+        std::fs::remove_file(&overlay_path).unwrap();
+    }
+
     #[test]
     fn write_read_start_backing_v2() {
         let disk_file = basic_file(&valid_header_v2());
@@ -5,6 +5,7 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
 };
+use crate::read_ahead::ReadAhead;
 use crate::DiskTopology;
 use io_uring::{opcode, squeue, types, IoUring};
 use std::fs::File;
@@ -12,13 +13,17 @@ use std::io::{Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use vmm_sys_util::eventfd::EventFd;
 
+// Marks the completions of the prefetch requests, which aren't reported
+const READ_AHEAD_USER_DATA: u64 = u64::MAX;
+
 pub struct RawFileDisk {
     file: File,
+    read_ahead: bool,
 }
 
 impl RawFileDisk {
-    pub fn new(file: File) -> Self {
-        RawFileDisk { file }
+    pub fn new(file: File, read_ahead: bool) -> Self {
+        RawFileDisk { file, read_ahead }
     }
 }
 
@@ -30,10 +35,13 @@ impl DiskFile for RawFileDisk {
     }
 
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(
-            RawFileAsync::new(self.file.as_raw_fd(), ring_depth)
-                .map_err(DiskFileError::NewAsyncIo)?,
-        ) as Box<dyn AsyncIo>)
+        let mut raw_file_async = RawFileAsync::new(self.file.as_raw_fd(), ring_depth)
+            .map_err(DiskFileError::NewAsyncIo)?;
+        if self.read_ahead {
+            raw_file_async.enable_read_ahead();
+        }
+
+        Ok(Box::new(raw_file_async) as Box<dyn AsyncIo>)
     }
 
     fn topology(&mut self) -> DiskTopology {
@@ -50,6 +58,7 @@ pub struct RawFileAsync {
     fd: RawFd,
     io_uring: IoUring,
     eventfd: EventFd,
+    read_ahead: Option<ReadAhead>,
 }
 
 impl RawFileAsync {
@@ -65,8 +74,15 @@ impl RawFileAsync {
             fd,
             io_uring,
             eventfd,
+            read_ahead: None,
         })
     }
+
+    /// Prefetches the data following the sequential read streams into the
+    /// page cache.
+    pub fn enable_read_ahead(&mut self) {
+        self.read_ahead = Some(ReadAhead::new());
+    }
 }
 
 impl AsyncIo for RawFileAsync {
@@ -94,6 +110,26 @@ impl AsyncIo for RawFileAsync {
             )
         };
 
+        if let Some(read_ahead) = self.read_ahead.as_mut() {
+            let len = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+            if let Some((offset, len)) = read_ahead.read(offset as u64, len) {
+                // SAFETY: we know the file descriptor is valid, and the
+                // advice doesn't access any memory.
+                let _ = unsafe {
+                    sq.push(
+                        &opcode::Fadvise::new(
+                            types::Fd(self.fd),
+                            len as libc::off_t,
+                            libc::POSIX_FADV_WILLNEED,
+                        )
+                        .offset(offset)
+                        .build()
+                        .user_data(READ_AHEAD_USER_DATA),
+                    )
+                };
+            }
+        }
+
         // Update the submission queue and submit new operations to the
         // io_uring instance.
         sq.sync();
@@ -159,7 +195,7 @@ impl AsyncIo for RawFileAsync {
     fn next_completed_request(&mut self) -> Option<(u64, i32)> {
         self.io_uring
             .completion()
-            .next()
+            .find(|entry| entry.user_data() != READ_AHEAD_USER_DATA)
             .map(|entry| (entry.user_data(), entry.result()))
     }
 }
@@ -8,6 +8,7 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
 };
+use crate::read_ahead::ReadAhead;
 use crate::DiskTopology;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
@@ -17,11 +18,12 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub struct RawFileDiskAio {
     file: File,
+    read_ahead: bool,
 }
 
 impl RawFileDiskAio {
-    pub fn new(file: File) -> Self {
-        RawFileDiskAio { file }
+    pub fn new(file: File, read_ahead: bool) -> Self {
+        RawFileDiskAio { file, read_ahead }
     }
 }
 
@@ -33,10 +35,13 @@ impl DiskFile for RawFileDiskAio {
     }
 
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(
-            RawFileAsyncAio::new(self.file.as_raw_fd(), ring_depth)
-                .map_err(DiskFileError::NewAsyncIo)?,
-        ) as Box<dyn AsyncIo>)
+        let mut raw_file_async = RawFileAsyncAio::new(self.file.as_raw_fd(), ring_depth)
+            .map_err(DiskFileError::NewAsyncIo)?;
+        if self.read_ahead {
+            raw_file_async.enable_read_ahead();
+        }
+
+        Ok(Box::new(raw_file_async) as Box<dyn AsyncIo>)
     }
 
     fn topology(&mut self) -> DiskTopology {
@@ -53,6 +58,7 @@ pub struct RawFileAsyncAio {
     fd: RawFd,
     ctx: aio::IoContext,
     eventfd: EventFd,
+    read_ahead: Option<ReadAhead>,
 }
 
 impl RawFileAsyncAio {
@@ -60,7 +66,18 @@ impl RawFileAsyncAio {
         let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
         let ctx = aio::IoContext::new(queue_depth)?;
 
-        Ok(RawFileAsyncAio { fd, ctx, eventfd })
+        Ok(RawFileAsyncAio {
+            fd,
+            ctx,
+            eventfd,
+            read_ahead: None,
+        })
+    }
+
+    /// Prefetches the data following the sequential read streams into the
+    /// page cache.
+    pub fn enable_read_ahead(&mut self) {
+        self.read_ahead = Some(ReadAhead::new());
     }
 }
 
@@ -91,6 +108,11 @@ impl AsyncIo for RawFileAsyncAio {
             .submit(&iocbs[..])
             .map_err(AsyncIoError::ReadVectored)?;
 
+        if let Some(read_ahead) = self.read_ahead.as_mut() {
+            let len = iovecs.iter().map(|iovec| iovec.iov_len as u64).sum();
+            read_ahead.advise(self.fd, offset as u64, len);
+        }
+
         Ok(())
     }
 
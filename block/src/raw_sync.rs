@@ -5,6 +5,7 @@
 use crate::async_io::{
     AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
 };
+use crate::read_ahead::ReadAhead;
 use crate::DiskTopology;
 use std::collections::VecDeque;
 use std::fs::File;
@@ -14,11 +15,12 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub struct RawFileDiskSync {
     file: File,
+    read_ahead: bool,
 }
 
 impl RawFileDiskSync {
-    pub fn new(file: File) -> Self {
-        RawFileDiskSync { file }
+    pub fn new(file: File, read_ahead: bool) -> Self {
+        RawFileDiskSync { file, read_ahead }
     }
 }
 
@@ -30,7 +32,12 @@ impl DiskFile for RawFileDiskSync {
     }
 
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
-        Ok(Box::new(RawFileSync::new(self.file.as_raw_fd())) as Box<dyn AsyncIo>)
+        let mut raw_file_sync = RawFileSync::new(self.file.as_raw_fd());
+        if self.read_ahead {
+            raw_file_sync.enable_read_ahead();
+        }
+
+        Ok(Box::new(raw_file_sync) as Box<dyn AsyncIo>)
     }
 
     fn topology(&mut self) -> DiskTopology {
@@ -47,6 +54,7 @@ pub struct RawFileSync {
     fd: RawFd,
     eventfd: EventFd,
     completion_list: VecDeque<(u64, i32)>,
+    read_ahead: Option<ReadAhead>,
 }
 
 impl RawFileSync {
@@ -55,8 +63,15 @@ impl RawFileSync {
             fd,
             eventfd: EventFd::new(libc::EFD_NONBLOCK).expect("Failed creating EventFd for RawFile"),
             completion_list: VecDeque::new(),
+            read_ahead: None,
         }
     }
+
+    /// Prefetches the data following the sequential read streams into the
+    /// page cache.
+    pub fn enable_read_ahead(&mut self) {
+        self.read_ahead = Some(ReadAhead::new());
+    }
 }
 
 impl AsyncIo for RawFileSync {
@@ -83,6 +98,10 @@ impl AsyncIo for RawFileSync {
             return Err(AsyncIoError::ReadVectored(std::io::Error::last_os_error()));
         }
 
+        if let Some(read_ahead) = self.read_ahead.as_mut() {
+            read_ahead.advise(self.fd, offset as u64, result as u64);
+        }
+
         self.completion_list.push_back((user_data, result as i32));
         self.eventfd.write(1).unwrap();
 
@@ -0,0 +1,126 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the sequential read streams, so that the data the guest is
+//! about to read can be prefetched into the host page cache.
+
+use std::cmp;
+use std::os::unix::io::RawFd;
+
+/// Number of contiguous reads after which a stream is deemed sequential
+const SEQUENTIAL_READS_THRESHOLD: u32 = 2;
+/// Size of the first window prefetched ahead of a sequential stream
+const MIN_WINDOW_SIZE: u64 = 128 << 10;
+/// The window doubles each time the stream catches up with it, up to this size
+const MAX_WINDOW_SIZE: u64 = 4 << 20;
+
+#[derive(Debug, Default)]
+pub struct ReadAhead {
+    // Offset following the last read
+    next_offset: u64,
+    // Number of reads contiguous to their previous one
+    sequential_reads: u32,
+    // Size of the window prefetched ahead of the stream
+    window_size: u64,
+    // End of the data prefetched so far
+    prefetched_end: u64,
+}
+
+impl ReadAhead {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a read of `len` bytes at `offset`, returning the offset and
+    /// the length of the range to prefetch, if any.
+    pub fn read(&mut self, offset: u64, len: u64) -> Option<(u64, u64)> {
+        let end = offset.saturating_add(len);
+        let sequential = offset == self.next_offset;
+        self.next_offset = end;
+
+        if !sequential {
+            // Start over with a new stream
+            self.sequential_reads = 1;
+            self.window_size = 0;
+            self.prefetched_end = 0;
+            return None;
+        }
+
+        self.sequential_reads = self.sequential_reads.saturating_add(1);
+        if self.sequential_reads < SEQUENTIAL_READS_THRESHOLD {
+            return None;
+        }
+
+        // Wait for the stream to consume half of the prefetched data before
+        // extending the window further
+        if self.prefetched_end.saturating_sub(end) > self.window_size / 2 {
+            return None;
+        }
+
+        self.window_size = if self.window_size == 0 {
+            MIN_WINDOW_SIZE
+        } else {
+            cmp::min(self.window_size * 2, MAX_WINDOW_SIZE)
+        };
+
+        let start = cmp::max(end, self.prefetched_end);
+        self.prefetched_end = end.saturating_add(self.window_size);
+
+        Some((start, self.prefetched_end - start))
+    }
+
+    /// Records a read of `len` bytes at `offset` of `fd`, advising the
+    /// kernel to prefetch the range following it when needed. Meant for the
+    /// backends which can't queue the advice along with the read.
+    pub fn advise(&mut self, fd: RawFd, offset: u64, len: u64) {
+        if let Some((offset, len)) = self.read(offset, len) {
+            // SAFETY: FFI call with a valid fd, the advice not accessing any
+            // memory. Failing to prefetch only costs performance.
+            let _ = unsafe {
+                libc::posix_fadvise(
+                    fd,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                    libc::POSIX_FADV_WILLNEED,
+                )
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ahead() {
+        let mut read_ahead = ReadAhead::new();
+        let len = MIN_WINDOW_SIZE / 2;
+
+        // The first read isn't enough to detect a stream
+        assert_eq!(read_ahead.read(0, len), None);
+        assert_eq!(read_ahead.read(len, len), Some((2 * len, 2 * len)));
+
+        // Only the part of the doubled window not prefetched yet is returned,
+        // once half of the previous window got read
+        assert_eq!(read_ahead.read(2 * len, len), Some((4 * len, 3 * len)));
+        assert_eq!(read_ahead.read(3 * len, len), None);
+        assert_eq!(read_ahead.read(4 * len, len), Some((7 * len, 6 * len)));
+        assert_eq!(read_ahead.read(5 * len, len), None);
+
+        // The window never grows beyond its maximum size
+        let mut offset = 6 * len;
+        for _ in 0..1024 {
+            if let Some((start, size)) = read_ahead.read(offset, len) {
+                assert!(start + size <= offset + len + MAX_WINDOW_SIZE);
+            }
+            offset += len;
+        }
+        assert_eq!(read_ahead.window_size, MAX_WINDOW_SIZE);
+
+        // Random reads reset the stream
+        assert_eq!(read_ahead.read(0, len), None);
+        assert_eq!(read_ahead.read(len, len), Some((2 * len, 2 * len)));
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only RAW images compressed in the zstd seekable format, made of
+//! independently compressed frames followed by a seek table locating them.
+//! The frames are decompressed on demand, the most recently used ones being
+//! kept in a cache.
+
+use crate::BlockBackend;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Magic number of a zstd frame, starting the images.
+pub const ZSTD_FRAME_MAGIC: u32 = 0xFD2F_B528;
+// Magic number of the skippable frame holding the seek table
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D_2A5E;
+// Magic number ending the seek table footer
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+// Number of frames, descriptor and magic number
+const FOOTER_SIZE: u64 = 9;
+// Magic number and size of the skippable frame
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+// The entries of the seek table include the checksum of the frames
+const DESCRIPTOR_CHECKSUM_FLAG: u8 = 1 << 7;
+// Bits which must be zero in the descriptor
+const DESCRIPTOR_RESERVED_MASK: u8 = 0x7c;
+// Decompressed frames are allocated at once, bound their size
+const MAX_FRAME_SIZE: u64 = 64 << 20;
+/// Amount of decompressed data cached by default.
+pub const DEFAULT_CACHE_SIZE: u64 = 32 << 20;
+
+#[derive(Error, Debug)]
+pub enum ZstdSeekableError {
+    #[error("Failed to read the seek table: {0}")]
+    ReadSeekTable(#[source] io::Error),
+    #[error("Invalid seek table footer")]
+    InvalidFooter,
+    #[error("Invalid seek table")]
+    InvalidSeekTable,
+    #[error("Frame {0} is larger than {MAX_FRAME_SIZE} bytes once decompressed")]
+    FrameTooLarge(usize),
+}
+
+pub type Result<T> = std::result::Result<T, ZstdSeekableError>;
+
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    // Offset of the compressed frame in the image
+    compressed_offset: u64,
+    compressed_size: u32,
+    // Offset of the frame data in the virtual disk
+    offset: u64,
+    size: u32,
+}
+
+// Decompressed frames, the most recently used first
+#[derive(Debug)]
+struct FrameCache {
+    frames: VecDeque<(usize, Arc<Vec<u8>>)>,
+    size: u64,
+    capacity: u64,
+}
+
+impl FrameCache {
+    fn new(capacity: u64) -> Self {
+        FrameCache {
+            frames: VecDeque::new(),
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, index: usize) -> Option<Arc<Vec<u8>>> {
+        let position = self.frames.iter().position(|(i, _)| *i == index)?;
+        let entry = self.frames.remove(position).unwrap();
+        let data = entry.1.clone();
+        self.frames.push_front(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, index: usize, data: Arc<Vec<u8>>) {
+        self.size += data.len() as u64;
+        self.frames.push_front((index, data));
+        // Always keep the frame just inserted
+        while self.size > self.capacity && self.frames.len() > 1 {
+            let (_, evicted) = self.frames.pop_back().unwrap();
+            self.size -= evicted.len() as u64;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ZstdSeekable {
+    file: File,
+    frames: Vec<Frame>,
+    virtual_size: u64,
+    current_offset: u64,
+    cache: FrameCache,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl ZstdSeekable {
+    /// Opens the image in `file`, caching up to `cache_size` bytes of
+    /// decompressed data.
+    pub fn new(file: File, cache_size: u64) -> Result<Self> {
+        let file_size = file
+            .metadata()
+            .map_err(ZstdSeekableError::ReadSeekTable)?
+            .len();
+        if file_size < FOOTER_SIZE + SKIPPABLE_HEADER_SIZE {
+            return Err(ZstdSeekableError::InvalidFooter);
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, file_size - FOOTER_SIZE)
+            .map_err(ZstdSeekableError::ReadSeekTable)?;
+        let num_frames = read_u32(&footer, 0) as u64;
+        let descriptor = footer[4];
+        if read_u32(&footer, 5) != SEEKABLE_MAGIC || descriptor & DESCRIPTOR_RESERVED_MASK != 0 {
+            return Err(ZstdSeekableError::InvalidFooter);
+        }
+
+        let entry_size = if descriptor & DESCRIPTOR_CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+        let table_size = num_frames * entry_size;
+        let table_offset = file_size
+            .checked_sub(FOOTER_SIZE + table_size + SKIPPABLE_HEADER_SIZE)
+            .ok_or(ZstdSeekableError::InvalidSeekTable)?;
+
+        let mut table = vec![0u8; (SKIPPABLE_HEADER_SIZE + table_size) as usize];
+        file.read_exact_at(&mut table, table_offset)
+            .map_err(ZstdSeekableError::ReadSeekTable)?;
+        if read_u32(&table, 0) != SKIPPABLE_FRAME_MAGIC
+            || read_u32(&table, 4) as u64 != table_size + FOOTER_SIZE
+        {
+            return Err(ZstdSeekableError::InvalidSeekTable);
+        }
+
+        let mut frames = Vec::with_capacity(num_frames as usize);
+        let mut compressed_offset = 0;
+        let mut offset = 0;
+        for (index, entry) in table[SKIPPABLE_HEADER_SIZE as usize..]
+            .chunks_exact(entry_size as usize)
+            .enumerate()
+        {
+            let compressed_size = read_u32(entry, 0);
+            let size = read_u32(entry, 4);
+            if size as u64 > MAX_FRAME_SIZE {
+                return Err(ZstdSeekableError::FrameTooLarge(index));
+            }
+
+            frames.push(Frame {
+                compressed_offset,
+                compressed_size,
+                offset,
+                size,
+            });
+            compressed_offset += compressed_size as u64;
+            offset += size as u64;
+        }
+
+        // The frames must precede the seek table
+        if compressed_offset != table_offset {
+            return Err(ZstdSeekableError::InvalidSeekTable);
+        }
+
+        Ok(ZstdSeekable {
+            file,
+            frames,
+            virtual_size: offset,
+            current_offset: 0,
+            cache: FrameCache::new(cache_size),
+        })
+    }
+
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    fn frame_data(&mut self, index: usize) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.cache.get(index) {
+            return Ok(data);
+        }
+
+        let frame = self.frames[index];
+        let mut compressed = vec![0u8; frame.compressed_size as usize];
+        self.file
+            .read_exact_at(&mut compressed, frame.compressed_offset)?;
+        let data = zstd::bulk::decompress(&compressed, frame.size as usize)?;
+        if data.len() != frame.size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Frame {index} decompressed to {} bytes instead of {}",
+                    data.len(),
+                    frame.size
+                ),
+            ));
+        }
+
+        let data = Arc::new(data);
+        self.cache.insert(index, data.clone());
+        Ok(data)
+    }
+
+    // Reads from the current offset, up to the end of the frame it falls in
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.current_offset >= self.virtual_size {
+            return Ok(0);
+        }
+
+        // Frames can be empty, pick the last one starting before the offset
+        let index = self
+            .frames
+            .partition_point(|f| f.offset <= self.current_offset)
+            - 1;
+        let frame = self.frames[index];
+        let data = self.frame_data(index)?;
+
+        let start = (self.current_offset - frame.offset) as usize;
+        let count = std::cmp::min(buf.len(), data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        self.current_offset += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl Read for ZstdSeekable {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            let count = self.read_frame(&mut buf[total..])?;
+            if count == 0 {
+                break;
+            }
+            total += count;
+        }
+
+        Ok(total)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let count = self.read(buf)?;
+            total += count;
+            if count < buf.len() {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+}
+
+impl Write for ZstdSeekable {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Compressed images are read-only",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ZstdSeekable {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.virtual_size.checked_add_signed(off),
+            SeekFrom::Current(off) => self.current_offset.checked_add_signed(off),
+        };
+
+        match new_offset {
+            Some(offset) => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek offset",
+            )),
+        }
+    }
+}
+
+impl BlockBackend for ZstdSeekable {
+    fn size(&self) -> std::result::Result<u64, crate::Error> {
+        Ok(self.virtual_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Compresses `data` in frames of `frame_size` bytes, along with the seek
+    // table of the frames.
+    fn seekable_image(data: &[u8], frame_size: usize, checksum: bool) -> TempFile {
+        let mut image = Vec::new();
+        let mut entries = Vec::new();
+        for chunk in data.chunks(frame_size) {
+            let frame = zstd::bulk::compress(chunk, 3).unwrap();
+            entries.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            entries.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            if checksum {
+                entries.extend_from_slice(&0u32.to_le_bytes());
+            }
+            image.extend_from_slice(&frame);
+        }
+
+        let num_frames = data.chunks(frame_size).count() as u32;
+        image.extend_from_slice(&SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+        image.extend_from_slice(&(entries.len() as u32 + FOOTER_SIZE as u32).to_le_bytes());
+        image.extend_from_slice(&entries);
+        image.extend_from_slice(&num_frames.to_le_bytes());
+        image.push(u8::from(checksum) * DESCRIPTOR_CHECKSUM_FLAG);
+        image.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&image).unwrap();
+        file
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..0x5_0000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let data = test_data();
+
+        for checksum in [false, true] {
+            let image = seekable_image(&data, 0x1_0000, checksum);
+            let mut disk =
+                ZstdSeekable::new(image.as_file().try_clone().unwrap(), 0x2_0000).unwrap();
+            assert_eq!(disk.virtual_size(), data.len() as u64);
+
+            // Reads spanning several frames, going through the cache
+            for offset in [0x0, 0xff00, 0x2_fff0, 0x8000, 0x4_ff00] {
+                let mut buf = vec![0u8; 0x1_0100];
+                disk.seek(SeekFrom::Start(offset)).unwrap();
+                let count = disk.read(&mut buf).unwrap();
+                let end = std::cmp::min(offset as usize + buf.len(), data.len());
+                assert_eq!(count, end - offset as usize);
+                assert_eq!(&buf[..count], &data[offset as usize..end]);
+            }
+            assert!(disk.cache.size <= 0x2_0000);
+
+            let mut first = [0u8; 0x10];
+            let mut second = [0u8; 0x10];
+            disk.seek(SeekFrom::Start(0xfff8)).unwrap();
+            let count = disk
+                .read_vectored(&mut [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)])
+                .unwrap();
+            assert_eq!(count, 0x20);
+            assert_eq!(&first, &data[0xfff8..0x1_0008]);
+            assert_eq!(&second, &data[0x1_0008..0x1_0018]);
+
+            disk.seek(SeekFrom::End(0)).unwrap();
+            assert_eq!(disk.read(&mut first).unwrap(), 0);
+            assert!(disk.write(&first).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_image() {
+        let image = TempFile::new().unwrap();
+        image.as_file().write_all(&[0u8; 0x1000]).unwrap();
+        assert!(matches!(
+            ZstdSeekable::new(image.as_file().try_clone().unwrap(), DEFAULT_CACHE_SIZE),
+            Err(ZstdSeekableError::InvalidFooter)
+        ));
+
+        // Data preceding the frames listed in the seek table
+        let image = seekable_image(&test_data(), 0x1_0000, false);
+        let mut content = Vec::new();
+        image.as_file().read_to_end(&mut content).unwrap();
+        content.insert(0, 0);
+        let image = TempFile::new().unwrap();
+        image.as_file().write_all(&content).unwrap();
+        assert!(matches!(
+            ZstdSeekable::new(image.as_file().try_clone().unwrap(), DEFAULT_CACHE_SIZE),
+            Err(ZstdSeekableError::InvalidSeekTable)
+        ));
+    }
+}
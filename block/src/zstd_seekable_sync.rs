@@ -0,0 +1,103 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{AsyncIo, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult};
+use crate::zstd_seekable::{Result as ZstdSeekableResult, ZstdSeekable, DEFAULT_CACHE_SIZE};
+use crate::AsyncAdaptor;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vmm_sys_util::eventfd::EventFd;
+
+pub struct ZstdSeekableDiskSync {
+    image: Arc<Mutex<ZstdSeekable>>,
+}
+
+impl ZstdSeekableDiskSync {
+    pub fn new(f: File) -> ZstdSeekableResult<Self> {
+        Ok(ZstdSeekableDiskSync {
+            image: Arc::new(Mutex::new(ZstdSeekable::new(f, DEFAULT_CACHE_SIZE)?)),
+        })
+    }
+}
+
+impl DiskFile for ZstdSeekableDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.image.lock().unwrap().virtual_size())
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(ZstdSeekableSync::new(self.image.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+pub struct ZstdSeekableSync {
+    image: Arc<Mutex<ZstdSeekable>>,
+    eventfd: EventFd,
+    completion_list: VecDeque<(u64, i32)>,
+}
+
+impl ZstdSeekableSync {
+    pub fn new(image: Arc<Mutex<ZstdSeekable>>) -> std::io::Result<Self> {
+        Ok(ZstdSeekableSync {
+            image,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: VecDeque::new(),
+        })
+    }
+}
+
+impl AsyncAdaptor<ZstdSeekable> for Arc<Mutex<ZstdSeekable>> {
+    fn file(&mut self) -> MutexGuard<ZstdSeekable> {
+        self.lock().unwrap()
+    }
+}
+
+impl AsyncIo for ZstdSeekableSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.image.read_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.image.write_vectored_sync(
+            offset,
+            iovecs,
+            user_data,
+            &self.eventfd,
+            &mut self.completion_list,
+        )
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.image
+            .fsync_sync(user_data, &self.eventfd, &mut self.completion_list)
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completion_list.pop_front()
+    }
+}
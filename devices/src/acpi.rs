@@ -21,19 +21,24 @@ pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
     vcpus_kill_signalled: Arc<AtomicBool>,
+    poweroff_signalled: Arc<AtomicBool>,
 }
 
 impl AcpiShutdownDevice {
     /// Constructs a device that will signal the given event when the guest requests it.
+    /// `poweroff_signalled` is set before signalling a guest shutdown, allowing the
+    /// VMM to tell it apart from other sources of the exit event.
     pub fn new(
         exit_evt: EventFd,
         reset_evt: EventFd,
         vcpus_kill_signalled: Arc<AtomicBool>,
+        poweroff_signalled: Arc<AtomicBool>,
     ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
             vcpus_kill_signalled,
+            poweroff_signalled,
         }
     }
 }
@@ -65,6 +70,7 @@ impl BusDevice for AcpiShutdownDevice {
         const SLEEP_VALUE_BIT: u8 = 2;
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             info!("ACPI Shutdown signalled");
+            self.poweroff_signalled.store(true, Ordering::SeqCst);
             if let Err(e) = self.exit_evt.write(1) {
                 error!("Error triggering ACPI shutdown event: {}", e);
             }
@@ -183,6 +189,22 @@ impl Aml for AcpiGedDevice {
                                 &0x80usize,
                             )],
                         ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &16usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &16usize),
+                            vec![&aml::Notify::new(
+                                &aml::Path::new("\\_SB_.SLPB"),
+                                &0x80usize,
+                            )],
+                        ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &32usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &32usize),
+                            vec![&aml::Notify::new(
+                                &aml::Path::new("\\_SB_.VGEN"),
+                                &0x80usize,
+                            )],
+                        ),
                     ],
                 ),
             ],
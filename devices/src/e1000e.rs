@@ -0,0 +1,1328 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated Intel 82574L (e1000e) network controller.
+//!
+//! This is only meant as a fallback for guests without virtio drivers, such as
+//! installer or rescue images, hence the model is kept minimal: a single queue
+//! pair, legacy INTx interrupts, no MSI/MSI-X, no packet split and no RSS.
+//! Frames are exchanged with the host through a TAP interface, checksum and
+//! segmentation offloads being forwarded through the virtio-net header.
+
+use anyhow::anyhow;
+use net_util::{MacAddr, Tap};
+use pci::{
+    BarReprogrammingParams, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType, PciInterruptPin,
+    PciNetworkControllerSubclass, PCI_CONFIGURATION_ID,
+};
+use seccompiler::{apply_filter, BpfProgram};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use thiserror::Error;
+use virtio_bindings::virtio_net::{
+    virtio_net_hdr_v1, VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_TCPV4,
+    VIRTIO_NET_HDR_GSO_TCPV6,
+};
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{BusDevice, Resource};
+use vm_memory::{
+    bitmap::AtomicBitmap, Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+const E1000E_VENDOR_ID: u16 = 0x8086;
+const E1000E_DEVICE_ID: u16 = 0x10d3;
+
+pub const E1000E_DEVICE_MMIO_SIZE: u64 = 0x2_0000;
+
+// Register offsets.
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EECD: usize = 0x0010;
+const EERD: usize = 0x0014;
+const MDIC: usize = 0x0020;
+const ICR: usize = 0x00c0;
+const ICS: usize = 0x00c8;
+const IMS: usize = 0x00d0;
+const IMC: usize = 0x00d8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const PBA: usize = 0x1000;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const STATS_START: usize = 0x4000;
+const STATS_END: usize = 0x40fc;
+const RFCTL: usize = 0x5008;
+const MTA: usize = 0x5200;
+const RAL0: usize = 0x5400;
+const RAH0: usize = 0x5404;
+const RA_ENTRIES: usize = 16;
+
+const CTRL_VME: u32 = 1 << 30;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_FD: u32 = 1 << 0;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_1000: u32 = 1 << 7;
+
+const EECD_REQ: u32 = 1 << 6;
+const EECD_GNT: u32 = 1 << 7;
+const EECD_PRES: u32 = 1 << 8;
+const EECD_AUTO_RD: u32 = 1 << 9;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 1;
+const EERD_ADDR_SHIFT: u32 = 2;
+const EERD_DATA_SHIFT: u32 = 16;
+
+const MDIC_REG_SHIFT: u32 = 16;
+const MDIC_PHY_SHIFT: u32 = 21;
+const MDIC_OP_WRITE: u32 = 1 << 26;
+const MDIC_OP_READ: u32 = 2 << 26;
+const MDIC_READY: u32 = 1 << 28;
+const MDIC_ERROR: u32 = 1 << 30;
+
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_TXQE: u32 = 1 << 1;
+const ICR_RXT0: u32 = 1 << 7;
+const ICR_INT_ASSERTED: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_MO_SHIFT: u32 = 12;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_BSIZE_SHIFT: u32 = 16;
+const RCTL_BSEX: u32 = 1 << 25;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const RFCTL_EXTEN: u32 = 1 << 15;
+
+const TCTL_EN: u32 = 1 << 1;
+
+const RAH_AV: u32 = 1 << 31;
+
+// Descriptor fields, common to the transmit and receive rings.
+const DESC_SIZE: u64 = 16;
+const DESC_STATUS_DD: u8 = 1 << 0;
+
+const TXD_CMD_EOP: u8 = 1 << 0;
+// Insert checksum (legacy descriptors only).
+const TXD_CMD_IC: u8 = 1 << 2;
+// TCP segmentation (extended descriptors only).
+const TXD_CMD_TSE: u8 = 1 << 2;
+const TXD_CMD_RS: u8 = 1 << 3;
+const TXD_CMD_DEXT: u8 = 1 << 5;
+const TXD_CMD_VLE: u8 = 1 << 6;
+const TXD_DTYP_CONTEXT: u32 = 0;
+const TXD_DTYP_DATA: u32 = 1;
+const TXD_POPTS_IXSM: u8 = 1 << 0;
+const TXD_POPTS_TXSM: u8 = 1 << 1;
+const TXD_TUCMD_IP: u8 = 1 << 1;
+
+const RXD_STAT_DD: u32 = 1 << 0;
+const RXD_STAT_EOP: u32 = 1 << 1;
+const RXD_STAT_VP: u32 = 1 << 3;
+
+// PHY registers, the PHY being an 82574 internal BME1000.
+const PHY_ADDR: u32 = 1;
+const PHY_REGS: usize = 32;
+const PHY_CTRL: usize = 0;
+const PHY_STATUS: usize = 1;
+const PHY_SPEC_STATUS: usize = 17;
+const PHY_CTRL_RESET: u16 = 1 << 15;
+const PHY_CTRL_RESTART_AN: u16 = 1 << 9;
+const PHY_DEFAULTS: [(usize, u16); 11] = [
+    (PHY_CTRL, 0x1140),
+    (PHY_STATUS, 0x796d),
+    (2, 0x0141),
+    (3, 0x0cb1),
+    (4, 0x0de1),
+    (5, 0x45e1),
+    (6, 0x000f),
+    (9, 0x0e00),
+    (10, 0x3c00),
+    (16, 0x3360),
+    (PHY_SPEC_STATUS, 0xac08),
+];
+
+// NVM layout.
+const NVM_WORDS: usize = 64;
+const NVM_INIT_CONTROL1: usize = 0x0a;
+const NVM_SUBSYSTEM_ID: usize = 0x0b;
+const NVM_SUBSYSTEM_VENDOR_ID: usize = 0x0c;
+const NVM_DEVICE_ID: usize = 0x0d;
+const NVM_VENDOR_ID: usize = 0x0e;
+const NVM_ALT_MAC_ADDR_PTR: usize = 0x37;
+const NVM_CHECKSUM: usize = 0x3f;
+const NVM_SUM: u16 = 0xbaba;
+
+// Largest frame the guest can hand over, including the TSO headers.
+const MAX_TX_FRAME_SIZE: usize = 0x1_0000 + 0x100;
+// Largest frame read from the TAP interface, which doesn't get any
+// segmentation offload enabled.
+const MAX_RX_FRAME_SIZE: usize = 0x1_0000;
+const MIN_FRAME_SIZE: usize = 60;
+const CRC_SIZE: usize = 4;
+const VLAN_TPID: u16 = 0x8100;
+const VNET_HDR_LEN: usize = std::mem::size_of::<virtio_net_hdr_v1>();
+
+// Frames read from the TAP interface in a row, before the lock protecting
+// the device registers is released.
+const RX_BURST: usize = 64;
+
+// Events of the RX thread.
+const KILL_EVENT: u64 = 1;
+const KICK_EVENT: u64 = 2;
+const TAP_EVENT: u64 = 3;
+
+#[derive(Debug, Error)]
+pub enum E1000eError {
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Failed to retrieve E1000eDeviceState: {0}")]
+    RetrieveState(#[source] anyhow::Error),
+    #[error("Failed to create an eventfd: {0}")]
+    CreateEventFd(#[source] io::Error),
+    #[error("Failed to create the epoll context: {0}")]
+    CreateEpoll(#[source] io::Error),
+    #[error("Failed to spawn the RX thread: {0}")]
+    SpawnThread(#[source] io::Error),
+}
+
+// Offloads requested through the last context descriptor.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct TxContext {
+    ipcss: u8,
+    ipcso: u8,
+    tucss: u8,
+    tucso: u8,
+    tucmd: u8,
+    hdr_len: u8,
+    mss: u16,
+}
+
+// Offloads applying to the frame being gathered, as given by its first
+// descriptor.
+#[derive(Clone, Copy, Default)]
+struct TxFrameOptions {
+    extended: bool,
+    tse: bool,
+    popts: u8,
+    // Checksum start and offset from legacy descriptors.
+    legacy_csum: Option<(u8, u8)>,
+    vlan: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct E1000eDeviceState {
+    regs: Vec<u32>,
+    phy: Vec<u16>,
+    context: TxContext,
+}
+
+// The device registers, shared between the vCPU threads and the RX thread.
+struct E1000eCore {
+    regs: Vec<u32>,
+    phy: [u16; PHY_REGS],
+    nvm: [u16; NVM_WORDS],
+    mac: MacAddr,
+    context: TxContext,
+    tx_frame: Vec<u8>,
+    tx_options: TxFrameOptions,
+    // Frame read from the TAP interface, waiting for receive descriptors.
+    rx_frame: Option<Vec<u8>>,
+    rx_buf: Vec<u8>,
+    tap: Tap,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt: Arc<dyn InterruptSourceGroup>,
+    paused: bool,
+}
+
+impl E1000eCore {
+    fn reg(&self, offset: usize) -> u32 {
+        self.regs[offset >> 2]
+    }
+
+    fn set_reg(&mut self, offset: usize, value: u32) {
+        self.regs[offset >> 2] = value;
+    }
+
+    fn reset(&mut self) {
+        self.regs.fill(0);
+        self.set_reg(PBA, 0x0014);
+        let mac = self.mac.get_bytes();
+        self.set_reg(RAL0, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        self.set_reg(
+            RAH0,
+            u32::from(u16::from_le_bytes([mac[4], mac[5]])) | RAH_AV,
+        );
+
+        self.phy = [0; PHY_REGS];
+        for (reg, value) in PHY_DEFAULTS {
+            self.phy[reg] = value;
+        }
+
+        self.context = TxContext::default();
+        self.tx_frame.clear();
+        self.rx_frame = None;
+    }
+
+    fn update_interrupt(&self) {
+        if self.reg(ICR) & self.reg(IMS) != 0 {
+            if let Err(e) = self.interrupt.trigger(0) {
+                error!("Failed to trigger e1000e interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn set_cause(&mut self, cause: u32) {
+        self.set_reg(ICR, self.reg(ICR) | cause);
+        self.update_interrupt();
+    }
+
+    fn read_reg(&mut self, offset: usize) -> u32 {
+        match offset {
+            STATUS => STATUS_FD | STATUS_LU | STATUS_SPEED_1000,
+            EECD => {
+                let eecd = self.reg(EECD);
+                let gnt = if eecd & EECD_REQ != 0 { EECD_GNT } else { 0 };
+                eecd | gnt | EECD_PRES | EECD_AUTO_RD
+            }
+            ICR => {
+                let mut icr = self.reg(ICR);
+                if icr & self.reg(IMS) != 0 {
+                    icr |= ICR_INT_ASSERTED;
+                }
+                self.set_reg(ICR, 0);
+                icr
+            }
+            // Statistics are cleared on read, and not accounted for.
+            STATS_START..=STATS_END => {
+                self.set_reg(offset, 0);
+                0
+            }
+            _ => self.reg(offset),
+        }
+    }
+
+    // Returns whether the RX thread must be kicked.
+    fn write_reg(&mut self, offset: usize, value: u32) -> bool {
+        match offset {
+            CTRL => {
+                if value & CTRL_RST != 0 {
+                    self.reset();
+                } else {
+                    self.set_reg(CTRL, value & !CTRL_PHY_RST);
+                }
+            }
+            STATUS => {}
+            EERD => {
+                let value = if value & EERD_START != 0 {
+                    let addr = (value >> EERD_ADDR_SHIFT) & 0x3fff;
+                    let data = self.nvm.get(addr as usize).copied().unwrap_or(0xffff);
+                    (u32::from(data) << EERD_DATA_SHIFT) | (addr << EERD_ADDR_SHIFT) | EERD_DONE
+                } else {
+                    value
+                };
+                self.set_reg(EERD, value);
+            }
+            MDIC => self.write_mdic(value),
+            ICR => self.set_reg(ICR, self.reg(ICR) & !value),
+            ICS => self.set_cause(value),
+            IMS => {
+                self.set_reg(IMS, self.reg(IMS) | value);
+                self.update_interrupt();
+            }
+            IMC => self.set_reg(IMS, self.reg(IMS) & !value),
+            RCTL | RDT => {
+                self.set_reg(offset, value);
+                return true;
+            }
+            RDLEN | TDLEN => self.set_reg(offset, value & 0xf_ff80),
+            RDH | TDH | TDT => {
+                self.set_reg(offset, value & 0xffff);
+                if offset == TDT {
+                    self.process_tx();
+                }
+            }
+            TCTL => {
+                self.set_reg(TCTL, value);
+                self.process_tx();
+            }
+            _ => self.set_reg(offset, value),
+        }
+
+        false
+    }
+
+    fn write_mdic(&mut self, value: u32) {
+        let reg = ((value >> MDIC_REG_SHIFT) & 0x1f) as usize;
+        let phy = (value >> MDIC_PHY_SHIFT) & 0x1f;
+        let data = value as u16;
+
+        let mut mdic = value & !(MDIC_READY | MDIC_ERROR);
+        if phy != PHY_ADDR {
+            mdic |= MDIC_ERROR;
+        } else if value & MDIC_OP_READ != 0 {
+            mdic = (mdic & !0xffff) | u32::from(self.phy[reg]);
+        } else if value & MDIC_OP_WRITE != 0 {
+            match reg {
+                // Resetting the PHY and restarting auto-negotiation complete
+                // immediately, the link being always up.
+                PHY_CTRL => self.phy[reg] = data & !(PHY_CTRL_RESET | PHY_CTRL_RESTART_AN),
+                PHY_STATUS | PHY_SPEC_STATUS => {}
+                _ => self.phy[reg] = data,
+            }
+        }
+        self.set_reg(MDIC, mdic | MDIC_READY);
+    }
+
+    fn read_desc(&self, addr: u64) -> Option<[u8; DESC_SIZE as usize]> {
+        let mut desc = [0u8; DESC_SIZE as usize];
+        self.mem
+            .memory()
+            .read_slice(&mut desc, GuestAddress(addr))
+            .map_err(|e| warn!("Failed to read e1000e descriptor at 0x{:x}: {:?}", addr, e))
+            .ok()?;
+        Some(desc)
+    }
+
+    fn ring_base(&self, bal: usize, bah: usize) -> u64 {
+        (u64::from(self.reg(bah)) << 32) | u64::from(self.reg(bal) & !0xf)
+    }
+
+    fn process_tx(&mut self) {
+        if self.reg(TCTL) & TCTL_EN == 0 {
+            return;
+        }
+
+        let base = self.ring_base(TDBAL, TDBAH);
+        let count = self.reg(TDLEN) / DESC_SIZE as u32;
+        let tail = self.reg(TDT);
+        let mut head = self.reg(TDH);
+        if head >= count || tail >= count {
+            return;
+        }
+
+        let mut cause = 0;
+        while head != tail {
+            let addr = base + u64::from(head) * DESC_SIZE;
+            let Some(desc) = self.read_desc(addr) else {
+                break;
+            };
+            self.process_tx_desc(&desc);
+
+            if desc[11] & TXD_CMD_RS != 0 {
+                if let Err(e) = self
+                    .mem
+                    .memory()
+                    .write_obj(desc[12] | DESC_STATUS_DD, GuestAddress(addr + 12))
+                {
+                    warn!("Failed to write e1000e TX descriptor status: {:?}", e);
+                }
+            }
+
+            head = (head + 1) % count;
+            self.set_reg(TDH, head);
+            cause |= ICR_TXDW;
+        }
+
+        if cause != 0 {
+            self.set_cause(cause | ICR_TXQE);
+        }
+    }
+
+    fn process_tx_desc(&mut self, desc: &[u8; DESC_SIZE as usize]) {
+        let cmd_len = u32::from_le_bytes([desc[8], desc[9], desc[10], desc[11]]);
+        let cmd = desc[11];
+        let special = u16::from_le_bytes([desc[14], desc[15]]);
+
+        let len = if cmd & TXD_CMD_DEXT != 0 {
+            match (cmd_len >> 20) & 0xf {
+                TXD_DTYP_CONTEXT => {
+                    self.context = TxContext {
+                        ipcss: desc[0],
+                        ipcso: desc[1],
+                        tucss: desc[4],
+                        tucso: desc[5],
+                        tucmd: cmd,
+                        hdr_len: desc[13],
+                        mss: u16::from_le_bytes([desc[14], desc[15]]),
+                    };
+                    return;
+                }
+                TXD_DTYP_DATA => {
+                    if self.tx_frame.is_empty() {
+                        self.tx_options = TxFrameOptions {
+                            extended: true,
+                            tse: cmd & TXD_CMD_TSE != 0,
+                            popts: desc[13],
+                            legacy_csum: None,
+                            vlan: (cmd & TXD_CMD_VLE != 0).then_some(special),
+                        };
+                    }
+                    (cmd_len & 0xf_ffff) as usize
+                }
+                dtyp => {
+                    warn!("Unsupported e1000e TX descriptor type {}", dtyp);
+                    return;
+                }
+            }
+        } else {
+            if self.tx_frame.is_empty() {
+                self.tx_options = TxFrameOptions {
+                    legacy_csum: (cmd & TXD_CMD_IC != 0).then_some((desc[13], desc[10])),
+                    vlan: (cmd & TXD_CMD_VLE != 0).then_some(special),
+                    ..Default::default()
+                };
+            }
+            (cmd_len & 0xffff) as usize
+        };
+
+        let buf_addr = u64::from_le_bytes(desc[..8].try_into().unwrap());
+        let offset = self.tx_frame.len();
+        if offset + len > MAX_TX_FRAME_SIZE {
+            warn!("Dropping oversized e1000e TX frame");
+            self.tx_frame.clear();
+            return;
+        }
+        self.tx_frame.resize(offset + len, 0);
+        if let Err(e) = self
+            .mem
+            .memory()
+            .read_slice(&mut self.tx_frame[offset..], GuestAddress(buf_addr))
+        {
+            warn!("Failed to read e1000e TX buffer: {:?}", e);
+            self.tx_frame.truncate(offset);
+            return;
+        }
+
+        if cmd & TXD_CMD_EOP != 0 {
+            let mut frame = std::mem::take(&mut self.tx_frame);
+            self.send_frame(&mut frame);
+            // Keep the allocation around for the next frame.
+            frame.clear();
+            self.tx_frame = frame;
+        }
+    }
+
+    fn send_frame(&mut self, frame: &mut Vec<u8>) {
+        let options = self.tx_options;
+        let context = self.context;
+        let mut hdr = VnetHeader::default();
+
+        if options.extended {
+            if options.tse {
+                if !tso_fixup(frame, &context) {
+                    warn!("Dropping malformed e1000e TSO frame");
+                    return;
+                }
+                hdr.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+                hdr.gso_type = if context.tucmd & TXD_TUCMD_IP != 0 {
+                    VIRTIO_NET_HDR_GSO_TCPV4 as u8
+                } else {
+                    VIRTIO_NET_HDR_GSO_TCPV6 as u8
+                };
+                hdr.hdr_len = u16::from(context.hdr_len);
+                hdr.gso_size = context.mss;
+                hdr.csum_start = u16::from(context.tucss);
+                hdr.csum_offset = u16::from(context.tucso.wrapping_sub(context.tucss));
+            } else {
+                if options.popts & TXD_POPTS_IXSM != 0 {
+                    ipv4_checksum(frame, context.ipcss as usize, context.ipcso as usize);
+                }
+                if options.popts & TXD_POPTS_TXSM != 0 {
+                    hdr.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+                    hdr.csum_start = u16::from(context.tucss);
+                    hdr.csum_offset = u16::from(context.tucso.wrapping_sub(context.tucss));
+                }
+            }
+        } else if let Some((css, cso)) = options.legacy_csum {
+            insert_checksum(frame, css as usize, cso as usize);
+        }
+
+        if let Some(vlan) = options.vlan {
+            if frame.len() >= 12 {
+                let tag = [VLAN_TPID.to_be_bytes(), vlan.to_be_bytes()].concat();
+                frame.splice(12..12, tag);
+                if hdr.flags != 0 {
+                    hdr.csum_start += 4;
+                }
+                if hdr.gso_type != 0 {
+                    hdr.hdr_len += 4;
+                }
+            }
+        }
+
+        let mut buf = Vec::with_capacity(VNET_HDR_LEN + frame.len());
+        buf.extend_from_slice(&hdr.to_bytes());
+        buf.extend_from_slice(frame);
+        if let Err(e) = self.tap.write(&buf) {
+            // Frames are dropped when the TAP queue is full, as a physical
+            // NIC would do on a congested link.
+            if e.kind() != io::ErrorKind::WouldBlock {
+                warn!("Failed to write e1000e frame to the TAP interface: {:?}", e);
+            }
+        }
+    }
+
+    // Returns whether the RX thread must stop polling the TAP interface until
+    // the guest provides more receive descriptors.
+    fn process_rx(&mut self) -> bool {
+        if self.paused {
+            return true;
+        }
+
+        for _ in 0..RX_BURST {
+            let frame = match self.rx_frame.take() {
+                Some(frame) => frame,
+                None => match self.read_frame() {
+                    Some(frame) => frame,
+                    None => return false,
+                },
+            };
+
+            if self.reg(RCTL) & RCTL_EN == 0 || !self.accept_frame(&frame) {
+                continue;
+            }
+
+            if !self.receive_frame(&frame) {
+                self.rx_frame = Some(frame);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn read_frame(&mut self) -> Option<Vec<u8>> {
+        let len = match self.tap.read(&mut self.rx_buf) {
+            Ok(len) => len,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    warn!(
+                        "Failed to read e1000e frame from the TAP interface: {:?}",
+                        e
+                    );
+                }
+                return None;
+            }
+        };
+        if len < VNET_HDR_LEN + 14 {
+            return Some(Vec::new());
+        }
+
+        let mut frame = self.rx_buf[VNET_HDR_LEN..len].to_vec();
+        if frame.len() < MIN_FRAME_SIZE {
+            frame.resize(MIN_FRAME_SIZE, 0);
+        }
+        Some(frame)
+    }
+
+    fn accept_frame(&self, frame: &[u8]) -> bool {
+        if frame.len() < 14 {
+            return false;
+        }
+
+        let rctl = self.reg(RCTL);
+        let dst = &frame[..6];
+        if dst[0] & 1 == 0 {
+            if rctl & RCTL_UPE != 0 {
+                return true;
+            }
+        } else if dst == [0xff; 6] {
+            return rctl & RCTL_BAM != 0;
+        } else if rctl & RCTL_MPE != 0 {
+            return true;
+        } else {
+            let hash = match (rctl >> RCTL_MO_SHIFT) & 0x3 {
+                0 => (u16::from(dst[4]) >> 4) | (u16::from(dst[5]) << 4),
+                1 => (u16::from(dst[4]) >> 3) | (u16::from(dst[5]) << 5),
+                2 => (u16::from(dst[4]) >> 2) | (u16::from(dst[5]) << 6),
+                _ => u16::from(dst[4]) | (u16::from(dst[5]) << 8),
+            } & 0xfff;
+            return self.reg(MTA + ((hash as usize >> 5) << 2)) & (1 << (hash & 0x1f)) != 0;
+        }
+
+        (0..RA_ENTRIES).any(|i| {
+            let ral = self.reg(RAL0 + i * 8).to_le_bytes();
+            let rah = self.reg(RAH0 + i * 8);
+            rah & RAH_AV != 0 && dst[..4] == ral && dst[4..] == rah.to_le_bytes()[..2]
+        })
+    }
+
+    fn rx_buffer_size(&self) -> usize {
+        let rctl = self.reg(RCTL);
+        let bsize = (rctl >> RCTL_BSIZE_SHIFT) & 0x3;
+        if rctl & RCTL_BSEX != 0 {
+            match bsize {
+                1 => 16384,
+                2 => 8192,
+                _ => 4096,
+            }
+        } else {
+            2048 >> bsize
+        }
+    }
+
+    // Returns false if the guest didn't provide enough descriptors to hold
+    // the frame.
+    fn receive_frame(&mut self, frame: &[u8]) -> bool {
+        let mut frame = frame.to_vec();
+        let mut vlan = None;
+        if self.reg(CTRL) & CTRL_VME != 0
+            && frame.len() >= 18
+            && u16::from_be_bytes([frame[12], frame[13]]) == VLAN_TPID
+        {
+            vlan = Some(u16::from_be_bytes([frame[14], frame[15]]));
+            frame.drain(12..16);
+        }
+        if self.reg(RCTL) & RCTL_SECRC == 0 {
+            // The CRC isn't available from the TAP interface.
+            frame.extend_from_slice(&[0; CRC_SIZE]);
+        }
+
+        let base = self.ring_base(RDBAL, RDBAH);
+        let count = self.reg(RDLEN) / DESC_SIZE as u32;
+        let tail = self.reg(RDT);
+        let mut head = self.reg(RDH);
+        if head >= count || tail >= count {
+            return false;
+        }
+        let available = (tail + count - head) % count;
+        let buf_size = self.rx_buffer_size();
+        if (available as usize) * buf_size < frame.len() {
+            return false;
+        }
+
+        let extended = self.reg(RFCTL) & RFCTL_EXTEN != 0;
+        let mem = self.mem.memory();
+        for (i, chunk) in frame.chunks(buf_size).enumerate() {
+            let addr = base + u64::from(head) * DESC_SIZE;
+            let mut desc = [0u8; DESC_SIZE as usize];
+            if let Err(e) = mem.read_slice(&mut desc, GuestAddress(addr)) {
+                warn!("Failed to read e1000e RX descriptor: {:?}", e);
+                return true;
+            }
+            let buf_addr = u64::from_le_bytes(desc[..8].try_into().unwrap());
+            if let Err(e) = mem.write_slice(chunk, GuestAddress(buf_addr)) {
+                warn!("Failed to write e1000e RX buffer: {:?}", e);
+            }
+
+            let mut status = RXD_STAT_DD;
+            if (i + 1) * buf_size >= frame.len() {
+                status |= RXD_STAT_EOP;
+                if vlan.is_some() {
+                    status |= RXD_STAT_VP;
+                }
+            }
+            let len = (chunk.len() as u16).to_le_bytes();
+            let special = vlan.unwrap_or(0).to_le_bytes();
+            // Both formats share the layout of the special/VLAN field.
+            let writeback = if extended {
+                let status = status.to_le_bytes();
+                [
+                    0, 0, 0, 0, 0, 0, 0, 0, status[0], status[1], status[2], status[3], len[0],
+                    len[1], special[0], special[1],
+                ]
+            } else {
+                desc[8] = len[0];
+                desc[9] = len[1];
+                desc[10] = 0;
+                desc[11] = 0;
+                desc[12] = status as u8;
+                desc[13] = 0;
+                desc[14] = special[0];
+                desc[15] = special[1];
+                desc
+            };
+            if let Err(e) = mem.write_slice(&writeback, GuestAddress(addr)) {
+                warn!("Failed to write e1000e RX descriptor: {:?}", e);
+            }
+
+            head = (head + 1) % count;
+        }
+
+        self.set_reg(RDH, head);
+        self.set_cause(ICR_RXT0);
+
+        true
+    }
+
+    fn state(&self) -> E1000eDeviceState {
+        E1000eDeviceState {
+            regs: self.regs.clone(),
+            phy: self.phy.to_vec(),
+            context: self.context,
+        }
+    }
+
+    fn set_state(&mut self, state: &E1000eDeviceState) {
+        if state.regs.len() == self.regs.len() {
+            self.regs.clone_from(&state.regs);
+        }
+        for (reg, value) in self.phy.iter_mut().zip(state.phy.iter()) {
+            *reg = *value;
+        }
+        self.context = state.context;
+    }
+}
+
+#[derive(Default)]
+struct VnetHeader {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHeader {
+    fn to_bytes(&self) -> [u8; VNET_HDR_LEN] {
+        let mut bytes = [0u8; VNET_HDR_LEN];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+        bytes
+    }
+}
+
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// Computes the checksum of the frame from `start` to its end, and stores it
+// at `offset`.
+fn insert_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    if start >= frame.len() || offset + 2 > frame.len() {
+        return;
+    }
+    let sum = !checksum_fold(checksum_add(0, &frame[start..]));
+    frame[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn ipv4_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    let Some(ihl) = frame.get(start).map(|b| usize::from(b & 0xf) * 4) else {
+        return;
+    };
+    if start + ihl > frame.len() || offset + 2 > start + ihl || offset < start {
+        return;
+    }
+    frame[offset..offset + 2].fill(0);
+    let sum = !checksum_fold(checksum_add(0, &frame[start..start + ihl]));
+    frame[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+// The guest prepares TSO frames with a zero IP length and a pseudo-header
+// checksum leaving out the length, as the NIC updates both for each segment.
+// The host stack expects the headers of the whole frame instead.
+fn tso_fixup(frame: &mut [u8], context: &TxContext) -> bool {
+    let ip = context.ipcss as usize;
+    let l4 = context.tucss as usize;
+    let csum = context.tucso as usize;
+    if l4 <= ip || csum + 2 > frame.len() || l4 > frame.len() {
+        return false;
+    }
+    let l4_len = (frame.len() - l4) as u32;
+
+    let sum = if context.tucmd & TXD_TUCMD_IP != 0 {
+        if ip + 20 > l4 {
+            return false;
+        }
+        let tot_len = (frame.len() - ip) as u16;
+        frame[ip + 2..ip + 4].copy_from_slice(&tot_len.to_be_bytes());
+        ipv4_checksum(frame, ip, context.ipcso as usize);
+        checksum_add(0, &frame[ip + 12..ip + 20])
+    } else {
+        if ip + 40 > l4 {
+            return false;
+        }
+        let payload_len = (frame.len() - ip - 40) as u16;
+        frame[ip + 4..ip + 6].copy_from_slice(&payload_len.to_be_bytes());
+        checksum_add(0, &frame[ip + 8..ip + 40])
+    };
+    // TCP protocol number and length of the TCP header and payload.
+    let sum = checksum_fold(sum + 6 + (l4_len >> 16) + (l4_len & 0xffff));
+    frame[csum..csum + 2].copy_from_slice(&sum.to_be_bytes());
+
+    true
+}
+
+fn build_nvm(mac: &MacAddr) -> [u16; NVM_WORDS] {
+    let mut nvm = [0u16; NVM_WORDS];
+    for (i, word) in mac.get_bytes().chunks(2).enumerate() {
+        nvm[i] = u16::from_le_bytes([word[0], word[1]]);
+    }
+    nvm[NVM_INIT_CONTROL1] = 0x0200;
+    nvm[NVM_SUBSYSTEM_ID] = 0x0000;
+    nvm[NVM_SUBSYSTEM_VENDOR_ID] = E1000E_VENDOR_ID;
+    nvm[NVM_DEVICE_ID] = E1000E_DEVICE_ID;
+    nvm[NVM_VENDOR_ID] = E1000E_VENDOR_ID;
+    nvm[NVM_ALT_MAC_ADDR_PTR] = 0xffff;
+
+    let sum = nvm[..NVM_CHECKSUM]
+        .iter()
+        .fold(0u16, |sum, word| sum.wrapping_add(*word));
+    nvm[NVM_CHECKSUM] = NVM_SUM.wrapping_sub(sum);
+    nvm
+}
+
+/// Intel 82574L network controller, backed by a TAP interface.
+pub struct E1000eDevice {
+    id: String,
+    core: Arc<Mutex<E1000eCore>>,
+    tap_fd: RawFd,
+    rx_kick: EventFd,
+    kill_evt: EventFd,
+    rx_thread: Option<thread::JoinHandle<()>>,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+}
+
+impl E1000eDevice {
+    pub fn new(
+        id: String,
+        tap: Tap,
+        mac: MacAddr,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+        irq: u8,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, E1000eError> {
+        let pci_configuration_state =
+            vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
+                E1000eError::RetrievePciConfigurationState(anyhow!(
+                    "Failed to get PciConfigurationState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let restoring = pci_configuration_state.is_some();
+
+        let mut configuration = PciConfiguration::new(
+            E1000E_VENDOR_ID,
+            E1000E_DEVICE_ID,
+            0x0,
+            PciClassCode::NetworkController,
+            &PciNetworkControllerSubclass::EthernetController,
+            None,
+            PciHeaderType::Device,
+            E1000E_VENDOR_ID,
+            0,
+            None,
+            pci_configuration_state,
+        );
+        if !restoring {
+            configuration.set_irq(irq, PciInterruptPin::IntA);
+        }
+
+        let state: Option<E1000eDeviceState> = snapshot
+            .as_ref()
+            .map(|s| s.to_state())
+            .transpose()
+            .map_err(|e| {
+                E1000eError::RetrieveState(anyhow!(
+                    "Failed to get E1000eDeviceState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        // Guest offloads are resolved before frames reach the TAP interface.
+        if let Err(e) = tap.set_offload(0) {
+            warn!("Failed to disable the TAP interface offloads: {:?}", e);
+        }
+
+        let tap_fd = tap.as_raw_fd();
+        let mut core = E1000eCore {
+            regs: vec![0; (E1000E_DEVICE_MMIO_SIZE >> 2) as usize],
+            phy: [0; PHY_REGS],
+            nvm: build_nvm(&mac),
+            mac,
+            context: TxContext::default(),
+            tx_frame: Vec::new(),
+            tx_options: TxFrameOptions::default(),
+            rx_frame: None,
+            rx_buf: vec![0; VNET_HDR_LEN + MAX_RX_FRAME_SIZE],
+            tap,
+            mem,
+            interrupt,
+            paused: false,
+        };
+        core.reset();
+        if let Some(state) = state {
+            core.set_state(&state);
+        }
+
+        Ok(E1000eDevice {
+            id,
+            core: Arc::new(Mutex::new(core)),
+            tap_fd,
+            rx_kick: EventFd::new(libc::EFD_NONBLOCK).map_err(E1000eError::CreateEventFd)?,
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(E1000eError::CreateEventFd)?,
+            rx_thread: None,
+            configuration,
+            bar_regions: vec![],
+        })
+    }
+
+    /// Starts the thread moving the frames from the TAP interface to the
+    /// guest, confined by the given seccomp filter. The thread is named after
+    /// the device.
+    pub fn start_thread(
+        &mut self,
+        seccomp_filter: BpfProgram,
+        exit_evt: EventFd,
+    ) -> Result<(), E1000eError> {
+        if self.rx_thread.is_some() {
+            warn!("Tried to start multiple e1000e RX threads, ignoring");
+            return Ok(());
+        }
+
+        let epoll_fd = epoll::create(true).map_err(E1000eError::CreateEpoll)?;
+        // SAFETY: epoll_fd is valid and owned by nothing else
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        for (fd, data) in [
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.rx_kick.as_raw_fd(), KICK_EVENT),
+            (self.tap_fd, TAP_EVENT),
+        ] {
+            net_util::register_listener(epoll_fd, fd, epoll::Events::EPOLLIN, data)
+                .map_err(E1000eError::CreateEpoll)?;
+        }
+
+        let core = self.core.clone();
+        let rx_kick = self
+            .rx_kick
+            .try_clone()
+            .map_err(E1000eError::CreateEventFd)?;
+        let tap_fd = self.tap_fd;
+        let thread = thread::Builder::new()
+            .name(self.id.clone())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        return;
+                    }
+                }
+
+                let result = std::panic::catch_unwind(AssertUnwindSafe(move || {
+                    let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 3];
+                    let mut polling_tap = true;
+
+                    loop {
+                        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events)
+                        {
+                            Ok(num_events) => num_events,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => {
+                                error!("Failed to wait on e1000e events: {:?}", e);
+                                return;
+                            }
+                        };
+
+                        for event in events.iter().take(num_events) {
+                            match event.data {
+                                KILL_EVENT => return,
+                                KICK_EVENT => {
+                                    let _ = rx_kick.read();
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        let blocked = core.lock().unwrap().process_rx();
+                        // Stop polling the TAP interface while no receive
+                        // descriptor is available, until the guest kicks us.
+                        let result = if blocked && polling_tap {
+                            net_util::unregister_listener(
+                                epoll_file.as_raw_fd(),
+                                tap_fd,
+                                epoll::Events::EPOLLIN,
+                                TAP_EVENT,
+                            )
+                        } else if !blocked && !polling_tap {
+                            net_util::register_listener(
+                                epoll_file.as_raw_fd(),
+                                tap_fd,
+                                epoll::Events::EPOLLIN,
+                                TAP_EVENT,
+                            )
+                        } else {
+                            Ok(())
+                        };
+                        match result {
+                            Ok(()) => polling_tap = !blocked,
+                            Err(e) => error!("Failed to update e1000e TAP polling: {:?}", e),
+                        }
+                    }
+                }));
+                if result.is_err() {
+                    error!("e1000e RX thread panicked");
+                    exit_evt.write(1).ok();
+                }
+            })
+            .map_err(E1000eError::SpawnThread)?;
+        self.rx_thread = Some(thread);
+
+        Ok(())
+    }
+
+    fn kick_rx(&self) {
+        if let Err(e) = self.rx_kick.write(1) {
+            error!("Failed to kick e1000e RX thread: {:?}", e);
+        }
+    }
+
+    pub fn config_bar_addr(&self) -> u64 {
+        self.configuration.get_bar_addr(0)
+    }
+}
+
+impl Drop for E1000eDevice {
+    fn drop(&mut self) {
+        self.kill_evt.write(1).ok();
+        if let Some(handle) = self.rx_thread.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl BusDevice for E1000eDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for E1000eDevice {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let mut bars = Vec::new();
+        let region_size = E1000E_DEVICE_MMIO_SIZE;
+        let restoring = resources.is_some();
+        let bar_addr = mmio32_allocator
+            .allocate(None, region_size, Some(region_size))
+            .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+
+        let bar = PciBarConfiguration::default()
+            .set_index(0)
+            .set_address(bar_addr.raw_value())
+            .set_size(region_size)
+            .set_region_type(PciBarRegionType::Memory32BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        debug!("e1000e bar address 0x{:x}", bar_addr.0);
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+        }
+
+        bars.push(bar);
+        self.bar_regions.clone_from(&bars);
+
+        Ok(bars)
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio32_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let reg = (offset & !0x3) as usize;
+        let shift = (offset & 0x3) as usize;
+        if data.len() + shift > 4 {
+            warn!("Invalid e1000e register read at 0x{:x}", offset);
+            return;
+        }
+
+        let value = self.core.lock().unwrap().read_reg(reg).to_le_bytes();
+        data.copy_from_slice(&value[shift..shift + data.len()]);
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        let reg = (offset & !0x3) as usize;
+        let shift = (offset & 0x3) as usize;
+        if data.len() + shift > 4 {
+            warn!("Invalid e1000e register write at 0x{:x}", offset);
+            return None;
+        }
+
+        let mut core = self.core.lock().unwrap();
+        let mut value = core.reg(reg).to_le_bytes();
+        value[shift..shift + data.len()].copy_from_slice(data);
+        if core.write_reg(reg, u32::from_le_bytes(value)) {
+            drop(core);
+            self.kick_rx();
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for E1000eDevice {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // Taking the lock guarantees the RX thread is done with any frame
+        // it was writing to guest memory.
+        self.core.lock().unwrap().paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.core.lock().unwrap().paused = false;
+        self.kick_rx();
+        Ok(())
+    }
+}
+
+impl Snapshottable for E1000eDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new_from_state(&self.core.lock().unwrap().state())?;
+
+        // Snapshot PciConfiguration
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for E1000eDevice {}
+impl Migratable for E1000eDevice {}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_nvm_checksum() {
+        let mac = MacAddr::parse_str("52:54:00:12:34:56").unwrap();
+        let nvm = build_nvm(&mac);
+
+        assert_eq!(&nvm[..3], &[0x5452, 0x3412, 0x5634]);
+        assert_eq!(
+            nvm.iter().fold(0u16, |sum, word| sum.wrapping_add(*word)),
+            NVM_SUM
+        );
+    }
+
+    #[test]
+    fn test_ipv4_checksum() {
+        // Example from RFC 1071 style IPv4 header, checksum 0xb861.
+        let mut frame = vec![
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        ipv4_checksum(&mut frame, 0, 10);
+        assert_eq!(&frame[10..12], &[0xb8, 0x61]);
+
+        // Out of bounds offsets are ignored.
+        ipv4_checksum(&mut frame, 4, 30);
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Minimal QEMU firmware configuration (fw_cfg) device, as found on the
+//! 0x510 I/O port of the PC machine types.
+//!
+//! Only the items firmware needs to discover the named files are exposed,
+//! along with the files added to the device. Files can be read through the
+//! traditional data port or the DMA interface, while writing them is only
+//! possible through the DMA interface.
+
+use std::borrow::Cow;
+use std::sync::{Arc, Barrier};
+use thiserror::Error;
+use vm_device::BusDevice;
+use vm_memory::{
+    bitmap::AtomicBitmap, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryError,
+};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// Base of the fw_cfg I/O ports
+pub const FW_CFG_PORT_BASE: u64 = 0x510;
+/// Size of the fw_cfg I/O ports, from the selector to the DMA address
+pub const FW_CFG_PORT_SIZE: u64 = 0xc;
+
+// Offsets of the registers from the base port
+const FW_CFG_SELECTOR_OFFSET: u64 = 0x0;
+const FW_CFG_DATA_OFFSET: u64 = 0x1;
+const FW_CFG_DMA_HIGH_OFFSET: u64 = 0x4;
+const FW_CFG_DMA_LOW_OFFSET: u64 = 0x8;
+
+// Items
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_ID: u16 = 0x0001;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FW_CFG_FILE_FIRST: u16 = 0x0020;
+
+const FW_CFG_FEATURE_TRADITIONAL: u32 = 1 << 0;
+const FW_CFG_FEATURE_DMA: u32 = 1 << 1;
+
+// Value read back from the DMA address register, "QEMU CFG"
+const FW_CFG_DMA_SIGNATURE: u64 = 0x5145_4d55_2043_4647;
+
+// Control bits of a DMA access
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SKIP: u32 = 1 << 2;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+const FW_CFG_DMA_CTL_WRITE: u32 = 1 << 4;
+
+// Size of the `FWCfgDmaAccess` structure: control, length and address
+const FW_CFG_DMA_ACCESS_SIZE: usize = 16;
+
+const FW_CFG_MAX_FILE_PATH: usize = 56;
+
+// Data copied at once by a DMA read, whatever the length the guest asked for
+const FW_CFG_DMA_CHUNK_SIZE: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("File name is too long: {0}")]
+    FileNameTooLong(String),
+    #[error("Too many files")]
+    TooManyFiles,
+    #[error("Error accessing guest memory: {0}")]
+    GuestMemory(#[source] GuestMemoryError),
+    #[error("Invalid DMA access control: {0:#x}")]
+    InvalidDmaControl(u32),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Called with the content of a file after the guest wrote to it
+pub type FwCfgWriteCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+struct FwCfgFile {
+    name: String,
+    data: Vec<u8>,
+    on_write: Option<FwCfgWriteCallback>,
+}
+
+pub struct FwCfg {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    files: Vec<FwCfgFile>,
+    selector: u16,
+    offset: usize,
+    dma_address_high: u32,
+}
+
+impl FwCfg {
+    pub fn new(mem: GuestMemoryAtomic<GuestMemoryMmap>) -> Self {
+        Self {
+            mem,
+            files: Vec::new(),
+            selector: FW_CFG_SIGNATURE,
+            offset: 0,
+            dma_address_high: 0,
+        }
+    }
+
+    /// Adds a named file, which the guest can only write to when `on_write`
+    /// is provided. Its size is fixed by the initial `data`.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        data: Vec<u8>,
+        on_write: Option<FwCfgWriteCallback>,
+    ) -> Result<()> {
+        if name.len() >= FW_CFG_MAX_FILE_PATH {
+            return Err(Error::FileNameTooLong(name.to_string()));
+        }
+        if self.files.len() >= (u16::MAX - FW_CFG_FILE_FIRST) as usize {
+            return Err(Error::TooManyFiles);
+        }
+
+        self.files.push(FwCfgFile {
+            name: name.to_string(),
+            data,
+            on_write,
+        });
+
+        Ok(())
+    }
+
+    fn file_dir(&self) -> Vec<u8> {
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+        for (index, file) in self.files.iter().enumerate() {
+            let mut name = [0u8; FW_CFG_MAX_FILE_PATH];
+            name[..file.name.len()].copy_from_slice(file.name.as_bytes());
+
+            dir.extend_from_slice(&(file.data.len() as u32).to_be_bytes());
+            dir.extend_from_slice(&(FW_CFG_FILE_FIRST + index as u16).to_be_bytes());
+            dir.extend_from_slice(&0u16.to_be_bytes());
+            dir.extend_from_slice(&name);
+        }
+
+        dir
+    }
+
+    fn item(&self, selector: u16) -> Cow<'_, [u8]> {
+        match selector {
+            FW_CFG_SIGNATURE => Cow::Borrowed(b"QEMU"),
+            FW_CFG_ID => Cow::Owned(
+                (FW_CFG_FEATURE_TRADITIONAL | FW_CFG_FEATURE_DMA)
+                    .to_le_bytes()
+                    .to_vec(),
+            ),
+            FW_CFG_FILE_DIR => Cow::Owned(self.file_dir()),
+            _ => selector
+                .checked_sub(FW_CFG_FILE_FIRST)
+                .and_then(|index| self.files.get(index as usize))
+                .map_or(Cow::Borrowed(&[][..]), |file| Cow::Borrowed(&file.data)),
+        }
+    }
+
+    fn select(&mut self, selector: u16) {
+        self.selector = selector;
+        self.offset = 0;
+    }
+
+    fn write_file(&mut self, data: &[u8]) {
+        let offset = self.offset;
+        self.offset += data.len();
+
+        let Some(file) = self
+            .selector
+            .checked_sub(FW_CFG_FILE_FIRST)
+            .and_then(|index| self.files.get_mut(index as usize))
+        else {
+            warn!("Write to read-only fw_cfg item {:#x}", self.selector);
+            return;
+        };
+        let Some(on_write) = file.on_write.as_mut() else {
+            warn!("Write to read-only fw_cfg file {}", file.name);
+            return;
+        };
+
+        // Writes can't grow the file
+        if offset < file.data.len() {
+            let len = data.len().min(file.data.len() - offset);
+            file.data[offset..offset + len].copy_from_slice(&data[..len]);
+            on_write(&file.data);
+        }
+    }
+
+    // Processes the `FWCfgDmaAccess` structure at `address`, writing back
+    // its control field to report completion.
+    fn dma_access(&mut self, address: u64) {
+        let mem = self.mem.memory();
+        let address = GuestAddress(address);
+
+        let control = match self.dma_transfer(address) {
+            Ok(()) => 0,
+            Err(e) => {
+                warn!("Error processing fw_cfg DMA access: {e}");
+                FW_CFG_DMA_CTL_ERROR
+            }
+        };
+
+        if let Err(e) = mem.write_obj(control.to_be(), address) {
+            warn!("Error completing fw_cfg DMA access: {e}");
+        }
+    }
+
+    fn dma_transfer(&mut self, address: GuestAddress) -> Result<()> {
+        let mem = self.mem.memory();
+
+        let mut access = [0u8; FW_CFG_DMA_ACCESS_SIZE];
+        mem.read_slice(&mut access, address)
+            .map_err(Error::GuestMemory)?;
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let buffer = GuestAddress(u64::from_be_bytes(access[8..16].try_into().unwrap()));
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+
+        if control & FW_CFG_DMA_CTL_READ != 0 {
+            // Reading past the end of the item returns zeros
+            let item = self.item(self.selector);
+            let mut chunk = [0u8; FW_CFG_DMA_CHUNK_SIZE];
+            let mut done = 0;
+            while done < length {
+                let len = (length - done).min(FW_CFG_DMA_CHUNK_SIZE);
+                let start = self.offset.saturating_add(done);
+                let available = item.len().saturating_sub(start).min(len);
+                if available > 0 {
+                    chunk[..available].copy_from_slice(&item[start..start + available]);
+                }
+                chunk[available..len].fill(0);
+                let address = buffer.checked_add(done as u64).ok_or(Error::GuestMemory(
+                    GuestMemoryError::InvalidGuestAddress(buffer),
+                ))?;
+                mem.write_slice(&chunk[..len], address)
+                    .map_err(Error::GuestMemory)?;
+                done += len;
+            }
+            self.offset = self.offset.saturating_add(length);
+        } else if control & FW_CFG_DMA_CTL_WRITE != 0 {
+            // Writes can't grow the file, what's past its end is skipped
+            let len = length.min(self.item(self.selector).len().saturating_sub(self.offset));
+            let mut data = vec![0u8; len];
+            mem.read_slice(&mut data, buffer)
+                .map_err(Error::GuestMemory)?;
+            self.write_file(&data);
+            self.offset = self.offset.saturating_add(length - len);
+        } else if control & FW_CFG_DMA_CTL_SKIP != 0 {
+            self.offset += length;
+        } else if control & !(0xffff_0000 | FW_CFG_DMA_CTL_SELECT) != 0 {
+            return Err(Error::InvalidDmaControl(control));
+        }
+
+        Ok(())
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match (offset, data.len()) {
+            (FW_CFG_SELECTOR_OFFSET, 2) => data.copy_from_slice(&self.selector.to_le_bytes()),
+            (FW_CFG_DATA_OFFSET, 1) => {
+                data[0] = self
+                    .item(self.selector)
+                    .get(self.offset)
+                    .copied()
+                    .unwrap_or_default();
+                self.offset += 1;
+            }
+            (FW_CFG_DMA_HIGH_OFFSET, 4) => {
+                data.copy_from_slice(&((FW_CFG_DMA_SIGNATURE >> 32) as u32).to_be_bytes())
+            }
+            (FW_CFG_DMA_LOW_OFFSET, 4) => {
+                data.copy_from_slice(&(FW_CFG_DMA_SIGNATURE as u32).to_be_bytes())
+            }
+            _ => {
+                warn!(
+                    "Invalid fw_cfg read: offset {:#x}, size {}",
+                    offset,
+                    data.len()
+                );
+                data.fill(0);
+            }
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match (offset, data.len()) {
+            (FW_CFG_SELECTOR_OFFSET, 2) => self.select(u16::from_le_bytes([data[0], data[1]])),
+            // Writes through the data port are ignored, as on QEMU
+            (FW_CFG_DATA_OFFSET, 1) => {}
+            (FW_CFG_DMA_HIGH_OFFSET, 4) => {
+                self.dma_address_high = u32::from_be_bytes(data.try_into().unwrap())
+            }
+            // Writing the low half of the address triggers the DMA access
+            (FW_CFG_DMA_LOW_OFFSET, 4) => {
+                let address = (u64::from(self.dma_address_high) << 32)
+                    | u64::from(u32::from_be_bytes(data.try_into().unwrap()));
+                self.dma_address_high = 0;
+                self.dma_access(address);
+            }
+            _ => warn!(
+                "Invalid fw_cfg write: offset {:#x}, size {}",
+                offset,
+                data.len()
+            ),
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn fw_cfg() -> FwCfg {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        FwCfg::new(GuestMemoryAtomic::new(mem))
+    }
+
+    fn dma(fw_cfg: &mut FwCfg, control: u32, length: u32, buffer: u64) -> u32 {
+        let mem = fw_cfg.mem.memory();
+        let mut access = control.to_be_bytes().to_vec();
+        access.extend_from_slice(&length.to_be_bytes());
+        access.extend_from_slice(&buffer.to_be_bytes());
+        mem.write_slice(&access, GuestAddress(0x1000)).unwrap();
+
+        fw_cfg.write(0, FW_CFG_DMA_HIGH_OFFSET, &0u32.to_be_bytes());
+        fw_cfg.write(0, FW_CFG_DMA_LOW_OFFSET, &0x1000u32.to_be_bytes());
+        u32::from_be(mem.read_obj(GuestAddress(0x1000)).unwrap())
+    }
+
+    #[test]
+    fn test_fw_cfg_data_port() {
+        let mut fw_cfg = fw_cfg();
+        fw_cfg.write(0, FW_CFG_SELECTOR_OFFSET, &FW_CFG_SIGNATURE.to_le_bytes());
+        let mut signature = Vec::new();
+        for _ in 0..4 {
+            let mut data = [0u8];
+            fw_cfg.read(0, FW_CFG_DATA_OFFSET, &mut data);
+            signature.push(data[0]);
+        }
+        assert_eq!(signature, b"QEMU");
+
+        let mut data = [0u8; 4];
+        fw_cfg.read(0, FW_CFG_DMA_HIGH_OFFSET, &mut data);
+        assert_eq!(&data, b"QEMU");
+        fw_cfg.read(0, FW_CFG_DMA_LOW_OFFSET, &mut data);
+        assert_eq!(&data, b" CFG");
+    }
+
+    #[test]
+    fn test_fw_cfg_dma() {
+        let mut fw_cfg = fw_cfg();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let on_write = {
+            let written = written.clone();
+            Box::new(move |data: &[u8]| *written.lock().unwrap() = data.to_vec())
+        };
+        fw_cfg
+            .add_file("etc/test", vec![0; 4], Some(on_write))
+            .unwrap();
+        fw_cfg.add_file("etc/ro", vec![1; 2], None).unwrap();
+        assert!(fw_cfg.add_file(&"a".repeat(56), vec![], None).is_err());
+
+        let control = (u32::from(FW_CFG_FILE_DIR) << 16) | FW_CFG_DMA_CTL_SELECT;
+        assert_eq!(
+            dma(&mut fw_cfg, control | FW_CFG_DMA_CTL_READ, 68, 0x2000),
+            0
+        );
+        let mem = fw_cfg.mem.memory();
+        let mut dir = [0u8; 68];
+        mem.read_slice(&mut dir, GuestAddress(0x2000)).unwrap();
+        assert_eq!(&dir[0..4], &2u32.to_be_bytes());
+        assert_eq!(&dir[4..8], &4u32.to_be_bytes());
+        assert_eq!(&dir[8..10], &FW_CFG_FILE_FIRST.to_be_bytes());
+        assert_eq!(&dir[12..20], b"etc/test");
+
+        mem.write_slice(&[1, 2, 3, 4, 5], GuestAddress(0x3000))
+            .unwrap();
+        let control = (u32::from(FW_CFG_FILE_FIRST) << 16) | FW_CFG_DMA_CTL_SELECT;
+        assert_eq!(
+            dma(&mut fw_cfg, control | FW_CFG_DMA_CTL_WRITE, 5, 0x3000),
+            0
+        );
+        assert_eq!(*written.lock().unwrap(), vec![1, 2, 3, 4]);
+
+        // Reading the file back, past its end
+        assert_eq!(
+            dma(&mut fw_cfg, control | FW_CFG_DMA_CTL_READ, 6, 0x4000),
+            0
+        );
+        let mut data = [0xffu8; 6];
+        mem.read_slice(&mut data, GuestAddress(0x4000)).unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 0, 0]);
+
+        // Reading more than a chunk at once
+        mem.write_slice(&[0xffu8; 0x2000], GuestAddress(0x5000))
+            .unwrap();
+        assert_eq!(
+            dma(&mut fw_cfg, control | FW_CFG_DMA_CTL_READ, 0x2000, 0x5000),
+            0
+        );
+        let mut data = vec![0xffu8; 0x2000];
+        mem.read_slice(&mut data, GuestAddress(0x5000)).unwrap();
+        assert_eq!(data[..4], [1, 2, 3, 4]);
+        assert!(data[4..].iter().all(|byte| *byte == 0));
+
+        // The length isn't trusted to allocate a buffer
+        assert_eq!(
+            dma(&mut fw_cfg, control | FW_CFG_DMA_CTL_READ, u32::MAX, 0x8000),
+            FW_CFG_DMA_CTL_ERROR
+        );
+
+        assert_eq!(
+            dma(&mut fw_cfg, FW_CFG_DMA_CTL_READ, 1, 0x20000),
+            FW_CFG_DMA_CTL_ERROR
+        );
+    }
+}
@@ -9,10 +9,14 @@ mod cmos;
 #[cfg(target_arch = "x86_64")]
 mod debug_port;
 #[cfg(target_arch = "x86_64")]
+mod fw_cfg;
+#[cfg(target_arch = "x86_64")]
 mod fwdebug;
 #[cfg(target_arch = "aarch64")]
 mod gpio_pl061;
 mod i8042;
+#[cfg(target_arch = "x86_64")]
+mod ramfb;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
 mod serial;
@@ -23,8 +27,14 @@ pub use self::cmos::Cmos;
 #[cfg(target_arch = "x86_64")]
 pub use self::debug_port::DebugPort;
 #[cfg(target_arch = "x86_64")]
+pub use self::fw_cfg::{
+    Error as FwCfgError, FwCfg, FwCfgWriteCallback, FW_CFG_PORT_BASE, FW_CFG_PORT_SIZE,
+};
+#[cfg(target_arch = "x86_64")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
+#[cfg(target_arch = "x86_64")]
+pub use self::ramfb::{Error as RamfbError, Ramfb, RamfbFrame, RAMFB_CFG_SIZE, RAMFB_FILE_NAME};
 pub use self::serial::Serial;
 
 #[cfg(target_arch = "aarch64")]
@@ -35,3 +45,7 @@ pub use self::gpio_pl061::Gpio;
 pub use self::rtc_pl031::Rtc;
 #[cfg(target_arch = "aarch64")]
 pub use self::uart_pl011::Pl011;
+
+/// Receives each byte the guest writes to a UART, whether or not the output
+/// of the UART is connected.
+pub type OutputObserver = Box<dyn FnMut(u8) + Send>;
@@ -0,0 +1,303 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Boot framebuffer in guest RAM, as QEMU's ramfb device.
+//!
+//! The firmware allocates the framebuffer and describes it by writing a
+//! `RAMFBCfg` structure to the `etc/ramfb` fw_cfg file. The framebuffer can be
+//! captured as a PNG image, or read as raw pixels to be displayed remotely.
+
+use thiserror::Error;
+use vm_memory::{
+    bitmap::AtomicBitmap, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryError,
+};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// Name of the fw_cfg file configuring the framebuffer
+pub const RAMFB_FILE_NAME: &str = "etc/ramfb";
+/// Size of the `RAMFBCfg` structure
+pub const RAMFB_CFG_SIZE: usize = 28;
+
+// DRM_FORMAT_XRGB8888, the only format supported by the firmware drivers
+const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+const RAMFB_BYTES_PER_PIXEL: u32 = 4;
+// Upper bound of the framebuffer size, as the largest 32bpp 8K mode
+const RAMFB_MAX_SIZE: u64 = 7680 * 4320 * RAMFB_BYTES_PER_PIXEL as u64;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("The framebuffer hasn't been configured by the guest")]
+    NotConfigured,
+    #[error("Unsupported framebuffer format: {0:#x}")]
+    UnsupportedFormat(u32),
+    #[error("Invalid framebuffer geometry: {width}x{height}, stride {stride}")]
+    InvalidGeometry {
+        width: u32,
+        height: u32,
+        stride: u32,
+    },
+    #[error("Error reading the framebuffer: {0}")]
+    GuestMemory(#[source] GuestMemoryError),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RamfbConfig {
+    address: u64,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
+
+impl RamfbConfig {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let be_u32 =
+            |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let fourcc = be_u32(8);
+        if fourcc != DRM_FORMAT_XRGB8888 {
+            return Err(Error::UnsupportedFormat(fourcc));
+        }
+
+        let config = RamfbConfig {
+            address: u64::from_be_bytes(data[0..8].try_into().unwrap()),
+            width: be_u32(16),
+            height: be_u32(20),
+            stride: be_u32(24),
+        };
+        // A zero stride means the lines are packed
+        let stride = if config.stride == 0 {
+            config.width.saturating_mul(RAMFB_BYTES_PER_PIXEL)
+        } else {
+            config.stride
+        };
+        if config.width == 0
+            || config.height == 0
+            || u64::from(stride) < u64::from(config.width) * u64::from(RAMFB_BYTES_PER_PIXEL)
+            || u64::from(stride) * u64::from(config.height) > RAMFB_MAX_SIZE
+        {
+            return Err(Error::InvalidGeometry {
+                width: config.width,
+                height: config.height,
+                stride: config.stride,
+            });
+        }
+
+        Ok(RamfbConfig { stride, ..config })
+    }
+}
+
+/// Content of the framebuffer, as packed XRGB8888 pixels stored as little
+/// endian, i.e. B, G, R, X.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RamfbFrame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+pub struct Ramfb {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    config: Option<RamfbConfig>,
+}
+
+impl Ramfb {
+    pub fn new(mem: GuestMemoryAtomic<GuestMemoryMmap>) -> Self {
+        Self { mem, config: None }
+    }
+
+    /// Handles the `RAMFBCfg` structure written by the guest to the fw_cfg
+    /// file, an invalid configuration disabling the framebuffer.
+    pub fn configure(&mut self, data: &[u8]) {
+        if data.len() < RAMFB_CFG_SIZE {
+            return;
+        }
+
+        self.config = match RamfbConfig::parse(data) {
+            Ok(config) => {
+                info!(
+                    "ramfb configured: {}x{} at {:#x}",
+                    config.width, config.height, config.address
+                );
+                Some(config)
+            }
+            Err(e) => {
+                warn!("Invalid ramfb configuration: {e}");
+                None
+            }
+        };
+    }
+
+    /// Reads the current content of the framebuffer, without the padding at
+    /// the end of the lines.
+    pub fn frame(&self) -> Result<RamfbFrame> {
+        let config = self.config.ok_or(Error::NotConfigured)?;
+        let mem = self.mem.memory();
+
+        let invalid_geometry = || Error::InvalidGeometry {
+            width: config.width,
+            height: config.height,
+            stride: config.stride,
+        };
+        let line_size = config
+            .width
+            .checked_mul(RAMFB_BYTES_PER_PIXEL)
+            .ok_or_else(invalid_geometry)? as usize;
+        let size = line_size
+            .checked_mul(config.height as usize)
+            .ok_or_else(invalid_geometry)?;
+        let mut pixels = vec![0u8; size];
+        for (y, line) in pixels.chunks_exact_mut(line_size).enumerate() {
+            let address = (y as u64)
+                .checked_mul(u64::from(config.stride))
+                .and_then(|offset| config.address.checked_add(offset))
+                .ok_or(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(
+                    GuestAddress(config.address),
+                )))?;
+            mem.read_slice(line, GuestAddress(address))
+                .map_err(Error::GuestMemory)?;
+        }
+
+        Ok(RamfbFrame {
+            width: config.width,
+            height: config.height,
+            pixels,
+        })
+    }
+
+    /// Captures the current content of the framebuffer as a PNG image.
+    pub fn screenshot(&self) -> Result<Vec<u8>> {
+        let frame = self.frame()?;
+
+        let mut rgb = Vec::with_capacity(frame.pixels.len() / RAMFB_BYTES_PER_PIXEL as usize * 3);
+        for pixel in frame.pixels.chunks_exact(RAMFB_BYTES_PER_PIXEL as usize) {
+            rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+
+        Ok(encode_png(frame.width, frame.height, &rgb))
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Encodes an RGB image as a PNG, using uncompressed deflate blocks as the
+// framebuffer is only captured for debugging purposes.
+fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let line_size = width as usize * 3;
+    let mut scanlines = Vec::with_capacity((line_size + 1) * height as usize);
+    for line in rgb.chunks_exact(line_size) {
+        // No filtering
+        scanlines.push(0);
+        scanlines.extend_from_slice(line);
+    }
+
+    // zlib stream made of stored blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = scanlines.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(u8::from(blocks.peek().is_none()));
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&scanlines).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel RGB, default compression, filter and no interlace
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut png, b"IHDR", &ihdr);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramfb_cfg(address: u64, fourcc: u32, width: u32, height: u32, stride: u32) -> Vec<u8> {
+        let mut data = address.to_be_bytes().to_vec();
+        data.extend_from_slice(&fourcc.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&stride.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_ramfb_screenshot() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut ramfb = Ramfb::new(GuestMemoryAtomic::new(mem.clone()));
+        assert!(matches!(ramfb.screenshot(), Err(Error::NotConfigured)));
+
+        ramfb.configure(&ramfb_cfg(0x1000, 0x3631_5242, 2, 2, 0));
+        assert!(ramfb.config.is_none());
+        ramfb.configure(&ramfb_cfg(0x1000, DRM_FORMAT_XRGB8888, 2, 2, 4));
+        assert!(ramfb.config.is_none());
+
+        // 2x2 framebuffer, with some padding at the end of the lines
+        ramfb.configure(&ramfb_cfg(0x1000, DRM_FORMAT_XRGB8888, 2, 2, 12));
+        mem.write_slice(&[1, 2, 3, 0, 4, 5, 6, 0], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[7, 8, 9, 0, 10, 11, 12, 0], GuestAddress(0x100c))
+            .unwrap();
+
+        let png = ramfb.screenshot().unwrap();
+        assert_eq!(&png[0..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        assert_eq!(&png[37..41], b"IDAT");
+        // zlib header, then a single final stored block of 14 bytes
+        assert_eq!(&png[41..48], &[0x78, 0x01, 1, 14, 0, 0xf1, 0xff]);
+        assert_eq!(&png[48..62], &[0, 3, 2, 1, 6, 5, 4, 0, 9, 8, 7, 12, 11, 10]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use super::OutputObserver;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::sync::{Arc, Barrier};
@@ -40,6 +41,7 @@ const IIR_RECV_BIT: u8 = 0x4;
 const LCR_DLAB_BIT: u8 = 0x80;
 
 const LSR_DATA_BIT: u8 = 0x1;
+const LSR_BREAK_BIT: u8 = 0x10;
 const LSR_EMPTY_BIT: u8 = 0x20;
 const LSR_IDLE_BIT: u8 = 0x40;
 
@@ -67,8 +69,11 @@ pub struct Serial {
     scratch: u8,
     baud_divisor: u16,
     in_buffer: VecDeque<u8>,
+    // Position in `in_buffer` of the character received along with a BREAK
+    break_offset: Option<usize>,
     interrupt: Arc<dyn InterruptSourceGroup>,
     out: Option<Box<dyn io::Write + Send>>,
+    output_observer: Option<OutputObserver>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -82,6 +87,8 @@ pub struct SerialState {
     scratch: u8,
     baud_divisor: u16,
     in_buffer: Vec<u8>,
+    #[serde(default)]
+    break_offset: Option<usize>,
 }
 
 impl Serial {
@@ -101,6 +108,7 @@ impl Serial {
             scratch,
             baud_divisor,
             in_buffer,
+            break_offset,
         ) = if let Some(state) = state {
             (
                 state.interrupt_enable,
@@ -112,6 +120,7 @@ impl Serial {
                 state.scratch,
                 state.baud_divisor,
                 state.in_buffer.into(),
+                state.break_offset,
             )
         } else {
             (
@@ -124,6 +133,7 @@ impl Serial {
                 0,
                 DEFAULT_BAUD_DIVISOR,
                 VecDeque::new(),
+                None,
             )
         };
 
@@ -138,8 +148,10 @@ impl Serial {
             scratch,
             baud_divisor,
             in_buffer,
+            break_offset,
             interrupt,
             out,
+            output_observer: None,
         }
     }
 
@@ -166,6 +178,10 @@ impl Serial {
         self.out = out;
     }
 
+    pub fn set_output_observer(&mut self, observer: Option<OutputObserver>) {
+        self.output_observer = observer;
+    }
+
     /// Queues raw bytes for the guest to read and signals the interrupt if the line status would
     /// change.
     pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<()> {
@@ -176,6 +192,17 @@ impl Serial {
         Ok(())
     }
 
+    /// Queues a BREAK condition for the guest, received as a NUL character flagged in the line
+    /// status. Only the latest one is reported if several are pending.
+    pub fn queue_break(&mut self) -> Result<()> {
+        if !self.is_loop() {
+            self.break_offset = Some(self.in_buffer.len());
+            self.in_buffer.push_back(0);
+            self.recv_data()?;
+        }
+        Ok(())
+    }
+
     pub fn flush_output(&mut self) -> result::Result<(), io::Error> {
         if let Some(out) = self.out.as_mut() {
             out.flush()?;
@@ -255,6 +282,9 @@ impl Serial {
                         out.write_all(&[v])?;
                         out.flush()?;
                     }
+                    if let Some(observer) = self.output_observer.as_mut() {
+                        observer(v);
+                    }
                     self.thr_empty()?;
                 }
             }
@@ -278,6 +308,7 @@ impl Serial {
             scratch: self.scratch,
             baud_divisor: self.baud_divisor,
             in_buffer: self.in_buffer.clone().into(),
+            break_offset: self.break_offset,
         }
     }
 }
@@ -296,6 +327,9 @@ impl BusDevice for Serial {
                 if self.in_buffer.len() <= 1 {
                     self.line_status &= !LSR_DATA_BIT;
                 }
+                if !self.in_buffer.is_empty() {
+                    self.break_offset = self.break_offset.and_then(|o| o.checked_sub(1));
+                }
                 self.in_buffer.pop_front().unwrap_or_default()
             }
             IER => self.interrupt_enable,
@@ -306,6 +340,7 @@ impl BusDevice for Serial {
             }
             LCR => self.line_control,
             MCR => self.modem_control,
+            LSR if self.break_offset == Some(0) => self.line_status | LSR_BREAK_BIT,
             LSR => self.line_status,
             MSR => self.modem_status,
             SCR => self.scratch,
@@ -460,6 +495,37 @@ mod tests {
         assert_eq!(data[0], 0);
     }
 
+    #[test]
+    fn serial_break() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(
+            String::from(SERIAL_NAME),
+            Arc::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            None,
+        );
+
+        serial.queue_input_bytes(&[b'a']).unwrap();
+        serial.queue_break().unwrap();
+        serial.queue_input_bytes(&[b'h']).unwrap();
+
+        let mut data = [0u8];
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0] & LSR_BREAK_BIT, 0);
+        serial.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], b'a');
+
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_ne!(data[0] & LSR_BREAK_BIT, 0);
+        serial.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 0);
+
+        serial.read(0, LSR as u64, &mut data[..]);
+        assert_eq!(data[0] & LSR_BREAK_BIT, 0);
+        assert_ne!(data[0] & LSR_DATA_BIT, 0);
+        serial.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], b'h');
+    }
+
     #[test]
     fn serial_thr() {
         let intr_evt = EventFd::new(0).unwrap();
@@ -6,6 +6,7 @@
 //! This module implements an ARM PrimeCell UART(PL011).
 //!
 
+use super::OutputObserver;
 use crate::{read_le_u32, write_le_u32};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
@@ -40,6 +41,8 @@ const PL011_INT_RX: u32 = 0x10;
 const PL011_FLAG_RXFF: u32 = 0x40;
 const PL011_FLAG_RXFE: u32 = 0x10;
 
+const PL011_DR_BE: u32 = 0x400;
+
 const PL011_ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
 // We are only interested in the margins.
 const AMBA_ID_LOW: u64 = 0x3f8;
@@ -80,6 +83,8 @@ pub struct Pl011 {
     int_enabled: u32,
     int_level: u32,
     read_fifo: VecDeque<u8>,
+    // Position in `read_fifo` of the character received along with a BREAK
+    break_offset: Option<usize>,
     ilpr: u32,
     ibrd: u32,
     fbrd: u32,
@@ -88,6 +93,7 @@ pub struct Pl011 {
     read_trigger: u32,
     irq: Arc<dyn InterruptSourceGroup>,
     out: Option<Box<dyn io::Write + Send>>,
+    output_observer: Option<OutputObserver>,
     timestamp: std::time::Instant,
 }
 
@@ -108,6 +114,8 @@ pub struct Pl011State {
     ifl: u32,
     read_count: u32,
     read_trigger: u32,
+    #[serde(default)]
+    break_offset: Option<usize>,
 }
 
 impl Pl011 {
@@ -135,6 +143,7 @@ impl Pl011 {
             ifl,
             read_count,
             read_trigger,
+            break_offset,
         ) = if let Some(state) = state {
             (
                 state.flags,
@@ -152,6 +161,7 @@ impl Pl011 {
                 state.ifl,
                 state.read_count,
                 state.read_trigger,
+                state.break_offset,
             )
         } else {
             (
@@ -170,6 +180,7 @@ impl Pl011 {
                 0x12,
                 0,
                 1,
+                None,
             )
         };
 
@@ -184,6 +195,7 @@ impl Pl011 {
             int_enabled,
             int_level,
             read_fifo,
+            break_offset,
             ilpr,
             ibrd,
             fbrd,
@@ -192,6 +204,7 @@ impl Pl011 {
             read_trigger,
             irq,
             out,
+            output_observer: None,
             timestamp,
         }
     }
@@ -200,6 +213,10 @@ impl Pl011 {
         self.out = out;
     }
 
+    pub fn set_output_observer(&mut self, observer: Option<OutputObserver>) {
+        self.output_observer = observer;
+    }
+
     fn state(&self) -> Pl011State {
         Pl011State {
             flags: self.flags,
@@ -217,6 +234,7 @@ impl Pl011 {
             ifl: self.ifl,
             read_count: self.read_count,
             read_trigger: self.read_trigger,
+            break_offset: self.break_offset,
         }
     }
 
@@ -238,6 +256,13 @@ impl Pl011 {
         Ok(())
     }
 
+    /// Queues a BREAK condition for the guest, received as a NUL character flagged with a break
+    /// error. Only the latest one is reported if several are pending.
+    pub fn queue_break(&mut self) -> vmm_sys_util::errno::Result<()> {
+        self.break_offset = Some(self.read_fifo.len());
+        self.queue_input_bytes(&[0])
+    }
+
     pub fn flush_output(&mut self) -> result::Result<(), io::Error> {
         if let Some(out) = self.out.as_mut() {
             out.flush()?;
@@ -277,6 +302,9 @@ impl Pl011 {
                         .map_err(Error::WriteAllFailure)?;
                     out.flush().map_err(Error::FlushFailure)?;
                 }
+                if let Some(observer) = self.output_observer.as_mut() {
+                    observer(val.to_le_bytes()[0]);
+                }
             }
             UARTRSR_UARTECR => {
                 self.rsr = 0;
@@ -381,7 +409,11 @@ impl BusDevice for Pl011 {
             match offset >> 2 {
                 UARTDR => {
                     self.flags &= !PL011_FLAG_RXFF;
-                    let c: u32 = self.read_fifo.pop_front().unwrap_or_default().into();
+                    let mut c: u32 = self.read_fifo.pop_front().unwrap_or_default().into();
+                    if self.break_offset == Some(0) {
+                        c |= PL011_DR_BE;
+                    }
+                    self.break_offset = self.break_offset.and_then(|o| o.checked_sub(1));
                     if self.read_count > 0 {
                         self.read_count -= 1;
                     }
@@ -568,4 +600,25 @@ mod tests {
         pl011.read(0, UARTDR, &mut data);
         assert_eq!(data[0], b'c');
     }
+
+    #[test]
+    fn pl011_break() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = Pl011::new(
+            String::from(SERIAL_NAME),
+            Arc::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            None,
+            Instant::now(),
+            None,
+        );
+
+        pl011.queue_break().unwrap();
+        pl011.queue_input_bytes(&[b'h']).unwrap();
+
+        let mut data = [0u8; 4];
+        pl011.read(0, UARTDR, &mut data);
+        assert_eq!(u32::from_le_bytes(data), PL011_DR_BE);
+        pl011.read(0, UARTDR, &mut data);
+        assert_eq!(u32::from_le_bytes(data), u32::from(b'h'));
+    }
 }
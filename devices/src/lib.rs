@@ -17,6 +17,7 @@ extern crate log;
 pub mod acpi;
 #[cfg(target_arch = "x86_64")]
 pub mod debug_console;
+pub mod e1000e;
 #[cfg(target_arch = "aarch64")]
 pub mod gic;
 pub mod interrupt_controller;
@@ -25,9 +26,14 @@ pub mod ioapic;
 pub mod legacy;
 pub mod pvpanic;
 pub mod tpm;
+pub mod usb;
+pub mod vmgenid;
 
 pub use self::acpi::{AcpiGedDevice, AcpiPmTimerDevice, AcpiShutdownDevice};
+pub use self::e1000e::{E1000eDevice, E1000E_DEVICE_MMIO_SIZE};
 pub use self::pvpanic::{PvPanicDevice, PVPANIC_DEVICE_MMIO_SIZE};
+pub use self::usb::{XhciDevice, XHCI_DEVICE_MMIO_SIZE};
+pub use self::vmgenid::{VmGenIdDevice, VMGENID_DEVICE_MMIO_SIZE};
 
 bitflags! {
     pub struct AcpiNotificationFlags: u8 {
@@ -36,6 +42,8 @@ bitflags! {
         const MEMORY_DEVICES_CHANGED = 0b10;
         const PCI_DEVICES_CHANGED = 0b100;
         const POWER_BUTTON_CHANGED = 0b1000;
+        const SLEEP_BUTTON_CHANGED = 0b10000;
+        const VMGENID_CHANGED = 0b100000;
     }
 }
 
@@ -12,6 +12,7 @@ use pci::{
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use thiserror::Error;
 use vm_allocator::{AddressAllocator, SystemAllocator};
@@ -51,6 +52,9 @@ impl PciSubclass for PvPanicSubclass {
 pub struct PvPanicDevice {
     id: String,
     events: u8,
+    // Set when the guest reports a panic, for the VMM to tell why the VM
+    // stopped.
+    panicked: Arc<AtomicBool>,
 
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -63,7 +67,11 @@ pub struct PvPanicDeviceState {
 }
 
 impl PvPanicDevice {
-    pub fn new(id: String, snapshot: Option<Snapshot>) -> Result<Self, PvPanicError> {
+    pub fn new(
+        id: String,
+        panicked: Arc<AtomicBool>,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, PvPanicError> {
         let pci_configuration_state =
             vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
                 PvPanicError::RetrievePciConfigurationState(anyhow!(
@@ -108,6 +116,7 @@ impl PvPanicDevice {
         let pvpanic_device = PvPanicDevice {
             id,
             events,
+            panicked,
             configuration,
             bar_regions: vec![],
         };
@@ -145,6 +154,9 @@ impl BusDevice for PvPanicDevice {
         let event = self.event_to_string(data[0]);
         info!("pvpanic got guest event {}", event);
         event!("guest", "panic", "event", &event);
+        if data[0] & PVPANIC_PANICKED != 0 {
+            self.panicked.store(true, Ordering::SeqCst);
+        }
         None
     }
 }
@@ -21,6 +21,8 @@ pub enum Error {
     CheckCaps(#[source] anyhow::Error),
     #[error("Failed to initialize tpm: {0}")]
     Init(#[source] anyhow::Error),
+    #[error("Failed to run tpm command: {0}")]
+    Command(#[source] anyhow::Error),
 }
 type Result<T> = anyhow::Result<T, Error>;
 
@@ -180,6 +182,44 @@ const PCI_VENDOR_ID_IBM: u32 = 0x1014;
 const CRB_CTRL_CMD_SIZE_REG: u32 = 0x58;
 const CRB_CTRL_CMD_SIZE: usize = TPM_CRB_ADDR_SIZE - CRB_DATA_BUFFER as usize;
 
+// TPM 2.0 commands issued by the VMM on behalf of the firmware
+const TPM2_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM2_ST_SESSIONS: u16 = 0x8002;
+const TPM2_CC_STARTUP: u32 = 0x144;
+const TPM2_CC_PCR_EXTEND: u32 = 0x182;
+const TPM2_SU_CLEAR: u16 = 0x0;
+const TPM2_RS_PW: u32 = 0x4000_0009;
+const TPM2_ALG_SHA256: u16 = 0xb;
+const TPM2_RC_SUCCESS: u32 = 0x0;
+const TPM2_RC_INITIALIZE: u32 = 0x100;
+const TPM2_HEADER_SIZE: usize = 10;
+
+fn tpm2_command(tag: u16, code: u32, params: &[u8]) -> Vec<u8> {
+    let mut command = Vec::with_capacity(TPM2_HEADER_SIZE + params.len());
+    command.extend_from_slice(&tag.to_be_bytes());
+    command.extend_from_slice(&((TPM2_HEADER_SIZE + params.len()) as u32).to_be_bytes());
+    command.extend_from_slice(&code.to_be_bytes());
+    command.extend_from_slice(params);
+    command
+}
+
+fn tpm2_pcr_extend_command(pcr: u32, digest: &[u8; 32]) -> Vec<u8> {
+    let mut params = Vec::new();
+    params.extend_from_slice(&pcr.to_be_bytes());
+    // Password session with an empty password, as PCRs have no auth value
+    params.extend_from_slice(&9_u32.to_be_bytes());
+    params.extend_from_slice(&TPM2_RS_PW.to_be_bytes());
+    params.extend_from_slice(&0_u16.to_be_bytes());
+    params.push(0);
+    params.extend_from_slice(&0_u16.to_be_bytes());
+    // Single SHA-256 digest
+    params.extend_from_slice(&1_u32.to_be_bytes());
+    params.extend_from_slice(&TPM2_ALG_SHA256.to_be_bytes());
+    params.extend_from_slice(digest);
+
+    tpm2_command(TPM2_ST_SESSIONS, TPM2_CC_PCR_EXTEND, &params)
+}
+
 // Returns (register base, offset, len)
 const fn get_field(reg: CrbRegister) -> (u32, u32, u32) {
     match reg {
@@ -336,6 +376,49 @@ impl Tpm {
         }
         Ok(())
     }
+
+    // Returns the response code of the command
+    fn execute(&mut self, command: &[u8]) -> Result<u32> {
+        let mut buffer = [0_u8; TPM_CRB_BUFFER_MAX];
+        buffer[..command.len()].copy_from_slice(command);
+        let mut cmd = BackendCmd {
+            buffer: &mut buffer,
+            input_len: command.len(),
+        };
+        self.emulator
+            .deliver_request(&mut cmd)
+            .map_err(|e| Error::Command(anyhow!("{:?}", e)))?;
+
+        Ok(u32::from_be_bytes(
+            buffer[6..TPM2_HEADER_SIZE].try_into().unwrap(),
+        ))
+    }
+
+    /// Start the TPM up, as the firmware does before measuring the
+    /// components it loads. Starting an already started TPM is harmless.
+    pub fn startup(&mut self) -> Result<()> {
+        let command = tpm2_command(
+            TPM2_ST_NO_SESSIONS,
+            TPM2_CC_STARTUP,
+            &TPM2_SU_CLEAR.to_be_bytes(),
+        );
+        match self.execute(&command)? {
+            TPM2_RC_SUCCESS | TPM2_RC_INITIALIZE => Ok(()),
+            rc => Err(Error::Command(anyhow!("TPM2_Startup failed: {:#x}", rc))),
+        }
+    }
+
+    /// Extend the SHA-256 bank of a PCR with the given digest.
+    pub fn extend_pcr(&mut self, pcr: u32, digest: &[u8; 32]) -> Result<()> {
+        match self.execute(&tpm2_pcr_extend_command(pcr, digest))? {
+            TPM2_RC_SUCCESS => Ok(()),
+            rc => Err(Error::Command(anyhow!(
+                "TPM2_PCR_Extend of PCR {} failed: {:#x}",
+                pcr,
+                rc
+            ))),
+        }
+    }
 }
 
 impl BusDevice for Tpm {
@@ -548,4 +631,15 @@ mod tests {
             concat!("Test: ", stringify!(set_get_reg_field))
         );
     }
+
+    #[test]
+    fn test_pcr_extend_command() {
+        let command = tpm2_pcr_extend_command(9, &[0xaa; 32]);
+        assert_eq!(command.len(), 65);
+        assert_eq!(command[..10], [0x80, 0x02, 0, 0, 0, 65, 0, 0, 0x01, 0x82]);
+        assert_eq!(command[10..14], 9_u32.to_be_bytes());
+        assert_eq!(command[27..31], [0, 0, 0, 1]);
+        assert_eq!(command[31..33], [0, 0xb]);
+        assert_eq!(command[33..], [0xaa; 32]);
+    }
 }
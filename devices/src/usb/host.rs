@@ -0,0 +1,642 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host USB device passed through usbfs.
+//!
+//! Every interface of the active configuration is claimed, detaching the
+//! host drivers until the device is released. Transfers are submitted as
+//! asynchronous URBs, the kernel making the file descriptor writable once
+//! some of them can be reaped. The few standard requests changing the state
+//! the kernel tracks for the device are turned into the matching usbfs
+//! calls instead of being forwarded.
+
+use super::{UsbCompletion, UsbDevice, UsbSpeed, UsbStatus, UsbTransfer, UsbTransferType};
+use libc::{c_int, c_uint, c_ulong, c_void};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use thiserror::Error;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+
+// See include/uapi/linux/usbdevice_fs.h in the kernel code.
+const USBDEVFS_CONTROL: c_ulong = 0xc018_5500;
+const USBDEVFS_SETINTERFACE: c_ulong = 0x8008_5504;
+const USBDEVFS_SETCONFIGURATION: c_ulong = 0x8004_5505;
+const USBDEVFS_SUBMITURB: c_ulong = 0x8038_550a;
+const USBDEVFS_DISCARDURB: c_ulong = 0x550b;
+const USBDEVFS_REAPURBNDELAY: c_ulong = 0x4008_550d;
+const USBDEVFS_RELEASEINTERFACE: c_ulong = 0x8004_5510;
+const USBDEVFS_IOCTL: c_ulong = 0xc010_5512;
+const USBDEVFS_RESET: c_ulong = 0x5514;
+const USBDEVFS_CLEAR_HALT: c_ulong = 0x8004_5515;
+const USBDEVFS_CONNECT: c_ulong = 0x5517;
+const USBDEVFS_DISCONNECT_CLAIM: c_ulong = 0x8108_551b;
+const USBDEVFS_GET_SPEED: c_ulong = 0x551f;
+
+const USBDEVFS_URB_TYPE_INTERRUPT: u8 = 1;
+const USBDEVFS_URB_TYPE_CONTROL: u8 = 2;
+const USBDEVFS_URB_TYPE_BULK: u8 = 3;
+
+const USBDEVFS_DISCONNECT_CLAIM_EXCEPT_DRIVER: c_uint = 0x02;
+
+// See include/uapi/linux/usb/ch9.h in the kernel code.
+const USB_SPEED_LOW: c_int = 1;
+const USB_SPEED_FULL: c_int = 2;
+const USB_SPEED_HIGH: c_int = 3;
+const USB_SPEED_SUPER: c_int = 5;
+const USB_SPEED_SUPER_PLUS: c_int = 6;
+
+const USB_DT_CONFIG: u8 = 0x02;
+const USB_DT_INTERFACE: u8 = 0x04;
+const USB_DT_CONFIG_SIZE: usize = 9;
+
+const USB_REQ_CLEAR_FEATURE: u8 = 0x01;
+const USB_REQ_GET_CONFIGURATION: u8 = 0x08;
+const USB_REQ_SET_CONFIGURATION: u8 = 0x09;
+const USB_REQ_SET_INTERFACE: u8 = 0x0b;
+
+const USB_DIR_IN: u8 = 0x80;
+const USB_RECIP_DEVICE: u8 = 0x00;
+const USB_RECIP_INTERFACE: u8 = 0x01;
+const USB_RECIP_ENDPOINT: u8 = 0x02;
+const USB_ENDPOINT_HALT: u16 = 0;
+
+const CONTROL_TIMEOUT_MS: u32 = 5000;
+
+#[derive(Debug, Error)]
+pub enum HostUsbError {
+    #[error("Failed to open {0}: {1}")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("Failed to read the device descriptors: {0}")]
+    ReadDescriptors(#[source] io::Error),
+    #[error("Invalid device descriptors")]
+    InvalidDescriptors,
+    #[error("Failed to get the device speed: {0}")]
+    GetSpeed(#[source] io::Error),
+    #[error("Unsupported device speed {0}")]
+    UnsupportedSpeed(c_int),
+    #[error("Failed to get the active configuration: {0}")]
+    GetConfiguration(#[source] io::Error),
+    #[error("Failed to claim the device interfaces: {0}")]
+    ClaimInterfaces(#[source] io::Error),
+}
+
+#[repr(C)]
+struct UsbdevfsCtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsSetInterface {
+    interface: c_uint,
+    altsetting: c_uint,
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    ifno: c_int,
+    ioctl_code: c_int,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsDisconnectClaim {
+    interface: c_uint,
+    flags: c_uint,
+    driver: [u8; 256],
+}
+
+#[repr(C)]
+struct UsbdevfsUrb {
+    urb_type: u8,
+    endpoint: u8,
+    status: c_int,
+    flags: c_uint,
+    buffer: *mut c_void,
+    buffer_length: c_int,
+    actual_length: c_int,
+    start_frame: c_int,
+    number_of_packets: c_int,
+    error_count: c_int,
+    signr: c_uint,
+    usercontext: *mut c_void,
+}
+
+// An URB and the buffer it points to, both staying at the same address until
+// the kernel hands the URB back.
+struct PendingUrb {
+    urb: UsbdevfsUrb,
+    buffer: Vec<u8>,
+    id: u64,
+    control: bool,
+    is_in: bool,
+    cancelled: bool,
+}
+
+// SAFETY: the pointer held by the URB only refers to the buffer owned by the
+// same structure.
+unsafe impl Send for PendingUrb {}
+
+// Configuration value and interface numbers of each configuration.
+type Configurations = Vec<(u8, Vec<u8>)>;
+
+fn parse_configurations(descriptors: &[u8]) -> Option<Configurations> {
+    let mut offset = usize::from(*descriptors.first()?);
+    let mut configurations = Vec::new();
+
+    while offset < descriptors.len() {
+        let config = descriptors.get(offset..offset + USB_DT_CONFIG_SIZE)?;
+        let total_length = usize::from(u16::from_le_bytes([config[2], config[3]]));
+        if config[1] != USB_DT_CONFIG || total_length < USB_DT_CONFIG_SIZE {
+            return None;
+        }
+
+        let end = descriptors.len().min(offset + total_length);
+        let mut interfaces = Vec::new();
+        let mut pos = offset + usize::from(config[0]);
+        while pos + 2 < end {
+            let length = usize::from(descriptors[pos]);
+            if length < 2 {
+                break;
+            }
+            if descriptors[pos + 1] == USB_DT_INTERFACE
+                && !interfaces.contains(&descriptors[pos + 2])
+            {
+                interfaces.push(descriptors[pos + 2]);
+            }
+            pos += length;
+        }
+
+        configurations.push((config[5], interfaces));
+        offset += total_length;
+    }
+
+    Some(configurations)
+}
+
+fn ioctl_result(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// A host USB device, reached through its usbfs node.
+pub struct HostUsbDevice {
+    path: PathBuf,
+    file: File,
+    speed: UsbSpeed,
+    configurations: Configurations,
+    active_configuration: u8,
+    claimed: Vec<u8>,
+    // In-flight URBs, indexed by their address.
+    urbs: HashMap<usize, Box<PendingUrb>>,
+    completed: Vec<UsbCompletion>,
+    disconnected: bool,
+}
+
+impl HostUsbDevice {
+    /// Opens the device node, such as /dev/bus/usb/001/004, and claims the
+    /// interfaces of the active configuration.
+    pub fn new(path: &Path) -> Result<Self, HostUsbError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|e| HostUsbError::Open(path.to_path_buf(), e))?;
+
+        let mut descriptors = Vec::new();
+        file.read_to_end(&mut descriptors)
+            .map_err(HostUsbError::ReadDescriptors)?;
+        let configurations =
+            parse_configurations(&descriptors).ok_or(HostUsbError::InvalidDescriptors)?;
+
+        // SAFETY: the ioctl doesn't take any argument
+        let speed = ioctl_result(unsafe { ioctl(&file, USBDEVFS_GET_SPEED) })
+            .map_err(HostUsbError::GetSpeed)?;
+        let speed = match speed {
+            USB_SPEED_LOW => UsbSpeed::Low,
+            USB_SPEED_FULL => UsbSpeed::Full,
+            USB_SPEED_HIGH => UsbSpeed::High,
+            USB_SPEED_SUPER | USB_SPEED_SUPER_PLUS => UsbSpeed::Super,
+            speed => return Err(HostUsbError::UnsupportedSpeed(speed)),
+        };
+
+        let mut active_configuration = 0u8;
+        let control = UsbdevfsCtrlTransfer {
+            request_type: USB_DIR_IN | USB_RECIP_DEVICE,
+            request: USB_REQ_GET_CONFIGURATION,
+            value: 0,
+            index: 0,
+            length: 1,
+            timeout: CONTROL_TIMEOUT_MS,
+            data: &mut active_configuration as *mut u8 as *mut c_void,
+        };
+        // SAFETY: the kernel writes at most `length` bytes to `data`, which
+        // outlives the call
+        ioctl_result(unsafe { ioctl_with_ref(&file, USBDEVFS_CONTROL, &control) })
+            .map_err(HostUsbError::GetConfiguration)?;
+
+        let mut device = HostUsbDevice {
+            path: path.to_path_buf(),
+            file,
+            speed,
+            configurations,
+            active_configuration,
+            claimed: Vec::new(),
+            urbs: HashMap::new(),
+            completed: Vec::new(),
+            disconnected: false,
+        };
+        device
+            .claim_interfaces()
+            .map_err(HostUsbError::ClaimInterfaces)?;
+
+        Ok(device)
+    }
+
+    fn claim_interfaces(&mut self) -> io::Result<()> {
+        let interfaces = self
+            .configurations
+            .iter()
+            .find(|(value, _)| *value == self.active_configuration)
+            .map(|(_, interfaces)| interfaces.clone())
+            .unwrap_or_default();
+
+        for interface in interfaces {
+            let mut claim = UsbdevfsDisconnectClaim {
+                interface: c_uint::from(interface),
+                flags: USBDEVFS_DISCONNECT_CLAIM_EXCEPT_DRIVER,
+                driver: [0; 256],
+            };
+            claim.driver[..5].copy_from_slice(b"usbfs");
+            // SAFETY: the argument is a valid usbdevfs_disconnect_claim
+            ioctl_result(unsafe { ioctl_with_ref(&self.file, USBDEVFS_DISCONNECT_CLAIM, &claim) })?;
+            self.claimed.push(interface);
+        }
+
+        Ok(())
+    }
+
+    // Releases the claimed interfaces, handing them back to the host drivers
+    // if `reconnect` is set.
+    fn release_interfaces(&mut self, reconnect: bool) {
+        for interface in std::mem::take(&mut self.claimed) {
+            let ifno = c_uint::from(interface);
+            // SAFETY: the argument is a valid interface number
+            if let Err(e) = ioctl_result(unsafe {
+                ioctl_with_ref(&self.file, USBDEVFS_RELEASEINTERFACE, &ifno)
+            }) {
+                warn!(
+                    "Failed to release interface {} of {:?}: {:?}",
+                    interface, self.path, e
+                );
+            }
+
+            if reconnect {
+                let command = UsbdevfsIoctl {
+                    ifno: c_int::from(interface),
+                    ioctl_code: USBDEVFS_CONNECT as c_int,
+                    data: null_mut(),
+                };
+                // SAFETY: the argument is a valid usbdevfs_ioctl, the
+                // command not taking any data
+                if let Err(e) =
+                    ioctl_result(unsafe { ioctl_with_ref(&self.file, USBDEVFS_IOCTL, &command) })
+                {
+                    debug!(
+                        "Failed to reconnect the driver of interface {} of {:?}: {:?}",
+                        interface, self.path, e
+                    );
+                }
+            }
+        }
+    }
+
+    fn set_configuration(&mut self, value: u8) -> io::Result<()> {
+        // The kernel refuses to change the configuration while any of its
+        // interfaces is claimed.
+        self.release_interfaces(false);
+        let configuration = c_uint::from(value);
+        // SAFETY: the argument is a valid configuration value
+        let result = ioctl_result(unsafe {
+            ioctl_with_ref(&self.file, USBDEVFS_SETCONFIGURATION, &configuration)
+        });
+        if result.is_ok() {
+            self.active_configuration = value;
+        }
+        self.claim_interfaces()?;
+        result.map(|_| ())
+    }
+
+    fn set_interface(&mut self, interface: u8, altsetting: u8) -> io::Result<()> {
+        let setting = UsbdevfsSetInterface {
+            interface: c_uint::from(interface),
+            altsetting: c_uint::from(altsetting),
+        };
+        // SAFETY: the argument is a valid usbdevfs_setinterface
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, USBDEVFS_SETINTERFACE, &setting) })
+            .map(|_| ())
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> io::Result<()> {
+        let endpoint = c_uint::from(endpoint);
+        // SAFETY: the argument is a valid endpoint address
+        ioctl_result(unsafe { ioctl_with_ref(&self.file, USBDEVFS_CLEAR_HALT, &endpoint) })
+            .map(|_| ())
+    }
+
+    // Handles the standard requests the kernel must know about, returning
+    // None for the ones to forward to the device.
+    fn intercept_control(&mut self, setup: &[u8; 8]) -> Option<io::Result<()>> {
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+
+        match (setup[0], setup[1]) {
+            (USB_RECIP_DEVICE, USB_REQ_SET_CONFIGURATION) => {
+                Some(self.set_configuration(value as u8))
+            }
+            (USB_RECIP_INTERFACE, USB_REQ_SET_INTERFACE) => {
+                Some(self.set_interface(index as u8, value as u8))
+            }
+            (USB_RECIP_ENDPOINT, USB_REQ_CLEAR_FEATURE) if value == USB_ENDPOINT_HALT => {
+                Some(self.clear_halt(index as u8))
+            }
+            _ => None,
+        }
+    }
+
+    fn discard_urbs(&mut self) {
+        for (address, urb) in self.urbs.iter_mut() {
+            if !urb.cancelled {
+                urb.cancelled = true;
+                // SAFETY: the argument is the address of an URB submitted
+                // through this file
+                unsafe { ioctl_with_val(&self.file, USBDEVFS_DISCARDURB, *address as c_ulong) };
+            }
+        }
+        self.completed.clear();
+    }
+
+    fn disconnect(&mut self) {
+        if self.disconnected {
+            return;
+        }
+
+        warn!("USB device {:?} disconnected", self.path);
+        self.disconnected = true;
+        // The kernel doesn't give back the URBs of a disconnected device.
+        for (_, urb) in self.urbs.drain() {
+            if !urb.cancelled {
+                self.completed.push(UsbCompletion {
+                    id: urb.id,
+                    status: UsbStatus::Disconnected,
+                    data: Vec::new(),
+                    actual_length: 0,
+                });
+            }
+        }
+    }
+}
+
+impl UsbDevice for HostUsbDevice {
+    fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    fn completion_fd(&self) -> Option<RawFd> {
+        // The node of a disconnected device never stops polling ready.
+        if self.disconnected {
+            None
+        } else {
+            Some(self.file.as_raw_fd())
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.discard_urbs();
+        // SAFETY: the ioctl doesn't take any argument
+        let result = ioctl_result(unsafe { ioctl(&self.file, USBDEVFS_RESET) });
+        if let Err(e) = &result {
+            if e.raw_os_error() == Some(libc::ENODEV) {
+                self.disconnect();
+            }
+            return result.map(|_| ());
+        }
+
+        // The interfaces got rebound to their host drivers.
+        self.claimed.clear();
+        self.claim_interfaces()
+    }
+
+    fn submit(&mut self, mut transfer: UsbTransfer) -> io::Result<()> {
+        if self.disconnected {
+            self.completed.push(UsbCompletion {
+                id: transfer.id,
+                status: UsbStatus::Disconnected,
+                data: Vec::new(),
+                actual_length: 0,
+            });
+            return Ok(());
+        }
+
+        let is_in = transfer.is_in();
+        let (urb_type, endpoint, buffer) = match transfer.transfer_type {
+            UsbTransferType::Control => {
+                let setup = transfer
+                    .setup
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+                if let Some(result) = self.intercept_control(&setup) {
+                    let status = match result {
+                        Ok(()) => UsbStatus::Success,
+                        Err(e) => {
+                            warn!(
+                                "USB request {:x?} failed on {:?}: {:?}",
+                                setup, self.path, e
+                            );
+                            UsbStatus::Stall
+                        }
+                    };
+                    self.completed.push(UsbCompletion {
+                        id: transfer.id,
+                        status,
+                        data: Vec::new(),
+                        actual_length: 0,
+                    });
+                    return Ok(());
+                }
+
+                // The kernel expects the SETUP packet to be followed by
+                // exactly the amount of data it announces.
+                transfer
+                    .data
+                    .resize(usize::from(u16::from_le_bytes([setup[6], setup[7]])), 0);
+                let mut buffer = setup.to_vec();
+                buffer.append(&mut transfer.data);
+                (USBDEVFS_URB_TYPE_CONTROL, setup[0] & USB_DIR_IN, buffer)
+            }
+            UsbTransferType::Bulk => (USBDEVFS_URB_TYPE_BULK, transfer.endpoint, transfer.data),
+            UsbTransferType::Interrupt => (
+                USBDEVFS_URB_TYPE_INTERRUPT,
+                transfer.endpoint,
+                transfer.data,
+            ),
+        };
+
+        let mut pending = Box::new(PendingUrb {
+            urb: UsbdevfsUrb {
+                urb_type,
+                endpoint,
+                status: 0,
+                flags: 0,
+                buffer: null_mut(),
+                buffer_length: buffer.len() as c_int,
+                actual_length: 0,
+                start_frame: 0,
+                number_of_packets: 0,
+                error_count: 0,
+                signr: 0,
+                usercontext: null_mut(),
+            },
+            buffer,
+            id: transfer.id,
+            control: urb_type == USBDEVFS_URB_TYPE_CONTROL,
+            is_in,
+            cancelled: false,
+        });
+        pending.urb.buffer = pending.buffer.as_mut_ptr() as *mut c_void;
+
+        // SAFETY: the URB and its buffer are kept alive, at the same address,
+        // until the kernel hands the URB back
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_SUBMITURB, &mut pending.urb) };
+        if let Err(e) = ioctl_result(ret) {
+            if e.raw_os_error() == Some(libc::ENODEV) {
+                self.disconnect();
+                self.completed.push(UsbCompletion {
+                    id: pending.id,
+                    status: UsbStatus::Disconnected,
+                    data: Vec::new(),
+                    actual_length: 0,
+                });
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        let address = &pending.urb as *const UsbdevfsUrb as usize;
+        self.urbs.insert(address, pending);
+
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: u64) {
+        self.completed.retain(|completion| completion.id != id);
+        if let Some((address, urb)) = self.urbs.iter_mut().find(|(_, urb)| urb.id == id) {
+            urb.cancelled = true;
+            // SAFETY: the argument is the address of an URB submitted
+            // through this file. The URB is reaped as usual once discarded.
+            unsafe { ioctl_with_val(&self.file, USBDEVFS_DISCARDURB, *address as c_ulong) };
+        }
+    }
+
+    fn completions(&mut self) -> Vec<UsbCompletion> {
+        while !self.disconnected {
+            let mut address: *mut UsbdevfsUrb = null_mut();
+            // SAFETY: the kernel only writes the address of a reaped URB
+            let ret =
+                unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_REAPURBNDELAY, &mut address) };
+            if let Err(e) = ioctl_result(ret) {
+                match e.raw_os_error() {
+                    Some(libc::EAGAIN) => {}
+                    Some(libc::ENODEV) => self.disconnect(),
+                    _ => warn!("Failed to reap URBs of {:?}: {:?}", self.path, e),
+                }
+                break;
+            }
+
+            let Some(urb) = self.urbs.remove(&(address as usize)) else {
+                warn!("Reaped unknown URB 0x{:x}", address as usize);
+                continue;
+            };
+            if urb.cancelled {
+                continue;
+            }
+
+            let status = match -urb.urb.status {
+                0 => UsbStatus::Success,
+                libc::EPIPE => UsbStatus::Stall,
+                libc::EOVERFLOW => UsbStatus::Babble,
+                libc::ENODEV | libc::ESHUTDOWN => UsbStatus::Disconnected,
+                _ => UsbStatus::Error,
+            };
+            let actual_length = urb.urb.actual_length.max(0) as usize;
+            let data = if urb.is_in {
+                let start = if urb.control { 8 } else { 0 };
+                let end = urb.buffer.len().min(start + actual_length);
+                urb.buffer[start.min(end)..end].to_vec()
+            } else {
+                Vec::new()
+            };
+            self.completed.push(UsbCompletion {
+                id: urb.id,
+                status,
+                data,
+                actual_length,
+            });
+        }
+
+        std::mem::take(&mut self.completed)
+    }
+}
+
+impl Drop for HostUsbDevice {
+    fn drop(&mut self) {
+        // Releasing the interfaces kills the URBs still in flight, before
+        // their buffers get freed.
+        self.discard_urbs();
+        self.release_interfaces(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_configurations() {
+        let mut descriptors = vec![
+            // Device descriptor
+            18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0x6b, 0x1d, 0x04, 0x01, 0, 1, 1, 2, 3, 1,
+            // Configuration 1, two interfaces
+            9, 0x02, 43, 0, 2, 1, 0, 0x80, 50, // Interface 0, alternate settings 0 and 1
+            9, 0x04, 0, 0, 1, 3, 1, 1, 0, // Endpoint 0x81
+            7, 0x05, 0x81, 0x03, 8, 0, 10, 9, 0x04, 0, 1, 0, 3, 1, 1, 0, // Interface 1
+            9, 0x04, 1, 0, 0, 3, 0, 0, 0, // Configuration 2, no interface
+            9, 0x02, 9, 0, 0, 2, 0, 0x80, 50,
+        ];
+        assert_eq!(
+            parse_configurations(&descriptors),
+            Some(vec![(1, vec![0, 1]), (2, vec![])])
+        );
+
+        // Truncated configuration descriptor
+        descriptors.truncate(descriptors.len() - 3);
+        assert_eq!(parse_configurations(&descriptors), None);
+
+        assert_eq!(parse_configurations(&[]), None);
+    }
+}
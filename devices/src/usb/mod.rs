@@ -0,0 +1,110 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! USB host controller emulation and the devices plugged into it.
+//!
+//! The controller only deals with [`UsbDevice`] trait objects, transfers
+//! being submitted asynchronously and reaped once the device signals its
+//! file descriptor.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+pub mod host;
+pub mod xhci;
+
+pub use self::host::{HostUsbDevice, HostUsbError};
+pub use self::xhci::{XhciDevice, XhciError, XHCI_DEVICE_MMIO_SIZE};
+
+/// Speed a USB device operates at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+/// Type of the endpoint a transfer is submitted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbTransferType {
+    Control,
+    Bulk,
+    Interrupt,
+}
+
+/// A transfer submitted to a USB device.
+#[derive(Debug)]
+pub struct UsbTransfer {
+    /// Identifier reported back with the completion.
+    pub id: u64,
+    pub transfer_type: UsbTransferType,
+    /// Endpoint address, bit 7 being set for IN endpoints.
+    pub endpoint: u8,
+    /// SETUP packet of control transfers.
+    pub setup: Option<[u8; 8]>,
+    /// Data sent to the device, or a buffer sized for the data expected
+    /// from it.
+    pub data: Vec<u8>,
+}
+
+impl UsbTransfer {
+    /// Returns true if data flows from the device to the host.
+    pub fn is_in(&self) -> bool {
+        match self.setup {
+            Some(setup) => setup[0] & 0x80 != 0,
+            None => self.endpoint & 0x80 != 0,
+        }
+    }
+}
+
+/// Outcome of a transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbStatus {
+    Success,
+    /// The endpoint returned a STALL handshake.
+    Stall,
+    /// The device sent more data than expected.
+    Babble,
+    /// Any other transaction error.
+    Error,
+    /// The device is gone.
+    Disconnected,
+}
+
+/// A completed transfer.
+#[derive(Debug)]
+pub struct UsbCompletion {
+    pub id: u64,
+    pub status: UsbStatus,
+    /// Data received from the device, truncated to the length actually
+    /// transferred. Empty for OUT transfers.
+    pub data: Vec<u8>,
+    /// Number of bytes actually transferred.
+    pub actual_length: usize,
+}
+
+/// A device plugged into a port of an emulated USB host controller.
+pub trait UsbDevice: Send {
+    /// Speed the device operates at.
+    fn speed(&self) -> UsbSpeed;
+
+    /// File descriptor readable or writable once transfers complete, if the
+    /// completions aren't all reported synchronously.
+    fn completion_fd(&self) -> Option<RawFd>;
+
+    /// Resets the device, as done when the guest resets its port. Transfers
+    /// still in flight are dropped.
+    fn reset(&mut self) -> io::Result<()>;
+
+    /// Starts a transfer, whose completion is returned by `completions()`.
+    fn submit(&mut self, transfer: UsbTransfer) -> io::Result<()>;
+
+    /// Cancels a transfer still in flight, which is then never reported.
+    fn cancel(&mut self, id: u64);
+
+    /// Returns the transfers completed since the last call.
+    fn completions(&mut self) -> Vec<UsbCompletion>;
+}
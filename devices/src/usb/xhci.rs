@@ -0,0 +1,2707 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated xHCI USB host controller.
+//!
+//! The controller exposes 8 USB 2.0 ports followed by 8 USB 3.0 ports, up to
+//! 16 device slots and a single interrupter signalled through INTx. Control,
+//! bulk and interrupt transfers are supported, isochronous endpoints being
+//! refused when configured.
+//!
+//! The vCPU threads only update the registers and record the doorbells. The
+//! command and transfer rings are processed by a worker thread, which also
+//! reaps the completions of the attached devices, so that the devices are
+//! never driven from the vCPU threads.
+
+use super::{UsbCompletion, UsbDevice, UsbSpeed, UsbStatus, UsbTransfer, UsbTransferType};
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType, PciInterruptPin,
+    PciProgrammingInterface, PciSerialBusSubClass, PCI_CONFIGURATION_ID,
+};
+use seccompiler::{apply_filter, BpfProgram};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::result;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Instant;
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{BusDevice, Resource};
+use vm_memory::{
+    bitmap::AtomicBitmap, Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+// Same identifiers as the QEMU xHCI controller, which guests already know.
+const XHCI_VENDOR_ID: u16 = 0x1b36;
+const XHCI_DEVICE_ID: u16 = 0x000d;
+const XHCI_PROG_IF: u8 = 0x30;
+
+pub const XHCI_DEVICE_MMIO_SIZE: u64 = 0x1_0000;
+
+const MAX_SLOTS: u8 = 16;
+const USB2_PORTS: u8 = 8;
+const USB3_PORTS: u8 = 8;
+const NUM_PORTS: u8 = USB2_PORTS + USB3_PORTS;
+const MAX_ENDPOINTS: usize = 31;
+// Log2 of the number of event ring segments.
+const ERST_MAX: u32 = 4;
+
+// Largest transfer descriptor, in bytes.
+const MAX_TD_SIZE: usize = 4 << 20;
+// Bounds the TRBs walked at once, guarding against link TRB loops.
+const MAX_TRBS: usize = 4096;
+// Bounds the completion rounds handled before yielding.
+const MAX_COMPLETION_ROUNDS: usize = 256;
+
+// Capability registers.
+const CAPLENGTH: u64 = 0x00;
+const HCSPARAMS1: u64 = 0x04;
+const HCSPARAMS2: u64 = 0x08;
+const HCSPARAMS3: u64 = 0x0c;
+const HCCPARAMS1: u64 = 0x10;
+const DBOFF: u64 = 0x14;
+const RTSOFF: u64 = 0x18;
+const HCCPARAMS2: u64 = 0x1c;
+
+const CAP_LENGTH: u64 = 0x40;
+const HCI_VERSION: u32 = 0x0100;
+const HCCPARAMS1_AC64: u32 = 1 << 0;
+
+// Operational registers.
+const USBCMD: u64 = CAP_LENGTH;
+const USBSTS: u64 = CAP_LENGTH + 0x04;
+const PAGESIZE: u64 = CAP_LENGTH + 0x08;
+const DNCTRL: u64 = CAP_LENGTH + 0x14;
+const CRCR_LO: u64 = CAP_LENGTH + 0x18;
+const CRCR_HI: u64 = CAP_LENGTH + 0x1c;
+const DCBAAP_LO: u64 = CAP_LENGTH + 0x30;
+const DCBAAP_HI: u64 = CAP_LENGTH + 0x34;
+const CONFIG: u64 = CAP_LENGTH + 0x38;
+const PORT_REGS: u64 = CAP_LENGTH + 0x400;
+const PORT_REGS_SIZE: u64 = 0x10;
+
+const USBCMD_RS: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+const USBCMD_LHCRST: u32 = 1 << 7;
+
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_HSE: u32 = 1 << 2;
+const USBSTS_EINT: u32 = 1 << 3;
+const USBSTS_PCD: u32 = 1 << 4;
+const USBSTS_SRE: u32 = 1 << 10;
+const USBSTS_RW1C: u32 = USBSTS_HSE | USBSTS_EINT | USBSTS_PCD | USBSTS_SRE;
+
+const CRCR_RCS: u32 = 1 << 0;
+const CRCR_CS: u32 = 1 << 1;
+const CRCR_CA: u32 = 1 << 2;
+const CRCR_CRR: u32 = 1 << 3;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PLS_SHIFT: u32 = 5;
+const PORTSC_PLS_MASK: u32 = 0xf << PORTSC_PLS_SHIFT;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_PIC_MASK: u32 = 0x3 << 14;
+const PORTSC_LWS: u32 = 1 << 16;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PEC: u32 = 1 << 18;
+const PORTSC_WRC: u32 = 1 << 19;
+const PORTSC_OCC: u32 = 1 << 20;
+const PORTSC_PRC: u32 = 1 << 21;
+const PORTSC_PLC: u32 = 1 << 22;
+const PORTSC_CEC: u32 = 1 << 23;
+const PORTSC_WAKE_MASK: u32 = 0x7 << 25;
+const PORTSC_WPR: u32 = 1 << 31;
+const PORTSC_CHANGE_MASK: u32 =
+    PORTSC_CSC | PORTSC_PEC | PORTSC_WRC | PORTSC_OCC | PORTSC_PRC | PORTSC_PLC | PORTSC_CEC;
+
+const PLS_U0: u32 = 0;
+const PLS_U3: u32 = 3;
+const PLS_RX_DETECT: u32 = 5;
+const PLS_POLLING: u32 = 7;
+const PLS_RESUME: u32 = 15;
+
+// Extended capabilities, describing which ports speak which protocol.
+const EXT_CAPS: u64 = 0x800;
+const SUPPORTED_PROTOCOLS: [u32; 8] = [
+    0x0200_0402,
+    0x2042_5355,
+    (USB2_PORTS as u32) << 8 | 1,
+    0,
+    0x0300_0002,
+    0x2042_5355,
+    (USB3_PORTS as u32) << 8 | (USB2_PORTS as u32 + 1),
+    0,
+];
+
+// Runtime registers.
+const RUNTIME_REGS: u64 = 0x1000;
+const MFINDEX: u64 = RUNTIME_REGS;
+const IMAN: u64 = RUNTIME_REGS + 0x20;
+const IMOD: u64 = RUNTIME_REGS + 0x24;
+const ERSTSZ: u64 = RUNTIME_REGS + 0x28;
+const ERSTBA_LO: u64 = RUNTIME_REGS + 0x30;
+const ERSTBA_HI: u64 = RUNTIME_REGS + 0x34;
+const ERDP_LO: u64 = RUNTIME_REGS + 0x38;
+const ERDP_HI: u64 = RUNTIME_REGS + 0x3c;
+
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+const IMOD_DEFAULT: u32 = 4000;
+const ERDP_EHB: u64 = 1 << 3;
+
+const DOORBELLS: u64 = 0x2000;
+
+// TRB types.
+const TRB_NORMAL: u8 = 1;
+const TRB_SETUP: u8 = 2;
+const TRB_DATA: u8 = 3;
+const TRB_STATUS: u8 = 4;
+const TRB_LINK: u8 = 6;
+const TRB_EVENT_DATA: u8 = 7;
+const TRB_NOOP: u8 = 8;
+const TRB_ENABLE_SLOT: u8 = 9;
+const TRB_DISABLE_SLOT: u8 = 10;
+const TRB_ADDRESS_DEVICE: u8 = 11;
+const TRB_CONFIGURE_ENDPOINT: u8 = 12;
+const TRB_EVALUATE_CONTEXT: u8 = 13;
+const TRB_RESET_ENDPOINT: u8 = 14;
+const TRB_STOP_ENDPOINT: u8 = 15;
+const TRB_SET_TR_DEQUEUE: u8 = 16;
+const TRB_RESET_DEVICE: u8 = 17;
+const TRB_NOOP_COMMAND: u8 = 23;
+const TRB_TRANSFER_EVENT: u8 = 32;
+const TRB_COMMAND_COMPLETION: u8 = 33;
+const TRB_PORT_STATUS_CHANGE: u8 = 34;
+
+const TRB_C: u32 = 1 << 0;
+const TRB_TC: u32 = 1 << 1;
+const TRB_ED: u32 = 1 << 2;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CH: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_BSR: u32 = 1 << 9;
+const TRB_DC: u32 = 1 << 9;
+const TRB_TYPE_SHIFT: u32 = 10;
+
+// Completion codes.
+const CC_SUCCESS: u8 = 1;
+const CC_DATA_BUFFER_ERROR: u8 = 2;
+const CC_BABBLE_DETECTED: u8 = 3;
+const CC_USB_TRANSACTION_ERROR: u8 = 4;
+const CC_TRB_ERROR: u8 = 5;
+const CC_STALL_ERROR: u8 = 6;
+const CC_BANDWIDTH_ERROR: u8 = 8;
+const CC_NO_SLOTS_AVAILABLE: u8 = 9;
+const CC_SLOT_NOT_ENABLED: u8 = 11;
+const CC_SHORT_PACKET: u8 = 13;
+const CC_PARAMETER_ERROR: u8 = 17;
+const CC_CONTEXT_STATE_ERROR: u8 = 19;
+const CC_COMMAND_RING_STOPPED: u8 = 24;
+
+// Slot and endpoint context states and endpoint types.
+const SLOT_DEFAULT: u32 = 1;
+const SLOT_ADDRESSED: u32 = 2;
+const SLOT_CONFIGURED: u32 = 3;
+
+const EP_DISABLED: u8 = 0;
+const EP_RUNNING: u8 = 1;
+const EP_HALTED: u8 = 2;
+const EP_STOPPED: u8 = 3;
+const EP_ERROR: u8 = 4;
+
+const EP_ISOCH_OUT: u8 = 1;
+const EP_BULK_OUT: u8 = 2;
+const EP_INTERRUPT_OUT: u8 = 3;
+const EP_CONTROL: u8 = 4;
+const EP_ISOCH_IN: u8 = 5;
+const EP_BULK_IN: u8 = 6;
+const EP_INTERRUPT_IN: u8 = 7;
+
+const CONTEXT_SIZE: u64 = 0x20;
+
+// Events of the worker thread. Device events are tagged with their port
+// index, offset by DEVICE_EVENT.
+const KILL_EVENT: u64 = 1;
+const KICK_EVENT: u64 = 2;
+const DEVICE_EVENT: u64 = 16;
+
+#[derive(Debug, Error)]
+pub enum XhciError {
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Failed to retrieve XhciDeviceState: {0}")]
+    RetrieveState(#[source] anyhow::Error),
+    #[error("Failed to create an eventfd: {0}")]
+    CreateEventFd(#[source] io::Error),
+    #[error("Failed to create the epoll context: {0}")]
+    CreateEpoll(#[source] io::Error),
+    #[error("Failed to spawn the worker thread: {0}")]
+    SpawnThread(#[source] io::Error),
+    #[error("No free USB port")]
+    NoFreePort,
+}
+
+struct XhciProgrammingInterface;
+
+impl PciProgrammingInterface for XhciProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        XHCI_PROG_IF
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn from_bytes(bytes: &[u8; 16]) -> Self {
+        Trb {
+            parameter: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            status: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            control: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    fn trb_type(&self) -> u8 {
+        ((self.control >> TRB_TYPE_SHIFT) & 0x3f) as u8
+    }
+
+    fn cycle(&self) -> bool {
+        self.control & TRB_C != 0
+    }
+
+    fn transfer_length(&self) -> usize {
+        (self.status & 0x1_ffff) as usize
+    }
+
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    fn endpoint_id(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+}
+
+// Position of the consumer of a ring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Ring {
+    dequeue: u64,
+    cycle: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct EventRing {
+    // Base address and size in TRBs of each segment.
+    segments: Vec<(u64, u32)>,
+    segment: usize,
+    index: u32,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn enqueue_address(&self) -> Option<u64> {
+        let (base, _) = self.segments.get(self.segment)?;
+        Some(base + 16 * u64::from(self.index))
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+        if self.index >= self.segments[self.segment].1 {
+            self.index = 0;
+            self.segment += 1;
+            if self.segment == self.segments.len() {
+                self.segment = 0;
+                self.cycle = !self.cycle;
+            }
+        }
+    }
+}
+
+// TRBs of a TD, along with the ring position following it.
+type FetchedTd = (Vec<(u64, Trb)>, Ring);
+
+// A transfer descriptor handed to a device.
+struct Td {
+    id: u64,
+    trbs: Vec<(u64, Trb)>,
+    // Ring position following the TD.
+    next: Ring,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Endpoint {
+    state: u8,
+    ep_type: u8,
+    ring: Ring,
+    #[serde(skip)]
+    td: Option<Td>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Slot {
+    enabled: bool,
+    state: u32,
+    // Root hub port, numbered from 1.
+    port: u8,
+    // Output device context.
+    context: u64,
+    endpoints: Vec<Endpoint>,
+    // Endpoints whose doorbell got rung, indexed by DCI.
+    #[serde(skip)]
+    doorbells: u32,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Slot {
+            endpoints: (0..MAX_ENDPOINTS).map(|_| Endpoint::default()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+struct Port {
+    portsc: u32,
+    device: Option<Box<dyn UsbDevice>>,
+    // Bumped on each attachment, to tell the worker the device changed.
+    generation: u64,
+    reset: bool,
+    warm_reset: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct XhciDeviceState {
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    command_ring: Ring,
+    command_ring_running: bool,
+    dcbaap: u64,
+    config: u32,
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    event_ring: EventRing,
+    slots: Vec<Slot>,
+}
+
+// The controller state, shared between the vCPU threads and the worker
+// thread.
+struct XhciCore {
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    command_ring: Ring,
+    command_ring_running: bool,
+    dcbaap: u64,
+    config: u32,
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    event_ring: EventRing,
+    slots: Vec<Slot>,
+    ports: Vec<Port>,
+    started: Instant,
+    next_transfer_id: u64,
+    generation: u64,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt: Arc<dyn InterruptSourceGroup>,
+    paused: bool,
+}
+
+fn speed_id(speed: UsbSpeed) -> u32 {
+    match speed {
+        UsbSpeed::Full => 1,
+        UsbSpeed::Low => 2,
+        UsbSpeed::High => 3,
+        UsbSpeed::Super => 4,
+    }
+}
+
+impl XhciCore {
+    fn new(
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+    ) -> Self {
+        let mut core = XhciCore {
+            usbcmd: 0,
+            usbsts: 0,
+            dnctrl: 0,
+            command_ring: Ring::default(),
+            command_ring_running: false,
+            dcbaap: 0,
+            config: 0,
+            iman: 0,
+            imod: 0,
+            erstsz: 0,
+            erstba: 0,
+            erdp: 0,
+            event_ring: EventRing::default(),
+            slots: Vec::new(),
+            ports: (0..NUM_PORTS)
+                .map(|_| Port {
+                    portsc: 0,
+                    device: None,
+                    generation: 0,
+                    reset: false,
+                    warm_reset: false,
+                })
+                .collect(),
+            started: Instant::now(),
+            next_transfer_id: 0,
+            generation: 0,
+            mem,
+            interrupt,
+            paused: false,
+        };
+        core.reset();
+        core
+    }
+
+    fn reset(&mut self) {
+        for slot_id in 1..=MAX_SLOTS {
+            self.disable_slot(slot_id);
+        }
+        self.slots = (0..MAX_SLOTS).map(|_| Slot::new()).collect();
+
+        self.usbcmd = 0;
+        self.usbsts = USBSTS_HCH;
+        self.dnctrl = 0;
+        self.command_ring = Ring::default();
+        self.command_ring_running = false;
+        self.dcbaap = 0;
+        self.config = 0;
+        self.iman = 0;
+        self.imod = IMOD_DEFAULT;
+        self.erstsz = 0;
+        self.erstba = 0;
+        self.erdp = 0;
+        self.event_ring = EventRing::default();
+
+        for port in 0..NUM_PORTS {
+            self.update_port_connection(port);
+            self.ports[port as usize].reset = false;
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.usbcmd & USBCMD_RS != 0
+    }
+
+    fn is_usb3_port(port: u8) -> bool {
+        port >= USB2_PORTS
+    }
+
+    // Sets the port status according to the device attached to it.
+    fn update_port_connection(&mut self, port: u8) {
+        let usb3 = Self::is_usb3_port(port);
+        let p = &mut self.ports[port as usize];
+        p.portsc = match &p.device {
+            Some(device) => {
+                let portsc = PORTSC_CCS
+                    | PORTSC_PP
+                    | PORTSC_CSC
+                    | speed_id(device.speed()) << PORTSC_SPEED_SHIFT;
+                // USB 3.0 links get enabled once trained, while USB 2.0
+                // ports wait for a reset.
+                if usb3 {
+                    portsc | PORTSC_PED | PLS_U0 << PORTSC_PLS_SHIFT
+                } else {
+                    portsc | PLS_POLLING << PORTSC_PLS_SHIFT
+                }
+            }
+            None => {
+                let portsc = PORTSC_PP | PLS_RX_DETECT << PORTSC_PLS_SHIFT;
+                if p.portsc & PORTSC_CCS != 0 {
+                    portsc | PORTSC_CSC
+                } else {
+                    portsc
+                }
+            }
+        };
+    }
+
+    fn update_interrupt(&self) {
+        if self.iman & (IMAN_IP | IMAN_IE) == (IMAN_IP | IMAN_IE) && self.usbcmd & USBCMD_INTE != 0
+        {
+            if let Err(e) = self.interrupt.trigger(0) {
+                error!("Failed to trigger xHCI interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn read_u64(&self, addr: u64) -> Option<u64> {
+        self.mem
+            .memory()
+            .read_obj(GuestAddress(addr))
+            .map_err(|e| warn!("Failed to read xHCI data at 0x{:x}: {:?}", addr, e))
+            .ok()
+    }
+
+    fn read_trb(&self, addr: u64) -> Option<Trb> {
+        let mut bytes = [0u8; 16];
+        self.mem
+            .memory()
+            .read_slice(&mut bytes, GuestAddress(addr))
+            .map_err(|e| warn!("Failed to read xHCI TRB at 0x{:x}: {:?}", addr, e))
+            .ok()?;
+        Some(Trb::from_bytes(&bytes))
+    }
+
+    fn read_context(&self, addr: u64) -> Option<[u32; 8]> {
+        let mut bytes = [0u8; CONTEXT_SIZE as usize];
+        self.mem
+            .memory()
+            .read_slice(&mut bytes, GuestAddress(addr))
+            .map_err(|e| warn!("Failed to read xHCI context at 0x{:x}: {:?}", addr, e))
+            .ok()?;
+        let mut context = [0u32; 8];
+        for (dword, bytes) in context.iter_mut().zip(bytes.chunks_exact(4)) {
+            *dword = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Some(context)
+    }
+
+    fn write_context(&self, addr: u64, context: &[u32; 8]) {
+        let bytes: Vec<u8> = context
+            .iter()
+            .flat_map(|dword| dword.to_le_bytes())
+            .collect();
+        if let Err(e) = self.mem.memory().write_slice(&bytes, GuestAddress(addr)) {
+            warn!("Failed to write xHCI context at 0x{:x}: {:?}", addr, e);
+        }
+    }
+
+    fn post_event(&mut self, mut trb: Trb) {
+        let Some(addr) = self.event_ring.enqueue_address() else {
+            warn!("xHCI event ring not set up, dropping event");
+            return;
+        };
+
+        let mut next = self.event_ring.clone();
+        next.advance();
+        if next.enqueue_address() == Some(self.erdp & !0xf) {
+            warn!("xHCI event ring full, dropping event");
+            return;
+        }
+
+        if self.event_ring.cycle {
+            trb.control |= TRB_C;
+        } else {
+            trb.control &= !TRB_C;
+        }
+        let mut bytes = [0u8; 12];
+        bytes[..8].copy_from_slice(&trb.parameter.to_le_bytes());
+        bytes[8..].copy_from_slice(&trb.status.to_le_bytes());
+        let mem = self.mem.memory();
+        if let Err(e) = mem.write_slice(&bytes, GuestAddress(addr)) {
+            warn!("Failed to write xHCI event at 0x{:x}: {:?}", addr, e);
+            return;
+        }
+        // The cycle bit hands the TRB over to the guest, hence comes last.
+        fence(Ordering::Release);
+        if let Err(e) = mem.write_obj(trb.control, GuestAddress(addr + 12)) {
+            warn!("Failed to write xHCI event at 0x{:x}: {:?}", addr, e);
+            return;
+        }
+        self.event_ring = next;
+
+        self.iman |= IMAN_IP;
+        self.erdp |= ERDP_EHB;
+        self.usbsts |= USBSTS_EINT;
+        self.update_interrupt();
+    }
+
+    fn post_command_completion(&mut self, addr: u64, code: u8, slot_id: u8) {
+        self.post_event(Trb {
+            parameter: addr,
+            status: u32::from(code) << 24,
+            control: u32::from(TRB_COMMAND_COMPLETION) << TRB_TYPE_SHIFT | u32::from(slot_id) << 24,
+        });
+    }
+
+    fn post_transfer_event(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        parameter: u64,
+        code: u8,
+        length: u32,
+        event_data: bool,
+    ) {
+        let mut control = u32::from(TRB_TRANSFER_EVENT) << TRB_TYPE_SHIFT
+            | u32::from(dci) << 16
+            | u32::from(slot_id) << 24;
+        if event_data {
+            control |= TRB_ED;
+        }
+        self.post_event(Trb {
+            parameter,
+            status: u32::from(code) << 24 | (length & 0xff_ffff),
+            control,
+        });
+    }
+
+    fn port_status_changed(&mut self, port: u8) {
+        if self.ports[port as usize].portsc & PORTSC_CHANGE_MASK == 0 {
+            return;
+        }
+
+        self.usbsts |= USBSTS_PCD;
+        if self.running() {
+            self.post_event(Trb {
+                parameter: u64::from(port + 1) << 24,
+                status: u32::from(CC_SUCCESS) << 24,
+                control: u32::from(TRB_PORT_STATUS_CHANGE) << TRB_TYPE_SHIFT,
+            });
+        }
+    }
+
+    fn init_event_ring(&mut self) {
+        let mut segments = Vec::new();
+        for i in 0..u64::from(self.erstsz.min(1 << ERST_MAX)) {
+            let entry = self.erstba + 16 * i;
+            let (Some(base), Some(size)) = (self.read_u64(entry), self.read_u64(entry + 8)) else {
+                return;
+            };
+            let size = (size & 0xffff) as u32;
+            if size == 0 {
+                warn!("Ignoring empty xHCI event ring segment");
+                continue;
+            }
+            segments.push((base & !0x3f, size));
+        }
+
+        self.event_ring = EventRing {
+            segments,
+            segment: 0,
+            index: 0,
+            cycle: true,
+        };
+    }
+
+    fn mfindex(&self) -> u32 {
+        if self.running() {
+            (self.started.elapsed().as_micros() / 125) as u32 & 0x3fff
+        } else {
+            0
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            CAPLENGTH => HCI_VERSION << 16 | CAP_LENGTH as u32,
+            HCSPARAMS1 => u32::from(NUM_PORTS) << 24 | 1 << 8 | u32::from(MAX_SLOTS),
+            HCSPARAMS2 => ERST_MAX << 4,
+            HCSPARAMS3 => 0,
+            HCCPARAMS1 => ((EXT_CAPS >> 2) as u32) << 16 | HCCPARAMS1_AC64,
+            DBOFF => DOORBELLS as u32,
+            RTSOFF => RUNTIME_REGS as u32,
+            HCCPARAMS2 => 0,
+            USBCMD => self.usbcmd,
+            USBSTS => self.usbsts,
+            // 4KiB pages
+            PAGESIZE => 1,
+            DNCTRL => self.dnctrl,
+            CRCR_LO if self.command_ring_running => CRCR_CRR,
+            DCBAAP_LO => self.dcbaap as u32,
+            DCBAAP_HI => (self.dcbaap >> 32) as u32,
+            CONFIG => self.config,
+            o if (PORT_REGS..PORT_REGS + PORT_REGS_SIZE * u64::from(NUM_PORTS)).contains(&o)
+                && (o - PORT_REGS) & (PORT_REGS_SIZE - 1) == 0 =>
+            {
+                self.ports[((o - PORT_REGS) / PORT_REGS_SIZE) as usize].portsc
+            }
+            o if (EXT_CAPS..EXT_CAPS + 4 * SUPPORTED_PROTOCOLS.len() as u64).contains(&o) => {
+                SUPPORTED_PROTOCOLS[((o - EXT_CAPS) >> 2) as usize]
+            }
+            MFINDEX => self.mfindex(),
+            IMAN => self.iman,
+            IMOD => self.imod,
+            ERSTSZ => self.erstsz,
+            ERSTBA_LO => self.erstba as u32,
+            ERSTBA_HI => (self.erstba >> 32) as u32,
+            ERDP_LO => self.erdp as u32,
+            ERDP_HI => (self.erdp >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    // Returns true if the worker thread has something to do.
+    fn write_reg(&mut self, offset: u64, value: u32) -> bool {
+        match offset {
+            USBCMD => return self.write_usbcmd(value),
+            USBSTS => self.usbsts &= !(value & USBSTS_RW1C),
+            DNCTRL => self.dnctrl = value & 0xffff,
+            CRCR_LO => self.write_crcr(value),
+            CRCR_HI => {
+                if !self.command_ring_running {
+                    self.command_ring.dequeue =
+                        u64::from(value) << 32 | (self.command_ring.dequeue & 0xffff_ffff);
+                }
+            }
+            DCBAAP_LO => self.dcbaap = (self.dcbaap & !0xffff_ffff) | u64::from(value & !0x3f),
+            DCBAAP_HI => self.dcbaap = u64::from(value) << 32 | (self.dcbaap & 0xffff_ffff),
+            CONFIG => self.config = value & 0xff,
+            o if (PORT_REGS..PORT_REGS + PORT_REGS_SIZE * u64::from(NUM_PORTS)).contains(&o)
+                && (o - PORT_REGS) & (PORT_REGS_SIZE - 1) == 0 =>
+            {
+                return self.write_portsc(((o - PORT_REGS) / PORT_REGS_SIZE) as u8, value);
+            }
+            IMAN => {
+                self.iman = (self.iman & !IMAN_IE) | (value & IMAN_IE);
+                if value & IMAN_IP != 0 {
+                    self.iman &= !IMAN_IP;
+                }
+                self.update_interrupt();
+            }
+            IMOD => self.imod = value,
+            ERSTSZ => self.erstsz = value & 0xffff,
+            ERSTBA_LO => self.erstba = (self.erstba & !0xffff_ffff) | u64::from(value & !0x3f),
+            // The event ring is set up once the 64 bits address is written,
+            // the low half coming first.
+            ERSTBA_HI => {
+                self.erstba = u64::from(value) << 32 | (self.erstba & 0xffff_ffff);
+                self.init_event_ring();
+            }
+            ERDP_LO => {
+                let mut erdp =
+                    (self.erdp & !0xffff_ffff) | u64::from(value & !0xf) | (value & 0x7) as u64;
+                if u64::from(value) & ERDP_EHB == 0 {
+                    erdp |= self.erdp & ERDP_EHB;
+                }
+                self.erdp = erdp;
+            }
+            ERDP_HI => {
+                self.erdp = u64::from(value) << 32 | (self.erdp & 0xffff_ffff);
+                // Events posted after the guest stopped looking at the ring
+                // must be signalled again.
+                if self.event_ring.enqueue_address() != Some(self.erdp & !0xf) {
+                    self.iman |= IMAN_IP;
+                    self.update_interrupt();
+                }
+            }
+            o if (DOORBELLS..=DOORBELLS + 4 * u64::from(MAX_SLOTS)).contains(&o) && o % 4 == 0 => {
+                return self.ring_doorbell(((o - DOORBELLS) >> 2) as u8, value);
+            }
+            _ => debug!("Ignoring xHCI register write at 0x{:x}", offset),
+        }
+
+        false
+    }
+
+    fn write_usbcmd(&mut self, value: u32) -> bool {
+        if value & USBCMD_HCRST != 0 {
+            // Reset by the worker thread, which owns the devices. The guest
+            // waits for the bit to clear.
+            self.usbcmd |= USBCMD_HCRST;
+            return true;
+        }
+
+        let was_running = self.running();
+        self.usbcmd = value & !(USBCMD_HCRST | USBCMD_LHCRST);
+        if self.running() && !was_running {
+            self.usbsts &= !USBSTS_HCH;
+            self.started = Instant::now();
+        } else if !self.running() && was_running {
+            self.usbsts |= USBSTS_HCH;
+            self.command_ring_running = false;
+        }
+        self.update_interrupt();
+
+        false
+    }
+
+    fn write_crcr(&mut self, value: u32) {
+        if !self.command_ring_running {
+            self.command_ring = Ring {
+                dequeue: (self.command_ring.dequeue & !0xffff_ffff) | u64::from(value & !0x3f),
+                cycle: value & CRCR_RCS != 0,
+            };
+        } else if value & (CRCR_CS | CRCR_CA) != 0 {
+            // Commands are handled as a whole, so there is never one to
+            // abort.
+            self.command_ring_running = false;
+            self.post_command_completion(self.command_ring.dequeue, CC_COMMAND_RING_STOPPED, 0);
+        }
+    }
+
+    fn write_portsc(&mut self, port: u8, value: u32) -> bool {
+        let usb3 = Self::is_usb3_port(port);
+        let p = &mut self.ports[port as usize];
+        let mut portsc = p.portsc & !(value & PORTSC_CHANGE_MASK);
+        if value & PORTSC_PED != 0 {
+            portsc &= !PORTSC_PED;
+        }
+        portsc = (portsc & !(PORTSC_WAKE_MASK | PORTSC_PIC_MASK))
+            | (value & (PORTSC_WAKE_MASK | PORTSC_PIC_MASK));
+
+        if value & PORTSC_LWS != 0 && portsc & PORTSC_PED != 0 {
+            let current = (portsc & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT;
+            match (value & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT {
+                PLS_U0 if current == PLS_U3 || current == PLS_RESUME => {
+                    portsc = (portsc & !PORTSC_PLS_MASK) | PLS_U0 << PORTSC_PLS_SHIFT;
+                    portsc |= PORTSC_PLC;
+                }
+                PLS_U3 => portsc = (portsc & !PORTSC_PLS_MASK) | PLS_U3 << PORTSC_PLS_SHIFT,
+                pls => debug!("Ignoring xHCI port {} link state {}", port + 1, pls),
+            }
+        }
+
+        let mut kick = false;
+        if (value & PORTSC_PR != 0 || (usb3 && value & PORTSC_WPR != 0)) && portsc & PORTSC_CCS != 0
+        {
+            portsc |= PORTSC_PR;
+            portsc &= !PORTSC_PED;
+            p.reset = true;
+            p.warm_reset = usb3 && value & PORTSC_WPR != 0;
+            kick = true;
+        }
+
+        let changed = portsc & !p.portsc & PORTSC_CHANGE_MASK != 0;
+        p.portsc = portsc;
+        if changed {
+            self.port_status_changed(port);
+        }
+
+        kick
+    }
+
+    fn ring_doorbell(&mut self, target: u8, value: u32) -> bool {
+        if !self.running() {
+            return false;
+        }
+
+        if target == 0 {
+            if value & 0xff != 0 {
+                return false;
+            }
+            self.command_ring_running = true;
+            return true;
+        }
+
+        let dci = value & 0xff;
+        match self.slots.get_mut(target as usize - 1) {
+            Some(slot) if slot.enabled && (1..=MAX_ENDPOINTS as u32).contains(&dci) => {
+                slot.doorbells |= 1 << dci;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn device(&mut self, port: u8) -> Option<&mut (dyn UsbDevice + 'static)> {
+        self.ports
+            .get_mut(port.checked_sub(1)? as usize)?
+            .device
+            .as_deref_mut()
+    }
+
+    fn endpoint(&mut self, slot_id: u8, dci: u8) -> &mut Endpoint {
+        &mut self.slots[slot_id as usize - 1].endpoints[dci as usize - 1]
+    }
+
+    fn valid_slot(&self, slot_id: u8) -> bool {
+        slot_id > 0
+            && self
+                .slots
+                .get(slot_id as usize - 1)
+                .is_some_and(|slot| slot.enabled)
+    }
+
+    // Writes the state and dequeue pointer of an endpoint back to its
+    // context.
+    fn write_endpoint_context(&mut self, slot_id: u8, dci: u8) {
+        let base = self.slots[slot_id as usize - 1].context;
+        if base == 0 {
+            return;
+        }
+
+        let addr = base + CONTEXT_SIZE * u64::from(dci);
+        let Some(mut context) = self.read_context(addr) else {
+            return;
+        };
+        let ep = self.endpoint(slot_id, dci);
+        context[0] = (context[0] & !0x7) | u32::from(ep.state);
+        context[2] = (ep.ring.dequeue as u32 & !0xf) | u32::from(ep.ring.cycle);
+        context[3] = (ep.ring.dequeue >> 32) as u32;
+        self.write_context(addr, &context);
+    }
+
+    fn write_slot_state(&mut self, slot_id: u8, state: u32, address: u8, entries: Option<u32>) {
+        let slot = &mut self.slots[slot_id as usize - 1];
+        slot.state = state;
+        let addr = slot.context;
+        if addr == 0 {
+            return;
+        }
+
+        let Some(mut context) = self.read_context(addr) else {
+            return;
+        };
+        if let Some(entries) = entries {
+            context[0] = (context[0] & !(0x1f << 27)) | entries << 27;
+        }
+        context[3] = state << 27 | u32::from(address);
+        self.write_context(addr, &context);
+    }
+
+    // Forgets the TD in flight on an endpoint, cancelling its transfer.
+    fn cancel_td(&mut self, slot_id: u8, dci: u8) {
+        if let Some(td) = self.endpoint(slot_id, dci).td.take() {
+            let port = self.slots[slot_id as usize - 1].port;
+            if let Some(device) = self.device(port) {
+                device.cancel(td.id);
+            }
+        }
+    }
+
+    fn disable_endpoint(&mut self, slot_id: u8, dci: u8) {
+        self.cancel_td(slot_id, dci);
+        if self.endpoint(slot_id, dci).state != EP_DISABLED {
+            *self.endpoint(slot_id, dci) = Endpoint::default();
+            self.write_endpoint_context(slot_id, dci);
+        }
+    }
+
+    fn disable_slot(&mut self, slot_id: u8) {
+        if !self.valid_slot(slot_id) {
+            return;
+        }
+
+        for dci in 1..=MAX_ENDPOINTS as u8 {
+            self.cancel_td(slot_id, dci);
+        }
+        self.slots[slot_id as usize - 1] = Slot::new();
+    }
+
+    fn enable_endpoint(&mut self, slot_id: u8, dci: u8, context: &mut [u32; 8]) {
+        self.cancel_td(slot_id, dci);
+        context[0] = (context[0] & !0x7) | u32::from(EP_RUNNING);
+        let dequeue = u64::from(context[3]) << 32 | u64::from(context[2]);
+        *self.endpoint(slot_id, dci) = Endpoint {
+            state: EP_RUNNING,
+            ep_type: ((context[1] >> 3) & 0x7) as u8,
+            ring: Ring {
+                dequeue: dequeue & !0xf,
+                cycle: dequeue & 1 != 0,
+            },
+            td: None,
+        };
+    }
+
+    fn process_commands(&mut self) {
+        if !self.running() || !self.command_ring_running {
+            return;
+        }
+
+        for _ in 0..MAX_TRBS {
+            let addr = self.command_ring.dequeue;
+            let Some(trb) = self.read_trb(addr) else {
+                self.command_ring_running = false;
+                self.usbsts |= USBSTS_HSE;
+                return;
+            };
+            if trb.cycle() != self.command_ring.cycle {
+                return;
+            }
+
+            if trb.trb_type() == TRB_LINK {
+                self.command_ring.dequeue = trb.parameter & !0xf;
+                if trb.control & TRB_TC != 0 {
+                    self.command_ring.cycle = !self.command_ring.cycle;
+                }
+                continue;
+            }
+
+            let (code, slot_id) = self.execute_command(&trb);
+            self.command_ring.dequeue += 16;
+            self.post_command_completion(addr, code, slot_id);
+        }
+    }
+
+    fn execute_command(&mut self, trb: &Trb) -> (u8, u8) {
+        let slot_id = trb.slot_id();
+        let trb_type = trb.trb_type();
+
+        match trb_type {
+            TRB_ENABLE_SLOT => {
+                let max_slots = match self.config {
+                    0 => MAX_SLOTS,
+                    n => (n as u8).min(MAX_SLOTS),
+                };
+                match self.slots[..max_slots as usize]
+                    .iter()
+                    .position(|slot| !slot.enabled)
+                {
+                    Some(index) => {
+                        let slot = &mut self.slots[index];
+                        *slot = Slot::new();
+                        slot.enabled = true;
+                        (CC_SUCCESS, index as u8 + 1)
+                    }
+                    None => (CC_NO_SLOTS_AVAILABLE, 0),
+                }
+            }
+            TRB_NOOP_COMMAND => (CC_SUCCESS, 0),
+            _ if !self.valid_slot(slot_id) => (CC_SLOT_NOT_ENABLED, slot_id),
+            TRB_DISABLE_SLOT => {
+                self.disable_slot(slot_id);
+                (CC_SUCCESS, slot_id)
+            }
+            TRB_ADDRESS_DEVICE => (
+                self.address_device(slot_id, trb.parameter & !0xf, trb.control & TRB_BSR != 0),
+                slot_id,
+            ),
+            TRB_CONFIGURE_ENDPOINT => (
+                self.configure_endpoint(slot_id, trb.parameter & !0xf, trb.control & TRB_DC != 0),
+                slot_id,
+            ),
+            TRB_EVALUATE_CONTEXT => (
+                self.evaluate_context(slot_id, trb.parameter & !0xf),
+                slot_id,
+            ),
+            TRB_RESET_DEVICE => (self.reset_device(slot_id), slot_id),
+            TRB_RESET_ENDPOINT | TRB_STOP_ENDPOINT | TRB_SET_TR_DEQUEUE => {
+                let dci = trb.endpoint_id();
+                if dci == 0 || self.endpoint(slot_id, dci).state == EP_DISABLED {
+                    return (CC_CONTEXT_STATE_ERROR, slot_id);
+                }
+                let code = match trb_type {
+                    TRB_RESET_ENDPOINT => self.reset_endpoint(slot_id, dci),
+                    TRB_STOP_ENDPOINT => self.stop_endpoint(slot_id, dci),
+                    _ => self.set_tr_dequeue(slot_id, dci, trb.parameter),
+                };
+                (code, slot_id)
+            }
+            _ => {
+                debug!("Unsupported xHCI command {}", trb_type);
+                (CC_TRB_ERROR, slot_id)
+            }
+        }
+    }
+
+    fn address_device(&mut self, slot_id: u8, input: u64, block_set_address: bool) -> u8 {
+        let (Some(control), Some(mut slot_context), Some(mut ep0_context)) = (
+            self.read_context(input),
+            self.read_context(input + CONTEXT_SIZE),
+            self.read_context(input + 2 * CONTEXT_SIZE),
+        ) else {
+            return CC_PARAMETER_ERROR;
+        };
+        if control[1] & 0x3 != 0x3 {
+            return CC_PARAMETER_ERROR;
+        }
+
+        let port = ((slot_context[1] >> 16) & 0xff) as u8;
+        if self.device(port).is_none() {
+            return CC_USB_TRANSACTION_ERROR;
+        }
+
+        let Some(output) = self.read_u64(self.dcbaap + 8 * u64::from(slot_id)) else {
+            return CC_PARAMETER_ERROR;
+        };
+        let output = output & !0x3f;
+        if output == 0 {
+            return CC_PARAMETER_ERROR;
+        }
+
+        let (state, address) = if block_set_address {
+            (SLOT_DEFAULT, 0)
+        } else {
+            (SLOT_ADDRESSED, slot_id)
+        };
+        slot_context[3] = state << 27 | u32::from(address);
+        self.enable_endpoint(slot_id, 1, &mut ep0_context);
+        self.write_context(output, &slot_context);
+        self.write_context(output + CONTEXT_SIZE, &ep0_context);
+
+        let slot = &mut self.slots[slot_id as usize - 1];
+        slot.state = state;
+        slot.port = port;
+        slot.context = output;
+
+        CC_SUCCESS
+    }
+
+    fn configure_endpoint(&mut self, slot_id: u8, input: u64, deconfigure: bool) -> u8 {
+        let state = self.slots[slot_id as usize - 1].state;
+        if state != SLOT_ADDRESSED && state != SLOT_CONFIGURED {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+
+        if deconfigure {
+            for dci in 2..=MAX_ENDPOINTS as u8 {
+                self.disable_endpoint(slot_id, dci);
+            }
+            self.write_slot_state(slot_id, SLOT_ADDRESSED, slot_id, Some(1));
+            return CC_SUCCESS;
+        }
+
+        let Some(control) = self.read_context(input) else {
+            return CC_PARAMETER_ERROR;
+        };
+        let (drop_flags, add_flags) = (control[0], control[1]);
+
+        let mut contexts = Vec::new();
+        for dci in 2..=MAX_ENDPOINTS as u8 {
+            if add_flags & 1 << dci == 0 {
+                continue;
+            }
+            let Some(context) = self.read_context(input + CONTEXT_SIZE * (u64::from(dci) + 1))
+            else {
+                return CC_PARAMETER_ERROR;
+            };
+            let ep_type = ((context[1] >> 3) & 0x7) as u8;
+            if ep_type == EP_ISOCH_OUT || ep_type == EP_ISOCH_IN {
+                warn!("Isochronous USB endpoints aren't supported");
+                return CC_BANDWIDTH_ERROR;
+            }
+            contexts.push((dci, context));
+        }
+
+        for dci in 2..=MAX_ENDPOINTS as u8 {
+            if drop_flags & 1 << dci != 0 {
+                self.disable_endpoint(slot_id, dci);
+            }
+        }
+
+        let output = self.slots[slot_id as usize - 1].context;
+        for (dci, mut context) in contexts {
+            self.enable_endpoint(slot_id, dci, &mut context);
+            self.write_context(output + CONTEXT_SIZE * u64::from(dci), &context);
+        }
+
+        let entries = if add_flags & 1 != 0 {
+            self.read_context(input + CONTEXT_SIZE)
+                .map(|context| context[0] >> 27)
+        } else {
+            None
+        };
+        let configured = self.slots[slot_id as usize - 1].endpoints[1..]
+            .iter()
+            .any(|ep| ep.state != EP_DISABLED);
+        let state = if configured {
+            SLOT_CONFIGURED
+        } else {
+            SLOT_ADDRESSED
+        };
+        self.write_slot_state(slot_id, state, slot_id, entries);
+
+        CC_SUCCESS
+    }
+
+    fn evaluate_context(&mut self, slot_id: u8, input: u64) -> u8 {
+        let output = self.slots[slot_id as usize - 1].context;
+        if output == 0 {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+        let Some(control) = self.read_context(input) else {
+            return CC_PARAMETER_ERROR;
+        };
+
+        if control[1] & 1 != 0 {
+            if let (Some(new), Some(mut context)) = (
+                self.read_context(input + CONTEXT_SIZE),
+                self.read_context(output),
+            ) {
+                // Max exit latency and interrupter target.
+                context[1] = (context[1] & !0xffff) | (new[1] & 0xffff);
+                context[2] = (context[2] & !(0x3ff << 22)) | (new[2] & (0x3ff << 22));
+                self.write_context(output, &context);
+            }
+        }
+        if control[1] & 2 != 0 {
+            if let (Some(new), Some(mut context)) = (
+                self.read_context(input + 2 * CONTEXT_SIZE),
+                self.read_context(output + CONTEXT_SIZE),
+            ) {
+                // Max packet size.
+                context[1] = (context[1] & 0xffff) | (new[1] & 0xffff_0000);
+                self.write_context(output + CONTEXT_SIZE, &context);
+            }
+        }
+
+        CC_SUCCESS
+    }
+
+    fn reset_device(&mut self, slot_id: u8) -> u8 {
+        let state = self.slots[slot_id as usize - 1].state;
+        if state != SLOT_DEFAULT && state != SLOT_ADDRESSED && state != SLOT_CONFIGURED {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+
+        self.cancel_td(slot_id, 1);
+        for dci in 2..=MAX_ENDPOINTS as u8 {
+            self.disable_endpoint(slot_id, dci);
+        }
+        self.write_slot_state(slot_id, SLOT_DEFAULT, 0, Some(1));
+
+        CC_SUCCESS
+    }
+
+    fn reset_endpoint(&mut self, slot_id: u8, dci: u8) -> u8 {
+        if self.endpoint(slot_id, dci).state != EP_HALTED {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+
+        self.endpoint(slot_id, dci).state = EP_STOPPED;
+        self.write_endpoint_context(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    fn stop_endpoint(&mut self, slot_id: u8, dci: u8) -> u8 {
+        if self.endpoint(slot_id, dci).state != EP_RUNNING {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+
+        // The dequeue pointer still points at the start of the cancelled
+        // TD, if any.
+        self.cancel_td(slot_id, dci);
+        self.endpoint(slot_id, dci).state = EP_STOPPED;
+        self.write_endpoint_context(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    fn set_tr_dequeue(&mut self, slot_id: u8, dci: u8, parameter: u64) -> u8 {
+        let ep = self.endpoint(slot_id, dci);
+        if ep.state != EP_STOPPED && ep.state != EP_ERROR {
+            return CC_CONTEXT_STATE_ERROR;
+        }
+
+        ep.ring = Ring {
+            dequeue: parameter & !0xf,
+            cycle: parameter & 1 != 0,
+        };
+        self.write_endpoint_context(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    // Gathers the TRBs of the next TD, returning None if the guest hasn't
+    // queued a complete one yet, or the address of the faulty TRB.
+    fn fetch_td(&self, mut ring: Ring, control: bool) -> Result<Option<FetchedTd>, u64> {
+        let mut trbs = Vec::new();
+
+        for _ in 0..MAX_TRBS {
+            let addr = ring.dequeue;
+            let trb = self.read_trb(addr).ok_or(addr)?;
+            if trb.cycle() != ring.cycle {
+                return Ok(None);
+            }
+
+            if trb.trb_type() == TRB_LINK {
+                ring.dequeue = trb.parameter & !0xf;
+                if trb.control & TRB_TC != 0 {
+                    ring.cycle = !ring.cycle;
+                }
+                continue;
+            }
+
+            ring.dequeue += 16;
+            trbs.push((addr, trb));
+            // Control TDs span from the setup stage to the status stage,
+            // other TDs end with the first TRB not chained to the next one.
+            let done = if control && trbs[0].1.trb_type() == TRB_SETUP {
+                trb.trb_type() == TRB_STATUS
+            } else {
+                trb.control & TRB_CH == 0
+            };
+            if done {
+                return Ok(Some((trbs, ring)));
+            }
+        }
+
+        Err(ring.dequeue)
+    }
+
+    // Builds the transfer described by a TD, returning None if there is
+    // nothing to transfer, or the completion code to report.
+    fn build_transfer(
+        &mut self,
+        ep_type: u8,
+        dci: u8,
+        trbs: &[(u64, Trb)],
+    ) -> Result<Option<UsbTransfer>, u8> {
+        let mut setup = None;
+        let mut is_in = ep_type == EP_BULK_IN || ep_type == EP_INTERRUPT_IN;
+        let mut data = Vec::new();
+        let mut length = 0;
+        let mut has_data = false;
+
+        for (_, trb) in trbs {
+            match trb.trb_type() {
+                TRB_SETUP if ep_type == EP_CONTROL => {
+                    if trb.control & TRB_IDT == 0 || trb.transfer_length() != 8 {
+                        return Err(CC_TRB_ERROR);
+                    }
+                    let packet = trb.parameter.to_le_bytes();
+                    is_in = packet[0] & 0x80 != 0;
+                    setup = Some(packet);
+                }
+                TRB_NORMAL | TRB_DATA => {
+                    let len = trb.transfer_length();
+                    length += len;
+                    if length > MAX_TD_SIZE {
+                        warn!("xHCI transfer descriptor larger than {} bytes", MAX_TD_SIZE);
+                        return Err(CC_TRB_ERROR);
+                    }
+                    has_data = true;
+                    if is_in {
+                        continue;
+                    }
+
+                    if trb.control & TRB_IDT != 0 {
+                        if len > 8 {
+                            return Err(CC_TRB_ERROR);
+                        }
+                        data.extend_from_slice(&trb.parameter.to_le_bytes()[..len]);
+                    } else {
+                        let start = data.len();
+                        data.resize(start + len, 0);
+                        self.mem
+                            .memory()
+                            .read_slice(&mut data[start..], GuestAddress(trb.parameter))
+                            .map_err(|e| {
+                                warn!("Failed to read USB data at 0x{:x}: {:?}", trb.parameter, e);
+                                CC_DATA_BUFFER_ERROR
+                            })?;
+                    }
+                }
+                TRB_STATUS | TRB_EVENT_DATA | TRB_NOOP => {}
+                trb_type => {
+                    debug!("Unsupported xHCI transfer TRB {}", trb_type);
+                    return Err(CC_TRB_ERROR);
+                }
+            }
+        }
+
+        if setup.is_none() && !has_data {
+            return Ok(None);
+        }
+        if ep_type == EP_CONTROL && setup.is_none() {
+            return Err(CC_TRB_ERROR);
+        }
+        if is_in {
+            data = vec![0; length];
+        }
+
+        let transfer_type = match ep_type {
+            EP_CONTROL => UsbTransferType::Control,
+            EP_BULK_OUT | EP_BULK_IN => UsbTransferType::Bulk,
+            EP_INTERRUPT_OUT | EP_INTERRUPT_IN => UsbTransferType::Interrupt,
+            _ => return Err(CC_TRB_ERROR),
+        };
+        let endpoint = if ep_type == EP_CONTROL {
+            0
+        } else {
+            (dci >> 1) | if dci & 1 != 0 { 0x80 } else { 0 }
+        };
+        self.next_transfer_id += 1;
+
+        Ok(Some(UsbTransfer {
+            id: self.next_transfer_id,
+            transfer_type,
+            endpoint,
+            setup,
+            data,
+        }))
+    }
+
+    fn halt_endpoint(&mut self, slot_id: u8, dci: u8) {
+        self.endpoint(slot_id, dci).state = EP_HALTED;
+        self.write_endpoint_context(slot_id, dci);
+    }
+
+    // Submits the TDs queued on an endpoint, one at a time.
+    fn kick_endpoint(&mut self, slot_id: u8, dci: u8) {
+        for _ in 0..MAX_TRBS {
+            let ep = self.endpoint(slot_id, dci);
+            if ep.state != EP_RUNNING || ep.td.is_some() {
+                return;
+            }
+            let (ring, ep_type) = (ep.ring, ep.ep_type);
+
+            let (trbs, next) = match self.fetch_td(ring, ep_type == EP_CONTROL) {
+                Ok(Some(td)) => td,
+                Ok(None) => return,
+                Err(addr) => {
+                    self.post_transfer_event(slot_id, dci, addr, CC_TRB_ERROR, 0, false);
+                    self.halt_endpoint(slot_id, dci);
+                    return;
+                }
+            };
+
+            let transfer = match self.build_transfer(ep_type, dci, &trbs) {
+                Ok(transfer) => transfer,
+                Err(code) => {
+                    self.post_transfer_event(slot_id, dci, trbs[0].0, code, 0, false);
+                    self.halt_endpoint(slot_id, dci);
+                    return;
+                }
+            };
+
+            let td = Td {
+                id: transfer.as_ref().map(|t| t.id).unwrap_or_default(),
+                trbs,
+                next,
+            };
+            let Some(transfer) = transfer else {
+                self.complete_td(slot_id, dci, td, UsbStatus::Success, &[], 0);
+                continue;
+            };
+
+            let port = self.slots[slot_id as usize - 1].port;
+            let result = match self.device(port) {
+                Some(device) => device.submit(transfer),
+                None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+            };
+            match result {
+                Ok(()) => {
+                    self.endpoint(slot_id, dci).td = Some(td);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to submit USB transfer on port {}: {:?}", port, e);
+                    self.complete_td(slot_id, dci, td, UsbStatus::Error, &[], 0);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Reports the completion of a TD to the guest, scattering the data
+    // received from the device into its buffers.
+    fn complete_td(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        td: Td,
+        status: UsbStatus,
+        data: &[u8],
+        actual_length: usize,
+    ) {
+        let code = match status {
+            UsbStatus::Success => CC_SUCCESS,
+            UsbStatus::Stall => CC_STALL_ERROR,
+            UsbStatus::Babble => CC_BABBLE_DETECTED,
+            UsbStatus::Error | UsbStatus::Disconnected => CC_USB_TRANSACTION_ERROR,
+        };
+        if code != CC_SUCCESS {
+            let (addr, trb) = td
+                .trbs
+                .iter()
+                .find(|(_, trb)| matches!(trb.trb_type(), TRB_NORMAL | TRB_DATA))
+                .unwrap_or(&td.trbs[0]);
+            let length = trb.transfer_length() as u32;
+            self.post_transfer_event(slot_id, dci, *addr, code, length, false);
+            self.halt_endpoint(slot_id, dci);
+            return;
+        }
+
+        let mut remaining = actual_length;
+        let mut offset = 0;
+        // Event data transfer length accumulator.
+        let mut edtla = 0u32;
+        let mut short = false;
+        let mut short_reported = false;
+
+        for (addr, trb) in td.trbs.iter() {
+            match trb.trb_type() {
+                TRB_NORMAL | TRB_DATA => {
+                    if short {
+                        continue;
+                    }
+                    let len = trb.transfer_length();
+                    let done = len.min(remaining);
+                    if done > 0 && trb.control & TRB_IDT == 0 && offset + done <= data.len() {
+                        if let Err(e) = self
+                            .mem
+                            .memory()
+                            .write_slice(&data[offset..offset + done], GuestAddress(trb.parameter))
+                        {
+                            warn!("Failed to write USB data at 0x{:x}: {:?}", trb.parameter, e);
+                        }
+                    }
+                    offset += done;
+                    remaining -= done;
+                    edtla += done as u32;
+
+                    if done < len {
+                        short = true;
+                        if trb.control & (TRB_ISP | TRB_IOC) != 0 {
+                            short_reported = true;
+                            let residue = (len - done) as u32;
+                            self.post_transfer_event(
+                                slot_id,
+                                dci,
+                                *addr,
+                                CC_SHORT_PACKET,
+                                residue,
+                                false,
+                            );
+                        }
+                    } else if trb.control & TRB_IOC != 0 {
+                        self.post_transfer_event(slot_id, dci, *addr, CC_SUCCESS, 0, false);
+                    }
+                }
+                TRB_EVENT_DATA => {
+                    if trb.control & TRB_IOC != 0 && !short_reported {
+                        let code = if short { CC_SHORT_PACKET } else { CC_SUCCESS };
+                        short_reported = short;
+                        self.post_transfer_event(slot_id, dci, trb.parameter, code, edtla, true);
+                    }
+                    edtla = 0;
+                }
+                // The status stage always runs, even after a short data
+                // stage.
+                TRB_SETUP | TRB_STATUS => {
+                    if trb.control & TRB_IOC != 0 {
+                        self.post_transfer_event(slot_id, dci, *addr, CC_SUCCESS, 0, false);
+                    }
+                }
+                _ => {
+                    if !short && trb.control & TRB_IOC != 0 {
+                        self.post_transfer_event(slot_id, dci, *addr, CC_SUCCESS, 0, false);
+                    }
+                }
+            }
+        }
+
+        if short && !short_reported {
+            // Neither ISP nor IOC on the short TRB, the event goes with the
+            // last TRB of the TD.
+            if let Some((addr, trb)) = td.trbs.last() {
+                if trb.control & TRB_IOC != 0 && trb.trb_type() != TRB_STATUS {
+                    self.post_transfer_event(slot_id, dci, *addr, CC_SHORT_PACKET, 0, false);
+                }
+            }
+        }
+
+        self.endpoint(slot_id, dci).ring = td.next;
+        self.write_endpoint_context(slot_id, dci);
+    }
+
+    fn complete_transfer(&mut self, port: u8, completion: UsbCompletion) {
+        let found = self.slots.iter().enumerate().find_map(|(index, slot)| {
+            if !slot.enabled || slot.port != port + 1 {
+                return None;
+            }
+            slot.endpoints
+                .iter()
+                .position(|ep| ep.td.as_ref().map(|td| td.id) == Some(completion.id))
+                .map(|ep_index| (index as u8 + 1, ep_index as u8 + 1))
+        });
+        let Some((slot_id, dci)) = found else {
+            debug!("Dropping stale USB completion {}", completion.id);
+            return;
+        };
+
+        if completion.status == UsbStatus::Disconnected {
+            self.device_gone(port);
+        }
+
+        let td = self.endpoint(slot_id, dci).td.take().unwrap();
+        self.complete_td(
+            slot_id,
+            dci,
+            td,
+            completion.status,
+            &completion.data,
+            completion.actual_length,
+        );
+        self.kick_endpoint(slot_id, dci);
+    }
+
+    // Reports a device unplugged from the host as disconnected, until it
+    // gets detached.
+    fn device_gone(&mut self, port: u8) {
+        let p = &mut self.ports[port as usize];
+        if p.portsc & PORTSC_CCS == 0 {
+            return;
+        }
+
+        p.portsc = PORTSC_PP | PORTSC_CSC | PLS_RX_DETECT << PORTSC_PLS_SHIFT;
+        self.port_status_changed(port);
+    }
+
+    fn reset_port(&mut self, port: u8) {
+        let p = &mut self.ports[port as usize];
+        p.reset = false;
+        let warm = std::mem::take(&mut p.warm_reset);
+        if let Some(device) = p.device.as_mut() {
+            if let Err(e) = device.reset() {
+                warn!("Failed to reset USB device on port {}: {:?}", port + 1, e);
+            }
+        }
+
+        // The transfers in flight got dropped by the reset.
+        for slot in self.slots.iter_mut() {
+            if slot.enabled && slot.port == port + 1 {
+                for ep in slot.endpoints.iter_mut() {
+                    ep.td = None;
+                }
+            }
+        }
+
+        let p = &mut self.ports[port as usize];
+        if p.portsc & PORTSC_CCS != 0 {
+            p.portsc = (p.portsc & !(PORTSC_PR | PORTSC_PLS_MASK))
+                | PORTSC_PED
+                | PORTSC_PRC
+                | PLS_U0 << PORTSC_PLS_SHIFT;
+            if warm {
+                p.portsc |= PORTSC_WRC;
+            }
+        } else {
+            p.portsc &= !PORTSC_PR;
+        }
+        self.port_status_changed(port);
+    }
+
+    // Runs the work queued by the guest and the devices. Returns true if
+    // some was left for later.
+    fn process(&mut self) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        if self.usbcmd & USBCMD_HCRST != 0 {
+            self.reset();
+        }
+
+        for port in 0..NUM_PORTS {
+            if self.ports[port as usize].reset {
+                self.reset_port(port);
+            }
+        }
+
+        self.process_commands();
+
+        for slot_id in 1..=MAX_SLOTS {
+            let doorbells = std::mem::take(&mut self.slots[slot_id as usize - 1].doorbells);
+            for dci in 1..=MAX_ENDPOINTS as u8 {
+                if doorbells & 1 << dci == 0 {
+                    continue;
+                }
+                // Ringing the doorbell restarts a stopped endpoint.
+                if self.endpoint(slot_id, dci).state == EP_STOPPED {
+                    self.endpoint(slot_id, dci).state = EP_RUNNING;
+                    self.write_endpoint_context(slot_id, dci);
+                }
+                self.kick_endpoint(slot_id, dci);
+            }
+        }
+
+        for _ in 0..MAX_COMPLETION_ROUNDS {
+            let mut completed = false;
+            for port in 0..NUM_PORTS {
+                let completions = match self.ports[port as usize].device.as_mut() {
+                    Some(device) => device.completions(),
+                    None => continue,
+                };
+                for completion in completions {
+                    completed = true;
+                    self.complete_transfer(port, completion);
+                }
+            }
+            if !completed {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // File descriptors the worker thread must poll, for each port.
+    fn completion_fds(&self) -> Vec<Option<(RawFd, u64)>> {
+        self.ports
+            .iter()
+            .map(|p| {
+                if self.paused {
+                    return None;
+                }
+                let fd = p.device.as_ref()?.completion_fd()?;
+                Some((fd, p.generation))
+            })
+            .collect()
+    }
+
+    fn attach(&mut self, device: Box<dyn UsbDevice>) -> Result<u8, XhciError> {
+        let mut ports = if device.speed() == UsbSpeed::Super {
+            USB2_PORTS..NUM_PORTS
+        } else {
+            0..USB2_PORTS
+        };
+        let port = ports
+            .find(|port| self.ports[*port as usize].device.is_none())
+            .ok_or(XhciError::NoFreePort)?;
+
+        self.generation += 1;
+        let p = &mut self.ports[port as usize];
+        p.device = Some(device);
+        p.generation = self.generation;
+        self.update_port_connection(port);
+        self.port_status_changed(port);
+
+        Ok(port + 1)
+    }
+
+    fn detach(&mut self, port: u8) -> Option<Box<dyn UsbDevice>> {
+        let index = port.checked_sub(1)?;
+        let device = self.ports.get_mut(index as usize)?.device.take()?;
+
+        // The guest cancels the TDs still in flight once it notices the
+        // disconnection.
+        for slot in self.slots.iter_mut() {
+            if slot.enabled && slot.port == port {
+                for ep in slot.endpoints.iter_mut() {
+                    ep.td = None;
+                }
+            }
+        }
+        self.update_port_connection(index);
+        self.port_status_changed(index);
+
+        Some(device)
+    }
+
+    fn state(&self) -> XhciDeviceState {
+        XhciDeviceState {
+            usbcmd: self.usbcmd,
+            usbsts: self.usbsts,
+            dnctrl: self.dnctrl,
+            command_ring: self.command_ring,
+            command_ring_running: self.command_ring_running,
+            dcbaap: self.dcbaap,
+            config: self.config,
+            iman: self.iman,
+            imod: self.imod,
+            erstsz: self.erstsz,
+            erstba: self.erstba,
+            erdp: self.erdp,
+            event_ring: self.event_ring.clone(),
+            slots: self
+                .slots
+                .iter()
+                .map(|slot| Slot {
+                    enabled: slot.enabled,
+                    state: slot.state,
+                    port: slot.port,
+                    context: slot.context,
+                    endpoints: slot
+                        .endpoints
+                        .iter()
+                        .map(|ep| Endpoint {
+                            state: ep.state,
+                            ep_type: ep.ep_type,
+                            ring: ep.ring,
+                            td: None,
+                        })
+                        .collect(),
+                    doorbells: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn set_state(&mut self, state: XhciDeviceState) {
+        self.usbcmd = state.usbcmd;
+        self.usbsts = state.usbsts;
+        self.dnctrl = state.dnctrl;
+        self.command_ring = state.command_ring;
+        self.command_ring_running = state.command_ring_running;
+        self.dcbaap = state.dcbaap;
+        self.config = state.config;
+        self.iman = state.iman;
+        self.imod = state.imod;
+        self.erstsz = state.erstsz;
+        self.erstba = state.erstba;
+        self.erdp = state.erdp;
+        self.event_ring = state.event_ring;
+        if state.slots.len() == MAX_SLOTS as usize
+            && state
+                .slots
+                .iter()
+                .all(|slot| slot.endpoints.len() == MAX_ENDPOINTS)
+        {
+            self.slots = state.slots;
+        }
+    }
+}
+
+/// xHCI USB host controller.
+pub struct XhciDevice {
+    id: String,
+    core: Arc<Mutex<XhciCore>>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+}
+
+impl XhciDevice {
+    pub fn new(
+        id: String,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+        irq: u8,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, XhciError> {
+        let pci_configuration_state =
+            vm_migration::state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID).map_err(|e| {
+                XhciError::RetrievePciConfigurationState(anyhow!(
+                    "Failed to get PciConfigurationState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let restoring = pci_configuration_state.is_some();
+
+        let mut configuration = PciConfiguration::new(
+            XHCI_VENDOR_ID,
+            XHCI_DEVICE_ID,
+            0x1,
+            PciClassCode::SerialBusController,
+            &PciSerialBusSubClass::Usb,
+            Some(&XhciProgrammingInterface),
+            PciHeaderType::Device,
+            XHCI_VENDOR_ID,
+            0,
+            None,
+            pci_configuration_state,
+        );
+        if !restoring {
+            configuration.set_irq(irq, PciInterruptPin::IntA);
+        }
+
+        let state: Option<XhciDeviceState> = snapshot
+            .as_ref()
+            .map(|s| s.to_state())
+            .transpose()
+            .map_err(|e| {
+                XhciError::RetrieveState(anyhow!(
+                    "Failed to get XhciDeviceState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        let mut core = XhciCore::new(mem, interrupt);
+        if let Some(state) = state {
+            core.set_state(state);
+        }
+
+        Ok(XhciDevice {
+            id,
+            core: Arc::new(Mutex::new(core)),
+            kick_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(XhciError::CreateEventFd)?,
+            kill_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(XhciError::CreateEventFd)?,
+            worker: None,
+            configuration,
+            bar_regions: vec![],
+        })
+    }
+
+    /// Starts the thread processing the rings and the device completions,
+    /// confined by the given seccomp filter. The thread is named after the
+    /// controller.
+    pub fn start_thread(
+        &mut self,
+        seccomp_filter: BpfProgram,
+        exit_evt: EventFd,
+    ) -> Result<(), XhciError> {
+        if self.worker.is_some() {
+            warn!("Tried to start multiple xHCI worker threads, ignoring");
+            return Ok(());
+        }
+
+        let epoll_fd = epoll::create(true).map_err(XhciError::CreateEpoll)?;
+        // SAFETY: epoll_fd is valid and owned by nothing else
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        for (fd, data) in [
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.kick_evt.as_raw_fd(), KICK_EVENT),
+        ] {
+            net_util::register_listener(epoll_fd, fd, epoll::Events::EPOLLIN, data)
+                .map_err(XhciError::CreateEpoll)?;
+        }
+
+        let core = self.core.clone();
+        let kick_evt = self
+            .kick_evt
+            .try_clone()
+            .map_err(XhciError::CreateEventFd)?;
+        let thread = thread::Builder::new()
+            .name(self.id.clone())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        return;
+                    }
+                }
+
+                let result = std::panic::catch_unwind(AssertUnwindSafe(move || {
+                    let mut events =
+                        [epoll::Event::new(epoll::Events::empty(), 0); NUM_PORTS as usize + 2];
+                    // Device file descriptors being polled, for each port.
+                    let mut polled: Vec<Option<(RawFd, u64)>> = vec![None; NUM_PORTS as usize];
+
+                    loop {
+                        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events)
+                        {
+                            Ok(num_events) => num_events,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => {
+                                error!("Failed to wait on xHCI events: {:?}", e);
+                                return;
+                            }
+                        };
+
+                        for event in events.iter().take(num_events) {
+                            match event.data {
+                                KILL_EVENT => return,
+                                KICK_EVENT => {
+                                    let _ = kick_evt.read();
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        let mut core = core.lock().unwrap();
+                        if core.process() {
+                            kick_evt.write(1).ok();
+                        }
+                        let wanted = core.completion_fds();
+                        drop(core);
+
+                        // Stale file descriptors are removed first, as their
+                        // numbers may have been reused by new devices.
+                        for (port, (polled, wanted)) in
+                            polled.iter_mut().zip(wanted.iter()).enumerate()
+                        {
+                            if let Some((fd, _)) = polled.filter(|p| Some(*p) != *wanted) {
+                                net_util::unregister_listener(
+                                    epoll_file.as_raw_fd(),
+                                    fd,
+                                    epoll::Events::EPOLLOUT,
+                                    DEVICE_EVENT + port as u64,
+                                )
+                                .ok();
+                                *polled = None;
+                            }
+                        }
+                        for (port, (polled, wanted)) in
+                            polled.iter_mut().zip(wanted.iter()).enumerate()
+                        {
+                            if let Some((fd, _)) = wanted.filter(|_| polled.is_none()) {
+                                match net_util::register_listener(
+                                    epoll_file.as_raw_fd(),
+                                    fd,
+                                    epoll::Events::EPOLLOUT,
+                                    DEVICE_EVENT + port as u64,
+                                ) {
+                                    Ok(()) => *polled = *wanted,
+                                    Err(e) => error!(
+                                        "Failed to poll USB device on port {}: {:?}",
+                                        port + 1,
+                                        e
+                                    ),
+                                }
+                            }
+                        }
+                    }
+                }));
+                if result.is_err() {
+                    error!("xHCI worker thread panicked");
+                    exit_evt.write(1).ok();
+                }
+            })
+            .map_err(XhciError::SpawnThread)?;
+        self.worker = Some(thread);
+
+        Ok(())
+    }
+
+    fn kick(&self) {
+        if let Err(e) = self.kick_evt.write(1) {
+            error!("Failed to kick xHCI worker thread: {:?}", e);
+        }
+    }
+
+    /// Plugs a device into the first free port matching its speed, returning
+    /// the port number.
+    pub fn attach(&self, device: Box<dyn UsbDevice>) -> Result<u8, XhciError> {
+        let port = self.core.lock().unwrap().attach(device)?;
+        self.kick();
+        Ok(port)
+    }
+
+    /// Unplugs the device from the given port.
+    pub fn detach(&self, port: u8) {
+        let device = self.core.lock().unwrap().detach(port);
+        // Releasing the device may take a while, hence without the lock.
+        drop(device);
+        self.kick();
+    }
+
+    pub fn config_bar_addr(&self) -> u64 {
+        self.configuration.get_bar_addr(0)
+    }
+}
+
+impl Drop for XhciDevice {
+    fn drop(&mut self) {
+        self.kill_evt.write(1).ok();
+        if let Some(handle) = self.worker.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl BusDevice for XhciDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for XhciDevice {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        _allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let mut bars = Vec::new();
+        let region_size = XHCI_DEVICE_MMIO_SIZE;
+        let restoring = resources.is_some();
+        let bar_addr = mmio32_allocator
+            .allocate(None, region_size, Some(region_size))
+            .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+
+        let bar = PciBarConfiguration::default()
+            .set_index(0)
+            .set_address(bar_addr.raw_value())
+            .set_size(region_size)
+            .set_region_type(PciBarRegionType::Memory32BitRegion)
+            .set_prefetchable(PciBarPrefetchable::NotPrefetchable);
+
+        debug!("xHCI bar address 0x{:x}", bar_addr.0);
+        if !restoring {
+            self.configuration
+                .add_pci_bar(&bar)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+        }
+
+        bars.push(bar);
+        self.bar_regions.clone_from(&bars);
+
+        Ok(bars)
+    }
+
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+        mmio32_allocator: &mut AddressAllocator,
+        _mmio64_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            mmio32_allocator.free(GuestAddress(bar.addr()), bar.size());
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let core = self.core.lock().unwrap();
+        if data.len() == 8 && offset & 0x7 == 0 {
+            let value =
+                u64::from(core.read_reg(offset)) | u64::from(core.read_reg(offset + 4)) << 32;
+            data.copy_from_slice(&value.to_le_bytes());
+            return;
+        }
+
+        let reg = offset & !0x3;
+        let shift = (offset & 0x3) as usize;
+        if data.len() + shift > 4 {
+            warn!("Invalid xHCI register read at 0x{:x}", offset);
+            return;
+        }
+
+        let value = core.read_reg(reg).to_le_bytes();
+        data.copy_from_slice(&value[shift..shift + data.len()]);
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        let mut core = self.core.lock().unwrap();
+        // Many registers are write-1-to-clear, so narrower writes can't be
+        // merged with the current value.
+        let kick = match (data.len(), offset & 0x7) {
+            (4, 0) | (4, 4) => core.write_reg(offset, u32::from_le_bytes(data.try_into().unwrap())),
+            (8, 0) => {
+                let lo = core.write_reg(offset, u32::from_le_bytes(data[..4].try_into().unwrap()));
+                let hi = core.write_reg(
+                    offset + 4,
+                    u32::from_le_bytes(data[4..].try_into().unwrap()),
+                );
+                lo || hi
+            }
+            _ => {
+                warn!("Invalid xHCI register write at 0x{:x}", offset);
+                false
+            }
+        };
+        drop(core);
+        if kick {
+            self.kick();
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for XhciDevice {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // Taking the lock guarantees the worker thread is done with guest
+        // memory.
+        self.core.lock().unwrap().paused = true;
+        self.kick();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.core.lock().unwrap().paused = false;
+        self.kick();
+        Ok(())
+    }
+}
+
+impl Snapshottable for XhciDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new_from_state(&self.core.lock().unwrap().state())?;
+
+        // Snapshot PciConfiguration
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for XhciDevice {}
+impl Migratable for XhciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    const DCBAA: u64 = 0x1000;
+    const COMMAND_RING: u64 = 0x2000;
+    const ERST: u64 = 0x3000;
+    const EVENT_RING: u64 = 0x4000;
+    const INPUT_CONTEXT: u64 = 0x5000;
+    const EP0_RING: u64 = 0x6000;
+    const DEVICE_CONTEXT: u64 = 0x7000;
+    const BUFFER: u64 = 0x8000;
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn set_gsi(&self) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            Some(self.event_fd.try_clone().unwrap())
+        }
+    }
+
+    #[derive(Default)]
+    struct TestDeviceState {
+        submitted: Vec<UsbTransfer>,
+        // Outcome of the next transfers.
+        responses: VecDeque<(UsbStatus, Vec<u8>)>,
+        cancelled: Vec<u64>,
+        resets: usize,
+    }
+
+    struct TestDevice {
+        state: Arc<Mutex<TestDeviceState>>,
+        pending: Vec<UsbTransfer>,
+    }
+
+    impl UsbDevice for TestDevice {
+        fn speed(&self) -> UsbSpeed {
+            UsbSpeed::High
+        }
+
+        fn completion_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn reset(&mut self) -> io::Result<()> {
+            self.state.lock().unwrap().resets += 1;
+            self.pending.clear();
+            Ok(())
+        }
+
+        fn submit(&mut self, transfer: UsbTransfer) -> io::Result<()> {
+            self.pending.push(UsbTransfer {
+                id: transfer.id,
+                transfer_type: transfer.transfer_type,
+                endpoint: transfer.endpoint,
+                setup: transfer.setup,
+                data: transfer.data.clone(),
+            });
+            self.state.lock().unwrap().submitted.push(transfer);
+            Ok(())
+        }
+
+        fn cancel(&mut self, id: u64) {
+            self.pending.retain(|transfer| transfer.id != id);
+            self.state.lock().unwrap().cancelled.push(id);
+        }
+
+        fn completions(&mut self) -> Vec<UsbCompletion> {
+            let mut state = self.state.lock().unwrap();
+            let mut completions = Vec::new();
+            while !self.pending.is_empty() {
+                let Some((status, data)) = state.responses.pop_front() else {
+                    break;
+                };
+                let transfer = self.pending.remove(0);
+                let actual_length = if transfer.is_in() {
+                    data.len()
+                } else {
+                    transfer.data.len()
+                };
+                completions.push(UsbCompletion {
+                    id: transfer.id,
+                    status,
+                    data,
+                    actual_length,
+                });
+            }
+            completions
+        }
+    }
+
+    struct TestController {
+        core: XhciCore,
+        mem: GuestMemoryMmap,
+        device: Arc<Mutex<TestDeviceState>>,
+        command_index: u64,
+        event_index: u64,
+    }
+
+    impl TestController {
+        fn new() -> Self {
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+            let interrupt = Arc::new(TestInterrupt {
+                event_fd: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            });
+            let mut core = XhciCore::new(GuestMemoryAtomic::new(mem.clone()), interrupt);
+
+            let device = Arc::new(Mutex::new(TestDeviceState::default()));
+            let port = core
+                .attach(Box::new(TestDevice {
+                    state: device.clone(),
+                    pending: Vec::new(),
+                }))
+                .unwrap();
+            assert_eq!(port, 1);
+
+            mem.write_obj(DEVICE_CONTEXT, GuestAddress(DCBAA + 8))
+                .unwrap();
+            mem.write_obj(EVENT_RING, GuestAddress(ERST)).unwrap();
+            mem.write_obj(16u64, GuestAddress(ERST + 8)).unwrap();
+
+            core.write_reg(DCBAAP_LO, DCBAA as u32);
+            core.write_reg(DCBAAP_HI, 0);
+            core.write_reg(CRCR_LO, COMMAND_RING as u32 | CRCR_RCS);
+            core.write_reg(CRCR_HI, 0);
+            core.write_reg(ERSTSZ, 1);
+            core.write_reg(ERSTBA_LO, ERST as u32);
+            core.write_reg(ERSTBA_HI, 0);
+            core.write_reg(ERDP_LO, EVENT_RING as u32);
+            core.write_reg(ERDP_HI, 0);
+            core.write_reg(IMAN, IMAN_IE);
+            core.write_reg(USBCMD, USBCMD_RS | USBCMD_INTE);
+
+            TestController {
+                core,
+                mem,
+                device,
+                command_index: 0,
+                event_index: 0,
+            }
+        }
+
+        fn write_trb(&self, addr: u64, parameter: u64, status: u32, control: u32) {
+            self.mem.write_obj(parameter, GuestAddress(addr)).unwrap();
+            self.mem.write_obj(status, GuestAddress(addr + 8)).unwrap();
+            self.mem
+                .write_obj(control | TRB_C, GuestAddress(addr + 12))
+                .unwrap();
+        }
+
+        fn command(&mut self, parameter: u64, trb_type: u8, flags: u32) -> Trb {
+            let addr = COMMAND_RING + 16 * self.command_index;
+            self.command_index += 1;
+            self.write_trb(
+                addr,
+                parameter,
+                0,
+                u32::from(trb_type) << TRB_TYPE_SHIFT | flags,
+            );
+            assert!(self.core.write_reg(DOORBELLS, 0));
+            self.core.process();
+
+            let event = self.next_event().unwrap();
+            assert_eq!(event.trb_type(), TRB_COMMAND_COMPLETION);
+            assert_eq!(event.parameter, addr);
+            event
+        }
+
+        fn next_event(&mut self) -> Option<Trb> {
+            let addr = EVENT_RING + 16 * self.event_index;
+            let mut bytes = [0u8; 16];
+            self.mem.read_slice(&mut bytes, GuestAddress(addr)).unwrap();
+            let trb = Trb::from_bytes(&bytes);
+            if !trb.cycle() {
+                return None;
+            }
+            self.event_index += 1;
+            Some(trb)
+        }
+
+        fn context(&self, addr: u64) -> [u32; 8] {
+            self.core.read_context(addr).unwrap()
+        }
+
+        // Enables slot 1 and addresses the device on port 1.
+        fn address_device(&mut self) {
+            let event = self.command(0, TRB_ENABLE_SLOT, 0);
+            assert_eq!(event.status >> 24, u32::from(CC_SUCCESS));
+            assert_eq!(event.slot_id(), 1);
+
+            let mut input = [0u32; 24];
+            input[1] = 0x3;
+            input[8] = 1 << 27;
+            input[9] = 1 << 16;
+            input[17] = u32::from(EP_CONTROL) << 3 | 64 << 16;
+            input[18] = EP0_RING as u32 | 1;
+            for (i, dword) in input.iter().enumerate() {
+                self.mem
+                    .write_obj(*dword, GuestAddress(INPUT_CONTEXT + 4 * i as u64))
+                    .unwrap();
+            }
+            let event = self.command(INPUT_CONTEXT, TRB_ADDRESS_DEVICE, 1 << 24);
+            assert_eq!(event.status >> 24, u32::from(CC_SUCCESS));
+        }
+
+        // Queues a GET_DESCRIPTOR(DEVICE) request on the default endpoint.
+        fn get_descriptor(&mut self) {
+            self.write_trb(
+                EP0_RING,
+                0x0012_0000_0100_0680,
+                8,
+                u32::from(TRB_SETUP) << TRB_TYPE_SHIFT | TRB_IDT | 3 << 16,
+            );
+            self.write_trb(
+                EP0_RING + 16,
+                BUFFER,
+                18,
+                u32::from(TRB_DATA) << TRB_TYPE_SHIFT | TRB_ISP | 1 << 16,
+            );
+            self.write_trb(
+                EP0_RING + 32,
+                0,
+                0,
+                u32::from(TRB_STATUS) << TRB_TYPE_SHIFT | TRB_IOC,
+            );
+            assert!(self.core.write_reg(DOORBELLS + 4, 1));
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let t = TestController::new();
+        assert_eq!(t.core.read_reg(CAPLENGTH), 0x0100_0040);
+        assert_eq!(t.core.read_reg(HCSPARAMS1), 0x1000_0110);
+        assert_eq!(t.core.read_reg(DBOFF), 0x2000);
+        assert_eq!(t.core.read_reg(RTSOFF), 0x1000);
+        // Extended capabilities pointer, in dwords
+        assert_eq!(t.core.read_reg(HCCPARAMS1) >> 16, 0x200);
+        assert_eq!(t.core.read_reg(EXT_CAPS + 8), 0x0801);
+        assert_eq!(t.core.read_reg(EXT_CAPS + 0x18), 0x0809);
+
+        // The device is connected to the first USB 2.0 port, waiting for a
+        // reset.
+        let portsc = t.core.read_reg(PORT_REGS);
+        assert_eq!(
+            portsc & (PORTSC_CCS | PORTSC_PED | PORTSC_CSC),
+            PORTSC_CCS | PORTSC_CSC
+        );
+        assert_eq!(t.core.read_reg(PORT_REGS + PORT_REGS_SIZE) & PORTSC_CCS, 0);
+    }
+
+    #[test]
+    fn test_port_reset() {
+        let mut t = TestController::new();
+        assert!(t
+            .core
+            .write_reg(PORT_REGS, PORTSC_PP | PORTSC_PR | PORTSC_CSC));
+        assert_eq!(t.core.read_reg(PORT_REGS) & PORTSC_CSC, 0);
+        t.core.process();
+
+        assert_eq!(t.device.lock().unwrap().resets, 1);
+        let portsc = t.core.read_reg(PORT_REGS);
+        assert_eq!(
+            portsc & (PORTSC_PED | PORTSC_PRC | PORTSC_PR),
+            PORTSC_PED | PORTSC_PRC
+        );
+        let event = t.next_event().unwrap();
+        assert_eq!(event.trb_type(), TRB_PORT_STATUS_CHANGE);
+        assert_eq!(event.parameter >> 24, 1);
+        assert!(t.core.usbsts & USBSTS_EINT != 0);
+    }
+
+    #[test]
+    fn test_control_transfer() {
+        let mut t = TestController::new();
+        t.address_device();
+        assert_eq!(t.context(DEVICE_CONTEXT)[3], SLOT_ADDRESSED << 27 | 1);
+        assert_eq!(t.context(DEVICE_CONTEXT + CONTEXT_SIZE)[0] & 0x7, 1);
+
+        let descriptor: Vec<u8> = (0..18).collect();
+        t.device
+            .lock()
+            .unwrap()
+            .responses
+            .push_back((UsbStatus::Success, descriptor.clone()));
+        t.get_descriptor();
+        t.core.process();
+
+        {
+            let device = t.device.lock().unwrap();
+            assert_eq!(device.submitted.len(), 1);
+            let transfer = &device.submitted[0];
+            assert_eq!(transfer.transfer_type, UsbTransferType::Control);
+            assert_eq!(transfer.setup, Some([0x80, 6, 0, 1, 0, 0, 18, 0]));
+            assert_eq!(transfer.data.len(), 18);
+        }
+
+        let event = t.next_event().unwrap();
+        assert_eq!(event.trb_type(), TRB_TRANSFER_EVENT);
+        assert_eq!(event.parameter, EP0_RING + 32);
+        assert_eq!(event.status, u32::from(CC_SUCCESS) << 24);
+        assert_eq!(event.endpoint_id(), 1);
+        assert!(t.next_event().is_none());
+
+        let mut data = [0u8; 18];
+        t.mem.read_slice(&mut data, GuestAddress(BUFFER)).unwrap();
+        assert_eq!(&data[..], &descriptor[..]);
+        // The dequeue pointer moved past the TD.
+        assert_eq!(
+            t.context(DEVICE_CONTEXT + CONTEXT_SIZE)[2],
+            (EP0_RING + 48) as u32 | 1
+        );
+    }
+
+    #[test]
+    fn test_short_control_transfer() {
+        let mut t = TestController::new();
+        t.address_device();
+        t.device
+            .lock()
+            .unwrap()
+            .responses
+            .push_back((UsbStatus::Success, vec![0x12; 8]));
+        t.get_descriptor();
+        t.core.process();
+
+        let event = t.next_event().unwrap();
+        assert_eq!(event.parameter, EP0_RING + 16);
+        assert_eq!(event.status, u32::from(CC_SHORT_PACKET) << 24 | 10);
+        let event = t.next_event().unwrap();
+        assert_eq!(event.parameter, EP0_RING + 32);
+        assert_eq!(event.status, u32::from(CC_SUCCESS) << 24);
+    }
+
+    #[test]
+    fn test_stall() {
+        let mut t = TestController::new();
+        t.address_device();
+        t.device
+            .lock()
+            .unwrap()
+            .responses
+            .push_back((UsbStatus::Stall, Vec::new()));
+        t.get_descriptor();
+        t.core.process();
+
+        let event = t.next_event().unwrap();
+        assert_eq!(event.parameter, EP0_RING + 16);
+        assert_eq!(event.status >> 24, u32::from(CC_STALL_ERROR));
+        // The endpoint is halted until reset, the TD left on the ring.
+        let context = t.context(DEVICE_CONTEXT + CONTEXT_SIZE);
+        assert_eq!(context[0] & 0x7, u32::from(EP_HALTED));
+        assert_eq!(context[2], EP0_RING as u32 | 1);
+
+        let event = t.command(0, TRB_RESET_ENDPOINT, 1 << 24 | 1 << 16);
+        assert_eq!(event.status >> 24, u32::from(CC_SUCCESS));
+        assert_eq!(
+            t.context(DEVICE_CONTEXT + CONTEXT_SIZE)[0] & 0x7,
+            u32::from(EP_STOPPED)
+        );
+    }
+
+    #[test]
+    fn test_stop_endpoint() {
+        let mut t = TestController::new();
+        t.address_device();
+        // No response, the transfer stays in flight.
+        t.get_descriptor();
+        t.core.process();
+        assert!(t.next_event().is_none());
+
+        let event = t.command(0, TRB_STOP_ENDPOINT, 1 << 24 | 1 << 16);
+        assert_eq!(event.status >> 24, u32::from(CC_SUCCESS));
+        let id = t.device.lock().unwrap().submitted[0].id;
+        assert_eq!(t.device.lock().unwrap().cancelled, vec![id]);
+        assert_eq!(
+            t.context(DEVICE_CONTEXT + CONTEXT_SIZE)[0] & 0x7,
+            u32::from(EP_STOPPED)
+        );
+
+        // Only running endpoints can be stopped.
+        let event = t.command(0, TRB_STOP_ENDPOINT, 1 << 24 | 1 << 16);
+        assert_eq!(event.status >> 24, u32::from(CC_CONTEXT_STATE_ERROR));
+    }
+
+    #[test]
+    fn test_isochronous_endpoint_refused() {
+        let mut t = TestController::new();
+        t.address_device();
+
+        let mut input = [0u32; 8 * 5];
+        // Add the slot context and endpoint 0x81 (DCI 3).
+        input[1] = 1 | 1 << 3;
+        input[8] = 3 << 27;
+        input[8 * 4 + 1] = u32::from(EP_ISOCH_IN) << 3;
+        for (i, dword) in input.iter().enumerate() {
+            t.mem
+                .write_obj(*dword, GuestAddress(INPUT_CONTEXT + 4 * i as u64))
+                .unwrap();
+        }
+        let event = t.command(INPUT_CONTEXT, TRB_CONFIGURE_ENDPOINT, 1 << 24);
+        assert_eq!(event.status >> 24, u32::from(CC_BANDWIDTH_ERROR));
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut t = TestController::new();
+        assert!(t.core.detach(1).is_some());
+        assert!(t.core.detach(1).is_none());
+
+        let portsc = t.core.read_reg(PORT_REGS);
+        assert_eq!(portsc & (PORTSC_CCS | PORTSC_CSC), PORTSC_CSC);
+        let event = t.next_event().unwrap();
+        assert_eq!(event.trb_type(), TRB_PORT_STATUS_CHANGE);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use acpi_tables::{aml, Aml, AmlSink};
+use std::io;
+use vm_device::BusDevice;
+use vm_memory::GuestAddress;
+
+pub const VMGENID_DEVICE_MMIO_SIZE: u64 = 0x10;
+
+/// A Virtual Machine Generation ID device, exposing a 128-bit identifier
+/// which changes when the VM is cloned. The guest reads it again when it's
+/// notified through the GED, e.g. to reseed its random number generator.
+pub struct VmGenIdDevice {
+    generation_id: [u8; VMGENID_DEVICE_MMIO_SIZE as usize],
+    address: GuestAddress,
+}
+
+impl VmGenIdDevice {
+    pub fn new(address: GuestAddress) -> Self {
+        VmGenIdDevice {
+            generation_id: random_generation_id(),
+            address,
+        }
+    }
+
+    /// Replaces the generation ID by a new random one.
+    pub fn regenerate(&mut self) {
+        self.generation_id = random_generation_id();
+    }
+
+    pub fn generation_id(&self) -> [u8; VMGENID_DEVICE_MMIO_SIZE as usize] {
+        self.generation_id
+    }
+}
+
+fn random_generation_id() -> [u8; VMGENID_DEVICE_MMIO_SIZE as usize] {
+    let mut generation_id = [0u8; VMGENID_DEVICE_MMIO_SIZE as usize];
+    // SAFETY: the buffer is valid for writes of its whole length.
+    let ret = unsafe {
+        libc::getrandom(
+            generation_id.as_mut_ptr() as *mut libc::c_void,
+            generation_id.len(),
+            0,
+        )
+    };
+    if ret != generation_id.len() as isize {
+        error!(
+            "Error generating the VM generation ID: {}",
+            io::Error::last_os_error()
+        );
+    }
+    generation_id
+}
+
+impl BusDevice for VmGenIdDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let start = offset as usize;
+        match self.generation_id.get(start..start + data.len()) {
+            Some(bytes) => data.copy_from_slice(bytes),
+            None => warn!(
+                "Invalid VM generation ID read of {} bytes at offset {}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+}
+
+impl Aml for VmGenIdDevice {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        let address_low = (self.address.0 & 0xffff_ffff) as u32;
+        let address_high = (self.address.0 >> 32) as u32;
+        aml::Device::new(
+            "_SB_.VGEN".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"VMGENCTR"),
+                &aml::Name::new("_CID".into(), &"VM_Gen_Counter"),
+                &aml::Name::new("_DDN".into(), &"VM_Gen_Counter"),
+                &aml::Name::new(
+                    "ADDR".into(),
+                    &aml::Package::new(vec![&address_low, &address_high]),
+                ),
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regenerate() {
+        let mut device = VmGenIdDevice::new(GuestAddress(0xfed0_0000));
+        let generation_id = device.generation_id();
+
+        let mut data = [0u8; 8];
+        device.read(0, 8, &mut data);
+        assert_eq!(data, generation_id[8..]);
+
+        device.regenerate();
+        assert_ne!(device.generation_id(), generation_id);
+    }
+}
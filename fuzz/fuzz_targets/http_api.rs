@@ -12,12 +12,17 @@ use std::sync::mpsc::{channel, Receiver};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use vm_migration::MigratableError;
+#[cfg(feature = "introspection")]
+use vmm::api::VmIntrospectData;
+#[cfg(feature = "pprof")]
+use vmm::api::VmmPprofData;
 use vmm::api::{
-    http::*, ApiRequest, RequestHandler, VmInfoResponse, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    http::*, ApiRequest, JobRequest, RequestHandler, StagedConfigChange, VmDumpMemoryData,
+    VmInfoResponse, VmReceiveMigrationData, VmRekeyData, VmSendMigrationData, VmSetBootParamsData,
+    VmmPingResponse,
 };
 use vmm::config::RestoreConfig;
-use vmm::vm::{Error as VmError, VmState};
+use vmm::vm::{Error as VmError, MemoryDump, VmState};
 use vmm::vm_config::*;
 use vmm::{EpollContext, EpollDispatch};
 use vmm_sys_util::eventfd::EventFd;
@@ -85,7 +90,7 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
-    fn vm_boot(&mut self) -> Result<(), VmError> {
+    fn vm_boot(&mut self, _: Option<VmIdentityConfig>) -> Result<(), VmError> {
         Ok(())
     }
 
@@ -97,7 +102,7 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
-    fn vm_snapshot(&mut self, _: &str) -> Result<(), VmError> {
+    fn vm_snapshot(&mut self, _: &str, _: Option<i32>, _: bool) -> Result<(), VmError> {
         Ok(())
     }
 
@@ -129,6 +134,8 @@ impl RequestHandler for StubApiRequestHandler {
                     max_phys_bits: 46,
                     affinity: None,
                     features: CpuFeatures::default(),
+                    sve_vl: None,
+                    pmu_events: None,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -139,9 +146,12 @@ impl RequestHandler for StubApiRequestHandler {
                     shared: false,
                     hugepages: false,
                     hugepage_size: None,
+                    hugepages_fallback: false,
                     prefault: false,
                     zones: None,
                     thp: true,
+                    locked: false,
+                    lock_policy: MemoryLockPolicy::Strict,
                 },
                 payload: Some(PayloadConfig {
                     kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -160,6 +170,7 @@ impl RequestHandler for StubApiRequestHandler {
                 },
                 balloon: None,
                 fs: None,
+                p9: None,
                 pmem: None,
                 serial: ConsoleConfig {
                     file: None,
@@ -179,7 +190,13 @@ impl RequestHandler for StubApiRequestHandler {
                 user_devices: None,
                 vdpa: None,
                 vsock: None,
+                xhci: false,
+                usb: None,
                 pvpanic: false,
+                #[cfg(target_arch = "x86_64")]
+                ramfb: false,
+                #[cfg(feature = "vnc")]
+                vnc: None,
                 iommu: false,
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
@@ -190,11 +207,19 @@ impl RequestHandler for StubApiRequestHandler {
                 pci_segments: None,
                 platform: None,
                 tpm: None,
+                rt: None,
+                payload_verification: None,
+                restart_policy: None,
+                boot_detect: None,
                 preserved_fds: None,
             })),
             state: VmState::Running,
             memory_actual_size: 0,
+            memory_backing: None,
             device_tree: None,
+            payload_verification: None,
+            shutdown_reason: None,
+            boot_state: None,
         })
     }
 
@@ -223,6 +248,22 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
+    fn vm_set_boot_params(&mut self, _: VmSetBootParamsData) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_rekey(&mut self, _: VmRekeyData) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_stage_config_change(&mut self, _: StagedConfigChange) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_update_config(&mut self, _: serde_json::Value) -> Result<(), VmError> {
+        Ok(())
+    }
+
     fn vm_add_device(&mut self, _: DeviceConfig) -> Result<Option<Vec<u8>>, VmError> {
         Ok(None)
     }
@@ -235,6 +276,10 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(())
     }
 
+    fn vm_replace_device(&mut self, _: String, _: ReplacementDeviceConfig) -> Result<(), VmError> {
+        Ok(())
+    }
+
     fn vm_add_disk(&mut self, _: DiskConfig) -> Result<Option<Vec<u8>>, VmError> {
         Ok(None)
     }
@@ -259,14 +304,79 @@ impl RequestHandler for StubApiRequestHandler {
         Ok(None)
     }
 
+    fn vm_add_usb(&mut self, _: UsbConfig) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError> {
         Ok(None)
     }
 
+    fn vm_vcpu_stats(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_migration_blockers(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_capabilities(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_device_tree(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vm_devices(&mut self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_threads(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_host_info(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_capabilities(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_fds(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_checkpoint(&mut self, _: &str) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vmm_post_restore(&mut self) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vmm_metrics(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    fn vmm_profile(&self) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "pprof")]
+    fn vmm_pprof(&self, _: VmmPprofData) -> Result<(), VmError> {
+        Ok(())
+    }
+
     fn vm_power_button(&mut self) -> Result<(), VmError> {
         Ok(())
     }
 
+    fn vm_suspend_to_ram(&mut self) -> Result<(), VmError> {
+        Ok(())
+    }
+
     fn vm_receive_migration(&mut self, _: VmReceiveMigrationData) -> Result<(), MigratableError> {
         Ok(())
     }
@@ -278,6 +388,25 @@ impl RequestHandler for StubApiRequestHandler {
     fn vm_nmi(&mut self) -> Result<(), VmError> {
         Ok(())
     }
+
+    fn vm_sysrq(&mut self, _: char) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_screenshot(&mut self, _: &str) -> Result<(), VmError> {
+        Ok(())
+    }
+
+    fn vm_dump_memory(&mut self, _: VmDumpMemoryData) -> Result<MemoryDump, VmError> {
+        Err(VmError::VmNotRunning)
+    }
+
+    fn job_run(&mut self, _: u64, _: JobRequest) {}
+
+    #[cfg(feature = "introspection")]
+    fn vm_introspect(&mut self, _: VmIntrospectData) -> Result<Option<Vec<u8>>, VmError> {
+        Ok(None)
+    }
 }
 
 fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receiver<ApiRequest>) {
@@ -7,6 +7,7 @@
 use libfuzzer_sys::fuzz_target;
 use seccompiler::SeccompAction;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use virtio_devices::{VirtioDevice, VirtioInterrupt, VirtioInterruptType};
 use virtio_queue::{Queue, QueueT};
@@ -36,6 +37,7 @@ fuzz_target!(|bytes| {
     let mut watchdog = virtio_devices::Watchdog::new(
         "fuzzer_watchdog".to_owned(),
         EventFd::new(EFD_NONBLOCK).unwrap(),
+        Arc::new(AtomicBool::new(false)),
         SeccompAction::Allow,
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
@@ -53,6 +53,10 @@ pub trait Vgic: Send + Sync {
     /// Returns the MSI reg property of the device
     fn msi_properties(&self) -> [u64; 2];
 
+    /// Returns whether the virtual LPIs and SGIs are directly injected by
+    /// the host GICv4.1, bypassing the hypervisor
+    fn direct_injection(&self) -> bool;
+
     /// Get the values of GICR_TYPER for each vCPU.
     fn set_gicr_typers(&mut self, vcpu_states: &[CpuState]);
 
@@ -189,6 +189,11 @@ pub enum HypervisorCpuError {
     #[error("Failed to enable HyperV SynIC")]
     EnableHyperVSyncIc(#[source] anyhow::Error),
     ///
+    /// Enabling HyperV enlightened VMCS error
+    ///
+    #[error("Failed to enable HyperV enlightened VMCS")]
+    EnableHyperVEvmcs(#[source] anyhow::Error),
+    ///
     /// Getting AArch64 core register error
     ///
     #[error("Failed to get core register: {0}")]
@@ -246,6 +251,18 @@ pub enum HypervisorCpuError {
     ///
     #[error("Failed to initialize PMU")]
     InitializePmu,
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Failed to set the PMU event filter
+    ///
+    #[error("Failed to set the PMU event filter: {0}")]
+    SetPmuEventFilter(#[source] anyhow::Error),
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Failed to enable SVE
+    ///
+    #[error("Failed to enable SVE: {0}")]
+    EnableSve(#[source] anyhow::Error),
     #[cfg(target_arch = "x86_64")]
     ///
     /// Error getting TSC frequency
@@ -353,6 +370,11 @@ pub trait Vcpu: Send + Sync {
     fn enable_hyperv_synic(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     ///
+    /// X86 specific call to enable HyperV enlightened VMCS
+    ///
+    fn enable_hyperv_evmcs(&self) -> Result<u16>;
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
     fn get_cpuid2(&self, num_entries: usize) -> Result<Vec<CpuIdEntry>>;
@@ -430,6 +452,17 @@ pub trait Vcpu: Send + Sync {
     #[cfg(target_arch = "aarch64")]
     fn init_pmu(&self, irq: u32) -> Result<()>;
     ///
+    /// Restrict the PMU to counting the given events
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_pmu_event_filter(&self, events: &[u16]) -> Result<()>;
+    ///
+    /// Enable SVE with the vector lengths supported by the host, up to
+    /// `max_vl` bits
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn init_sve(&self, max_vl: u16) -> Result<()>;
+    ///
     /// Retrieve the vCPU state.
     /// This function is necessary to snapshot the VM
     ///
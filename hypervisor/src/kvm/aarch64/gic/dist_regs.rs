@@ -16,6 +16,7 @@ by the system memory map. Unless otherwise stated in the register description,
 all GIC registers are 32-bits wide.
  */
 const GICD_CTLR: u32 = 0x0;
+const GICD_TYPER2: u32 = 0x000C;
 const GICD_STATUSR: u32 = 0x0010;
 const GICD_IGROUPR: u32 = 0x0080;
 const GICD_ISENABLER: u32 = 0x0100;
@@ -108,6 +109,14 @@ pub fn write_ctlr(gic: &DeviceFd, val: u32) -> Result<()> {
     dist_attr_access(gic, GICD_CTLR, &val, true)
 }
 
+/// Get the distributor type register 2, only exposed by KVM on GICv4.1
+/// capable hosts.
+pub fn read_typer2(gic: &DeviceFd) -> Result<u32> {
+    let val: u32 = 0;
+    dist_attr_access(gic, GICD_TYPER2, &val, false)?;
+    Ok(val)
+}
+
 fn get_interrupts_num(gic: &DeviceFd) -> Result<u32> {
     let num_irq = 0;
 
@@ -10,7 +10,7 @@ use crate::arch::aarch64::gic::{Error, Result, Vgic, VgicConfig};
 use crate::device::HypervisorDeviceError;
 use crate::kvm::KvmVm;
 use crate::{CpuState, Vm};
-use dist_regs::{get_dist_regs, read_ctlr, set_dist_regs, write_ctlr};
+use dist_regs::{get_dist_regs, read_ctlr, read_typer2, set_dist_regs, write_ctlr};
 use icc_regs::{get_icc_regs, set_icc_regs};
 use kvm_ioctls::DeviceFd;
 use redist_regs::{construct_gicr_typers, get_redist_regs, set_redist_regs};
@@ -24,6 +24,12 @@ const GITS_CWRITER: u32 = 0x0088;
 const GITS_CREADR: u32 = 0x0090;
 const GITS_BASER: u32 = 0x0100;
 
+// GICD_TYPER2.nASSGIcap, set by KVM when the host GICv4.1 injects the
+// virtual SGIs and LPIs directly.
+const GICD_TYPER2_NASSGICAP: u32 = 1 << 8;
+// GICD_CTLR.nASSGIreq, set by the guest to have its SGIs directly injected.
+const GICD_CTLR_NASSGIREQ: u32 = 1 << 8;
+
 /// Access an ITS device attribute.
 ///
 /// This is a helper function to get/set the ITS device attribute depending
@@ -105,6 +111,9 @@ pub struct KvmGicV3Its {
 
     /// Number of CPUs handled by the device
     vcpu_count: u64,
+
+    /// Whether the host GICv4.1 directly injects the virtual interrupts
+    direct_injection: bool,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -261,10 +270,20 @@ impl KvmGicV3Its {
             msi_addr: config.msi_addr,
             msi_size: config.msi_size,
             vcpu_count: config.vcpu_count,
+            direct_injection: false,
         };
 
         gic_device.init_device_attributes(vm, config.nr_irqs)?;
 
+        // Older kernels don't expose GICD_TYPER2 at all.
+        gic_device.direct_injection =
+            read_typer2(&gic_device.device).is_ok_and(|typer2| typer2 & GICD_TYPER2_NASSGICAP != 0);
+        if gic_device.direct_injection {
+            info!("GICv4.1 direct injection of virtual LPIs and SGIs enabled");
+        } else {
+            debug!("GICv4.1 direct injection not available, interrupts are injected by KVM");
+        }
+
         Ok(gic_device)
     }
 }
@@ -303,6 +322,10 @@ impl Vgic for KvmGicV3Its {
         [self.msi_addr, self.msi_size]
     }
 
+    fn direct_injection(&self) -> bool {
+        self.direct_injection
+    }
+
     fn set_gicr_typers(&mut self, vcpu_states: &[CpuState]) {
         let gicr_typers = construct_gicr_typers(vcpu_states);
         self.gicr_typers = gicr_typers;
@@ -398,7 +421,14 @@ impl Vgic for KvmGicV3Its {
     fn set_state(&mut self, state: &Gicv3ItsState) -> Result<()> {
         let gicr_typers = self.gicr_typers.clone();
 
-        write_ctlr(&self.device, state.gicd_ctlr)?;
+        let mut gicd_ctlr = state.gicd_ctlr;
+        if gicd_ctlr & GICD_CTLR_NASSGIREQ != 0 && !self.direct_injection {
+            // The state comes from a GICv4.1 host, KVM refuses the bit when
+            // it can't honour it, while the SGIs are still delivered without.
+            warn!("GICv4.1 direct injection not available, falling back on KVM injected SGIs");
+            gicd_ctlr &= !GICD_CTLR_NASSGIREQ;
+        }
+        write_ctlr(&self.device, gicd_ctlr)?;
 
         set_dist_regs(&self.device, &state.dist)?;
 
@@ -12,8 +12,9 @@ pub mod gic;
 
 use crate::kvm::{KvmError, KvmResult};
 use kvm_bindings::{
-    kvm_mp_state, kvm_one_reg, kvm_regs, KVM_REG_ARM_COPROC_MASK, KVM_REG_ARM_CORE,
-    KVM_REG_SIZE_MASK, KVM_REG_SIZE_U32, KVM_REG_SIZE_U64,
+    kvm_mp_state, kvm_one_reg, kvm_regs, KVM_REG_ARM64, KVM_REG_ARM_COPROC_MASK,
+    KVM_REG_ARM_COPROC_SHIFT, KVM_REG_ARM_CORE, KVM_REG_SIZE_MASK, KVM_REG_SIZE_SHIFT,
+    KVM_REG_SIZE_U32, KVM_REG_SIZE_U512, KVM_REG_SIZE_U64,
 };
 pub use kvm_bindings::{
     kvm_one_reg as Register, kvm_regs as StandardRegisters, kvm_vcpu_init as VcpuInit, RegList,
@@ -83,6 +84,25 @@ macro_rules! arm64_core_reg_id {
     };
 }
 
+// SVE registers, see Documentation/virt/kvm/api.rst in the kernel tree.
+const KVM_REG_ARM64_SVE: u64 = 0x15 << KVM_REG_ARM_COPROC_SHIFT;
+/// Pseudo register holding the bitmap of the SVE vector lengths, in
+/// quadwords, which are enabled for the vCPU.
+pub const KVM_REG_ARM64_SVE_VLS: u64 =
+    KVM_REG_ARM64 as u64 | KVM_REG_ARM64_SVE | KVM_REG_SIZE_U512 | 0xffff;
+
+/// Specifies whether a particular register is an SVE register or not,
+/// excluding the vector lengths pseudo register which can't be changed once
+/// the vCPU is finalized.
+pub fn is_sve_register(regid: u64) -> bool {
+    (regid & KVM_REG_ARM_COPROC_MASK as u64) == KVM_REG_ARM64_SVE && regid != KVM_REG_ARM64_SVE_VLS
+}
+
+/// Size in bytes of the register.
+pub fn register_size(regid: u64) -> usize {
+    1 << ((regid & KVM_REG_SIZE_MASK) >> KVM_REG_SIZE_SHIFT)
+}
+
 /// Specifies whether a particular register is a system register or not.
 /// The kernel splits the registers on aarch64 in core registers and system registers.
 /// So, below we get the system registers by checking that they are not core registers.
@@ -91,7 +111,8 @@ macro_rules! arm64_core_reg_id {
 ///
 /// * `regid` - The index of the register we are checking.
 pub fn is_system_register(regid: u64) -> bool {
-    if (regid & KVM_REG_ARM_COPROC_MASK as u64) == KVM_REG_ARM_CORE as u64 {
+    let coproc = regid & KVM_REG_ARM_COPROC_MASK as u64;
+    if coproc == KVM_REG_ARM_CORE as u64 || coproc == KVM_REG_ARM64_SVE {
         return false;
     }
 
@@ -115,9 +136,18 @@ pub fn check_required_kvm_extensions(kvm: &Kvm) -> KvmResult<()> {
     Ok(())
 }
 
+/// Register wider than 64 bits, such as the SVE ones.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WideRegister {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct VcpuKvmState {
     pub mp_state: kvm_mp_state,
     pub core_regs: kvm_regs,
     pub sys_regs: Vec<kvm_one_reg>,
+    #[serde(default)]
+    pub sve_regs: Vec<WideRegister>,
 }
@@ -12,8 +12,8 @@
 use crate::aarch64::gic::KvmGicV3Its;
 #[cfg(target_arch = "aarch64")]
 pub use crate::aarch64::{
-    check_required_kvm_extensions, gic::Gicv3ItsState as GicState, is_system_register, VcpuInit,
-    VcpuKvmState,
+    check_required_kvm_extensions, gic::Gicv3ItsState as GicState, is_sve_register,
+    is_system_register, register_size, VcpuInit, VcpuKvmState, WideRegister, KVM_REG_ARM64_SVE_VLS,
 };
 #[cfg(target_arch = "aarch64")]
 use crate::arch::aarch64::gic::{Vgic, VgicConfig};
@@ -58,8 +58,8 @@ use crate::{
 use aarch64::{RegList, Register, StandardRegisters};
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
-    kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
-    KVM_GUESTDBG_USE_HW_BP,
+    kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_ENLIGHTENED_VMCS, KVM_CAP_HYPERV_SYNIC,
+    KVM_CAP_SPLIT_IRQCHIP, KVM_GUESTDBG_USE_HW_BP,
 };
 #[cfg(target_arch = "x86_64")]
 use x86_64::check_required_kvm_extensions;
@@ -1498,6 +1498,27 @@ impl cpu::Vcpu for KvmVcpu {
             .map_err(|e| cpu::HypervisorCpuError::EnableHyperVSyncIc(e.into()))
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call to enable the HyperV enlightened VMCS, returning
+    /// the range of supported eVMCS versions
+    ///
+    fn enable_hyperv_evmcs(&self) -> cpu::Result<u16> {
+        let mut evmcs_version: u16 = 0;
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_HYPERV_ENLIGHTENED_VMCS,
+            ..Default::default()
+        };
+        // KVM writes the supported versions to the address passed as the
+        // first argument.
+        cap.args[0] = &mut evmcs_version as *mut u16 as u64;
+        self.fd
+            .enable_cap(&cap)
+            .map_err(|e| cpu::HypervisorCpuError::EnableHyperVEvmcs(e.into()))?;
+
+        Ok(evmcs_version)
+    }
+
     ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
@@ -2024,6 +2045,16 @@ impl cpu::Vcpu for KvmVcpu {
             .get_reg_list(&mut reg_list)
             .map_err(|e| cpu::HypervisorCpuError::GetRegList(e.into()))?;
 
+        // The SVE registers are only listed when SVE is enabled, and are
+        // wider than 64 bits.
+        for index in reg_list.as_slice().iter().filter(|r| is_sve_register(**r)) {
+            let mut data = vec![0_u8; register_size(*index)];
+            self.fd
+                .get_one_reg(*index, &mut data)
+                .map_err(|e| cpu::HypervisorCpuError::GetSysRegister(e.into()))?;
+            state.sve_regs.push(WideRegister { id: *index, data });
+        }
+
         // At this point reg_list should contain: core registers and system
         // registers.
         // The register list contains the number of registers and their ids. We
@@ -2156,6 +2187,11 @@ impl cpu::Vcpu for KvmVcpu {
                 .set_one_reg(reg.id, &reg.addr.to_le_bytes())
                 .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
         }
+        for reg in &state.sve_regs {
+            self.fd
+                .set_one_reg(reg.id, &reg.data)
+                .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+        }
 
         self.set_mp_state(state.mp_state.into())?;
 
@@ -2286,6 +2322,73 @@ impl cpu::Vcpu for KvmVcpu {
             .map_err(|_| cpu::HypervisorCpuError::InitializePmu)
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn set_pmu_event_filter(&self, events: &[u16]) -> cpu::Result<()> {
+        // The first filter allowing events denies all the others.
+        for event in events {
+            let filter = kvm_bindings::kvm_pmu_event_filter {
+                base_event: *event,
+                nevents: 1,
+                action: kvm_bindings::KVM_PMU_EVENT_ALLOW as u8,
+                ..Default::default()
+            };
+            let cpu_attr = kvm_bindings::kvm_device_attr {
+                group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+                attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_FILTER),
+                addr: &filter as *const kvm_bindings::kvm_pmu_event_filter as u64,
+                flags: 0,
+            };
+            self.fd
+                .set_device_attr(&cpu_attr)
+                .map_err(|e| cpu::HypervisorCpuError::SetPmuEventFilter(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn init_sve(&self, max_vl: u16) -> cpu::Result<()> {
+        // One bit per vector length, in quadwords, starting from 1.
+        let mut bytes = [0_u8; 64];
+        self.fd
+            .get_one_reg(KVM_REG_ARM64_SVE_VLS, &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::EnableSve(e.into()))?;
+        let mut vls = [0_u64; 8];
+        for (i, vl) in vls.iter_mut().enumerate() {
+            *vl = u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+
+        let max_vq = usize::from(max_vl / 128);
+        let supported = |vq: usize| vls[(vq - 1) / 64] & (1 << ((vq - 1) % 64)) != 0;
+        if !supported(max_vq) {
+            let supported_vls: Vec<String> = (1..=512)
+                .filter(|vq| supported(*vq))
+                .map(|vq| (vq * 128).to_string())
+                .collect();
+            return Err(cpu::HypervisorCpuError::EnableSve(anyhow!(
+                "Vector length of {} bits not supported by the host, supported lengths: {}",
+                max_vl,
+                supported_vls.join(", ")
+            )));
+        }
+
+        // KVM requires every vector length supported by the host up to the
+        // maximum one to be enabled.
+        for vq in max_vq + 1..=512 {
+            vls[(vq - 1) / 64] &= !(1 << ((vq - 1) % 64));
+        }
+        for (i, vl) in vls.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&vl.to_le_bytes());
+        }
+        self.fd
+            .set_one_reg(KVM_REG_ARM64_SVE_VLS, &bytes)
+            .map_err(|e| cpu::HypervisorCpuError::EnableSve(e.into()))?;
+
+        self.fd
+            .vcpu_finalize(&(kvm_bindings::KVM_ARM_VCPU_SVE as i32))
+            .map_err(|e| cpu::HypervisorCpuError::EnableSve(e.into()))
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Get the frequency of the TSC if available
@@ -495,6 +495,16 @@ impl cpu::Vcpu for MshvVcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call to enable HyperV enlightened VMCS
+    ///
+    fn enable_hyperv_evmcs(&self) -> cpu::Result<u16> {
+        Err(cpu::HypervisorCpuError::EnableHyperVEvmcs(anyhow!(
+            "Enlightened VMCS is not supported on MSHV"
+        )))
+    }
+
     #[allow(non_upper_case_globals)]
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         let hv_message: hv_message = hv_message::default();
@@ -1170,6 +1180,16 @@ impl cpu::Vcpu for MshvVcpu {
         unimplemented!()
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn set_pmu_event_filter(&self, events: &[u16]) -> cpu::Result<()> {
+        unimplemented!()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn init_sve(&self, max_vl: u16) -> cpu::Result<()> {
+        unimplemented!()
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn setup_regs(&self, cpu_id: u8, boot_ip: u64, fdt_start: u64) -> cpu::Result<()> {
         unimplemented!()
@@ -0,0 +1,119 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attachment of TAP interfaces to a Linux bridge and configuration of the
+//! VLAN of the bridge port.
+
+use crate::netlink::{self, if_index, rtattr};
+use std::fs;
+use std::io;
+use thiserror::Error;
+
+const IFINFOMSG_LEN: usize = 16;
+
+const IFLA_MASTER: u16 = 10;
+const IFLA_AF_SPEC: u16 = 26;
+const IFLA_BRIDGE_VLAN_INFO: u16 = 2;
+
+const BRIDGE_VLAN_INFO_PVID: u16 = 1 << 1;
+const BRIDGE_VLAN_INFO_UNTAGGED: u16 = 1 << 2;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Netlink(#[from] netlink::Error),
+    #[error("Failed to read the default PVID of bridge {0}: {1}")]
+    ReadDefaultPvid(String, io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn vlan_info_attr(flags: u16, vid: u16) -> Vec<u8> {
+    let mut vlan_info = Vec::with_capacity(4);
+    vlan_info.extend_from_slice(&flags.to_ne_bytes());
+    vlan_info.extend_from_slice(&vid.to_ne_bytes());
+
+    rtattr(IFLA_AF_SPEC, &rtattr(IFLA_BRIDGE_VLAN_INFO, &vlan_info))
+}
+
+// Sends a link request about the interface 'if_index' and waits for the
+// kernel acknowledgement.
+fn link_request(msg_type: u16, family: u8, if_index: u32, attrs: &[u8]) -> Result<()> {
+    // struct ifinfomsg
+    let mut ifinfomsg = Vec::with_capacity(IFINFOMSG_LEN);
+    ifinfomsg.push(family);
+    ifinfomsg.push(0);
+    ifinfomsg.extend_from_slice(&0u16.to_ne_bytes());
+    ifinfomsg.extend_from_slice(&(if_index as i32).to_ne_bytes());
+    ifinfomsg.extend_from_slice(&0u32.to_ne_bytes());
+    ifinfomsg.extend_from_slice(&0u32.to_ne_bytes());
+
+    Ok(netlink::request(msg_type, 0, &ifinfomsg, attrs)?)
+}
+
+fn default_pvid(bridge: &str) -> io::Result<u16> {
+    fs::read_to_string(format!("/sys/class/net/{bridge}/bridge/default_pvid"))?
+        .trim()
+        .parse::<u16>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Attach the network interface `if_name` to the bridge `bridge`.
+///
+/// When `vlan` is provided, the bridge port is configured as an access port
+/// for this VLAN: it becomes the PVID of the port and its frames are sent
+/// untagged to the interface. The default PVID of the bridge is removed from
+/// the port so that the interface only sees the traffic of its own VLAN.
+pub fn bridge_attach(if_name: &str, bridge: &str, vlan: Option<u16>) -> Result<()> {
+    let port_index = if_index(if_name)?;
+    let bridge_index = if_index(bridge)?;
+
+    link_request(
+        libc::RTM_SETLINK,
+        libc::AF_UNSPEC as u8,
+        port_index,
+        &rtattr(IFLA_MASTER, &bridge_index.to_ne_bytes()),
+    )?;
+
+    if let Some(vid) = vlan {
+        let default_pvid =
+            default_pvid(bridge).map_err(|e| Error::ReadDefaultPvid(bridge.to_owned(), e))?;
+
+        link_request(
+            libc::RTM_SETLINK,
+            libc::AF_BRIDGE as u8,
+            port_index,
+            &vlan_info_attr(BRIDGE_VLAN_INFO_PVID | BRIDGE_VLAN_INFO_UNTAGGED, vid),
+        )?;
+
+        if default_pvid != 0 && default_pvid != vid {
+            link_request(
+                libc::RTM_DELLINK,
+                libc::AF_BRIDGE as u8,
+                port_index,
+                &vlan_info_attr(0, default_pvid),
+            )?;
+        }
+    }
+
+    info!(
+        "Attached {} to bridge {} (VLAN: {:?})",
+        if_name, bridge, vlan
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vlan_info_attr() {
+        let attr = vlan_info_attr(BRIDGE_VLAN_INFO_PVID, 10);
+        assert_eq!(attr.len(), 12);
+        assert_eq!(u16::from_ne_bytes([attr[4], attr[5]]), 8);
+        assert_eq!(u16::from_ne_bytes([attr[10], attr[11]]), 10);
+    }
+}
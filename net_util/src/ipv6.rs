@@ -0,0 +1,352 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! IPv6 configuration of a TAP interface, and router advertisements letting
+//! the guest autoconfigure its address and default route through the host.
+
+use crate::netlink::{self, if_index, rtattr};
+use crate::MacAddr;
+use std::io;
+use std::mem::size_of;
+use std::net::Ipv6Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+const IFADDRMSG_LEN: usize = 8;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_F_NODAD: u8 = 0x02;
+
+const ICMP6_FILTER: libc::c_int = 1;
+const ND_ROUTER_SOLICIT: u8 = 133;
+const ND_ROUTER_ADVERT: u8 = 134;
+const ND_OPT_SOURCE_LINKADDR: u8 = 1;
+const ND_OPT_PREFIX_INFORMATION: u8 = 3;
+const ND_OPT_PI_FLAG_ONLINK: u8 = 0x80;
+const ND_OPT_PI_FLAG_AUTO: u8 = 0x40;
+// Neighbor discovery packets are only valid if they haven't been routed
+const ND_HOP_LIMIT: libc::c_int = 255;
+
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+// Lifetimes and intervals from RFC 4861 (section 6.2.1), the first
+// advertisements being sent faster for the guest to be configured promptly.
+const ROUTER_LIFETIME_S: u16 = 1800;
+const VALID_LIFETIME_S: u32 = 86400;
+const PREFERRED_LIFETIME_S: u32 = 14400;
+const INITIAL_ADVERT_INTERVAL: Duration = Duration::from_secs(4);
+const INITIAL_ADVERTS: u32 = 3;
+const ADVERT_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_DELAY_BETWEEN_ADVERTS: Duration = Duration::from_secs(3);
+
+// Keeps the first 'prefix_len' bits of 'addr'.
+fn prefix(addr: Ipv6Addr, prefix_len: u8) -> Ipv6Addr {
+    let mask = u128::MAX
+        .checked_shl(128 - prefix_len.min(128) as u32)
+        .unwrap_or(0);
+    Ipv6Addr::from(u128::from(addr) & mask)
+}
+
+/// Assign the address `addr` to the network interface `if_name`, the kernel
+/// adding the route to the on-link prefix along with it.
+pub fn set_ipv6_addr(if_name: &str, addr: Ipv6Addr, prefix_len: u8) -> netlink::Result<()> {
+    let index = if_index(if_name)?;
+
+    // struct ifaddrmsg, the address being usable right away as it is chosen
+    // by the user.
+    let mut ifaddrmsg = Vec::with_capacity(IFADDRMSG_LEN);
+    ifaddrmsg.push(libc::AF_INET6 as u8);
+    ifaddrmsg.push(prefix_len);
+    ifaddrmsg.push(IFA_F_NODAD);
+    ifaddrmsg.push(libc::RT_SCOPE_UNIVERSE);
+    ifaddrmsg.extend_from_slice(&index.to_ne_bytes());
+
+    let mut attrs = rtattr(IFA_LOCAL, &addr.octets());
+    attrs.extend(rtattr(IFA_ADDRESS, &addr.octets()));
+
+    netlink::request(
+        libc::RTM_NEWADDR,
+        (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16,
+        &ifaddrmsg,
+        &attrs,
+    )?;
+
+    info!("Assigned {}/{} to {}", addr, prefix_len, if_name);
+
+    Ok(())
+}
+
+// Builds a router advertisement for the prefix of 'addr', the guest being
+// able to derive its address from the prefix only if it is 64 bits long.
+fn router_advertisement(mac: &MacAddr, addr: Ipv6Addr, prefix_len: u8) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + 8 + 32);
+
+    // The checksum is computed by the kernel
+    packet.extend_from_slice(&[ND_ROUTER_ADVERT, 0, 0, 0]);
+    // Current hop limit and flags
+    packet.extend_from_slice(&[64, 0]);
+    packet.extend_from_slice(&ROUTER_LIFETIME_S.to_be_bytes());
+    // Reachable time and retransmission timer left unspecified
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+
+    packet.extend_from_slice(&[ND_OPT_SOURCE_LINKADDR, 1]);
+    packet.extend_from_slice(mac.get_bytes());
+
+    let mut flags = ND_OPT_PI_FLAG_ONLINK;
+    if prefix_len == 64 {
+        flags |= ND_OPT_PI_FLAG_AUTO;
+    }
+    packet.extend_from_slice(&[ND_OPT_PREFIX_INFORMATION, 4, prefix_len, flags]);
+    packet.extend_from_slice(&VALID_LIFETIME_S.to_be_bytes());
+    packet.extend_from_slice(&PREFERRED_LIFETIME_S.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&prefix(addr, prefix_len).octets());
+
+    packet
+}
+
+fn setsockopt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: FFI call with a valid socket and option value
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Advertises the host as a router on a TAP interface, periodically and in
+/// response to the solicitations of the guest.
+pub struct RouterAdvertiser {
+    socket: OwnedFd,
+    if_index: u32,
+    packet: Vec<u8>,
+}
+
+impl RouterAdvertiser {
+    pub fn new(if_name: &str, mac: &MacAddr, addr: Ipv6Addr, prefix_len: u8) -> io::Result<Self> {
+        let index = if_index(if_name).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+
+        // SAFETY: FFI call, the return value is checked
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET6,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::IPPROTO_ICMPV6,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: 'fd' is a valid file descriptor we own
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: FFI call with a valid socket and interface name
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                if_name.as_ptr() as *const libc::c_void,
+                if_name.len() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        setsockopt(
+            &socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_HOPS,
+            &ND_HOP_LIMIT,
+        )?;
+        setsockopt(
+            &socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_UNICAST_HOPS,
+            &ND_HOP_LIMIT,
+        )?;
+        setsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, &index)?;
+
+        // Only the router solicitations are of interest
+        let mut filter = [u32::MAX; 8];
+        filter[(ND_ROUTER_SOLICIT >> 5) as usize] &= !(1 << (ND_ROUTER_SOLICIT & 31));
+        setsockopt(&socket, libc::IPPROTO_ICMPV6, ICMP6_FILTER, &filter)?;
+
+        let mreq = libc::ipv6_mreq {
+            ipv6mr_multiaddr: libc::in6_addr {
+                s6_addr: ALL_ROUTERS.octets(),
+            },
+            ipv6mr_interface: index,
+        };
+        setsockopt(
+            &socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_ADD_MEMBERSHIP,
+            &mreq,
+        )?;
+
+        Ok(RouterAdvertiser {
+            socket,
+            if_index: index,
+            packet: router_advertisement(mac, addr, prefix_len),
+        })
+    }
+
+    fn advertise(&self) {
+        // SAFETY: all zero values are valid for sockaddr_in6
+        let mut dest: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        dest.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        dest.sin6_addr.s6_addr = ALL_NODES.octets();
+        dest.sin6_scope_id = self.if_index;
+
+        // SAFETY: FFI call with a valid socket, buffer and address
+        let ret = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                self.packet.as_ptr() as *const libc::c_void,
+                self.packet.len(),
+                0,
+                &dest as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        };
+        // The link-local address of the interface might not be usable yet,
+        // the next advertisement being sent anyway.
+        if ret < 0 {
+            debug!(
+                "Error sending router advertisement: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    // Drains the pending solicitations, returning whether there was any.
+    fn solicited(&self) -> bool {
+        let mut solicited = false;
+        let mut buf = [0u8; 1500];
+        loop {
+            // SAFETY: FFI call with a valid socket and buffer
+            let ret = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if ret <= 0 {
+                return solicited;
+            }
+            solicited |= buf[0] == ND_ROUTER_SOLICIT;
+        }
+    }
+
+    /// Sends the advertisements until `kill_evt` is signalled.
+    pub fn run(&self, kill_evt: &EventFd) -> io::Result<()> {
+        let mut adverts = 0;
+        let mut last_advert: Option<Instant> = None;
+        let mut next_advert = Instant::now();
+
+        loop {
+            let now = Instant::now();
+            if now >= next_advert {
+                self.advertise();
+                adverts += 1;
+                last_advert = Some(now);
+                next_advert = now
+                    + if adverts < INITIAL_ADVERTS {
+                        INITIAL_ADVERT_INTERVAL
+                    } else {
+                        ADVERT_INTERVAL
+                    };
+            }
+
+            let mut fds = [
+                libc::pollfd {
+                    fd: kill_evt.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.socket.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let timeout = next_advert.saturating_duration_since(now).as_millis() as libc::c_int;
+            // SAFETY: FFI call with valid pollfds
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            if fds[0].revents & libc::POLLIN != 0 {
+                return Ok(());
+            }
+
+            if fds[1].revents & libc::POLLIN != 0 && self.solicited() {
+                // Answer right away, unless an advertisement has just been sent
+                let now = Instant::now();
+                let earliest = last_advert.map_or(now, |t| t + MIN_DELAY_BETWEEN_ADVERTS);
+                next_advert = next_advert.min(earliest.max(now));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix() {
+        let addr: Ipv6Addr = "fd00:1:2:3:4:5:6:7".parse().unwrap();
+        assert_eq!(
+            prefix(addr, 64),
+            "fd00:1:2:3::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(prefix(addr, 48), "fd00:1:2::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(prefix(addr, 128), addr);
+        assert_eq!(prefix(addr, 0), Ipv6Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn test_router_advertisement() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let addr: Ipv6Addr = "fd00::1".parse().unwrap();
+
+        let packet = router_advertisement(&mac, addr, 64);
+        assert_eq!(packet.len(), 16 + 8 + 32);
+        assert_eq!(packet[0], ND_ROUTER_ADVERT);
+        assert_eq!(&packet[18..24], mac.get_bytes());
+        assert_eq!(packet[24], ND_OPT_PREFIX_INFORMATION);
+        assert_eq!(packet[26], 64);
+        assert_eq!(packet[27], ND_OPT_PI_FLAG_ONLINK | ND_OPT_PI_FLAG_AUTO);
+        assert_eq!(
+            &packet[40..56],
+            &Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0).octets()
+        );
+
+        // No autoconfiguration out of a prefix which isn't 64 bits long
+        let packet = router_advertisement(&mac, addr, 56);
+        assert_eq!(packet[27], ND_OPT_PI_FLAG_ONLINK);
+    }
+}
@@ -8,9 +8,13 @@
 #[macro_use]
 extern crate log;
 
+mod bridge;
 mod ctrl_queue;
+mod ipv6;
 mod mac;
+mod netlink;
 mod open_tap;
+mod qdisc;
 mod queue_pair;
 mod tap;
 
@@ -30,9 +34,15 @@ use vm_memory::{bitmap::AtomicBitmap, ByteValued};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
+pub use bridge::{bridge_attach, Error as BridgeError};
 pub use ctrl_queue::{CtrlQueue, Error as CtrlQueueError};
+pub use ipv6::{set_ipv6_addr, RouterAdvertiser};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
+pub use netlink::Error as NetlinkError;
 pub use open_tap::{open_tap, Error as OpenTapError};
+pub use qdisc::{
+    set_egress_shaping, EgressShaping, EgressShapingParseError, InstalledEgressShaping,
+};
 pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
 pub use tap::{Error as TapError, Tap};
 
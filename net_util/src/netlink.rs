@@ -0,0 +1,141 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal rtnetlink client, only sending requests and waiting for their
+//! acknowledgement.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use thiserror::Error;
+
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unknown network interface: {0}")]
+    UnknownInterface(String),
+    #[error("Failed to create netlink socket: {0}")]
+    CreateSocket(io::Error),
+    #[error("Failed to send netlink request: {0}")]
+    SendRequest(io::Error),
+    #[error("Failed to receive netlink response: {0}")]
+    ReceiveResponse(io::Error),
+    #[error("Invalid netlink response")]
+    InvalidResponse,
+    #[error("Netlink request failed: {0}")]
+    Request(io::Error),
+    #[error("A root qdisc is already configured on {0}")]
+    QdiscExists(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub(crate) fn if_index(if_name: &str) -> Result<u32> {
+    let c_if_name =
+        CString::new(if_name).map_err(|_| Error::UnknownInterface(if_name.to_owned()))?;
+    // SAFETY: FFI call with a valid NUL terminated string
+    let index = unsafe { libc::if_nametoindex(c_if_name.as_ptr()) };
+    if index == 0 {
+        return Err(Error::UnknownInterface(if_name.to_owned()));
+    }
+
+    Ok(index)
+}
+
+// Builds a route attribute, padded to a 4 bytes boundary.
+pub(crate) fn rtattr(attr_type: u16, payload: &[u8]) -> Vec<u8> {
+    let len = 4 + payload.len();
+    let mut attr = Vec::with_capacity((len + 3) & !3);
+    attr.extend_from_slice(&(len as u16).to_ne_bytes());
+    attr.extend_from_slice(&attr_type.to_ne_bytes());
+    attr.extend_from_slice(payload);
+    attr.resize((len + 3) & !3, 0);
+    attr
+}
+
+// Sends a request made of the family specific header 'header' followed by
+// the attributes 'attrs', and waits for the kernel acknowledgement.
+pub(crate) fn request(msg_type: u16, flags: u16, header: &[u8], attrs: &[u8]) -> Result<()> {
+    // SAFETY: FFI call, the return value is checked
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::CreateSocket(io::Error::last_os_error()));
+    }
+    // SAFETY: 'fd' is a valid file descriptor we own
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let len = NLMSG_HDR_LEN + header.len() + attrs.len();
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16 | flags).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(header);
+    msg.extend_from_slice(attrs);
+
+    // SAFETY: FFI call with a valid socket and buffer
+    let ret = unsafe { libc::send(socket.as_raw_fd(), msg.as_ptr() as *const _, msg.len(), 0) };
+    if ret < 0 {
+        return Err(Error::SendRequest(io::Error::last_os_error()));
+    }
+
+    let mut response = [0u8; 4096];
+    // SAFETY: FFI call with a valid socket and buffer
+    let ret = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            response.as_mut_ptr() as *mut _,
+            response.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::ReceiveResponse(io::Error::last_os_error()));
+    }
+    if (ret as usize) < NLMSG_HDR_LEN + 4 {
+        return Err(Error::InvalidResponse);
+    }
+
+    let response_type = u16::from_ne_bytes([response[4], response[5]]);
+    if response_type != NLMSG_ERROR {
+        return Err(Error::InvalidResponse);
+    }
+    let errno = i32::from_ne_bytes(
+        response[NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4]
+            .try_into()
+            .unwrap(),
+    );
+    if errno != 0 {
+        return Err(Error::Request(io::Error::from_raw_os_error(-errno)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtattr_padding() {
+        let attr = rtattr(10, &[1, 2, 3, 4]);
+        assert_eq!(attr.len(), 8);
+        assert_eq!(u16::from_ne_bytes([attr[0], attr[1]]), 8);
+        assert_eq!(u16::from_ne_bytes([attr[2], attr[3]]), 10);
+
+        let attr = rtattr(10, &[1]);
+        assert_eq!(attr.len(), 8);
+        assert_eq!(u16::from_ne_bytes([attr[0], attr[1]]), 5);
+    }
+}
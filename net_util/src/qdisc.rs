@@ -0,0 +1,413 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Installation of a traffic control queueing discipline on a TAP interface,
+//! shaping the traffic egressing the interface towards the guest.
+
+use crate::netlink::{self, if_index, rtattr};
+use serde::de::Error as SerdeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+const TCMSG_LEN: usize = 20;
+
+const TC_H_ROOT: u32 = 0xffff_ffff;
+// Handle of the root qdisc, and of the HTB class all the traffic goes through
+const QDISC_HANDLE: u32 = 0x1_0000;
+const HTB_CLASS_HANDLE: u32 = 0x1_0001;
+
+const TCA_KIND: u16 = 1;
+const TCA_OPTIONS: u16 = 2;
+const TCA_HTB_PARMS: u16 = 1;
+const TCA_HTB_INIT: u16 = 2;
+const TCA_HTB_RATE64: u16 = 6;
+const TCA_HTB_CEIL64: u16 = 7;
+
+const TC_HTB_PROTOVER: u32 = 3;
+const TC_LINKLAYER_ETHERNET: u8 = 1;
+const NETEM_DEFAULT_LIMIT: u32 = 1000;
+
+// Scheduler clock ticks are 64ns long
+const PSCHED_SHIFT: u32 = 6;
+const NSEC_PER_SEC: u128 = 1_000_000_000;
+// Largest frame sent in a burst on top of the traffic of one millisecond
+const MAX_FRAME_LEN: u64 = 1600;
+
+/// Queueing discipline installed as the root qdisc of a TAP interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EgressShaping {
+    /// Hierarchical token bucket capping the rate, in bits per second.
+    Htb { rate: u64 },
+    /// Network emulator delaying each packet, in microseconds.
+    Netem { delay_us: u32, jitter_us: u32 },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EgressShapingParseError {
+    InvalidValue(String),
+}
+
+impl fmt::Display for EgressShapingParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EgressShapingParseError::InvalidValue(s) => write!(
+                f,
+                "Invalid egress shaping {s} (expected htb:<rate> or netem:<delay>[/<jitter>])"
+            ),
+        }
+    }
+}
+
+// Parses a rate such as "100mbit" into bits per second.
+fn parse_rate(s: &str) -> Option<u64> {
+    let (value, factor) = [
+        ("gbit", 1_000_000_000),
+        ("mbit", 1_000_000),
+        ("kbit", 1_000),
+        ("bit", 1),
+    ]
+    .iter()
+    .find_map(|(unit, factor)| s.strip_suffix(unit).map(|v| (v, *factor)))
+    .unwrap_or((s, 1));
+
+    value.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+// Parses a duration such as "20ms" into microseconds.
+fn parse_time_us(s: &str) -> Option<u32> {
+    let (value, factor) = [("us", 1), ("ms", 1_000), ("s", 1_000_000)]
+        .iter()
+        .find_map(|(unit, factor)| s.strip_suffix(unit).map(|v| (v, *factor)))
+        .unwrap_or((s, 1));
+
+    value.parse::<u32>().ok()?.checked_mul(factor)
+}
+
+fn format_rate(rate: u64) -> String {
+    match rate {
+        r if r != 0 && r % 1_000_000_000 == 0 => format!("{}gbit", r / 1_000_000_000),
+        r if r != 0 && r % 1_000_000 == 0 => format!("{}mbit", r / 1_000_000),
+        r if r != 0 && r % 1_000 == 0 => format!("{}kbit", r / 1_000),
+        r => format!("{r}bit"),
+    }
+}
+
+fn format_time(us: u32) -> String {
+    if us != 0 && us % 1_000 == 0 {
+        format!("{}ms", us / 1_000)
+    } else {
+        format!("{us}us")
+    }
+}
+
+impl FromStr for EgressShaping {
+    type Err = EgressShapingParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EgressShapingParseError::InvalidValue(s.to_owned());
+
+        match s.split_once(':').ok_or_else(invalid)? {
+            ("htb", rate) => {
+                let rate = parse_rate(rate).filter(|r| *r > 0).ok_or_else(invalid)?;
+                Ok(EgressShaping::Htb { rate })
+            }
+            ("netem", times) => {
+                let (delay, jitter) = times.split_once('/').unwrap_or((times, "0"));
+                Ok(EgressShaping::Netem {
+                    delay_us: parse_time_us(delay).ok_or_else(invalid)?,
+                    jitter_us: parse_time_us(jitter).ok_or_else(invalid)?,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for EgressShaping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EgressShaping::Htb { rate } => write!(f, "htb:{}", format_rate(*rate)),
+            EgressShaping::Netem {
+                delay_us,
+                jitter_us: 0,
+            } => write!(f, "netem:{}", format_time(*delay_us)),
+            EgressShaping::Netem {
+                delay_us,
+                jitter_us,
+            } => write!(
+                f,
+                "netem:{}/{}",
+                format_time(*delay_us),
+                format_time(*jitter_us)
+            ),
+        }
+    }
+}
+
+impl Serialize for EgressShaping {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for EgressShaping {
+    fn deserialize<D>(deserializer: D) -> Result<EgressShaping, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| D::Error::custom(format!("{e}")))
+    }
+}
+
+// Converts a duration into scheduler clock ticks.
+fn ns_to_ticks(ns: u128) -> u32 {
+    (ns >> PSCHED_SHIFT).min(u32::MAX as u128) as u32
+}
+
+fn tcmsg(if_index: u32, handle: u32, parent: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(TCMSG_LEN);
+    msg.push(libc::AF_UNSPEC as u8);
+    msg.push(0);
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&(if_index as i32).to_ne_bytes());
+    msg.extend_from_slice(&handle.to_ne_bytes());
+    msg.extend_from_slice(&parent.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg
+}
+
+// struct tc_ratespec, for a rate in bytes per second
+fn ratespec(rate: u64) -> Vec<u8> {
+    let mut spec = Vec::with_capacity(12);
+    spec.push(0);
+    spec.push(TC_LINKLAYER_ETHERNET);
+    spec.extend_from_slice(&0u16.to_ne_bytes());
+    spec.extend_from_slice(&0i16.to_ne_bytes());
+    spec.extend_from_slice(&0u16.to_ne_bytes());
+    // Rates beyond 32 bits are carried by a separate attribute
+    spec.extend_from_slice(&(rate.min(u32::MAX as u64) as u32).to_ne_bytes());
+    spec
+}
+
+// Options of the HTB qdisc, sending the unclassified traffic to the class
+fn htb_qdisc_options() -> Vec<u8> {
+    // struct tc_htb_glob
+    let mut glob = Vec::with_capacity(20);
+    glob.extend_from_slice(&TC_HTB_PROTOVER.to_ne_bytes());
+    // rate2quantum
+    glob.extend_from_slice(&10u32.to_ne_bytes());
+    // defcls
+    glob.extend_from_slice(&(HTB_CLASS_HANDLE & 0xffff).to_ne_bytes());
+    glob.extend_from_slice(&0u32.to_ne_bytes());
+    glob.extend_from_slice(&0u32.to_ne_bytes());
+
+    rtattr(TCA_OPTIONS, &rtattr(TCA_HTB_INIT, &glob))
+}
+
+// Options of the HTB class capping the rate to 'rate' bits per second
+fn htb_class_options(rate: u64) -> Vec<u8> {
+    let rate = rate / 8;
+    // The bucket holds the traffic of one millisecond on top of a full frame
+    let burst = rate / 1000 + MAX_FRAME_LEN;
+    let buffer = ns_to_ticks(burst as u128 * NSEC_PER_SEC / rate.max(1) as u128);
+
+    // struct tc_htb_opt
+    let mut opt = Vec::with_capacity(44);
+    opt.extend_from_slice(&ratespec(rate));
+    opt.extend_from_slice(&ratespec(rate));
+    // buffer and cbuffer
+    opt.extend_from_slice(&buffer.to_ne_bytes());
+    opt.extend_from_slice(&buffer.to_ne_bytes());
+    // quantum, level and prio
+    opt.extend_from_slice(&0u32.to_ne_bytes());
+    opt.extend_from_slice(&0u32.to_ne_bytes());
+    opt.extend_from_slice(&0u32.to_ne_bytes());
+
+    let mut options = rtattr(TCA_HTB_PARMS, &opt);
+    if rate > u32::MAX as u64 {
+        options.extend(rtattr(TCA_HTB_RATE64, &rate.to_ne_bytes()));
+        options.extend(rtattr(TCA_HTB_CEIL64, &rate.to_ne_bytes()));
+    }
+
+    rtattr(TCA_OPTIONS, &options)
+}
+
+fn netem_qdisc_options(delay_us: u32, jitter_us: u32) -> Vec<u8> {
+    // struct tc_netem_qopt
+    let mut qopt = Vec::with_capacity(24);
+    qopt.extend_from_slice(&ns_to_ticks(delay_us as u128 * 1000).to_ne_bytes());
+    qopt.extend_from_slice(&NETEM_DEFAULT_LIMIT.to_ne_bytes());
+    // loss, gap and duplicate
+    qopt.extend_from_slice(&0u32.to_ne_bytes());
+    qopt.extend_from_slice(&0u32.to_ne_bytes());
+    qopt.extend_from_slice(&0u32.to_ne_bytes());
+    qopt.extend_from_slice(&ns_to_ticks(jitter_us as u128 * 1000).to_ne_bytes());
+
+    // The netem options aren't nested, unlike the ones of the other qdiscs
+    rtattr(TCA_OPTIONS, &qopt)
+}
+
+fn kind_attr(kind: &str) -> Vec<u8> {
+    let mut kind = kind.as_bytes().to_vec();
+    kind.push(0);
+    rtattr(TCA_KIND, &kind)
+}
+
+/// Egress shaping installed on a network interface, the qdisc being removed
+/// when dropped, unless the interface is gone already.
+pub struct InstalledEgressShaping {
+    if_name: String,
+}
+
+impl Drop for InstalledEgressShaping {
+    fn drop(&mut self) {
+        // Only the qdisc with the handle it was installed with is removed,
+        // the interface may have been reconfigured meanwhile.
+        let ret = if_index(&self.if_name).and_then(|index| {
+            netlink::request(
+                libc::RTM_DELQDISC,
+                0,
+                &tcmsg(index, QDISC_HANDLE, TC_H_ROOT),
+                &[],
+            )
+        });
+        match ret {
+            Ok(()) | Err(netlink::Error::UnknownInterface(_)) => {}
+            Err(e) => warn!(
+                "Failed to remove the egress shaping of {}: {}",
+                self.if_name, e
+            ),
+        }
+    }
+}
+
+// Installs the root qdisc of the interface, the default qdisc the kernel
+// attaches to it being the only one which may be replaced.
+fn add_root_qdisc(
+    if_name: &str,
+    index: u32,
+    attrs: &[u8],
+) -> netlink::Result<InstalledEgressShaping> {
+    // Without NLM_F_REPLACE, the kernel only creates the qdisc when the root
+    // one is the default qdisc, which isn't accounted as configured.
+    netlink::request(
+        libc::RTM_NEWQDISC,
+        (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+        &tcmsg(index, QDISC_HANDLE, TC_H_ROOT),
+        attrs,
+    )
+    .map_err(|e| match e {
+        netlink::Error::Request(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+            netlink::Error::QdiscExists(if_name.to_owned())
+        }
+        e => e,
+    })?;
+
+    Ok(InstalledEgressShaping {
+        if_name: if_name.to_owned(),
+    })
+}
+
+/// Install `shaping` as the root qdisc of the network interface `if_name`,
+/// until the returned value is dropped. A root qdisc already configured on
+/// the interface is left untouched, the installation failing instead.
+pub fn set_egress_shaping(
+    if_name: &str,
+    shaping: &EgressShaping,
+) -> netlink::Result<InstalledEgressShaping> {
+    let index = if_index(if_name)?;
+
+    let installed = match shaping {
+        EgressShaping::Htb { rate } => {
+            let mut attrs = kind_attr("htb");
+            attrs.extend(htb_qdisc_options());
+            let installed = add_root_qdisc(if_name, index, &attrs)?;
+
+            let mut attrs = kind_attr("htb");
+            attrs.extend(htb_class_options(*rate));
+            netlink::request(
+                libc::RTM_NEWTCLASS,
+                (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+                &tcmsg(index, HTB_CLASS_HANDLE, QDISC_HANDLE),
+                &attrs,
+            )?;
+
+            installed
+        }
+        EgressShaping::Netem {
+            delay_us,
+            jitter_us,
+        } => {
+            let mut attrs = kind_attr("netem");
+            attrs.extend(netem_qdisc_options(*delay_us, *jitter_us));
+            add_root_qdisc(if_name, index, &attrs)?
+        }
+    };
+
+    info!("Installed egress shaping {} on {}", shaping, if_name);
+
+    Ok(installed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_egress_shaping_parsing() {
+        assert_eq!(
+            "htb:100mbit".parse::<EgressShaping>(),
+            Ok(EgressShaping::Htb { rate: 100_000_000 })
+        );
+        assert_eq!(
+            "htb:1500".parse::<EgressShaping>(),
+            Ok(EgressShaping::Htb { rate: 1500 })
+        );
+        assert_eq!(
+            "netem:20ms".parse::<EgressShaping>(),
+            Ok(EgressShaping::Netem {
+                delay_us: 20_000,
+                jitter_us: 0
+            })
+        );
+        assert_eq!(
+            "netem:1s/500us".parse::<EgressShaping>(),
+            Ok(EgressShaping::Netem {
+                delay_us: 1_000_000,
+                jitter_us: 500
+            })
+        );
+        assert!("htb:0".parse::<EgressShaping>().is_err());
+        assert!("htb:fast".parse::<EgressShaping>().is_err());
+        assert!("netem".parse::<EgressShaping>().is_err());
+        assert!("tbf:1mbit".parse::<EgressShaping>().is_err());
+
+        for s in ["htb:2gbit", "htb:1500bit", "netem:20ms", "netem:1ms/250us"] {
+            assert_eq!(s.parse::<EgressShaping>().unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_tc_options() {
+        // TCA_OPTIONS holding TCA_HTB_PARMS
+        let options = htb_class_options(100_000_000);
+        assert_eq!(options.len(), 4 + 4 + 44);
+        let rate = u32::from_ne_bytes(options[16..20].try_into().unwrap());
+        assert_eq!(rate, 12_500_000);
+
+        // 64 bits rates
+        let options = htb_class_options(100_000_000_000);
+        assert_eq!(options.len(), 4 + 4 + 44 + 2 * 12);
+
+        let options = netem_qdisc_options(20_000, 0);
+        assert_eq!(options.len(), 4 + 24);
+        let latency = u32::from_ne_bytes(options[4..8].try_into().unwrap());
+        assert_eq!(latency, 20_000_000 >> PSCHED_SHIFT);
+    }
+}
@@ -13,12 +13,13 @@ use std::sync::Arc;
 use thiserror::Error;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::{Bytes, GuestMemory};
-use vm_virtio::{AccessPlatform, Translatable};
+use vm_virtio::{AccessPlatform, QueueCounters, Translatable};
 
 #[derive(Clone)]
 pub struct TxVirtio {
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub queue_counters: QueueCounters,
 }
 
 impl Default for TxVirtio {
@@ -32,6 +33,7 @@ impl TxVirtio {
         TxVirtio {
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            queue_counters: QueueCounters::default(),
         }
     }
 
@@ -145,6 +147,7 @@ impl TxVirtio {
 pub struct RxVirtio {
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub queue_counters: QueueCounters,
 }
 
 impl Default for RxVirtio {
@@ -158,6 +161,7 @@ impl RxVirtio {
         RxVirtio {
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            queue_counters: QueueCounters::default(),
         }
     }
 
@@ -355,6 +359,7 @@ impl NetQueuePair {
         mem: &GuestMemoryMmap,
         queue: &mut Queue,
     ) -> Result<bool, NetQueuePairError> {
+        let used = queue.next_used();
         let tx_tap_retry = self.tx.process_desc_chain(
             mem,
             &self.tap,
@@ -395,9 +400,15 @@ impl NetQueuePair {
         self.tx.counter_bytes = Wrapping(0);
         self.tx.counter_frames = Wrapping(0);
 
-        queue
+        self.tx.queue_counters.record_batch(queue, used);
+        let needs_notification = queue
             .needs_notification(mem)
-            .map_err(NetQueuePairError::QueueNeedsNotification)
+            .map_err(NetQueuePairError::QueueNeedsNotification)?;
+        self.tx
+            .queue_counters
+            .record_notification(needs_notification);
+
+        Ok(needs_notification)
     }
 
     pub fn process_rx(
@@ -405,6 +416,7 @@ impl NetQueuePair {
         mem: &GuestMemoryMmap,
         queue: &mut Queue,
     ) -> Result<bool, NetQueuePairError> {
+        let used = queue.next_used();
         self.rx_desc_avail = !self.rx.process_desc_chain(
             mem,
             &self.tap,
@@ -440,8 +452,19 @@ impl NetQueuePair {
         self.rx.counter_bytes = Wrapping(0);
         self.rx.counter_frames = Wrapping(0);
 
-        queue
+        self.rx.queue_counters.record_batch(queue, used);
+        // Frames are left pending on the TAP until the driver provides more
+        // descriptors.
+        if !self.rx_desc_avail {
+            self.rx.queue_counters.record_ring_full();
+        }
+        let needs_notification = queue
             .needs_notification(mem)
-            .map_err(NetQueuePairError::QueueNeedsNotification)
+            .map_err(NetQueuePairError::QueueNeedsNotification)?;
+        self.rx
+            .queue_counters
+            .record_notification(needs_notification);
+
+        Ok(needs_notification)
     }
 }
@@ -181,6 +181,11 @@ impl PciBus {
         }
     }
 
+    /// Number of device slots still available on the bus.
+    pub fn free_device_ids(&self) -> usize {
+        self.device_ids.iter().filter(|used| !**used).count()
+    }
+
     pub fn put_device_id(&mut self, id: usize) -> Result<()> {
         if id < NUM_DEVICE_IDS {
             self.device_ids[id] = false;
@@ -274,6 +279,23 @@ impl PciConfigIo {
                 }
             }
 
+            // Find out if one of the device's BAR is being resized, and
+            // reallocate it if needed.
+            if let Some(params) = device.detect_bar_resize(register, data) {
+                if let Err(e) = pci_bus.device_reloc.resize_bar(
+                    params.old_base,
+                    params.old_len,
+                    params.new_len,
+                    device.deref_mut(),
+                    params.region_type,
+                ) {
+                    error!(
+                        "Failed resizing device BAR: {}: 0x{:x}(0x{:x}->0x{:x})",
+                        e, params.old_base, params.old_len, params.new_len
+                    );
+                }
+            }
+
             // Update the register value
             device.write_config_register(register, offset, data)
         } else {
@@ -398,6 +420,23 @@ impl PciConfigMmio {
                 }
             }
 
+            // Find out if one of the device's BAR is being resized, and
+            // reallocate it if needed.
+            if let Some(params) = device.detect_bar_resize(register, data) {
+                if let Err(e) = pci_bus.device_reloc.resize_bar(
+                    params.old_base,
+                    params.old_len,
+                    params.new_len,
+                    device.deref_mut(),
+                    params.region_type,
+                ) {
+                    error!(
+                        "Failed resizing device BAR: {}: 0x{:x}(0x{:x}->0x{:x})",
+                        e, params.old_base, params.old_len, params.new_len
+                    );
+                }
+            }
+
             // Update the register value
             device.write_config_register(register, offset, data);
         }
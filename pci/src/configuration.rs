@@ -832,6 +832,49 @@ impl PciConfiguration {
         Ok(())
     }
 
+    /// Changes the size of a memory BAR, relocated to `addr` in the process.
+    pub fn resize_bar(&mut self, bar_idx: usize, addr: u64, size: u64) -> Result<()> {
+        let reg_idx = BAR0_REG + bar_idx;
+
+        if bar_idx >= NUM_BAR_REGS {
+            return Err(Error::BarInvalid(bar_idx));
+        }
+
+        if size.count_ones() != 1 {
+            return Err(Error::BarSizeInvalid(size));
+        }
+
+        match self.bars[bar_idx].r#type {
+            Some(PciBarRegionType::Memory64BitRegion) => {
+                let (bar_size_hi, bar_size_lo) =
+                    encode_64_bits_bar_size(size).ok_or(Error::Encode64BarSize)?;
+
+                self.registers[reg_idx + 1] = (addr >> 32) as u32;
+                self.bars[bar_idx + 1].addr = self.registers[reg_idx + 1];
+                self.bars[bar_idx].size = bar_size_lo;
+                self.bars[bar_idx + 1].size = bar_size_hi;
+            }
+            Some(PciBarRegionType::Memory32BitRegion) => {
+                let end_addr = addr
+                    .checked_add(size - 1)
+                    .ok_or(Error::BarAddressInvalid(addr, size))?;
+                if end_addr > u64::from(u32::max_value()) {
+                    return Err(Error::BarAddressInvalid(addr, size));
+                }
+
+                self.bars[bar_idx].size =
+                    encode_32_bits_bar_size(size as u32).ok_or(Error::Encode32BarSize)?;
+            }
+            _ => return Err(Error::BarInvalid(bar_idx)),
+        }
+
+        self.registers[reg_idx] =
+            (self.registers[reg_idx] & !BAR_MEM_ADDR_MASK) | ((addr as u32) & BAR_MEM_ADDR_MASK);
+        self.bars[bar_idx].addr = self.registers[reg_idx];
+
+        Ok(())
+    }
+
     /// Returns the address of the given BAR region.
     pub fn get_bar_addr(&self, bar_num: usize) -> u64 {
         let bar_idx = BAR0_REG + bar_num;
@@ -1260,4 +1303,49 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn resize_bar() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+            None,
+        );
+
+        let bar = PciBarConfiguration::default()
+            .set_index(2)
+            .set_address(0x1_0000_0000)
+            .set_size(0x1000_0000)
+            .set_region_type(PciBarRegionType::Memory64BitRegion)
+            .set_prefetchable(PciBarPrefetchable::Prefetchable);
+        cfg.add_pci_bar(&bar).unwrap();
+
+        cfg.resize_bar(2, 0x2_0000_0000, 0x1_0000_0000).unwrap();
+        assert_eq!(cfg.get_bar_addr(2), 0x2_0000_0000);
+        // The flags are kept
+        assert_eq!(cfg.read_reg(BAR0_REG + 2), 0xc);
+
+        // The guest sizing the BAR sees the new size
+        cfg.write_reg(BAR0_REG + 2, 0xffff_ffff);
+        cfg.write_reg(BAR0_REG + 3, 0xffff_ffff);
+        assert_eq!(cfg.read_reg(BAR0_REG + 2) & BAR_MEM_ADDR_MASK, 0);
+        assert_eq!(cfg.read_reg(BAR0_REG + 3), 0xffff_ffff);
+
+        assert!(matches!(
+            cfg.resize_bar(2, 0x2_0000_0000, 0x3000),
+            Err(Error::BarSizeInvalid(0x3000))
+        ));
+        assert!(matches!(
+            cfg.resize_bar(0, 0x2_0000_0000, 0x1000),
+            Err(Error::BarInvalid(0))
+        ));
+    }
 }
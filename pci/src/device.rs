@@ -54,6 +54,14 @@ pub struct BarReprogrammingParams {
     pub region_type: PciBarRegionType,
 }
 
+#[derive(Clone, Copy)]
+pub struct BarResizeParams {
+    pub old_base: u64,
+    pub old_len: u64,
+    pub new_len: u64,
+    pub region_type: PciBarRegionType,
+}
+
 pub trait PciDevice: BusDevice {
     /// Allocates the needed PCI BARs space using the `allocate` function which takes a size and
     /// returns an address. Returns a Vec of (GuestAddress, GuestUsize) tuples.
@@ -97,6 +105,10 @@ pub trait PciDevice: BusDevice {
     ) -> Option<BarReprogrammingParams> {
         None
     }
+    /// Detects if a BAR is being resized.
+    fn detect_bar_resize(&mut self, _reg_idx: usize, _data: &[u8]) -> Option<BarResizeParams> {
+        None
+    }
     /// Reads from a BAR region mapped in to the device.
     /// * `addr` - The guest address inside the BAR.
     /// * `data` - Filled with the data from `addr`.
@@ -111,6 +123,16 @@ pub trait PciDevice: BusDevice {
     fn move_bar(&mut self, _old_base: u64, _new_base: u64) -> result::Result<(), io::Error> {
         Ok(())
     }
+    /// Relocates the BAR, resized to `new_len`, to a different address in
+    /// guest address space.
+    fn resize_bar(
+        &mut self,
+        _old_base: u64,
+        _new_base: u64,
+        _new_len: u64,
+    ) -> result::Result<(), io::Error> {
+        Ok(())
+    }
     /// Provides a mutable reference to the Any trait. This is useful to let
     /// the caller have access to the underlying type behind the trait.
     fn as_any(&mut self) -> &mut dyn Any;
@@ -132,4 +154,16 @@ pub trait DeviceRelocation: Send + Sync {
         pci_dev: &mut dyn PciDevice,
         region_type: PciBarRegionType,
     ) -> result::Result<(), io::Error>;
+
+    /// The BAR has been resized by the software running in the guest, and
+    /// needs to be moved to a range of the new size in the guest address
+    /// space.
+    fn resize_bar(
+        &self,
+        old_base: u64,
+        old_len: u64,
+        new_len: u64,
+        pci_dev: &mut dyn PciDevice,
+        region_type: PciBarRegionType,
+    ) -> result::Result<(), io::Error>;
 }
@@ -6,8 +6,8 @@
 use crate::msi::{MsiConfigState, MSI_CONFIG_ID};
 use crate::msix::MsixConfigState;
 use crate::{
-    msi_num_enabled_vectors, BarReprogrammingParams, MsiCap, MsiConfig, MsixCap, MsixConfig,
-    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciBdf, PciCapabilityId,
+    msi_num_enabled_vectors, BarReprogrammingParams, BarResizeParams, MsiCap, MsiConfig, MsixCap,
+    MsixConfig, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciBdf, PciCapabilityId,
     PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciExpressCapabilityId,
     PciHeaderType, PciSubclass, MSIX_CONFIG_ID, MSIX_TABLE_ENTRY_SIZE, PCI_CONFIGURATION_ID,
 };
@@ -437,11 +437,26 @@ impl Vfio for VfioDeviceWrapper {
     }
 }
 
+// BAR which can be resized through the Resizable BAR capability, the sizes
+// being encoded as the powers of two from 1 MiB.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct ResizableBar {
+    bar_index: u32,
+    // Offset of the Resizable BAR Capability register of the BAR, followed by
+    // its Resizable BAR Control register
+    cap_offset: u32,
+    // Bitmap of the sizes the guest can pick
+    sizes: u32,
+    size: u32,
+}
+
 #[derive(Serialize, Deserialize)]
 struct VfioCommonState {
     intx_state: Option<IntxState>,
     msi_state: Option<MsiState>,
     msix_state: Option<MsixState>,
+    #[serde(default)]
+    rebars: Vec<ResizableBar>,
 }
 
 pub(crate) struct ConfigPatch {
@@ -458,6 +473,10 @@ pub(crate) struct VfioCommon {
     pub(crate) vfio_wrapper: Arc<dyn Vfio>,
     pub(crate) patches: HashMap<usize, ConfigPatch>,
     x_nv_gpudirect_clique: Option<u8>,
+    // Option ROM exposed through the expansion ROM BAR in place of the one of
+    // the device, if any
+    pub(crate) rom: Option<Vec<u8>>,
+    rebars: Vec<ResizableBar>,
 }
 
 impl VfioCommon {
@@ -505,6 +524,8 @@ impl VfioCommon {
             vfio_wrapper,
             patches: HashMap::new(),
             x_nv_gpudirect_clique,
+            rom: None,
+            rebars: Vec::new(),
         };
 
         let state: Option<VfioCommonState> = snapshot
@@ -614,6 +635,17 @@ impl VfioCommon {
                     bar_id += 1;
                     continue;
                 }
+            } else if let Some(rom) = self
+                .rom
+                .as_ref()
+                .filter(|_| bar_id == VFIO_PCI_ROM_REGION_INDEX)
+            {
+                // The ROM BAR only decodes the address bits from bit 11, and
+                // is left enabled as the guest can't toggle it.
+                region_size = (rom.len() as u64)
+                    .next_power_of_two()
+                    .max(PCI_ROM_BAR_MIN_SIZE);
+                flags = 0x1;
             } else {
                 let bar_offset = if bar_id == VFIO_PCI_ROM_REGION_INDEX {
                     (PCI_ROM_EXP_BAR_INDEX * 4) as u32
@@ -994,8 +1026,10 @@ impl VfioCommon {
             let cap_next: u16 = ((ext_cap_hdr >> 20) & 0xfff) as u16;
 
             match PciExpressCapabilityId::from(cap_id) {
+                PciExpressCapabilityId::ResizeableBar => {
+                    self.parse_rebar_capability(current_offset);
+                }
                 PciExpressCapabilityId::AlternativeRoutingIdentificationInterpretation
-                | PciExpressCapabilityId::ResizeableBar
                 | PciExpressCapabilityId::SingleRootIoVirtualization => {
                     let reg_idx = (current_offset / 4) as usize;
                     self.patches.insert(
@@ -1017,6 +1051,128 @@ impl VfioCommon {
         }
     }
 
+    // The BARs can only be resized in the guest within the regions of the
+    // device, sized on the host, and the BARs holding the MSI-X structures
+    // are left alone.
+    fn parse_rebar_capability(&mut self, cap_offset: u32) {
+        let ctrl = self.vfio_wrapper.read_config_dword(cap_offset + 8);
+        let num_bars = (ctrl >> PCI_REBAR_CTRL_NBAR_SHIFT) & PCI_REBAR_CTRL_NBAR_MASK;
+
+        for i in 0..num_bars {
+            let offset = cap_offset + 4 + i * 8;
+            let cap = self.vfio_wrapper.read_config_dword(offset);
+            let ctrl = self.vfio_wrapper.read_config_dword(offset + 4);
+            let bar_index = ctrl & PCI_REBAR_CTRL_BAR_IDX_MASK;
+            let size = (ctrl >> PCI_REBAR_CTRL_BAR_SIZE_SHIFT) & PCI_REBAR_CTRL_BAR_SIZE_MASK;
+
+            let msix_bar = self.interrupt.msix.as_ref().is_some_and(|msix| {
+                msix.cap.table_bir() == bar_index || msix.cap.pba_bir() == bar_index
+            });
+            let supported = if msix_bar {
+                0
+            } else {
+                cap >> PCI_REBAR_CAP_SIZES_SHIFT
+            };
+
+            self.rebars.push(ResizableBar {
+                bar_index,
+                cap_offset: offset,
+                sizes: rebar_sizes(supported, size),
+                size,
+            });
+        }
+    }
+
+    // Returns the value of the Resizable BAR register at `reg_idx`, if any,
+    // reflecting the sizes the guest can pick and the one in use.
+    fn read_rebar_register(&self, reg_idx: usize) -> Option<u32> {
+        let offset = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u32;
+        let rebar = self
+            .rebars
+            .iter()
+            .find(|rebar| offset == rebar.cap_offset || offset == rebar.cap_offset + 4)?;
+
+        if offset == rebar.cap_offset {
+            Some(rebar.sizes << PCI_REBAR_CAP_SIZES_SHIFT)
+        } else {
+            // Only the BAR index and the number of BARs come from the device
+            let ctrl = self.vfio_wrapper.read_config_dword(offset);
+            Some((ctrl & 0xff) | (rebar.size << PCI_REBAR_CTRL_BAR_SIZE_SHIFT))
+        }
+    }
+
+    pub(crate) fn detect_bar_resize(&self, reg_idx: usize, data: &[u8]) -> Option<BarResizeParams> {
+        if data.len() != 4 {
+            return None;
+        }
+
+        let offset = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u32;
+        let rebar = self
+            .rebars
+            .iter()
+            .find(|rebar| offset == rebar.cap_offset + 4)?;
+
+        let size = (LittleEndian::read_u32(data) >> PCI_REBAR_CTRL_BAR_SIZE_SHIFT)
+            & PCI_REBAR_CTRL_BAR_SIZE_MASK;
+        if size == rebar.size {
+            return None;
+        }
+        if rebar.sizes & 1u32.checked_shl(size).unwrap_or(0) == 0 {
+            warn!(
+                "Ignoring unsupported size for BAR {}: {} MiB",
+                rebar.bar_index,
+                1u64 << size
+            );
+            return None;
+        }
+
+        let region = self
+            .mmio_regions
+            .iter()
+            .find(|region| region.index == rebar.bar_index)?;
+
+        Some(BarResizeParams {
+            old_base: region.start.raw_value(),
+            old_len: region.length,
+            new_len: 1 << (size + PCI_REBAR_MIN_SIZE_SHIFT),
+            region_type: region.type_,
+        })
+    }
+
+    pub(crate) fn resize_bar(
+        &mut self,
+        old_base: u64,
+        new_base: u64,
+        new_len: u64,
+    ) -> Result<(), io::Error> {
+        let region = self
+            .mmio_regions
+            .iter_mut()
+            .find(|region| region.start.raw_value() == old_base)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Couldn't find a BAR with base 0x{old_base:x}"),
+                )
+            })?;
+
+        self.configuration
+            .resize_bar(region.index as usize, new_base, new_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        region.start = GuestAddress(new_base);
+        region.length = new_len;
+
+        if let Some(rebar) = self
+            .rebars
+            .iter_mut()
+            .find(|rebar| rebar.bar_index == region.index)
+        {
+            rebar.size = new_len.trailing_zeros() - PCI_REBAR_MIN_SIZE_SHIFT;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn enable_intx(&mut self) -> Result<(), VfioPciError> {
         if let Some(intx) = &mut self.interrupt.intx {
             if !intx.enabled {
@@ -1180,6 +1336,12 @@ impl VfioCommon {
 
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_read_table(offset, data);
+            } else if let Some(rom) = self
+                .rom
+                .as_ref()
+                .filter(|_| region.index == VFIO_PCI_ROM_REGION_INDEX)
+            {
+                read_rom(rom, offset, data);
             } else {
                 self.vfio_wrapper.region_read(region.index, offset, data);
             }
@@ -1208,6 +1370,8 @@ impl VfioCommon {
             // If the MSI-X table is written to, we need to update our cache.
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_write_table(offset, data);
+            } else if region.index == VFIO_PCI_ROM_REGION_INDEX && self.rom.is_some() {
+                debug!("Ignoring write to the read-only option ROM");
             } else {
                 self.vfio_wrapper.region_write(region.index, offset, data);
             }
@@ -1247,6 +1411,16 @@ impl VfioCommon {
 
         let reg = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u64;
 
+        // The BARs are resized through detect_bar_resize(), the device
+        // keeping the sizes set up on the host.
+        if self
+            .rebars
+            .iter()
+            .any(|rebar| (rebar.cap_offset..rebar.cap_offset + 8).contains(&(reg as u32)))
+        {
+            return None;
+        }
+
         // If the MSI or MSI-X capabilities are accessed, we need to
         // update our local cache accordingly.
         // Depending on how the capabilities are modified, this could
@@ -1301,6 +1475,10 @@ impl VfioCommon {
             }
         }
 
+        if let Some(value) = self.read_rebar_register(reg_idx) {
+            return value;
+        }
+
         // Since we don't support passing multi-functions devices, we should
         // mask the multi-function bit, bit 7 of the Header Type byte on the
         // register 3.
@@ -1340,6 +1518,7 @@ impl VfioCommon {
             intx_state,
             msi_state,
             msix_state,
+            rebars: self.rebars.clone(),
         }
     }
 
@@ -1370,6 +1549,8 @@ impl VfioCommon {
             self.initialize_msix(msix.cap, msix.cap_offset, msix.bdf.into(), msix_state);
         }
 
+        self.rebars = state.rebars.clone();
+
         Ok(())
     }
 }
@@ -1432,13 +1613,14 @@ impl VfioPciDevice {
         memory_slot: Arc<dyn Fn() -> u32 + Send + Sync>,
         snapshot: Option<Snapshot>,
         x_nv_gpudirect_clique: Option<u8>,
+        rom: Option<Vec<u8>>,
     ) -> Result<Self, VfioPciError> {
         let device = Arc::new(device);
         device.reset();
 
         let vfio_wrapper = VfioDeviceWrapper::new(Arc::clone(&device));
 
-        let common = VfioCommon::new(
+        let mut common = VfioCommon::new(
             msi_interrupt_manager,
             legacy_interrupt_group,
             Arc::new(vfio_wrapper) as Arc<dyn Vfio>,
@@ -1447,6 +1629,7 @@ impl VfioPciDevice {
             vm_migration::snapshot_from_id(snapshot.as_ref(), VFIO_COMMON_ID),
             x_nv_gpudirect_clique,
         )?;
+        common.rom = rom;
 
         let vfio_pci_device = VfioPciDevice {
             id,
@@ -1561,6 +1744,11 @@ impl VfioPciDevice {
         let fd = self.device.as_raw_fd();
 
         for region in self.common.mmio_regions.iter_mut() {
+            // The injected option ROM is read through the BAR accesses.
+            if region.index == VFIO_PCI_ROM_REGION_INDEX && self.common.rom.is_some() {
+                continue;
+            }
+
             let region_flags = self.device.get_region_flags(region.index);
             if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
                 let mut prot = 0;
@@ -1589,16 +1777,25 @@ impl VfioPciDevice {
                     }
                 }
 
-                let mmap_size = self.device.get_region_size(region.index);
+                // A BAR resized below the size of the region only maps the
+                // beginning of it.
+                let mmap_size = self.device.get_region_size(region.index).min(region.length);
                 let mmap_offset = self.device.get_region_offset(region.index);
 
-                let sparse_areas = Self::generate_sparse_areas(
+                let sparse_areas: Vec<VfioRegionSparseMmapArea> = Self::generate_sparse_areas(
                     &caps,
                     region.index,
                     region.start.0,
                     mmap_size,
                     self.common.interrupt.msix.as_ref(),
-                )?;
+                )?
+                .into_iter()
+                .filter(|area| area.offset < mmap_size)
+                .map(|area| VfioRegionSparseMmapArea {
+                    offset: area.offset,
+                    size: area.size.min(mmap_size - area.offset),
+                })
+                .collect();
 
                 for area in sparse_areas.iter() {
                     // SAFETY: FFI call with correct arguments
@@ -1794,6 +1991,37 @@ const PCI_HEADER_TYPE_REG_INDEX: usize = 3;
 const PCI_CONFIG_BAR0_INDEX: usize = 4;
 // PCI ROM expansion BAR register index
 const PCI_ROM_EXP_BAR_INDEX: usize = 12;
+// Smallest PCI ROM expansion BAR, the address being decoded from bit 11
+const PCI_ROM_BAR_MIN_SIZE: u64 = 0x800;
+// Smallest BAR size of the Resizable BAR capability, 1 MiB.
+const PCI_REBAR_MIN_SIZE_SHIFT: u32 = 20;
+// Supported sizes in the Resizable BAR Capability register.
+const PCI_REBAR_CAP_SIZES_SHIFT: u32 = 4;
+// Fields of the Resizable BAR Control register.
+const PCI_REBAR_CTRL_BAR_IDX_MASK: u32 = 0x7;
+const PCI_REBAR_CTRL_NBAR_SHIFT: u32 = 5;
+const PCI_REBAR_CTRL_NBAR_MASK: u32 = 0x7;
+const PCI_REBAR_CTRL_BAR_SIZE_SHIFT: u32 = 8;
+const PCI_REBAR_CTRL_BAR_SIZE_MASK: u32 = 0x3f;
+
+// Sizes out of the `supported` ones a BAR of `size` can be resized to, the
+// region of the device not growing past the size set up on the host.
+fn rebar_sizes(supported: u32, size: u32) -> u32 {
+    let current = 1u32.checked_shl(size).unwrap_or(0);
+    let up_to_current = 1u32
+        .checked_shl(size + 1)
+        .map_or(u32::MAX, |sizes| sizes - 1);
+    (supported | current) & up_to_current
+}
+
+// Reads the option ROM, the BAR being padded with zeros past its end.
+fn read_rom(rom: &[u8], offset: u64, data: &mut [u8]) {
+    data.fill(0);
+    if let Some(rom) = usize::try_from(offset).ok().and_then(|o| rom.get(o..)) {
+        let len = rom.len().min(data.len());
+        data[..len].copy_from_slice(&rom[..len]);
+    }
+}
 
 impl PciDevice for VfioPciDevice {
     fn allocate_bars(
@@ -1840,6 +2068,10 @@ impl PciDevice for VfioPciDevice {
             .detect_bar_reprogramming(reg_idx, data)
     }
 
+    fn detect_bar_resize(&mut self, reg_idx: usize, data: &[u8]) -> Option<BarResizeParams> {
+        self.common.detect_bar_resize(reg_idx, data)
+    }
+
     fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
         self.common.read_bar(base, offset, data)
     }
@@ -1895,6 +2127,20 @@ impl PciDevice for VfioPciDevice {
         Ok(())
     }
 
+    fn resize_bar(&mut self, old_base: u64, new_base: u64, new_len: u64) -> Result<(), io::Error> {
+        // The regions are mapped again, up to the new size of the BAR
+        self.unmap_mmio_regions();
+        for region in self.common.mmio_regions.iter_mut() {
+            region.user_memory_regions.clear();
+        }
+
+        // Mapped again at the same place should the BAR not be resized
+        let resized = self.common.resize_bar(old_base, new_base, new_len);
+        self.map_mmio_regions()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        resized
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
@@ -1999,3 +2245,31 @@ impl<M: GuestAddressSpace + Sync + Send> ExternalDmaMapping for VfioDmaMapping<M
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_rom() {
+        let rom = [0x55, 0xaa, 0x01, 0x02];
+        let mut data = [0xffu8; 4];
+
+        read_rom(&rom, 0, &mut data);
+        assert_eq!(data, [0x55, 0xaa, 0x01, 0x02]);
+        read_rom(&rom, 2, &mut data);
+        assert_eq!(data, [0x01, 0x02, 0, 0]);
+        read_rom(&rom, 0x700, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test]
+    fn test_rebar_sizes() {
+        // 256 MiB BAR supporting 1 MiB to 8 GiB: only sizes up to 256 MiB
+        assert_eq!(rebar_sizes(0x1fff, 8), 0x1ff);
+        // The size in use is always offered
+        assert_eq!(rebar_sizes(0, 8), 0x100);
+        assert_eq!(rebar_sizes(0x0fff_ffff, 31), 0x8fff_ffff);
+        assert_eq!(rebar_sizes(0x0fff_ffff, 40), 0x0fff_ffff);
+    }
+}
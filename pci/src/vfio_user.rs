@@ -4,7 +4,7 @@
 //
 
 use crate::vfio::{UserMemoryRegion, Vfio, VfioCommon, VfioError, VFIO_COMMON_ID};
-use crate::{BarReprogrammingParams, PciBarConfiguration, VfioPciError};
+use crate::{BarReprogrammingParams, BarResizeParams, PciBarConfiguration, VfioPciError};
 use crate::{PciBdf, PciDevice, PciDeviceError, PciSubclass};
 use hypervisor::HypervisorVmError;
 use std::any::Any;
@@ -143,13 +143,22 @@ impl VfioUserPciDevice {
                     prot |= libc::PROT_WRITE;
                 }
 
-                let mmaps = if sparse_areas.is_empty() {
+                // A BAR resized below the size of the region only maps the
+                // beginning of it.
+                let mmaps: Vec<vfio_region_sparse_mmap_area> = if sparse_areas.is_empty() {
                     vec![vfio_region_sparse_mmap_area {
                         offset: 0,
                         size: mmio_region.length,
                     }]
                 } else {
                     sparse_areas
+                        .into_iter()
+                        .filter(|area| area.offset < mmio_region.length)
+                        .map(|area| vfio_region_sparse_mmap_area {
+                            offset: area.offset,
+                            size: area.size.min(mmio_region.length - area.offset),
+                        })
+                        .collect()
                 };
 
                 for s in mmaps.iter() {
@@ -430,6 +439,10 @@ impl PciDevice for VfioUserPciDevice {
             .detect_bar_reprogramming(reg_idx, data)
     }
 
+    fn detect_bar_resize(&mut self, reg_idx: usize, data: &[u8]) -> Option<BarResizeParams> {
+        self.common.detect_bar_resize(reg_idx, data)
+    }
+
     fn write_config_register(
         &mut self,
         reg_idx: usize,
@@ -500,6 +513,29 @@ impl PciDevice for VfioUserPciDevice {
         Ok(())
     }
 
+    fn resize_bar(
+        &mut self,
+        old_base: u64,
+        new_base: u64,
+        new_len: u64,
+    ) -> Result<(), std::io::Error> {
+        info!(
+            "Resizing BAR 0x{:x} -> 0x{:x} (0x{:x})",
+            old_base, new_base, new_len
+        );
+        // The regions are mapped again, up to the new size of the BAR
+        self.unmap_mmio_regions();
+        for mmio_region in self.common.mmio_regions.iter_mut() {
+            mmio_region.user_memory_regions.clear();
+        }
+
+        // Mapped again at the same place should the BAR not be resized
+        let resized = self.common.resize_bar(old_base, new_base, new_len);
+        self.map_mmio_regions()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        resized
+    }
+
     fn id(&self) -> Option<String> {
         Some(self.id.clone())
     }
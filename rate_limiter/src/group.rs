@@ -4,7 +4,7 @@
 // Copyright 2023 Crusoe Energy Systems LLC
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{RateLimiter, TokenType};
+use crate::{BucketUpdate, RateLimiter, TokenType};
 use core::panic::AssertUnwindSafe;
 use std::fs::File;
 use std::io;
@@ -212,6 +212,11 @@ impl RateLimiterGroup {
         RateLimiterGroupHandle::new(self.inner.clone())
     }
 
+    /// Updates the parameters of the token buckets shared by the handles.
+    pub fn update_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
+        self.inner.rate_limiter.update_buckets(bytes, ops)
+    }
+
     /// Start a worker thread to broadcast an event to each RateLimiterGroupHandle
     /// when the RateLimiter becomes unblocked.
     pub fn start_thread(&mut self, exit_evt: EventFd) -> result::Result<(), Error> {
@@ -298,7 +303,9 @@ impl Drop for RateLimiterGroup {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::RateLimiterGroupHandle;
-    use crate::{group::RateLimiterGroup, TokenBucket, TokenType, REFILL_TIMER_INTERVAL_MS};
+    use crate::{
+        group::RateLimiterGroup, BucketUpdate, TokenBucket, TokenType, REFILL_TIMER_INTERVAL_MS,
+    };
     use std::{os::fd::AsRawFd, thread, time::Duration};
     use vmm_sys_util::eventfd::EventFd;
 
@@ -331,6 +338,21 @@ pub(crate) mod tests {
         assert_eq!(ops.budget(), 1003);
     }
 
+    #[test]
+    fn test_rate_limiter_group_update_buckets() {
+        let l = RateLimiterGroup::new("test", 1000, 0, 1000, 10, 0, 1000).unwrap();
+        let h = l.new_handle().unwrap();
+
+        l.update_buckets(
+            BucketUpdate::Update(TokenBucket::new(2000, 0, 500).unwrap()),
+            BucketUpdate::Disabled,
+        );
+        let bw = h.bandwidth().unwrap();
+        assert_eq!(bw.capacity(), 2000);
+        assert_eq!(bw.refill_time_ms(), 500);
+        assert!(h.ops().is_none());
+    }
+
     #[test]
     fn test_rate_limiter_group_manual_replenish() {
         // rate limiter with limit of 1000 bytes/s and 1000 ops/s
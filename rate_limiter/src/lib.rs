@@ -481,7 +481,7 @@ impl RateLimiter {
 
     /// Updates the parameters of the token buckets associated with this RateLimiter.
     // TODO: Please note that, right now, the buckets become full after being updated.
-    pub fn update_buckets(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
+    pub fn update_buckets(&self, bytes: BucketUpdate, ops: BucketUpdate) {
         let mut guard = self.inner.lock().unwrap();
         match bytes {
             BucketUpdate::Disabled => guard.bandwidth = None,
@@ -871,7 +871,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_update_buckets() {
-        let mut x = RateLimiter::new(1000, 2000, 1000, 10, 20, 1000).unwrap();
+        let x = RateLimiter::new(1000, 2000, 1000, 10, 20, 1000).unwrap();
 
         let initial_bw = x.bandwidth();
         let initial_ops = x.ops();
@@ -6,14 +6,29 @@
 use api_client::simple_api_command;
 use api_client::simple_api_command_with_fds;
 use api_client::simple_api_full_command;
+use api_client::simple_api_full_command_and_response;
 use api_client::Error as ApiClientError;
-use clap::{Arg, ArgAction, ArgMatches, Command};
+use clap::error::ErrorKind;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap_complete::Shell;
 use option_parser::{ByteSized, ByteSizedParseError};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::io::Read;
+use std::io::{self, Read};
 use std::marker::PhantomData;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "dbus_api")]
+use vmm::api::dbus::args::{
+    DeviceArgs, DiskArgs, FsArgs, NetArgs, PmemArgs, UsbArgs, UserDeviceArgs, VdpaArgs, VsockArgs,
+};
+use vmm::config::{
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UsbConfig, UserDeviceConfig,
+    VdpaConfig, VsockConfig,
+};
 #[cfg(feature = "dbus_api")]
 use zbus::{dbus_proxy, zvariant::Optional};
 
@@ -24,6 +39,8 @@ enum Error {
     HttpApiClient(ApiClientError),
     #[cfg(feature = "dbus_api")]
     DBusApiClient(zbus::Error),
+    #[cfg(feature = "dbus_api")]
+    DBusFds,
     InvalidCpuCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
@@ -35,9 +52,24 @@ enum Error {
     AddUserDeviceConfig(vmm::config::Error),
     AddVdpaConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    AddUsbConfig(vmm::config::Error),
+    InvalidReplacementDeviceType(String),
+    InvalidGuestAddress(std::num::ParseIntError),
+    InvalidIntrospectSize(ByteSizedParseError),
+    InvalidIntrospectPattern(String),
+    InvalidDumpMemoryLength(ByteSizedParseError),
+    InvalidSysRqKey(String),
+    InvalidKeyFd(std::num::ParseIntError),
+    InvalidStreamFd(std::num::ParseIntError),
     Restore(vmm::config::Error),
     ReadingStdin(std::io::Error),
     ReadingFile(std::io::Error),
+    InvalidSocketGlob(String),
+    ReadingSocketDirectory(std::io::Error),
+    NoMatchingSocket(String),
+    UnsupportedBulkCommand(String),
+    ConnectingSocket(std::io::Error),
+    InvalidWatchInterval(String),
 }
 
 impl fmt::Display for Error {
@@ -47,6 +79,8 @@ impl fmt::Display for Error {
             HttpApiClient(e) => e.fmt(f),
             #[cfg(feature = "dbus_api")]
             DBusApiClient(e) => write!(f, "Error D-Bus proxy: {e}"),
+            #[cfg(feature = "dbus_api")]
+            DBusFds => write!(f, "File descriptors can't be passed through the D-Bus API"),
             InvalidCpuCount(e) => write!(f, "Error parsing CPU count: {e}"),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {e:?}"),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {e:?}"),
@@ -58,9 +92,30 @@ impl fmt::Display for Error {
             AddUserDeviceConfig(e) => write!(f, "Error parsing user device syntax: {e}"),
             AddVdpaConfig(e) => write!(f, "Error parsing vDPA device syntax: {e}"),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {e}"),
+            AddUsbConfig(e) => write!(f, "Error parsing USB device syntax: {e}"),
+            InvalidReplacementDeviceType(t) => write!(f, "Invalid replacement device type: {t}"),
+            InvalidGuestAddress(e) => write!(f, "Error parsing guest physical address: {e}"),
+            InvalidIntrospectSize(e) => write!(f, "Error parsing introspection size: {e:?}"),
+            InvalidIntrospectPattern(p) => write!(f, "Invalid hexadecimal pattern: {p}"),
+            InvalidDumpMemoryLength(e) => write!(f, "Error parsing memory dump length: {e:?}"),
+            InvalidSysRqKey(k) => write!(f, "Invalid SysRq key {k:?}, expected a single character"),
+            InvalidKeyFd(e) => write!(f, "Error parsing snapshot key file descriptor: {e}"),
+            InvalidStreamFd(e) => write!(f, "Error parsing snapshot stream file descriptor: {e}"),
             Restore(e) => write!(f, "Error parsing restore syntax: {e}"),
             ReadingStdin(e) => write!(f, "Error reading from stdin: {e}"),
             ReadingFile(e) => write!(f, "Error reading from file: {e}"),
+            InvalidSocketGlob(g) => write!(
+                f,
+                "Invalid socket glob {g} (wildcards are only supported in the file name)"
+            ),
+            ReadingSocketDirectory(e) => write!(f, "Error reading socket directory: {e}"),
+            NoMatchingSocket(g) => write!(f, "No API socket matching {g}"),
+            UnsupportedBulkCommand(c) => write!(
+                f,
+                "Command {c} can't be run against several VMMs (only info, counters and ping can)"
+            ),
+            ConnectingSocket(e) => write!(f, "Error opening HTTP socket: {e}"),
+            InvalidWatchInterval(i) => write!(f, "Invalid watch interval {i}"),
         }
     }
 }
@@ -75,27 +130,54 @@ enum TargetApi<'a> {
 #[dbus_proxy(name = "org.cloudhypervisor.DBusApi1", assume_defaults = false)]
 trait DBusApi1 {
     fn vmm_ping(&self) -> zbus::Result<String>;
+    fn vmm_threads(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_host_info(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_capabilities(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_fds(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_checkpoint(&self, checkpoint_data: &str) -> zbus::Result<()>;
+    fn vmm_post_restore(&self) -> zbus::Result<()>;
+    fn vmm_profile(&self) -> zbus::Result<Optional<String>>;
+    fn vmm_pprof(&self, vmm_pprof_data: &str) -> zbus::Result<()>;
     fn vmm_shutdown(&self) -> zbus::Result<()>;
-    fn vm_add_device(&self, device_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_disk(&self, disk_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_fs(&self, fs_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_net(&self, net_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_pmem(&self, pmem_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_user_device(&self, vm_add_user_device: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_vdpa(&self, vdpa_config: &str) -> zbus::Result<Optional<String>>;
-    fn vm_add_vsock(&self, vsock_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_device(&self, device_config: &DeviceArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_disk(&self, disk_config: &DiskArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_fs(&self, fs_config: &FsArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_net(&self, net_config: &NetArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_pmem(&self, pmem_config: &PmemArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_user_device(
+        &self,
+        vm_add_user_device: &UserDeviceArgs,
+    ) -> zbus::Result<Optional<String>>;
+    fn vm_add_vdpa(&self, vdpa_config: &VdpaArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_vsock(&self, vsock_config: &VsockArgs) -> zbus::Result<Optional<String>>;
+    fn vm_add_usb(&self, usb_config: &UsbArgs) -> zbus::Result<Optional<String>>;
     fn vm_boot(&self) -> zbus::Result<()>;
+    fn vm_boot_with_identity(&self, vm_identity_config: &str) -> zbus::Result<()>;
     fn vm_coredump(&self, vm_coredump_data: &str) -> zbus::Result<()>;
     fn vm_counters(&self) -> zbus::Result<Optional<String>>;
+    fn vm_vcpu_stats(&self) -> zbus::Result<Optional<String>>;
+    fn vm_migration_blockers(&self) -> zbus::Result<Optional<String>>;
+    fn vm_capabilities(&self) -> zbus::Result<Optional<String>>;
+    fn vm_device_tree(&self) -> zbus::Result<Optional<String>>;
+    fn vm_devices(&self) -> zbus::Result<Optional<String>>;
     fn vm_create(&self, vm_config: &str) -> zbus::Result<()>;
     fn vm_delete(&self) -> zbus::Result<()>;
     fn vm_info(&self) -> zbus::Result<String>;
     fn vm_pause(&self) -> zbus::Result<()>;
+    fn vm_nmi(&self) -> zbus::Result<()>;
     fn vm_power_button(&self) -> zbus::Result<()>;
+    fn vm_suspend_to_ram(&self) -> zbus::Result<()>;
     fn vm_reboot(&self) -> zbus::Result<()>;
     fn vm_remove_device(&self, vm_remove_device: &str) -> zbus::Result<()>;
+    fn vm_replace_device(&self, vm_replace_device: &str) -> zbus::Result<()>;
+    fn vm_sysrq(&self, vm_sysrq: &str) -> zbus::Result<()>;
+    fn vm_screenshot(&self, vm_screenshot_data: &str) -> zbus::Result<()>;
+    fn vm_dump_memory(&self, vm_dump_memory_data: &str) -> zbus::Result<()>;
     fn vm_resize(&self, vm_resize: &str) -> zbus::Result<()>;
     fn vm_resize_zone(&self, vm_resize_zone: &str) -> zbus::Result<()>;
+    fn vm_set_boot_params(&self, vm_set_boot_params: &str) -> zbus::Result<()>;
+    fn vm_rekey(&self, vm_rekey_data: &str) -> zbus::Result<()>;
+    fn vm_update_config(&self, vm_update_config: &str) -> zbus::Result<()>;
     fn vm_restore(&self, restore_config: &str) -> zbus::Result<()>;
     fn vm_receive_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
     fn vm_send_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
@@ -119,6 +201,21 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .build()
     }
 
+    fn new_p2p_connection(socket: &str, path: &'a str) -> Result<Self, zbus::Error> {
+        let stream = UnixStream::connect(socket)?;
+        let connection = zbus::blocking::ConnectionBuilder::unix_stream(stream)
+            .p2p()
+            .build()?;
+
+        // There is no bus routing the messages on a peer-to-peer connection,
+        // so the destination is only there to fill in the message header.
+        Self::builder(&connection)
+            .destination("org.cloudhypervisor.DBusApi")?
+            .path(path)?
+            .cache_properties(zbus::CacheProperties::No)
+            .build()
+    }
+
     fn print_response(&self, result: zbus::Result<Optional<String>>) -> ApiResult {
         result
             .map(|ret| {
@@ -139,42 +236,51 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vmm_shutdown().map_err(Error::DBusApiClient)
     }
 
-    fn api_vm_add_device(&self, device_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_device(device_config))
+    fn api_vm_add_device(&self, device_config: DeviceConfig) -> ApiResult {
+        self.print_response(self.vm_add_device(&device_config.into()))
     }
 
-    fn api_vm_add_disk(&self, disk_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_disk(disk_config))
+    fn api_vm_add_disk(&self, disk_config: DiskConfig) -> ApiResult {
+        self.print_response(self.vm_add_disk(&disk_config.into()))
     }
 
-    fn api_vm_add_fs(&self, fs_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_fs(fs_config))
+    fn api_vm_add_fs(&self, fs_config: FsConfig) -> ApiResult {
+        self.print_response(self.vm_add_fs(&fs_config.into()))
     }
 
-    fn api_vm_add_net(&self, net_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_net(net_config))
+    fn api_vm_add_net(&self, net_config: NetConfig) -> ApiResult {
+        self.print_response(self.vm_add_net(&net_config.into()))
     }
 
-    fn api_vm_add_pmem(&self, pmem_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_pmem(pmem_config))
+    fn api_vm_add_pmem(&self, pmem_config: PmemConfig) -> ApiResult {
+        self.print_response(self.vm_add_pmem(&pmem_config.into()))
     }
 
-    fn api_vm_add_user_device(&self, vm_add_user_device: &str) -> ApiResult {
-        self.print_response(self.vm_add_user_device(vm_add_user_device))
+    fn api_vm_add_user_device(&self, vm_add_user_device: UserDeviceConfig) -> ApiResult {
+        self.print_response(self.vm_add_user_device(&vm_add_user_device.into()))
     }
 
-    fn api_vm_add_vdpa(&self, vdpa_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_vdpa(vdpa_config))
+    fn api_vm_add_vdpa(&self, vdpa_config: VdpaConfig) -> ApiResult {
+        self.print_response(self.vm_add_vdpa(&vdpa_config.into()))
     }
 
-    fn api_vm_add_vsock(&self, vsock_config: &str) -> ApiResult {
-        self.print_response(self.vm_add_vsock(vsock_config))
+    fn api_vm_add_vsock(&self, vsock_config: VsockConfig) -> ApiResult {
+        self.print_response(self.vm_add_vsock(&vsock_config.into()))
+    }
+
+    fn api_vm_add_usb(&self, usb_config: UsbConfig) -> ApiResult {
+        self.print_response(self.vm_add_usb(&usb_config.into()))
     }
 
     fn api_vm_boot(&self) -> ApiResult {
         self.vm_boot().map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_boot_with_identity(&self, vm_identity_config: &str) -> ApiResult {
+        self.vm_boot_with_identity(vm_identity_config)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_coredump(&self, vm_coredump_data: &str) -> ApiResult {
         self.vm_coredump(vm_coredump_data)
             .map_err(Error::DBusApiClient)
@@ -184,6 +290,59 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.print_response(self.vm_counters())
     }
 
+    fn api_vmm_threads(&self) -> ApiResult {
+        self.print_response(self.vmm_threads())
+    }
+
+    fn api_vmm_host_info(&self) -> ApiResult {
+        self.print_response(self.vmm_host_info())
+    }
+
+    fn api_vmm_capabilities(&self) -> ApiResult {
+        self.print_response(self.vmm_capabilities())
+    }
+
+    fn api_vmm_fds(&self) -> ApiResult {
+        self.print_response(self.vmm_fds())
+    }
+
+    fn api_vmm_checkpoint(&self, checkpoint_data: &str) -> ApiResult {
+        self.vmm_checkpoint(checkpoint_data)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vmm_post_restore(&self) -> ApiResult {
+        self.vmm_post_restore().map_err(Error::DBusApiClient)
+    }
+
+    fn api_vmm_profile(&self) -> ApiResult {
+        self.print_response(self.vmm_profile())
+    }
+
+    fn api_vmm_pprof(&self, vmm_pprof_data: &str) -> ApiResult {
+        self.vmm_pprof(vmm_pprof_data).map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_vcpu_stats(&self) -> ApiResult {
+        self.print_response(self.vm_vcpu_stats())
+    }
+
+    fn api_vm_migration_blockers(&self) -> ApiResult {
+        self.print_response(self.vm_migration_blockers())
+    }
+
+    fn api_vm_capabilities(&self) -> ApiResult {
+        self.print_response(self.vm_capabilities())
+    }
+
+    fn api_vm_device_tree(&self) -> ApiResult {
+        self.print_response(self.vm_device_tree())
+    }
+
+    fn api_vm_devices(&self) -> ApiResult {
+        self.print_response(self.vm_devices())
+    }
+
     fn api_vm_create(&self, vm_config: &str) -> ApiResult {
         self.vm_create(vm_config).map_err(Error::DBusApiClient)
     }
@@ -202,10 +361,18 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vm_pause().map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_nmi(&self) -> ApiResult {
+        self.vm_nmi().map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_power_button(&self) -> ApiResult {
         self.vm_power_button().map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_suspend_to_ram(&self) -> ApiResult {
+        self.vm_suspend_to_ram().map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_reboot(&self) -> ApiResult {
         self.vm_reboot().map_err(Error::DBusApiClient)
     }
@@ -215,6 +382,25 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_sysrq(&self, vm_sysrq: &str) -> ApiResult {
+        self.vm_sysrq(vm_sysrq).map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_screenshot(&self, vm_screenshot_data: &str) -> ApiResult {
+        self.vm_screenshot(vm_screenshot_data)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_dump_memory(&self, vm_dump_memory_data: &str) -> ApiResult {
+        self.vm_dump_memory(vm_dump_memory_data)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_replace_device(&self, vm_replace_device: &str) -> ApiResult {
+        self.vm_replace_device(vm_replace_device)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_resize(&self, vm_resize: &str) -> ApiResult {
         self.vm_resize(vm_resize).map_err(Error::DBusApiClient)
     }
@@ -224,6 +410,20 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_set_boot_params(&self, vm_set_boot_params: &str) -> ApiResult {
+        self.vm_set_boot_params(vm_set_boot_params)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_rekey(&self, vm_rekey_data: &str) -> ApiResult {
+        self.vm_rekey(vm_rekey_data).map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_update_config(&self, vm_update_config: &str) -> ApiResult {
+        self.vm_update_config(vm_update_config)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_restore(&self, restore_config: &str) -> ApiResult {
         self.vm_restore(restore_config)
             .map_err(Error::DBusApiClient)
@@ -255,18 +455,180 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
 
 impl<'a> TargetApi<'a> {
     fn do_command(&mut self, matches: &ArgMatches) -> ApiResult {
+        if let Some((command @ ("info" | "counters"), args)) = matches.subcommand() {
+            if let Some(interval) = args.get_one::<String>("watch") {
+                return self.watch(command, interval);
+            }
+        }
+
         match self {
             Self::HttpApi(api_socket, _) => rest_api_do_command(matches, api_socket),
             #[cfg(feature = "dbus_api")]
             Self::DBusApi(proxy) => dbus_api_do_command(matches, proxy),
         }
     }
+
+    // Returns the response to the info or counters command rather than
+    // printing it, for the watch mode to render it.
+    fn query(&mut self, command: &str) -> Result<Option<String>, Error> {
+        match self {
+            Self::HttpApi(api_socket, _) => simple_api_full_command_and_response(
+                api_socket,
+                "GET",
+                &format!("vm.{command}"),
+                None,
+            )
+            .map_err(Error::HttpApiClient),
+            #[cfg(feature = "dbus_api")]
+            Self::DBusApi(proxy) => match command {
+                "info" => proxy.vm_info().map(Some),
+                "counters" => proxy.vm_counters().map(|r| (*r).clone()),
+                _ => unreachable!(),
+            }
+            .map_err(Error::DBusApiClient),
+        }
+    }
+
+    // Repeatedly runs the command, until interrupted, rendering the values of
+    // the response along with how much they changed since the last refresh.
+    fn watch(&mut self, command: &str, interval: &str) -> ApiResult {
+        let interval = vmm::config::parse_duration_ms(interval)
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis)
+            .ok_or_else(|| Error::InvalidWatchInterval(interval.to_owned()))?;
+
+        // SAFETY: FFI call with a valid file descriptor
+        let ansi = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let mut previous: Option<(Instant, BTreeMap<String, serde_json::Value>)> = None;
+        loop {
+            let response = self.query(command)?;
+            let now = Instant::now();
+            let mut values = BTreeMap::new();
+            if let Some(response) = response {
+                let response =
+                    serde_json::from_str(&response).unwrap_or(serde_json::Value::String(response));
+                flatten_json(String::new(), response, &mut values);
+            }
+
+            let previous_values = previous
+                .as_ref()
+                .map(|(time, values)| (now.duration_since(*time), values));
+            print!(
+                "{}",
+                render_watch(command, interval, previous_values, &values, ansi)
+            );
+
+            previous = Some((now, values));
+            thread::sleep(interval);
+        }
+    }
+}
+
+// Collects the leaves of a JSON value, keyed by their dotted path.
+fn flatten_json(
+    path: String,
+    value: serde_json::Value,
+    leaves: &mut BTreeMap<String, serde_json::Value>,
+) {
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                flatten_json(join(&key), value, leaves);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for (index, value) in array.into_iter().enumerate() {
+                flatten_json(join(&index.to_string()), value, leaves);
+            }
+        }
+        value => {
+            leaves.insert(path, value);
+        }
+    }
+}
+
+// Renders a top-style view of the values, along with the difference and the
+// rate of change of the numeric ones since the previous refresh. With `ansi`,
+// when writing to a terminal, the terminal is cleared first and the values
+// which changed are highlighted, the views being appended otherwise.
+fn render_watch(
+    command: &str,
+    interval: Duration,
+    previous: Option<(Duration, &BTreeMap<String, serde_json::Value>)>,
+    values: &BTreeMap<String, serde_json::Value>,
+    ansi: bool,
+) -> String {
+    let as_integer = |value: &serde_json::Value| {
+        value
+            .as_u64()
+            .map(i128::from)
+            .or_else(|| value.as_i64().map(i128::from))
+    };
+    let width = values.keys().map(|k| k.len()).max().unwrap_or(0).max(4);
+
+    let mut view = format!(
+        "{}{command} every {}ms\n\n{:<width$}  {:>20}  {:>14}  {:>14}\n",
+        if ansi { "\x1b[H\x1b[2J" } else { "\n" },
+        interval.as_millis(),
+        "NAME",
+        "VALUE",
+        "DELTA",
+        "RATE/s"
+    );
+    for (name, value) in values {
+        let old = previous.and_then(|(_, previous)| previous.get(name));
+        let changed = old.is_some_and(|old| old != value);
+        let (delta, rate) = match (previous, old.and_then(as_integer), as_integer(value)) {
+            (Some((elapsed, _)), Some(old), Some(new)) => {
+                let delta = new - old;
+                (
+                    format!("{delta:+}"),
+                    format!("{:.1}", delta as f64 / elapsed.as_secs_f64()),
+                )
+            }
+            _ => (String::new(), String::new()),
+        };
+        let value = match value {
+            serde_json::Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
+        let row = format!("{name:<width$}  {value:>20}  {delta:>14}  {rate:>14}");
+        if changed && ansi {
+            view.push_str(&format!("\x1b[7m{row}\x1b[0m\n"));
+        } else {
+            view.push_str(&format!("{row}\n"));
+        }
+    }
+
+    view
 }
 
 fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResult {
     match matches.subcommand_name() {
         Some("boot") => {
-            simple_api_command(socket, "PUT", "boot", None).map_err(Error::HttpApiClient)
+            let identity = matches
+                .subcommand_matches("boot")
+                .unwrap()
+                .get_one::<String>("identity");
+            simple_api_command(socket, "PUT", "boot", identity.map(|s| s.as_str()))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("rekey") => {
+            let rekey_data = matches
+                .subcommand_matches("rekey")
+                .unwrap()
+                .get_one::<String>("rekey_data")
+                .map(|s| s.as_str())
+                .unwrap_or("{}");
+            simple_api_command(socket, "PUT", "rekey", Some(rekey_data))
+                .map_err(Error::HttpApiClient)
         }
         Some("delete") => {
             simple_api_command(socket, "PUT", "delete", None).map_err(Error::HttpApiClient)
@@ -279,6 +641,9 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
         Some("power-button") => {
             simple_api_command(socket, "PUT", "power-button", None).map_err(Error::HttpApiClient)
         }
+        Some("suspend-to-ram") => {
+            simple_api_command(socket, "PUT", "suspend-to-ram", None).map_err(Error::HttpApiClient)
+        }
         Some("reboot") => {
             simple_api_command(socket, "PUT", "reboot", None).map_err(Error::HttpApiClient)
         }
@@ -291,13 +656,107 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
         Some("counters") => {
             simple_api_command(socket, "GET", "counters", None).map_err(Error::HttpApiClient)
         }
+        Some("vcpu-stats") => {
+            simple_api_command(socket, "GET", "vcpu-stats", None).map_err(Error::HttpApiClient)
+        }
+        Some("migration-blockers") => simple_api_command(socket, "GET", "migration-blockers", None)
+            .map_err(Error::HttpApiClient),
+        Some("capabilities") => {
+            simple_api_command(socket, "GET", "capabilities", None).map_err(Error::HttpApiClient)
+        }
+        Some("device-tree") => {
+            simple_api_command(socket, "GET", "device-tree", None).map_err(Error::HttpApiClient)
+        }
+        Some("devices") => {
+            simple_api_command(socket, "GET", "devices", None).map_err(Error::HttpApiClient)
+        }
         Some("ping") => {
             simple_api_full_command(socket, "GET", "vmm.ping", None).map_err(Error::HttpApiClient)
         }
+        Some("threads") => simple_api_full_command(socket, "GET", "vmm.threads", None)
+            .map_err(Error::HttpApiClient),
+        Some("host-info") => simple_api_full_command(socket, "GET", "vmm.host-info", None)
+            .map_err(Error::HttpApiClient),
+        Some("vmm-capabilities") => {
+            simple_api_full_command(socket, "GET", "vmm.capabilities", None)
+                .map_err(Error::HttpApiClient)
+        }
+        Some("fds") => {
+            simple_api_full_command(socket, "GET", "vmm.fds", None).map_err(Error::HttpApiClient)
+        }
+        Some("checkpoint") => {
+            let checkpoint_data =
+                checkpoint_data(matches.subcommand_matches("checkpoint").unwrap());
+            simple_api_full_command(
+                socket,
+                "PUT",
+                "vmm.checkpoint",
+                Some(&serde_json::to_string(&checkpoint_data).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("post-restore") => simple_api_full_command(socket, "PUT", "vmm.post-restore", None)
+            .map_err(Error::HttpApiClient),
+        Some("profile") => simple_api_full_command(socket, "GET", "vmm.profile", None)
+            .map_err(Error::HttpApiClient),
+        Some("pprof") => {
+            let pprof_data = pprof_data(matches.subcommand_matches("pprof").unwrap());
+            simple_api_full_command(socket, "PUT", "vmm.pprof", Some(&pprof_data))
+                .map_err(Error::HttpApiClient)
+        }
         Some("shutdown") => {
             simple_api_command(socket, "PUT", "shutdown", None).map_err(Error::HttpApiClient)
         }
         Some("nmi") => simple_api_command(socket, "PUT", "nmi", None).map_err(Error::HttpApiClient),
+        Some("sysrq") => {
+            let sysrq_data = sysrq_config(
+                matches
+                    .subcommand_matches("sysrq")
+                    .unwrap()
+                    .get_one::<String>("key")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "sysrq", Some(&sysrq_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("screenshot") => {
+            let screenshot_data = screenshot_config(
+                matches
+                    .subcommand_matches("screenshot")
+                    .unwrap()
+                    .get_one::<String>("path")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "screenshot", Some(&screenshot_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("dump-memory") => {
+            let dump_memory_data =
+                dump_memory_config(matches.subcommand_matches("dump-memory").unwrap())?;
+            simple_api_command(socket, "PUT", "dump-memory", Some(&dump_memory_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("introspect") => {
+            let introspect = introspect_config(
+                matches
+                    .subcommand_matches("introspect")
+                    .unwrap()
+                    .get_one::<String>("gpa")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("introspect")
+                    .unwrap()
+                    .get_one::<String>("size")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("introspect")
+                    .unwrap()
+                    .get_one::<String>("pattern")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "introspect", Some(&introspect))
+                .map_err(Error::HttpApiClient)
+        }
         Some("resize") => {
             let resize = resize_config(
                 matches
@@ -315,6 +774,10 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .unwrap()
                     .get_one::<String>("balloon")
                     .map(|x| x as &str),
+                matches
+                    .subcommand_matches("resize")
+                    .unwrap()
+                    .get_flag("next_boot"),
             )?;
             simple_api_command(socket, "PUT", "resize", Some(&resize)).map_err(Error::HttpApiClient)
         }
@@ -334,6 +797,20 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "resize-zone", Some(&resize_zone))
                 .map_err(Error::HttpApiClient)
         }
+        Some("set-boot-params") => {
+            let boot_params =
+                set_boot_params_config(matches.subcommand_matches("set-boot-params").unwrap());
+            simple_api_command(socket, "PUT", "set-boot-params", Some(&boot_params))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("update-config") => {
+            let config = matches
+                .subcommand_matches("update-config")
+                .unwrap()
+                .get_one::<String>("config")
+                .unwrap();
+            simple_api_command(socket, "PUT", "config", Some(config)).map_err(Error::HttpApiClient)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -342,8 +819,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-device", Some(&device_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-device",
+                Some(&serde_json::to_string(&device_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("remove-device") => {
             let remove_device_data = remove_device_config(
@@ -356,16 +838,36 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "remove-device", Some(&remove_device_data))
                 .map_err(Error::HttpApiClient)
         }
+        Some("replace-device") => {
+            let replace_device_matches = matches.subcommand_matches("replace-device").unwrap();
+            let replace_device_data = replace_device_config(
+                replace_device_matches.get_one::<String>("id").unwrap(),
+                replace_device_matches
+                    .get_one::<String>("device_type")
+                    .unwrap(),
+                replace_device_matches
+                    .get_one::<String>("device_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "replace-device", Some(&replace_device_data))
+                .map_err(Error::HttpApiClient)
+        }
         Some("add-disk") => {
-            let disk_config = add_disk_config(
+            let (disk_config, fds) = add_disk_config(
                 matches
                     .subcommand_matches("add-disk")
                     .unwrap()
                     .get_one::<String>("disk_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-disk", Some(&disk_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command_with_fds(
+                socket,
+                "PUT",
+                "add-disk",
+                Some(&serde_json::to_string(&disk_config).unwrap()),
+                fds,
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-fs") => {
             let fs_config = add_fs_config(
@@ -375,8 +877,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("fs_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-fs", Some(&fs_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-fs",
+                Some(&serde_json::to_string(&fs_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-pmem") => {
             let pmem_config = add_pmem_config(
@@ -386,8 +893,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("pmem_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-pmem", Some(&pmem_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-pmem",
+                Some(&serde_json::to_string(&pmem_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-net") => {
             let (net_config, fds) = add_net_config(
@@ -397,8 +909,14 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("net_config")
                     .unwrap(),
             )?;
-            simple_api_command_with_fds(socket, "PUT", "add-net", Some(&net_config), fds)
-                .map_err(Error::HttpApiClient)
+            simple_api_command_with_fds(
+                socket,
+                "PUT",
+                "add-net",
+                Some(&serde_json::to_string(&net_config).unwrap()),
+                fds,
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-user-device") => {
             let device_config = add_user_device_config(
@@ -408,8 +926,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-user-device", Some(&device_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-user-device",
+                Some(&serde_json::to_string(&device_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-vdpa") => {
             let vdpa_config = add_vdpa_config(
@@ -419,8 +942,13 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("vdpa_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-vdpa", Some(&vdpa_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-vdpa",
+                Some(&serde_json::to_string(&vdpa_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("add-vsock") => {
             let vsock_config = add_vsock_config(
@@ -430,30 +958,68 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
                     .get_one::<String>("vsock_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "add-vsock", Some(&vsock_config))
-                .map_err(Error::HttpApiClient)
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-vsock",
+                Some(&serde_json::to_string(&vsock_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
         }
-        Some("snapshot") => {
-            let snapshot_config = snapshot_config(
+        Some("add-usb") => {
+            let usb_config = add_usb_config(
                 matches
-                    .subcommand_matches("snapshot")
+                    .subcommand_matches("add-usb")
                     .unwrap()
-                    .get_one::<String>("snapshot_config")
+                    .get_one::<String>("usb_config")
                     .unwrap(),
-            );
-            simple_api_command(socket, "PUT", "snapshot", Some(&snapshot_config))
-                .map_err(Error::HttpApiClient)
+            )?;
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-usb",
+                Some(&serde_json::to_string(&usb_config).unwrap()),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("snapshot") => {
+            let mut snapshot_config =
+                snapshot_config(matches.subcommand_matches("snapshot").unwrap())?;
+            // The stream and the key are passed along with the request, their
+            // file descriptors being meaningless to the server side process.
+            let fds = snapshot_stream_fd(&snapshot_config.destination_url)?
+                .into_iter()
+                .chain(snapshot_config.key_fd.take())
+                .collect();
+            simple_api_command_with_fds(
+                socket,
+                "PUT",
+                "snapshot",
+                Some(&serde_json::to_string(&snapshot_config).unwrap()),
+                fds,
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("restore") => {
-            let restore_config = restore_config(
+            let mut restore_config = restore_config(
                 matches
                     .subcommand_matches("restore")
                     .unwrap()
                     .get_one::<String>("restore_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "restore", Some(&restore_config))
-                .map_err(Error::HttpApiClient)
+            let fds = snapshot_stream_fd(&restore_config.source_url.to_string_lossy())?
+                .into_iter()
+                .chain(restore_config.key_fd.take())
+                .collect();
+            simple_api_command_with_fds(
+                socket,
+                "PUT",
+                "restore",
+                Some(&serde_json::to_string(&restore_config).unwrap()),
+                fds,
+            )
+            .map_err(Error::HttpApiClient)
         }
         Some("coredump") => {
             let coredump_config = coredump_config(
@@ -514,16 +1080,78 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
 #[cfg(feature = "dbus_api")]
 fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>) -> ApiResult {
     match matches.subcommand_name() {
-        Some("boot") => proxy.api_vm_boot(),
+        Some("boot") => match matches
+            .subcommand_matches("boot")
+            .unwrap()
+            .get_one::<String>("identity")
+        {
+            Some(identity) => proxy.api_vm_boot_with_identity(identity),
+            None => proxy.api_vm_boot(),
+        },
+        Some("rekey") => proxy.api_vm_rekey(
+            matches
+                .subcommand_matches("rekey")
+                .unwrap()
+                .get_one::<String>("rekey_data")
+                .map(|s| s.as_str())
+                .unwrap_or("{}"),
+        ),
         Some("delete") => proxy.api_vm_delete(),
         Some("shutdown-vmm") => proxy.api_vmm_shutdown(),
         Some("resume") => proxy.api_vm_resume(),
         Some("power-button") => proxy.api_vm_power_button(),
+        Some("suspend-to-ram") => proxy.api_vm_suspend_to_ram(),
+        Some("nmi") => proxy.api_vm_nmi(),
+        Some("sysrq") => {
+            let sysrq_data = sysrq_config(
+                matches
+                    .subcommand_matches("sysrq")
+                    .unwrap()
+                    .get_one::<String>("key")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_sysrq(&sysrq_data)
+        }
+        Some("screenshot") => {
+            let screenshot_data = screenshot_config(
+                matches
+                    .subcommand_matches("screenshot")
+                    .unwrap()
+                    .get_one::<String>("path")
+                    .unwrap(),
+            );
+            proxy.api_vm_screenshot(&screenshot_data)
+        }
+        Some("dump-memory") => {
+            let dump_memory_data =
+                dump_memory_config(matches.subcommand_matches("dump-memory").unwrap())?;
+            proxy.api_vm_dump_memory(&dump_memory_data)
+        }
         Some("reboot") => proxy.api_vm_reboot(),
         Some("pause") => proxy.api_vm_pause(),
         Some("info") => proxy.api_vm_info(),
         Some("counters") => proxy.api_vm_counters(),
+        Some("vcpu-stats") => proxy.api_vm_vcpu_stats(),
+        Some("migration-blockers") => proxy.api_vm_migration_blockers(),
+        Some("capabilities") => proxy.api_vm_capabilities(),
+        Some("device-tree") => proxy.api_vm_device_tree(),
+        Some("devices") => proxy.api_vm_devices(),
         Some("ping") => proxy.api_vmm_ping(),
+        Some("threads") => proxy.api_vmm_threads(),
+        Some("host-info") => proxy.api_vmm_host_info(),
+        Some("vmm-capabilities") => proxy.api_vmm_capabilities(),
+        Some("fds") => proxy.api_vmm_fds(),
+        Some("checkpoint") => {
+            let checkpoint_data =
+                checkpoint_data(matches.subcommand_matches("checkpoint").unwrap());
+            proxy.api_vmm_checkpoint(&serde_json::to_string(&checkpoint_data).unwrap())
+        }
+        Some("post-restore") => proxy.api_vmm_post_restore(),
+        Some("profile") => proxy.api_vmm_profile(),
+        Some("pprof") => {
+            let pprof_data = pprof_data(matches.subcommand_matches("pprof").unwrap());
+            proxy.api_vmm_pprof(&pprof_data)
+        }
         Some("shutdown") => proxy.api_vm_shutdown(),
         Some("resize") => {
             let resize = resize_config(
@@ -542,6 +1170,10 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .unwrap()
                     .get_one::<String>("balloon")
                     .map(|x| x as &str),
+                matches
+                    .subcommand_matches("resize")
+                    .unwrap()
+                    .get_flag("next_boot"),
             )?;
             proxy.api_vm_resize(&resize)
         }
@@ -560,6 +1192,19 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_resize_zone(&resize_zone)
         }
+        Some("set-boot-params") => {
+            let boot_params =
+                set_boot_params_config(matches.subcommand_matches("set-boot-params").unwrap());
+            proxy.api_vm_set_boot_params(&boot_params)
+        }
+        Some("update-config") => {
+            let config = matches
+                .subcommand_matches("update-config")
+                .unwrap()
+                .get_one::<String>("config")
+                .unwrap();
+            proxy.api_vm_update_config(config)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -568,7 +1213,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_device(&device_config)
+            proxy.api_vm_add_device(device_config)
         }
         Some("remove-device") => {
             let remove_device_data = remove_device_config(
@@ -580,15 +1225,31 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             );
             proxy.api_vm_remove_device(&remove_device_data)
         }
+        Some("replace-device") => {
+            let replace_device_matches = matches.subcommand_matches("replace-device").unwrap();
+            let replace_device_data = replace_device_config(
+                replace_device_matches.get_one::<String>("id").unwrap(),
+                replace_device_matches
+                    .get_one::<String>("device_type")
+                    .unwrap(),
+                replace_device_matches
+                    .get_one::<String>("device_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_replace_device(&replace_device_data)
+        }
         Some("add-disk") => {
-            let disk_config = add_disk_config(
+            let (disk_config, fds) = add_disk_config(
                 matches
                     .subcommand_matches("add-disk")
                     .unwrap()
                     .get_one::<String>("disk_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_disk(&disk_config)
+            if !fds.is_empty() {
+                return Err(Error::DBusFds);
+            }
+            proxy.api_vm_add_disk(disk_config)
         }
         Some("add-fs") => {
             let fs_config = add_fs_config(
@@ -598,7 +1259,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("fs_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_fs(&fs_config)
+            proxy.api_vm_add_fs(fs_config)
         }
         Some("add-pmem") => {
             let pmem_config = add_pmem_config(
@@ -608,17 +1269,20 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("pmem_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_pmem(&pmem_config)
+            proxy.api_vm_add_pmem(pmem_config)
         }
         Some("add-net") => {
-            let (net_config, _fds) = add_net_config(
+            let (net_config, fds) = add_net_config(
                 matches
                     .subcommand_matches("add-net")
                     .unwrap()
                     .get_one::<String>("net_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_net(&net_config)
+            if !fds.is_empty() {
+                return Err(Error::DBusFds);
+            }
+            proxy.api_vm_add_net(net_config)
         }
         Some("add-user-device") => {
             let device_config = add_user_device_config(
@@ -628,7 +1292,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("device_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_user_device(&device_config)
+            proxy.api_vm_add_user_device(device_config)
         }
         Some("add-vdpa") => {
             let vdpa_config = add_vdpa_config(
@@ -638,7 +1302,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("vdpa_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_vdpa(&vdpa_config)
+            proxy.api_vm_add_vdpa(vdpa_config)
         }
         Some("add-vsock") => {
             let vsock_config = add_vsock_config(
@@ -648,17 +1312,22 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("vsock_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_add_vsock(&vsock_config)
+            proxy.api_vm_add_vsock(vsock_config)
         }
-        Some("snapshot") => {
-            let snapshot_config = snapshot_config(
+        Some("add-usb") => {
+            let usb_config = add_usb_config(
                 matches
-                    .subcommand_matches("snapshot")
+                    .subcommand_matches("add-usb")
                     .unwrap()
-                    .get_one::<String>("snapshot_config")
+                    .get_one::<String>("usb_config")
                     .unwrap(),
-            );
-            proxy.api_vm_snapshot(&snapshot_config)
+            )?;
+            proxy.api_vm_add_usb(usb_config)
+        }
+        Some("snapshot") => {
+            // The server turns down the key, which can't be passed along.
+            let snapshot_config = snapshot_config(matches.subcommand_matches("snapshot").unwrap())?;
+            proxy.api_vm_snapshot(&serde_json::to_string(&snapshot_config).unwrap())
         }
         Some("restore") => {
             let restore_config = restore_config(
@@ -668,7 +1337,7 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
                     .get_one::<String>("restore_config")
                     .unwrap(),
             )?;
-            proxy.api_vm_restore(&restore_config)
+            proxy.api_vm_restore(&serde_json::to_string(&restore_config).unwrap())
         }
         Some("coredump") => {
             let coredump_config = coredump_config(
@@ -722,6 +1391,7 @@ fn resize_config(
     cpus: Option<&str>,
     memory: Option<&str>,
     balloon: Option<&str>,
+    next_boot: bool,
 ) -> Result<String, Error> {
     let desired_vcpus: Option<u8> = if let Some(cpus) = cpus {
         Some(cpus.parse().map_err(Error::InvalidCpuCount)?)
@@ -755,11 +1425,20 @@ fn resize_config(
         desired_vcpus,
         desired_ram,
         desired_balloon,
+        apply: apply_mode(next_boot),
     };
 
     Ok(serde_json::to_string(&resize).unwrap())
 }
 
+fn apply_mode(next_boot: bool) -> vmm::api::ApplyMode {
+    if next_boot {
+        vmm::api::ApplyMode::NextBoot
+    } else {
+        vmm::api::ApplyMode::Now
+    }
+}
+
 fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     let resize_zone = vmm::api::VmResizeZoneData {
         id: id.to_owned(),
@@ -772,19 +1451,82 @@ fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     Ok(serde_json::to_string(&resize_zone).unwrap())
 }
 
-fn add_device_config(config: &str) -> Result<String, Error> {
-    let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
-    let device_config = serde_json::to_string(&device_config).unwrap();
+fn parse_guest_address(gpa: &str) -> Result<u64, Error> {
+    match gpa.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => gpa.parse::<u64>(),
+    }
+    .map_err(Error::InvalidGuestAddress)
+}
 
-    Ok(device_config)
+fn introspect_config(gpa: &str, size: &str, pattern: &str) -> Result<String, Error> {
+    let gpa = parse_guest_address(gpa)?;
+    let size = size
+        .parse::<ByteSized>()
+        .map_err(Error::InvalidIntrospectSize)?
+        .0;
+
+    let invalid_pattern = || Error::InvalidIntrospectPattern(pattern.to_owned());
+    if pattern.is_empty() || pattern.len() % 2 != 0 {
+        return Err(invalid_pattern());
+    }
+    let pattern = (0..pattern.len())
+        .step_by(2)
+        .map(|i| {
+            pattern
+                .get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid_pattern)
+        })
+        .collect::<Result<Vec<u8>, Error>>()?;
+
+    let introspect = vmm::api::VmIntrospectData { gpa, size, pattern };
+
+    Ok(serde_json::to_string(&introspect).unwrap())
 }
 
-fn add_user_device_config(config: &str) -> Result<String, Error> {
-    let device_config =
-        vmm::config::UserDeviceConfig::parse(config).map_err(Error::AddUserDeviceConfig)?;
-    let device_config = serde_json::to_string(&device_config).unwrap();
+fn dump_memory_config(matches: &ArgMatches) -> Result<String, Error> {
+    let path = matches.get_one::<String>("path").unwrap();
+    let destination_url = if path.starts_with("file://") {
+        path.to_owned()
+    } else {
+        format!("file://{path}")
+    };
+    let offset = parse_guest_address(matches.get_one::<String>("offset").unwrap())?;
+    let length = matches
+        .get_one::<String>("length")
+        .unwrap()
+        .parse::<ByteSized>()
+        .map_err(Error::InvalidDumpMemoryLength)?
+        .0;
+
+    let dump_memory = vmm::api::VmDumpMemoryData {
+        destination_url,
+        offset,
+        length,
+    };
 
-    Ok(device_config)
+    Ok(serde_json::to_string(&dump_memory).unwrap())
+}
+
+fn set_boot_params_config(matches: &ArgMatches) -> String {
+    let boot_params = vmm::api::VmSetBootParamsData {
+        firmware: matches.get_one::<String>("firmware").map(PathBuf::from),
+        kernel: matches.get_one::<String>("kernel").map(PathBuf::from),
+        cmdline: matches.get_one::<String>("cmdline").cloned(),
+        initramfs: matches.get_one::<String>("initramfs").map(PathBuf::from),
+        apply: apply_mode(matches.get_flag("next_boot")),
+    };
+
+    serde_json::to_string(&boot_params).unwrap()
+}
+
+fn add_device_config(config: &str) -> Result<DeviceConfig, Error> {
+    DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)
+}
+
+fn add_user_device_config(config: &str) -> Result<UserDeviceConfig, Error> {
+    UserDeviceConfig::parse(config).map_err(Error::AddUserDeviceConfig)
 }
 
 fn remove_device_config(id: &str) -> String {
@@ -793,67 +1535,163 @@ fn remove_device_config(id: &str) -> String {
     serde_json::to_string(&remove_device_data).unwrap()
 }
 
-fn add_disk_config(config: &str) -> Result<String, Error> {
-    let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
-    let disk_config = serde_json::to_string(&disk_config).unwrap();
+fn sysrq_config(key: &str) -> Result<String, Error> {
+    let mut chars = key.chars();
+    let (Some(key), None) = (chars.next(), chars.next()) else {
+        return Err(Error::InvalidSysRqKey(key.to_owned()));
+    };
+    let sysrq_data = vmm::api::VmSysRqData { key };
 
-    Ok(disk_config)
+    Ok(serde_json::to_string(&sysrq_data).unwrap())
 }
 
-fn add_fs_config(config: &str) -> Result<String, Error> {
-    let fs_config = vmm::config::FsConfig::parse(config).map_err(Error::AddFsConfig)?;
-    let fs_config = serde_json::to_string(&fs_config).unwrap();
+fn replace_device_config(id: &str, device_type: &str, config: &str) -> Result<String, Error> {
+    use vmm::config::ReplacementDeviceConfig;
+
+    // The replacement is created after the request completes, which is why
+    // file descriptors can't be used to describe it.
+    let replacement = match device_type {
+        "disk" => {
+            let mut disk_config =
+                vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+            disk_config.fd = None;
+            ReplacementDeviceConfig::Disk(disk_config)
+        }
+        "fs" => ReplacementDeviceConfig::Fs(
+            vmm::config::FsConfig::parse(config).map_err(Error::AddFsConfig)?,
+        ),
+        "net" => {
+            let mut net_config =
+                vmm::config::NetConfig::parse(config).map_err(Error::AddNetConfig)?;
+            net_config.fds = None;
+            ReplacementDeviceConfig::Net(net_config)
+        }
+        "pmem" => ReplacementDeviceConfig::Pmem(
+            vmm::config::PmemConfig::parse(config).map_err(Error::AddPmemConfig)?,
+        ),
+        _ => return Err(Error::InvalidReplacementDeviceType(device_type.to_owned())),
+    };
+
+    let replace_device_data = vmm::api::VmReplaceDeviceData {
+        id: id.to_owned(),
+        replacement,
+    };
 
-    Ok(fs_config)
+    Ok(serde_json::to_string(&replace_device_data).unwrap())
 }
 
-fn add_pmem_config(config: &str) -> Result<String, Error> {
-    let pmem_config = vmm::config::PmemConfig::parse(config).map_err(Error::AddPmemConfig)?;
-    let pmem_config = serde_json::to_string(&pmem_config).unwrap();
+fn add_disk_config(config: &str) -> Result<(DiskConfig, Vec<i32>), Error> {
+    let mut disk_config = DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
 
-    Ok(pmem_config)
+    // Like for NetConfig, the file descriptor is taken out of DiskConfig on
+    // purpose since it would not be valid in the server side process.
+    let fds = disk_config.fd.take().into_iter().collect();
+
+    Ok((disk_config, fds))
+}
+
+fn add_fs_config(config: &str) -> Result<FsConfig, Error> {
+    FsConfig::parse(config).map_err(Error::AddFsConfig)
 }
 
-fn add_net_config(config: &str) -> Result<(String, Vec<i32>), Error> {
-    let mut net_config = vmm::config::NetConfig::parse(config).map_err(Error::AddNetConfig)?;
+fn add_pmem_config(config: &str) -> Result<PmemConfig, Error> {
+    PmemConfig::parse(config).map_err(Error::AddPmemConfig)
+}
+
+fn add_net_config(config: &str) -> Result<(NetConfig, Vec<i32>), Error> {
+    let mut net_config = NetConfig::parse(config).map_err(Error::AddNetConfig)?;
 
     // NetConfig is modified on purpose here by taking the list of file
     // descriptors out. Keeping the list and send it to the server side
     // process would not make any sense since the file descriptor may be
     // represented with different values.
     let fds = net_config.fds.take().unwrap_or_default();
-    let net_config = serde_json::to_string(&net_config).unwrap();
 
     Ok((net_config, fds))
 }
 
-fn add_vdpa_config(config: &str) -> Result<String, Error> {
-    let vdpa_config = vmm::config::VdpaConfig::parse(config).map_err(Error::AddVdpaConfig)?;
-    let vdpa_config = serde_json::to_string(&vdpa_config).unwrap();
+fn add_vdpa_config(config: &str) -> Result<VdpaConfig, Error> {
+    VdpaConfig::parse(config).map_err(Error::AddVdpaConfig)
+}
 
-    Ok(vdpa_config)
+fn add_vsock_config(config: &str) -> Result<VsockConfig, Error> {
+    VsockConfig::parse(config).map_err(Error::AddVsockConfig)
 }
 
-fn add_vsock_config(config: &str) -> Result<String, Error> {
-    let vsock_config = vmm::config::VsockConfig::parse(config).map_err(Error::AddVsockConfig)?;
-    let vsock_config = serde_json::to_string(&vsock_config).unwrap();
+fn add_usb_config(config: &str) -> Result<UsbConfig, Error> {
+    UsbConfig::parse(config).map_err(Error::AddUsbConfig)
+}
 
-    Ok(vsock_config)
+fn checkpoint_data(matches: &ArgMatches) -> vmm::api::VmmCheckpointData {
+    vmm::api::VmmCheckpointData {
+        destination_url: matches
+            .get_one::<String>("destination_url")
+            .unwrap()
+            .to_owned(),
+    }
 }
 
-fn snapshot_config(url: &str) -> String {
-    let snapshot_config = vmm::api::VmSnapshotConfig {
-        destination_url: String::from(url),
+fn pprof_data(matches: &ArgMatches) -> String {
+    let path = matches.get_one::<String>("path").unwrap();
+    let destination_url = if path.starts_with("file://") {
+        path.to_owned()
+    } else {
+        format!("file://{path}")
+    };
+    let kind = if matches.get_flag("heap") {
+        vmm::api::PprofKind::Heap
+    } else {
+        vmm::api::PprofKind::Cpu
+    };
+
+    let pprof = vmm::api::VmmPprofData {
+        kind,
+        duration_s: matches.get_one::<u64>("duration").copied(),
+        frequency: matches.get_one::<u32>("frequency").copied(),
+        destination_url,
     };
 
-    serde_json::to_string(&snapshot_config).unwrap()
+    serde_json::to_string(&pprof).unwrap()
 }
 
-fn restore_config(config: &str) -> Result<String, Error> {
-    let restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)?;
-    let restore_config = serde_json::to_string(&restore_config).unwrap();
+fn snapshot_config(matches: &ArgMatches) -> Result<vmm::api::VmSnapshotConfig, Error> {
+    let key_fd = matches
+        .get_one::<String>("key_fd")
+        .map(|fd| fd.parse::<i32>())
+        .transpose()
+        .map_err(Error::InvalidKeyFd)?;
+
+    Ok(vmm::api::VmSnapshotConfig {
+        destination_url: matches
+            .get_one::<String>("snapshot_config")
+            .unwrap()
+            .to_owned(),
+        key_fd,
+        compress: matches.get_flag("compress"),
+    })
+}
 
-    Ok(restore_config)
+// File descriptor of the snapshot streamed through `fd:`.
+fn snapshot_stream_fd(url: &str) -> Result<Option<i32>, Error> {
+    url.strip_prefix("fd:")
+        .map(|fd| fd.parse::<i32>())
+        .transpose()
+        .map_err(Error::InvalidStreamFd)
+}
+
+fn restore_config(config: &str) -> Result<vmm::config::RestoreConfig, Error> {
+    vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)
+}
+
+fn screenshot_config(path: &str) -> String {
+    let destination_url = if path.starts_with("file://") {
+        path.to_owned()
+    } else {
+        format!("file://{path}")
+    };
+    let screenshot_data = vmm::api::VmScreenshotData { destination_url };
+
+    serde_json::to_string(&screenshot_data).unwrap()
 }
 
 fn coredump_config(destination_url: &str) -> String {
@@ -894,18 +1732,136 @@ fn create_data(path: &str) -> Result<String, Error> {
     Ok(data)
 }
 
+// Maximum time waited for each VMM when running a command against several of
+// them, so that a hung VMM doesn't block the whole report.
+const BULK_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Matches a file name against a pattern made of '*' and '?' wildcards.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn expand_socket_glob(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = Path::new(pattern);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_pattern = path
+        .file_name()
+        .ok_or_else(|| Error::InvalidSocketGlob(pattern.to_owned()))?
+        .to_string_lossy();
+    if dir.to_string_lossy().contains(['*', '?']) {
+        return Err(Error::InvalidSocketGlob(pattern.to_owned()));
+    }
+
+    let mut sockets = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(Error::ReadingSocketDirectory)? {
+        let entry = entry.map_err(Error::ReadingSocketDirectory)?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // As in shells, hidden files only match an explicit leading dot
+        if name.starts_with('.') && !file_pattern.starts_with('.') {
+            continue;
+        }
+        if glob_match(file_pattern.as_bytes(), name.as_bytes()) {
+            sockets.push(dir.join(entry.file_name()));
+        }
+    }
+    if sockets.is_empty() {
+        return Err(Error::NoMatchingSocket(pattern.to_owned()));
+    }
+    sockets.sort();
+
+    Ok(sockets)
+}
+
+fn bulk_api_command(socket_path: &Path, full_command: &str) -> Result<serde_json::Value, Error> {
+    let mut socket = UnixStream::connect(socket_path).map_err(Error::ConnectingSocket)?;
+    socket
+        .set_read_timeout(Some(BULK_COMMAND_TIMEOUT))
+        .map_err(Error::ConnectingSocket)?;
+    socket
+        .set_write_timeout(Some(BULK_COMMAND_TIMEOUT))
+        .map_err(Error::ConnectingSocket)?;
+
+    let response = simple_api_full_command_and_response(&mut socket, "GET", full_command, None)
+        .map_err(Error::HttpApiClient)?;
+
+    Ok(response
+        .map(|r| serde_json::from_str(&r).unwrap_or(serde_json::Value::String(r)))
+        .unwrap_or(serde_json::Value::Null))
+}
+
+// Runs a read-only command against all the VMMs whose API socket matches the
+// glob, in parallel, and prints a JSON object mapping each socket path to the
+// response of its VMM, or to the error met while querying it.
+fn bulk_api_do_command(matches: &ArgMatches, pattern: &str) -> ApiResult {
+    let full_command = match matches.subcommand_name() {
+        Some("info") => "vm.info",
+        Some("counters") => "vm.counters",
+        Some("ping") => "vmm.ping",
+        Some(c) => return Err(Error::UnsupportedBulkCommand(c.to_owned())),
+        None => unreachable!(),
+    };
+
+    let sockets = expand_socket_glob(pattern)?;
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = sockets
+            .iter()
+            .map(|socket| s.spawn(move || bulk_api_command(socket, full_command)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut failures = 0;
+    let mut report = serde_json::Map::new();
+    for (socket, result) in sockets.iter().zip(results) {
+        let value = result.unwrap_or_else(|e| {
+            failures += 1;
+            serde_json::json!({ "error": e.to_string() })
+        });
+        report.insert(socket.to_string_lossy().into_owned(), value);
+    }
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if failures > 0 {
+        eprintln!("{failures} of {} VMMs failed to respond", sockets.len());
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
 fn main() {
-    let app = Command::new("ch-remote")
+    // The subcommand being left out with --generate-man, whether one was
+    // given is checked once the arguments are parsed.
+    let mut app = Command::new("ch-remote")
         .author(env!("CARGO_PKG_AUTHORS"))
         .version(env!("BUILD_VERSION"))
         .about("Remotely control a cloud-hypervisor VMM.")
         .arg_required_else_help(true)
-        .subcommand_required(true)
         .args([
             Arg::new("api-socket")
                 .long("api-socket")
                 .help("HTTP API socket path (UNIX domain socket).")
                 .num_args(1),
+            Arg::new("api-sockets")
+                .long("api-sockets")
+                .help(
+                    "Glob matching the HTTP API sockets of several VMMs (e.g. /run/ch/*.sock), \
+                    to run a read-only command (info, counters or ping) against all of them",
+                )
+                .num_args(1)
+                .conflicts_with("api-socket"),
             #[cfg(feature = "dbus_api")]
             Arg::new("dbus-service-name")
                 .long("dbus-service-name")
@@ -922,6 +1878,20 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .num_args(0)
                 .help("Use the system bus instead of a session bus"),
+            #[cfg(feature = "dbus_api")]
+            Arg::new("dbus-p2p-socket")
+                .long("dbus-p2p-socket")
+                .help("Peer-to-peer dbus socket path (UNIX domain socket)")
+                .num_args(1)
+                .conflicts_with_all(["dbus-service-name", "dbus-system-bus"]),
+            Arg::new("generate-man")
+                .long("generate-man")
+                .help("Write the man pages of ch-remote and its subcommands to a directory")
+                .value_name("dir")
+                .value_parser(value_parser!(PathBuf))
+                .num_args(1)
+                .exclusive(true)
+                .hide(true),
         ])
         .subcommand(
             Command::new("add-device").about("Add VFIO device").arg(
@@ -985,16 +1955,70 @@ fn main() {
                     .help(vmm::config::VsockConfig::SYNTAX),
             ),
         )
+        .subcommand(
+            Command::new("add-usb").about("Add host USB device").arg(
+                Arg::new("usb_config")
+                    .index(1)
+                    .help(vmm::config::UsbConfig::SYNTAX),
+            ),
+        )
         .subcommand(
             Command::new("remove-device")
                 .about("Remove VFIO device")
                 .arg(Arg::new("id").index(1).help("<device_id>")),
         )
-        .subcommand(Command::new("info").about("Info on the VM"))
-        .subcommand(Command::new("counters").about("Counters from the VM"))
+        .subcommand(
+            Command::new("replace-device")
+                .about("Replace a device, keeping its PCI slot")
+                .arg(Arg::new("id").index(1).help("<device_id>"))
+                .arg(Arg::new("device_type").index(2).help("<disk|fs|net|pmem>"))
+                .arg(
+                    Arg::new("device_config")
+                        .index(3)
+                        .help("Syntax of the matching add-<device_type> command"),
+                ),
+        )
+        .subcommand(
+            Command::new("info").about("Info on the VM").arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .help(
+                        "Refresh a view of the changes every <interval> (e.g. 500ms, 2s) until interrupted",
+                    )
+                    .value_name("interval")
+                    .num_args(0..=1)
+                    .default_missing_value("2s"),
+            ),
+        )
+        .subcommand(
+            Command::new("counters").about("Counters from the VM").arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .help(
+                        "Refresh a view of the changes every <interval> (e.g. 500ms, 2s) until interrupted",
+                    )
+                    .value_name("interval")
+                    .num_args(0..=1)
+                    .default_missing_value("2s"),
+            ),
+        )
+        .subcommand(Command::new("vcpu-stats").about("CPU time consumed by the vCPUs"))
+        .subcommand(
+            Command::new("migration-blockers")
+                .about("Devices and features preventing live migration or snapshot"),
+        )
+        .subcommand(
+            Command::new("capabilities").about("Hotplug limits and features supported by the VM"),
+        )
+        .subcommand(Command::new("device-tree").about("Devices of the VM and their PCI addresses"))
+        .subcommand(Command::new("devices").about("Devices which can be removed from the VM"))
         .subcommand(Command::new("pause").about("Pause the VM"))
         .subcommand(Command::new("reboot").about("Reboot the VM"))
         .subcommand(Command::new("power-button").about("Trigger a power button in the VM"))
+        .subcommand(
+            Command::new("suspend-to-ram")
+                .about("Trigger a sleep button in the VM, for the guest to suspend to idle"),
+        )
         .subcommand(
             Command::new("resize")
                 .about("Resize the VM")
@@ -1015,6 +2039,13 @@ fn main() {
                         .long("balloon")
                         .help("New balloon size in bytes (supports K/M/G suffix)")
                         .num_args(1),
+                )
+                .arg(
+                    Arg::new("next_boot")
+                        .long("next-boot")
+                        .help("Stage the change until the next boot of the VM")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -1033,17 +2064,89 @@ fn main() {
                         .num_args(1),
                 ),
         )
+        .subcommand(
+            Command::new("set-boot-params")
+                .about("Update the boot parameters of a created VM before booting it")
+                .arg(
+                    Arg::new("firmware")
+                        .long("firmware")
+                        .help("Path to the firmware")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("kernel")
+                        .long("kernel")
+                        .help("Path to the kernel")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("cmdline")
+                        .long("cmdline")
+                        .help("Kernel command line")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("initramfs")
+                        .long("initramfs")
+                        .help("Path to the initramfs")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("next_boot")
+                        .long("next-boot")
+                        .help("Stage the change until the next boot of the VM")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("update-config")
+                .about("Update the configuration fields which can be changed while the VM runs")
+                .arg(
+                    Arg::new("config")
+                        .index(1)
+                        .help("<partial VM configuration in JSON>"),
+                ),
+        )
         .subcommand(Command::new("resume").about("Resume the VM"))
-        .subcommand(Command::new("boot").about("Boot a created VM"))
+        .subcommand(
+            Command::new("boot").about("Boot a created VM").arg(
+                Arg::new("identity")
+                    .index(1)
+                    .help("<identity of a ready VM in JSON>"),
+            ),
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Give a paused VM restored from a snapshot an identity of its own")
+                .arg(
+                    Arg::new("rekey_data")
+                        .index(1)
+                        .help("<identity of the VM in JSON>"),
+                ),
+        )
         .subcommand(Command::new("delete").about("Delete a VM"))
         .subcommand(Command::new("shutdown").about("Shutdown the VM"))
         .subcommand(
             Command::new("snapshot")
                 .about("Create a snapshot from VM")
                 .arg(
-                    Arg::new("snapshot_config")
-                        .index(1)
-                        .help("<destination_url>"),
+                    Arg::new("snapshot_config").index(1).help(
+                        "<destination_url> (file://<dir>, fd:<fd> or http(s)://<host>/<path>)",
+                    ),
+                )
+                .arg(
+                    Arg::new("key_fd")
+                        .long("key-fd")
+                        .help("File descriptor the key to encrypt the snapshot with is read from")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .help("Compress the guest memory")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -1091,9 +2194,168 @@ fn main() {
         )
         .subcommand(Command::new("ping").about("Ping the VMM to check for API server availability"))
         .subcommand(Command::new("shutdown-vmm").about("Shutdown the VMM"))
-        .subcommand(Command::new("nmi").about("Trigger NMI"));
+        .subcommand(Command::new("threads").about("List the threads of the VMM"))
+        .subcommand(
+            Command::new("host-info")
+                .about("Host NUMA topology, huge pages, IOMMU groups and capabilities"),
+        )
+        .subcommand(
+            Command::new("vmm-capabilities")
+                .about("API endpoints, compiled-in features and hypervisor of the VMM"),
+        )
+        .subcommand(
+            Command::new("fds")
+                .about("List the file descriptors of the VMM and whether CRIU can checkpoint it"),
+        )
+        .subcommand(
+            Command::new("checkpoint")
+                .about("Snapshot and delete the VM for CRIU to checkpoint the VMM")
+                .arg(
+                    Arg::new("destination_url")
+                        .index(1)
+                        .required(true)
+                        .help("<destination_url> (file://<dir>)"),
+                ),
+        )
+        .subcommand(
+            Command::new("post-restore")
+                .about("Restore the VM checkpointed along with the VMM once CRIU restored it"),
+        )
+        .subcommand(
+            Command::new("profile").about("Memory and CPU usage of the VMM and its threads"),
+        )
+        .subcommand(
+            Command::new("pprof")
+                .about("Write a pprof CPU or heap profile of the VMM to a file")
+                .arg(Arg::new("path").index(1).required(true).help("<file_path>"))
+                .arg(
+                    Arg::new("heap")
+                        .long("heap")
+                        .help("Profile the live heap allocations instead of the CPU usage")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("duration")
+                        .long("duration")
+                        .help("How long the CPU usage is sampled for, in seconds")
+                        .num_args(1)
+                        .value_parser(value_parser!(u64))
+                        .conflicts_with("heap"),
+                )
+                .arg(
+                    Arg::new("frequency")
+                        .long("frequency")
+                        .help("How many times per second the CPU usage is sampled")
+                        .num_args(1)
+                        .value_parser(value_parser!(u32))
+                        .conflicts_with("heap"),
+                ),
+        )
+        .subcommand(Command::new("nmi").about("Trigger NMI"))
+        .subcommand(
+            Command::new("sysrq")
+                .about("Send a magic SysRq to the VM over its serial port")
+                .arg(
+                    Arg::new("key")
+                        .index(1)
+                        .required(true)
+                        .help("SysRq key, e.g. c to crash the guest or t to dump its tasks"),
+                ),
+        )
+        .subcommand(
+            Command::new("screenshot")
+                .about("Capture the ramfb framebuffer of the VM as a PNG image")
+                .arg(Arg::new("path").index(1).required(true).help("<file_path>")),
+        )
+        .subcommand(
+            Command::new("dump-memory")
+                .about("Write a raw dump of a guest physical memory range to a file")
+                .arg(Arg::new("path").index(1).required(true).help("<file_path>"))
+                .arg(
+                    Arg::new("offset")
+                        .long("offset")
+                        .help("Guest physical address to start from (decimal or 0x prefixed)")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("length")
+                        .long("length")
+                        .help("Size of the range to dump in bytes (supports K/M/G suffix)")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("introspect")
+                .about("Scan guest memory for a byte pattern")
+                .arg(
+                    Arg::new("gpa")
+                        .long("gpa")
+                        .help("Guest physical address to start from (decimal or 0x prefixed)")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("Size of the range to scan in bytes (supports K/M/G suffix)")
+                        .num_args(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("pattern")
+                        .long("pattern")
+                        .help("Hexadecimal byte pattern to look for, e.g. 7f454c46")
+                        .num_args(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Print the completion script of ch-remote for a shell")
+                .arg(
+                    Arg::new("shell")
+                        .index(1)
+                        .required(true)
+                        .value_parser(value_parser!(Shell))
+                        .help("<shell>"),
+                ),
+        );
 
-    let matches = app.get_matches();
+    let matches = app.get_matches_mut();
+
+    if let Some(dir) = matches.get_one::<PathBuf>("generate-man") {
+        if let Err(e) = clap_mangen::generate_to(app, dir) {
+            eprintln!("Error generating the man pages: {e}");
+            process::exit(1)
+        }
+        return;
+    }
+
+    match matches.subcommand() {
+        Some(("completions", args)) => {
+            let shell = *args.get_one::<Shell>("shell").unwrap();
+            clap_complete::generate(shell, &mut app, "ch-remote", &mut io::stdout());
+            return;
+        }
+        Some(_) => {}
+        None => app
+            .error(
+                ErrorKind::MissingSubcommand,
+                "A subcommand is required, see --help",
+            )
+            .exit(),
+    }
+
+    if let Some(pattern) = matches.get_one::<String>("api-sockets") {
+        if let Err(e) = bulk_api_do_command(&matches, pattern) {
+            eprintln!("Error running command: {e}");
+            process::exit(1)
+        }
+        return;
+    }
 
     let mut target_api = match (
         matches.get_one::<String>("api-socket"),
@@ -1101,6 +2363,8 @@ fn main() {
         matches.get_one::<String>("dbus-service-name"),
         #[cfg(feature = "dbus_api")]
         matches.get_one::<String>("dbus-object-path"),
+        #[cfg(feature = "dbus_api")]
+        matches.get_one::<String>("dbus-p2p-socket"),
     ) {
         #[cfg(not(feature = "dbus_api"))]
         (Some(api_sock),) => TargetApi::HttpApi(
@@ -1111,7 +2375,7 @@ fn main() {
             PhantomData,
         ),
         #[cfg(feature = "dbus_api")]
-        (Some(api_sock), None, None) => TargetApi::HttpApi(
+        (Some(api_sock), None, None, None) => TargetApi::HttpApi(
             UnixStream::connect(api_sock).unwrap_or_else(|e| {
                 eprintln!("Error opening HTTP socket: {e}");
                 process::exit(1)
@@ -1119,7 +2383,16 @@ fn main() {
             PhantomData,
         ),
         #[cfg(feature = "dbus_api")]
-        (None, Some(dbus_name), Some(dbus_path)) => TargetApi::DBusApi(
+        (None, None, Some(dbus_path), Some(dbus_socket)) => TargetApi::DBusApi(
+            DBusApi1ProxyBlocking::new_p2p_connection(dbus_socket, dbus_path)
+                .map_err(Error::DBusApiClient)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error creating D-Bus proxy: {e}");
+                    process::exit(1)
+                }),
+        ),
+        #[cfg(feature = "dbus_api")]
+        (None, Some(dbus_name), Some(dbus_path), None) => TargetApi::DBusApi(
             DBusApi1ProxyBlocking::new_connection(
                 dbus_name,
                 dbus_path,
@@ -1132,14 +2405,14 @@ fn main() {
             }),
         ),
         #[cfg(feature = "dbus_api")]
-        (Some(_), Some(_) | None, Some(_) | None) => {
+        (Some(_), _, _, _) => {
             println!(
-                "`api-socket` and (dbus-service-name or dbus-object-path) are mutually exclusive"
+                "`api-socket` and (dbus-service-name, dbus-object-path or dbus-p2p-socket) are mutually exclusive"
             );
             process::exit(1);
         }
         _ => {
-            println!("Please either provide the api-socket option or dbus-service-name and dbus-object-path options");
+            println!("Please either provide the api-socket option or dbus-service-name (or dbus-p2p-socket) and dbus-object-path options");
             process::exit(1);
         }
     };
@@ -1149,3 +2422,88 @@ fn main() {
         process::exit(1)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_flatten_json() {
+        let mut leaves = BTreeMap::new();
+        flatten_json(
+            String::new(),
+            json!({"a": {"b": 1, "c": [true, "x"]}, "d": null}),
+            &mut leaves,
+        );
+        assert_eq!(
+            leaves,
+            BTreeMap::from([
+                ("a.b".to_owned(), json!(1)),
+                ("a.c.0".to_owned(), json!(true)),
+                ("a.c.1".to_owned(), json!("x")),
+                ("d".to_owned(), json!(null)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_render_watch() {
+        let interval = Duration::from_secs(1);
+        let previous = BTreeMap::from([
+            ("disk.read_ops".to_owned(), json!(10)),
+            ("state".to_owned(), json!("Running")),
+        ]);
+        let values = BTreeMap::from([
+            ("disk.read_ops".to_owned(), json!(14)),
+            ("state".to_owned(), json!("Running")),
+        ]);
+
+        let view = render_watch("counters", interval, None, &values, false);
+        assert!(!view.contains('\x1b'));
+        assert!(view.contains("counters every 1000ms"));
+        let row = view
+            .lines()
+            .find(|l| l.starts_with("disk.read_ops"))
+            .unwrap();
+        assert_eq!(
+            row.split_whitespace().collect::<Vec<_>>(),
+            ["disk.read_ops", "14"]
+        );
+
+        let elapsed = Duration::from_secs(2);
+        let view = render_watch(
+            "counters",
+            interval,
+            Some((elapsed, &previous)),
+            &values,
+            false,
+        );
+        assert!(!view.contains('\x1b'));
+        let row = view
+            .lines()
+            .find(|l| l.starts_with("disk.read_ops"))
+            .unwrap();
+        assert_eq!(
+            row.split_whitespace().collect::<Vec<_>>(),
+            ["disk.read_ops", "14", "+4", "2.0"]
+        );
+        let row = view.lines().find(|l| l.starts_with("state")).unwrap();
+        assert_eq!(
+            row.split_whitespace().collect::<Vec<_>>(),
+            ["state", "Running"]
+        );
+
+        // Only the values which changed are highlighted on a terminal
+        let view = render_watch(
+            "counters",
+            interval,
+            Some((elapsed, &previous)),
+            &values,
+            true,
+        );
+        assert!(view.starts_with("\x1b[H\x1b[2J"));
+        assert_eq!(view.matches("\x1b[7m").count(), 1);
+        assert!(view.contains("\x1b[7mdisk.read_ops"));
+    }
+}
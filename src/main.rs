@@ -9,7 +9,7 @@ extern crate event_monitor;
 use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use libc::EFD_NONBLOCK;
 use log::{warn, LevelFilter};
-use option_parser::OptionParser;
+use option_parser::{IntegerList, OptionParser};
 use seccompiler::SeccompAction;
 use signal_hook::consts::SIGSYS;
 use std::env;
@@ -19,7 +19,7 @@ use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
 #[cfg(feature = "dbus_api")]
-use vmm::api::dbus::{dbus_api_graceful_shutdown, DBusApiOptions};
+use vmm::api::dbus::{dbus_api_graceful_shutdown, DBusApiOptions, DBusApiTransport};
 use vmm::api::http::http_api_graceful_shutdown;
 use vmm::api::ApiAction;
 use vmm::config;
@@ -30,6 +30,20 @@ use vmm_sys_util::signal::block_signal;
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
+#[cfg(all(feature = "dhat-heap", feature = "pprof"))]
+compile_error!("The dhat-heap and pprof features can't be enabled together");
+
+// The heap profiles served by `vmm.pprof` come from jemalloc, sampling an
+// allocation every 512 KiB on average.
+#[cfg(feature = "pprof")]
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "pprof")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
+
 #[derive(Error, Debug)]
 enum Error {
     #[error("Failed to create API EventFd: {0}")]
@@ -59,14 +73,36 @@ enum Error {
     VmmThread(#[source] vmm::Error),
     #[error("Error parsing --api-socket: {0}")]
     ParsingApiSocket(std::num::ParseIntError),
+    #[error("Error parsing --api-socket: {0}")]
+    ParsingApiSocketOptions(option_parser::OptionParserError),
+    #[error("Error parsing --api-socket: tcp requires the http_tls feature")]
+    ApiSocketTcpNotSupported,
+    #[cfg(feature = "http_tls")]
+    #[error("Error parsing --api-socket: {0}")]
+    ParsingApiSocketAddr(std::net::AddrParseError),
+    #[cfg(feature = "http_tls")]
+    #[error("Error parsing --api-socket: {0} missing with tcp")]
+    ApiSocketTlsOptionMissing(&'static str),
+    #[error("Error parsing --api-socket: {0}")]
+    ParsingApiSocketVsockCid(option_parser::OptionParserError),
+    #[error("Error parsing --api-socket: cid missing with vsock")]
+    ApiSocketVsockCidMissing,
+    #[error("Error parsing --api-socket: invalid vsock CID {0}")]
+    InvalidApiSocketVsockCid(u64),
     #[error("Error parsing --event-monitor: {0}")]
     ParsingEventMonitor(option_parser::OptionParserError),
     #[cfg(feature = "dbus_api")]
     #[error("`--dbus-object-path` option isn't provided")]
     MissingDBusObjectPath,
     #[cfg(feature = "dbus_api")]
-    #[error("`--dbus-service-name` option isn't provided")]
+    #[error("`--dbus-service-name` or `--dbus-p2p-socket` option isn't provided")]
     MissingDBusServiceName,
+    #[cfg(feature = "dbus_api")]
+    #[error("Invalid `--dbus-service-name`: {0}")]
+    InvalidDBusServiceName(#[source] zbus::names::Error),
+    #[cfg(feature = "dbus_api")]
+    #[error("Invalid `--dbus-object-path`: {0}")]
+    InvalidDBusObjectPath(#[source] zbus::zvariant::Error),
     #[error("Error parsing --event-monitor: path or fd required")]
     BareEventMonitor,
     #[error("Error doing event monitor I/O: {0}")]
@@ -85,6 +121,18 @@ enum Error {
     LoggerSetup(log::SetLoggerError),
     #[error("Failed to gracefully shutdown http api: {0}")]
     HttpApiShutdown(#[source] vmm::Error),
+    #[error("Error creating pidfile: {0}")]
+    PidFile(#[source] vmm::cleanup::Error),
+    #[error("Error cleaning up stale resources: {0}")]
+    Cleanup(#[source] vmm::cleanup::Error),
+    #[error("Error setting up the hooks: {0}")]
+    Hooks(#[source] vmm::hooks::Error),
+    #[error("Error setting up the API audit log: {0}")]
+    ApiAuditLog(#[source] vmm::api::audit::Error),
+    #[error("Error setting up the HTTP API limits: {0}")]
+    ApiLimits(#[source] vmm::api::http::limits::Error),
+    #[error("Error serializing the host information: {0}")]
+    HostInfo(#[source] serde_json::Error),
 }
 
 struct Logger {
@@ -171,7 +219,8 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
                     kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
                     affinity=<list_of_vcpus_with_their_associated_cpuset>,\
-                    features=<list_of_features_to_enable>",
+                    features=<list_of_features_to_enable>,sve_vl=<max_sve_vector_length_in_bits>,\
+                    pmu_events=<list_of_pmu_events_to_count>",
                 )
                 .default_value(default_vcpus)
                 .group("vm-config"),
@@ -179,7 +228,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("platform")
                 .long("platform")
-                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>")
+                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>,device_numa_policy=off|preferred|bind,device_access_warn_us=<threshold_in_us>,legacy_devices=<list_of_legacy_devices>,on_reset=restart|shutdown|pause|event,dt_overlay=<list_of_dtbo_paths>,mmio32_size=<aperture_size>,mmio64_size=<aperture_size>,vmgenid=on|off")
                 .num_args(1)
                 .group("vm-config"),
         )
@@ -189,11 +238,12 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .help(
                     "Memory parameters \
                      \"size=<guest_memory_size>,mergeable=on|off,shared=on|off,\
-                     hugepages=on|off,hugepage_size=<hugepage_size>,\
+                     hugepages=on|off|try,hugepage_size=<hugepage_size>,\
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off,thp=on|off\"",
+                     prefault=on|off,thp=on|off,locked=on|off,\
+                     lock_policy=strict|best-effort\"",
                 )
                 .default_value(default_memory)
                 .group("vm-config"),
@@ -205,11 +255,12 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                     "User defined memory zone parameters \
                      \"size=<guest_memory_region_size>,file=<backing_file>,\
                      shared=on|off,\
-                     hugepages=on|off,hugepage_size=<hugepage_size>,\
+                     hugepages=on|off|try,hugepage_size=<hugepage_size>,\
                      host_numa_node=<node_id>,\
                      id=<zone_identifier>,hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off\"",
+                     prefault=on|off,shared_with=<socket_path>,\
+                     shared_from=<socket_path>\"",
                 )
                 .num_args(1..)
                 .group("vm-config"),
@@ -289,6 +340,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1..)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("p9")
+                .long("p9")
+                .help(config::P9Config::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pmem")
                 .long("pmem")
@@ -340,6 +398,21 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("xhci")
+                .long("xhci")
+                .help("Enable xHCI USB controller")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("usb")
+                .long("usb")
+                .help(config::UsbConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pvpanic")
                 .long("pvpanic")
@@ -387,7 +460,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("api-socket")
                 .long("api-socket")
-                .help("HTTP API socket (UNIX domain socket): path=</path/to/a/file> or fd=<fd>.")
+                .help(
+                    "HTTP API socket (UNIX domain socket): path=</path/to/a/file> or fd=<fd>, \
+                    vsock port of the host, restricted to the given guest CIDs: \
+                    vsock=<port>,cid=<cid>|[<cid>,...], \
+                    or TCP socket with mutual TLS: \
+                    tcp=<host:port>,cert=<server_cert.pem>,key=<server_key.pem>,ca=<ca_cert.pem>.",
+                )
                 .num_args(1)
                 .group("vmm-config"),
         )
@@ -398,6 +477,58 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::new("api-events")
+                .long("api-events")
+                .help("Stream the events as server-sent events on the HTTP API socket")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .requires("api-socket")
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("api-token")
+                .long("api-token")
+                .help("File holding the bearer token the HTTP API requests must carry")
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("api-limits")
+                .long("api-limits")
+                .help(vmm::api::http::limits::HttpApiLimits::SYNTAX)
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("api-audit-log")
+                .long("api-audit-log")
+                .help("File to record the API requests to, one JSON object per line")
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("pidfile")
+                .long("pidfile")
+                .help("File to write the VMM pid to, locked as long as the VMM runs")
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("cleanup-on-start")
+                .long("cleanup-on-start")
+                .help("Remove the sockets left behind by a previous instance that crashed")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("hook")
+                .long("hook")
+                .help(vmm::hooks::HooksConfig::SYNTAX)
+                .num_args(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::new("restore")
                 .long("restore")
@@ -409,9 +540,38 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             Arg::new("seccomp")
                 .long("seccomp")
                 .num_args(1)
-                .value_parser(["true", "false", "log"])
+                .value_parser(["true", "false", "log", "audit"])
                 .default_value("true"),
         )
+        .arg(
+            Arg::new("rt")
+                .long("rt")
+                .help(config::RtConfig::SYNTAX)
+                .num_args(0..=1)
+                .default_missing_value("")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("payload-verification")
+                .long("payload-verification")
+                .help(config::PayloadVerificationConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("restart-policy")
+                .long("restart-policy")
+                .help(config::RestartPolicyConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("boot-detect")
+                .long("boot-detect")
+                .help(config::BootDetectConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("tpm")
                 .long("tpm")
@@ -420,6 +580,25 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .group("vmm-config"),
         );
 
+    #[cfg(target_arch = "x86_64")]
+    let app = app.arg(
+        Arg::new("ramfb")
+            .long("ramfb")
+            .help("Enable ramfb boot framebuffer device")
+            .num_args(0)
+            .action(ArgAction::SetTrue)
+            .group("vm-config"),
+    );
+
+    #[cfg(feature = "vnc")]
+    let app = app.arg(
+        Arg::new("vnc")
+            .long("vnc")
+            .help(config::VncConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
+    );
+
     #[cfg(target_arch = "x86_64")]
     let app = app.arg(
         Arg::new("sgx-epc")
@@ -452,7 +631,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("dbus-service-name")
                 .long("dbus-service-name")
-                .help("Well known name of the device")
+                .help("Well known name of the service")
                 .num_args(1)
                 .group("vmm-config"),
         )
@@ -470,6 +649,29 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .help("Use the system bus instead of a session bus")
                 .num_args(0)
                 .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("dbus-p2p-socket")
+                .long("dbus-p2p-socket")
+                .help(
+                    "Serve the dbus interface to the peers connecting to this \
+                    UNIX domain socket, instead of a bus",
+                )
+                .num_args(1)
+                .conflicts_with_all(["dbus-service-name", "dbus-system-bus"])
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("dbus-polkit")
+                .long("dbus-polkit")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Require the org.cloudhypervisor.manage polkit authorization \
+                    for the dbus methods changing the VMM or the VM",
+                )
+                .num_args(0)
+                .requires("dbus-system-bus")
+                .group("vmm-config"),
         );
     #[cfg(feature = "igvm")]
     let app = app.arg(
@@ -488,6 +690,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             .group("vm-config"),
     );
     app.arg(
+        Arg::new("host-info")
+            .long("host-info")
+            .action(ArgAction::SetTrue)
+            .help("Print the host NUMA topology, huge pages, IOMMU groups and capabilities as JSON")
+            .num_args(0),
+    )
+    .arg(
         Arg::new("version")
             .short('V')
             .long("version")
@@ -497,7 +706,52 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
     )
 }
 
-fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
+// Returns the configuration of the HTTP API served over TLS, when a TCP
+// address is given to --api-socket
+#[cfg(feature = "http_tls")]
+fn api_socket_tls_config(socket_config: &str) -> Result<Option<vmm::api::HttpTlsConfig>, Error> {
+    let mut parser = OptionParser::new();
+    parser
+        .add("path")
+        .add("fd")
+        .add("tcp")
+        .add("cert")
+        .add("key")
+        .add("ca")
+        .add("vsock")
+        .add("cid");
+    if parser.parse(socket_config).is_err() || !parser.is_set("tcp") {
+        return Ok(None);
+    }
+
+    let option = |name: &'static str| {
+        parser
+            .get(name)
+            .ok_or(Error::ApiSocketTlsOptionMissing(name))
+    };
+    Ok(Some(vmm::api::HttpTlsConfig {
+        listen: option("tcp")?
+            .parse()
+            .map_err(Error::ParsingApiSocketAddr)?,
+        cert: option("cert")?.into(),
+        key: option("key")?.into(),
+        ca: option("ca")?.into(),
+    }))
+}
+
+fn print_host_info() -> Result<(), Error> {
+    let hypervisor = hypervisor::new().map_err(Error::CreateHypervisor)?;
+    let host_info = vmm::host_info::host_info(hypervisor.as_ref());
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&host_info).map_err(Error::HostInfo)?
+    );
+    Ok(())
+}
+
+fn start_vmm(
+    cmd_arguments: ArgMatches,
+) -> Result<(Option<String>, Option<vmm::vm::ShutdownReason>), Error> {
     let log_level = match cmd_arguments.get_count("v") {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
@@ -520,30 +774,100 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     .map(|()| log::set_max_level(log_level))
     .map_err(Error::LoggerSetup)?;
 
-    let (api_socket_path, api_socket_fd) =
+    let (api_socket_path, api_socket_fd, api_socket_vsock) =
         if let Some(socket_config) = cmd_arguments.get_one::<String>("api-socket") {
             let mut parser = OptionParser::new();
-            parser.add("path").add("fd");
-            parser.parse(socket_config).unwrap_or_default();
+            parser
+                .add("path")
+                .add("fd")
+                .add("tcp")
+                .add("cert")
+                .add("key")
+                .add("ca")
+                .add("vsock")
+                .add("cid");
+            if let Err(e) = parser.parse(socket_config) {
+                // Only a bare path is given without any option, an unknown
+                // option would otherwise be taken for a path
+                if socket_config.contains('=') {
+                    return Err(Error::ParsingApiSocketOptions(e));
+                }
+            }
 
             if let Some(fd) = parser.get("fd") {
                 (
                     None,
                     Some(fd.parse::<RawFd>().map_err(Error::ParsingApiSocket)?),
+                    None,
                 )
             } else if let Some(path) = parser.get("path") {
-                (Some(path), None)
+                (Some(path), None, None)
+            } else if let Some(port) = parser.get("vsock") {
+                // The guests allowed to reach the API must be listed, the
+                // port being reachable from any CID otherwise
+                let cids = parser
+                    .convert::<IntegerList>("cid")
+                    .map_err(Error::ParsingApiSocketVsockCid)?
+                    .ok_or(Error::ApiSocketVsockCidMissing)?
+                    .0
+                    .into_iter()
+                    .map(|cid| u32::try_from(cid).map_err(|_| Error::InvalidApiSocketVsockCid(cid)))
+                    .collect::<Result<Vec<u32>, Error>>()?;
+                (
+                    None,
+                    None,
+                    Some(vmm::api::HttpVsockConfig {
+                        port: port.parse::<u32>().map_err(Error::ParsingApiSocket)?,
+                        cids,
+                    }),
+                )
+            } else if parser.is_set("tcp") {
+                if !cfg!(feature = "http_tls") {
+                    return Err(Error::ApiSocketTcpNotSupported);
+                }
+                (None, None, None)
             } else {
                 (
                     cmd_arguments
                         .get_one::<String>("api-socket")
                         .map(|s| s.to_string()),
                     None,
+                    None,
                 )
             }
         } else {
-            (None, None)
+            (None, None, None)
         };
+    #[cfg(feature = "http_tls")]
+    let api_socket_tls = cmd_arguments
+        .get_one::<String>("api-socket")
+        .map(String::as_str)
+        .map(api_socket_tls_config)
+        .transpose()?
+        .flatten();
+
+    let api_token_path = cmd_arguments
+        .get_one::<String>("api-token")
+        .map(std::path::PathBuf::from);
+
+    // Opened before the API gets served, so that every request is recorded
+    if let Some(path) = cmd_arguments.get_one::<String>("api-audit-log") {
+        vmm::api::audit::set_log_file(std::path::Path::new(path)).map_err(Error::ApiAuditLog)?;
+    }
+
+    if let Some(limits) = cmd_arguments.get_one::<String>("api-limits") {
+        let limits =
+            vmm::api::http::limits::HttpApiLimits::parse(limits).map_err(Error::ApiLimits)?;
+        vmm::api::http::limits::set_limits(&limits).map_err(Error::ApiLimits)?;
+    }
+
+    // Held until the VMM terminates, the lock telling a running instance
+    // apart from a crashed one.
+    let _pidfile = cmd_arguments
+        .get_one::<String>("pidfile")
+        .map(|path| vmm::cleanup::PidFile::create(std::path::Path::new(path)))
+        .transpose()
+        .map_err(Error::PidFile)?;
 
     let (api_request_sender, api_request_receiver) = channel();
     let api_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateApiEventFd)?;
@@ -551,7 +875,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     let api_request_sender_clone = api_request_sender.clone();
     let seccomp_action = if let Some(seccomp_value) = cmd_arguments.get_one::<String>("seccomp") {
         match seccomp_value as &str {
-            "true" => SeccompAction::Trap,
+            "true" | "audit" => SeccompAction::Trap,
             "false" => SeccompAction::Allow,
             "log" => SeccompAction::Log,
             val => {
@@ -600,8 +924,36 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         }
     }
 
+    // The process running the hooks is forked before any thread is started,
+    // as it would inherit the seccomp filters otherwise.
+    let hooks = cmd_arguments
+        .get_one::<String>("hook")
+        .map(|hooks| vmm::hooks::HooksConfig::parse(hooks).and_then(vmm::hooks::HooksRunner::new))
+        .transpose()
+        .map_err(Error::Hooks)?;
+
     let hypervisor = hypervisor::new().map_err(Error::CreateHypervisor)?;
 
+    // In audit mode, the violations are reported and let through until the
+    // VM is booted, the filters being enforced from then on.
+    if cmd_arguments
+        .get_one::<String>("seccomp")
+        .map(String::as_str)
+        == Some("audit")
+    {
+        vmm::seccomp_filters::get_seccomp_filter(
+            &seccomp_action,
+            vmm::seccomp_filters::Thread::SeccompNotifier,
+            hypervisor.hypervisor_type(),
+        )
+        .map_err(|e| eprintln!("Error creating the seccomp notifier filter: {e}"))
+        .and_then(|filter| {
+            vmm::seccomp_violations::start(filter)
+                .map_err(|e| eprintln!("Error starting the seccomp notifier: {e}"))
+        })
+        .ok();
+    }
+
     #[cfg(feature = "guest_debug")]
     let gdb_socket_path = if let Some(gdb_config) = cmd_arguments.get_one::<String>("gdb") {
         let mut parser = OptionParser::new();
@@ -621,6 +973,21 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     #[cfg(feature = "guest_debug")]
     let vm_debug_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateDebugEventFd)?;
 
+    if cmd_arguments.get_flag("cleanup-on-start") {
+        let mut sockets: Vec<std::path::PathBuf> = api_socket_path
+            .iter()
+            .map(std::path::PathBuf::from)
+            .collect();
+        #[cfg(feature = "guest_debug")]
+        sockets.extend(gdb_socket_path.clone());
+        if cmd_arguments.contains_id("kernel") || cmd_arguments.contains_id("firmware") {
+            let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+            let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
+            sockets.extend(vmm::cleanup::vm_sockets(&vm_config));
+        }
+        vmm::cleanup::cleanup_sockets(&sockets).map_err(Error::Cleanup)?;
+    }
+
     let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateExitEventFd)?;
 
     #[allow(unused_mut)]
@@ -660,12 +1027,36 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         })
         .transpose()?;
 
+    #[cfg(feature = "dbus_api")]
+    let dbus_p2p_socket = cmd_arguments.get_one::<String>("dbus-p2p-socket");
+
     #[cfg(feature = "dbus_api")]
     let dbus_options = match (
         cmd_arguments.get_one::<String>("dbus-service-name"),
+        dbus_p2p_socket,
         cmd_arguments.get_one::<String>("dbus-object-path"),
     ) {
-        (Some(name), Some(path)) => {
+        (name, socket, Some(path)) if name.is_some() || socket.is_some() => {
+            // Reported here rather than when the VMM thread connects to the
+            // bus, as several VMMs can only share a bus with distinct names.
+            if let Some(name) = name {
+                zbus::names::WellKnownName::try_from(name.as_str())
+                    .map_err(Error::InvalidDBusServiceName)?;
+            }
+            zbus::zvariant::ObjectPath::try_from(path.as_str())
+                .map_err(Error::InvalidDBusObjectPath)?;
+
+            let transport = match socket {
+                Some(socket) => DBusApiTransport::Peer {
+                    socket_path: socket.to_string(),
+                },
+                None => DBusApiTransport::Bus {
+                    service_name: name.unwrap().to_string(),
+                    system_bus: cmd_arguments.get_flag("dbus-system-bus"),
+                    polkit: cmd_arguments.get_flag("dbus-polkit"),
+                },
+            };
+
             // monitor is either set (file based) or not.
             // if it's not set, create one without file support.
             let mut monitor = match event_monitor.take() {
@@ -673,20 +1064,34 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
                 None => event_monitor::set_monitor(None).map_err(Error::EventMonitorIo)?,
             };
             let options = DBusApiOptions {
-                service_name: name.to_string(),
+                transport,
                 object_path: path.to_string(),
-                system_bus: cmd_arguments.get_flag("dbus-system-bus"),
                 event_monitor_rx: monitor.subscribe(),
             };
 
             event_monitor = Some(monitor);
             Ok(Some(options))
         }
-        (Some(_), None) => Err(Error::MissingDBusObjectPath),
-        (None, Some(_)) => Err(Error::MissingDBusServiceName),
-        (None, None) => Ok(None),
+        (Some(_), _, None) | (_, Some(_), None) => Err(Error::MissingDBusObjectPath),
+        (None, None, Some(_)) => Err(Error::MissingDBusServiceName),
+        (None, None, None) => Ok(None),
     }?;
 
+    // The events are streamed by any of the HTTP API sockets, each of them
+    // serving a single request per connection for this purpose, hence the
+    // streaming being requested explicitly.
+    let http_events_rx = if cmd_arguments.get_flag("api-events") {
+        let mut monitor = match event_monitor.take() {
+            Some(monitor) => monitor,
+            None => event_monitor::set_monitor(None).map_err(Error::EventMonitorIo)?,
+        };
+        let rx = monitor.subscribe();
+        event_monitor = Some(monitor);
+        Some(rx)
+    } else {
+        None
+    };
+
     if let Some(monitor) = event_monitor {
         vmm::start_event_monitor_thread(
             monitor,
@@ -703,6 +1108,11 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         vmm::VmmVersionInfo::new(env!("BUILD_VERSION"), env!("CARGO_PKG_VERSION")),
         &api_socket_path,
         api_socket_fd,
+        #[cfg(feature = "http_tls")]
+        api_socket_tls,
+        api_socket_vsock,
+        api_token_path,
+        http_events_rx,
         #[cfg(feature = "dbus_api")]
         dbus_options,
         api_evt.try_clone().unwrap(),
@@ -717,6 +1127,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         exit_evt.try_clone().unwrap(),
         &seccomp_action,
         hypervisor,
+        hooks,
     )
     .map_err(Error::StartVmmThread)?;
 
@@ -743,7 +1154,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
                 )
                 .map_err(Error::VmCreate)?;
             vmm::api::VmBoot
-                .send(api_evt.try_clone().unwrap(), sender, ())
+                .send(api_evt.try_clone().unwrap(), sender, None)
                 .map_err(Error::VmBoot)?;
         } else if let Some(restore_params) = cmd_arguments.get_one::<String>("restore") {
             vmm::api::VmRestore
@@ -764,7 +1175,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         }
     }
 
-    vmm_thread_handle
+    let shutdown_reason = vmm_thread_handle
         .thread_handle
         .join()
         .map_err(Error::ThreadJoin)?
@@ -777,9 +1188,12 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     #[cfg(feature = "dbus_api")]
     if let Some(chs) = vmm_thread_handle.dbus_shutdown_chs {
         dbus_api_graceful_shutdown(chs);
+        if let Some(socket) = dbus_p2p_socket {
+            std::fs::remove_file(socket).ok();
+        }
     }
 
-    r.map(|_| api_socket_path)
+    r.map(|_| (api_socket_path, shutdown_reason))
 }
 
 fn main() {
@@ -808,10 +1222,18 @@ fn main() {
         return;
     }
 
+    if cmd_arguments.get_flag("host-info") {
+        if let Err(e) = print_host_info() {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let exit_code = match start_vmm(cmd_arguments) {
-        Ok(path) => {
+        Ok((path, shutdown_reason)) => {
             path.map(|s| std::fs::remove_file(s).ok());
-            0
+            shutdown_reason.map_or(0, |reason| reason.exit_code())
         }
         Err(e) => {
             eprintln!("{e}");
@@ -827,7 +1249,7 @@ fn main() {
 
 #[cfg(test)]
 mod unit_tests {
-    use crate::config::HotplugMethod;
+    use crate::config::{HotplugMethod, MemoryLockPolicy};
     use crate::{create_app, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
@@ -881,6 +1303,8 @@ mod unit_tests {
                 max_phys_bits: 46,
                 affinity: None,
                 features: CpuFeatures::default(),
+                sve_vl: None,
+                pmu_events: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -891,9 +1315,12 @@ mod unit_tests {
                 shared: false,
                 hugepages: false,
                 hugepage_size: None,
+                hugepages_fallback: false,
                 prefault: false,
                 zones: None,
                 thp: true,
+                locked: false,
+                lock_policy: MemoryLockPolicy::Strict,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -914,6 +1341,7 @@ mod unit_tests {
             },
             balloon: None,
             fs: None,
+            p9: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
@@ -933,7 +1361,13 @@ mod unit_tests {
             user_devices: None,
             vdpa: None,
             vsock: None,
+            xhci: false,
+            usb: None,
             pvpanic: false,
+            #[cfg(target_arch = "x86_64")]
+            ramfb: false,
+            #[cfg(feature = "vnc")]
+            vnc: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
@@ -944,6 +1378,10 @@ mod unit_tests {
             pci_segments: None,
             platform: None,
             tpm: None,
+            rt: None,
+            payload_verification: None,
+            restart_policy: None,
+            boot_detect: None,
             preserved_fds: None,
         };
 
@@ -1931,4 +2369,36 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[test]
+    fn test_valid_vm_config_rt() {
+        [
+            (
+                vec!["cloud-hypervisor", "--kernel", "/path/to/kernel", "--rt"],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "rt": {}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--rt",
+                    "cpus=[2,3],priority=10",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "rt": {"cpus": [2, 3], "priority": 10}
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
 }
@@ -43,7 +43,7 @@ use virtio_bindings::virtio_config::*;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
-use vm_virtio::AccessPlatform;
+use vm_virtio::{AccessPlatform, QueueCounters};
 use vmm_sys_util::eventfd::EventFd;
 
 const SECTOR_SHIFT: u8 = 9;
@@ -77,6 +77,8 @@ pub enum Error {
     QueueAddUsed(virtio_queue::Error),
     #[error("Failed creating an iterator over the queue: {0}")]
     QueueIterator(virtio_queue::Error),
+    #[error("Failed to check if queue needs notification: {0}")]
+    QueueNeedsNotification(virtio_queue::Error),
     #[error("Failed to update request status: {0}")]
     RequestStatus(GuestMemoryError),
 }
@@ -97,6 +99,10 @@ pub struct BlockCounters {
     write_latency_min: Arc<AtomicU64>,
     write_latency_max: Arc<AtomicU64>,
     write_latency_avg: Arc<AtomicU64>,
+    flush_ops: Arc<AtomicU64>,
+    flush_latency_min: Arc<AtomicU64>,
+    flush_latency_max: Arc<AtomicU64>,
+    flush_latency_avg: Arc<AtomicU64>,
 }
 
 impl Default for BlockCounters {
@@ -112,10 +118,41 @@ impl Default for BlockCounters {
             write_latency_min: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_max: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_avg: Arc::new(AtomicU64::new(u64::MAX)),
+            flush_ops: Arc::new(AtomicU64::new(0)),
+            flush_latency_min: Arc::new(AtomicU64::new(u64::MAX)),
+            flush_latency_max: Arc::new(AtomicU64::new(u64::MAX)),
+            flush_latency_avg: Arc::new(AtomicU64::new(u64::MAX)),
         }
     }
 }
 
+// Accounts the latency, in microseconds, of a completed request in the
+// minimum, the maximum and the cumulative average latency of its kind, `ops`
+// being the number of requests of this kind completed so far, this one
+// included.
+fn record_latency(min: &AtomicU64, max: &AtomicU64, avg: &AtomicU64, ops: u64, latency: u64) {
+    if latency < min.load(Ordering::Relaxed) {
+        min.store(latency, Ordering::Relaxed);
+    }
+    let last_max = max.load(Ordering::Relaxed);
+    if latency > last_max || last_max == u64::MAX {
+        max.store(latency, Ordering::Relaxed);
+    }
+
+    // Special case the first real latency report
+    let last_avg = avg.load(Ordering::Relaxed);
+    let new_avg = if last_avg == u64::MAX {
+        latency * LATENCY_SCALE
+    } else {
+        // Cumulative average is guaranteed to be
+        // positive if being calculated properly
+        (last_avg as i64 + ((latency * LATENCY_SCALE) as i64 - last_avg as i64) / ops as i64)
+            .try_into()
+            .unwrap()
+    };
+    avg.store(new_avg, Ordering::Relaxed);
+}
+
 struct BlockEpollHandler {
     queue_index: u16,
     queue: Queue,
@@ -128,6 +165,7 @@ struct BlockEpollHandler {
     pause_evt: EventFd,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
+    queue_counters: QueueCounters,
     queue_evt: EventFd,
     inflight_requests: VecDeque<(u16, Request)>,
     rate_limiter: Option<RateLimiterGroupHandle>,
@@ -227,13 +265,24 @@ impl BlockEpollHandler {
             }
         }
 
-        Ok(used_descs)
+        if used_descs {
+            self.needs_notification()
+        } else {
+            Ok(false)
+        }
     }
 
     fn process_queue_submit_and_signal(&mut self) -> result::Result<(), EpollHelperError> {
+        let used = self.queue.next_used();
         let needs_notification = self.process_queue_submit().map_err(|e| {
             EpollHelperError::HandleEvent(anyhow!("Failed to process queue (submit): {:?}", e))
         })?;
+        self.queue_counters.record_batch(&self.queue, used);
+        // The driver can't submit any further request until some of the
+        // inflight ones complete.
+        if self.inflight_requests.len() >= usize::from(self.queue.size()) {
+            self.queue_counters.record_ring_full();
+        }
 
         if needs_notification {
             self.signal_used_queue().map_err(|e| {
@@ -265,6 +314,20 @@ impl BlockEpollHandler {
         Err(Error::MissingEntryRequestList)
     }
 
+    // Check whether the driver has to be notified about the used descriptors,
+    // accounting for the notifications it asked not to receive.
+    fn needs_notification(&mut self) -> Result<bool> {
+        let needs_notification = self
+            .queue
+            .needs_notification(self.mem.memory().deref())
+            .map_err(Error::QueueNeedsNotification)?;
+        if !needs_notification {
+            self.queue_counters.record_notification(false);
+        }
+
+        Ok(needs_notification)
+    }
+
     fn process_queue_complete(&mut self) -> Result<bool> {
         let mut used_descs = false;
         let mem = self.mem.memory();
@@ -272,6 +335,7 @@ impl BlockEpollHandler {
         let mut write_bytes = Wrapping(0);
         let mut read_ops = Wrapping(0);
         let mut write_ops = Wrapping(0);
+        let mut flush_ops = Wrapping(0);
 
         while let Some((user_data, result)) = self.disk_image.next_completed_request() {
             let desc_index = user_data as u16;
@@ -281,12 +345,6 @@ impl BlockEpollHandler {
             request.complete_async().map_err(Error::RequestCompleting)?;
 
             let latency = request.start.elapsed().as_micros() as u64;
-            let read_ops_last = self.counters.read_ops.load(Ordering::Relaxed);
-            let write_ops_last = self.counters.write_ops.load(Ordering::Relaxed);
-            let read_max = self.counters.read_latency_max.load(Ordering::Relaxed);
-            let write_max = self.counters.write_latency_max.load(Ordering::Relaxed);
-            let mut read_avg = self.counters.read_latency_avg.load(Ordering::Relaxed);
-            let mut write_avg = self.counters.write_latency_avg.load(Ordering::Relaxed);
             let (status, len) = if result >= 0 {
                 match request.request_type {
                     RequestType::In => {
@@ -294,29 +352,13 @@ impl BlockEpollHandler {
                             read_bytes += Wrapping(*data_len as u64);
                         }
                         read_ops += Wrapping(1);
-                        if latency < self.counters.read_latency_min.load(Ordering::Relaxed) {
-                            self.counters
-                                .read_latency_min
-                                .store(latency, Ordering::Relaxed);
-                        }
-                        if latency > read_max || read_max == u64::MAX {
-                            self.counters
-                                .read_latency_max
-                                .store(latency, Ordering::Relaxed);
-                        }
-
-                        // Special case the first real latency report
-                        read_avg = if read_avg == u64::MAX {
-                            latency * LATENCY_SCALE
-                        } else {
-                            // Cumulative average is guaranteed to be
-                            // positive if being calculated properly
-                            (read_avg as i64
-                                + ((latency * LATENCY_SCALE) as i64 - read_avg as i64)
-                                    / (read_ops_last + read_ops.0) as i64)
-                                .try_into()
-                                .unwrap()
-                        };
+                        record_latency(
+                            &self.counters.read_latency_min,
+                            &self.counters.read_latency_max,
+                            &self.counters.read_latency_avg,
+                            self.counters.read_ops.load(Ordering::Relaxed) + read_ops.0,
+                            latency,
+                        );
                     }
                     RequestType::Out => {
                         if !request.writeback {
@@ -326,41 +368,27 @@ impl BlockEpollHandler {
                             write_bytes += Wrapping(*data_len as u64);
                         }
                         write_ops += Wrapping(1);
-                        if latency < self.counters.write_latency_min.load(Ordering::Relaxed) {
-                            self.counters
-                                .write_latency_min
-                                .store(latency, Ordering::Relaxed);
-                        }
-                        if latency > write_max || write_max == u64::MAX {
-                            self.counters
-                                .write_latency_max
-                                .store(latency, Ordering::Relaxed);
-                        }
-
-                        // Special case the first real latency report
-                        write_avg = if write_avg == u64::MAX {
-                            latency * LATENCY_SCALE
-                        } else {
-                            // Cumulative average is guaranteed to be
-                            // positive if being calculated properly
-                            (write_avg as i64
-                                + ((latency * LATENCY_SCALE) as i64 - write_avg as i64)
-                                    / (write_ops_last + write_ops.0) as i64)
-                                .try_into()
-                                .unwrap()
-                        }
+                        record_latency(
+                            &self.counters.write_latency_min,
+                            &self.counters.write_latency_max,
+                            &self.counters.write_latency_avg,
+                            self.counters.write_ops.load(Ordering::Relaxed) + write_ops.0,
+                            latency,
+                        );
+                    }
+                    RequestType::Flush => {
+                        flush_ops += Wrapping(1);
+                        record_latency(
+                            &self.counters.flush_latency_min,
+                            &self.counters.flush_latency_max,
+                            &self.counters.flush_latency_avg,
+                            self.counters.flush_ops.load(Ordering::Relaxed) + flush_ops.0,
+                            latency,
+                        );
                     }
                     _ => {}
                 }
 
-                self.counters
-                    .read_latency_avg
-                    .store(read_avg, Ordering::Relaxed);
-
-                self.counters
-                    .write_latency_avg
-                    .store(write_avg, Ordering::Relaxed);
-
                 (VIRTIO_BLK_S_OK, result as u32)
             } else {
                 error!(
@@ -396,10 +424,19 @@ impl BlockEpollHandler {
             .read_ops
             .fetch_add(read_ops.0, Ordering::AcqRel);
 
-        Ok(used_descs)
+        self.counters
+            .flush_ops
+            .fetch_add(flush_ops.0, Ordering::AcqRel);
+
+        if used_descs {
+            self.needs_notification()
+        } else {
+            Ok(false)
+        }
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.queue_counters.record_notification(true);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(self.queue_index))
             .map_err(|e| {
@@ -487,12 +524,14 @@ impl EpollHelperHandler for BlockEpollHandler {
                     EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
                 })?;
 
+                let used = self.queue.next_used();
                 let needs_notification = self.process_queue_complete().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!(
                         "Failed to process queue (complete): {:?}",
                         e
                     ))
                 })?;
+                self.queue_counters.record_batch(&self.queue, used);
 
                 if needs_notification {
                     self.signal_used_queue().map_err(|e| {
@@ -542,6 +581,7 @@ pub struct Block {
     config: VirtioBlockConfig,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
+    queue_counters: Vec<QueueCounters>,
     seccomp_action: SeccompAction,
     rate_limiter: Option<Arc<RateLimiterGroup>>,
     exit_evt: EventFd,
@@ -674,6 +714,7 @@ impl Block {
             config,
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
+            queue_counters: (0..num_queues).map(|_| QueueCounters::default()).collect(),
             seccomp_action,
             rate_limiter,
             exit_evt,
@@ -683,6 +724,49 @@ impl Block {
         })
     }
 
+    /// Replace the disk image before the device is activated, such as when a
+    /// restored VM gets its own overlay of the image it was snapshot with. The
+    /// guest having read the capacity already, the size can't change.
+    pub fn set_disk_image(
+        &mut self,
+        mut disk_image: Box<dyn DiskFile>,
+        disk_path: PathBuf,
+    ) -> io::Result<()> {
+        if self.common.epoll_threads.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Can't replace the disk image of an activated device",
+            ));
+        }
+
+        let disk_size = disk_image.size().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed getting disk size: {e}"),
+            )
+        })?;
+        if disk_size / SECTOR_SIZE != self.disk_nsectors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Disk {} has {} sectors rather than {}",
+                    disk_path.display(),
+                    disk_size / SECTOR_SIZE,
+                    self.disk_nsectors
+                ),
+            ));
+        }
+
+        info!(
+            "Replacing disk image {} with {}",
+            self.disk_path.display(),
+            disk_path.display()
+        );
+        self.disk_image = disk_image;
+        self.disk_path = disk_path;
+        Ok(())
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.to_str().unwrap().to_owned(),
@@ -803,6 +887,7 @@ impl VirtioDevice for Block {
                 pause_evt,
                 writeback: self.writeback.clone(),
                 counters: self.counters.clone(),
+                queue_counters: self.queue_counters[i].clone(),
                 queue_evt,
                 // Analysis during boot shows around ~40 maximum requests
                 // This gives head room for systems with slower I/O without
@@ -887,10 +972,40 @@ impl VirtioDevice for Block {
             "read_latency_avg",
             Wrapping(self.counters.read_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
         );
+        counters.insert(
+            "flush_ops",
+            Wrapping(self.counters.flush_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "flush_latency_min",
+            Wrapping(self.counters.flush_latency_min.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "flush_latency_max",
+            Wrapping(self.counters.flush_latency_max.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "flush_latency_avg",
+            Wrapping(self.counters.flush_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
+        );
+        // Cache mode in use, 1 for writeback and 0 for writethrough
+        counters.insert(
+            "writeback",
+            Wrapping(u64::from(self.writeback.load(Ordering::Acquire))),
+        );
 
         Some(counters)
     }
 
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        Some(
+            self.queue_counters
+                .iter()
+                .map(QueueCounters::counters)
+                .collect(),
+        )
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
@@ -917,3 +1032,39 @@ impl Snapshottable for Block {
 }
 impl Transportable for Block {}
 impl Migratable for Block {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_latency() {
+        let counters = BlockCounters::default();
+        let record = |ops, latency| {
+            record_latency(
+                &counters.flush_latency_min,
+                &counters.flush_latency_max,
+                &counters.flush_latency_avg,
+                ops,
+                latency,
+            )
+        };
+        let latencies = || {
+            (
+                counters.flush_latency_min.load(Ordering::Relaxed),
+                counters.flush_latency_max.load(Ordering::Relaxed),
+                counters.flush_latency_avg.load(Ordering::Relaxed),
+            )
+        };
+
+        record(1, 40);
+        assert_eq!(latencies(), (40, 40, 40 * LATENCY_SCALE));
+        record(2, 20);
+        assert_eq!(latencies(), (20, 40, 30 * LATENCY_SCALE));
+        record(3, 90);
+        assert_eq!(latencies(), (20, 90, 50 * LATENCY_SCALE));
+
+        // The other kinds of requests aren't accounted
+        assert_eq!(counters.read_latency_avg.load(Ordering::Relaxed), u64::MAX);
+    }
+}
@@ -152,6 +152,23 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Return the counters of each of the virtqueues of this device
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        None
+    }
+
+    /// Return the reason why this device can't be snapshotted, if it can't
+    fn snapshot_blocker(&self) -> Option<String> {
+        None
+    }
+
+    /// Return the reason why this device can't be live migrated, if it can't.
+    /// A device that can't be snapshotted can't be migrated either, unless it
+    /// overrides this.
+    fn migration_blocker(&self) -> Option<String> {
+        self.snapshot_blocker()
+    }
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
@@ -0,0 +1,521 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Built-in virtio-fs device.
+//!
+//! The FUSE requests sent by the guest are served from within the VMM process
+//! by a passthrough filesystem, sharing a host directory without requiring an
+//! external vhost-user backend such as virtiofsd.
+//!
+//! The thread serving the requests is confined to the shared directory with
+//! Landlock, read-only access being enforced the same way. Since Landlock
+//! doesn't prevent truncating files nor changing their attributes, the requests
+//! modifying a read-only share are turned down before reaching the filesystem.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::vhost_user::VirtioFsConfig;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{Config as PassthroughConfig, PassthroughFs};
+use fuse_backend_rs::transport::{Reader, VirtioFsWriter, Writer};
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use seccompiler::SeccompAction;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier};
+use thiserror::Error;
+use virtio_queue::{DescriptorChain, Queue, QueueT};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
+    GuestMemoryLoadGuard,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+
+// The high priority queue comes first, followed by the request queues.
+const NUM_QUEUE_OFFSET: usize = 1;
+
+// New descriptors are pending on one of the virtio queues. The queue index is
+// added to this base event.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+// Sizes of the fuse_in_header and fuse_out_header structures.
+const FUSE_IN_HEADER_SIZE: u32 = 40;
+const FUSE_OUT_HEADER_SIZE: u32 = 16;
+
+// Opcodes of the FUSE requests modifying the filesystem.
+const FUSE_SETATTR: u32 = 4;
+const FUSE_SYMLINK: u32 = 6;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_WRITE: u32 = 16;
+const FUSE_SETXATTR: u32 = 21;
+const FUSE_REMOVEXATTR: u32 = 24;
+const FUSE_CREATE: u32 = 35;
+const FUSE_FALLOCATE: u32 = 43;
+const FUSE_RENAME2: u32 = 45;
+const FUSE_COPY_FILE_RANGE: u32 = 47;
+const FUSE_TMPFILE: u32 = 51;
+
+fn is_mutating_opcode(opcode: u32) -> bool {
+    matches!(
+        opcode,
+        FUSE_SETATTR
+            | FUSE_SYMLINK
+            | FUSE_MKNOD
+            | FUSE_MKDIR
+            | FUSE_UNLINK
+            | FUSE_RMDIR
+            | FUSE_RENAME
+            | FUSE_LINK
+            | FUSE_WRITE
+            | FUSE_SETXATTR
+            | FUSE_REMOVEXATTR
+            | FUSE_CREATE
+            | FUSE_FALLOCATE
+            | FUSE_RENAME2
+            | FUSE_COPY_FILE_RANGE
+            | FUSE_TMPFILE
+    )
+}
+
+// Opening a file for writing or truncating it modifies the share, the flags
+// being the first field of fuse_open_in.
+fn is_mutating_open(flags: u32) -> bool {
+    let flags = flags as i32;
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Bad guest memory addresses: {0}")]
+    GuestMemory(GuestMemoryError),
+    #[error("Invalid descriptor chain: {0}")]
+    InvalidDescriptorChain(fuse_backend_rs::transport::Error),
+    #[error("Failed to process FUSE request: {0}")]
+    ProcessRequest(fuse_backend_rs::Error),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+struct FsEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queues: Vec<(usize, Queue, EventFd)>,
+    server: Arc<Server<PassthroughFs>>,
+    shared_dir: PathBuf,
+    readonly: bool,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl FsEpollHandler {
+    // Prevents the current thread from accessing anything outside of the
+    // shared directory, and from modifying it when sharing it read-only.
+    fn restrict_to_shared_dir(&self) -> io::Result<()> {
+        let abi = ABI::V1;
+        let access = if self.readonly {
+            AccessFs::from_read(abi)
+        } else {
+            AccessFs::from_all(abi)
+        };
+
+        let shared_dir =
+            PathFd::new(&self.shared_dir).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .and_then(|ruleset| ruleset.create())
+            .and_then(|ruleset| ruleset.add_rule(PathBeneath::new(shared_dir, access)))
+            .and_then(|ruleset| ruleset.restrict_self())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        if status.ruleset == RulesetStatus::NotEnforced {
+            // Read-only access can't be guaranteed without Landlock.
+            if self.readonly {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Landlock is required to share a directory read-only",
+                ));
+            }
+            warn!(
+                "Landlock not supported, access is not restricted to {:?}",
+                self.shared_dir
+            );
+        }
+
+        Ok(())
+    }
+
+    // Reads the u32 at `offset` in the request, which may span several
+    // descriptors, or None if the request is shorter.
+    fn read_request_u32(
+        desc_chain: &DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+        offset: u32,
+    ) -> result::Result<Option<u32>, Error> {
+        let mem = desc_chain.memory();
+        let mut bytes = [0u8; 4];
+        let mut filled = 0;
+        let mut skip = offset as usize;
+        for desc in desc_chain.clone().take_while(|desc| !desc.is_write_only()) {
+            let len = desc.len() as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let count = (len - skip).min(bytes.len() - filled);
+            mem.read_slice(
+                &mut bytes[filled..filled + count],
+                desc.addr().unchecked_add(skip as u64),
+            )
+            .map_err(Error::GuestMemory)?;
+            filled += count;
+            skip = 0;
+            if filled == bytes.len() {
+                return Ok(Some(u32::from_le_bytes(bytes)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Answers the requests modifying a read-only share with EROFS, returning
+    // the length of the reply, or None if the request can be served.
+    fn reject_mutating_request(
+        desc_chain: &DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+    ) -> result::Result<Option<u32>, Error> {
+        let mem = desc_chain.memory();
+        let mut descs = desc_chain.clone();
+        let header = match descs.next() {
+            Some(desc) if !desc.is_write_only() && desc.len() >= FUSE_IN_HEADER_SIZE => desc,
+            // Left to the server to turn down.
+            _ => return Ok(None),
+        };
+
+        // The opcode follows the length of the request, the unique ID of the
+        // request coming next.
+        let opcode: u32 = mem
+            .read_obj(header.addr().unchecked_add(4))
+            .map_err(Error::GuestMemory)?;
+        let mutating = match opcode {
+            // A request too short to carry the flags is turned down as well
+            FUSE_OPEN => Self::read_request_u32(desc_chain, FUSE_IN_HEADER_SIZE)?
+                .map_or(true, is_mutating_open),
+            opcode => is_mutating_opcode(opcode),
+        };
+        if !mutating {
+            return Ok(None);
+        }
+        let unique: u64 = mem
+            .read_obj(header.addr().unchecked_add(8))
+            .map_err(Error::GuestMemory)?;
+
+        let reply = match descs.find(|desc| desc.is_write_only()) {
+            Some(desc) if desc.len() >= FUSE_OUT_HEADER_SIZE => desc,
+            _ => return Ok(Some(0)),
+        };
+        mem.write_obj(FUSE_OUT_HEADER_SIZE, reply.addr())
+            .and_then(|_| mem.write_obj(-libc::EROFS, reply.addr().unchecked_add(4)))
+            .and_then(|_| mem.write_obj(unique, reply.addr().unchecked_add(8)))
+            .map_err(Error::GuestMemory)?;
+
+        Ok(Some(FUSE_OUT_HEADER_SIZE))
+    }
+
+    fn process_queue(&mut self, queue_index: usize) -> result::Result<bool, Error> {
+        let queue = &mut self.queues[queue_index].1;
+
+        let mut used_descs = false;
+        while let Some(desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            if self.readonly {
+                if let Some(len) = Self::reject_mutating_request(&desc_chain)? {
+                    queue
+                        .add_used(desc_chain.memory(), desc_chain.head_index(), len)
+                        .map_err(Error::QueueAddUsed)?;
+                    used_descs = true;
+                    continue;
+                }
+            }
+
+            let reader = Reader::from_descriptor_chain(desc_chain.memory(), desc_chain.clone())
+                .map_err(Error::InvalidDescriptorChain)?;
+            let writer = Writer::VirtioFs(
+                VirtioFsWriter::new(desc_chain.memory(), desc_chain.clone())
+                    .map_err(Error::InvalidDescriptorChain)?,
+            );
+
+            let len = self
+                .server
+                .handle_message(reader, writer, None, None)
+                .map_err(Error::ProcessRequest)?;
+
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len as u32)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(
+                self.queues[queue_index].0 as u16,
+            ))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        self.restrict_to_shared_dir()
+            .map_err(EpollHelperError::IoError)?;
+
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        for (queue_index, (_, _, queue_evt)) in self.queues.iter().enumerate() {
+            helper.add_event(
+                queue_evt.as_raw_fd(),
+                QUEUE_AVAIL_EVENT + queue_index as u16,
+            )?;
+        }
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for FsEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        let queue_index = ev_type.wrapping_sub(QUEUE_AVAIL_EVENT) as usize;
+        if queue_index >= self.queues.len() {
+            return Err(EpollHelperError::HandleEvent(anyhow!(
+                "Unexpected event: {}",
+                ev_type
+            )));
+        }
+
+        self.queues[queue_index].2.read().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+        })?;
+        let needs_notification = self.process_queue(queue_index).map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue(queue_index).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio-fs device serving a host directory from within the VMM.
+pub struct Fs {
+    common: VirtioCommon,
+    id: String,
+    config: VirtioFsConfig,
+    server: Arc<Server<PassthroughFs>>,
+    shared_dir: PathBuf,
+    readonly: bool,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl Fs {
+    /// Create a new virtio-fs device sharing `shared_dir` with the guest,
+    /// preventing any modification of its content if `readonly` is set.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        shared_dir: &Path,
+        tag: &str,
+        req_num_queues: usize,
+        queue_size: u16,
+        readonly: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<Fs> {
+        let root_dir = shared_dir
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid shared_dir"))?;
+
+        let passthrough_fs = PassthroughFs::new(PassthroughConfig {
+            root_dir: root_dir.to_owned(),
+            do_import: true,
+            ..Default::default()
+        })?;
+        passthrough_fs.import()?;
+
+        let mut config = VirtioFsConfig::default();
+        let tag_bytes = tag.as_bytes();
+        let len = tag_bytes.len().min(config.tag.len());
+        config.tag[..len].copy_from_slice(&tag_bytes[..len]);
+        config.num_request_queues = req_num_queues as u32;
+
+        let num_queues = NUM_QUEUE_OFFSET + req_num_queues;
+
+        Ok(Fs {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Fs as u32,
+                queue_sizes: vec![queue_size; num_queues],
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features: 1u64 << VIRTIO_F_VERSION_1,
+                min_queues: num_queues as u16,
+                paused: Arc::new(AtomicBool::new(false)),
+                ..Default::default()
+            },
+            id,
+            config,
+            server: Arc::new(Server::new(passthrough_fs)),
+            shared_dir: shared_dir.to_owned(),
+            readonly,
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for Fs {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Fs {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(self.config.as_slice(), offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let mut handler = FsEpollHandler {
+            mem,
+            queues,
+            server: self.server.clone(),
+            shared_dir: self.shared_dir.clone(),
+            readonly: self.readonly,
+            interrupt_cb,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioFs,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn snapshot_blocker(&self) -> Option<String> {
+        Some(String::from(
+            "built-in virtio-fs server state can't be carried over",
+        ))
+    }
+}
+
+impl Pausable for Fs {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Fs {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The state of the passthrough filesystem (open inodes and handles)
+    // lives in the VMM process and can't be carried over.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "Built-in virtio-fs device {} does not support snapshot",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for Fs {}
+impl Migratable for Fs {}
@@ -0,0 +1,42 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Entry points into the request parsers of the virtio devices.
+//!
+//! They let fuzzers feed arbitrary descriptor chains to the parsers without
+//! having to create and activate the devices, nor to reach into their
+//! internals.
+
+use crate::vsock::{VsockError, VsockPacket};
+use crate::GuestMemoryMmap;
+use block::{Error as BlockError, Request};
+use net_util::{CtrlQueue, CtrlQueueError};
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::GuestMemoryLoadGuard;
+
+pub type FuzzDescriptorChain = DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>;
+
+/// Parses a virtio-block request.
+pub fn parse_block_request(desc_chain: &mut FuzzDescriptorChain) -> Result<(), BlockError> {
+    Request::parse(desc_chain, None).map(|_| ())
+}
+
+/// Parses a packet sent by the driver on the virtio-vsock TX queue.
+pub fn parse_vsock_tx_packet(desc_chain: &mut FuzzDescriptorChain) -> Result<(), VsockError> {
+    VsockPacket::from_tx_virtq_head(desc_chain, None).map(|_| ())
+}
+
+/// Parses a buffer provided by the driver on the virtio-vsock RX queue.
+pub fn parse_vsock_rx_packet(desc_chain: &mut FuzzDescriptorChain) -> Result<(), VsockError> {
+    VsockPacket::from_rx_virtq_head(desc_chain, None).map(|_| ())
+}
+
+/// Processes the virtio-net control queue, the commands applying to no TAP
+/// interface.
+pub fn process_net_ctrl_queue(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+) -> Result<(), CtrlQueueError> {
+    CtrlQueue::new(Vec::new()).process(mem, queue, None)
+}
@@ -0,0 +1,657 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio input device, through which the VMM injects keyboard and pointer
+//! events into the guest, e.g. those of a remote console.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccompiler::SeccompAction;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 64;
+// Event queue, then status queue
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// New descriptors are pending on the status queue.
+const STATUS_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// New input events are pending.
+const INPUT_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+
+// Events kept while the driver provides no buffer, the oldest being dropped
+// beyond that.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Configuration space selectors, see the virtio specification.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+// select, subsel, size and padding
+const CONFIG_HEADER_SIZE: usize = 8;
+const CONFIG_DATA_SIZE: usize = 128;
+
+// See include/uapi/linux/input.h and input-event-codes.h in the kernel code.
+const BUS_VIRTUAL: u16 = 0x06;
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const SYN_REPORT: u16 = 0;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+// Highest key code a keyboard reports, KEY_MICMUTE
+const KEYBOARD_KEY_MAX: u16 = 248;
+
+/// Largest coordinate reported by a tablet, on both axes.
+pub const TABLET_ABS_MAX: u32 = 0x7fff;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Descriptor chain too short")]
+    DescriptorChainTooShort,
+    #[error("Invalid descriptor")]
+    InvalidDescriptor,
+    #[error("Failed to write to guest memory: {0}")]
+    GuestMemoryWrite(vm_memory::guest_memory::Error),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+/// Kind of input device, which decides of the events the guest expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum InputKind {
+    Keyboard,
+    /// Pointer reporting absolute coordinates, which follows the pointer of
+    /// a remote console without the guest accelerating it.
+    Tablet,
+}
+
+impl InputKind {
+    fn name(&self) -> &'static [u8] {
+        match self {
+            InputKind::Keyboard => b"Cloud Hypervisor Keyboard",
+            InputKind::Tablet => b"Cloud Hypervisor Tablet",
+        }
+    }
+
+    fn product(&self) -> u16 {
+        match self {
+            InputKind::Keyboard => 1,
+            InputKind::Tablet => 2,
+        }
+    }
+
+    // Codes reported for the events of the given type
+    fn codes(&self, event_type: u16) -> Vec<u16> {
+        match (self, event_type) {
+            (InputKind::Keyboard, EV_KEY) => (1..=KEYBOARD_KEY_MAX).collect(),
+            (InputKind::Tablet, EV_KEY) => vec![BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+            (InputKind::Tablet, EV_REL) => vec![REL_WHEEL],
+            (InputKind::Tablet, EV_ABS) => vec![ABS_X, ABS_Y],
+            _ => Vec::new(),
+        }
+    }
+
+    // Content of the configuration space for the given selector
+    fn config_data(&self, select: u8, subsel: u8) -> Vec<u8> {
+        match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => self.name().to_vec(),
+            VIRTIO_INPUT_CFG_ID_DEVIDS if subsel == 0 => [BUS_VIRTUAL, 0x0627, self.product(), 1]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect(),
+            VIRTIO_INPUT_CFG_EV_BITS => {
+                let codes = self.codes(subsel.into());
+                let mut bitmap =
+                    vec![0u8; codes.iter().max().map_or(0, |max| *max as usize / 8 + 1)];
+                for code in codes {
+                    bitmap[code as usize / 8] |= 1 << (code % 8);
+                }
+                bitmap
+            }
+            VIRTIO_INPUT_CFG_ABS_INFO
+                if *self == InputKind::Tablet && matches!(u16::from(subsel), ABS_X | ABS_Y) =>
+            {
+                // min, max, fuzz, flat and resolution
+                [0, TABLET_ABS_MAX, 0, 0, 0]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Event injected into the guest, as defined by the evdev interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    pub fn new(event_type: u16, code: u16, value: u32) -> Self {
+        InputEvent {
+            event_type,
+            code,
+            value,
+        }
+    }
+
+    /// Marks the end of a batch of events, which the guest handles at once.
+    pub fn sync() -> Self {
+        InputEvent::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[0..2].copy_from_slice(&self.event_type.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.code.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+/// Handle through which events are injected into the guest, whether the
+/// device is activated or not.
+#[derive(Clone)]
+pub struct InputEventSender {
+    pending: Arc<Mutex<VecDeque<InputEvent>>>,
+    input_evt: Arc<EventFd>,
+}
+
+impl InputEventSender {
+    pub fn send(&self, events: &[InputEvent]) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.extend(events);
+        let excess = pending.len().saturating_sub(MAX_PENDING_EVENTS);
+        pending.drain(..excess);
+        drop(pending);
+
+        if let Err(e) = self.input_evt.write(1) {
+            warn!("Failed to signal the input events: {:?}", e);
+        }
+    }
+}
+
+struct InputEpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    event_queue: Queue,
+    status_queue: Queue,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    event_queue_evt: EventFd,
+    status_queue_evt: EventFd,
+    input_evt: Arc<EventFd>,
+    pending: Arc<Mutex<VecDeque<InputEvent>>>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+}
+
+impl InputEpollHandler {
+    // Writes the pending events into the buffers of the driver, those left
+    // waiting for more buffers.
+    fn process_events(&mut self) -> result::Result<bool, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        let queue = &mut self.event_queue;
+
+        let mut used_descs = false;
+        while let Some(event) = pending.front() {
+            let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) else {
+                break;
+            };
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+
+            let bytes = event.to_bytes();
+            if !desc.is_write_only() || (desc.len() as usize) < bytes.len() {
+                return Err(Error::InvalidDescriptor);
+            }
+            desc_chain
+                .memory()
+                .write_slice(
+                    &bytes,
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), bytes.len()),
+                )
+                .map_err(Error::GuestMemoryWrite)?;
+            pending.pop_front();
+
+            queue
+                .add_used(
+                    desc_chain.memory(),
+                    desc_chain.head_index(),
+                    bytes.len() as u32,
+                )
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    // The status of the LEDs sent by the driver is of no use.
+    fn process_status(&mut self) -> result::Result<bool, Error> {
+        let queue = &mut self.status_queue;
+
+        let mut used_descs = false;
+        while let Some(desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+            queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(queue_index))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn handle_events(&mut self) -> result::Result<(), EpollHelperError> {
+        let needs_notification = self.process_events().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process events : {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue(0).map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.event_queue_evt.as_raw_fd(), EVENT_QUEUE_EVENT)?;
+        helper.add_event(self.status_queue_evt.as_raw_fd(), STATUS_QUEUE_EVENT)?;
+        helper.add_event(self.input_evt.as_raw_fd(), INPUT_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for InputEpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            EVENT_QUEUE_EVENT => {
+                self.event_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                self.handle_events()?;
+            }
+            STATUS_QUEUE_EVENT => {
+                self.status_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_status().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process status : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue(1).map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to signal used queue: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+            }
+            INPUT_EVENT => {
+                self.input_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get input event: {:?}", e))
+                })?;
+                self.handle_events()?;
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Virtio device injecting keyboard or pointer events into the guest.
+pub struct Input {
+    common: VirtioCommon,
+    id: String,
+    kind: InputKind,
+    // Selector of the configuration space, written by the driver
+    select: u8,
+    subsel: u8,
+    pending: Arc<Mutex<VecDeque<InputEvent>>>,
+    input_evt: Arc<EventFd>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct InputState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub kind: InputKind,
+}
+
+impl Input {
+    /// Create a new virtio input device of the given kind.
+    pub fn new(
+        id: String,
+        kind: InputKind,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+        state: Option<InputState>,
+    ) -> io::Result<Input> {
+        let (avail_features, acked_features, kind, paused) = if let Some(state) = state {
+            info!("Restoring virtio-input {}", id);
+            (state.avail_features, state.acked_features, state.kind, true)
+        } else {
+            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+            if iommu {
+                avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+            }
+
+            (avail_features, 0, kind, false)
+        };
+
+        Ok(Input {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Input as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                acked_features,
+                min_queues: 2,
+                paused: Arc::new(AtomicBool::new(paused)),
+                ..Default::default()
+            },
+            id,
+            kind,
+            select: 0,
+            subsel: 0,
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            input_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    fn state(&self) -> InputState {
+        InputState {
+            avail_features: self.common.avail_features,
+            acked_features: self.common.acked_features,
+            kind: self.kind,
+        }
+    }
+
+    /// Returns a handle injecting events into the guest.
+    pub fn event_sender(&self) -> InputEventSender {
+        InputEventSender {
+            pending: self.pending.clone(),
+            input_evt: self.input_evt.clone(),
+        }
+    }
+
+    fn config(&self) -> [u8; CONFIG_HEADER_SIZE + CONFIG_DATA_SIZE] {
+        let data = self.kind.config_data(self.select, self.subsel);
+        let size = data.len().min(CONFIG_DATA_SIZE);
+
+        let mut config = [0u8; CONFIG_HEADER_SIZE + CONFIG_DATA_SIZE];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = size as u8;
+        config[CONFIG_HEADER_SIZE..CONFIG_HEADER_SIZE + size].copy_from_slice(&data[..size]);
+        config
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for Input {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(&self.config(), offset, data);
+    }
+
+    // Only the selector is writable.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        for (offset, value) in (offset..).zip(data) {
+            match offset {
+                0 => self.select = *value,
+                1 => self.subsel = *value,
+                _ => warn!("Write to read-only virtio-input configuration at {offset}"),
+            }
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let (_, event_queue, event_queue_evt) = queues.remove(0);
+        let (_, status_queue, status_queue_evt) = queues.remove(0);
+        if self.input_evt.write(1).is_err() {
+            error!("Failed to signal the pending input events");
+            return Err(ActivateError::BadActivate);
+        }
+
+        let mut handler = InputEpollHandler {
+            mem,
+            event_queue,
+            status_queue,
+            interrupt_cb,
+            event_queue_evt,
+            status_queue_evt,
+            input_evt: self.input_evt.clone(),
+            pending: self.pending.clone(),
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioInput,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        // The events meant for the previous driver are dropped.
+        self.pending.lock().unwrap().clear();
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+}
+
+impl Pausable for Input {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for Input {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+
+impl Transportable for Input {}
+impl Migratable for Input {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(kind: InputKind) -> Input {
+        Input::new(
+            "input0".to_string(),
+            kind,
+            false,
+            SeccompAction::Trap,
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn select(device: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        device.write_config(0, &[select, subsel]);
+        let mut header = [0u8; CONFIG_HEADER_SIZE];
+        device.read_config(0, &mut header);
+        assert_eq!(&header[..2], &[select, subsel]);
+
+        let mut data = vec![0u8; header[2] as usize];
+        device.read_config(CONFIG_HEADER_SIZE as u64, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_input_config() {
+        let mut keyboard = input(InputKind::Keyboard);
+        assert_eq!(
+            select(&mut keyboard, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"Cloud Hypervisor Keyboard"
+        );
+        let keys = select(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(keys.len(), 32);
+        assert_eq!(keys[0], 0xfe);
+        assert!(select(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8).is_empty());
+
+        let mut tablet = input(InputKind::Tablet);
+        let buttons = select(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(buttons.len(), 35);
+        assert_eq!(buttons[34], 0x07);
+        assert_eq!(
+            select(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            [0x03]
+        );
+        let abs_info = select(&mut tablet, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(&abs_info[4..8], &TABLET_ABS_MAX.to_le_bytes());
+        assert!(select(&mut tablet, 0x42, 0).is_empty());
+    }
+
+    #[test]
+    fn test_input_pending_events() {
+        let keyboard = input(InputKind::Keyboard);
+        let sender = keyboard.event_sender();
+        let events: Vec<InputEvent> = (0..MAX_PENDING_EVENTS as u32 + 2)
+            .map(|value| InputEvent::new(EV_KEY, 30, value))
+            .collect();
+        sender.send(&events);
+
+        // The oldest events are dropped
+        let pending = keyboard.pending.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_EVENTS);
+        assert_eq!(pending.front().unwrap().value, 2);
+        assert_eq!(
+            InputEvent::new(EV_KEY, 30, 1).to_bytes(),
+            [1, 0, 30, 0, 1, 0, 0, 0]
+        );
+    }
+}
@@ -25,9 +25,16 @@ pub mod balloon;
 pub mod block;
 mod console;
 pub mod epoll_helper;
+#[cfg(feature = "fs_builtin")]
+pub mod fs;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod input;
 mod iommu;
 pub mod mem;
 pub mod net;
+#[cfg(feature = "virtio_9p")]
+pub mod p9;
 mod pmem;
 mod rng;
 pub mod seccomp_filters;
@@ -48,6 +55,7 @@ pub use self::device::{
 pub use self::epoll_helper::{
     EpollHelper, EpollHelperError, EpollHelperHandler, EPOLL_HELPER_EVENT_LAST,
 };
+pub use self::input::{Input, InputEventSender, InputKind};
 pub use self::iommu::{AccessPlatformMapping, Iommu, IommuMapping};
 pub use self::mem::{BlocksState, Mem, VirtioMemMappingSource, VIRTIO_MEM_ALIGN_SIZE};
 pub use self::net::{Net, NetCtrlEpollHandler};
@@ -122,6 +130,8 @@ pub enum Error {
     QueueAddUsed(virtio_queue::Error),
     #[error("Failed to : {0}")]
     QueueIterator(virtio_queue::Error),
+    #[error("Failed to check if queue needs notification: {0}")]
+    QueueNeedsNotification(virtio_queue::Error),
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
@@ -155,6 +165,28 @@ impl TryInto<rate_limiter::RateLimiter> for RateLimiterConfig {
     }
 }
 
+impl TokenBucketConfig {
+    fn bucket_update(config: Option<Self>) -> rate_limiter::BucketUpdate {
+        match config.and_then(|c| {
+            rate_limiter::TokenBucket::new(c.size, c.one_time_burst.unwrap_or(0), c.refill_time)
+        }) {
+            Some(bucket) => rate_limiter::BucketUpdate::Update(bucket),
+            None => rate_limiter::BucketUpdate::Disabled,
+        }
+    }
+}
+
+impl RateLimiterConfig {
+    /// Bandwidth and ops bucket updates bringing a rate limiter in line with
+    /// the configuration.
+    pub fn bucket_updates(&self) -> (rate_limiter::BucketUpdate, rate_limiter::BucketUpdate) {
+        (
+            TokenBucketConfig::bucket_update(self.bandwidth),
+            TokenBucketConfig::bucket_update(self.ops),
+        )
+    }
+}
+
 /// Convert an absolute address into an address space (GuestMemory)
 /// to a host pointer and verify that the provided size define a valid
 /// range within a single memory region.
@@ -21,12 +21,13 @@ use net_util::virtio_features_to_tap_offload;
 use net_util::CtrlQueue;
 use net_util::{
     build_net_config_space, build_net_config_space_with_mq, open_tap, MacAddr, NetCounters,
-    NetQueuePair, OpenTapError, RxVirtio, Tap, TapError, TxVirtio, VirtioNetConfig,
+    NetQueuePair, OpenTapError, RouterAdvertiser, RxVirtio, Tap, TapError, TxVirtio,
+    VirtioNetConfig,
 };
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -41,7 +42,7 @@ use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
-use vm_virtio::AccessPlatform;
+use vm_virtio::{AccessPlatform, QueueCounters};
 use vmm_sys_util::eventfd::EventFd;
 
 /// Control queue
@@ -161,6 +162,8 @@ pub enum Error {
     TapError(TapError),
     #[error("Error calling dup() on tap fd: {0}")]
     DuplicateTapFd(std::io::Error),
+    #[error("Failed to set up the router advertisements: {0}")]
+    RouterAdvertiser(std::io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -416,9 +419,11 @@ pub struct Net {
     config: VirtioNetConfig,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     counters: NetCounters,
+    queue_counters: Vec<QueueCounters>,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
     exit_evt: EventFd,
+    router_advertiser: Option<Arc<RouterAdvertiser>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -535,9 +540,11 @@ impl Net {
             config,
             ctrl_queue_epoll_thread: None,
             counters: NetCounters::default(),
+            queue_counters: (0..num_queues).map(|_| QueueCounters::default()).collect(),
             seccomp_action,
             rate_limiter_config,
             exit_evt,
+            router_advertiser: None,
         })
     }
 
@@ -653,6 +660,32 @@ impl Net {
         }
     }
 
+    /// Name of the TAP interface backing the device.
+    pub fn tap_if_name(&self) -> String {
+        let if_name = self.taps[0].get_if_name();
+        String::from_utf8_lossy(&if_name)
+            .trim_end_matches(char::from(0))
+            .to_owned()
+    }
+
+    /// Change the MAC address the device reports. The driver only reads it
+    /// when probing the device, a running guest keeping the address it got
+    /// until it's told the new one by other means.
+    pub fn set_mac(&mut self, mac: MacAddr) {
+        self.config.mac.copy_from_slice(mac.get_bytes());
+    }
+
+    /// Advertise `addr` as the router of the on-link prefix `addr/prefix_len`
+    /// to the guest, so that it can autoconfigure its IPv6 address (SLAAC) and
+    /// default route. The advertisements are sent while the device is active.
+    pub fn enable_router_advertisement(&mut self, addr: Ipv6Addr, prefix_len: u8) -> Result<()> {
+        let mac = self.taps[0].get_mac_addr().map_err(Error::TapError)?;
+        let advertiser = RouterAdvertiser::new(&self.tap_if_name(), &mac, addr, prefix_len)
+            .map_err(Error::RouterAdvertiser)?;
+        self.router_advertiser = Some(Arc::new(advertiser));
+        Ok(())
+    }
+
     #[cfg(fuzzing)]
     pub fn wait_for_epoll_threads(&mut self) {
         self.common.wait_for_epoll_threads();
@@ -747,8 +780,10 @@ impl VirtioDevice for Net {
         let mut epoll_threads = Vec::new();
         let mut taps = self.taps.clone();
         for i in 0..queues.len() / 2 {
-            let rx = RxVirtio::new();
-            let tx = TxVirtio::new();
+            let mut rx = RxVirtio::new();
+            rx.queue_counters = self.queue_counters[i * 2].clone();
+            let mut tx = TxVirtio::new();
+            tx.queue_counters = self.queue_counters[i * 2 + 1].clone();
             let rx_tap_listening = false;
 
             let (_, queue_0, queue_evt_0) = queues.remove(0);
@@ -821,6 +856,21 @@ impl VirtioDevice for Net {
             )?;
         }
 
+        // The advertisements thread doesn't process any queue, hence it is
+        // not accounted for in the pause barrier. It is stopped along with
+        // the queue pairs threads through the kill event.
+        if let Some(advertiser) = self.router_advertiser.clone() {
+            let (kill_evt, _) = self.common.dup_eventfds();
+            spawn_virtio_thread(
+                &format!("{}_ra", self.id),
+                &self.seccomp_action,
+                Thread::VirtioNetRa,
+                &mut epoll_threads,
+                &self.exit_evt,
+                move || advertiser.run(&kill_evt).map_err(EpollHelperError::IoError),
+            )?;
+        }
+
         self.common.epoll_threads = Some(epoll_threads);
 
         event!("virtio-device", "activated", "id", &self.id);
@@ -856,6 +906,15 @@ impl VirtioDevice for Net {
         Some(counters)
     }
 
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        Some(
+            self.queue_counters
+                .iter()
+                .map(QueueCounters::counters)
+                .collect(),
+        )
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
@@ -0,0 +1,402 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio-9p device.
+//!
+//! The 9P2000.L requests sent by the guest are served by a local passthrough
+//! server, sharing a host directory with the guest.
+
+use super::Error as DeviceError;
+use super::{
+    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::seccomp_filters::Thread;
+use crate::thread_helper::spawn_virtio_thread;
+use crate::GuestMemoryMmap;
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use seccompiler::SeccompAction;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::result;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use virtio_queue::{DescriptorChain, Queue, QueueT};
+use vm_memory::{
+    Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError, GuestMemoryLoadGuard,
+};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{AccessPlatform, Translatable};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+// The mount tag is provided through the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+/// Maximum length of the mount tag.
+pub const VIRTIO_9P_TAG_LEN: usize = u16::MAX as usize;
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+// Largest message accepted until the guest negotiates it through Tversion,
+// which is the first message it sends.
+const DEFAULT_MSIZE: u32 = 8192;
+// size[4] type[1] tag[2], followed by the msize in the version messages
+const P9_HEADER_SIZE: usize = 7;
+const P9_RVERSION: u8 = 101;
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Bad guest memory addresses: {0}")]
+    GuestMemory(GuestMemoryError),
+    #[error("Failed to process 9P request: {0}")]
+    ProcessRequest(io::Error),
+    #[error("Request larger than the negotiated msize ({0} bytes)")]
+    RequestTooLarge(usize),
+    #[error("Response does not fit in the descriptor chain ({0} bytes)")]
+    ResponseTooLarge(usize),
+    #[error("Failed adding used index: {0}")]
+    QueueAddUsed(virtio_queue::Error),
+}
+
+// Returns the msize agreed on by the server when `response` is a Rversion.
+fn negotiated_msize(response: &[u8]) -> Option<u32> {
+    if response.get(4) != Some(&P9_RVERSION) {
+        return None;
+    }
+    let msize = response.get(P9_HEADER_SIZE..P9_HEADER_SIZE + 4)?;
+    Some(u32::from_le_bytes(msize.try_into().unwrap()))
+}
+
+struct P9EpollHandler {
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queue: Queue,
+    server: Arc<Mutex<p9::Server>>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    access_platform: Option<Arc<dyn AccessPlatform>>,
+    // Largest message accepted, as negotiated by the guest
+    msize: u32,
+}
+
+impl P9EpollHandler {
+    // Gathers the request from the device readable descriptors, and scatters
+    // the response across the device writable ones.
+    fn process_request(
+        &mut self,
+        desc_chain: &mut DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>,
+    ) -> result::Result<u32, Error> {
+        let mut request = Vec::new();
+        let mut response_descs = Vec::new();
+        for desc in desc_chain.by_ref() {
+            let addr = desc
+                .addr()
+                .translate_gva(self.access_platform.as_ref(), desc.len() as usize);
+            if desc.is_write_only() {
+                response_descs.push((addr, desc.len() as usize));
+            } else {
+                // The buffer is sized after the guest descriptors, hence
+                // being capped at the size of a message
+                let offset = request.len();
+                let size = offset + desc.len() as usize;
+                if size > self.msize as usize {
+                    return Err(Error::RequestTooLarge(size));
+                }
+                request.resize(size, 0);
+                desc_chain
+                    .memory()
+                    .read_slice(&mut request[offset..], addr)
+                    .map_err(Error::GuestMemory)?;
+            }
+        }
+
+        let mut response = Vec::new();
+        self.server
+            .lock()
+            .unwrap()
+            .handle_message(&mut request.as_slice(), &mut response)
+            .map_err(Error::ProcessRequest)?;
+        if let Some(msize) = negotiated_msize(&response) {
+            self.msize = msize;
+        }
+
+        let mut written = 0;
+        for (addr, len) in response_descs {
+            if written == response.len() {
+                break;
+            }
+            let len = len.min(response.len() - written);
+            desc_chain
+                .memory()
+                .write_slice(&response[written..written + len], addr)
+                .map_err(Error::GuestMemory)?;
+            written += len;
+        }
+        if written < response.len() {
+            return Err(Error::ResponseTooLarge(response.len()));
+        }
+
+        Ok(written as u32)
+    }
+
+    fn process_queue(&mut self) -> result::Result<bool, Error> {
+        let mut used_descs = false;
+        while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
+            let len = self.process_request(&mut desc_chain)?;
+
+            self.queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(0))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+}
+
+impl EpollHelperHandler for P9EpollHandler {
+    fn handle_event(
+        &mut self,
+        _helper: &mut EpollHelper,
+        event: &epoll::Event,
+    ) -> result::Result<(), EpollHelperError> {
+        let ev_type = event.data as u16;
+        match ev_type {
+            QUEUE_AVAIL_EVENT => {
+                self.queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to signal used queue: {:?}",
+                            e
+                        ))
+                    })?;
+                }
+            }
+            _ => {
+                return Err(EpollHelperError::HandleEvent(anyhow!(
+                    "Unexpected event: {}",
+                    ev_type
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Virtio device sharing a host directory with the guest through 9P.
+pub struct P9 {
+    common: VirtioCommon,
+    id: String,
+    config: Vec<u8>,
+    server: Arc<Mutex<p9::Server>>,
+    seccomp_action: SeccompAction,
+    exit_evt: EventFd,
+}
+
+impl P9 {
+    /// Create a new virtio-9p device sharing `shared_dir` under the mount tag `tag`.
+    pub fn new(
+        id: String,
+        shared_dir: &Path,
+        tag: &str,
+        iommu: bool,
+        seccomp_action: SeccompAction,
+        exit_evt: EventFd,
+    ) -> io::Result<P9> {
+        if tag.len() > VIRTIO_9P_TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "9P mount tag too long",
+            ));
+        }
+
+        let server = p9::Server::new(shared_dir, Default::default(), Default::default())?;
+
+        // struct virtio_9p_config {
+        //     le16 tag_len;
+        //     u8 tag[tag_len];
+        // }
+        let mut config = Vec::with_capacity(2 + tag.len());
+        config.extend_from_slice(&(tag.len() as u16).to_le_bytes());
+        config.extend_from_slice(tag.as_bytes());
+
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_9P_MOUNT_TAG;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        Ok(P9 {
+            common: VirtioCommon {
+                device_type: VirtioDeviceType::Fs9P as u32,
+                queue_sizes: QUEUE_SIZES.to_vec(),
+                paused_sync: Some(Arc::new(Barrier::new(2))),
+                avail_features,
+                min_queues: 1,
+                paused: Arc::new(AtomicBool::new(false)),
+                ..Default::default()
+            },
+            id,
+            config,
+            server: Arc::new(Mutex::new(server)),
+            seccomp_action,
+            exit_evt,
+        })
+    }
+
+    #[cfg(fuzzing)]
+    pub fn wait_for_epoll_threads(&mut self) {
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl Drop for P9 {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.common.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        self.common.wait_for_epoll_threads();
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn device_type(&self) -> u32 {
+        self.common.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.common.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.common.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.common.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.read_config_from_slice(&self.config, offset, data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<(usize, Queue, EventFd)>,
+    ) -> ActivateResult {
+        self.common.activate(&queues, &interrupt_cb)?;
+        let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        let (_, queue, queue_evt) = queues.remove(0);
+
+        let mut handler = P9EpollHandler {
+            mem,
+            queue,
+            server: self.server.clone(),
+            interrupt_cb,
+            queue_evt,
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+            msize: DEFAULT_MSIZE,
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioP9,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        let result = self.common.reset();
+        event!("virtio-device", "reset", "id", &self.id);
+        result
+    }
+
+    fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
+        self.common.set_access_platform(access_platform)
+    }
+
+    fn snapshot_blocker(&self) -> Option<String> {
+        Some(String::from("virtio-9p server state can't be carried over"))
+    }
+}
+
+impl Pausable for P9 {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.common.pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.common.resume()
+    }
+}
+
+impl Snapshottable for P9 {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The fids opened by the guest only exist in the server state, which
+    // can't be carried over.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "Virtio-9p device {} does not support snapshot",
+            self.id
+        )))
+    }
+}
+
+impl Transportable for P9 {}
+impl Migratable for P9 {}
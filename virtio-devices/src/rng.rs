@@ -6,9 +6,8 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
-    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
-    VIRTIO_F_VERSION_1,
+    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -22,7 +21,7 @@ use std::io;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
 use thiserror::Error;
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{GuestAddressSpace, GuestMemory, GuestMemoryAtomic};
@@ -51,7 +50,7 @@ enum Error {
 struct RngEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queue: Queue,
-    random_file: File,
+    random_file: Arc<Mutex<File>>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -78,7 +77,7 @@ impl RngEpollHandler {
                 .read_volatile_from(
                     desc.addr()
                         .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
-                    &mut self.random_file,
+                    &mut *self.random_file.lock().unwrap(),
                     desc.len() as usize,
                 )
                 .map_err(Error::GuestMemoryWrite)?;
@@ -153,7 +152,9 @@ impl EpollHelperHandler for RngEpollHandler {
 pub struct Rng {
     common: VirtioCommon,
     id: String,
-    random_file: Option<File>,
+    // Shared with the epoll handler, so that the source can be replaced
+    // while the device is active.
+    random_file: Arc<Mutex<File>>,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
 }
@@ -201,7 +202,7 @@ impl Rng {
                 ..Default::default()
             },
             id,
-            random_file: Some(random_file),
+            random_file: Arc::new(Mutex::new(random_file)),
             seccomp_action,
             exit_evt,
         })
@@ -214,6 +215,15 @@ impl Rng {
         }
     }
 
+    /// Replaces the source of entropy, taking effect on the next request of
+    /// the guest.
+    pub fn set_source(&self, path: &str) -> io::Result<()> {
+        let random_file = File::open(path)?;
+        *self.random_file.lock().unwrap() = random_file;
+        info!("virtio-rng {} now reading from {}", self.id, path);
+        Ok(())
+    }
+
     #[cfg(fuzzing)]
     pub fn wait_for_epoll_threads(&mut self) {
         self.common.wait_for_epoll_threads();
@@ -256,43 +266,35 @@ impl VirtioDevice for Rng {
         self.common.activate(&queues, &interrupt_cb)?;
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
-        if let Some(file) = self.random_file.as_ref() {
-            let random_file = file.try_clone().map_err(|e| {
-                error!("failed cloning rng source: {}", e);
-                ActivateError::BadActivate
-            })?;
-
-            let (_, queue, queue_evt) = queues.remove(0);
-
-            let mut handler = RngEpollHandler {
-                mem,
-                queue,
-                random_file,
-                interrupt_cb,
-                queue_evt,
-                kill_evt,
-                pause_evt,
-                access_platform: self.common.access_platform.clone(),
-            };
-
-            let paused = self.common.paused.clone();
-            let paused_sync = self.common.paused_sync.clone();
-            let mut epoll_threads = Vec::new();
-            spawn_virtio_thread(
-                &self.id,
-                &self.seccomp_action,
-                Thread::VirtioRng,
-                &mut epoll_threads,
-                &self.exit_evt,
-                move || handler.run(paused, paused_sync.unwrap()),
-            )?;
-
-            self.common.epoll_threads = Some(epoll_threads);
-
-            event!("virtio-device", "activated", "id", &self.id);
-            return Ok(());
-        }
-        Err(ActivateError::BadActivate)
+        let (_, queue, queue_evt) = queues.remove(0);
+
+        let mut handler = RngEpollHandler {
+            mem,
+            queue,
+            random_file: self.random_file.clone(),
+            interrupt_cb,
+            queue_evt,
+            kill_evt,
+            pause_evt,
+            access_platform: self.common.access_platform.clone(),
+        };
+
+        let paused = self.common.paused.clone();
+        let paused_sync = self.common.paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        spawn_virtio_thread(
+            &self.id,
+            &self.seccomp_action,
+            Thread::VirtioRng,
+            &mut epoll_threads,
+            &self.exit_evt,
+            move || handler.run(paused, paused_sync.unwrap()),
+        )?;
+
+        self.common.epoll_threads = Some(epoll_threads);
+
+        event!("virtio-device", "activated", "id", &self.id);
+        Ok(())
     }
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
@@ -13,10 +13,16 @@ pub enum Thread {
     VirtioBalloon,
     VirtioBlock,
     VirtioConsole,
+    #[cfg(feature = "fs_builtin")]
+    VirtioFs,
+    VirtioInput,
     VirtioIommu,
     VirtioMem,
     VirtioNet,
     VirtioNetCtl,
+    VirtioNetRa,
+    #[cfg(feature = "virtio_9p")]
+    VirtioP9,
     VirtioPmem,
     VirtioRng,
     VirtioVhostBlock,
@@ -56,6 +62,17 @@ const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 
+#[cfg(feature = "fs_builtin")]
+fn create_virtio_fs_prctl_seccomp_rule() -> Vec<SeccompRule> {
+    or![and![Cond::new(
+        0,
+        ArgLen::Dword,
+        Eq,
+        libc::PR_SET_NO_NEW_PRIVS as u64
+    )
+    .unwrap()]]
+}
+
 fn create_virtio_console_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![and![Cond::new(1, ArgLen::Dword, Eq, TIOCGWINSZ).unwrap()]]
 }
@@ -80,6 +97,7 @@ fn virtio_balloon_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 
 fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_fadvise64, vec![]),
         (libc::SYS_fallocate, vec![]),
         (libc::SYS_fdatasync, vec![]),
         (libc::SYS_fsync, vec![]),
@@ -138,10 +156,104 @@ fn virtio_net_ctl_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![(libc::SYS_ioctl, create_virtio_net_ctl_ioctl_seccomp_rule())]
 }
 
+fn virtio_net_ra_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (libc::SYS_ppoll, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_sendto, vec![]),
+    ]
+}
+
+// The passthrough filesystem performs the file operations requested by the
+// guest, on behalf of the guest, switching to the credentials of the guest
+// process when creating files.
+#[cfg(feature = "fs_builtin")]
+fn virtio_fs_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_fallocate, vec![]),
+        (libc::SYS_fchmod, vec![]),
+        (libc::SYS_fchmodat, vec![]),
+        (libc::SYS_fchownat, vec![]),
+        (libc::SYS_fdatasync, vec![]),
+        (libc::SYS_fgetxattr, vec![]),
+        (libc::SYS_flistxattr, vec![]),
+        (libc::SYS_flock, vec![]),
+        (libc::SYS_fremovexattr, vec![]),
+        (libc::SYS_fsetxattr, vec![]),
+        (libc::SYS_fstatfs, vec![]),
+        (libc::SYS_fsync, vec![]),
+        (libc::SYS_ftruncate, vec![]),
+        (libc::SYS_getdents64, vec![]),
+        (libc::SYS_landlock_add_rule, vec![]),
+        (libc::SYS_landlock_create_ruleset, vec![]),
+        (libc::SYS_landlock_restrict_self, vec![]),
+        (libc::SYS_linkat, vec![]),
+        (libc::SYS_lseek, vec![]),
+        (libc::SYS_mkdirat, vec![]),
+        (libc::SYS_mknodat, vec![]),
+        (libc::SYS_name_to_handle_at, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        (libc::SYS_open_by_handle_at, vec![]),
+        (libc::SYS_openat, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_prctl, create_virtio_fs_prctl_seccomp_rule()),
+        (libc::SYS_preadv, vec![]),
+        (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_pwritev, vec![]),
+        (libc::SYS_readlinkat, vec![]),
+        (libc::SYS_renameat2, vec![]),
+        (libc::SYS_setresgid, vec![]),
+        (libc::SYS_setresuid, vec![]),
+        (libc::SYS_statx, vec![]),
+        (libc::SYS_symlinkat, vec![]),
+        (libc::SYS_unlinkat, vec![]),
+        (libc::SYS_utimensat, vec![]),
+    ]
+}
+
+// The 9P server performs the file operations requested by the guest, on
+// behalf of the guest.
+#[cfg(feature = "virtio_9p")]
+fn virtio_p9_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_fchmodat, vec![]),
+        (libc::SYS_fchownat, vec![]),
+        (libc::SYS_fdatasync, vec![]),
+        (libc::SYS_fstatfs, vec![]),
+        (libc::SYS_fsync, vec![]),
+        (libc::SYS_ftruncate, vec![]),
+        (libc::SYS_getdents64, vec![]),
+        (libc::SYS_linkat, vec![]),
+        (libc::SYS_lseek, vec![]),
+        (libc::SYS_mkdirat, vec![]),
+        (libc::SYS_mknodat, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        (libc::SYS_openat, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_readlinkat, vec![]),
+        (libc::SYS_renameat2, vec![]),
+        (libc::SYS_statx, vec![]),
+        (libc::SYS_symlinkat, vec![]),
+        (libc::SYS_unlinkat, vec![]),
+        (libc::SYS_utimensat, vec![]),
+    ]
+}
+
 fn virtio_pmem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![(libc::SYS_fsync, vec![])]
 }
 
+fn virtio_input_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
+    vec![
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+    ]
+}
+
 fn virtio_rng_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_sched_getaffinity, vec![]),
@@ -179,6 +291,7 @@ fn virtio_vhost_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_recvmsg, vec![]),
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_sendto, vec![]),
+        (libc::SYS_setsockopt, vec![]),
         (libc::SYS_socket, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_unlink, vec![]),
@@ -208,6 +321,7 @@ fn virtio_vsock_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_connect, vec![]),
         (libc::SYS_ioctl, create_vsock_ioctl_seccomp_rule()),
         (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_sendto, vec![]),
         (libc::SYS_socket, vec![]),
     ]
 }
@@ -225,10 +339,16 @@ fn get_seccomp_rules(thread_type: Thread) -> Vec<(i64, Vec<SeccompRule>)> {
         Thread::VirtioBalloon => virtio_balloon_thread_rules(),
         Thread::VirtioBlock => virtio_block_thread_rules(),
         Thread::VirtioConsole => virtio_console_thread_rules(),
+        #[cfg(feature = "fs_builtin")]
+        Thread::VirtioFs => virtio_fs_thread_rules(),
+        Thread::VirtioInput => virtio_input_thread_rules(),
         Thread::VirtioIommu => virtio_iommu_thread_rules(),
         Thread::VirtioMem => virtio_mem_thread_rules(),
         Thread::VirtioNet => virtio_net_thread_rules(),
         Thread::VirtioNetCtl => virtio_net_ctl_thread_rules(),
+        Thread::VirtioNetRa => virtio_net_ra_thread_rules(),
+        #[cfg(feature = "virtio_9p")]
+        Thread::VirtioP9 => virtio_p9_thread_rules(),
         Thread::VirtioPmem => virtio_pmem_thread_rules(),
         Thread::VirtioRng => virtio_rng_thread_rules(),
         Thread::VirtioVhostBlock => virtio_vhost_block_thread_rules(),
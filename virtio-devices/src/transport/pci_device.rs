@@ -290,6 +290,10 @@ pub struct VirtioPciDeviceActivator {
 }
 
 impl VirtioPciDeviceActivator {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn activate(&mut self) -> ActivateResult {
         self.device.lock().unwrap().activate(
             self.memory.take().unwrap(),
@@ -605,18 +609,19 @@ impl VirtioPciDevice {
             )));
         }
 
-        // In case of a restore, we can activate the device, as we know at
-        // this point the virtqueues are in the right state and the device is
-        // ready to be activated, which will spawn each virtio worker thread.
+        // In case of a restore, the device can be activated, as we know at
+        // this point the virtqueues are in the right state. The activation,
+        // spawning each virtio worker thread, is left pending for the VMM to
+        // perform once it's done restoring the VM.
         if virtio_pci_device.device_activated.load(Ordering::SeqCst)
             && virtio_pci_device.is_driver_ready()
         {
-            virtio_pci_device.activate().map_err(|e| {
-                VirtioPciDeviceError::CreateVirtioPciDevice(anyhow!(
-                    "Failed activating the device: {}",
-                    e
-                ))
-            })?;
+            let activator = virtio_pci_device.prepare_activator(None);
+            virtio_pci_device
+                .pending_activations
+                .lock()
+                .unwrap()
+                .push(activator);
         }
 
         Ok(virtio_pci_device)
@@ -816,10 +821,6 @@ impl VirtioPciDevice {
         }
     }
 
-    fn activate(&mut self) -> ActivateResult {
-        self.prepare_activator(None).activate()
-    }
-
     fn needs_activation(&self) -> bool {
         !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
     }
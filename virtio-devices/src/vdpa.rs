@@ -448,6 +448,20 @@ impl VirtioDevice for Vdpa {
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
+
+    fn snapshot_blocker(&self) -> Option<String> {
+        Some(String::from(
+            "vDPA device can only be snapshotted during live migration",
+        ))
+    }
+
+    fn migration_blocker(&self) -> Option<String> {
+        if self.backend_features & (1 << VHOST_BACKEND_F_SUSPEND) == 0 {
+            Some(String::from("vDPA device can't be suspended"))
+        } else {
+            None
+        }
+    }
 }
 
 impl Pausable for Vdpa {
@@ -74,8 +74,13 @@ impl Blk {
     ) -> Result<Blk> {
         let num_queues = vu_cfg.num_queues;
 
-        let mut vu =
-            VhostUserHandle::connect_vhost_user(false, &vu_cfg.socket, num_queues as u64, false)?;
+        let mut vu = VhostUserHandle::connect_vhost_user(
+            false,
+            &vu_cfg.socket,
+            num_queues as u64,
+            false,
+            vu_cfg.instance_id.as_deref(),
+        )?;
 
         let (
             avail_features,
@@ -190,6 +195,7 @@ impl Blk {
                 acked_protocol_features,
                 socket_path: vu_cfg.socket,
                 vu_num_queues,
+                instance_id: vu_cfg.instance_id,
                 ..Default::default()
             },
             id,
@@ -359,6 +365,10 @@ impl VirtioDevice for Blk {
     ) -> std::result::Result<(), crate::Error> {
         self.vu_common.add_memory_region(&self.guest_memory, region)
     }
+
+    fn migration_blocker(&self) -> Option<String> {
+        self.vu_common.migration_blocker()
+    }
 }
 
 impl Pausable for Blk {
@@ -11,6 +11,7 @@ use crate::{
     VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_IOMMU_PLATFORM,
 };
 use crate::{GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use anyhow::anyhow;
 use libc::{c_void, off64_t, pread64, pwrite64};
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
@@ -21,6 +22,7 @@ use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use vhost::vhost_user::message::{
     VhostUserFSBackendMsg, VhostUserFSBackendMsgFlags, VhostUserProtocolFeatures,
     VhostUserVirtioFeatures, VHOST_USER_FS_BACKEND_ENTRIES,
@@ -40,6 +42,9 @@ use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
 const DEFAULT_QUEUE_NUMBER: usize = 2;
+// How long the backend is given to complete the pending requests when the
+// device is paused.
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize)]
 pub struct State {
@@ -305,6 +310,8 @@ pub struct Fs {
     epoll_thread: Option<thread::JoinHandle<()>>,
     exit_evt: EventFd,
     iommu: bool,
+    // No request was in flight when the device got paused
+    quiesced: bool,
 }
 
 impl Fs {
@@ -328,7 +335,8 @@ impl Fs {
         let num_queues = NUM_QUEUE_OFFSET + req_num_queues;
 
         // Connect to the vhost-user socket.
-        let mut vu = VhostUserHandle::connect_vhost_user(false, path, num_queues as u64, false)?;
+        let mut vu =
+            VhostUserHandle::connect_vhost_user(false, path, num_queues as u64, false, None)?;
 
         let (
             avail_features,
@@ -449,6 +457,8 @@ impl Fs {
             epoll_thread: None,
             exit_evt,
             iommu,
+            // The device was quiesced when it got snapshotted
+            quiesced: paused,
         })
     }
 
@@ -626,6 +636,10 @@ impl VirtioDevice for Fs {
         self.vu_common.add_memory_region(&self.guest_memory, region)
     }
 
+    fn migration_blocker(&self) -> Option<String> {
+        self.vu_common.migration_blocker()
+    }
+
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
         let mut mappings = Vec::new();
         if let Some(cache) = self.cache.as_ref() {
@@ -644,11 +658,26 @@ impl VirtioDevice for Fs {
 
 impl Pausable for Fs {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The vCPUs being paused already, let the backend complete the
+        // requests the guest submitted. The vrings restart from the available
+        // index of the guest once restored, which would skip any request
+        // still in flight when snapshotting.
+        self.quiesced = self
+            .vu_common
+            .wait_for_idle_vrings(&self.guest_memory, QUIESCE_TIMEOUT)?;
+        if !self.quiesced {
+            warn!(
+                "vhost-user-fs {}: requests still in flight after {:?}",
+                self.id, QUIESCE_TIMEOUT
+            );
+        }
+
         self.vu_common.pause()?;
         self.common.pause()
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.quiesced = false;
         self.common.resume()?;
 
         if let Some(epoll_thread) = &self.epoll_thread {
@@ -665,6 +694,13 @@ impl Snapshottable for Fs {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        if !self.quiesced {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "vhost-user-fs {} had requests in flight when paused, resume the VM and retry",
+                self.id
+            )));
+        }
+
         self.vu_common.snapshot(&self.state())
     }
 }
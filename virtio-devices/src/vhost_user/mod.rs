@@ -13,6 +13,7 @@ use std::io;
 use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::sync::{atomic::AtomicBool, Arc, Barrier, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 use vhost::vhost_user::message::{
     VhostUserInflight, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
@@ -37,7 +38,7 @@ pub mod vu_common_ctrl;
 pub use self::blk::Blk;
 pub use self::fs::*;
 pub use self::net::Net;
-pub use self::vu_common_ctrl::VhostUserConfig;
+pub use self::vu_common_ctrl::{VhostUserConfig, INSTANCE_ID_MAX_LEN};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -67,6 +68,10 @@ pub enum Error {
     VhostUserOpen(VhostError),
     #[error("Connection to socket failed")]
     VhostUserConnect,
+    #[error("Failed identifying the instance to the backend: {0}")]
+    VhostUserInstanceId(io::Error),
+    #[error("Instance {0} rejected by the backend with status {1}")]
+    VhostUserInstanceRejected(String, u32),
     #[error("Get features failed: {0}")]
     VhostUserGetFeatures(VhostError),
     #[error("Get queue max number failed: {0}")]
@@ -143,6 +148,8 @@ pub enum Error {
     NewMmapRegion(MmapRegionError),
     #[error("Could not find the shm log region")]
     MissingShmLogRegion,
+    #[error("Failed reading the vring index: {0}")]
+    ReadVringIndex(MmapError),
 }
 type Result<T> = std::result::Result<T, Error>;
 
@@ -174,6 +181,7 @@ pub struct VhostUserEpollHandler<S: VhostUserFrontendReqHandler> {
     pub acked_protocol_features: u64,
     pub socket_path: String,
     pub server: bool,
+    pub instance_id: Option<String>,
     pub backend_req_handler: Option<FrontendReqHandler<S>>,
     pub inflight: Option<Inflight>,
 }
@@ -212,6 +220,7 @@ impl<S: VhostUserFrontendReqHandler> VhostUserEpollHandler<S> {
             &self.socket_path,
             self.queues.len() as u64,
             true,
+            self.instance_id.as_deref(),
         )
         .map_err(|e| {
             EpollHelperError::IoError(std::io::Error::new(
@@ -300,6 +309,7 @@ pub struct VhostUserCommon {
     pub vu_num_queues: usize,
     pub migration_started: bool,
     pub server: bool,
+    pub instance_id: Option<String>,
 }
 
 impl VhostUserCommon {
@@ -353,6 +363,7 @@ impl VhostUserCommon {
             acked_protocol_features: self.acked_protocol_features,
             socket_path: self.socket_path.clone(),
             server: self.server,
+            instance_id: self.instance_id.clone(),
             backend_req_handler,
             inflight,
         })
@@ -364,6 +375,7 @@ impl VhostUserCommon {
             &self.socket_path,
             self.vu_num_queues as u64,
             false,
+            self.instance_id.as_deref(),
         )?;
 
         vu.set_protocol_features_vhost_user(acked_features, self.acked_protocol_features)?;
@@ -430,6 +442,35 @@ impl VhostUserCommon {
         }
     }
 
+    pub fn wait_for_idle_vrings(
+        &mut self,
+        guest_memory: &Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+        timeout: Duration,
+    ) -> std::result::Result<bool, MigratableError> {
+        match (&self.vu, guest_memory) {
+            (Some(vu), Some(guest_memory)) => vu
+                .lock()
+                .unwrap()
+                .wait_for_idle_vrings(guest_memory.memory().deref(), timeout)
+                .map_err(|e| {
+                    MigratableError::Pause(anyhow!(
+                        "Error waiting for the vhost-user backend requests: {:?}",
+                        e
+                    ))
+                }),
+            _ => Ok(true),
+        }
+    }
+
+    pub fn migration_blocker(&self) -> Option<String> {
+        match &self.vu {
+            Some(vu) if !vu.lock().unwrap().supports_migration() => Some(String::from(
+                "vhost-user backend does not support dirty page logging",
+            )),
+            _ => None,
+        }
+    }
+
     pub fn snapshot<'a, T>(&mut self, state: &T) -> std::result::Result<Snapshot, MigratableError>
     where
         T: Serialize + Deserialize<'a>,
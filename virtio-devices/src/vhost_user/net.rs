@@ -80,8 +80,13 @@ impl Net {
     ) -> Result<Net> {
         let mut num_queues = vu_cfg.num_queues;
 
-        let mut vu =
-            VhostUserHandle::connect_vhost_user(server, &vu_cfg.socket, num_queues as u64, false)?;
+        let mut vu = VhostUserHandle::connect_vhost_user(
+            server,
+            &vu_cfg.socket,
+            num_queues as u64,
+            false,
+            vu_cfg.instance_id.as_deref(),
+        )?;
 
         let (
             avail_features,
@@ -217,6 +222,7 @@ impl Net {
                 socket_path: vu_cfg.socket,
                 vu_num_queues,
                 server,
+                instance_id: vu_cfg.instance_id,
                 ..Default::default()
             },
             config,
@@ -409,6 +415,10 @@ impl VirtioDevice for Net {
     ) -> std::result::Result<(), crate::Error> {
         self.vu_common.add_memory_region(&self.guest_memory, region)
     }
+
+    fn migration_blocker(&self) -> Option<String> {
+        self.vu_common.migration_blocker()
+    }
 }
 
 impl Pausable for Net {
@@ -9,8 +9,9 @@ use crate::{
 };
 use std::ffi;
 use std::fs::File;
+use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -25,7 +26,7 @@ use vhost::vhost_user::{
 use vhost::{VhostBackend, VhostUserDirtyLogRegion, VhostUserMemoryRegionInfo, VringConfigData};
 use virtio_queue::{Descriptor, Queue, QueueT};
 use vm_memory::{
-    Address, Error as MmapError, FileOffset, GuestAddress, GuestMemory, GuestMemoryRegion,
+    Address, Bytes, Error as MmapError, FileOffset, GuestAddress, GuestMemory, GuestMemoryRegion,
 };
 use vm_migration::protocol::MemoryRangeTable;
 use vmm_sys_util::eventfd::EventFd;
@@ -33,17 +34,59 @@ use vmm_sys_util::eventfd::EventFd;
 // Size of a dirty page for vhost-user.
 const VHOST_LOG_PAGE: u64 = 0x1000;
 
+// Magic number starting the instance identification sent to backends
+// multiplexing several VMs over a single listener.
+const INSTANCE_ID_MAGIC: &[u8; 4] = b"VUID";
+// Maximum length of an instance identifier.
+pub const INSTANCE_ID_MAX_LEN: usize = 255;
+// Time given to the backend to accept or reject the instance identifier.
+const INSTANCE_ID_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct VhostUserConfig {
     pub socket: String,
     pub num_queues: usize,
     pub queue_size: u16,
+    pub instance_id: Option<String>,
+}
+
+// Identifies the VM to the backend, before any vhost-user message is sent on
+// the connection. The identification is made of the magic number, followed
+// by the length of the identifier (u32 LE) and the identifier itself. The
+// backend replies with a status (u32 LE), 0 meaning the connection has been
+// routed to the instance.
+fn identify_instance(stream: &mut UnixStream, instance_id: &str) -> Result<()> {
+    let mut msg = Vec::with_capacity(8 + instance_id.len());
+    msg.extend_from_slice(INSTANCE_ID_MAGIC);
+    msg.extend_from_slice(&(instance_id.len() as u32).to_le_bytes());
+    msg.extend_from_slice(instance_id.as_bytes());
+    stream.write_all(&msg).map_err(Error::VhostUserInstanceId)?;
+
+    let mut status = [0u8; 4];
+    stream
+        .set_read_timeout(Some(INSTANCE_ID_TIMEOUT))
+        .map_err(Error::VhostUserInstanceId)?;
+    stream
+        .read_exact(&mut status)
+        .map_err(Error::VhostUserInstanceId)?;
+    stream
+        .set_read_timeout(None)
+        .map_err(Error::VhostUserInstanceId)?;
+
+    match u32::from_le_bytes(status) {
+        0 => Ok(()),
+        status => Err(Error::VhostUserInstanceRejected(
+            instance_id.to_owned(),
+            status,
+        )),
+    }
 }
 
 #[derive(Clone)]
 struct VringInfo {
     config_data: VringConfigData,
     used_guest_addr: u64,
+    avail_guest_addr: u64,
 }
 
 #[derive(Clone)]
@@ -235,6 +278,7 @@ impl VhostUserHandle {
             vrings_info.push(VringInfo {
                 config_data,
                 used_guest_addr: queue.used_ring(),
+                avail_guest_addr: queue.avail_ring(),
             });
 
             self.vu
@@ -361,6 +405,7 @@ impl VhostUserHandle {
         socket_path: &str,
         num_queues: u64,
         unlink_socket: bool,
+        instance_id: Option<&str>,
     ) -> Result<Self> {
         if server {
             if unlink_socket {
@@ -386,17 +431,21 @@ impl VhostUserHandle {
 
             // Retry connecting for a full minute
             let err = loop {
-                let err = match Frontend::connect(socket_path, num_queues) {
-                    Ok(m) => {
+                let err = match UnixStream::connect(socket_path) {
+                    Ok(mut stream) => {
+                        if let Some(instance_id) = instance_id {
+                            identify_instance(&mut stream, instance_id)?;
+                        }
+
                         return Ok(VhostUserHandle {
-                            vu: m,
+                            vu: Frontend::from_stream(stream, num_queues),
                             ready: false,
                             supports_migration: false,
                             shm_log: None,
                             acked_features: 0,
                             vrings_info: None,
                             queue_indexes: Vec::new(),
-                        })
+                        });
                     }
                     Err(e) => e,
                 };
@@ -435,6 +484,47 @@ impl VhostUserHandle {
         Ok(())
     }
 
+    /// Waits for the backend to return every descriptor chain made available
+    /// by the guest, i.e. for the used index of each vring to catch up with
+    /// its available index. Returns whether it did within the timeout.
+    pub fn wait_for_idle_vrings(&self, mem: &GuestMemoryMmap, timeout: Duration) -> Result<bool> {
+        let vrings_info = match &self.vrings_info {
+            Some(vrings_info) if self.ready => vrings_info,
+            _ => return Ok(true),
+        };
+
+        let start = Instant::now();
+        loop {
+            let mut idle = true;
+            for vring_info in vrings_info {
+                // Both rings start with a 16 bits flags field, followed by
+                // the 16 bits index.
+                let avail_idx: u16 = mem
+                    .read_obj(GuestAddress(vring_info.avail_guest_addr + 2))
+                    .map_err(Error::ReadVringIndex)?;
+                let used_idx: u16 = mem
+                    .read_obj(GuestAddress(vring_info.used_guest_addr + 2))
+                    .map_err(Error::ReadVringIndex)?;
+                if u16::from_le(avail_idx) != u16::from_le(used_idx) {
+                    idle = false;
+                    break;
+                }
+            }
+
+            if idle {
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                return Ok(false);
+            }
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn supports_migration(&self) -> bool {
+        self.supports_migration
+    }
+
     fn update_supports_migration(&mut self, acked_features: u64, acked_protocol_features: u64) {
         if (acked_features & u64::from(vhost::vhost_kern::vhost_binding::VHOST_F_LOG_ALL) != 0)
             && (acked_protocol_features & VhostUserProtocolFeatures::LOG_SHMFD.bits() != 0)
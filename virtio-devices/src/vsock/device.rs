@@ -27,39 +27,48 @@
 /// - an event queue FD; and
 /// - a backend FD.
 ///
+use super::defs::{uapi, DGRAM_SOCK_SUFFIX};
 use super::{VsockBackend, VsockPacket};
 use crate::seccomp_filters::Thread;
 use crate::Error as DeviceError;
 use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
 use crate::{
-    thread_helper::spawn_virtio_thread, ActivateResult, EpollHelper, EpollHelperError,
-    EpollHelperHandler, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
-    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+    thread_helper::spawn_virtio_thread, ActivateError, ActivateResult, EpollHelper,
+    EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice, VirtioDeviceType,
+    VirtioInterruptType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
 };
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io;
+use std::num::Wrapping;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, RwLock};
 use virtio_queue::Queue;
 use virtio_queue::QueueOwnedT;
 use virtio_queue::QueueT;
+use vm_memory::Bytes;
 use vm_memory::GuestAddressSpace;
 use vm_memory::GuestMemoryAtomic;
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
-use vm_virtio::AccessPlatform;
+use vm_virtio::{AccessPlatform, QueueCounters, Translatable};
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
+// The device supports connectionless (datagram) sockets.
+pub const VIRTIO_VSOCK_F_DGRAM: u64 = 3;
+
 // New descriptors are pending on the rx queue.
 pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 // New descriptors are pending on the tx queue.
@@ -96,6 +105,9 @@ pub struct VsockEpollHandler<B: VsockBackend> {
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub backend: Arc<RwLock<B>>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
+    pub queue_counters: Vec<QueueCounters>,
+    /// A transport reset event is to be sent to the driver, for it to read the guest CID again.
+    pub transport_reset: Arc<AtomicBool>,
 }
 
 impl<B> VsockEpollHandler<B>
@@ -107,6 +119,7 @@ where
     ///
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
         debug!("vsock: raising IRQ");
+        self.queue_counters[queue_index as usize].record_notification(true);
 
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(queue_index))
@@ -116,6 +129,19 @@ where
             })
     }
 
+    /// Check whether the driver has to be notified about the used descriptors, accounting for the
+    /// notifications it asked not to receive.
+    fn needs_notification(&mut self, queue_index: u16) -> result::Result<bool, DeviceError> {
+        let needs_notification = self.queues[queue_index as usize]
+            .needs_notification(self.mem.memory().deref())
+            .map_err(DeviceError::QueueNeedsNotification)?;
+        if !needs_notification {
+            self.queue_counters[queue_index as usize].record_notification(false);
+        }
+
+        Ok(needs_notification)
+    }
+
     /// Walk the driver-provided RX queue buffers and attempt to fill them up with any data that we
     /// have pending.
     ///
@@ -123,6 +149,7 @@ where
         debug!("vsock: epoll_handler::process_rx()");
 
         let mut used_descs = false;
+        let used = self.queues[0].next_used();
 
         while let Some(mut desc_chain) = self.queues[0].pop_descriptor_chain(self.mem.memory()) {
             let used_len = match VsockPacket::from_rx_virtq_head(
@@ -151,7 +178,14 @@ where
             used_descs = true;
         }
 
-        if used_descs {
+        self.queue_counters[0].record_batch(&self.queues[0], used);
+        // Packets are left pending on the backend until the driver provides
+        // more RX buffers.
+        if self.backend.read().unwrap().has_pending_rx() {
+            self.queue_counters[0].record_ring_full();
+        }
+
+        if used_descs && self.needs_notification(0)? {
             self.signal_used_queue(0)
         } else {
             Ok(())
@@ -165,6 +199,7 @@ where
         debug!("vsock: epoll_handler::process_tx()");
 
         let mut used_descs = false;
+        let used = self.queues[1].next_used();
 
         while let Some(mut desc_chain) = self.queues[1].pop_descriptor_chain(self.mem.memory()) {
             let pkt = match VsockPacket::from_tx_virtq_head(
@@ -193,18 +228,68 @@ where
             used_descs = true;
         }
 
-        if used_descs {
+        self.queue_counters[1].record_batch(&self.queues[1], used);
+
+        if used_descs && self.needs_notification(1)? {
             self.signal_used_queue(1)
         } else {
             Ok(())
         }
     }
 
+    /// Place a pending transport reset event into the first buffer the driver made available in
+    /// the EVT queue. The event stays pending until the driver provides a buffer.
+    ///
+    fn process_evt(&mut self) -> result::Result<(), DeviceError> {
+        if !self.transport_reset.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let Some(mut desc_chain) = self.queues[2].pop_descriptor_chain(self.mem.memory()) else {
+            return Ok(());
+        };
+        let event = uapi::VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes();
+        let used_len = match desc_chain.next() {
+            Some(desc) if desc.is_write_only() && desc.len() as usize >= event.len() => {
+                desc_chain
+                    .memory()
+                    .write_slice(
+                        &event,
+                        desc.addr()
+                            .translate_gva(self.access_platform.as_ref(), event.len()),
+                    )
+                    .map_err(|e| DeviceError::IoError(io::Error::new(io::ErrorKind::Other, e)))?;
+                self.transport_reset.store(false, Ordering::SeqCst);
+                event.len() as u32
+            }
+            _ => {
+                warn!("vsock: invalid EVT queue buffer");
+                0
+            }
+        };
+
+        self.queues[2]
+            .add_used(desc_chain.memory(), desc_chain.head_index(), used_len)
+            .map_err(DeviceError::QueueAddUsed)?;
+
+        // The EVT queue has no counters of its own.
+        self.interrupt_cb
+            .trigger(VirtioInterruptType::Queue(2))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
+        self.process_evt().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process EVT queue: {:?}", e))
+        })?;
+
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evts[0].as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evts[1].as_raw_fd(), TX_QUEUE_EVENT)?;
@@ -277,6 +362,9 @@ where
                 self.queue_evts[2].read().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!("Failed to get EVT queue event: {:?}", e))
                 })?;
+                self.process_evt().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process EVT queue: {:?}", e))
+                })?;
             }
             BACKEND_EVENT => {
                 debug!("vsock: backend event");
@@ -318,6 +406,11 @@ pub struct Vsock<B: VsockBackend> {
     path: PathBuf,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    queue_counters: Vec<QueueCounters>,
+    transport_reset: Arc<AtomicBool>,
+    // Wakes the handler of the activated device up, for it to send the
+    // transport reset event.
+    evt_queue_evt: Option<EventFd>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -347,7 +440,9 @@ where
             info!("Restoring virtio-vsock {}", id);
             (state.avail_features, state.acked_features, true)
         } else {
-            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+                | 1u64 << VIRTIO_F_IN_ORDER
+                | 1u64 << VIRTIO_VSOCK_F_DGRAM;
 
             if iommu {
                 avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -372,9 +467,31 @@ where
             path,
             seccomp_action,
             exit_evt,
+            // The event queue isn't processed by the device.
+            queue_counters: vec![QueueCounters::default(), QueueCounters::default()],
+            transport_reset: Arc::new(AtomicBool::new(false)),
+            evt_queue_evt: None,
         })
     }
 
+    /// Change the CID of the guest. The driver is sent a transport reset
+    /// event for it to read the new CID, once the device is activated and
+    /// running.
+    pub fn set_cid(&mut self, cid: u32) -> io::Result<()> {
+        self.cid = cid.into();
+        self.backend.write().unwrap().set_cid(self.cid);
+
+        // A driver which never saw the device doesn't need to be told.
+        if self.common.acked_features == 0 {
+            return Ok(());
+        }
+        self.transport_reset.store(true, Ordering::SeqCst);
+        if let Some(evt_queue_evt) = &self.evt_queue_evt {
+            evt_queue_evt.write(1)?;
+        }
+        Ok(())
+    }
+
     fn state(&self) -> VsockState {
         VsockState {
             avail_features: self.common.avail_features,
@@ -446,6 +563,15 @@ where
             virtqueues.push(queue);
             queue_evts.push(queue_evt);
         }
+        self.evt_queue_evt = Some(queue_evts[2].try_clone().map_err(|e| {
+            error!("Failed to clone EVT queue eventfd: {}", e);
+            ActivateError::BadActivate
+        })?);
+
+        self.backend
+            .write()
+            .unwrap()
+            .set_dgram_enabled(self.common.feature_acked(VIRTIO_VSOCK_F_DGRAM));
 
         let mut handler = VsockEpollHandler {
             mem,
@@ -456,6 +582,8 @@ where
             interrupt_cb,
             backend: self.backend.clone(),
             access_platform: self.common.access_platform.clone(),
+            queue_counters: self.queue_counters.clone(),
+            transport_reset: self.transport_reset.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -478,6 +606,7 @@ where
     }
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
+        self.evt_queue_evt = None;
         let result = self.common.reset();
         event!("virtio-device", "reset", "id", &self.id);
         result
@@ -485,6 +614,19 @@ where
 
     fn shutdown(&mut self) {
         std::fs::remove_file(&self.path).ok();
+
+        let mut dgram_path = self.path.clone().into_os_string();
+        dgram_path.push(DGRAM_SOCK_SUFFIX);
+        std::fs::remove_file(dgram_path).ok();
+    }
+
+    fn queue_counters(&self) -> Option<Vec<HashMap<&'static str, Wrapping<u64>>>> {
+        Some(
+            self.queue_counters
+                .iter()
+                .map(QueueCounters::counters)
+                .collect(),
+        )
     }
 
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
@@ -531,7 +673,8 @@ mod tests {
     #[test]
     fn test_virtio_device() {
         let mut ctx = TestContext::new();
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+        let avail_features =
+            1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER | 1u64 << VIRTIO_VSOCK_F_DGRAM;
         let device_features = avail_features;
         let driver_features: u64 = avail_features | 1 | (1 << 32);
         let device_pages = [
@@ -809,6 +952,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_transport_reset_event() {
+        let test_ctx = TestContext::new();
+        let mut ctx = test_ctx.create_epoll_handler_context();
+        ctx.handler.transport_reset.store(true, Ordering::SeqCst);
+
+        // Nothing is sent as long as the driver provides no buffer.
+        ctx.handler.process_evt().unwrap();
+        assert_eq!(ctx.guest_evvq.used.idx.get(), 0);
+        assert!(ctx.handler.transport_reset.load(Ordering::SeqCst));
+
+        let addr = vm_memory::GuestAddress(0x0060_0000);
+        test_ctx.mem.write_obj(u32::MAX, addr).unwrap();
+        ctx.guest_evvq.dtable[0].set(
+            addr.0,
+            4,
+            virtio_bindings::virtio_ring::VRING_DESC_F_WRITE
+                .try_into()
+                .unwrap(),
+            0,
+        );
+        ctx.guest_evvq.avail.ring[0].set(0);
+        ctx.guest_evvq.avail.idx.set(1);
+
+        ctx.handler.process_evt().unwrap();
+        assert_eq!(ctx.guest_evvq.used.idx.get(), 1);
+        assert_eq!(ctx.guest_evvq.used.ring[0].get().len(), 4);
+        assert_eq!(
+            test_ctx.mem.read_obj::<u32>(addr).unwrap(),
+            uapi::VIRTIO_VSOCK_EVENT_TRANSPORT_RESET
+        );
+        assert!(!ctx.handler.transport_reset.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_set_cid() {
+        let mut ctx = TestContext::new();
+        ctx.device.set_cid(42).unwrap();
+
+        let mut data = [0u8; 8];
+        ctx.device.read_config(0, &mut data);
+        assert_eq!(LittleEndian::read_u64(&data), 42);
+    }
+
     #[test]
     fn test_backend_event() {
         // Test case:
@@ -864,6 +1051,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_notification_suppression() {
+        // Test case: without VIRTIO_RING_F_EVENT_IDX, the driver is notified about every used
+        // descriptor.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_epoll_handler_context();
+
+            ctx.handler.backend.write().unwrap().set_pending_rx(false);
+            ctx.signal_txq_event();
+            ctx.guest_txvq.avail.ring[1].set(0);
+            ctx.guest_txvq.avail.idx.set(2);
+            ctx.signal_txq_event();
+
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 2);
+            let counters = ctx.handler.queue_counters[1].counters();
+            assert_eq!(counters["notifications"], Wrapping(2));
+            assert_eq!(counters["notifications_suppressed"], Wrapping(0));
+        }
+
+        // Test case: with VIRTIO_RING_F_EVENT_IDX, the driver is not notified until the used
+        // index goes past the one it asked for.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_epoll_handler_context();
+
+            ctx.handler.queues[1].set_event_idx(true);
+            ctx.guest_txvq.avail.event.set(5);
+            ctx.handler.backend.write().unwrap().set_pending_rx(false);
+            ctx.signal_txq_event();
+            ctx.guest_txvq.avail.ring[1].set(0);
+            ctx.guest_txvq.avail.idx.set(2);
+            ctx.signal_txq_event();
+
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 2);
+            let counters = ctx.handler.queue_counters[1].counters();
+            // The first used descriptor is always notified.
+            assert_eq!(counters["notifications"], Wrapping(1));
+            assert_eq!(counters["notifications_suppressed"], Wrapping(1));
+        }
+    }
+
     #[test]
     fn test_unknown_event() {
         let test_ctx = TestContext::new();
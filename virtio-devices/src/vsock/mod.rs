@@ -13,11 +13,12 @@ mod device;
 mod packet;
 mod unix;
 
+pub use self::defs::DGRAM_SOCK_SUFFIX;
 pub use self::device::Vsock;
 pub use self::unix::VsockUnixBackend;
 pub use self::unix::VsockUnixError;
 
-use packet::VsockPacket;
+pub(crate) use packet::VsockPacket;
 use std::os::unix::io::RawFd;
 
 mod defs {
@@ -25,6 +26,10 @@ mod defs {
     /// Max vsock packet data/buffer size.
     pub const MAX_PKT_BUF_SIZE: usize = 64 * 1024;
 
+    /// Suffix appended to the host-side Unix socket path, to get the path of the Unix datagram
+    /// socket through which vsock datagrams are exchanged with the host.
+    pub const DGRAM_SOCK_SUFFIX: &str = "_dgram";
+
     pub mod uapi {
 
         /// Vsock packet operation IDs.
@@ -56,8 +61,22 @@ mod defs {
         /// Vsock packet type.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
         ///
-        /// Stream / connection-oriented packet (the only currently valid type).
+        /// Stream / connection-oriented packet.
         pub const VSOCK_TYPE_STREAM: u16 = 1;
+        /// Datagram / connectionless packet, only valid once `VIRTIO_VSOCK_F_DGRAM` has been
+        /// negotiated.
+        pub const VSOCK_TYPE_DGRAM: u16 = 3;
+
+        /// Vsock event IDs.
+        /// Defined in `/include/uapi/linux/virtio_vsock.h`.
+        ///
+        /// The communication has been interrupted: the driver resets its connections and reads
+        /// the guest CID again.
+        pub const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+        /// Wildcard port, used as the source port of datagrams that can't be replied to.
+        /// Defined in `/include/uapi/linux/vm_sockets.h`.
+        pub const VSOCK_PORT_ANY: u32 = u32::MAX;
 
         pub const VSOCK_HOST_CID: u64 = 2;
     }
@@ -155,7 +174,15 @@ pub trait VsockChannel {
 /// sendable through a mpsc channel (the latter due to how `vmm::EpollContext` works).
 /// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
 /// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Let the backend know whether the driver has negotiated datagram support, i.e. whether
+    /// datagrams can be exchanged with the guest.
+    fn set_dgram_enabled(&mut self, _enabled: bool) {}
+
+    /// Let the backend know the guest CID changed, for the packets it sends to the guest to be
+    /// addressed to the new one.
+    fn set_cid(&mut self, _cid: u64) {}
+}
 
 #[cfg(test)]
 mod tests {
@@ -169,10 +196,12 @@ mod tests {
     use libc::EFD_NONBLOCK;
     use std::os::unix::io::AsRawFd;
     use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
     use std::sync::{Arc, RwLock};
     use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
     use vm_memory::{GuestAddress, GuestMemoryAtomic};
     use vm_virtio::queue::testing::VirtQueue as GuestQ;
+    use vm_virtio::QueueCounters;
     use vmm_sys_util::eventfd::EventFd;
 
     pub struct NoopVirtioInterrupt {}
@@ -335,6 +364,8 @@ mod tests {
                     interrupt_cb,
                     backend: Arc::new(RwLock::new(TestBackend::new())),
                     access_platform: None,
+                    queue_counters: vec![QueueCounters::default(), QueueCounters::default()],
+                    transport_reset: Arc::new(AtomicBool::new(false)),
                 },
             }
         }
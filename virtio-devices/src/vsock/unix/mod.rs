@@ -5,7 +5,8 @@
 //! This module implements the Unix Domain Sockets backend for vsock - a mediator between
 //! guest-side AF_VSOCK sockets and host-side AF_UNIX sockets. The heavy lifting is performed by
 //! `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
-//! handling vsock connection states.
+//! handling vsock connection states. Datagrams, being connectionless, are forwarded by the
+//! muxer itself, through a host-side Unix datagram socket.
 //!
 //! Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
 
@@ -25,6 +26,15 @@ mod defs {
 
     /// Size of the muxer connection kill queue.
     pub const MUXER_KILLQ_SIZE: usize = 128;
+
+    /// Size of the muxer queue of datagrams waiting to be delivered to the guest.
+    pub const MUXER_DGRAM_RXQ_SIZE: usize = 256;
+
+    /// Maximum number of host sockets that can be assigned a port for sending datagrams.
+    pub const MAX_DGRAM_PEERS: usize = 256;
+
+    /// Maximum length of the "SEND \<port>" header of a host datagram.
+    pub const MAX_DGRAM_HEADER_LEN: usize = 32;
 }
 
 #[derive(Debug)]
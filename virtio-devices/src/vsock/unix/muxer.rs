@@ -37,15 +37,30 @@
 //!
 //! To route all these events to their handlers, the muxer uses another `HashMap` object,
 //! mapping `RawFd`s to `EpollListener`s.
+//!
+//! ## Datagram forwarder
+//!
+//! Once the driver has negotiated `VIRTIO_VSOCK_F_DGRAM`, the muxer also forwards datagrams
+//! through a Unix datagram socket, bound to "\<host socket path>_dgram". There are no
+//! connections involved, so every datagram exchanged with the host is prefixed with the guest
+//! port it is addressed to, or coming from:
+//! - the host sends "SEND \<port>\n\<data>" to the muxer datagram socket, and the data is
+//!   delivered to the guest port `port`; and
+//! - data sent by the guest to the host port `port` is delivered as "FROM \<guest port>\n\<data>"
+//!   to the host socket bound to "\<host socket path>_\<port>".
+//!
+//! Host sockets bound to "\<host socket path>_\<port>" send their datagrams from `port`, while
+//! other named host sockets are assigned a local port, so that the guest can reply to them.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::{self, ErrorKind, Read};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 
 use super::super::csm::ConnState;
-use super::super::defs::uapi;
+use super::super::defs::{uapi, DGRAM_SOCK_SUFFIX, MAX_PKT_BUF_SIZE};
 use super::super::packet::VsockPacket;
 use super::super::{
     Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
@@ -87,6 +102,8 @@ enum EpollListener {
     },
     /// A listener interested in new host-initiated connections.
     HostSock,
+    /// A listener interested in datagrams sent by the host.
+    DgramSock,
     /// A listener interested in reading host "connect \<port>" commands from a freshly
     /// connected host socket.
     LocalStream(UnixStream),
@@ -101,6 +118,16 @@ struct PartiallyReadCommand {
     len: usize,
 }
 
+/// A datagram sent by the host, waiting to be delivered to the guest.
+struct MuxerDgram {
+    /// Host-side port the datagram is sent from.
+    local_port: u32,
+    /// Guest-side port the datagram is addressed to.
+    peer_port: u32,
+    /// The datagram data, stripped from its "SEND" header.
+    data: Vec<u8>,
+}
+
 /// The vsock connection multiplexer.
 ///
 pub struct VsockMuxer {
@@ -133,6 +160,15 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// The Unix datagram socket, through which datagrams are exchanged with the host.
+    dgram_sock: UnixDatagram,
+    /// Whether the driver has negotiated datagram support.
+    dgram_enabled: bool,
+    /// The datagrams received from the host, waiting to be delivered to the guest.
+    dgram_rxq: VecDeque<MuxerDgram>,
+    /// A hash map used to store the host-side ports assigned to named host sockets sending
+    /// datagrams, so that the guest can reply to them.
+    dgram_peer_map: HashMap<PathBuf, u32>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -205,6 +241,36 @@ impl VsockChannel for VsockMuxer {
             }
         }
 
+        // Datagrams don't belong to any connection, and are delivered from their own queue.
+        while let Some(dgram) = self.dgram_rxq.pop_front() {
+            match pkt.buf_mut() {
+                Some(buf) if buf.len() >= dgram.data.len() => {
+                    buf[..dgram.data.len()].copy_from_slice(&dgram.data);
+                }
+                _ => {
+                    warn!(
+                        "vsock: dropping host datagram of {} bytes, larger than the RX buffer",
+                        dgram.data.len()
+                    );
+                    continue;
+                }
+            }
+
+            pkt.set_op(uapi::VSOCK_OP_RW)
+                .set_src_cid(uapi::VSOCK_HOST_CID)
+                .set_dst_cid(self.cid)
+                .set_src_port(dgram.local_port)
+                .set_dst_port(dgram.peer_port)
+                .set_len(dgram.data.len() as u32)
+                .set_type(uapi::VSOCK_TYPE_DGRAM)
+                .set_flags(0)
+                .set_buf_alloc(0)
+                .set_fwd_cnt(0);
+
+            debug!("vsock muxer: RX pkt: {:?}", pkt.hdr());
+            return Ok(());
+        }
+
         Err(VsockError::NoData)
     }
 
@@ -229,9 +295,11 @@ impl VsockChannel for VsockMuxer {
             pkt.hdr()
         );
 
-        // If this packet has an unsupported type (!=stream), we must send back an RST.
+        // If this packet has an unsupported type, we must send back an RST. Datagrams are only
+        // supported once the driver has negotiated them.
         //
-        if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
+        let is_dgram = self.dgram_enabled && pkt.type_() == uapi::VSOCK_TYPE_DGRAM;
+        if pkt.type_() != uapi::VSOCK_TYPE_STREAM && !is_dgram {
             self.enq_rst(pkt.dst_port(), pkt.src_port());
             return Ok(());
         }
@@ -246,6 +314,12 @@ impl VsockChannel for VsockMuxer {
             return Ok(());
         }
 
+        // Datagrams are connectionless, and are forwarded straight to the host.
+        if is_dgram {
+            self.handle_peer_dgram_pkt(pkt);
+            return Ok(());
+        }
+
         if !self.conn_map.contains_key(&conn_key) {
             // This packet can't be routed to any active connection (based on its src and dst
             // ports).  The only orphan / unroutable packets we know how to handle are
@@ -281,7 +355,7 @@ impl VsockChannel for VsockMuxer {
     /// buffer.
     ///
     fn has_pending_rx(&self) -> bool {
-        !self.rxq.is_empty() || !self.rxq.is_synced()
+        !self.rxq.is_empty() || !self.rxq.is_synced() || !self.dgram_rxq.is_empty()
     }
 }
 
@@ -342,7 +416,18 @@ impl VsockEpollListener for VsockMuxer {
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl VsockBackend for VsockMuxer {
+    fn set_dgram_enabled(&mut self, enabled: bool) {
+        self.dgram_enabled = enabled;
+        if !enabled {
+            self.dgram_rxq.clear();
+        }
+    }
+
+    fn set_cid(&mut self, cid: u64) {
+        self.cid = cid;
+    }
+}
 
 impl VsockMuxer {
     /// Muxer constructor.
@@ -361,6 +446,13 @@ impl VsockMuxer {
             .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
             .map_err(Error::UnixBind)?;
 
+        // Bind the host Unix datagram socket, so we can exchange datagrams with the host.
+        let dgram_sock_path = format!("{host_sock_path}{DGRAM_SOCK_SUFFIX}");
+        Self::remove_stale_dgram_sock(&dgram_sock_path);
+        let dgram_sock = UnixDatagram::bind(&dgram_sock_path)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(Error::UnixBind)?;
+
         let mut muxer = Self {
             cid: cid.into(),
             host_sock,
@@ -373,12 +465,46 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            dgram_sock,
+            dgram_enabled: false,
+            dgram_rxq: VecDeque::with_capacity(defs::MUXER_DGRAM_RXQ_SIZE),
+            dgram_peer_map: HashMap::new(),
         };
 
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+        muxer.add_listener(muxer.dgram_sock.as_raw_fd(), EpollListener::DgramSock)?;
         Ok(muxer)
     }
 
+    /// Remove the datagram socket a previous VMM left behind, if nothing receives from it
+    /// anymore. Unlike the host socket path, the datagram one isn't picked by the user, who
+    /// would otherwise have to clean it up before the VMM can start again.
+    ///
+    fn remove_stale_dgram_sock(path: &str) {
+        use std::os::unix::fs::FileTypeExt;
+
+        let is_socket = std::fs::symlink_metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+        if !is_socket {
+            return;
+        }
+
+        let stale = match UnixDatagram::unbound().and_then(|sock| sock.connect(path)) {
+            Ok(()) => false,
+            Err(e) => e.kind() == ErrorKind::ConnectionRefused,
+        };
+        if stale {
+            info!("vsock: removing stale datagram socket {}", path);
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!(
+                    "vsock: failed removing stale datagram socket {}: {}",
+                    path, e
+                );
+            }
+        }
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     ///
     fn handle_event(&mut self, fd: RawFd, event_set: epoll::Events) {
@@ -473,6 +599,9 @@ impl VsockMuxer {
                 }
             }
 
+            // Datagrams sent by the host are ready to be read.
+            Some(EpollListener::DgramSock) => self.read_host_dgrams(),
+
             _ => {
                 info!(
                     "vsock: unexpected event: fd={:?}, event_set={:?}",
@@ -532,6 +661,124 @@ impl VsockMuxer {
             .map_err(|e| Error::ReadStreamPort(Box::new(e)))
     }
 
+    /// Read all the datagrams sent by the host, and queue them for delivery to the guest.
+    ///
+    /// Datagrams are unreliable by nature, so the ones that can't be delivered are dropped.
+    ///
+    fn read_host_dgrams(&mut self) {
+        let mut buf = vec![0u8; defs::MAX_DGRAM_HEADER_LEN + MAX_PKT_BUF_SIZE];
+
+        loop {
+            let (len, addr) = match self.dgram_sock.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("vsock: error reading host datagram: {:?}", e);
+                    break;
+                }
+            };
+
+            if !self.dgram_enabled {
+                debug!("vsock: dropping host datagram, datagrams not negotiated");
+                continue;
+            }
+
+            let (peer_port, data) = match Self::parse_dgram_header(&buf[..len]) {
+                Ok(res) => res,
+                Err(err) => {
+                    info!("vsock: dropping invalid host datagram: {:?}", err);
+                    continue;
+                }
+            };
+
+            if self.dgram_rxq.len() >= defs::MUXER_DGRAM_RXQ_SIZE {
+                warn!(
+                    "vsock: muxer datagram queue full; dropping datagram for pp={}",
+                    peer_port
+                );
+                continue;
+            }
+
+            let data = data.to_vec();
+            let local_port = self.dgram_local_port(addr.as_pathname());
+            self.dgram_rxq.push_back(MuxerDgram {
+                local_port,
+                peer_port,
+                data,
+            });
+        }
+    }
+
+    /// Parse the "SEND" header of a host datagram, and extract the destination vsock port,
+    /// along with the datagram data.
+    ///
+    fn parse_dgram_header(buf: &[u8]) -> Result<(u32, &[u8])> {
+        let header_len = buf
+            .iter()
+            .take(defs::MAX_DGRAM_HEADER_LEN)
+            .position(|b| *b == b'\n')
+            .ok_or(Error::InvalidPortRequest)?
+            + 1;
+
+        let mut word_iter = std::str::from_utf8(&buf[..header_len])
+            .map_err(Error::ConvertFromUtf8)?
+            .split_whitespace();
+
+        word_iter
+            .next()
+            .ok_or(Error::InvalidPortRequest)
+            .and_then(|word| {
+                if word.to_lowercase() == "send" {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidPortRequest)
+                }
+            })
+            .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
+            .and_then(|word| word.parse::<u32>().map_err(Error::ParseInteger))
+            .map(|port| (port, &buf[header_len..]))
+    }
+
+    /// Get the host-side port a datagram sent from the host socket bound to `path` comes from.
+    ///
+    /// Host sockets bound to "\<host socket path>_\<port>" send their datagrams from `port`,
+    /// while other named host sockets are assigned a local port for the lifetime of the muxer.
+    /// Datagrams sent from unnamed sockets can't be replied to.
+    ///
+    fn dgram_local_port(&mut self, path: Option<&Path>) -> u32 {
+        let path = match path {
+            Some(path) => path,
+            None => return uapi::VSOCK_PORT_ANY,
+        };
+
+        let listening_port = path
+            .to_str()
+            .and_then(|p| p.strip_prefix(self.host_sock_path.as_str()))
+            .and_then(|p| p.strip_prefix('_'))
+            .and_then(|p| p.parse::<u32>().ok());
+        if let Some(port) = listening_port {
+            return port;
+        }
+
+        if let Some(port) = self.dgram_peer_map.get(path) {
+            return *port;
+        }
+
+        if self.dgram_peer_map.len() >= defs::MAX_DGRAM_PEERS {
+            warn!(
+                "vsock: datagram peer limit reached ({}); {:?} can't be replied to",
+                defs::MAX_DGRAM_PEERS,
+                path
+            );
+            return uapi::VSOCK_PORT_ANY;
+        }
+
+        let port = self.allocate_local_port();
+        self.dgram_peer_map.insert(path.to_path_buf(), port);
+        port
+    }
+
     /// Add a new connection to the active connection pool.
     ///
     fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
@@ -604,6 +851,7 @@ impl VsockMuxer {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => epoll::Events::EPOLLIN,
             EpollListener::HostSock => epoll::Events::EPOLLIN,
+            EpollListener::DgramSock => epoll::Events::EPOLLIN,
         };
 
         epoll::ctl(
@@ -698,6 +946,45 @@ impl VsockMuxer {
             .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
     }
 
+    /// Handle a datagram coming from our peer (the guest vsock driver).
+    ///
+    /// The datagram is forwarded to the host socket assigned to its destination port, or else to
+    /// the host Unix socket expected to be bound at the file system path corresponding to the
+    /// destination port. Datagrams that can't be delivered are dropped.
+    ///
+    fn handle_peer_dgram_pkt(&mut self, pkt: &VsockPacket) {
+        if pkt.op() != uapi::VSOCK_OP_RW {
+            debug!("vsock: dropping guest datagram with op {}", pkt.op());
+            return;
+        }
+
+        let data = match pkt.buf().unwrap_or_default().get(..pkt.len() as usize) {
+            Some(data) => data,
+            None => {
+                debug!("vsock: dropping truncated guest datagram");
+                return;
+            }
+        };
+
+        let path = self
+            .dgram_peer_map
+            .iter()
+            .find(|(_, port)| **port == pkt.dst_port())
+            .map(|(path, _)| path.clone())
+            .unwrap_or_else(|| {
+                PathBuf::from(format!("{}_{}", self.host_sock_path, pkt.dst_port()))
+            });
+
+        let mut msg = format!("FROM {}\n", pkt.src_port()).into_bytes();
+        msg.extend_from_slice(data);
+        if let Err(err) = self.dgram_sock.send_to(&msg, &path) {
+            debug!(
+                "vsock: unable to deliver guest datagram to {:?}: {:?}",
+                path, err
+            );
+        }
+    }
+
     /// Perform an action that might mutate a connection's state.
     ///
     /// This is used as shorthand for repetitive tasks that need to be performed after a
@@ -870,6 +1157,11 @@ mod tests {
     impl Drop for MuxerTestContext {
         fn drop(&mut self) {
             std::fs::remove_file(self.muxer.host_sock_path.as_str()).unwrap();
+            std::fs::remove_file(format!(
+                "{}{}",
+                self.muxer.host_sock_path, DGRAM_SOCK_SUFFIX
+            ))
+            .unwrap();
         }
     }
 
@@ -1453,4 +1745,119 @@ mod tests {
         // not be any pending RX in the muxer.
         assert!(!ctx.muxer.has_pending_rx());
     }
+
+    #[test]
+    fn test_dgram_guest_to_host() {
+        let mut ctx = MuxerTestContext::new("dgram_guest_to_host");
+        ctx.muxer.set_dgram_enabled(true);
+        let local_port = 1026;
+        let peer_port = 1025;
+
+        let path = format!("{}_{}", ctx.muxer.host_sock_path, local_port);
+        let sock = UnixDatagram::bind(&path).unwrap();
+
+        let data = [1u8, 2, 3, 4];
+        ctx.init_data_pkt(local_port, peer_port, &data)
+            .set_type(uapi::VSOCK_TYPE_DGRAM);
+        ctx.send();
+
+        // No connection is involved, hence nothing to reply.
+        assert!(!ctx.muxer.has_pending_rx());
+        assert!(ctx.muxer.conn_map.is_empty());
+
+        let mut buf = [0u8; 32];
+        let len = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"FROM 1025\n\x01\x02\x03\x04");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dgram_host_to_guest() {
+        let mut ctx = MuxerTestContext::new("dgram_host_to_guest");
+        ctx.muxer.set_dgram_enabled(true);
+        let dgram_path = format!("{}{}", ctx.muxer.host_sock_path, DGRAM_SOCK_SUFFIX);
+        let peer_port = 1025;
+
+        // A host socket bound to a port path sends its datagrams from that port.
+        let path = format!("{}_{}", ctx.muxer.host_sock_path, 1026);
+        let sock = UnixDatagram::bind(&path).unwrap();
+        sock.send_to(b"SEND 1025\nhello", &dgram_path).unwrap();
+        ctx.notify_muxer();
+
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_DGRAM);
+        assert_eq!(ctx.pkt.src_cid(), uapi::VSOCK_HOST_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID as u64);
+        assert_eq!(ctx.pkt.src_port(), 1026);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+        assert_eq!(&ctx.pkt.buf().unwrap()[..ctx.pkt.len() as usize], b"hello");
+        assert!(!ctx.muxer.has_pending_rx());
+        std::fs::remove_file(path).unwrap();
+
+        // Any other named host socket is assigned a local port, so that the guest can reply.
+        let path = "test_vsock_dgram_host_to_guest_peer.sock";
+        let sock = UnixDatagram::bind(path).unwrap();
+        sock.send_to(b"SEND 1025\nping", &dgram_path).unwrap();
+        ctx.notify_muxer();
+
+        ctx.recv();
+        let local_port = ctx.pkt.src_port();
+        assert_ne!(local_port, uapi::VSOCK_PORT_ANY);
+        assert_eq!(&ctx.pkt.buf().unwrap()[..ctx.pkt.len() as usize], b"ping");
+
+        ctx.init_data_pkt(local_port, peer_port, b"pong")
+            .set_type(uapi::VSOCK_TYPE_DGRAM);
+        ctx.send();
+        let mut buf = [0u8; 32];
+        let len = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"FROM 1025\npong");
+
+        // Datagrams with an invalid header are dropped.
+        sock.send_to(b"CONNECT 1025\nping", &dgram_path).unwrap();
+        ctx.notify_muxer();
+        assert!(!ctx.muxer.has_pending_rx());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_dgram_stale_sock() {
+        let uds_path = "test_vsock_dgram_stale_sock.sock";
+        let dgram_path = format!("{uds_path}{DGRAM_SOCK_SUFFIX}");
+
+        // The socket is left behind once dropped.
+        drop(UnixDatagram::bind(&dgram_path).unwrap());
+        let _muxer = VsockMuxer::new(PEER_CID, uds_path.to_string()).unwrap();
+        std::fs::remove_file(uds_path).unwrap();
+
+        // A socket still in use is not taken over.
+        assert!(VsockMuxer::new(PEER_CID, uds_path.to_string()).is_err());
+        std::fs::remove_file(uds_path).unwrap();
+        std::fs::remove_file(&dgram_path).unwrap();
+    }
+
+    #[test]
+    fn test_dgram_not_negotiated() {
+        let mut ctx = MuxerTestContext::new("dgram_not_negotiated");
+        let dgram_path = format!("{}{}", ctx.muxer.host_sock_path, DGRAM_SOCK_SUFFIX);
+
+        // Host datagrams are dropped.
+        let sock = UnixDatagram::unbound().unwrap();
+        sock.send_to(b"SEND 1025\nhello", &dgram_path).unwrap();
+        ctx.notify_muxer();
+        assert!(!ctx.muxer.has_pending_rx());
+
+        // Guest datagrams are reset, as any other unsupported packet.
+        ctx.init_data_pkt(1026, 1025, b"hello")
+            .set_type(uapi::VSOCK_TYPE_DGRAM);
+        ctx.send();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), 1026);
+        assert_eq!(ctx.pkt.dst_port(), 1025);
+    }
 }
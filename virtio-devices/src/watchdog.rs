@@ -21,7 +21,7 @@ use std::fs::File;
 use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::time::Instant;
 use thiserror::Error;
@@ -69,6 +69,7 @@ struct WatchdogEpollHandler {
     timer: File,
     last_ping_time: Arc<Mutex<Option<Instant>>>,
     reset_evt: EventFd,
+    triggered: Arc<AtomicBool>,
 }
 
 impl WatchdogEpollHandler {
@@ -169,6 +170,7 @@ impl EpollHelperHandler for WatchdogEpollHandler {
                     let gap = now.duration_since(*last_ping_time).as_secs();
                     if gap > WATCHDOG_TIMEOUT {
                         error!("Watchdog triggered: {} seconds since last ping", gap);
+                        self.triggered.store(true, Ordering::SeqCst);
                         self.reset_evt.write(1).ok();
                     }
                 }
@@ -190,6 +192,7 @@ pub struct Watchdog {
     id: String,
     seccomp_action: SeccompAction,
     reset_evt: EventFd,
+    triggered: Arc<AtomicBool>,
     last_ping_time: Arc<Mutex<Option<Instant>>>,
     timer: File,
     exit_evt: EventFd,
@@ -203,10 +206,13 @@ pub struct WatchdogState {
 }
 
 impl Watchdog {
-    /// Create a new virtio watchdog device that will reboot VM if the guest hangs
+    /// Create a new virtio watchdog device that will reboot VM if the guest hangs.
+    /// `triggered` is set before signalling the reset event, allowing the VMM
+    /// to tell it apart from a reset requested by the guest.
     pub fn new(
         id: String,
         reset_evt: EventFd,
+        triggered: Arc<AtomicBool>,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         state: Option<WatchdogState>,
@@ -248,6 +254,7 @@ impl Watchdog {
             id,
             seccomp_action,
             reset_evt,
+            triggered,
             last_ping_time: Arc::new(Mutex::new(last_ping_time)),
             timer,
             exit_evt,
@@ -359,6 +366,7 @@ impl VirtioDevice for Watchdog {
             timer,
             last_ping_time: self.last_ping_time.clone(),
             reset_evt,
+            triggered: self.triggered.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -10,7 +10,10 @@
 
 //! Implements virtio queues
 
+use std::collections::HashMap;
 use std::fmt::{self, Debug};
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use virtio_queue::{Queue, QueueT};
 use vm_memory::GuestAddress;
@@ -149,3 +152,70 @@ pub fn clone_queue(queue: &Queue) -> Queue {
 
     q
 }
+
+/// Statistics of a single virtqueue, shared between the device and the
+/// thread processing the queue.
+#[derive(Clone, Default)]
+pub struct QueueCounters {
+    descriptors: Arc<AtomicU64>,
+    batches: Arc<AtomicU64>,
+    notifications: Arc<AtomicU64>,
+    notifications_suppressed: Arc<AtomicU64>,
+    ring_full: Arc<AtomicU64>,
+}
+
+impl QueueCounters {
+    /// Accounts for the descriptors returned to the used ring since `used`,
+    /// the value of `queue.next_used()` before the queue was processed.
+    pub fn record_batch(&self, queue: &Queue, used: u16) {
+        let descriptors = queue.next_used().wrapping_sub(used);
+        if descriptors > 0 {
+            self.descriptors
+                .fetch_add(u64::from(descriptors), Ordering::Relaxed);
+            self.batches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts for a notification of the driver, or for its suppression.
+    pub fn record_notification(&self, notified: bool) {
+        if notified {
+            self.notifications.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.notifications_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts for the device being stalled because the driver did not
+    /// provide enough descriptors.
+    pub fn record_ring_full(&self) {
+        self.ring_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> HashMap<&'static str, Wrapping<u64>> {
+        let descriptors = self.descriptors.load(Ordering::Acquire);
+        let batches = self.batches.load(Ordering::Acquire);
+
+        let mut counters = HashMap::new();
+        counters.insert("descriptors", Wrapping(descriptors));
+        counters.insert("batches", Wrapping(batches));
+        counters.insert(
+            "avg_batch_size",
+            Wrapping(descriptors.checked_div(batches).unwrap_or(0)),
+        );
+        counters.insert(
+            "notifications",
+            Wrapping(self.notifications.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "notifications_suppressed",
+            Wrapping(self.notifications_suppressed.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "ring_full",
+            Wrapping(self.ring_full.load(Ordering::Acquire)),
+        );
+
+        counters
+    }
+}
@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+#[cfg(target_arch = "x86_64")]
+use crate::config::LegacyDevice;
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
@@ -223,6 +225,14 @@ fn create_facp_table(dsdt_offset: GuestAddress, device_manager: &Arc<Mutex<Devic
             // X_PM_TMR_BLK
             facp.write(208, address);
         }
+
+        // IAPC_BOOT_ARCH: the 8042 and legacy devices flags being left
+        // clear already, only report the absence of the CMOS RTC.
+        #[cfg(target_arch = "x86_64")]
+        if !device_manager.legacy_device_enabled(LegacyDevice::Rtc) {
+            // CMOS RTC Not Present
+            facp.write(109, 1u16 << 5);
+        }
     }
 
     // aarch64 specific fields
@@ -0,0 +1,307 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit log of the API requests, recording who asked for what and how it
+//! went, one JSON object per line.
+//!
+//! Each record carries the time the request got answered, the transport it
+//! came through, the peer, the action and its outcome. The peer of a UNIX
+//! domain socket is identified by the credentials of the connected process,
+//! the one of a D-Bus method call by the unique name of its sender.
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+static AUDIT_LOG: OnceCell<Mutex<File>> = OnceCell::new();
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Cannot open the audit log.
+    #[error("Error opening the API audit log {0}: {1}")]
+    OpenLog(PathBuf, #[source] io::Error),
+
+    /// The audit log has already been set.
+    #[error("The API audit log can only be set once")]
+    LogAlreadySet,
+}
+
+/// Client an API request came from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Peer {
+    /// Process connected to a UNIX domain socket, as given by SO_PEERCRED
+    Unix {
+        pid: i32,
+        uid: u32,
+        gid: u32,
+    },
+    Vsock {
+        cid: u32,
+    },
+    Tcp {
+        address: String,
+    },
+    /// Unique name of the sender of a D-Bus method call
+    DBus {
+        sender: String,
+    },
+    Unknown,
+}
+
+impl Peer {
+    /// Identifies the process at the other end of a UNIX domain socket.
+    pub fn from_unix_socket(socket: &impl AsRawFd) -> Self {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: FFI call with a valid socket and a buffer large enough for
+        // the option
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            warn!(
+                "Error getting the credentials of the API peer: {}",
+                io::Error::last_os_error()
+            );
+            return Peer::Unknown;
+        }
+
+        Peer::Unix {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Transport {
+    Http,
+    DBus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    transport: Transport,
+    peer: &'a Peer,
+    action: &'a str,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+// Formats the time as RFC 3339 in UTC, with a millisecond precision. The
+// date is computed from the days since the epoch following
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn write_record<W: Write>(log: &mut W, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // A single write per record, the log being opened in append mode
+    log.write_all(&line)
+}
+
+fn record(record: Record) {
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+    if let Err(e) = write_record(&mut *log.lock().unwrap(), &record) {
+        error!("Error writing to the API audit log: {e}");
+    }
+}
+
+/// Opens the audit log at `path`, appending to it if it exists, and records
+/// all the API requests from then on.
+pub fn set_log_file(path: &Path) -> Result<(), Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| Error::OpenLog(path.to_owned(), e))?;
+
+    AUDIT_LOG
+        .set(Mutex::new(file))
+        .map_err(|_| Error::LogAlreadySet)
+}
+
+/// Returns whether the API requests are recorded.
+pub fn enabled() -> bool {
+    AUDIT_LOG.get().is_some()
+}
+
+/// Records an HTTP request to `path` with `method`, answered with `status`.
+pub fn record_http(peer: &Peer, method: &str, path: &str, status: u16) {
+    record(Record {
+        timestamp: format_timestamp(SystemTime::now()),
+        transport: Transport::Http,
+        peer,
+        action: &format!("{method} {path}"),
+        outcome: if status < 400 {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        status: Some(status),
+        error: None,
+    })
+}
+
+/// Records a call to the D-Bus method `action`, which failed with `error`
+/// if any.
+pub fn record_dbus(peer: &Peer, action: &str, error: Option<&str>) {
+    record(Record {
+        timestamp: format_timestamp(SystemTime::now()),
+        transport: Transport::DBus,
+        peer,
+        action,
+        outcome: if error.is_some() {
+            Outcome::Failure
+        } else {
+            Outcome::Success
+        },
+        status: None,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_timestamp() {
+        let at = |secs, millis| UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis);
+        assert_eq!(format_timestamp(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(at(951782400, 7)),
+            "2000-02-29T00:00:00.007Z"
+        );
+        assert_eq!(
+            format_timestamp(at(1709251199, 999)),
+            "2024-02-29T23:59:59.999Z"
+        );
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        // SAFETY: FFI calls, trivially safe
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert_eq!(
+            Peer::from_unix_socket(&a),
+            Peer::Unix {
+                pid: std::process::id() as i32,
+                uid,
+                gid,
+            }
+        );
+    }
+
+    #[test]
+    fn test_write_record() {
+        let peer = Peer::Unix {
+            pid: 42,
+            uid: 1000,
+            gid: 1000,
+        };
+        let mut log = Vec::new();
+        write_record(
+            &mut log,
+            &Record {
+                timestamp: format_timestamp(UNIX_EPOCH),
+                transport: Transport::Http,
+                peer: &peer,
+                action: "PUT /api/v1/vm.boot",
+                outcome: Outcome::Success,
+                status: Some(204),
+                error: None,
+            },
+        )
+        .unwrap();
+        write_record(
+            &mut log,
+            &Record {
+                timestamp: format_timestamp(UNIX_EPOCH),
+                transport: Transport::DBus,
+                peer: &Peer::DBus {
+                    sender: ":1.42".to_string(),
+                },
+                action: "org.cloudhypervisor.DBusApi1.VmPause",
+                outcome: Outcome::Failure,
+                status: None,
+                error: Some("VM is not booted"),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"transport\":\"http\",\
+             \"peer\":{\"unix\":{\"pid\":42,\"uid\":1000,\"gid\":1000}},\
+             \"action\":\"PUT /api/v1/vm.boot\",\"outcome\":\"success\",\"status\":204}\n\
+             {\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"transport\":\"dbus\",\
+             \"peer\":{\"dbus\":{\"sender\":\":1.42\"}},\
+             \"action\":\"org.cloudhypervisor.DBusApi1.VmPause\",\"outcome\":\"failure\",\
+             \"error\":\"VM is not booted\"}\n"
+        );
+    }
+}
@@ -0,0 +1,675 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Arguments of the D-Bus methods adding devices to the VM.
+//!
+//! Each device configuration is passed as an `a{sv}` dictionary, whose keys
+//! are the fields of the matching REST API request body. Keys which are left
+//! out take the same default values as with the REST API. File descriptors
+//! can't be passed this way.
+
+use crate::vm_config::{
+    default_diskconfig_num_queues, default_diskconfig_queue_size, default_fsconfig_num_queues,
+    default_fsconfig_queue_size, default_netconfig_ip, default_netconfig_ipv6_prefix_len,
+    default_netconfig_mac, default_netconfig_mask, default_netconfig_num_queues,
+    default_netconfig_queue_size, default_vdpaconfig_num_queues, DeviceConfig, DiskConfig,
+    FsConfig, NetConfig, NetModel, PmemConfig, UsbConfig, UserDeviceConfig, VdpaConfig, VhostMode,
+    VirtQueueAffinity, VsockConfig,
+};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
+use zbus::zvariant::{DeserializeDict, SerializeDict, Type};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Missing required key `{0}`")]
+    MissingKey(&'static str),
+
+    #[error("Invalid value for key `{0}`: {1}")]
+    InvalidValue(&'static str, String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn required<T>(value: Option<T>, key: &'static str) -> Result<T> {
+    value.ok_or(Error::MissingKey(key))
+}
+
+fn parse<T>(value: Option<String>, key: &'static str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .map(|v| {
+            v.parse()
+                .map_err(|e: T::Err| Error::InvalidValue(key, e.to_string()))
+        })
+        .transpose()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct TokenBucketArgs {
+    pub size: Option<u64>,
+    pub one_time_burst: Option<u64>,
+    pub refill_time: Option<u64>,
+}
+
+impl TryFrom<TokenBucketArgs> for TokenBucketConfig {
+    type Error = Error;
+
+    fn try_from(args: TokenBucketArgs) -> Result<Self> {
+        Ok(TokenBucketConfig {
+            size: required(args.size, "size")?,
+            one_time_burst: args.one_time_burst,
+            refill_time: required(args.refill_time, "refill_time")?,
+        })
+    }
+}
+
+impl From<TokenBucketConfig> for TokenBucketArgs {
+    fn from(config: TokenBucketConfig) -> Self {
+        TokenBucketArgs {
+            size: Some(config.size),
+            one_time_burst: config.one_time_burst,
+            refill_time: Some(config.refill_time),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct RateLimiterArgs {
+    pub bandwidth: Option<TokenBucketArgs>,
+    pub ops: Option<TokenBucketArgs>,
+}
+
+impl TryFrom<RateLimiterArgs> for RateLimiterConfig {
+    type Error = Error;
+
+    fn try_from(args: RateLimiterArgs) -> Result<Self> {
+        Ok(RateLimiterConfig {
+            bandwidth: args.bandwidth.map(TryInto::try_into).transpose()?,
+            ops: args.ops.map(TryInto::try_into).transpose()?,
+        })
+    }
+}
+
+impl From<RateLimiterConfig> for RateLimiterArgs {
+    fn from(config: RateLimiterConfig) -> Self {
+        RateLimiterArgs {
+            bandwidth: config.bandwidth.map(Into::into),
+            ops: config.ops.map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct DeviceArgs {
+    pub path: Option<String>,
+    pub iommu: Option<bool>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+    pub x_nv_gpudirect_clique: Option<u8>,
+    pub romfile: Option<String>,
+}
+
+impl TryFrom<DeviceArgs> for DeviceConfig {
+    type Error = Error;
+
+    fn try_from(args: DeviceArgs) -> Result<Self> {
+        Ok(DeviceConfig {
+            path: required(args.path, "path")?.into(),
+            iommu: args.iommu.unwrap_or_default(),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+            x_nv_gpudirect_clique: args.x_nv_gpudirect_clique,
+            romfile: args.romfile.map(Into::into),
+        })
+    }
+}
+
+impl From<DeviceConfig> for DeviceArgs {
+    fn from(config: DeviceConfig) -> Self {
+        DeviceArgs {
+            path: Some(config.path.to_string_lossy().into_owned()),
+            iommu: Some(config.iommu),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+            x_nv_gpudirect_clique: config.x_nv_gpudirect_clique,
+            romfile: config
+                .romfile
+                .map(|romfile| romfile.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct DiskArgs {
+    pub path: Option<String>,
+    pub readonly: Option<bool>,
+    pub direct: Option<bool>,
+    pub read_ahead: Option<bool>,
+    pub base: Option<String>,
+    pub create_overlay: Option<bool>,
+    pub ephemeral: Option<bool>,
+    pub secure_erase: Option<bool>,
+    pub iommu: Option<bool>,
+    pub num_queues: Option<u64>,
+    pub queue_size: Option<u16>,
+    pub vhost_user: Option<bool>,
+    pub vhost_socket: Option<String>,
+    pub rate_limit_group: Option<String>,
+    pub rate_limiter_config: Option<RateLimiterArgs>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+    pub serial: Option<String>,
+    /// Host CPUs of each queue, indexed by the queue index
+    pub queue_affinity: Option<HashMap<u16, Vec<u64>>>,
+}
+
+impl TryFrom<DiskArgs> for DiskConfig {
+    type Error = Error;
+
+    fn try_from(args: DiskArgs) -> Result<Self> {
+        let queue_affinity = args.queue_affinity.map(|affinity| {
+            let mut affinity: Vec<_> = affinity
+                .into_iter()
+                .map(|(queue_index, host_cpus)| VirtQueueAffinity {
+                    queue_index,
+                    host_cpus: host_cpus.into_iter().map(|cpu| cpu as usize).collect(),
+                })
+                .collect();
+            affinity.sort_by_key(|a| a.queue_index);
+            affinity
+        });
+
+        Ok(DiskConfig {
+            path: args.path.map(Into::into),
+            fd: None,
+            readonly: args.readonly.unwrap_or_default(),
+            direct: args.direct.unwrap_or_default(),
+            read_ahead: args.read_ahead.unwrap_or_default(),
+            base: args.base.map(Into::into),
+            create_overlay: args.create_overlay.unwrap_or_default(),
+            ephemeral: args.ephemeral.unwrap_or_default(),
+            secure_erase: args.secure_erase.unwrap_or_default(),
+            iommu: args.iommu.unwrap_or_default(),
+            num_queues: args
+                .num_queues
+                .map_or_else(default_diskconfig_num_queues, |n| n as usize),
+            queue_size: args
+                .queue_size
+                .unwrap_or_else(default_diskconfig_queue_size),
+            vhost_user: args.vhost_user.unwrap_or_default(),
+            vhost_socket: args.vhost_socket,
+            rate_limit_group: args.rate_limit_group,
+            rate_limiter_config: args
+                .rate_limiter_config
+                .map(TryInto::try_into)
+                .transpose()?,
+            id: args.id,
+            disable_io_uring: false,
+            disable_aio: false,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+            serial: args.serial,
+            queue_affinity,
+        })
+    }
+}
+
+impl From<DiskConfig> for DiskArgs {
+    fn from(config: DiskConfig) -> Self {
+        DiskArgs {
+            path: config.path.map(|p| p.to_string_lossy().into_owned()),
+            readonly: Some(config.readonly),
+            direct: Some(config.direct),
+            read_ahead: Some(config.read_ahead),
+            base: config.base.map(|p| p.to_string_lossy().into_owned()),
+            create_overlay: Some(config.create_overlay),
+            ephemeral: Some(config.ephemeral),
+            secure_erase: Some(config.secure_erase),
+            iommu: Some(config.iommu),
+            num_queues: Some(config.num_queues as u64),
+            queue_size: Some(config.queue_size),
+            vhost_user: Some(config.vhost_user),
+            vhost_socket: config.vhost_socket,
+            rate_limit_group: config.rate_limit_group,
+            rate_limiter_config: config.rate_limiter_config.map(Into::into),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+            serial: config.serial,
+            queue_affinity: config.queue_affinity.map(|affinity| {
+                affinity
+                    .into_iter()
+                    .map(|a| {
+                        let host_cpus = a.host_cpus.into_iter().map(|cpu| cpu as u64).collect();
+                        (a.queue_index, host_cpus)
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct FsArgs {
+    pub tag: Option<String>,
+    pub socket: Option<String>,
+    pub num_queues: Option<u64>,
+    pub queue_size: Option<u16>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+    pub builtin: Option<bool>,
+    pub shared_dir: Option<String>,
+    pub readonly: Option<bool>,
+}
+
+impl TryFrom<FsArgs> for FsConfig {
+    type Error = Error;
+
+    fn try_from(args: FsArgs) -> Result<Self> {
+        Ok(FsConfig {
+            tag: required(args.tag, "tag")?,
+            socket: args.socket.unwrap_or_default().into(),
+            num_queues: args
+                .num_queues
+                .map_or_else(default_fsconfig_num_queues, |n| n as usize),
+            queue_size: args.queue_size.unwrap_or_else(default_fsconfig_queue_size),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+            builtin: args.builtin.unwrap_or_default(),
+            shared_dir: args.shared_dir.map(Into::into),
+            readonly: args.readonly.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<FsConfig> for FsArgs {
+    fn from(config: FsConfig) -> Self {
+        FsArgs {
+            tag: Some(config.tag),
+            socket: Some(config.socket.to_string_lossy().into_owned()),
+            num_queues: Some(config.num_queues as u64),
+            queue_size: Some(config.queue_size),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+            builtin: Some(config.builtin),
+            shared_dir: config.shared_dir.map(|p| p.to_string_lossy().into_owned()),
+            readonly: Some(config.readonly),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct NetArgs {
+    pub tap: Option<String>,
+    pub ip: Option<String>,
+    pub mask: Option<String>,
+    pub mac: Option<String>,
+    pub mac_pool: Option<String>,
+    pub host_mac: Option<String>,
+    pub mtu: Option<u16>,
+    pub iommu: Option<bool>,
+    pub num_queues: Option<u64>,
+    pub queue_size: Option<u16>,
+    pub vhost_user: Option<bool>,
+    pub vhost_socket: Option<String>,
+    pub vhost_mode: Option<String>,
+    pub vhost_instance_id: Option<String>,
+    pub id: Option<String>,
+    pub rate_limiter_config: Option<RateLimiterArgs>,
+    pub pci_segment: Option<u16>,
+    pub offload_tso: Option<bool>,
+    pub offload_ufo: Option<bool>,
+    pub offload_csum: Option<bool>,
+    pub bridge: Option<String>,
+    pub vlan: Option<u16>,
+    pub model: Option<String>,
+    pub egress_shaping: Option<String>,
+    pub ipv6: Option<String>,
+    pub ipv6_prefix_len: Option<u8>,
+    pub ipv6_ra: Option<bool>,
+}
+
+impl TryFrom<NetArgs> for NetConfig {
+    type Error = Error;
+
+    fn try_from(args: NetArgs) -> Result<Self> {
+        let ipv6 = parse(args.ipv6, "ipv6")?;
+        Ok(NetConfig {
+            tap: args.tap,
+            ip: Some(parse(args.ip, "ip")?.unwrap_or_else(|| default_netconfig_ip(ipv6))),
+            mask: parse(args.mask, "mask")?.unwrap_or_else(default_netconfig_mask),
+            mac: parse(args.mac, "mac")?.unwrap_or_else(default_netconfig_mac),
+            mac_pool: args.mac_pool,
+            host_mac: parse(args.host_mac, "host_mac")?,
+            mtu: args.mtu,
+            iommu: args.iommu.unwrap_or_default(),
+            num_queues: args
+                .num_queues
+                .map_or_else(default_netconfig_num_queues, |n| n as usize),
+            queue_size: args.queue_size.unwrap_or_else(default_netconfig_queue_size),
+            vhost_user: args.vhost_user.unwrap_or_default(),
+            vhost_socket: args.vhost_socket,
+            vhost_mode: parse(args.vhost_mode, "vhost_mode")?.unwrap_or_default(),
+            vhost_instance_id: args.vhost_instance_id,
+            id: args.id,
+            fds: None,
+            rate_limiter_config: args
+                .rate_limiter_config
+                .map(TryInto::try_into)
+                .transpose()?,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+            offload_tso: args.offload_tso.unwrap_or(true),
+            offload_ufo: args.offload_ufo.unwrap_or(true),
+            offload_csum: args.offload_csum.unwrap_or(true),
+            bridge: args.bridge,
+            vlan: args.vlan,
+            model: parse(args.model, "model")?.unwrap_or_default(),
+            egress_shaping: parse(args.egress_shaping, "egress_shaping")?,
+            ipv6,
+            ipv6_prefix_len: args
+                .ipv6_prefix_len
+                .unwrap_or_else(default_netconfig_ipv6_prefix_len),
+            ipv6_ra: args.ipv6_ra.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<NetConfig> for NetArgs {
+    fn from(config: NetConfig) -> Self {
+        NetArgs {
+            tap: config.tap,
+            ip: config.ip.map(|ip| ip.to_string()),
+            mask: Some(config.mask.to_string()),
+            mac: Some(config.mac.to_string()),
+            mac_pool: config.mac_pool,
+            host_mac: config.host_mac.map(|mac| mac.to_string()),
+            mtu: config.mtu,
+            iommu: Some(config.iommu),
+            num_queues: Some(config.num_queues as u64),
+            queue_size: Some(config.queue_size),
+            vhost_user: Some(config.vhost_user),
+            vhost_socket: config.vhost_socket,
+            vhost_mode: Some(
+                match config.vhost_mode {
+                    VhostMode::Client => "client",
+                    VhostMode::Server => "server",
+                }
+                .to_string(),
+            ),
+            vhost_instance_id: config.vhost_instance_id,
+            id: config.id,
+            rate_limiter_config: config.rate_limiter_config.map(Into::into),
+            pci_segment: Some(config.pci_segment),
+            offload_tso: Some(config.offload_tso),
+            offload_ufo: Some(config.offload_ufo),
+            offload_csum: Some(config.offload_csum),
+            bridge: config.bridge,
+            vlan: config.vlan,
+            model: Some(
+                match config.model {
+                    NetModel::Virtio => "virtio",
+                    NetModel::E1000e => "e1000e",
+                }
+                .to_string(),
+            ),
+            egress_shaping: config.egress_shaping.map(|shaping| shaping.to_string()),
+            ipv6: config.ipv6.map(|ip| ip.to_string()),
+            ipv6_prefix_len: Some(config.ipv6_prefix_len),
+            ipv6_ra: Some(config.ipv6_ra),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct PmemArgs {
+    pub file: Option<String>,
+    pub size: Option<u64>,
+    pub iommu: Option<bool>,
+    pub discard_writes: Option<bool>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+}
+
+impl TryFrom<PmemArgs> for PmemConfig {
+    type Error = Error;
+
+    fn try_from(args: PmemArgs) -> Result<Self> {
+        Ok(PmemConfig {
+            file: required(args.file, "file")?.into(),
+            size: args.size,
+            iommu: args.iommu.unwrap_or_default(),
+            discard_writes: args.discard_writes.unwrap_or_default(),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<PmemConfig> for PmemArgs {
+    fn from(config: PmemConfig) -> Self {
+        PmemArgs {
+            file: Some(config.file.to_string_lossy().into_owned()),
+            size: config.size,
+            iommu: Some(config.iommu),
+            discard_writes: Some(config.discard_writes),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct UserDeviceArgs {
+    pub socket: Option<String>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+}
+
+impl TryFrom<UserDeviceArgs> for UserDeviceConfig {
+    type Error = Error;
+
+    fn try_from(args: UserDeviceArgs) -> Result<Self> {
+        Ok(UserDeviceConfig {
+            socket: required(args.socket, "socket")?.into(),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<UserDeviceConfig> for UserDeviceArgs {
+    fn from(config: UserDeviceConfig) -> Self {
+        UserDeviceArgs {
+            socket: Some(config.socket.to_string_lossy().into_owned()),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct VdpaArgs {
+    pub path: Option<String>,
+    pub num_queues: Option<u64>,
+    pub iommu: Option<bool>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+}
+
+impl TryFrom<VdpaArgs> for VdpaConfig {
+    type Error = Error;
+
+    fn try_from(args: VdpaArgs) -> Result<Self> {
+        Ok(VdpaConfig {
+            path: required(args.path, "path")?.into(),
+            num_queues: args
+                .num_queues
+                .map_or_else(default_vdpaconfig_num_queues, |n| n as usize),
+            iommu: args.iommu.unwrap_or_default(),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<VdpaConfig> for VdpaArgs {
+    fn from(config: VdpaConfig) -> Self {
+        VdpaArgs {
+            path: Some(config.path.to_string_lossy().into_owned()),
+            num_queues: Some(config.num_queues as u64),
+            iommu: Some(config.iommu),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct UsbArgs {
+    pub path: Option<String>,
+    pub id: Option<String>,
+}
+
+impl TryFrom<UsbArgs> for UsbConfig {
+    type Error = Error;
+
+    fn try_from(args: UsbArgs) -> Result<Self> {
+        Ok(UsbConfig {
+            path: required(args.path, "path")?.into(),
+            id: args.id,
+        })
+    }
+}
+
+impl From<UsbConfig> for UsbArgs {
+    fn from(config: UsbConfig) -> Self {
+        UsbArgs {
+            path: Some(config.path.to_string_lossy().into_owned()),
+            id: config.id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "dict")]
+pub struct VsockArgs {
+    pub cid: Option<u32>,
+    pub socket: Option<String>,
+    pub iommu: Option<bool>,
+    pub id: Option<String>,
+    pub pci_segment: Option<u16>,
+}
+
+impl TryFrom<VsockArgs> for VsockConfig {
+    type Error = Error;
+
+    fn try_from(args: VsockArgs) -> Result<Self> {
+        Ok(VsockConfig {
+            cid: required(args.cid, "cid")?,
+            socket: required(args.socket, "socket")?.into(),
+            iommu: args.iommu.unwrap_or_default(),
+            id: args.id,
+            pci_segment: args.pci_segment.unwrap_or_default(),
+        })
+    }
+}
+
+impl From<VsockConfig> for VsockArgs {
+    fn from(config: VsockConfig) -> Self {
+        VsockArgs {
+            cid: Some(config.cid),
+            socket: Some(config.socket.to_string_lossy().into_owned()),
+            iommu: Some(config.iommu),
+            id: config.id,
+            pci_segment: Some(config.pci_segment),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_args() {
+        let config = crate::config::DiskConfig::parse(
+            "path=/path/to/disk.img,num_queues=4,queue_affinity=[0@[1,2],1@[3]],bw_size=1000,bw_refill_time=100",
+        )
+        .unwrap();
+        assert_eq!(DiskArgs::signature(), "a{sv}");
+        let args = DiskArgs::from(config.clone());
+        assert_eq!(DiskConfig::try_from(args).unwrap(), config);
+
+        // Missing keys take the REST API defaults
+        let args = DiskArgs {
+            path: Some("/path/to/disk.img".to_string()),
+            ..Default::default()
+        };
+        let config: DiskConfig = serde_json::from_str(r#"{"path": "/path/to/disk.img"}"#).unwrap();
+        assert_eq!(DiskConfig::try_from(args).unwrap(), config);
+    }
+
+    #[test]
+    fn test_net_args() {
+        let config = crate::config::NetConfig::parse(
+            "tap=tap0,mac=12:34:56:78:90:ab,ip=192.168.1.1,vhost_mode=server,model=e1000e",
+        )
+        .unwrap();
+        let args = NetArgs::from(config.clone());
+        assert_eq!(NetConfig::try_from(args).unwrap(), config);
+
+        let args = NetArgs {
+            mac: Some("not a mac".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            NetConfig::try_from(args),
+            Err(Error::InvalidValue("mac", _))
+        ));
+    }
+
+    #[test]
+    fn test_usb_args() {
+        let config = crate::config::UsbConfig::parse("path=/dev/bus/usb/001/004,id=usb0").unwrap();
+        let args = UsbArgs::from(config.clone());
+        assert_eq!(UsbConfig::try_from(args).unwrap(), config);
+
+        let args = UsbArgs {
+            id: Some("usb0".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            UsbConfig::try_from(args),
+            Err(Error::MissingKey("path"))
+        ));
+    }
+
+    #[test]
+    fn test_vsock_args() {
+        let args = VsockArgs {
+            socket: Some("/tmp/vsock".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            VsockConfig::try_from(args),
+            Err(Error::MissingKey("cid"))
+        ));
+    }
+}
@@ -2,47 +2,162 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
-use super::{ApiAction, ApiRequest};
+
+pub mod args;
+
+use self::args::{
+    DeviceArgs, DiskArgs, FsArgs, NetArgs, PmemArgs, UsbArgs, UserDeviceArgs, VdpaArgs, VsockArgs,
+};
+use super::audit::{self, Peer};
+use super::{ApiAction, ApiError, ApiErrorCode, ApiErrorResponse, ApiRequest};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
+#[cfg(feature = "pprof")]
+use crate::api::VmmPprof;
 use crate::api::{
-    AddDisk, Body, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice, VmAddVdpa,
-    VmAddVsock, VmBoot, VmCounters, VmCreate, VmDelete, VmInfo, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot, VmmPing, VmmShutdown,
+    AddDisk, Body, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUsb, VmAddUserDevice, VmAddVdpa,
+    VmAddVsock, VmBoot, VmCapabilities, VmCounters, VmCreate, VmDelete, VmDeviceTree, VmDevices,
+    VmDumpMemory, VmInfo, VmMigrationBlockers, VmNmi, VmPause, VmPowerButton, VmReboot,
+    VmReceiveMigration, VmRekey, VmRemoveDevice, VmReplaceDevice, VmResize, VmResizeZone,
+    VmRestore, VmResume, VmScreenshot, VmSendMigration, VmSetBootParams, VmShutdown, VmSnapshot,
+    VmSnapshotConfig, VmSuspendToRam, VmSysRq, VmUpdateConfig, VmVcpuStats, VmmCapabilities,
+    VmmCheckpoint, VmmCheckpointData, VmmFds, VmmHostInfo, VmmPing, VmmPostRestore, VmmProfile,
+    VmmShutdown, VmmThreads,
 };
+use crate::config::RestoreConfig;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::seccomp_violations;
+use crate::vm::Error as VmError;
+use crate::VmConfig;
 use crate::{Error as VmmError, Result as VmmResult};
-use crate::{NetConfig, VmConfig};
 use futures::channel::oneshot;
-use futures::{executor, FutureExt};
+use futures::stream::FuturesUnordered;
+use futures::{executor, FutureExt, StreamExt};
 use hypervisor::HypervisorType;
-use seccompiler::{apply_filter, SeccompAction};
+use seccompiler::SeccompAction;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic::AssertUnwindSafe;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use vmm_sys_util::eventfd::EventFd;
 use zbus::fdo::{self, Result};
-use zbus::zvariant::Optional;
-use zbus::{dbus_interface, ConnectionBuilder};
+use zbus::names::InterfaceName;
+use zbus::zvariant::{Optional, Value};
+use zbus::{
+    dbus_interface, Connection, ConnectionBuilder, Guid, Interface, InterfaceRef, MessageHeader,
+    MessageStream, SignalContext,
+};
 
 pub type DBusApiShutdownChannels = (oneshot::Sender<()>, oneshot::Receiver<()>);
 
+const POLKIT_SERVICE: &str = "org.freedesktop.PolicyKit1";
+const POLKIT_AUTHORITY_PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+const POLKIT_AUTHORITY_INTERFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+// Authorizes the methods changing the VMM or the VM, or exposing the content
+// of the guest
+const POLKIT_MANAGE_ACTION: &str = "org.cloudhypervisor.manage";
+// Lets polkit ask the caller to authenticate through its agent
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+
+// VM events after which the `State`, `MemoryActualSize` or `VcpuCount`
+// properties may have changed
+const VM_PROPERTIES_EVENTS: &[&str] = &[
+    "created", "booted", "paused", "resumed", "rebooted", "shutdown", "deleted", "restored",
+    "resized",
+];
+const VM_NOT_CREATED: &str = "NotCreated";
+
+// Bounds of the delay between the attempts at reconnecting to the bus, which
+// doubles after each failure
+const BUS_RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const BUS_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+// Time given to a peer to complete the D-Bus handshake
+const PEER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Peers going through the D-Bus handshake at once, the others being
+// disconnected until one of them completes
+const MAX_PENDING_PEERS: usize = 16;
+
+// A file descriptor in the body would refer to one of the VMM, whereas
+// ignoring it would silently store the snapshot in the clear.
+const SNAPSHOT_KEY_FD_UNSUPPORTED: &str = "Snapshot keys can't be passed through D-Bus";
+// The same goes for the file descriptor of a snapshot stream.
+const SNAPSHOT_STREAM_FD_UNSUPPORTED: &str = "Snapshot streams can't be passed through D-Bus";
+
+type VmProperties = (String, u64, u32);
+
+pub enum DBusApiTransport {
+    /// Own the well-known `service_name` on the session or the system bus
+    Bus {
+        service_name: String,
+        system_bus: bool,
+        /// Check with polkit that the callers of the privileged methods are
+        /// granted the `org.cloudhypervisor.manage` action
+        polkit: bool,
+    },
+    /// Serve peer-to-peer connections accepted on a UNIX domain socket,
+    /// without any bus daemon
+    Peer { socket_path: String },
+}
+
 pub struct DBusApiOptions {
-    pub service_name: String,
+    pub transport: DBusApiTransport,
     pub object_path: String,
-    pub system_bus: bool,
     pub event_monitor_rx: flume::Receiver<Arc<String>>,
 }
 
+enum DBusServer {
+    Bus {
+        connection: Connection,
+        iface_ref: InterfaceRef<DBusApi>,
+        service_name: String,
+        system_bus: bool,
+    },
+    Peer {
+        listener: UnixListener,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    },
+}
+
 pub struct DBusApi {
     api_notifier: EventFd,
     api_sender: futures::lock::Mutex<Sender<ApiRequest>>,
+    polkit: bool,
+    // Credentials of the peer, on a peer-to-peer connection
+    peer: Option<Peer>,
+}
+
+// Errors are returned as a JSON serialized ApiErrorResponse, matching the
+// body of the HTTP API error responses.
+fn error_reply(
+    code: ApiErrorCode,
+    message: impl std::fmt::Display,
+    detail: Option<String>,
+) -> fdo::Error {
+    let response = ApiErrorResponse {
+        code,
+        message: message.to_string(),
+        detail,
+    };
+    fdo::Error::Failed(serde_json::to_string(&response).unwrap_or(response.message))
 }
 
-fn api_error(error: impl std::fmt::Debug + std::fmt::Display) -> fdo::Error {
-    fdo::Error::Failed(format!("{error}"))
+fn api_error(error: ApiError) -> fdo::Error {
+    let detail = format!("{error:?}");
+    error_reply(error.code(), error, Some(detail))
+}
+
+fn request_error(error: impl std::fmt::Display) -> fdo::Error {
+    error_reply(ApiErrorCode::InvalidRequest, error, None)
+}
+
+fn internal_error(error: impl std::fmt::Display) -> fdo::Error {
+    error_reply(ApiErrorCode::InternalError, error, None)
 }
 
 // This method is intended to ensure that the DBusApi thread has enough time to
@@ -66,13 +181,61 @@ pub fn dbus_api_graceful_shutdown(ch: DBusApiShutdownChannels) {
 }
 
 impl DBusApi {
-    pub fn new(api_notifier: EventFd, api_sender: Sender<ApiRequest>) -> Self {
+    pub fn new(api_notifier: EventFd, api_sender: Sender<ApiRequest>, polkit: bool) -> Self {
         Self {
             api_notifier,
             api_sender: futures::lock::Mutex::new(api_sender),
+            polkit,
+            peer: None,
         }
     }
 
+    // Asks polkit whether the sender of the method call is granted the
+    // management action, which may involve interactively authenticating the
+    // caller. Every caller is allowed when polkit isn't enabled.
+    async fn authorize(&self, connection: &Connection, header: &MessageHeader<'_>) -> Result<()> {
+        if !self.polkit {
+            return Ok(());
+        }
+
+        let sender = header
+            .sender()
+            .map_err(internal_error)?
+            .ok_or_else(|| fdo::Error::AccessDenied("Unknown sender".to_string()))?;
+        let subject = (
+            "system-bus-name",
+            HashMap::from([("name", Value::from(sender.as_str()))]),
+        );
+        let details: HashMap<&str, &str> = HashMap::new();
+
+        let reply = connection
+            .call_method(
+                Some(POLKIT_SERVICE),
+                POLKIT_AUTHORITY_PATH,
+                Some(POLKIT_AUTHORITY_INTERFACE),
+                "CheckAuthorization",
+                &(
+                    subject,
+                    POLKIT_MANAGE_ACTION,
+                    details,
+                    POLKIT_ALLOW_USER_INTERACTION,
+                    "",
+                ),
+            )
+            .await
+            .map_err(|e| internal_error(format!("Error checking polkit authorization: {e}")))?;
+        let (authorized, _, _): (bool, bool, HashMap<String, String>) =
+            reply.body().map_err(internal_error)?;
+
+        if !authorized {
+            return Err(fdo::Error::AccessDenied(format!(
+                "{sender} is not authorized for {POLKIT_MANAGE_ACTION}"
+            )));
+        }
+
+        Ok(())
+    }
+
     async fn clone_api_sender(&self) -> Sender<ApiRequest> {
         // lock the async mutex, clone the `Sender` and then immediately
         // drop the MutexGuard so that other tasks can clone the
@@ -80,6 +243,47 @@ impl DBusApi {
         self.api_sender.lock().await.clone()
     }
 
+    async fn duplicate(&self) -> Result<Self> {
+        let mut api = Self::new(
+            self.clone_api_notifier()?,
+            self.clone_api_sender().await,
+            self.polkit,
+        );
+        api.peer = self.peer.clone();
+        Ok(api)
+    }
+
+    // Records the method call and its outcome in the audit log, the caller
+    // being identified by its credentials on a peer-to-peer connection, by
+    // its unique name on a bus.
+    async fn audited<T>(
+        &self,
+        header: &MessageHeader<'_>,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let result = call.await;
+        if !audit::enabled() {
+            return result;
+        }
+
+        let peer = self.peer.clone().unwrap_or_else(|| match header.sender() {
+            Ok(Some(sender)) => Peer::DBus {
+                sender: sender.to_string(),
+            },
+            _ => Peer::Unknown,
+        });
+        let interface = header.interface().ok().flatten();
+        let member = header.member().ok().flatten();
+        let action = match (interface, member) {
+            (Some(interface), Some(member)) => format!("{interface}.{member}"),
+            _ => String::new(),
+        };
+        let error = result.as_ref().err().map(|e| e.to_string());
+        audit::record_dbus(&peer, &action, error.as_deref());
+
+        result
+    }
+
     fn clone_api_notifier(&self) -> Result<EventFd> {
         self.api_notifier
             .try_clone()
@@ -103,207 +307,1378 @@ impl DBusApi {
 
         Ok(result.into())
     }
+
+    // Returns the values of the `State`, `MemoryActualSize` and `VcpuCount`
+    // properties, the state being `NotCreated` until a VM is created.
+    async fn vm_properties(&self) -> Result<VmProperties> {
+        let api_sender = self.clone_api_sender().await;
+        let api_notifier = self.clone_api_notifier()?;
+
+        match blocking::unblock(move || VmInfo.send(api_notifier, api_sender, ())).await {
+            Ok(info) => {
+                let vcpus = info.config.lock().unwrap().cpus.boot_vcpus;
+                Ok((
+                    format!("{:?}", info.state),
+                    info.memory_actual_size,
+                    vcpus.into(),
+                ))
+            }
+            Err(ApiError::VmInfo(VmError::VmNotCreated)) => Ok((VM_NOT_CREATED.to_string(), 0, 0)),
+            Err(e) => Err(api_error(e)),
+        }
+    }
 }
 
 #[dbus_interface(name = "org.cloudhypervisor.DBusApi1")]
 impl DBusApi {
-    async fn vmm_ping(&self) -> Result<String> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vmm_ping(&self, #[zbus(header)] header: MessageHeader<'_>) -> Result<String> {
+        self.audited(&header, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
 
-        let result = blocking::unblock(move || VmmPing.send(api_notifier, api_sender, ()))
+            let result = blocking::unblock(move || VmmPing.send(api_notifier, api_sender, ()))
+                .await
+                .map_err(api_error)?;
+            serde_json::to_string(&result).map_err(internal_error)
+        })
+        .await
+    }
+
+    async fn vmm_threads(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmmThreads, ()).await })
             .await
-            .map_err(api_error)?;
-        serde_json::to_string(&result).map_err(api_error)
     }
 
-    async fn vmm_shutdown(&self) -> Result<()> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vmm_host_info(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmmHostInfo, ()).await })
+            .await
+    }
+
+    async fn vmm_capabilities(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.vm_action(&VmmCapabilities, ()).await
+        })
+        .await
+    }
 
-        blocking::unblock(move || VmmShutdown.send(api_notifier, api_sender, ()))
+    async fn vmm_fds(&self, #[zbus(header)] header: MessageHeader<'_>) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmmFds, ()).await })
             .await
-            .map_err(api_error)
     }
 
-    async fn vm_add_device(&self, device_config: String) -> Result<Optional<String>> {
-        let device_config = serde_json::from_str(&device_config).map_err(api_error)?;
-        self.vm_action(&VmAddDevice, device_config).await
+    async fn vmm_checkpoint(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        checkpoint_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let checkpoint_data: VmmCheckpointData =
+                serde_json::from_str(&checkpoint_data).map_err(request_error)?;
+            self.vm_action(&VmmCheckpoint, checkpoint_data)
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_add_disk(&self, disk_config: String) -> Result<Optional<String>> {
-        let disk_config = serde_json::from_str(&disk_config).map_err(api_error)?;
-        self.vm_action(&AddDisk, disk_config).await
+    async fn vmm_post_restore(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmmPostRestore, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_add_fs(&self, fs_config: String) -> Result<Optional<String>> {
-        let fs_config = serde_json::from_str(&fs_config).map_err(api_error)?;
-        self.vm_action(&VmAddFs, fs_config).await
+    async fn vmm_profile(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmmProfile, ()).await })
+            .await
     }
 
-    async fn vm_add_net(&self, net_config: String) -> Result<Optional<String>> {
-        let mut net_config: NetConfig = serde_json::from_str(&net_config).map_err(api_error)?;
-        if net_config.fds.is_some() {
-            warn!("Ignoring FDs sent via the D-Bus request body");
-            net_config.fds = None;
-        }
-        self.vm_action(&VmAddNet, net_config).await
+    async fn vmm_pprof(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vmm_pprof_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            #[cfg(feature = "pprof")]
+            {
+                let vmm_pprof_data =
+                    serde_json::from_str(&vmm_pprof_data).map_err(request_error)?;
+                self.vm_action(&VmmPprof, vmm_pprof_data).await.map(|_| ())
+            }
+
+            #[cfg(not(feature = "pprof"))]
+            Err(request_error(
+                "VmmPprof only works with the `pprof` feature enabled",
+            ))
+        })
+        .await
+    }
+
+    async fn vmm_shutdown(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            blocking::unblock(move || VmmShutdown.send(api_notifier, api_sender, ()))
+                .await
+                .map_err(api_error)
+        })
+        .await
+    }
+
+    async fn vm_add_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        device_config: DeviceArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let device_config = device_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddDevice, device_config).await
+        })
+        .await
     }
 
-    async fn vm_add_pmem(&self, pmem_config: String) -> Result<Optional<String>> {
-        let pmem_config = serde_json::from_str(&pmem_config).map_err(api_error)?;
-        self.vm_action(&VmAddPmem, pmem_config).await
+    async fn vm_add_disk(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        disk_config: DiskArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let disk_config = disk_config.try_into().map_err(request_error)?;
+            self.vm_action(&AddDisk, disk_config).await
+        })
+        .await
     }
 
-    async fn vm_add_user_device(&self, vm_add_user_device: String) -> Result<Optional<String>> {
-        let vm_add_user_device = serde_json::from_str(&vm_add_user_device).map_err(api_error)?;
-        self.vm_action(&VmAddUserDevice, vm_add_user_device).await
+    async fn vm_add_fs(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        fs_config: FsArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let fs_config = fs_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddFs, fs_config).await
+        })
+        .await
     }
 
-    async fn vm_add_vdpa(&self, vdpa_config: String) -> Result<Optional<String>> {
-        let vdpa_config = serde_json::from_str(&vdpa_config).map_err(api_error)?;
-        self.vm_action(&VmAddVdpa, vdpa_config).await
+    async fn vm_add_net(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        net_config: NetArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let net_config = net_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddNet, net_config).await
+        })
+        .await
     }
 
-    async fn vm_add_vsock(&self, vsock_config: String) -> Result<Optional<String>> {
-        let vsock_config = serde_json::from_str(&vsock_config).map_err(api_error)?;
-        self.vm_action(&VmAddVsock, vsock_config).await
+    async fn vm_add_pmem(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        pmem_config: PmemArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let pmem_config = pmem_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddPmem, pmem_config).await
+        })
+        .await
     }
 
-    async fn vm_boot(&self) -> Result<()> {
-        self.vm_action(&VmBoot, ()).await.map(|_| ())
+    async fn vm_add_user_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_add_user_device: UserDeviceArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_add_user_device = vm_add_user_device.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddUserDevice, vm_add_user_device).await
+        })
+        .await
+    }
+
+    async fn vm_add_vdpa(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vdpa_config: VdpaArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vdpa_config = vdpa_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddVdpa, vdpa_config).await
+        })
+        .await
+    }
+
+    async fn vm_add_vsock(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vsock_config: VsockArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vsock_config = vsock_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddVsock, vsock_config).await
+        })
+        .await
+    }
+
+    async fn vm_add_usb(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        usb_config: UsbArgs,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let usb_config = usb_config.try_into().map_err(request_error)?;
+            self.vm_action(&VmAddUsb, usb_config).await
+        })
+        .await
+    }
+
+    async fn vm_boot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmBoot, None).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_boot_with_identity(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_identity_config: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_identity_config =
+                serde_json::from_str(&vm_identity_config).map_err(request_error)?;
+            self.vm_action(&VmBoot, Some(vm_identity_config))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
     #[allow(unused_variables)]
     // zbus doesn't support cfg attributes on interface methods
     // as a workaround, we make the *call to the internal API* conditionally
     // compile and return an error on unsupported platforms.
-    async fn vm_coredump(&self, vm_coredump_data: String) -> Result<()> {
-        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
-        {
-            let vm_coredump_data = serde_json::from_str(&vm_coredump_data).map_err(api_error)?;
-            self.vm_action(&VmCoredump, vm_coredump_data)
-                .await
-                .map(|_| ())
-        }
+    async fn vm_coredump(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_coredump_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+            {
+                let vm_coredump_data =
+                    serde_json::from_str(&vm_coredump_data).map_err(request_error)?;
+                self.vm_action(&VmCoredump, vm_coredump_data)
+                    .await
+                    .map(|_| ())
+            }
+
+            #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+            Err(request_error(
+                "VmCoredump only works on x86_64 with the `guest_debug` feature enabled",
+            ))
+        })
+        .await
+    }
 
-        #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
-        Err(api_error(
-            "VmCoredump only works on x86_64 with the `guest_debug` feature enabled",
-        ))
+    async fn vm_counters(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmCounters, ()).await })
+            .await
     }
 
-    async fn vm_counters(&self) -> Result<Optional<String>> {
-        self.vm_action(&VmCounters, ()).await
+    async fn vm_vcpu_stats(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmVcpuStats, ()).await })
+            .await
     }
 
-    async fn vm_create(&self, vm_config: String) -> Result<()> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vm_migration_blockers(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async {
+            self.vm_action(&VmMigrationBlockers, ()).await
+        })
+        .await
+    }
 
-        let mut vm_config: VmConfig = serde_json::from_str(&vm_config).map_err(api_error)?;
+    async fn vm_capabilities(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmCapabilities, ()).await })
+            .await
+    }
 
-        if let Some(ref mut nets) = vm_config.net {
-            if nets.iter().any(|net| net.fds.is_some()) {
-                warn!("Ignoring FDs sent via the D-Bus request body");
+    async fn vm_device_tree(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmDeviceTree, ()).await })
+            .await
+    }
+
+    async fn vm_devices(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<Optional<String>> {
+        self.audited(&header, async { self.vm_action(&VmDevices, ()).await })
+            .await
+    }
+
+    async fn vm_create(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_config: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            let mut vm_config: VmConfig =
+                serde_json::from_str(&vm_config).map_err(request_error)?;
+
+            if let Some(ref mut nets) = vm_config.net {
+                if nets.iter().any(|net| net.fds.is_some()) {
+                    warn!("Ignoring FDs sent via the D-Bus request body");
+                }
+                for net in nets {
+                    net.fds = None;
+                }
             }
-            for net in nets {
-                net.fds = None;
+
+            if let Some(ref mut disks) = vm_config.disks {
+                if disks.iter().any(|disk| disk.fd.is_some()) {
+                    warn!("Ignoring FDs sent via the D-Bus request body");
+                }
+                for disk in disks {
+                    disk.fd = None;
+                }
             }
-        }
 
-        blocking::unblock(move || {
-            VmCreate.send(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
+            blocking::unblock(move || {
+                VmCreate.send(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
+            })
+            .await
+            .map_err(api_error)?;
+
+            Ok(())
         })
         .await
-        .map_err(api_error)?;
+    }
 
-        Ok(())
+    async fn vm_delete(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmDelete, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_delete(&self) -> Result<()> {
-        self.vm_action(&VmDelete, ()).await.map(|_| ())
+    async fn vm_info(&self, #[zbus(header)] header: MessageHeader<'_>) -> Result<String> {
+        self.audited(&header, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            let result = blocking::unblock(move || VmInfo.send(api_notifier, api_sender, ()))
+                .await
+                .map_err(api_error)?;
+            serde_json::to_string(&result).map_err(internal_error)
+        })
+        .await
     }
 
-    async fn vm_info(&self) -> Result<String> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vm_pause(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmPause, ()).await.map(|_| ())
+        })
+        .await
+    }
 
-        let result = blocking::unblock(move || VmInfo.send(api_notifier, api_sender, ()))
-            .await
-            .map_err(api_error)?;
-        serde_json::to_string(&result).map_err(api_error)
+    async fn vm_nmi(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmNmi, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_pause(&self) -> Result<()> {
-        self.vm_action(&VmPause, ()).await.map(|_| ())
+    async fn vm_power_button(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmPowerButton, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_power_button(&self) -> Result<()> {
-        self.vm_action(&VmPowerButton, ()).await.map(|_| ())
+    async fn vm_reboot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmReboot, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_reboot(&self) -> Result<()> {
-        self.vm_action(&VmReboot, ()).await.map(|_| ())
+    async fn vm_remove_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_remove_device: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_remove_device =
+                serde_json::from_str(&vm_remove_device).map_err(request_error)?;
+            self.vm_action(&VmRemoveDevice, vm_remove_device)
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_remove_device(&self, vm_remove_device: String) -> Result<()> {
-        let vm_remove_device = serde_json::from_str(&vm_remove_device).map_err(api_error)?;
-        self.vm_action(&VmRemoveDevice, vm_remove_device)
-            .await
-            .map(|_| ())
+    async fn vm_replace_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_replace_device: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_replace_device =
+                serde_json::from_str(&vm_replace_device).map_err(request_error)?;
+            self.vm_action(&VmReplaceDevice, vm_replace_device)
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resize(&self, vm_resize: String) -> Result<()> {
-        let vm_resize = serde_json::from_str(&vm_resize).map_err(api_error)?;
-        self.vm_action(&VmResize, vm_resize).await.map(|_| ())
+    async fn vm_sysrq(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_sysrq: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_sysrq = serde_json::from_str(&vm_sysrq).map_err(request_error)?;
+            self.vm_action(&VmSysRq, vm_sysrq).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resize_zone(&self, vm_resize_zone: String) -> Result<()> {
-        let vm_resize_zone = serde_json::from_str(&vm_resize_zone).map_err(api_error)?;
-        self.vm_action(&VmResizeZone, vm_resize_zone)
-            .await
-            .map(|_| ())
+    async fn vm_screenshot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_screenshot_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_screenshot_data =
+                serde_json::from_str(&vm_screenshot_data).map_err(request_error)?;
+            self.vm_action(&VmScreenshot, vm_screenshot_data)
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_restore(&self, restore_config: String) -> Result<()> {
-        let restore_config = serde_json::from_str(&restore_config).map_err(api_error)?;
-        self.vm_action(&VmRestore, restore_config).await.map(|_| ())
+    async fn vm_dump_memory(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_dump_memory_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_dump_memory_data =
+                serde_json::from_str(&vm_dump_memory_data).map_err(request_error)?;
+            self.vm_action(&VmDumpMemory, vm_dump_memory_data)
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_receive_migration(&self, receive_migration_data: String) -> Result<()> {
-        let receive_migration_data =
-            serde_json::from_str(&receive_migration_data).map_err(api_error)?;
-        self.vm_action(&VmReceiveMigration, receive_migration_data)
-            .await
-            .map(|_| ())
+    async fn vm_resize(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_resize: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_resize = serde_json::from_str(&vm_resize).map_err(request_error)?;
+            self.vm_action(&VmResize, vm_resize).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_send_migration(&self, send_migration_data: String) -> Result<()> {
-        let send_migration_data = serde_json::from_str(&send_migration_data).map_err(api_error)?;
-        self.vm_action(&VmSendMigration, send_migration_data)
-            .await
-            .map(|_| ())
+    async fn vm_resize_zone(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_resize_zone: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_resize_zone = serde_json::from_str(&vm_resize_zone).map_err(request_error)?;
+            self.vm_action(&VmResizeZone, vm_resize_zone)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_set_boot_params(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_set_boot_params: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_set_boot_params =
+                serde_json::from_str(&vm_set_boot_params).map_err(request_error)?;
+            self.vm_action(&VmSetBootParams, vm_set_boot_params)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_rekey(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_rekey_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_rekey_data = serde_json::from_str(&vm_rekey_data).map_err(request_error)?;
+            self.vm_action(&VmRekey, vm_rekey_data).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_update_config(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        patch: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let patch = serde_json::from_str(&patch).map_err(request_error)?;
+            self.vm_action(&VmUpdateConfig, patch).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_restore(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        restore_config: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let restore_config: RestoreConfig =
+                serde_json::from_str(&restore_config).map_err(request_error)?;
+            if restore_config.key_fd.is_some() {
+                return Err(request_error(SNAPSHOT_KEY_FD_UNSUPPORTED));
+            }
+            if restore_config
+                .source_url
+                .to_string_lossy()
+                .starts_with("fd:")
+            {
+                return Err(request_error(SNAPSHOT_STREAM_FD_UNSUPPORTED));
+            }
+            self.vm_action(&VmRestore, restore_config).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_receive_migration(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        receive_migration_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let receive_migration_data =
+                serde_json::from_str(&receive_migration_data).map_err(request_error)?;
+            self.vm_action(&VmReceiveMigration, receive_migration_data)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_send_migration(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        send_migration_data: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let send_migration_data =
+                serde_json::from_str(&send_migration_data).map_err(request_error)?;
+            self.vm_action(&VmSendMigration, send_migration_data)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_resume(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmResume, ()).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_shutdown(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmShutdown, ()).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_snapshot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        vm_snapshot_config: String,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            let vm_snapshot_config: VmSnapshotConfig =
+                serde_json::from_str(&vm_snapshot_config).map_err(request_error)?;
+            if vm_snapshot_config.key_fd.is_some() {
+                return Err(request_error(SNAPSHOT_KEY_FD_UNSUPPORTED));
+            }
+            if vm_snapshot_config.destination_url.starts_with("fd:") {
+                return Err(request_error(SNAPSHOT_STREAM_FD_UNSUPPORTED));
+            }
+            self.vm_action(&VmSnapshot, vm_snapshot_config)
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_suspend_to_ram(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.audited(&header, async {
+            self.authorize(connection, &header).await?;
+            self.vm_action(&VmSuspendToRam, ()).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resume(&self) -> Result<()> {
-        self.vm_action(&VmResume, ()).await.map(|_| ())
+    #[dbus_interface(property)]
+    async fn state(&self) -> Result<String> {
+        self.vm_properties().await.map(|(state, _, _)| state)
     }
 
-    async fn vm_shutdown(&self) -> Result<()> {
-        self.vm_action(&VmShutdown, ()).await.map(|_| ())
+    #[dbus_interface(property)]
+    async fn memory_actual_size(&self) -> Result<u64> {
+        self.vm_properties()
+            .await
+            .map(|(_, memory_actual_size, _)| memory_actual_size)
     }
 
-    async fn vm_snapshot(&self, vm_snapshot_config: String) -> Result<()> {
-        let vm_snapshot_config = serde_json::from_str(&vm_snapshot_config).map_err(api_error)?;
-        self.vm_action(&VmSnapshot, vm_snapshot_config)
+    #[dbus_interface(property)]
+    async fn vcpu_count(&self) -> Result<u32> {
+        self.vm_properties()
             .await
-            .map(|_| ())
+            .map(|(_, _, vcpu_count)| vcpu_count)
     }
 
     // implementation of this function is provided by the `dbus_interface` macro
     #[dbus_interface(signal)]
     async fn event(ctxt: &zbus::SignalContext<'_>, event: Arc<String>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_booted(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_paused(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_resumed(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_rebooted(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    // named after the signal, as `vm_shutdown` is taken by the method
+    #[dbus_interface(signal, name = "VmShutdown")]
+    async fn vm_shutdown_signal(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_deleted(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn vm_restored(ctxt: &zbus::SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn device_hotplugged(
+        ctxt: &zbus::SignalContext<'_>,
+        id: &str,
+        bdf: &str,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn device_removed(
+        ctxt: &zbus::SignalContext<'_>,
+        id: &str,
+        bdf: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Object of the VM created in the VMM, served under the object path of the
+/// API as `vm<N>`, `N` being the number of VMs previously created.
+pub struct DBusVm {
+    api: DBusApi,
+}
+
+#[dbus_interface(name = "org.cloudhypervisor.Vm1")]
+impl DBusVm {
+    async fn boot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_boot(connection, header).await
+    }
+
+    async fn delete(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_delete(connection, header).await
+    }
+
+    async fn info(&self, #[zbus(header)] header: MessageHeader<'_>) -> Result<String> {
+        self.api.vm_info(header).await
+    }
+
+    async fn pause(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_pause(connection, header).await
+    }
+
+    async fn power_button(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_power_button(connection, header).await
+    }
+
+    async fn reboot(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_reboot(connection, header).await
+    }
+
+    async fn resume(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_resume(connection, header).await
+    }
+
+    async fn shutdown(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_shutdown(connection, header).await
+    }
+
+    async fn suspend_to_ram(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        self.api.vm_suspend_to_ram(connection, header).await
+    }
+
+    #[dbus_interface(property)]
+    async fn state(&self) -> Result<String> {
+        self.api.state().await
+    }
+
+    #[dbus_interface(property)]
+    async fn memory_actual_size(&self) -> Result<u64> {
+        self.api.memory_actual_size().await
+    }
+
+    #[dbus_interface(property)]
+    async fn vcpu_count(&self) -> Result<u32> {
+        self.api.vcpu_count().await
+    }
+}
+
+// Path of the VM object, which only exists while a VM is created.
+struct VmPath {
+    object_path: String,
+    next_id: u64,
+    current: Option<String>,
+}
+
+impl VmPath {
+    fn new(object_path: &str) -> Self {
+        Self {
+            object_path: object_path.trim_end_matches('/').to_string(),
+            next_id: 0,
+            current: None,
+        }
+    }
+
+    // Returns the path of the VM object to remove and the one to add, after
+    // the VM got deleted or created.
+    fn update(&mut self, created: bool) -> (Option<String>, Option<String>) {
+        match (created, &self.current) {
+            (true, None) => {
+                let path = format!("{}/vm{}", self.object_path, self.next_id);
+                self.next_id += 1;
+                self.current = Some(path.clone());
+                (None, Some(path))
+            }
+            (false, Some(_)) => (self.current.take(), None),
+            _ => (None, None),
+        }
+    }
+}
+
+// Event as published by the `event-monitor` crate.
+#[derive(Deserialize)]
+struct MonitorEvent {
+    source: String,
+    event: String,
+    #[serde(default)]
+    properties: Option<HashMap<String, String>>,
+}
+
+// Emits the signal dedicated to the VM lifecycle event, if any, in addition
+// to the generic `Event` signal.
+async fn lifecycle_signal(ctxt: &zbus::SignalContext<'_>, event: &str) -> zbus::Result<()> {
+    let Ok(event) = serde_json::from_str::<MonitorEvent>(event) else {
+        return Ok(());
+    };
+    if event.source != "vm" {
+        return Ok(());
+    }
+
+    let property = |name: &str| {
+        event
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.get(name))
+            .map(String::as_str)
+            .unwrap_or_default()
+    };
+
+    match event.event.as_str() {
+        "booted" => DBusApi::vm_booted(ctxt).await,
+        "paused" => DBusApi::vm_paused(ctxt).await,
+        "resumed" => DBusApi::vm_resumed(ctxt).await,
+        "rebooted" => DBusApi::vm_rebooted(ctxt).await,
+        "shutdown" => DBusApi::vm_shutdown_signal(ctxt).await,
+        "deleted" => DBusApi::vm_deleted(ctxt).await,
+        "restored" => DBusApi::vm_restored(ctxt).await,
+        "device-added" => DBusApi::device_hotplugged(ctxt, property("id"), property("bdf")).await,
+        "device-removed" => DBusApi::device_removed(ctxt, property("id"), property("bdf")).await,
+        _ => Ok(()),
+    }
+}
+
+// Whether the event is one of the VM transitions affecting the properties.
+fn changes_properties(event: &str) -> bool {
+    serde_json::from_str::<MonitorEvent>(event).is_ok_and(|event| {
+        event.source == "vm" && VM_PROPERTIES_EVENTS.contains(&event.event.as_str())
+    })
+}
+
+// Emits a single `PropertiesChanged` signal for all the VM properties.
+async fn properties_changed(
+    ctxt: &SignalContext<'_>,
+    interface_name: InterfaceName<'_>,
+    properties: &VmProperties,
+) -> zbus::Result<()> {
+    let (state, memory_actual_size, vcpu_count) = properties;
+    let (state, memory_actual_size, vcpu_count) = (
+        Value::from(state.as_str()),
+        Value::from(*memory_actual_size),
+        Value::from(*vcpu_count),
+    );
+    let changed = HashMap::from([
+        ("State", &state),
+        ("MemoryActualSize", &memory_actual_size),
+        ("VcpuCount", &vcpu_count),
+    ]);
+
+    fdo::Properties::properties_changed(ctxt, interface_name, &changed, &[]).await
+}
+
+async fn add_vm_object(iface_ref: &InterfaceRef<DBusApi>, path: &str) -> zbus::Result<()> {
+    let api = iface_ref.get().await.duplicate().await?;
+    iface_ref
+        .signal_context()
+        .connection()
+        .object_server()
+        .at(path, DBusVm { api })
+        .await?;
+
+    Ok(())
+}
+
+// Adds or removes the VM object on each connection after the VM got created
+// or deleted, returning the properties of the VM.
+async fn update_vm_objects(
+    api: &DBusApi,
+    vm_path: &mut VmPath,
+    iface_refs: &[&InterfaceRef<DBusApi>],
+) -> Option<VmProperties> {
+    let properties = api.vm_properties().await.ok()?;
+    let (removed, added) = vm_path.update(properties.0 != VM_NOT_CREATED);
+
+    for iface_ref in iface_refs {
+        let object_server = iface_ref.signal_context().connection().object_server();
+        if let Some(path) = &removed {
+            object_server.remove::<DBusVm, _>(path.as_str()).await.ok();
+        }
+        if let Some(path) = &added {
+            if let Err(e) = add_vm_object(iface_ref, path).await {
+                warn!("Error adding D-Bus VM object {path}: {e}");
+            }
+        }
+    }
+
+    Some(properties)
+}
+
+async fn emit_event(
+    api: &DBusApi,
+    vm_path: &mut VmPath,
+    iface_refs: &[&InterfaceRef<DBusApi>],
+    event: Arc<String>,
+) {
+    let properties = if changes_properties(&event) {
+        update_vm_objects(api, vm_path, iface_refs).await
+    } else {
+        None
+    };
+
+    for iface_ref in iface_refs {
+        let ctxt = iface_ref.signal_context();
+        lifecycle_signal(ctxt, &event).await.ok();
+        if let Some(properties) = &properties {
+            properties_changed(ctxt, DBusApi::name(), properties)
+                .await
+                .ok();
+            if let Some(path) = &vm_path.current {
+                if let Ok(vm_ctxt) = SignalContext::new(ctxt.connection(), path.as_str()) {
+                    properties_changed(&vm_ctxt, DBusVm::name(), properties)
+                        .await
+                        .ok();
+                }
+            }
+        }
+        DBusApi::event(ctxt, event.clone()).await.ok();
+    }
+}
+
+async fn peer_connection(
+    stream: UnixStream,
+    object_path: &str,
+    dbus_iface: DBusApi,
+) -> zbus::Result<(Connection, InterfaceRef<DBusApi>)> {
+    let conn = ConnectionBuilder::unix_stream(stream)
+        .server(&Guid::generate())
+        .p2p()
+        .internal_executor(false)
+        .serve_at(object_path, dbus_iface)?
+        .serve_at(object_path, fdo::ObjectManager)?
+        .build()
+        .await?;
+
+    let iface_ref = conn
+        .object_server()
+        .interface::<_, DBusApi>(object_path)
+        .await?;
+
+    Ok((conn, iface_ref))
+}
+
+// Connects a peer, giving up once the handshake takes too long, so that a
+// peer stalling it doesn't hold a slot forever.
+async fn peer_handshake(
+    stream: UnixStream,
+    object_path: String,
+    dbus_iface: DBusApi,
+) -> Option<(Connection, InterfaceRef<DBusApi>)> {
+    let connection = peer_connection(stream, &object_path, dbus_iface).fuse();
+    let timeout = blocking::unblock(|| thread::sleep(PEER_HANDSHAKE_TIMEOUT)).fuse();
+    futures::pin_mut!(connection, timeout);
+
+    futures::select! {
+        ret = connection => match ret {
+            Ok(ret) => Some(ret),
+            Err(e) => {
+                warn!("Error connecting D-Bus peer: {e}");
+                None
+            }
+        },
+        _ = timeout => {
+            warn!("D-Bus peer didn't complete the handshake in time");
+            None
+        }
+    }
+}
+
+// Runs the executor of a peer connection until the peer disconnects.
+async fn serve_peer(id: u64, connection: Connection) -> u64 {
+    let messages = MessageStream::from(&connection).fuse();
+    let executor_tick = futures::future::Fuse::terminated();
+    futures::pin_mut!(messages, executor_tick);
+    executor_tick.set(connection.executor().tick().fuse());
+
+    loop {
+        futures::select! {
+            _ = executor_tick => executor_tick.set(connection.executor().tick().fuse()),
+            msg = messages.next() => {
+                if !matches!(msg, Some(Ok(_))) {
+                    break;
+                }
+            }
+        }
+    }
+
+    id
+}
+
+async fn bus_connection(
+    service_name: &str,
+    system_bus: bool,
+    object_path: &str,
+    dbus_iface: DBusApi,
+) -> zbus::Result<(Connection, InterfaceRef<DBusApi>)> {
+    let conn_builder = if system_bus {
+        ConnectionBuilder::system()?
+    } else {
+        ConnectionBuilder::session()?
+    };
+
+    let conn = conn_builder
+        .internal_executor(false)
+        .name(service_name)?
+        .serve_at(object_path, dbus_iface)?
+        .serve_at(object_path, fdo::ObjectManager)?
+        .build()
+        .await?;
+
+    let iface_ref = conn
+        .object_server()
+        .interface::<_, DBusApi>(object_path)
+        .await?;
+
+    Ok((conn, iface_ref))
+}
+
+async fn serve_bus(
+    mut connection: Connection,
+    mut iface_ref: InterfaceRef<DBusApi>,
+    service_name: String,
+    system_bus: bool,
+    object_path: String,
+    recv_shutdown: oneshot::Receiver<()>,
+    event_monitor_rx: flume::Receiver<Arc<String>>,
+) {
+    let api = match iface_ref.get().await.duplicate().await {
+        Ok(api) => api,
+        Err(e) => {
+            error!("Error cloning the D-Bus API: {e}");
+            return;
+        }
+    };
+    let mut vm_path = VmPath::new(&object_path);
+    update_vm_objects(&api, &mut vm_path, &[&iface_ref]).await;
+
+    let recv_shutdown = recv_shutdown.fuse();
+    futures::pin_mut!(recv_shutdown);
+
+    loop {
+        // Serve the API until the connection to the bus gets closed, which
+        // ends the stream of the incoming messages
+        {
+            let messages = MessageStream::from(&connection).fuse();
+            let executor_tick = futures::future::Fuse::terminated();
+            futures::pin_mut!(messages, executor_tick);
+            executor_tick.set(connection.executor().tick().fuse());
+
+            loop {
+                futures::select! {
+                    _ = executor_tick => executor_tick.set(connection.executor().tick().fuse()),
+                    msg = messages.next() => {
+                        if !matches!(msg, Some(Ok(_))) {
+                            break;
+                        }
+                    }
+                    _ = recv_shutdown => return,
+                    ret = event_monitor_rx.recv_async() => {
+                        if let Ok(event) = ret {
+                            emit_event(&api, &mut vm_path, &[&iface_ref], event).await;
+                        }
+                    }
+                }
+            }
+        }
+
+        warn!("Lost the connection to the D-Bus daemon, reconnecting");
+        event!("dbus", "disconnected");
+
+        // Keep track of the VM objects while disconnected, so that the
+        // current one gets served again once reconnected
+        let mut delay = BUS_RECONNECT_MIN_DELAY;
+        (connection, iface_ref) = loop {
+            let sleep = blocking::unblock(move || thread::sleep(delay)).fuse();
+            futures::pin_mut!(sleep);
+
+            loop {
+                futures::select! {
+                    _ = sleep => break,
+                    _ = recv_shutdown => return,
+                    ret = event_monitor_rx.recv_async() => {
+                        if let Ok(event) = ret {
+                            emit_event(&api, &mut vm_path, &[], event).await;
+                        }
+                    }
+                }
+            }
+
+            let dbus_iface = match api.duplicate().await {
+                Ok(dbus_iface) => dbus_iface,
+                Err(e) => {
+                    error!("Error cloning the D-Bus API: {e}");
+                    return;
+                }
+            };
+            match bus_connection(&service_name, system_bus, &object_path, dbus_iface).await {
+                Ok(ret) => break ret,
+                Err(e) => {
+                    debug!("Error reconnecting to the D-Bus daemon: {e}");
+                    delay = std::cmp::min(delay * 2, BUS_RECONNECT_MAX_DELAY);
+                }
+            }
+        };
+
+        if let Some(path) = &vm_path.current {
+            if let Err(e) = add_vm_object(&iface_ref, path).await {
+                warn!("Error adding D-Bus VM object {path}: {e}");
+            }
+        }
+
+        info!("Reconnected to the D-Bus daemon");
+        event!("dbus", "reconnected");
+    }
+}
+
+async fn serve_peers(
+    listener: UnixListener,
+    object_path: String,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    recv_shutdown: oneshot::Receiver<()>,
+    event_monitor_rx: flume::Receiver<Arc<String>>,
+) {
+    let listener = Arc::new(listener);
+    let accept = || {
+        let listener = listener.clone();
+        blocking::unblock(move || listener.accept()).fuse()
+    };
+
+    let recv_shutdown = recv_shutdown.fuse();
+    let next_peer = futures::future::Fuse::terminated();
+    futures::pin_mut!(recv_shutdown, next_peer);
+    next_peer.set(accept());
+
+    let api = DBusApi::new(api_notifier, api_sender, false);
+    let mut vm_path = VmPath::new(&object_path);
+    update_vm_objects(&api, &mut vm_path, &[]).await;
+
+    let mut next_id = 0u64;
+    // The handshakes go on alongside, a slow peer not holding back the
+    // others nor the events
+    let mut handshakes = FuturesUnordered::new();
+    let mut peers = FuturesUnordered::new();
+    let mut peer_ifaces = HashMap::new();
+
+    loop {
+        futures::select! {
+            ret = next_peer => {
+                next_peer.set(accept());
+
+                let stream = match ret {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Error accepting D-Bus peer: {e}");
+                        continue;
+                    }
+                };
+                if handshakes.len() >= MAX_PENDING_PEERS {
+                    // Dropping the stream closes the connection
+                    warn!("Error connecting D-Bus peer: too many pending peers");
+                    continue;
+                }
+                let mut dbus_iface = match api.duplicate().await {
+                    Ok(dbus_iface) => dbus_iface,
+                    Err(e) => {
+                        warn!("Error cloning the D-Bus API for peer: {e}");
+                        continue;
+                    }
+                };
+                if audit::enabled() {
+                    dbus_iface.peer = Some(Peer::from_unix_socket(&stream));
+                }
+                handshakes.push(peer_handshake(stream, object_path.clone(), dbus_iface));
+            },
+            ret = handshakes.select_next_some() => {
+                let Some((connection, iface_ref)) = ret else {
+                    continue;
+                };
+                if let Some(path) = &vm_path.current {
+                    if let Err(e) = add_vm_object(&iface_ref, path).await {
+                        warn!("Error adding D-Bus VM object {path}: {e}");
+                    }
+                }
+                next_id += 1;
+                peer_ifaces.insert(next_id, iface_ref);
+                peers.push(serve_peer(next_id, connection));
+            },
+            id = peers.select_next_some() => {
+                peer_ifaces.remove(&id);
+            },
+            _ = recv_shutdown => break,
+            ret = event_monitor_rx.recv_async() => {
+                if let Ok(event) = ret {
+                    let iface_refs: Vec<_> = peer_ifaces.values().collect();
+                    emit_event(&api, &mut vm_path, &iface_refs, event).await;
+                }
+            }
+        }
+    }
 }
 
 pub fn start_dbus_thread(
@@ -314,29 +1689,42 @@ pub fn start_dbus_thread(
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
 ) -> VmmResult<(thread::JoinHandle<VmmResult<()>>, DBusApiShutdownChannels)> {
-    let dbus_iface = DBusApi::new(api_notifier, api_sender);
-    let (connection, iface_ref) = executor::block_on(async move {
-        let conn_builder = if dbus_options.system_bus {
-            ConnectionBuilder::system()?
-        } else {
-            ConnectionBuilder::session()?
-        };
+    let DBusApiOptions {
+        transport,
+        object_path,
+        event_monitor_rx,
+    } = dbus_options;
+    let server = match transport {
+        DBusApiTransport::Bus {
+            service_name,
+            system_bus,
+            polkit,
+        } => {
+            let dbus_iface = DBusApi::new(api_notifier, api_sender, polkit);
+            let (connection, iface_ref) = executor::block_on(bus_connection(
+                &service_name,
+                system_bus,
+                &object_path,
+                dbus_iface,
+            ))
+            .map_err(VmmError::CreateDBusSession)?;
 
-        let conn = conn_builder
-            .internal_executor(false)
-            .name(dbus_options.service_name)?
-            .serve_at(dbus_options.object_path.as_str(), dbus_iface)?
-            .build()
-            .await?;
-
-        let iface_ref = conn
-            .object_server()
-            .interface::<_, DBusApi>(dbus_options.object_path)
-            .await?;
-
-        Ok((conn, iface_ref))
-    })
-    .map_err(VmmError::CreateDBusSession)?;
+            DBusServer::Bus {
+                connection,
+                iface_ref,
+                service_name,
+                system_bus,
+            }
+        }
+        DBusApiTransport::Peer { socket_path } => {
+            let listener = UnixListener::bind(socket_path).map_err(VmmError::CreateDBusSocket)?;
+            DBusServer::Peer {
+                listener,
+                api_notifier,
+                api_sender,
+            }
+        }
+    };
 
     let (send_shutdown, recv_shutdown) = oneshot::channel::<()>();
     let (send_done, recv_done) = oneshot::channel::<()>();
@@ -350,7 +1738,7 @@ pub fn start_dbus_thread(
         .spawn(move || {
             // Apply seccomp filter for API thread.
             if !api_seccomp_filter.is_empty() {
-                apply_filter(&api_seccomp_filter)
+                seccomp_violations::apply_filter(&api_seccomp_filter)
                     .map_err(VmmError::ApplySeccompFilter)
                     .map_err(|e| {
                         error!("Error applying seccomp filter: {:?}", e);
@@ -361,25 +1749,41 @@ pub fn start_dbus_thread(
 
             std::panic::catch_unwind(AssertUnwindSafe(move || {
                 executor::block_on(async move {
-                    let recv_shutdown = recv_shutdown.fuse();
-                    let executor_tick = futures::future::Fuse::terminated();
-                    futures::pin_mut!(recv_shutdown, executor_tick);
-                    executor_tick.set(connection.executor().tick().fuse());
-
-                    loop {
-                        futures::select! {
-                            _ = executor_tick => executor_tick.set(connection.executor().tick().fuse()),
-                            _ = recv_shutdown => {
-                                send_done.send(()).ok();
-                                break;
-                            },
-                            ret = dbus_options.event_monitor_rx.recv_async() => {
-                                if let Ok(event) = ret {
-                                    DBusApi::event(iface_ref.signal_context(), event).await.ok();
-                                }
-                            }
+                    match server {
+                        DBusServer::Bus {
+                            connection,
+                            iface_ref,
+                            service_name,
+                            system_bus,
+                        } => {
+                            serve_bus(
+                                connection,
+                                iface_ref,
+                                service_name,
+                                system_bus,
+                                object_path,
+                                recv_shutdown,
+                                event_monitor_rx,
+                            )
+                            .await
+                        }
+                        DBusServer::Peer {
+                            listener,
+                            api_notifier,
+                            api_sender,
+                        } => {
+                            serve_peers(
+                                listener,
+                                object_path,
+                                api_notifier,
+                                api_sender,
+                                recv_shutdown,
+                                event_monitor_rx,
+                            )
+                            .await
                         }
                     }
+                    send_done.send(()).ok();
                 })
             }))
             .map_err(|_| {
@@ -394,3 +1798,28 @@ pub fn start_dbus_thread(
 
     Ok((thread_join_handle, (send_shutdown, recv_done)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_path() {
+        let mut vm_path = VmPath::new("/org/cloudhypervisor/DBusApi");
+        assert_eq!(vm_path.update(false), (None, None));
+
+        let vm0 = "/org/cloudhypervisor/DBusApi/vm0".to_string();
+        assert_eq!(vm_path.update(true), (None, Some(vm0.clone())));
+        assert_eq!(vm_path.update(true), (None, None));
+        assert_eq!(vm_path.current, Some(vm0.clone()));
+        assert_eq!(vm_path.update(false), (Some(vm0), None));
+        assert_eq!(vm_path.current, None);
+
+        // Paths aren't reused by the next VMs
+        let vm1 = "/org/cloudhypervisor/DBusApi/vm1".to_string();
+        assert_eq!(vm_path.update(true), (None, Some(vm1)));
+
+        let mut vm_path = VmPath::new("/");
+        assert_eq!(vm_path.update(true), (None, Some("/vm0".to_string())));
+    }
+}
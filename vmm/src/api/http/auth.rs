@@ -0,0 +1,111 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Bearer token authentication, the requests having to carry the token given
+//! at startup in their `Authorization` header when one is configured.
+
+use micro_http::Request;
+use once_cell::sync::OnceCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const AUTHORIZATION_HEADER: &str = "Authorization";
+const BEARER_SCHEME: &str = "Bearer";
+
+static API_TOKEN: OnceCell<Vec<u8>> = OnceCell::new();
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Cannot read the token file.
+    #[error("Error reading the API token file {0}: {1}")]
+    ReadToken(PathBuf, #[source] io::Error),
+
+    /// The token file is empty.
+    #[error("No API token found in {0}")]
+    EmptyToken(PathBuf),
+
+    /// The token has already been set.
+    #[error("The API token can only be set once")]
+    TokenAlreadySet,
+}
+
+/// Reads the token from the file at `path`, surrounding whitespaces being
+/// ignored, and requires it from all the requests from then on.
+pub fn set_token_from_file(path: &Path) -> Result<(), Error> {
+    let content = fs::read_to_string(path).map_err(|e| Error::ReadToken(path.to_owned(), e))?;
+    let token = content.trim();
+    if token.is_empty() {
+        return Err(Error::EmptyToken(path.to_owned()));
+    }
+
+    API_TOKEN
+        .set(token.as_bytes().to_vec())
+        .map_err(|_| Error::TokenAlreadySet)
+}
+
+// Compares the tokens in a time only depending on their length, so that the
+// response time doesn't tell how many of the first bytes were correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_authorized(request: &Request, token: &[u8]) -> bool {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(AUTHORIZATION_HEADER))
+        .and_then(|(_, value)| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case(BEARER_SCHEME))
+        .is_some_and(|(_, credentials)| constant_time_eq(credentials.trim().as_bytes(), token))
+}
+
+/// Returns whether the request carries the API token, if any is required.
+pub fn authorized(request: &Request) -> bool {
+    match API_TOKEN.get() {
+        Some(token) => is_authorized(request, token),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let header = authorization
+            .map(|value| format!("Authorization: {value}\r\n"))
+            .unwrap_or_default();
+        let raw = format!("GET /api/v1/vmm.ping HTTP/1.1\r\n{header}\r\n");
+        Request::try_from(raw.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret0"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let token = b"s3cr3t";
+
+        assert!(is_authorized(&request(Some("Bearer s3cr3t")), token));
+        assert!(is_authorized(&request(Some("bearer s3cr3t")), token));
+        assert!(!is_authorized(&request(None), token));
+        assert!(!is_authorized(&request(Some("Bearer wrong")), token));
+        assert!(!is_authorized(&request(Some("Bearer s3cr3")), token));
+        assert!(!is_authorized(&request(Some("Basic s3cr3t")), token));
+        assert!(!is_authorized(&request(Some("s3cr3t")), token));
+    }
+}
@@ -0,0 +1,483 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! HTTP API served on listening sockets micro_http can't accept connections
+//! from, or whose peers must be known, a single request being handled per
+//! connection.
+
+use super::{
+    auth, error_response, events, handle_http_request, HttpApiHandle, HttpError, TOO_MANY_REQUESTS,
+};
+use crate::api::audit::{self, Peer};
+use crate::api::ApiRequest;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::seccomp_violations;
+use crate::{Error as VmmError, Result};
+use hypervisor::HypervisorType;
+use micro_http::{Request, Response, StatusCode};
+use seccompiler::{BpfProgram, SeccompAction};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+// Largest request accepted, way above the size of the API JSON bodies
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+// Connections served at once, by as many threads started along with the
+// listener, the others being closed until one of them completes
+const MAX_CONNECTIONS: usize = 16;
+/// Time given to a client to send its request, the TLS handshake included
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Socket the clients of the HTTP API connect to
+pub trait Listener: AsRawFd + Send + 'static {
+    /// Connection accepted from the socket
+    type Connection: Connection;
+
+    /// Accepts a connection, without reading anything from the peer
+    fn accept_connection(&self) -> io::Result<Self::Connection>;
+}
+
+/// Connection of a client of the HTTP API, served by one of the client threads
+pub trait Connection: Send + 'static {
+    /// Serves the request of the connection through `serve_request()`
+    fn serve(self, api_notifier: &EventFd, api_sender: &Sender<ApiRequest>) -> io::Result<()>;
+}
+
+/// Stream of a connection, whose reads are bounded in time
+pub trait RequestStream: Read {
+    /// Sets the time after which a read fails
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl RequestStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+// Reads the header of a request and its body, whose size is given by the
+// Content-Length header, failing if the request isn't complete by `deadline`.
+fn read_request<R: RequestStream>(stream: &mut R, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut expected_len = None;
+
+    loop {
+        if let Some(len) = expected_len {
+            if request.len() >= len {
+                request.truncate(len);
+                return Ok(request);
+            }
+        }
+
+        // A client sending its request bit by bit doesn't get more time
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ));
+        }
+        stream.set_read_timeout(remaining)?;
+        let count = stream.read(&mut buf)?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        request.extend_from_slice(&buf[..count]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+
+        if expected_len.is_none() {
+            let Some(header_len) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let header = String::from_utf8_lossy(&request[..header_len]);
+            let body_len = header
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .map(|(_, value)| value.trim().parse::<usize>())
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .unwrap_or(0);
+            // Reject the oversized bodies before they are read, without
+            // letting the announced length overflow the expected one
+            if body_len > MAX_REQUEST_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request too large",
+                ));
+            }
+            let len = (header_len + 4)
+                .checked_add(body_len)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "request too large"))?;
+            expected_len = Some(len);
+        }
+    }
+}
+
+// Writes `response` with the status `status`, micro_http having no code for
+// the requests turned down by the limits.
+fn write_response<W: Write>(response: &Response, status: u16, stream: &mut W) -> io::Result<()> {
+    if status != TOO_MANY_REQUESTS {
+        return response.write_all(stream).map_err(io::Error::from);
+    }
+
+    let mut buf = Vec::new();
+    response.write_all(&mut buf).map_err(io::Error::from)?;
+    let status_line_len = buf
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    stream.write_all(b"HTTP/1.1 429 Too Many Requests")?;
+    stream.write_all(&buf[status_line_len..])
+}
+
+/// Reads a request from `stream` and writes the response back, returning
+/// `stream` unless it got handed over to stream the events.
+pub fn serve_request<S: RequestStream + Write + Send + 'static>(
+    mut stream: S,
+    peer: &Peer,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<Option<S>> {
+    let request = read_request(&mut stream, Instant::now() + CLIENT_TIMEOUT)?;
+    let (response, status) = match Request::try_from(&request, Some(MAX_REQUEST_SIZE)) {
+        Ok(request)
+            if events::is_events_request(&request)
+                && events::enabled()
+                && auth::authorized(&request) =>
+        {
+            if !events::client_allowed() {
+                audit::record_http(peer, "GET", events::EVENTS_URI, 503);
+                let response = error_response(
+                    HttpError::TooManyEventClients,
+                    StatusCode::ServiceUnavailable,
+                );
+                (response, 503)
+            } else {
+                audit::record_http(peer, "GET", events::EVENTS_URI, 200);
+                events::add_client(stream)?;
+                return Ok(None);
+            }
+        }
+        Ok(request) => handle_http_request(&request, peer, api_notifier, api_sender),
+        Err(e) => {
+            warn!("Invalid HTTP request: {e:?}");
+            let response = error_response(HttpError::BadRequest, StatusCode::BadRequest);
+            (response, 400)
+        }
+    };
+
+    write_response(&response, status, &mut stream)?;
+    stream.flush()?;
+    Ok(Some(stream))
+}
+
+pub struct UnixConnection {
+    stream: UnixStream,
+    peer: Peer,
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixConnection;
+
+    fn accept_connection(&self) -> io::Result<UnixConnection> {
+        let (stream, _) = self.accept()?;
+        let peer = Peer::from_unix_socket(&stream);
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(UnixConnection { stream, peer })
+    }
+}
+
+impl Connection for UnixConnection {
+    fn serve(self, api_notifier: &EventFd, api_sender: &Sender<ApiRequest>) -> io::Result<()> {
+        // A single request is served per connection
+        serve_request(self.stream, &self.peer, api_notifier, api_sender).map(|_| ())
+    }
+}
+
+// Releases the slot of a connection once a client thread is done with it
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Hands a connection over to the client threads, a client slow to complete
+// the TLS handshake or to send its request not holding back the others.
+fn dispatch_connection<C: Connection>(
+    connection: C,
+    connections: &Arc<AtomicUsize>,
+    clients: &Sender<(C, ConnectionSlot)>,
+) -> io::Result<()> {
+    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+        connections.fetch_sub(1, Ordering::SeqCst);
+        // Dropping the connection closes it
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "too many connections",
+        ));
+    }
+    let slot = ConnectionSlot(connections.clone());
+
+    clients
+        .send((connection, slot))
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+}
+
+fn serve<L: Listener>(
+    listener: L,
+    shutdown_fd: EventFd,
+    clients: Sender<(L::Connection, ConnectionSlot)>,
+) -> io::Result<()> {
+    const EPOLL_EVENT_SHUTDOWN: u64 = 0;
+    const EPOLL_EVENT_LISTENER: u64 = 1;
+
+    let epoll_fd = epoll::create(true)?;
+    // Use 'File' to enforce closing on 'epoll_fd'
+    // SAFETY: epoll_fd is valid
+    let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    for (fd, data) in [
+        (shutdown_fd.as_raw_fd(), EPOLL_EVENT_SHUTDOWN),
+        (listener.as_raw_fd(), EPOLL_EVENT_LISTENER),
+    ] {
+        epoll::ctl(
+            epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, data),
+        )?;
+    }
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mut events = [epoll::Event::new(epoll::Events::empty(), 0); 2];
+    loop {
+        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            Ok(res) => res,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            if event.data == EPOLL_EVENT_SHUTDOWN {
+                return Ok(());
+            }
+
+            if let Err(e) = listener
+                .accept_connection()
+                .and_then(|connection| dispatch_connection(connection, &connections, &clients))
+            {
+                warn!("HTTP API connection error: {e}");
+            }
+        }
+    }
+}
+
+// Starts the threads serving the connections, which exit once the listener
+// thread is gone. They are started along with it, as the filtered threads
+// aren't allowed to create threads.
+fn start_client_threads<C: Connection>(
+    clients: Receiver<(C, ConnectionSlot)>,
+    api_notifier: EventFd,
+    api_sender: &Sender<ApiRequest>,
+    seccomp_filter: &BpfProgram,
+) -> Result<()> {
+    let clients = Arc::new(Mutex::new(clients));
+    let api_notifier = Arc::new(api_notifier);
+
+    for _ in 0..MAX_CONNECTIONS {
+        let clients = clients.clone();
+        let api_notifier = api_notifier.clone();
+        let api_sender = api_sender.clone();
+        let seccomp_filter = seccomp_filter.clone();
+
+        thread::Builder::new()
+            .name("http-client".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = seccomp_violations::apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+
+                loop {
+                    // The lock is only held while waiting for a connection
+                    let Ok((connection, _slot)) = clients.lock().unwrap().recv() else {
+                        return;
+                    };
+                    match std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        connection.serve(&api_notifier, &api_sender)
+                    })) {
+                        Ok(Err(e)) => warn!("HTTP API connection error: {e}"),
+                        Err(_) => error!("http-client thread panicked"),
+                        Ok(Ok(())) => {}
+                    }
+                }
+            })
+            .map_err(VmmError::HttpThreadSpawn)?;
+    }
+
+    Ok(())
+}
+
+pub fn start_listener_thread<L: Listener>(
+    listener: L,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+    exit_evt: EventFd,
+    hypervisor_type: HypervisorType,
+) -> Result<HttpApiHandle> {
+    // Retrieve seccomp filter for API thread
+    let api_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::HttpApi, hypervisor_type)
+        .map_err(VmmError::CreateSeccompFilter)?;
+
+    let api_shutdown_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(VmmError::EventFdCreate)?;
+    let shutdown_fd = api_shutdown_fd
+        .try_clone()
+        .map_err(VmmError::EventFdClone)?;
+
+    let (clients, receiver) = channel::<(L::Connection, ConnectionSlot)>();
+    start_client_threads(receiver, api_notifier, &api_sender, &api_seccomp_filter)?;
+
+    let thread = thread::Builder::new()
+        .name("http-server".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for API thread.
+            if !api_seccomp_filter.is_empty() {
+                seccomp_violations::apply_filter(&api_seccomp_filter)
+                    .map_err(VmmError::ApplySeccompFilter)
+                    .map_err(|e| {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        e
+                    })?;
+            }
+
+            std::panic::catch_unwind(AssertUnwindSafe(move || {
+                if let Err(e) = serve(listener, shutdown_fd, clients) {
+                    error!("HTTP API server error: {e}");
+                }
+            }))
+            .map_err(|_| {
+                error!("http-server thread panicked");
+                exit_evt.write(1).ok()
+            })
+            .ok();
+
+            Ok(())
+        })
+        .map_err(VmmError::HttpThreadSpawn)?;
+
+    Ok((thread, api_shutdown_fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    impl<T: AsRef<[u8]>> RequestStream for Cursor<T> {
+        fn set_read_timeout(&self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_request<R: RequestStream>(stream: &mut R) -> io::Result<Vec<u8>> {
+        super::read_request(stream, Instant::now() + CLIENT_TIMEOUT)
+    }
+
+    #[test]
+    fn test_write_response() {
+        let response = error_response(HttpError::RateLimited, StatusCode::ServiceUnavailable);
+        let mut written = Vec::new();
+        write_response(&response, TOO_MANY_REQUESTS, &mut written).unwrap();
+        assert!(written.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+
+        let mut expected = Vec::new();
+        response.write_all(&mut expected).unwrap();
+        let mut written = Vec::new();
+        write_response(&response, 503, &mut written).unwrap();
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = b"PUT /api/v1/vm.pause HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap(),
+            request.to_vec()
+        );
+
+        let request =
+            b"PUT /api/v1/vm.resize HTTP/1.1\r\ncontent-length: 20\r\n\r\n{\"desired_vcpus\": 2}";
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap(),
+            request.to_vec()
+        );
+
+        // Truncated body
+        let request = b"PUT /api/v1/vm.resize HTTP/1.1\r\nContent-Length: 20\r\n\r\n{}";
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let request = b"PUT /api/v1/vm.resize HTTP/1.1\r\nContent-Length: foo\r\n\r\n";
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Oversized and overflowing bodies are rejected before being read
+        let request = b"PUT /api/v1/vm.resize HTTP/1.1\r\nContent-Length: 1073741824\r\n\r\n";
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let request = format!(
+            "PUT /api/v1/vm.resize HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        assert_eq!(
+            read_request(&mut Cursor::new(request.as_bytes()))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let request = vec![b'a'; MAX_REQUEST_SIZE + 1];
+        assert_eq!(
+            read_request(&mut Cursor::new(request)).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // The request must be complete by the deadline
+        let request = b"PUT /api/v1/vm.pause HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            super::read_request(&mut Cursor::new(request), Instant::now())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Events of the VMM streamed to the HTTP API clients as server-sent events,
+//! on the sockets serving a single request per connection.
+
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{Error as VmmError, Result};
+use hypervisor::HypervisorType;
+use micro_http::{Method, Request};
+use once_cell::sync::OnceCell;
+use seccompiler::{apply_filter, SeccompAction};
+use std::io::{self, Write};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+pub const EVENTS_URI: &str = "/api/v1/vm.events";
+
+// The connection is closed by the VMM when the client is gone, hence no
+// length being given for the body.
+const RESPONSE_HEADER: &[u8] = b"HTTP/1.1 200 OK\r\n\
+    Server: Cloud Hypervisor API\r\n\
+    Content-Type: text/event-stream\r\n\
+    Cache-Control: no-cache\r\n\
+    Connection: close\r\n\r\n";
+
+// Clients the events are streamed to at once, each of them taking one of the
+// threads serving the connections until handed over
+const MAX_CLIENTS: usize = 8;
+
+type Client = Box<dyn Write + Send>;
+
+static EVENT_CLIENTS: OnceCell<flume::Sender<Client>> = OnceCell::new();
+// Number of clients the events are streamed to
+static CLIENTS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+enum Message {
+    Client(Client),
+    Event(Arc<String>),
+}
+
+/// Returns whether the events are requested.
+pub fn is_events_request(request: &Request) -> bool {
+    matches!(request.method(), Method::Get) && request.uri().get_abs_path() == EVENTS_URI
+}
+
+/// Returns whether the events can be streamed.
+pub fn enabled() -> bool {
+    EVENT_CLIENTS.get().is_some()
+}
+
+/// Returns whether another client can be added through `add_client()`.
+pub fn client_allowed() -> bool {
+    CLIENTS_COUNT.load(Ordering::SeqCst) < MAX_CLIENTS
+}
+
+/// Writes the header of the response to `stream` and hands it over to the
+/// thread streaming the events.
+pub fn add_client<S: Write + Send + 'static>(mut stream: S) -> io::Result<()> {
+    let Some(clients) = EVENT_CLIENTS.get() else {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    };
+
+    if CLIENTS_COUNT.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+        CLIENTS_COUNT.fetch_sub(1, Ordering::SeqCst);
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "too many clients of the events",
+        ));
+    }
+    // Released by the thread streaming the events once the client is gone
+    let added = stream
+        .write_all(RESPONSE_HEADER)
+        .and_then(|_| stream.flush())
+        .and_then(|_| {
+            clients
+                .send(Box::new(stream))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        });
+    if added.is_err() {
+        CLIENTS_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+    added
+}
+
+// Each line of the pretty-printed JSON event goes into a data field, the
+// client joining them back.
+fn format_event(event: &str) -> String {
+    let mut message: String = event
+        .lines()
+        .map(|line| format!("data: {line}\n"))
+        .collect();
+    message.push('\n');
+    message
+}
+
+fn stream_events(events: flume::Receiver<Arc<String>>, new_clients: flume::Receiver<Client>) {
+    let mut clients = Vec::new();
+
+    loop {
+        let message = flume::Selector::new()
+            .recv(&new_clients, |client| client.ok().map(Message::Client))
+            .recv(&events, |event| event.ok().map(Message::Event))
+            .wait();
+
+        match message {
+            Some(Message::Client(client)) => clients.push(client),
+            Some(Message::Event(event)) => {
+                let message = format_event(&event);
+                // The clients which went away are only noticed when writing
+                clients.retain_mut(|client| {
+                    let alive = client
+                        .write_all(message.as_bytes())
+                        .and_then(|_| client.flush())
+                        .is_ok();
+                    if !alive {
+                        CLIENTS_COUNT.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    alive
+                });
+            }
+            None => return,
+        }
+    }
+}
+
+/// Starts the thread streaming the events received from `events` to the
+/// clients added through `add_client()`.
+pub fn start_http_events_thread(
+    events: flume::Receiver<Arc<String>>,
+    seccomp_action: &SeccompAction,
+    exit_evt: EventFd,
+    hypervisor_type: HypervisorType,
+) -> Result<()> {
+    // Retrieve seccomp filter for API thread
+    let api_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::HttpApi, hypervisor_type)
+        .map_err(VmmError::CreateSeccompFilter)?;
+
+    let (clients_tx, clients_rx) = flume::unbounded();
+
+    thread::Builder::new()
+        .name("http-events".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for API thread.
+            if !api_seccomp_filter.is_empty() {
+                apply_filter(&api_seccomp_filter)
+                    .map_err(VmmError::ApplySeccompFilter)
+                    .map_err(|e| {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        e
+                    })?;
+            }
+
+            std::panic::catch_unwind(AssertUnwindSafe(move || {
+                stream_events(events, clients_rx);
+            }))
+            .map_err(|_| {
+                error!("http-events thread panicked");
+                exit_evt.write(1).ok()
+            })
+            .ok();
+
+            Ok(())
+        })
+        .map_err(VmmError::HttpThreadSpawn)?;
+
+    // Only started once, along with the VMM thread
+    EVENT_CLIENTS.set(clients_tx).ok();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_events_request() {
+        let request = Request::try_from(b"GET /api/v1/vm.events HTTP/1.1\r\n\r\n", None).unwrap();
+        assert!(is_events_request(&request));
+
+        let request = Request::try_from(b"PUT /api/v1/vm.events HTTP/1.1\r\n\r\n", None).unwrap();
+        assert!(!is_events_request(&request));
+
+        let request = Request::try_from(b"GET /api/v1/vm.info HTTP/1.1\r\n\r\n", None).unwrap();
+        assert!(!is_events_request(&request));
+    }
+
+    #[test]
+    fn test_format_event() {
+        assert_eq!(
+            format_event("{\n  \"source\": \"vm\",\n  \"event\": \"booted\"\n}"),
+            "data: {\ndata:   \"source\": \"vm\",\ndata:   \"event\": \"booted\"\ndata: }\n\n"
+        );
+    }
+}
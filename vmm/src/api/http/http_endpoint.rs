@@ -7,13 +7,21 @@
 use crate::api::http::{error_response, EndpointHandler, HttpError};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
+#[cfg(feature = "introspection")]
+use crate::api::VmIntrospect;
+use crate::api::VmSnapshotConfig;
+#[cfg(feature = "pprof")]
+use crate::api::VmmPprof;
 use crate::api::{
-    AddDisk, ApiAction, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmConfig, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton,
-    VmReboot, VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    AddDisk, ApiAction, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUsb,
+    VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot, VmCapabilities, VmConfig, VmCounters, VmDelete,
+    VmDeviceTree, VmDevices, VmDumpMemory, VmMigrationBlockers, VmNmi, VmPause, VmPowerButton,
+    VmReboot, VmReceiveMigration, VmRekey, VmRemoveDevice, VmReplaceDevice, VmResize, VmResizeZone,
+    VmRestore, VmResume, VmScreenshot, VmSendMigration, VmSetBootParams, VmShutdown, VmSnapshot,
+    VmSuspendToRam, VmSysRq, VmUpdateConfig, VmVcpuStats, VmmCapabilities, VmmCheckpoint, VmmFds,
+    VmmHostInfo, VmmPostRestore, VmmProfile, VmmThreads,
 };
-use crate::config::NetConfig;
+use crate::config::{DiskConfig, NetConfig, RestoreConfig};
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::fs::File;
 use std::os::unix::io::IntoRawFd;
@@ -52,6 +60,15 @@ impl EndpointHandler for VmCreate {
                             }
                         }
 
+                        if let Some(ref mut disks) = vm_config.disks {
+                            if disks.iter().any(|disk| disk.fd.is_some()) {
+                                warn!("Ignoring FDs sent via the HTTP request body");
+                            }
+                            for disk in disks {
+                                disk.fd = None;
+                            }
+                        }
+
                         match crate::api::VmCreate
                             .send(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
                             .map_err(HttpError::ApiError)
@@ -61,7 +78,7 @@ impl EndpointHandler for VmCreate {
                         }
                     }
 
-                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                    None => error_response(HttpError::BadRequest, StatusCode::BadRequest),
                 }
             }
 
@@ -164,34 +181,77 @@ macro_rules! vm_action_put_handler_body {
 }
 
 vm_action_get_handler!(VmCounters);
+vm_action_get_handler!(VmVcpuStats);
+vm_action_get_handler!(VmMigrationBlockers);
+vm_action_get_handler!(VmCapabilities);
+vm_action_get_handler!(VmDeviceTree);
+vm_action_get_handler!(VmDevices);
+vm_action_get_handler!(VmmThreads);
+vm_action_get_handler!(VmmHostInfo);
+vm_action_get_handler!(VmmCapabilities);
+vm_action_get_handler!(VmmFds);
+vm_action_get_handler!(VmmProfile);
 
-vm_action_put_handler!(VmBoot);
 vm_action_put_handler!(VmDelete);
 vm_action_put_handler!(VmShutdown);
 vm_action_put_handler!(VmReboot);
 vm_action_put_handler!(VmPause);
 vm_action_put_handler!(VmResume);
 vm_action_put_handler!(VmPowerButton);
+vm_action_put_handler!(VmSuspendToRam);
 vm_action_put_handler!(VmNmi);
+vm_action_put_handler!(VmmPostRestore);
 
 vm_action_put_handler_body!(VmAddDevice);
-vm_action_put_handler_body!(AddDisk);
 vm_action_put_handler_body!(VmAddFs);
 vm_action_put_handler_body!(VmAddPmem);
+vm_action_put_handler_body!(VmAddUsb);
 vm_action_put_handler_body!(VmAddVdpa);
 vm_action_put_handler_body!(VmAddVsock);
 vm_action_put_handler_body!(VmAddUserDevice);
 vm_action_put_handler_body!(VmRemoveDevice);
+vm_action_put_handler_body!(VmReplaceDevice);
+vm_action_put_handler_body!(VmSysRq);
+vm_action_put_handler_body!(VmScreenshot);
+vm_action_put_handler_body!(VmDumpMemory);
 vm_action_put_handler_body!(VmResize);
 vm_action_put_handler_body!(VmResizeZone);
-vm_action_put_handler_body!(VmRestore);
-vm_action_put_handler_body!(VmSnapshot);
+vm_action_put_handler_body!(VmSetBootParams);
+vm_action_put_handler_body!(VmRekey);
+vm_action_put_handler_body!(VmUpdateConfig);
 vm_action_put_handler_body!(VmReceiveMigration);
 vm_action_put_handler_body!(VmSendMigration);
+vm_action_put_handler_body!(VmmCheckpoint);
 
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 vm_action_put_handler_body!(VmCoredump);
 
+#[cfg(feature = "introspection")]
+vm_action_put_handler_body!(VmIntrospect);
+
+#[cfg(feature = "pprof")]
+vm_action_put_handler_body!(VmmPprof);
+
+// The identity of a ready VM is optionally given on boot.
+impl PutHandler for VmBoot {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        _files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let identity = body
+            .as_ref()
+            .map(|body| serde_json::from_slice(body.raw()))
+            .transpose()?;
+        self.send(api_notifier, api_sender, identity)
+            .map_err(HttpError::ApiError)
+    }
+}
+
+impl GetHandler for VmBoot {}
+
 impl PutHandler for VmAddNet {
     fn handle_request(
         &'static self,
@@ -220,6 +280,116 @@ impl PutHandler for VmAddNet {
 
 impl GetHandler for VmAddNet {}
 
+impl PutHandler for AddDisk {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        mut files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let mut disk_cfg: DiskConfig = serde_json::from_slice(body.raw())?;
+            if disk_cfg.fd.is_some() {
+                warn!("Ignoring FD sent via the HTTP request body");
+                disk_cfg.fd = None;
+            }
+            if files.len() > 1 {
+                return Err(HttpError::BadRequest);
+            }
+            if let Some(file) = files.pop() {
+                disk_cfg.fd = Some(file.into_raw_fd());
+            }
+            self.send(api_notifier, api_sender, disk_cfg)
+                .map_err(HttpError::ApiError)
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for AddDisk {}
+
+// The key of an encrypted snapshot is passed along with the request, the
+// file descriptor from the body being meaningless to the VMM.
+// The file descriptor of a snapshot streamed through `fd:` comes first with
+// the request, followed by the one of the key, if any. The URL is updated to
+// refer to the received file descriptor.
+fn snapshot_fds(
+    url: &mut String,
+    body_fd: Option<i32>,
+    files: Vec<File>,
+) -> Result<Option<i32>, HttpError> {
+    if body_fd.is_some() {
+        warn!("Ignoring FD sent via the HTTP request body");
+    }
+    let mut files = files.into_iter();
+    if url.starts_with("fd:") {
+        let file = files.next().ok_or(HttpError::BadRequest)?;
+        *url = format!("fd:{}", file.into_raw_fd());
+    }
+    let key_fd = files.next().map(|file| file.into_raw_fd());
+    if files.next().is_some() {
+        return Err(HttpError::BadRequest);
+    }
+    Ok(key_fd)
+}
+
+/// Configuration of the snapshot, along with the stream and the key passed
+/// with the request.
+pub fn snapshot_config(
+    body: &Option<Body>,
+    files: Vec<File>,
+) -> std::result::Result<VmSnapshotConfig, HttpError> {
+    let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+    let mut snapshot_cfg: VmSnapshotConfig = serde_json::from_slice(body.raw())?;
+    snapshot_cfg.key_fd = snapshot_fds(
+        &mut snapshot_cfg.destination_url,
+        snapshot_cfg.key_fd,
+        files,
+    )?;
+    Ok(snapshot_cfg)
+}
+
+impl PutHandler for VmSnapshot {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let snapshot_cfg = snapshot_config(body, files)?;
+        self.send(api_notifier, api_sender, snapshot_cfg)
+            .map_err(HttpError::ApiError)
+    }
+}
+
+impl GetHandler for VmSnapshot {}
+
+impl PutHandler for VmRestore {
+    fn handle_request(
+        &'static self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+        files: Vec<File>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        if let Some(body) = body {
+            let mut restore_cfg: RestoreConfig = serde_json::from_slice(body.raw())?;
+            let mut source_url = restore_cfg.source_url.to_string_lossy().into_owned();
+            restore_cfg.key_fd = snapshot_fds(&mut source_url, restore_cfg.key_fd, files)?;
+            restore_cfg.source_url = source_url.into();
+            self.send(api_notifier, api_sender, restore_cfg)
+                .map_err(HttpError::ApiError)
+        } else {
+            Err(HttpError::BadRequest)
+        }
+    }
+}
+
+impl GetHandler for VmRestore {}
+
 // Common handler for boot, shutdown and reboot
 pub struct VmActionHandler {
     action: &'static dyn HttpVmAction,
@@ -0,0 +1,269 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Replay cache for the mutating requests carrying an `Idempotency-Key`
+//! header, so that a request retried after a client side timeout returns the
+//! response of the first attempt instead of being executed twice (e.g. a
+//! hotplug creating a duplicate device).
+
+use super::{error_response, HttpError};
+use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Condvar, Mutex};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const MAX_KEY_LEN: usize = 255;
+// Number of responses kept around, the oldest ones being evicted first.
+const CACHE_CAPACITY: usize = 128;
+
+struct CachedResponse {
+    // Identifies the request the key has been first used with
+    fingerprint: u64,
+    status: StatusCode,
+    body: Option<Body>,
+}
+
+#[derive(Default)]
+struct Entries {
+    responses: HashMap<String, CachedResponse>,
+    keys: VecDeque<String>,
+    // Fingerprints of the requests being run, by key
+    in_flight: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: Mutex<Entries>,
+    // Notified whenever a request in flight completes
+    completed: Condvar,
+}
+
+// Withdraws the key of a request from the requests in flight once it
+// completes, however it does, waking up the retries waiting for it.
+struct InFlight<'a> {
+    cache: &'a IdempotencyCache,
+    key: &'a str,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut entries) = self.cache.entries.lock() {
+            entries.in_flight.remove(self.key);
+        }
+        self.cache.completed.notify_all();
+    }
+}
+
+/// Returns the idempotency key of a mutating request, if any.
+pub fn idempotency_key(request: &Request) -> Result<Option<String>, HttpError> {
+    if request.method() != Method::Put {
+        return Ok(None);
+    }
+
+    let key = request
+        .headers
+        .custom_entries()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(IDEMPOTENCY_KEY_HEADER))
+        .map(|(_, key)| key);
+
+    match key {
+        Some(key)
+            if key.is_empty()
+                || key.len() > MAX_KEY_LEN
+                || !key.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            Err(HttpError::InvalidIdempotencyKey)
+        }
+        key => Ok(key.cloned()),
+    }
+}
+
+fn fingerprint(path: &str, body: Option<&Body>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    body.map(|b| b.raw()).hash(&mut hasher);
+    hasher.finish()
+}
+
+impl IdempotencyCache {
+    /// Replays the response previously returned for `key`, or runs `handler`
+    /// and records its response when successful. Failed requests aren't
+    /// recorded, so that they can be retried with the same key. The cache is
+    /// only locked while looking the key up and recording the response, a
+    /// retry sent while the first attempt is still running waiting for it to
+    /// complete.
+    pub fn handle<F>(&self, key: &str, path: &str, request: &Request, handler: F) -> Response
+    where
+        F: FnOnce() -> Response,
+    {
+        let fingerprint = fingerprint(path, request.body.as_ref());
+
+        let mut entries = self.entries.lock().unwrap();
+        loop {
+            if let Some(cached) = entries.responses.get(key) {
+                if cached.fingerprint != fingerprint {
+                    return error_response(HttpError::IdempotencyKeyReused, StatusCode::BadRequest);
+                }
+
+                info!("Replaying the response of request {} on {}", key, path);
+                let mut response = Response::new(Version::Http11, cached.status);
+                if let Some(body) = &cached.body {
+                    response.set_body(body.clone());
+                }
+                return response;
+            }
+
+            match entries.in_flight.get(key) {
+                Some(in_flight) if *in_flight != fingerprint => {
+                    return error_response(HttpError::IdempotencyKeyReused, StatusCode::BadRequest);
+                }
+                Some(_) => entries = self.completed.wait(entries).unwrap(),
+                None => break,
+            }
+        }
+        entries.in_flight.insert(key.to_owned(), fingerprint);
+        drop(entries);
+
+        let _in_flight = InFlight { cache: self, key };
+        let response = handler();
+        if matches!(response.status(), StatusCode::OK | StatusCode::NoContent) {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.keys.len() == CACHE_CAPACITY {
+                if let Some(oldest) = entries.keys.pop_front() {
+                    entries.responses.remove(&oldest);
+                }
+            }
+            entries.keys.push_back(key.to_owned());
+            entries.responses.insert(
+                key.to_owned(),
+                CachedResponse {
+                    fingerprint,
+                    status: response.status(),
+                    body: response.body(),
+                },
+            );
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ApiErrorCode, ApiErrorResponse};
+
+    fn request(key: &str, path: &str, body: &str) -> Request {
+        let raw = format!(
+            "PUT {path} HTTP/1.1\r\n{IDEMPOTENCY_KEY_HEADER}: {key}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        Request::try_from(raw.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let req = request("a1b2", "/api/v1/vm.add-net", "{}");
+        assert_eq!(idempotency_key(&req).unwrap(), Some("a1b2".to_owned()));
+
+        let req = request(&"k".repeat(MAX_KEY_LEN + 1), "/api/v1/vm.add-net", "{}");
+        assert!(idempotency_key(&req).is_err());
+
+        let req = Request::try_from(b"GET /api/v1/vm.info HTTP/1.1\r\n\r\n", None).unwrap();
+        assert_eq!(idempotency_key(&req).unwrap(), None);
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let cache = IdempotencyCache::default();
+        let mut calls = 0;
+        let mut add_net = |cache: &IdempotencyCache, key: &str, body: &str| {
+            let req = request(key, "/api/v1/vm.add-net", body);
+            cache
+                .handle(key, "/api/v1/vm.add-net", &req, || {
+                    calls += 1;
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    response.set_body(Body::new(format!("{{\"id\":\"_net{calls}\"}}")));
+                    response
+                })
+                .status()
+        };
+
+        assert_eq!(add_net(&cache, "k1", "{\"tap\":\"a\"}"), StatusCode::OK);
+        // The retried request is replayed without being run again
+        assert_eq!(add_net(&cache, "k1", "{\"tap\":\"a\"}"), StatusCode::OK);
+        // The key can't be reused for another request
+        assert_eq!(
+            add_net(&cache, "k1", "{\"tap\":\"b\"}"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(add_net(&cache, "k2", "{\"tap\":\"b\"}"), StatusCode::OK);
+        assert_eq!(calls, 2);
+
+        let req = request("k1", "/api/v1/vm.add-net", "{\"tap\":\"a\"}");
+        let response = cache.handle("k1", "/api/v1/vm.add-net", &req, || unreachable!());
+        assert_eq!(response.body().unwrap().raw(), b"{\"id\":\"_net1\"}");
+
+        let req = request("k1", "/api/v1/vm.add-net", "{\"tap\":\"b\"}");
+        let response = cache.handle("k1", "/api/v1/vm.add-net", &req, || unreachable!());
+        let error: ApiErrorResponse =
+            serde_json::from_slice(response.body().unwrap().raw()).unwrap();
+        assert_eq!(error.code, ApiErrorCode::IdempotencyKeyReused);
+    }
+
+    #[test]
+    fn test_idempotency_cache_failure_not_recorded() {
+        let cache = IdempotencyCache::default();
+        let req = request("k1", "/api/v1/vm.add-disk", "{}");
+
+        let response = cache.handle("k1", "/api/v1/vm.add-disk", &req, || {
+            Response::new(Version::Http11, StatusCode::InternalServerError)
+        });
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+
+        let response = cache.handle("k1", "/api/v1/vm.add-disk", &req, || {
+            Response::new(Version::Http11, StatusCode::NoContent)
+        });
+        assert_eq!(response.status(), StatusCode::NoContent);
+    }
+
+    #[test]
+    fn test_idempotency_cache_retry_in_flight() {
+        let cache = &IdempotencyCache::default();
+        let path = "/api/v1/vm.add-disk";
+        let req = &request("k1", path, "{}");
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        std::thread::scope(|s| {
+            let first = s.spawn(move || {
+                cache
+                    .handle("k1", path, req, || {
+                        started_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                        Response::new(Version::Http11, StatusCode::NoContent)
+                    })
+                    .status()
+            });
+            started_rx.recv().unwrap();
+
+            // The requests with other keys aren't held up
+            let other = request("k2", path, "{}");
+            let response = cache.handle("k2", path, &other, || {
+                Response::new(Version::Http11, StatusCode::OK)
+            });
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // The retry waits for the first attempt, and replays its response
+            let retry = s.spawn(move || cache.handle("k1", path, req, || unreachable!()).status());
+            release_tx.send(()).unwrap();
+            assert_eq!(first.join().unwrap(), StatusCode::NoContent);
+            assert_eq!(retry.join().unwrap(), StatusCode::NoContent);
+        });
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Jobs endpoints, and the long-running requests answered with a job as soon
+//! as they are queued when sent with `Prefer: respond-async`.
+
+use super::http_endpoint::snapshot_config;
+use super::{dup_files, error_response, HttpError};
+use crate::api::{ApiError, ApiRequest, JobRequest};
+use crate::jobs::{JobInfo, Jobs};
+use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+pub const JOBS_PATH: &str = "/api/v1/jobs";
+const PREFER_HEADER: &str = "Prefer";
+const RESPOND_ASYNC: &str = "respond-async";
+
+static JOBS: OnceCell<Arc<Mutex<Jobs>>> = OnceCell::new();
+
+/// Shares the jobs of the VMM with the HTTP API.
+pub fn set_jobs(jobs: Arc<Mutex<Jobs>>) {
+    // Only set once, along with the VMM thread
+    JOBS.set(jobs).ok();
+}
+
+/// Number of jobs queued or running.
+pub fn pending_jobs() -> usize {
+    JOBS.get()
+        .map(|jobs| jobs.lock().unwrap().pending())
+        .unwrap_or_default()
+}
+
+fn respond_async(request: &Request) -> bool {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(PREFER_HEADER))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case(RESPOND_ASYNC))
+}
+
+fn job_response(job: &JobInfo) -> Response {
+    let mut response = Response::new(Version::Http11, StatusCode::OK);
+    response.set_body(Body::new(serde_json::to_string(job).unwrap()));
+    response
+}
+
+fn request_data<T: DeserializeOwned>(body: &Option<Body>) -> Result<T, HttpError> {
+    Ok(serde_json::from_slice(
+        body.as_ref().ok_or(HttpError::BadRequest)?.raw(),
+    )?)
+}
+
+fn queue_job(
+    name: &str,
+    request: JobRequest,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Result<JobInfo, HttpError> {
+    let jobs = JOBS.get().ok_or(HttpError::NotFound)?;
+
+    let job = jobs.lock().unwrap().create(name);
+    let id = job.id;
+    // The VMM thread starts the job when it gets to it, the job running on
+    // its own thread and its outcome being recorded instead of being sent
+    // back.
+    let job_request: ApiRequest = Box::new(move |vmm| {
+        vmm.job_run(id, request);
+        Ok(false)
+    });
+
+    api_sender
+        .send(job_request)
+        .map_err(|e| HttpError::ApiError(ApiError::RequestSend(e)))?;
+    api_notifier
+        .write(1)
+        .map_err(|e| HttpError::ApiError(ApiError::EventFdWrite(e)))?;
+
+    Ok(job)
+}
+
+/// Queues the request as a job when the client asks for it and the endpoint
+/// supports it, returning the response telling the job.
+pub fn handle_async_request(
+    path: &str,
+    request: &Request,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Option<Response> {
+    if !respond_async(request) || !matches!(request.method(), Method::Put) {
+        return None;
+    }
+
+    let body = &request.body;
+    let job = match path {
+        "/api/v1/vm.snapshot" => {
+            // The key passed along is dup()'ed, the request owning the
+            // received file descriptor.
+            dup_files(request)
+                .and_then(|files| snapshot_config(body, files))
+                .and_then(|data| {
+                    queue_job(
+                        "vm.snapshot",
+                        JobRequest::Snapshot(data),
+                        api_notifier,
+                        api_sender,
+                    )
+                })
+        }
+        "/api/v1/vm.send-migration" => request_data(body).and_then(|data| {
+            queue_job(
+                "vm.send-migration",
+                JobRequest::SendMigration(data),
+                api_notifier,
+                api_sender,
+            )
+        }),
+        "/api/v1/vm.dump-memory" => request_data(body).and_then(|data| {
+            queue_job(
+                "vm.dump-memory",
+                JobRequest::DumpMemory(data),
+                api_notifier,
+                api_sender,
+            )
+        }),
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        "/api/v1/vm.coredump" => request_data(body).and_then(|data| {
+            queue_job(
+                "vm.coredump",
+                JobRequest::Coredump(data),
+                api_notifier,
+                api_sender,
+            )
+        }),
+        #[cfg(feature = "pprof")]
+        "/api/v1/vmm.pprof" => request_data(body).and_then(|data| {
+            queue_job(
+                "vmm.pprof",
+                JobRequest::Pprof(data),
+                api_notifier,
+                api_sender,
+            )
+        }),
+        _ => return None,
+    };
+
+    Some(match job {
+        Ok(job) => job_response(&job),
+        Err(e @ (HttpError::BadRequest | HttpError::SerdeJsonDeserialize(_))) => {
+            error_response(e, StatusCode::BadRequest)
+        }
+        Err(e @ HttpError::NotFound) => error_response(e, StatusCode::NotFound),
+        Err(e) => error_response(e, StatusCode::InternalServerError),
+    })
+}
+
+/// Answers `GET /api/v1/jobs`, `GET /api/v1/jobs/{id}` and
+/// `PUT /api/v1/jobs/{id}/cancel`, without involving the VMM thread.
+pub fn handle_jobs_request(path: &str, request: &Request) -> Option<Response> {
+    let path = path.strip_prefix(JOBS_PATH)?;
+    let Some(jobs) = JOBS.get() else {
+        return Some(error_response(HttpError::NotFound, StatusCode::NotFound));
+    };
+    let mut jobs = jobs.lock().unwrap();
+
+    if path.is_empty() {
+        return Some(match request.method() {
+            Method::Get => {
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                response.set_body(Body::new(serde_json::to_string(&jobs.list()).unwrap()));
+                response
+            }
+            _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
+        });
+    }
+
+    let (id, cancel) = match path.strip_suffix("/cancel") {
+        Some(id) => (id, true),
+        None => (path, false),
+    };
+    let id = id.strip_prefix('/')?.parse::<u64>().ok()?;
+
+    Some(match (request.method(), cancel) {
+        (Method::Get, false) => match jobs.get(id) {
+            Some(job) => job_response(job),
+            None => error_response(
+                HttpError::Job(crate::jobs::Error::NotFound(id)),
+                StatusCode::NotFound,
+            ),
+        },
+        (Method::Put, true) => match jobs.cancel(id) {
+            Ok(job) => job_response(&job),
+            Err(e @ crate::jobs::Error::NotFound(_)) => {
+                error_response(HttpError::Job(e), StatusCode::NotFound)
+            }
+            Err(e) => error_response(HttpError::Job(e), StatusCode::BadRequest),
+        },
+        _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond_async() {
+        let request = |prefer: &str| {
+            let raw = format!("PUT /api/v1/vm.snapshot HTTP/1.1\r\n{prefer}\r\n");
+            Request::try_from(raw.as_bytes(), None).unwrap()
+        };
+
+        assert!(respond_async(&request("Prefer: respond-async\r\n")));
+        assert!(respond_async(&request(
+            "prefer: wait=10, respond-async\r\n"
+        )));
+        assert!(!respond_async(&request("Prefer: wait=10\r\n")));
+        assert!(!respond_async(&request("")));
+    }
+}
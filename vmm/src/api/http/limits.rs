@@ -0,0 +1,303 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Limits keeping a misbehaving client from starving the HTTP API thread: a
+//! bound on the requests in flight, and a token bucket per client each
+//! request consumes a token from.
+//!
+//! The requests in flight are the ones being answered, whichever socket they
+//! came from, along with the jobs queued or running. The jobs and metrics
+//! endpoints don't involve the VMM thread and are never turned down for being
+//! in flight.
+//!
+//! The sockets identifying their peers serve a single request per
+//! connection, hence the connections of a same client sharing its bucket:
+//! the process connected to the UNIX socket, the vsock CID or the IP address.
+
+use super::{jobs, metrics, HttpError};
+use crate::api::audit::Peer;
+use once_cell::sync::OnceCell;
+use option_parser::{OptionParser, OptionParserError};
+use rate_limiter::{BucketReduction, TokenBucket};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use virtio_devices::TokenBucketConfig;
+
+// Clients tracked before the idle ones get forgotten, their bucket being
+// full again anyway
+const MAX_CLIENTS: usize = 1024;
+
+static LIMITER: OnceCell<Limiter> = OnceCell::new();
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error parsing --api-limits: {0}")]
+    Parse(OptionParserError),
+
+    #[error("Error parsing --api-limits: max_in_flight must be at least 1")]
+    InvalidMaxInFlight,
+
+    #[error(
+        "Error parsing --api-limits: ops_size and ops_refill_time must both be set and non-zero"
+    )]
+    InvalidRateLimit,
+
+    #[error("The HTTP API limits can only be set once")]
+    LimitsAlreadySet,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpApiLimits {
+    /// Requests being answered and jobs pending beyond which the new requests
+    /// are turned down
+    pub max_in_flight: Option<usize>,
+    /// Bucket the requests of each client consume a token from
+    pub rate_limit: Option<TokenBucketConfig>,
+}
+
+impl HttpApiLimits {
+    pub const SYNTAX: &'static str = "HTTP API limits \
+        \"max_in_flight=<requests>,ops_size=<requests>,\
+        ops_one_time_burst=<requests>,ops_refill_time=<ms>\"";
+
+    pub fn parse(limits: &str) -> Result<Self, Error> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("max_in_flight")
+            .add("ops_size")
+            .add("ops_one_time_burst")
+            .add("ops_refill_time");
+        parser.parse(limits).map_err(Error::Parse)?;
+
+        let max_in_flight = parser
+            .convert::<usize>("max_in_flight")
+            .map_err(Error::Parse)?;
+        if max_in_flight == Some(0) {
+            return Err(Error::InvalidMaxInFlight);
+        }
+        let ops_size = parser.convert("ops_size").map_err(Error::Parse)?;
+        let ops_one_time_burst = parser.convert("ops_one_time_burst").map_err(Error::Parse)?;
+        let ops_refill_time = parser.convert("ops_refill_time").map_err(Error::Parse)?;
+
+        // A partial bucket would silently leave the requests unbounded
+        let rate_limit = match (ops_size, ops_refill_time) {
+            (None, None) if ops_one_time_burst.is_none() => None,
+            (Some(size), Some(refill_time)) if size != 0 && refill_time != 0 => {
+                Some(TokenBucketConfig {
+                    size,
+                    one_time_burst: Some(ops_one_time_burst.unwrap_or_default()),
+                    refill_time,
+                })
+            }
+            _ => return Err(Error::InvalidRateLimit),
+        };
+
+        Ok(HttpApiLimits {
+            max_in_flight,
+            rate_limit,
+        })
+    }
+}
+
+/// Request accounted as in flight until dropped, once answered.
+pub struct InFlight<'a>(Option<&'a AtomicUsize>);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(in_flight) = self.0 {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+struct Limiter {
+    max_in_flight: Option<usize>,
+    // Requests being answered, the pending jobs being accounted apart
+    in_flight: AtomicUsize,
+    // Bucket given to the clients on their first request
+    bucket: Option<TokenBucket>,
+    // Buckets of the clients, along with the time of their last request
+    buckets: Mutex<HashMap<Peer, (TokenBucket, Instant)>>,
+}
+
+// The connections of a TCP client come from different ports.
+fn client(peer: &Peer) -> Peer {
+    match peer {
+        Peer::Tcp { address } => Peer::Tcp {
+            address: address
+                .parse::<SocketAddr>()
+                .map_or_else(|_| address.clone(), |address| address.ip().to_string()),
+        },
+        peer => peer.clone(),
+    }
+}
+
+// Whether the request is answered without involving the VMM thread.
+fn answered_by_api_thread(path: &str) -> bool {
+    path.starts_with(jobs::JOBS_PATH) || path == metrics::METRICS_URI
+}
+
+impl Limiter {
+    fn new(limits: &HttpApiLimits) -> Self {
+        Limiter {
+            max_in_flight: limits.max_in_flight,
+            in_flight: AtomicUsize::new(0),
+            bucket: limits.rate_limit.and_then(|config| {
+                TokenBucket::new(
+                    config.size,
+                    config.one_time_burst.unwrap_or_default(),
+                    config.refill_time,
+                )
+            }),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn consume(&self, template: &TokenBucket, peer: &Peer) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let client = client(peer);
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            let refill_time = Duration::from_millis(template.refill_time_ms());
+            buckets.retain(|_, (_, last_request)| last_request.elapsed() < refill_time);
+        }
+
+        let (bucket, last_request) = buckets
+            .entry(client)
+            .or_insert_with(|| (template.clone(), Instant::now()));
+        *last_request = Instant::now();
+        matches!(bucket.reduce(1), BucketReduction::Success)
+    }
+
+    fn admit(&self, peer: &Peer, path: &str, pending_jobs: usize) -> Result<InFlight, HttpError> {
+        // Released when turned down as well
+        let in_flight = InFlight(Some(&self.in_flight));
+        let answered = self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if let Some(max_in_flight) = self.max_in_flight {
+            if !answered_by_api_thread(path) && answered + pending_jobs >= max_in_flight {
+                return Err(HttpError::TooManyRequestsInFlight);
+            }
+        }
+
+        match &self.bucket {
+            Some(bucket) if !self.consume(bucket, peer) => Err(HttpError::RateLimited),
+            _ => Ok(in_flight),
+        }
+    }
+}
+
+/// Applies `limits` to the requests from then on.
+pub fn set_limits(limits: &HttpApiLimits) -> Result<(), Error> {
+    LIMITER
+        .set(Limiter::new(limits))
+        .map_err(|_| Error::LimitsAlreadySet)
+}
+
+/// Whether the requests are limited, which requires them to be answered
+/// outside of micro_http, lacking the status code of the turned down ones,
+/// and the clients to be identified.
+pub fn enabled() -> bool {
+    LIMITER.get().is_some()
+}
+
+/// Checks whether the request to `path` from `peer` can be served, the
+/// returned value accounting it as in flight until dropped.
+pub fn admit(peer: &Peer, path: &str) -> Result<InFlight<'static>, HttpError> {
+    match LIMITER.get() {
+        Some(limiter) => limiter.admit(peer, path, jobs::pending_jobs()),
+        None => Ok(InFlight(None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(
+            HttpApiLimits::parse("max_in_flight=4,ops_size=10,ops_refill_time=1000").unwrap(),
+            HttpApiLimits {
+                max_in_flight: Some(4),
+                rate_limit: Some(TokenBucketConfig {
+                    size: 10,
+                    one_time_burst: Some(0),
+                    refill_time: 1000,
+                }),
+            }
+        );
+        assert!(matches!(
+            HttpApiLimits::parse("ops_size=10"),
+            Err(Error::InvalidRateLimit)
+        ));
+        assert!(matches!(
+            HttpApiLimits::parse("ops_size=0,ops_refill_time=1000"),
+            Err(Error::InvalidRateLimit)
+        ));
+        assert!(matches!(
+            HttpApiLimits::parse("max_in_flight=0"),
+            Err(Error::InvalidMaxInFlight)
+        ));
+        assert!(matches!(
+            HttpApiLimits::parse("max_requests=4"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let limiter = Limiter::new(&HttpApiLimits {
+            max_in_flight: Some(2),
+            rate_limit: None,
+        });
+
+        assert!(limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 1).is_ok());
+        assert!(matches!(
+            limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 2),
+            Err(HttpError::TooManyRequestsInFlight)
+        ));
+        // The jobs can still be queried
+        assert!(limiter.admit(&Peer::Unknown, "/api/v1/jobs/1", 2).is_ok());
+
+        // The requests being answered are in flight too
+        let first = limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 0).unwrap();
+        let second = limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 0).unwrap();
+        assert!(matches!(
+            limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 0),
+            Err(HttpError::TooManyRequestsInFlight)
+        ));
+        drop((first, second));
+        assert!(limiter.admit(&Peer::Unknown, "/api/v1/vm.info", 0).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = Limiter::new(&HttpApiLimits {
+            max_in_flight: None,
+            rate_limit: Some(TokenBucketConfig {
+                size: 2,
+                one_time_burst: None,
+                refill_time: 3_600_000,
+            }),
+        });
+        let peer = |port| Peer::Tcp {
+            address: format!("192.0.2.1:{port}"),
+        };
+
+        assert!(limiter.admit(&peer(40000), "/api/v1/vm.info", 0).is_ok());
+        assert!(limiter.admit(&peer(40001), "/api/v1/vm.info", 0).is_ok());
+        // Another connection from the same client
+        assert!(matches!(
+            limiter.admit(&peer(40002), "/api/v1/vm.info", 0),
+            Err(HttpError::RateLimited)
+        ));
+        assert!(limiter
+            .admit(&Peer::Vsock { cid: 3 }, "/api/v1/vm.info", 0)
+            .is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! `GET /metrics`, exposing the metrics of the VMM along with the latencies of
+//! the API requests in the Prometheus text format.
+
+use super::{error_response, HttpError};
+use crate::api::{ApiAction, ApiRequest, VmmMetrics};
+use crate::metrics::{MetricType, MetricsWriter};
+use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::Duration;
+use vmm_sys_util::eventfd::EventFd;
+
+pub const METRICS_URI: &str = "/metrics";
+
+const API_LATENCY_METRIC: &str = "api_request_duration_seconds";
+
+#[derive(Default)]
+struct Latency {
+    count: u64,
+    total: Duration,
+}
+
+/// Latencies of the API requests, per endpoint.
+static API_LATENCIES: Lazy<Mutex<BTreeMap<String, Latency>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Records how long it took to answer a request to `endpoint`.
+pub fn record_request(endpoint: &str, latency: Duration) {
+    let mut latencies = API_LATENCIES.lock().unwrap();
+    let entry = latencies.entry(endpoint.to_string()).or_default();
+    entry.count += 1;
+    entry.total += latency;
+}
+
+fn write_api_latencies(writer: &mut MetricsWriter) {
+    writer.family(
+        API_LATENCY_METRIC,
+        MetricType::Summary,
+        "Time taken to answer the API requests",
+    );
+    for (endpoint, latency) in API_LATENCIES.lock().unwrap().iter() {
+        let labels = [("endpoint", endpoint.as_str())];
+        writer.sample(
+            &format!("{API_LATENCY_METRIC}_count"),
+            &labels,
+            latency.count,
+        );
+        writer.sample(
+            &format!("{API_LATENCY_METRIC}_sum"),
+            &labels,
+            latency.total.as_secs_f64(),
+        );
+    }
+}
+
+/// Answers `GET /metrics`, the VMM thread gathering the metrics of the VMM
+/// and of the VM.
+pub fn handle_metrics_request(
+    path: &str,
+    request: &Request,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Option<Response> {
+    if path != METRICS_URI {
+        return None;
+    }
+    if !matches!(request.method(), Method::Get) {
+        return Some(error_response(
+            HttpError::BadRequest,
+            StatusCode::BadRequest,
+        ));
+    }
+
+    let Ok(notifier) = api_notifier.try_clone() else {
+        return Some(error_response(
+            HttpError::InternalServerError,
+            StatusCode::InternalServerError,
+        ));
+    };
+
+    Some(match VmmMetrics.send(notifier, api_sender.clone(), ()) {
+        Ok(body) => {
+            let mut text = body.map(|body| body.raw().to_vec()).unwrap_or_default();
+            let mut writer = MetricsWriter::default();
+            write_api_latencies(&mut writer);
+            text.extend(writer.finish().into_bytes());
+
+            let mut response = Response::new(Version::Http11, StatusCode::OK);
+            response.set_body(Body::new(text));
+            response
+        }
+        Err(e) => error_response(HttpError::ApiError(e), StatusCode::InternalServerError),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_latencies() {
+        record_request("/api/v1/vm.info", Duration::from_millis(2));
+        record_request("/api/v1/vm.info", Duration::from_millis(3));
+
+        let mut writer = MetricsWriter::default();
+        write_api_latencies(&mut writer);
+        let text = writer.finish();
+        assert!(text.contains(
+            "cloud_hypervisor_api_request_duration_seconds_count{endpoint=\"/api/v1/vm.info\"} 2\n"
+        ));
+        assert!(text.contains(
+            "cloud_hypervisor_api_request_duration_seconds_sum{endpoint=\"/api/v1/vm.info\"} 0.005\n"
+        ));
+    }
+}
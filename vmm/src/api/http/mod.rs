@@ -4,15 +4,25 @@
 //
 
 use self::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
+use self::idempotency::{idempotency_key, IdempotencyCache};
+use crate::api::audit::{self, Peer};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::VmCoredump;
+#[cfg(feature = "introspection")]
+use crate::api::VmIntrospect;
+#[cfg(feature = "pprof")]
+use crate::api::VmmPprof;
 use crate::api::{
-    AddDisk, ApiError, ApiRequest, VmAddDevice, VmAddFs, VmAddNet, VmAddPmem, VmAddUserDevice,
-    VmAddVdpa, VmAddVsock, VmBoot, VmCounters, VmDelete, VmNmi, VmPause, VmPowerButton, VmReboot,
-    VmReceiveMigration, VmRemoveDevice, VmResize, VmResizeZone, VmRestore, VmResume,
-    VmSendMigration, VmShutdown, VmSnapshot,
+    AddDisk, ApiError, ApiErrorCode, ApiErrorResponse, ApiRequest, VmAddDevice, VmAddFs, VmAddNet,
+    VmAddPmem, VmAddUsb, VmAddUserDevice, VmAddVdpa, VmAddVsock, VmBoot, VmCapabilities,
+    VmCounters, VmDelete, VmDeviceTree, VmDevices, VmDumpMemory, VmMigrationBlockers, VmNmi,
+    VmPause, VmPowerButton, VmReboot, VmReceiveMigration, VmRekey, VmRemoveDevice, VmReplaceDevice,
+    VmResize, VmResizeZone, VmRestore, VmResume, VmScreenshot, VmSendMigration, VmSetBootParams,
+    VmShutdown, VmSnapshot, VmSuspendToRam, VmSysRq, VmUpdateConfig, VmVcpuStats, VmmCapabilities,
+    VmmCheckpoint, VmmFds, VmmHostInfo, VmmPostRestore, VmmProfile, VmmThreads,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::seccomp_violations;
 use crate::{Error as VmmError, Result};
 use core::fmt;
 use hypervisor::HypervisorType;
@@ -20,20 +30,31 @@ use micro_http::{
     Body, HttpServer, MediaType, Method, Request, Response, ServerError, StatusCode, Version,
 };
 use once_cell::sync::Lazy;
-use seccompiler::{apply_filter, SeccompAction};
+use seccompiler::SeccompAction;
 use serde_json::Error as SerdeError;
 use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::fs::File;
-use std::os::unix::io::{IntoRawFd, RawFd};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 use std::thread;
+use std::time::Instant;
 use vmm_sys_util::eventfd::EventFd;
 
+pub mod auth;
+mod connection;
+pub mod events;
 pub mod http_endpoint;
+mod idempotency;
+pub mod jobs;
+pub mod limits;
+pub mod metrics;
+#[cfg(feature = "http_tls")]
+pub mod tls;
+pub mod vsock;
 
 pub type HttpApiHandle = (thread::JoinHandle<Result<()>>, EventFd);
 
@@ -54,6 +75,30 @@ pub enum HttpError {
 
     /// Error from internal API
     ApiError(ApiError),
+
+    /// Invalid Idempotency-Key header
+    InvalidIdempotencyKey,
+
+    /// Idempotency key already used for another request
+    IdempotencyKeyReused,
+
+    /// Missing or invalid bearer token
+    Unauthorized,
+
+    /// Unknown job, or job which can't be cancelled
+    Job(crate::jobs::Error),
+
+    /// The client exceeded its request rate
+    RateLimited,
+
+    /// Too many requests are in flight
+    TooManyRequestsInFlight,
+
+    /// Too many clients receive the events
+    TooManyEventClients,
+
+    /// Failed duplicating the file descriptors passed with the request
+    DupFiles(std::io::Error),
 }
 
 impl Display for HttpError {
@@ -65,6 +110,41 @@ impl Display for HttpError {
             InternalServerError => write!(f, "Internal Server Error"),
             SerdeJsonDeserialize(serde_error) => write!(f, "{}", serde_error),
             ApiError(api_error) => write!(f, "{}", api_error),
+            InvalidIdempotencyKey => write!(
+                f,
+                "Invalid Idempotency-Key (expected 1-255 printable ASCII characters)"
+            ),
+            IdempotencyKeyReused => {
+                write!(f, "Idempotency-Key already used for a different request")
+            }
+            Unauthorized => write!(f, "Missing or invalid bearer token"),
+            Job(job_error) => write!(f, "{}", job_error),
+            RateLimited => write!(f, "Request rate exceeded"),
+            TooManyRequestsInFlight => write!(f, "Too many requests in flight"),
+            TooManyEventClients => write!(f, "Too many clients receiving the events"),
+            DupFiles(e) => write!(f, "Error duplicating the file descriptors: {}", e),
+        }
+    }
+}
+
+impl HttpError {
+    /// Returns the stable identifier of the error.
+    pub fn code(&self) -> ApiErrorCode {
+        use self::HttpError::*;
+        match self {
+            SerdeJsonDeserialize(_) | BadRequest | InvalidIdempotencyKey => {
+                ApiErrorCode::InvalidRequest
+            }
+            NotFound => ApiErrorCode::NotFound,
+            InternalServerError | DupFiles(_) => ApiErrorCode::InternalError,
+            ApiError(api_error) => api_error.code(),
+            IdempotencyKeyReused => ApiErrorCode::IdempotencyKeyReused,
+            Unauthorized => ApiErrorCode::Unauthorized,
+            Job(crate::jobs::Error::NotFound(_)) => ApiErrorCode::NotFound,
+            Job(crate::jobs::Error::AlreadyCompleted(_)) => ApiErrorCode::JobAlreadyCompleted,
+            RateLimited | TooManyRequestsInFlight | TooManyEventClients => {
+                ApiErrorCode::TooManyRequests
+            }
         }
     }
 }
@@ -79,11 +159,31 @@ const HTTP_ROOT: &str = "/api/v1";
 
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     let mut response = Response::new(Version::Http11, status);
-    response.set_body(Body::new(format!("{error}")));
+    let body = ApiErrorResponse {
+        code: error.code(),
+        message: error.to_string(),
+        detail: match &error {
+            HttpError::ApiError(api_error) => Some(format!("{api_error:?}")),
+            _ => None,
+        },
+    };
+    // Serializing the error can't fail, fall back on the message regardless
+    response.set_body(Body::new(
+        serde_json::to_string(&body).unwrap_or(body.message),
+    ));
 
     response
 }
 
+/// Duplicates the file descriptors received along with the request.
+pub fn dup_files(req: &Request) -> std::result::Result<Vec<File>, HttpError> {
+    req.files
+        .iter()
+        .map(File::try_clone)
+        .collect::<std::io::Result<_>>()
+        .map_err(HttpError::DupFiles)
+}
+
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler {
     /// Handles an HTTP request.
@@ -101,11 +201,14 @@ pub trait EndpointHandler {
         // descriptors, leaving open the one that was received. This way,
         // rebooting the VM will work since the VM will be created from the
         // original file descriptors.
-        let files = req.files.iter().map(|f| f.try_clone().unwrap()).collect();
+        let files = match dup_files(req) {
+            Ok(files) => files,
+            Err(e) => return error_response(e, StatusCode::InternalServerError),
+        };
         let res = match req.method() {
             Method::Put => self.put_handler(api_notifier, api_sender, &req.body, files),
             Method::Get => self.get_handler(api_notifier, api_sender, &req.body),
-            _ => return Response::new(Version::Http11, StatusCode::BadRequest),
+            _ => return error_response(HttpError::BadRequest, StatusCode::BadRequest),
         };
 
         match res {
@@ -188,6 +291,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.add-pmem"),
         Box::new(VmActionHandler::new(&VmAddPmem)),
     );
+    r.routes.insert(
+        endpoint!("/vm.add-usb"),
+        Box::new(VmActionHandler::new(&VmAddUsb)),
+    );
     r.routes.insert(
         endpoint!("/vm.add-vdpa"),
         Box::new(VmActionHandler::new(&VmAddVdpa)),
@@ -204,6 +311,26 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.counters"),
         Box::new(VmActionHandler::new(&VmCounters)),
     );
+    r.routes.insert(
+        endpoint!("/vm.vcpu-stats"),
+        Box::new(VmActionHandler::new(&VmVcpuStats)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.migration-blockers"),
+        Box::new(VmActionHandler::new(&VmMigrationBlockers)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.capabilities"),
+        Box::new(VmActionHandler::new(&VmCapabilities)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.device-tree"),
+        Box::new(VmActionHandler::new(&VmDeviceTree)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.devices"),
+        Box::new(VmActionHandler::new(&VmDevices)),
+    );
     r.routes
         .insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
     r.routes.insert(
@@ -231,6 +358,10 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.remove-device"),
         Box::new(VmActionHandler::new(&VmRemoveDevice)),
     );
+    r.routes.insert(
+        endpoint!("/vm.replace-device"),
+        Box::new(VmActionHandler::new(&VmReplaceDevice)),
+    );
     r.routes.insert(
         endpoint!("/vm.resize"),
         Box::new(VmActionHandler::new(&VmResize)),
@@ -251,6 +382,18 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.send-migration"),
         Box::new(VmActionHandler::new(&VmSendMigration)),
     );
+    r.routes.insert(
+        endpoint!("/vm.set-boot-params"),
+        Box::new(VmActionHandler::new(&VmSetBootParams)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.rekey"),
+        Box::new(VmActionHandler::new(&VmRekey)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.config"),
+        Box::new(VmActionHandler::new(&VmUpdateConfig)),
+    );
     r.routes.insert(
         endpoint!("/vm.shutdown"),
         Box::new(VmActionHandler::new(&VmShutdown)),
@@ -264,23 +407,97 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.coredump"),
         Box::new(VmActionHandler::new(&VmCoredump)),
     );
+    #[cfg(feature = "introspection")]
+    r.routes.insert(
+        endpoint!("/vm.introspect"),
+        Box::new(VmActionHandler::new(&VmIntrospect)),
+    );
     r.routes
         .insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+    r.routes.insert(
+        endpoint!("/vmm.threads"),
+        Box::new(VmActionHandler::new(&VmmThreads)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.host-info"),
+        Box::new(VmActionHandler::new(&VmmHostInfo)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.capabilities"),
+        Box::new(VmActionHandler::new(&VmmCapabilities)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.fds"),
+        Box::new(VmActionHandler::new(&VmmFds)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.checkpoint"),
+        Box::new(VmActionHandler::new(&VmmCheckpoint)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.post-restore"),
+        Box::new(VmActionHandler::new(&VmmPostRestore)),
+    );
+    r.routes.insert(
+        endpoint!("/vmm.profile"),
+        Box::new(VmActionHandler::new(&VmmProfile)),
+    );
+    #[cfg(feature = "pprof")]
+    r.routes.insert(
+        endpoint!("/vmm.pprof"),
+        Box::new(VmActionHandler::new(&VmmPprof)),
+    );
     r.routes
         .insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
     r.routes
         .insert(endpoint!("/vm.nmi"), Box::new(VmActionHandler::new(&VmNmi)));
+    r.routes.insert(
+        endpoint!("/vm.suspend-to-ram"),
+        Box::new(VmActionHandler::new(&VmSuspendToRam)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.sysrq"),
+        Box::new(VmActionHandler::new(&VmSysRq)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.screenshot"),
+        Box::new(VmActionHandler::new(&VmScreenshot)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.dump-memory"),
+        Box::new(VmActionHandler::new(&VmDumpMemory)),
+    );
 
     r
 });
 
-fn handle_http_request(
+/// Paths of the endpoints served by the HTTP API.
+pub fn endpoints() -> Vec<String> {
+    HTTP_ROUTES
+        .routes
+        .keys()
+        .cloned()
+        .chain([jobs::JOBS_PATH, metrics::METRICS_URI].map(String::from))
+        .collect()
+}
+
+/// Responses of the mutating requests sent with an idempotency key.
+static IDEMPOTENCY_CACHE: Lazy<IdempotencyCache> = Lazy::new(IdempotencyCache::default);
+
+fn route_http_request(
+    path: &str,
     request: &Request,
     api_notifier: &EventFd,
     api_sender: &Sender<ApiRequest>,
 ) -> Response {
-    let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
+    if let Some(response) = jobs::handle_jobs_request(path, request)
+        .or_else(|| jobs::handle_async_request(path, request, api_notifier, api_sender))
+        .or_else(|| metrics::handle_metrics_request(path, request, api_notifier, api_sender))
+    {
+        return response;
+    }
+
+    match HTTP_ROUTES.routes.get(path) {
         Some(route) => match api_notifier.try_clone() {
             Ok(notifier) => route.handle_request(request, notifier, api_sender.clone()),
             Err(_) => error_response(
@@ -289,10 +506,94 @@ fn handle_http_request(
             ),
         },
         None => error_response(HttpError::NotFound, StatusCode::NotFound),
+    }
+}
+
+/// Status of the responses turning down the requests beyond the limits,
+/// which micro_http has no code for, hence the limited requests being served
+/// per connection.
+pub const TOO_MANY_REQUESTS: u16 = 429;
+
+// Status code of the response as a number, micro_http only giving its text.
+fn status_code(response: &Response) -> u16 {
+    std::str::from_utf8(response.status().raw())
+        .ok()
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_default()
+}
+
+// Returns the response along with its status code, which is the one to
+// write it with.
+fn handle_http_request(
+    request: &Request,
+    peer: &Peer,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> (Response, u16) {
+    let path = request.uri().get_abs_path().to_string();
+    let (response, status) = match admit_http_request(&path, request, peer) {
+        Ok(_in_flight) => {
+            let response = admitted_http_request(&path, request, api_notifier, api_sender);
+            let status = status_code(&response);
+            (response, status)
+        }
+        Err((mut response, status)) => {
+            response.set_server("Cloud Hypervisor API");
+            response.set_content_type(MediaType::ApplicationJson);
+            (response, status)
+        }
     };
+    audit::record_http(peer, request.method().to_str(), &path, status);
+    (response, status)
+}
+
+fn admit_http_request(
+    path: &str,
+    request: &Request,
+    peer: &Peer,
+) -> std::result::Result<limits::InFlight<'static>, (Response, u16)> {
+    if !auth::authorized(request) {
+        let response = error_response(HttpError::Unauthorized, StatusCode::Unauthorized);
+        let status = status_code(&response);
+        return Err((response, status));
+    }
+
+    limits::admit(peer, path).map_err(|e| {
+        (
+            error_response(e, StatusCode::ServiceUnavailable),
+            TOO_MANY_REQUESTS,
+        )
+    })
+}
+
+fn admitted_http_request(
+    path: &str,
+    request: &Request,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Response {
+    let start = Instant::now();
+    // The requests are served by several threads, a retry sent while the
+    // previous attempt is still running waiting for it to complete.
+    let mut response = match idempotency_key(request) {
+        Ok(Some(key)) => IDEMPOTENCY_CACHE.handle(&key, path, request, || {
+            route_http_request(path, request, api_notifier, api_sender)
+        }),
+        Ok(None) => route_http_request(path, request, api_notifier, api_sender),
+        Err(e) => error_response(e, StatusCode::BadRequest),
+    };
+
+    // Only the known endpoints are accounted, the others being unbounded
+    if HTTP_ROUTES.routes.contains_key(path) || path == metrics::METRICS_URI {
+        metrics::record_request(path, start.elapsed());
+    }
 
     response.set_server("Cloud Hypervisor API");
-    response.set_content_type(MediaType::ApplicationJson);
+    if path == metrics::METRICS_URI && response.status() == StatusCode::OK {
+        response.set_content_type(MediaType::PlainText);
+    } else {
+        response.set_content_type(MediaType::ApplicationJson);
+    }
     response
 }
 
@@ -320,7 +621,7 @@ fn start_http_thread(
         .spawn(move || {
             // Apply seccomp filter for API thread.
             if !api_seccomp_filter.is_empty() {
-                apply_filter(&api_seccomp_filter)
+                seccomp_violations::apply_filter(&api_seccomp_filter)
                     .map_err(VmmError::ApplySeccompFilter)
                     .map_err(|e| {
                         error!("Error applying seccomp filter: {:?}", e);
@@ -335,8 +636,16 @@ fn start_http_thread(
                     match server.requests() {
                         Ok(request_vec) => {
                             for server_request in request_vec {
+                                // Never turned down by the limits, which
+                                // aren't served by micro_http
                                 if let Err(e) = server.respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
+                                    handle_http_request(
+                                        request,
+                                        &Peer::Unknown,
+                                        &api_notifier,
+                                        &api_sender,
+                                    )
+                                    .0
                                 })) {
                                     error!("HTTP server error on response: {}", e);
                                 }
@@ -368,6 +677,13 @@ fn start_http_thread(
     Ok((thread, api_shutdown_fd))
 }
 
+// micro_http doesn't tell which connection a request came from, nor hands
+// it over, hence the UNIX socket serving a request per connection when the
+// peers must be identified, the requests limited or the events streamed.
+fn served_per_connection() -> bool {
+    audit::enabled() || limits::enabled() || events::enabled()
+}
+
 pub fn start_http_path_thread(
     path: &str,
     api_notifier: EventFd,
@@ -378,6 +694,16 @@ pub fn start_http_path_thread(
 ) -> Result<HttpApiHandle> {
     let socket_path = PathBuf::from(path);
     let socket_fd = UnixListener::bind(socket_path).map_err(VmmError::CreateApiServerSocket)?;
+    if served_per_connection() {
+        return connection::start_listener_thread(
+            socket_fd,
+            api_notifier,
+            api_sender,
+            seccomp_action,
+            exit_evt,
+            hypervisor_type,
+        );
+    }
     // SAFETY: Valid FD just opened
     let server = unsafe { HttpServer::new_from_fd(socket_fd.into_raw_fd()) }
         .map_err(VmmError::CreateApiServer)?;
@@ -400,6 +726,17 @@ pub fn start_http_fd_thread(
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
 ) -> Result<HttpApiHandle> {
+    if served_per_connection() {
+        return connection::start_listener_thread(
+            // SAFETY: Valid FD
+            unsafe { UnixListener::from_raw_fd(fd) },
+            api_notifier,
+            api_sender,
+            seccomp_action,
+            exit_evt,
+            hypervisor_type,
+        );
+    }
     // SAFETY: Valid FD
     let server = unsafe { HttpServer::new_from_fd(fd) }.map_err(VmmError::CreateApiServer)?;
     start_http_thread(
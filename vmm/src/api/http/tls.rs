@@ -0,0 +1,235 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! HTTP API served over TCP, the clients being authenticated through mutual
+//! TLS.
+
+use super::connection::{
+    serve_request, start_listener_thread, Connection, Listener, RequestStream, CLIENT_TIMEOUT,
+};
+use super::HttpApiHandle;
+use crate::api::audit::Peer;
+use crate::api::ApiRequest;
+use crate::{Error as VmmError, Result};
+use hypervisor::HypervisorType;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use seccompiler::SeccompAction;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Cannot read a PEM file.
+    #[error("Error reading {0}: {1}")]
+    ReadPem(PathBuf, #[source] io::Error),
+
+    /// No certificate in a PEM file.
+    #[error("No certificate found in {0}")]
+    MissingCertificate(PathBuf),
+
+    /// No private key in a PEM file.
+    #[error("No private key found in {0}")]
+    MissingPrivateKey(PathBuf),
+
+    /// Invalid CA certificate.
+    #[error("Invalid CA certificate: {0}")]
+    CaCertificate(#[source] rustls::Error),
+
+    /// Cannot create the verifier of the client certificates.
+    #[error("Error creating the client certificate verifier: {0}")]
+    ClientVerifier(#[source] rustls::server::VerifierBuilderError),
+
+    /// Cannot create the TLS configuration.
+    #[error("Error creating the TLS configuration: {0}")]
+    ServerConfig(#[source] rustls::Error),
+}
+
+/// HTTP API served over TLS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpTlsConfig {
+    /// Address the API listens on
+    pub listen: SocketAddr,
+    /// PEM file holding the certificate chain of the server
+    pub cert: PathBuf,
+    /// PEM file holding the private key of the server
+    pub key: PathBuf,
+    /// PEM file holding the CA certificates the client certificates must be
+    /// issued by
+    pub ca: PathBuf,
+}
+
+fn read_certificates(path: &Path) -> std::result::Result<Vec<CertificateDer<'static>>, Error> {
+    let file = File::open(path).map_err(|e| Error::ReadPem(path.to_owned(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| Error::ReadPem(path.to_owned(), e))?;
+    if certs.is_empty() {
+        return Err(Error::MissingCertificate(path.to_owned()));
+    }
+
+    Ok(certs)
+}
+
+impl HttpTlsConfig {
+    fn server_config(&self) -> std::result::Result<Arc<ServerConfig>, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let mut roots = RootCertStore::empty();
+        for cert in read_certificates(&self.ca)? {
+            roots.add(cert).map_err(Error::CaCertificate)?;
+        }
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(Error::ClientVerifier)?;
+
+        let certs = read_certificates(&self.cert)?;
+        let file = File::open(&self.key).map_err(|e| Error::ReadPem(self.key.clone(), e))?;
+        let key: PrivateKeyDer = rustls_pemfile::private_key(&mut BufReader::new(file))
+            .map_err(|e| Error::ReadPem(self.key.clone(), e))?
+            .ok_or_else(|| Error::MissingPrivateKey(self.key.clone()))?;
+
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(Error::ServerConfig)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(Error::ServerConfig)?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+struct TlsListener {
+    listener: TcpListener,
+    server_config: Arc<ServerConfig>,
+}
+
+impl AsRawFd for TlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+// Socket of a TLS connection, whose reads fail once the time given to the
+// client is over, the handshake being done through them as well
+pub struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl RequestStream for StreamOwned<ServerConnection, DeadlineStream> {
+    fn set_read_timeout(&self, _timeout: Duration) -> io::Result<()> {
+        // The reads of the socket are already bounded by its deadline
+        Ok(())
+    }
+}
+
+pub struct TlsConnection {
+    stream: StreamOwned<ServerConnection, DeadlineStream>,
+    peer: SocketAddr,
+}
+
+impl Listener for TlsListener {
+    type Connection = TlsConnection;
+
+    fn accept_connection(&self) -> io::Result<TlsConnection> {
+        let (stream, peer) = self.listener.accept()?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let connection = ServerConnection::new(self.server_config.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // The handshake, including the verification of the client
+        // certificate, completes on the first read, once the connection is
+        // served by a client thread
+        let stream = DeadlineStream {
+            stream,
+            deadline: Instant::now() + CLIENT_TIMEOUT,
+        };
+        Ok(TlsConnection {
+            stream: StreamOwned::new(connection, stream),
+            peer,
+        })
+    }
+}
+
+impl Connection for TlsConnection {
+    fn serve(self, api_notifier: &EventFd, api_sender: &Sender<ApiRequest>) -> io::Result<()> {
+        let peer = self.peer;
+        // A single request is served per connection
+        let address = Peer::Tcp {
+            address: peer.to_string(),
+        };
+        if let Some(mut stream) = serve_request(self.stream, &address, api_notifier, api_sender)
+            .map_err(|e| io::Error::new(e.kind(), format!("client {peer}: {e}")))?
+        {
+            stream.conn.send_close_notify();
+            stream.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn start_http_tls_thread(
+    config: &HttpTlsConfig,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+    exit_evt: EventFd,
+    hypervisor_type: HypervisorType,
+) -> Result<HttpApiHandle> {
+    let server_config = config.server_config().map_err(VmmError::HttpTls)?;
+    let listener = TcpListener::bind(config.listen).map_err(VmmError::CreateApiServerSocket)?;
+    info!(
+        "HTTP API listening on {}",
+        listener.local_addr().unwrap_or(config.listen)
+    );
+
+    start_listener_thread(
+        TlsListener {
+            listener,
+            server_config,
+        },
+        api_notifier,
+        api_sender,
+        seccomp_action,
+        exit_evt,
+        hypervisor_type,
+    )
+}
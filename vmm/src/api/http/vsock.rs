@@ -0,0 +1,170 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! HTTP API served on a vsock port of the host, for the management agents
+//! not sharing a filesystem with the VMM.
+
+use super::connection::{
+    serve_request, start_listener_thread, Connection, Listener, CLIENT_TIMEOUT,
+};
+use super::HttpApiHandle;
+use crate::api::audit::Peer;
+use crate::api::ApiRequest;
+use crate::{Error as VmmError, Result};
+use hypervisor::HypervisorType;
+use seccompiler::SeccompAction;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::Sender;
+use vmm_sys_util::eventfd::EventFd;
+
+// Maximum number of pending connections
+const LISTEN_BACKLOG: libc::c_int = 16;
+
+/// HTTP API served on a vsock port
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpVsockConfig {
+    /// Port the API listens on
+    pub port: u32,
+    /// CIDs of the peers allowed to connect, any other peer being
+    /// disconnected before its request is read
+    pub cids: Vec<u32>,
+}
+
+struct VsockListener {
+    fd: OwnedFd,
+    cids: Vec<u32>,
+}
+
+impl VsockListener {
+    fn bind(port: u32, cids: Vec<u32>) -> io::Result<Self> {
+        // SAFETY: FFI call with valid arguments
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just created and is owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_vm is a plain C struct, all zeroes being valid
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = libc::VMADDR_CID_ANY;
+        addr.svm_port = port;
+
+        // SAFETY: FFI call with a valid socket and address
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: FFI call with a valid socket
+        if unsafe { libc::listen(fd.as_raw_fd(), LISTEN_BACKLOG) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { fd, cids })
+    }
+
+    // Returns the accepted connection along with the CID of the peer
+    fn accept(&self) -> io::Result<(UnixStream, u32)> {
+        // SAFETY: sockaddr_vm is a plain C struct, all zeroes being valid
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+        // SAFETY: FFI call with a valid socket, addr being large enough for
+        // the address of a vsock peer
+        let fd = unsafe {
+            libc::accept4(
+                self.fd.as_raw_fd(),
+                &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+                &mut addr_len,
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The stream operations being independent of the address family,
+        // the connection is handled as a UnixStream.
+        // SAFETY: fd was just accepted and is owned by nothing else
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        Ok((stream, addr.svm_cid))
+    }
+}
+
+impl AsRawFd for VsockListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub struct VsockConnection {
+    stream: UnixStream,
+    cid: u32,
+}
+
+impl Listener for VsockListener {
+    type Connection = VsockConnection;
+
+    fn accept_connection(&self) -> io::Result<VsockConnection> {
+        let (stream, cid) = self.accept()?;
+        if !self.cids.contains(&cid) {
+            // Dropping the stream closes the connection
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("vsock CID {cid} is not allowed to connect"),
+            ));
+        }
+        debug!("HTTP API connection from vsock CID {cid}");
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        Ok(VsockConnection { stream, cid })
+    }
+}
+
+impl Connection for VsockConnection {
+    fn serve(self, api_notifier: &EventFd, api_sender: &Sender<ApiRequest>) -> io::Result<()> {
+        let cid = self.cid;
+        // A single request is served per connection
+        serve_request(self.stream, &Peer::Vsock { cid }, api_notifier, api_sender)
+            .map(|_| ())
+            .map_err(|e| io::Error::new(e.kind(), format!("vsock CID {cid}: {e}")))
+    }
+}
+
+pub fn start_http_vsock_thread(
+    config: &HttpVsockConfig,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+    exit_evt: EventFd,
+    hypervisor_type: HypervisorType,
+) -> Result<HttpApiHandle> {
+    let listener = VsockListener::bind(config.port, config.cids.clone())
+        .map_err(VmmError::CreateApiServerSocket)?;
+    info!(
+        "HTTP API listening on vsock port {} for CIDs {:?}",
+        config.port, config.cids
+    );
+
+    start_listener_thread(
+        listener,
+        api_notifier,
+        api_sender,
+        seccomp_action,
+        exit_evt,
+        hypervisor_type,
+    )
+}
@@ -29,6 +29,7 @@
 //!    response channel Receiver.
 //! 5. The thread handles the response and forwards potential errors.
 
+pub mod audit;
 #[cfg(feature = "dbus_api")]
 pub mod dbus;
 pub mod http;
@@ -37,21 +38,37 @@ pub mod http;
 pub use self::dbus::start_dbus_thread;
 pub use self::http::start_http_fd_thread;
 pub use self::http::start_http_path_thread;
+#[cfg(feature = "http_tls")]
+pub use self::http::tls::{start_http_tls_thread, HttpTlsConfig};
+pub use self::http::vsock::{start_http_vsock_thread, HttpVsockConfig};
 
+use crate::boot_detect::BootState;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, UserDeviceConfig,
-    VdpaConfig, VmConfig, VsockConfig,
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, ReplacementDeviceConfig,
+    RestoreConfig, UsbConfig, UserDeviceConfig, VdpaConfig, VmConfig, VmIdentityConfig,
+    VsockConfig,
 };
+use crate::config_update;
+use crate::cpu::{VcpuCpuTime, VcpuStats};
 use crate::device_tree::DeviceTree;
-use crate::vm::{Error as VmError, VmState};
+use crate::fds::FdInfo;
+use crate::host_checks::Remediation;
+use crate::memory_manager::MemoryZoneBacking;
+use crate::payload_verification::PayloadVerification;
+use crate::profile::ProcessCpuTime;
+use crate::threads::ThreadInfo;
+use crate::vm::{Error as VmError, MemoryDump, ShutdownReason, VmState};
 use crate::Error as VmmError;
 use core::fmt;
 use micro_http::Body;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use vm_migration::MigratableError;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -118,6 +135,15 @@ pub enum ApiError {
     /// The memory zone could not be resized.
     VmResizeZone(VmError),
 
+    /// The boot parameters could not be updated.
+    VmSetBootParams(VmError),
+
+    /// The identity of the VM could not be updated.
+    VmRekey(VmError),
+
+    /// The VM configuration could not be updated.
+    VmUpdateConfig(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
@@ -127,6 +153,9 @@ pub enum ApiError {
     /// The device could not be removed from the VM.
     VmRemoveDevice(VmError),
 
+    /// The device could not be replaced.
+    VmReplaceDevice(VmError),
+
     /// Cannot create seccomp filter
     CreateSeccompFilter(seccompiler::Error),
 
@@ -151,6 +180,9 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The host USB device could not be added to the VM.
+    VmAddUsb(VmError),
+
     /// Error starting migration receiever
     VmReceiveMigration(MigratableError),
 
@@ -160,8 +192,65 @@ pub enum ApiError {
     /// Error triggering power button
     VmPowerButton(VmError),
 
+    /// Error triggering sleep button
+    VmSuspendToRam(VmError),
+
     /// Error triggering NMI
     VmNmi(VmError),
+
+    /// Error sending a SysRq to the VM
+    VmSysRq(VmError),
+
+    /// Error capturing the framebuffer
+    VmScreenshot(VmError),
+
+    /// Error dumping the guest memory
+    VmDumpMemory(VmError),
+
+    /// Error introspecting guest memory
+    VmIntrospect(VmError),
+
+    /// Error getting the vCPUs statistics
+    VmVcpuStats(VmError),
+
+    /// Error getting the migration blockers
+    VmMigrationBlockers(VmError),
+
+    /// Error getting the VM capabilities
+    VmCapabilities(VmError),
+
+    /// Error getting the VM device tree
+    VmDeviceTree(VmError),
+
+    /// Error listing the removable devices
+    VmDevices(VmError),
+
+    /// Error listing the VMM threads
+    VmmThreads(VmError),
+
+    /// Error gathering the host information
+    VmmHostInfo(VmError),
+
+    /// Error listing the VMM capabilities
+    VmmCapabilities(VmError),
+
+    /// Error listing the VMM file descriptors
+    VmmFds(VmError),
+
+    /// Error preparing the VMM to be checkpointed by CRIU
+    VmmCheckpoint(VmError),
+
+    /// Error bringing the VM back once the VMM is restored by CRIU
+    VmmPostRestore(VmError),
+
+    /// Error gathering the VMM metrics
+    VmmMetrics(VmError),
+
+    /// Error profiling the VMM
+    VmmProfile(VmError),
+
+    /// Error gathering a pprof profile of the VMM
+    VmmPprof(VmError),
 }
 pub type ApiResult<T> = Result<T, ApiError>;
 
@@ -189,9 +278,13 @@ impl Display for ApiError {
             VmmShutdown(vm_error) => write!(f, "{}", vm_error),
             VmResize(vm_error) => write!(f, "{}", vm_error),
             VmResizeZone(vm_error) => write!(f, "{}", vm_error),
+            VmSetBootParams(vm_error) => write!(f, "{}", vm_error),
+            VmRekey(vm_error) => write!(f, "{}", vm_error),
+            VmUpdateConfig(vm_error) => write!(f, "{}", vm_error),
             VmAddDevice(vm_error) => write!(f, "{}", vm_error),
             VmAddUserDevice(vm_error) => write!(f, "{}", vm_error),
             VmRemoveDevice(vm_error) => write!(f, "{}", vm_error),
+            VmReplaceDevice(vm_error) => write!(f, "{}", vm_error),
             CreateSeccompFilter(seccomp_error) => write!(f, "{}", seccomp_error),
             ApplySeccompFilter(seccomp_error) => write!(f, "{}", seccomp_error),
             VmAddDisk(vm_error) => write!(f, "{}", vm_error),
@@ -200,20 +293,248 @@ impl Display for ApiError {
             VmAddNet(vm_error) => write!(f, "{}", vm_error),
             VmAddVdpa(vm_error) => write!(f, "{}", vm_error),
             VmAddVsock(vm_error) => write!(f, "{}", vm_error),
+            VmAddUsb(vm_error) => write!(f, "{}", vm_error),
             VmReceiveMigration(migratable_error) => write!(f, "{}", migratable_error),
             VmSendMigration(migratable_error) => write!(f, "{}", migratable_error),
             VmPowerButton(vm_error) => write!(f, "{}", vm_error),
+            VmSuspendToRam(vm_error) => write!(f, "{}", vm_error),
             VmNmi(vm_error) => write!(f, "{}", vm_error),
+            VmSysRq(vm_error) => write!(f, "{}", vm_error),
+            VmScreenshot(vm_error) => write!(f, "{}", vm_error),
+            VmDumpMemory(vm_error) => write!(f, "{}", vm_error),
+            VmIntrospect(vm_error) => write!(f, "{}", vm_error),
+            VmVcpuStats(vm_error) => write!(f, "{}", vm_error),
+            VmMigrationBlockers(vm_error) => write!(f, "{}", vm_error),
+            VmCapabilities(vm_error) => write!(f, "{}", vm_error),
+            VmDeviceTree(vm_error) => write!(f, "{}", vm_error),
+            VmDevices(vm_error) => write!(f, "{}", vm_error),
+            VmmThreads(vm_error) => write!(f, "{}", vm_error),
+            VmmHostInfo(vm_error) => write!(f, "{}", vm_error),
+            VmmCapabilities(vm_error) => write!(f, "{}", vm_error),
+            VmmFds(vm_error) => write!(f, "{}", vm_error),
+            VmmCheckpoint(vm_error) => write!(f, "{}", vm_error),
+            VmmPostRestore(vm_error) => write!(f, "{}", vm_error),
+            VmmMetrics(vm_error) => write!(f, "{}", vm_error),
+            VmmProfile(vm_error) => write!(f, "{}", vm_error),
+            VmmPprof(vm_error) => write!(f, "{}", vm_error),
+        }
+    }
+}
+
+/// Stable identifier of an API error, returned along with the error message
+/// so that clients can handle errors without parsing the message.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "code")]
+pub enum ApiErrorCode {
+    /// The request is malformed or its body can't be parsed
+    InvalidRequest,
+    /// The endpoint doesn't exist
+    NotFound,
+    /// The VM has not been created yet
+    VmNotCreated,
+    /// A VM has already been created
+    VmAlreadyCreated,
+    /// The VM has not been booted yet
+    VmNotBooted,
+    /// The VM has already been booted
+    VmAlreadyBooted,
+    /// The VM is not in a state allowing the request
+    InvalidVmState,
+    /// The configuration is invalid, `field` being the offending section of
+    /// the VM configuration when it can be identified
+    InvalidConfig {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        field: Option<String>,
+    },
+    /// No device with the given identifier is attached to the VM
+    DeviceNotFound { id: String },
+    /// The device can't be removed from the VM
+    DeviceRemovalNotAllowed,
+    /// No more devices of this kind can be added to the VM
+    HotplugLimitReached,
+    /// The host lacks something the VM relies on, `remediation` listing the
+    /// changes to make to the host
+    HostDependency { remediation: Vec<Remediation> },
+    /// The idempotency key has already been used for another request
+    IdempotencyKeyReused,
+    /// The request lacks the bearer token the API requires
+    Unauthorized,
+    /// The job can't be cancelled as it has already completed
+    JobAlreadyCompleted,
+    /// The fields of the VM configuration can't be updated, at least not
+    /// while the VM is running
+    ImmutableConfigFields { fields: Vec<String> },
+    /// The client exceeded its request rate, or too many requests are in
+    /// flight
+    TooManyRequests,
+    /// Any other failure
+    InternalError,
+}
+
+impl ApiErrorCode {
+    fn from_vm_error(error: &VmError, config_field: Option<&str>) -> Self {
+        use crate::device_manager::DeviceManagerError;
+
+        match error {
+            VmError::VmNotCreated | VmError::VmMissingConfig => ApiErrorCode::VmNotCreated,
+            VmError::VmAlreadyCreated => ApiErrorCode::VmAlreadyCreated,
+            VmError::VmAlreadyBooted => ApiErrorCode::VmAlreadyBooted,
+            VmError::VmNotRunning
+            | VmError::InvalidStateTransition(..)
+            | VmError::CheckpointPending
+            | VmError::NoCheckpoint => ApiErrorCode::InvalidVmState,
+            VmError::ConfigValidation(e) => ApiErrorCode::InvalidConfig {
+                field: e.field().or(config_field).map(String::from),
+            },
+            VmError::NoDeviceToRemove(id)
+            | VmError::DeviceManager(DeviceManagerError::UnknownDeviceId(id)) => {
+                ApiErrorCode::DeviceNotFound { id: id.clone() }
+            }
+            VmError::DeviceManager(DeviceManagerError::RemovalNotAllowed(_)) => {
+                ApiErrorCode::DeviceRemovalNotAllowed
+            }
+            VmError::TooManyVsockDevices
+            | VmError::DeviceManager(
+                DeviceManagerError::NoAvailableDeviceName
+                | DeviceManagerError::NextPciDeviceId(pci::PciRootError::NoPciDeviceSlotAvailable)
+                | DeviceManagerError::UsbAttach(devices::usb::XhciError::NoFreePort),
+            ) => ApiErrorCode::HotplugLimitReached,
+            VmError::DeviceManager(DeviceManagerError::NoXhciDevice) => {
+                ApiErrorCode::InvalidConfig {
+                    field: Some("xhci".to_string()),
+                }
+            }
+            VmError::HostCheck(e) => ApiErrorCode::HostDependency {
+                remediation: e.remediation(),
+            },
+            VmError::InvalidSysRqKey(_)
+            | VmError::InvalidCheckpointUrl(_)
+            | VmError::InvalidScreenshotUrl(_)
+            | VmError::InvalidMemoryDumpUrl(_)
+            | VmError::InvalidMemoryDumpRange(..) => ApiErrorCode::InvalidRequest,
+            #[cfg(feature = "pprof")]
+            VmError::InvalidPprofUrl(_) => ApiErrorCode::InvalidRequest,
+            VmError::UpdateConfig(config_update::Error::ImmutableFields(fields)) => {
+                ApiErrorCode::ImmutableConfigFields {
+                    fields: fields.clone(),
+                }
+            }
+            VmError::UpdateConfig(
+                config_update::Error::NotAnObject | config_update::Error::Deserialize(_),
+            ) => ApiErrorCode::InvalidRequest,
+            VmError::DeviceManager(DeviceManagerError::NoSerialDevice) => {
+                ApiErrorCode::InvalidConfig {
+                    field: Some("serial".to_string()),
+                }
+            }
+            VmError::DeviceManager(DeviceManagerError::NoRamfbDevice) => {
+                ApiErrorCode::InvalidConfig {
+                    field: Some("ramfb".to_string()),
+                }
+            }
+            // The firmware hasn't set up the framebuffer yet
+            #[cfg(target_arch = "x86_64")]
+            VmError::DeviceManager(DeviceManagerError::RamfbScreenshot(
+                devices::legacy::RamfbError::NotConfigured,
+            )) => ApiErrorCode::InvalidVmState,
+            _ => ApiErrorCode::InternalError,
         }
     }
 }
 
+impl ApiError {
+    /// Returns the stable identifier of the error.
+    pub fn code(&self) -> ApiErrorCode {
+        use self::ApiError::*;
+        match self {
+            VmNotBooted => ApiErrorCode::VmNotBooted,
+            VmNotCreated => ApiErrorCode::VmNotCreated,
+            VmAddDisk(e) => ApiErrorCode::from_vm_error(e, Some("disks")),
+            VmAddFs(e) => ApiErrorCode::from_vm_error(e, Some("fs")),
+            VmAddPmem(e) => ApiErrorCode::from_vm_error(e, Some("pmem")),
+            VmAddNet(e) => ApiErrorCode::from_vm_error(e, Some("net")),
+            VmAddVdpa(e) => ApiErrorCode::from_vm_error(e, Some("vdpa")),
+            VmAddVsock(e) => ApiErrorCode::from_vm_error(e, Some("vsock")),
+            VmAddUsb(e) => ApiErrorCode::from_vm_error(e, Some("usb")),
+            VmAddDevice(e) => ApiErrorCode::from_vm_error(e, Some("devices")),
+            VmAddUserDevice(e) => ApiErrorCode::from_vm_error(e, Some("user_devices")),
+            VmBoot(e)
+            | VmCreate(e)
+            | VmDelete(e)
+            | VmInfo(e)
+            | VmPause(e)
+            | VmResume(e)
+            | VmShutdown(e)
+            | VmReboot(e)
+            | VmSnapshot(e)
+            | VmRestore(e)
+            | VmCoredump(e)
+            | VmmShutdown(e)
+            | VmResize(e)
+            | VmResizeZone(e)
+            | VmSetBootParams(e)
+            | VmRekey(e)
+            | VmUpdateConfig(e)
+            | VmRemoveDevice(e)
+            | VmReplaceDevice(e)
+            | VmPowerButton(e)
+            | VmSuspendToRam(e)
+            | VmNmi(e)
+            | VmSysRq(e)
+            | VmScreenshot(e)
+            | VmDumpMemory(e)
+            | VmIntrospect(e)
+            | VmVcpuStats(e)
+            | VmMigrationBlockers(e)
+            | VmCapabilities(e)
+            | VmDeviceTree(e)
+            | VmDevices(e)
+            | VmmThreads(e)
+            | VmmHostInfo(e)
+            | VmmCapabilities(e)
+            | VmmFds(e)
+            | VmmCheckpoint(e)
+            | VmmPostRestore(e)
+            | VmmMetrics(e)
+            | VmmProfile(e)
+            | VmmPprof(e) => ApiErrorCode::from_vm_error(e, None),
+            EventFdWrite(_)
+            | RequestSend(_)
+            | ResponsePayloadType
+            | ResponseRecv(_)
+            | CreateSeccompFilter(_)
+            | ApplySeccompFilter(_)
+            | VmReceiveMigration(_)
+            | VmSendMigration(_) => ApiErrorCode::InternalError,
+        }
+    }
+}
+
+/// Body of the error responses returned by the HTTP and D-Bus APIs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiErrorResponse {
+    #[serde(flatten)]
+    pub code: ApiErrorCode,
+    pub message: String,
+    /// Full error reported by the VMM, along with its causes, for bug reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct VmInfoResponse {
     pub config: Arc<Mutex<VmConfig>>,
     pub state: VmState,
     pub memory_actual_size: u64,
+    #[serde(default)]
+    pub memory_backing: Option<Vec<MemoryZoneBacking>>,
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
+    #[serde(default)]
+    pub payload_verification: Option<Vec<PayloadVerification>>,
+    #[serde(default)]
+    pub shutdown_reason: Option<ShutdownReason>,
+    #[serde(default)]
+    pub boot_state: Option<BootState>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -224,11 +545,55 @@ pub struct VmmPingResponse {
     pub features: Vec<String>,
 }
 
+/// When a configuration change requested through the API takes effect.
+#[derive(Clone, Copy, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApplyMode {
+    /// Apply the change immediately
+    #[default]
+    Now,
+    /// Stage the change until the VM is (re)booted
+    NextBoot,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmThreadsResponse {
+    pub threads: Vec<ThreadInfo>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmCapabilitiesResponse {
+    /// Version of the API, prefixing the endpoints
+    pub api_version: String,
+    /// Hypervisor the VMM runs on
+    pub hypervisor: String,
+    /// Features Cloud Hypervisor was built with
+    pub features: Vec<String>,
+    /// Endpoints served by the HTTP API
+    pub endpoints: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmFdsResponse {
+    pub fds: Vec<FdInfo>,
+    /// Whether CRIU can checkpoint the VMM process
+    pub checkpointable: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmCheckpointData {
+    /// URL the VM is snapshotted to before being deleted, and restored from
+    /// once the VMM process is restored
+    pub destination_url: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmResizeData {
     pub desired_vcpus: Option<u8>,
     pub desired_ram: Option<u64>,
     pub desired_balloon: Option<u64>,
+    #[serde(default)]
+    pub apply: ApplyMode,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
@@ -237,15 +602,85 @@ pub struct VmResizeZoneData {
     pub desired_ram: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSetBootParamsData {
+    /// Firmware replacing the current one
+    pub firmware: Option<PathBuf>,
+    /// Kernel replacing the current one
+    pub kernel: Option<PathBuf>,
+    /// Kernel command line replacing the current one
+    pub cmdline: Option<String>,
+    /// Initramfs replacing the current one
+    pub initramfs: Option<PathBuf>,
+    #[serde(default)]
+    pub apply: ApplyMode,
+}
+
+// The MAC addresses can't be changed, the guest having read them already,
+// and the requests giving them are rejected rather than partly applied.
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VmRekeyData {
+    /// CID given to the guest, the vsock device keeping its CID otherwise
+    pub vsock_cid: Option<u32>,
+    /// SMBIOS UUID seen by the guest from its next reboot, a random one being
+    /// generated otherwise if the VM has one
+    pub uuid: Option<String>,
+}
+
+/// Configuration change staged by the VMM until the next boot of the VM.
+#[derive(Clone, Debug)]
+pub enum StagedConfigChange {
+    BootParams(VmSetBootParamsData),
+    Resize(VmResizeData),
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct VmSysRqData {
+    /// Magic SysRq key, e.g. `c` to crash the guest or `t` to dump its tasks
+    pub key: char,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmScreenshotData {
+    /// The PNG image destination file, as a `file://` URL
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmDumpMemoryData {
+    /// The dump destination file, as a `file://` URL
+    pub destination_url: String,
+    /// Guest physical address the dump starts from
+    pub offset: u64,
+    /// Size of the guest physical range to dump
+    pub length: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct VmReplaceDeviceData {
+    /// Identifier of the device to replace
+    pub id: String,
+    /// Configuration of the device plugged once the replaced one is ejected
+    #[serde(flatten)]
+    pub replacement: ReplacementDeviceConfig,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// File descriptor the key the snapshot is encrypted with is read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_fd: Option<i32>,
+    /// Compress the guest memory
+    #[serde(default)]
+    pub compress: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
@@ -254,6 +689,64 @@ pub struct VmCoredumpData {
     pub destination_url: String,
 }
 
+/// Kind of profile gathered in the pprof format.
+#[derive(Clone, Copy, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PprofKind {
+    /// Samples of the call stacks running on the CPUs
+    #[default]
+    Cpu,
+    /// Samples of the call stacks the live heap allocations were made from
+    Heap,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmPprofData {
+    #[serde(default)]
+    pub kind: PprofKind,
+    /// How long the CPU usage is sampled for, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_s: Option<u64>,
+    /// How many times per second the CPU usage is sampled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u32>,
+    /// The profile destination file
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmIntrospectData {
+    /// Guest physical address the scan starts from
+    pub gpa: u64,
+    /// Size of the guest physical range to scan
+    pub size: u64,
+    /// Byte pattern to look for
+    pub pattern: Vec<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmVcpuStatsResponse {
+    /// CPU time consumed by each vCPU
+    pub vcpus: Vec<VcpuStats>,
+    /// CPU time consumed by all the vCPUs
+    pub total: VcpuCpuTime,
+    /// CPU time consumed by the whole VMM process, vCPU threads included
+    #[serde(default)]
+    pub process: ProcessCpuTime,
+    /// CPU time spent by the VMM process outside of the guest, in the VMM
+    /// and the host kernel, in microseconds
+    #[serde(default)]
+    pub vmm_time_us: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmIntrospectResponse {
+    /// Guest physical addresses where the pattern was found
+    pub matches: Vec<u64>,
+    /// Whether more matches were found than the ones returned
+    pub truncated: bool,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmReceiveMigrationData {
     /// URL for the reception of migration state
@@ -269,6 +762,18 @@ pub struct VmSendMigrationData {
     pub local: bool,
 }
 
+/// Long-running request run as a job.
+#[derive(Debug)]
+pub enum JobRequest {
+    Snapshot(VmSnapshotConfig),
+    SendMigration(VmSendMigrationData),
+    DumpMemory(VmDumpMemoryData),
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    Coredump(VmCoredumpData),
+    #[cfg(feature = "pprof")]
+    Pprof(VmmPprofData),
+}
+
 pub enum ApiResponsePayload {
     /// No data is sent on the channel.
     Empty,
@@ -289,13 +794,18 @@ pub type ApiResponse = Result<ApiResponsePayload, ApiError>;
 pub trait RequestHandler {
     fn vm_create(&mut self, config: Arc<Mutex<VmConfig>>) -> Result<(), VmError>;
 
-    fn vm_boot(&mut self) -> Result<(), VmError>;
+    fn vm_boot(&mut self, identity: Option<VmIdentityConfig>) -> Result<(), VmError>;
 
     fn vm_pause(&mut self) -> Result<(), VmError>;
 
     fn vm_resume(&mut self) -> Result<(), VmError>;
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> Result<(), VmError>;
+    fn vm_snapshot(
+        &mut self,
+        destination_url: &str,
+        key_fd: Option<i32>,
+        compress: bool,
+    ) -> Result<(), VmError>;
 
     fn vm_restore(&mut self, restore_cfg: RestoreConfig) -> Result<(), VmError>;
 
@@ -310,6 +820,25 @@ pub trait RequestHandler {
 
     fn vmm_ping(&self) -> VmmPingResponse;
 
+    fn vmm_threads(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vmm_host_info(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vmm_capabilities(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vmm_fds(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vmm_checkpoint(&mut self, destination_url: &str) -> Result<(), VmError>;
+
+    fn vmm_post_restore(&mut self) -> Result<(), VmError>;
+
+    fn vmm_metrics(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vmm_profile(&self) -> Result<Option<Vec<u8>>, VmError>;
+
+    #[cfg(feature = "pprof")]
+    fn vmm_pprof(&self, pprof_data: VmmPprofData) -> Result<(), VmError>;
+
     fn vm_delete(&mut self) -> Result<(), VmError>;
 
     fn vmm_shutdown(&mut self) -> Result<(), VmError>;
@@ -323,6 +852,14 @@ pub trait RequestHandler {
 
     fn vm_resize_zone(&mut self, id: String, desired_ram: u64) -> Result<(), VmError>;
 
+    fn vm_set_boot_params(&mut self, boot_params: VmSetBootParamsData) -> Result<(), VmError>;
+
+    fn vm_rekey(&mut self, rekey_data: VmRekeyData) -> Result<(), VmError>;
+
+    fn vm_stage_config_change(&mut self, change: StagedConfigChange) -> Result<(), VmError>;
+
+    fn vm_update_config(&mut self, patch: serde_json::Value) -> Result<(), VmError>;
+
     fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> Result<Option<Vec<u8>>, VmError>;
 
     fn vm_add_user_device(
@@ -332,6 +869,12 @@ pub trait RequestHandler {
 
     fn vm_remove_device(&mut self, id: String) -> Result<(), VmError>;
 
+    fn vm_replace_device(
+        &mut self,
+        id: String,
+        replacement: ReplacementDeviceConfig,
+    ) -> Result<(), VmError>;
+
     fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> Result<Option<Vec<u8>>, VmError>;
 
     fn vm_add_fs(&mut self, fs_cfg: FsConfig) -> Result<Option<Vec<u8>>, VmError>;
@@ -344,10 +887,24 @@ pub trait RequestHandler {
 
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_add_usb(&mut self, usb_cfg: UsbConfig) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_counters(&mut self) -> Result<Option<Vec<u8>>, VmError>;
 
+    fn vm_vcpu_stats(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_migration_blockers(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_capabilities(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_device_tree(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
+    fn vm_devices(&mut self) -> Result<Option<Vec<u8>>, VmError>;
+
     fn vm_power_button(&mut self) -> Result<(), VmError>;
 
+    fn vm_suspend_to_ram(&mut self) -> Result<(), VmError>;
+
     fn vm_receive_migration(
         &mut self,
         receive_data_migration: VmReceiveMigrationData,
@@ -359,6 +916,24 @@ pub trait RequestHandler {
     ) -> Result<(), MigratableError>;
 
     fn vm_nmi(&mut self) -> Result<(), VmError>;
+
+    fn vm_sysrq(&mut self, key: char) -> Result<(), VmError>;
+
+    fn vm_screenshot(&mut self, destination_url: &str) -> Result<(), VmError>;
+
+    /// Checks the memory dump can be done, returning the dump to write.
+    fn vm_dump_memory(&mut self, dump_memory_data: VmDumpMemoryData)
+        -> Result<MemoryDump, VmError>;
+
+    /// Runs the request as the job `id` on its own thread, unless the job
+    /// got cancelled in the meantime.
+    fn job_run(&mut self, id: u64, request: JobRequest);
+
+    #[cfg(feature = "introspection")]
+    fn vm_introspect(
+        &mut self,
+        introspect_data: VmIntrospectData,
+    ) -> Result<Option<Vec<u8>>, VmError>;
 }
 
 /// It would be nice if we could pass around an object like this:
@@ -682,6 +1257,43 @@ impl ApiAction for VmAddVsock {
     }
 }
 
+pub struct VmAddUsb;
+
+impl ApiAction for VmAddUsb {
+    type RequestBody = UsbConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmAddUsb {:?}", config);
+
+            let response = vmm
+                .vm_add_usb(config)
+                .map_err(ApiError::VmAddUsb)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
 pub struct VmAddUserDevice;
 
 impl ApiAction for VmAddUserDevice {
@@ -722,15 +1334,19 @@ impl ApiAction for VmAddUserDevice {
 pub struct VmBoot;
 
 impl ApiAction for VmBoot {
-    type RequestBody = ();
+    type RequestBody = Option<VmIdentityConfig>;
     type ResponseBody = Option<Body>;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        identity: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmBoot");
+            info!("API request event: VmBoot {:?}", identity);
 
             let response = vmm
-                .vm_boot()
+                .vm_boot(identity)
                 .map_err(ApiError::VmBoot)
                 .map(|_| ApiResponsePayload::Empty);
 
@@ -824,24 +1440,20 @@ impl ApiAction for VmCounters {
     }
 }
 
-pub struct VmCreate;
+pub struct VmVcpuStats;
 
-impl ApiAction for VmCreate {
-    type RequestBody = Arc<Mutex<VmConfig>>;
-    type ResponseBody = ();
+impl ApiAction for VmVcpuStats {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
 
-    fn request(
-        &self,
-        config: Self::RequestBody,
-        response_sender: Sender<ApiResponse>,
-    ) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmCreate {:?}", config);
+            info!("API request event: VmVcpuStats");
 
             let response = vmm
-                .vm_create(config)
-                .map_err(ApiError::VmCreate)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_vcpu_stats()
+                .map_err(ApiError::VmVcpuStats)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -856,27 +1468,25 @@ impl ApiAction for VmCreate {
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
         data: Self::RequestBody,
-    ) -> ApiResult<()> {
-        get_response(self, api_evt, api_sender, data)?;
-
-        Ok(())
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
     }
 }
 
-pub struct VmDelete;
+pub struct VmMigrationBlockers;
 
-impl ApiAction for VmDelete {
+impl ApiAction for VmMigrationBlockers {
     type RequestBody = ();
     type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmDelete");
+            info!("API request event: VmMigrationBlockers");
 
             let response = vmm
-                .vm_delete()
-                .map_err(ApiError::VmDelete)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_migration_blockers()
+                .map_err(ApiError::VmMigrationBlockers)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -896,20 +1506,20 @@ impl ApiAction for VmDelete {
     }
 }
 
-pub struct VmInfo;
+pub struct VmCapabilities;
 
-impl ApiAction for VmInfo {
+impl ApiAction for VmCapabilities {
     type RequestBody = ();
-    type ResponseBody = VmInfoResponse;
+    type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmInfo");
+            info!("API request event: VmCapabilities");
 
             let response = vmm
-                .vm_info()
-                .map_err(ApiError::VmInfo)
-                .map(ApiResponsePayload::VmInfo);
+                .vm_capabilities()
+                .map_err(ApiError::VmCapabilities)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -923,31 +1533,26 @@ impl ApiAction for VmInfo {
         &self,
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
-        data: (),
-    ) -> ApiResult<VmInfoResponse> {
-        let vm_info = get_response(self, api_evt, api_sender, data)?;
-
-        match vm_info {
-            ApiResponsePayload::VmInfo(info) => Ok(info),
-            _ => Err(ApiError::ResponsePayloadType),
-        }
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
     }
 }
 
-pub struct VmPause;
+pub struct VmDeviceTree;
 
-impl ApiAction for VmPause {
+impl ApiAction for VmDeviceTree {
     type RequestBody = ();
     type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmPause");
+            info!("API request event: VmDeviceTree");
 
             let response = vmm
-                .vm_pause()
-                .map_err(ApiError::VmPause)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_device_tree()
+                .map_err(ApiError::VmDeviceTree)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -967,20 +1572,20 @@ impl ApiAction for VmPause {
     }
 }
 
-pub struct VmPowerButton;
+pub struct VmDevices;
 
-impl ApiAction for VmPowerButton {
+impl ApiAction for VmDevices {
     type RequestBody = ();
     type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmPowerButton");
+            info!("API request event: VmDevices");
 
             let response = vmm
-                .vm_power_button()
-                .map_err(ApiError::VmPowerButton)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_devices()
+                .map_err(ApiError::VmDevices)
+                .map(ApiResponsePayload::VmAction);
 
             response_sender
                 .send(response)
@@ -1000,19 +1605,23 @@ impl ApiAction for VmPowerButton {
     }
 }
 
-pub struct VmReboot;
+pub struct VmCreate;
 
-impl ApiAction for VmReboot {
-    type RequestBody = ();
-    type ResponseBody = Option<Body>;
+impl ApiAction for VmCreate {
+    type RequestBody = Arc<Mutex<VmConfig>>;
+    type ResponseBody = ();
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmReboot");
+            info!("API request event: VmCreate {:?}", config);
 
             let response = vmm
-                .vm_reboot()
-                .map_err(ApiError::VmReboot)
+                .vm_create(config)
+                .map_err(ApiError::VmCreate)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1028,24 +1637,26 @@ impl ApiAction for VmReboot {
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
         data: Self::RequestBody,
-    ) -> ApiResult<Self::ResponseBody> {
-        get_response_body(self, api_evt, api_sender, data)
+    ) -> ApiResult<()> {
+        get_response(self, api_evt, api_sender, data)?;
+
+        Ok(())
     }
 }
 
-pub struct VmReceiveMigration;
+pub struct VmDelete;
 
-impl ApiAction for VmReceiveMigration {
-    type RequestBody = VmReceiveMigrationData;
+impl ApiAction for VmDelete {
+    type RequestBody = ();
     type ResponseBody = Option<Body>;
 
-    fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmReceiveMigration {:?}", data);
+            info!("API request event: VmDelete");
 
             let response = vmm
-                .vm_receive_migration(data)
-                .map_err(ApiError::VmReceiveMigration)
+                .vm_delete()
+                .map_err(ApiError::VmDelete)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1066,24 +1677,20 @@ impl ApiAction for VmReceiveMigration {
     }
 }
 
-pub struct VmRemoveDevice;
+pub struct VmInfo;
 
-impl ApiAction for VmRemoveDevice {
-    type RequestBody = VmRemoveDeviceData;
-    type ResponseBody = Option<Body>;
+impl ApiAction for VmInfo {
+    type RequestBody = ();
+    type ResponseBody = VmInfoResponse;
 
-    fn request(
-        &self,
-        remove_device_data: Self::RequestBody,
-        response_sender: Sender<ApiResponse>,
-    ) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmRemoveDevice {:?}", remove_device_data);
+            info!("API request event: VmInfo");
 
             let response = vmm
-                .vm_remove_device(remove_device_data.id)
-                .map_err(ApiError::VmRemoveDevice)
-                .map(|_| ApiResponsePayload::Empty);
+                .vm_info()
+                .map_err(ApiError::VmInfo)
+                .map(ApiResponsePayload::VmInfo);
 
             response_sender
                 .send(response)
@@ -1097,33 +1704,30 @@ impl ApiAction for VmRemoveDevice {
         &self,
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
-        data: Self::RequestBody,
-    ) -> ApiResult<Self::ResponseBody> {
-        get_response_body(self, api_evt, api_sender, data)
+        data: (),
+    ) -> ApiResult<VmInfoResponse> {
+        let vm_info = get_response(self, api_evt, api_sender, data)?;
+
+        match vm_info {
+            ApiResponsePayload::VmInfo(info) => Ok(info),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
     }
 }
 
-pub struct VmResize;
+pub struct VmPause;
 
-impl ApiAction for VmResize {
-    type RequestBody = VmResizeData;
+impl ApiAction for VmPause {
+    type RequestBody = ();
     type ResponseBody = Option<Body>;
 
-    fn request(
-        &self,
-        resize_data: Self::RequestBody,
-        response_sender: Sender<ApiResponse>,
-    ) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmResize {:?}", resize_data);
+            info!("API request event: VmPause");
 
             let response = vmm
-                .vm_resize(
-                    resize_data.desired_vcpus,
-                    resize_data.desired_ram,
-                    resize_data.desired_balloon,
-                )
-                .map_err(ApiError::VmResize)
+                .vm_pause()
+                .map_err(ApiError::VmPause)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1144,23 +1748,19 @@ impl ApiAction for VmResize {
     }
 }
 
-pub struct VmResizeZone;
+pub struct VmPowerButton;
 
-impl ApiAction for VmResizeZone {
-    type RequestBody = VmResizeZoneData;
+impl ApiAction for VmPowerButton {
+    type RequestBody = ();
     type ResponseBody = Option<Body>;
 
-    fn request(
-        &self,
-        resize_zone_data: Self::RequestBody,
-        response_sender: Sender<ApiResponse>,
-    ) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmResizeZone {:?}", resize_zone_data);
+            info!("API request event: VmPowerButton");
 
             let response = vmm
-                .vm_resize_zone(resize_zone_data.id, resize_zone_data.desired_ram)
-                .map_err(ApiError::VmResizeZone)
+                .vm_power_button()
+                .map_err(ApiError::VmPowerButton)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1181,23 +1781,19 @@ impl ApiAction for VmResizeZone {
     }
 }
 
-pub struct VmRestore;
+pub struct VmSuspendToRam;
 
-impl ApiAction for VmRestore {
-    type RequestBody = RestoreConfig;
+impl ApiAction for VmSuspendToRam {
+    type RequestBody = ();
     type ResponseBody = Option<Body>;
 
-    fn request(
-        &self,
-        config: Self::RequestBody,
-        response_sender: Sender<ApiResponse>,
-    ) -> ApiRequest {
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmRestore {:?}", config);
+            info!("API request event: VmSuspendToRam");
 
             let response = vmm
-                .vm_restore(config)
-                .map_err(ApiError::VmRestore)
+                .vm_suspend_to_ram()
+                .map_err(ApiError::VmSuspendToRam)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1218,19 +1814,19 @@ impl ApiAction for VmRestore {
     }
 }
 
-pub struct VmResume;
+pub struct VmReboot;
 
-impl ApiAction for VmResume {
+impl ApiAction for VmReboot {
     type RequestBody = ();
     type ResponseBody = Option<Body>;
 
     fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmResume");
+            info!("API request event: VmReboot");
 
             let response = vmm
-                .vm_resume()
-                .map_err(ApiError::VmResume)
+                .vm_reboot()
+                .map_err(ApiError::VmReboot)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1251,19 +1847,19 @@ impl ApiAction for VmResume {
     }
 }
 
-pub struct VmSendMigration;
+pub struct VmReceiveMigration;
 
-impl ApiAction for VmSendMigration {
-    type RequestBody = VmSendMigrationData;
+impl ApiAction for VmReceiveMigration {
+    type RequestBody = VmReceiveMigrationData;
     type ResponseBody = Option<Body>;
 
     fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmSendMigration {:?}", data);
+            info!("API request event: VmReceiveMigration {:?}", data);
 
             let response = vmm
-                .vm_send_migration(data)
-                .map_err(ApiError::VmSendMigration)
+                .vm_receive_migration(data)
+                .map_err(ApiError::VmReceiveMigration)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1284,23 +1880,23 @@ impl ApiAction for VmSendMigration {
     }
 }
 
-pub struct VmShutdown;
+pub struct VmRemoveDevice;
 
-impl ApiAction for VmShutdown {
-    type RequestBody = ();
+impl ApiAction for VmRemoveDevice {
+    type RequestBody = VmRemoveDeviceData;
     type ResponseBody = Option<Body>;
 
     fn request(
         &self,
-        config: Self::RequestBody,
+        remove_device_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmShutdown {:?}", config);
+            info!("API request event: VmRemoveDevice {:?}", remove_device_data);
 
             let response = vmm
-                .vm_shutdown()
-                .map_err(ApiError::VmShutdown)
+                .vm_remove_device(remove_device_data.id)
+                .map_err(ApiError::VmRemoveDevice)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1321,23 +1917,26 @@ impl ApiAction for VmShutdown {
     }
 }
 
-pub struct VmSnapshot;
+pub struct VmReplaceDevice;
 
-impl ApiAction for VmSnapshot {
-    type RequestBody = VmSnapshotConfig;
+impl ApiAction for VmReplaceDevice {
+    type RequestBody = VmReplaceDeviceData;
     type ResponseBody = Option<Body>;
 
     fn request(
         &self,
-        config: Self::RequestBody,
+        replace_device_data: Self::RequestBody,
         response_sender: Sender<ApiResponse>,
     ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmSnapshot {:?}", config);
+            info!(
+                "API request event: VmReplaceDevice {:?}",
+                replace_device_data
+            );
 
             let response = vmm
-                .vm_snapshot(&config.destination_url)
-                .map_err(ApiError::VmSnapshot)
+                .vm_replace_device(replace_device_data.id, replace_device_data.replacement)
+                .map_err(ApiError::VmReplaceDevice)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1358,20 +1957,34 @@ impl ApiAction for VmSnapshot {
     }
 }
 
-pub struct VmmPing;
+pub struct VmResize;
 
-impl ApiAction for VmmPing {
-    type RequestBody = ();
-    type ResponseBody = VmmPingResponse;
+impl ApiAction for VmResize {
+    type RequestBody = VmResizeData;
+    type ResponseBody = Option<Body>;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        resize_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmmPing");
+            info!("API request event: VmResize {:?}", resize_data);
 
-            let response = ApiResponsePayload::VmmPing(vmm.vmm_ping());
+            let response = if resize_data.apply == ApplyMode::NextBoot {
+                vmm.vm_stage_config_change(StagedConfigChange::Resize(resize_data))
+            } else {
+                vmm.vm_resize(
+                    resize_data.desired_vcpus,
+                    resize_data.desired_ram,
+                    resize_data.desired_balloon,
+                )
+            }
+            .map_err(ApiError::VmResize)
+            .map(|_| ApiResponsePayload::Empty);
 
             response_sender
-                .send(Ok(response))
+                .send(response)
                 .map_err(VmmError::ApiResponseSend)?;
 
             Ok(false)
@@ -1382,60 +1995,106 @@ impl ApiAction for VmmPing {
         &self,
         api_evt: EventFd,
         api_sender: Sender<ApiRequest>,
-        data: (),
-    ) -> ApiResult<VmmPingResponse> {
-        let vmm_pong = get_response(self, api_evt, api_sender, data)?;
-
-        match vmm_pong {
-            ApiResponsePayload::VmmPing(pong) => Ok(pong),
-            _ => Err(ApiError::ResponsePayloadType),
-        }
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
     }
 }
 
-pub struct VmmShutdown;
+pub struct VmResizeZone;
 
-impl ApiAction for VmmShutdown {
-    type RequestBody = ();
-    type ResponseBody = ();
+impl ApiAction for VmResizeZone {
+    type RequestBody = VmResizeZoneData;
+    type ResponseBody = Option<Body>;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        resize_zone_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmmShutdown");
+            info!("API request event: VmResizeZone {:?}", resize_zone_data);
 
             let response = vmm
-                .vmm_shutdown()
-                .map_err(ApiError::VmmShutdown)
+                .vm_resize_zone(resize_zone_data.id, resize_zone_data.desired_ram)
+                .map_err(ApiError::VmResizeZone)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
                 .send(response)
                 .map_err(VmmError::ApiResponseSend)?;
 
-            Ok(true)
+            Ok(false)
         })
     }
 
-    fn send(&self, api_evt: EventFd, api_sender: Sender<ApiRequest>, data: ()) -> ApiResult<()> {
-        get_response(self, api_evt, api_sender, data)?;
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
 
-        Ok(())
+pub struct VmSetBootParams;
+
+impl ApiAction for VmSetBootParams {
+    type RequestBody = VmSetBootParamsData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        boot_params: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSetBootParams {:?}", boot_params);
+
+            let response = if boot_params.apply == ApplyMode::NextBoot {
+                vmm.vm_stage_config_change(StagedConfigChange::BootParams(boot_params))
+            } else {
+                vmm.vm_set_boot_params(boot_params)
+            }
+            .map_err(ApiError::VmSetBootParams)
+            .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
     }
 }
 
-pub struct VmNmi;
+pub struct VmRekey;
 
-impl ApiAction for VmNmi {
-    type RequestBody = ();
+impl ApiAction for VmRekey {
+    type RequestBody = VmRekeyData;
     type ResponseBody = Option<Body>;
 
-    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+    fn request(
+        &self,
+        rekey_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
         Box::new(move |vmm| {
-            info!("API request event: VmNmi");
+            info!("API request event: VmRekey {:?}", rekey_data);
 
             let response = vmm
-                .vm_nmi()
-                .map_err(ApiError::VmNmi)
+                .vm_rekey(rekey_data)
+                .map_err(ApiError::VmRekey)
                 .map(|_| ApiResponsePayload::Empty);
 
             response_sender
@@ -1455,3 +2114,787 @@ impl ApiAction for VmNmi {
         get_response_body(self, api_evt, api_sender, data)
     }
 }
+
+pub struct VmUpdateConfig;
+
+impl ApiAction for VmUpdateConfig {
+    type RequestBody = serde_json::Value;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        patch: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmUpdateConfig {}", patch);
+
+            let response = vmm
+                .vm_update_config(patch)
+                .map_err(ApiError::VmUpdateConfig)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmRestore;
+
+impl ApiAction for VmRestore {
+    type RequestBody = RestoreConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmRestore {:?}", config);
+
+            let response = vmm
+                .vm_restore(config)
+                .map_err(ApiError::VmRestore)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmResume;
+
+impl ApiAction for VmResume {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmResume");
+
+            let response = vmm
+                .vm_resume()
+                .map_err(ApiError::VmResume)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSendMigration;
+
+impl ApiAction for VmSendMigration {
+    type RequestBody = VmSendMigrationData;
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSendMigration {:?}", data);
+
+            let response = vmm
+                .vm_send_migration(data)
+                .map_err(ApiError::VmSendMigration)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmShutdown;
+
+impl ApiAction for VmShutdown {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmShutdown {:?}", config);
+
+            let response = vmm
+                .vm_shutdown()
+                .map_err(ApiError::VmShutdown)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSnapshot;
+
+impl ApiAction for VmSnapshot {
+    type RequestBody = VmSnapshotConfig;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        config: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSnapshot {:?}", config);
+
+            let response = vmm
+                .vm_snapshot(&config.destination_url, config.key_fd, config.compress)
+                .map_err(ApiError::VmSnapshot)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmPing;
+
+impl ApiAction for VmmPing {
+    type RequestBody = ();
+    type ResponseBody = VmmPingResponse;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmPing");
+
+            let response = ApiResponsePayload::VmmPing(vmm.vmm_ping());
+
+            response_sender
+                .send(Ok(response))
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: (),
+    ) -> ApiResult<VmmPingResponse> {
+        let vmm_pong = get_response(self, api_evt, api_sender, data)?;
+
+        match vmm_pong {
+            ApiResponsePayload::VmmPing(pong) => Ok(pong),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+}
+
+pub struct VmmThreads;
+
+impl ApiAction for VmmThreads {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmThreads");
+
+            let response = vmm
+                .vmm_threads()
+                .map_err(ApiError::VmmThreads)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmHostInfo;
+
+impl ApiAction for VmmHostInfo {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmHostInfo");
+
+            let response = vmm
+                .vmm_host_info()
+                .map_err(ApiError::VmmHostInfo)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmCapabilities;
+
+impl ApiAction for VmmCapabilities {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmCapabilities");
+
+            let response = vmm
+                .vmm_capabilities()
+                .map_err(ApiError::VmmCapabilities)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmFds;
+
+impl ApiAction for VmmFds {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmFds");
+
+            let response = vmm
+                .vmm_fds()
+                .map_err(ApiError::VmmFds)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmCheckpoint;
+
+impl ApiAction for VmmCheckpoint {
+    type RequestBody = VmmCheckpointData;
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, data: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmCheckpoint {:?}", data);
+
+            let response = vmm
+                .vmm_checkpoint(&data.destination_url)
+                .map_err(ApiError::VmmCheckpoint)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmPostRestore;
+
+impl ApiAction for VmmPostRestore {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmPostRestore");
+
+            let response = vmm
+                .vmm_post_restore()
+                .map_err(ApiError::VmmPostRestore)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmMetrics;
+
+impl ApiAction for VmmMetrics {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmMetrics");
+
+            let response = vmm
+                .vmm_metrics()
+                .map_err(ApiError::VmmMetrics)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmProfile;
+
+impl ApiAction for VmmProfile {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmProfile");
+
+            let response = vmm
+                .vmm_profile()
+                .map_err(ApiError::VmmProfile)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+#[cfg(feature = "pprof")]
+pub struct VmmPprof;
+
+#[cfg(feature = "pprof")]
+impl ApiAction for VmmPprof {
+    type RequestBody = VmmPprofData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        pprof_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmPprof {:?}", pprof_data);
+
+            let response = vmm
+                .vmm_pprof(pprof_data)
+                .map_err(ApiError::VmmPprof)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmmShutdown;
+
+impl ApiAction for VmmShutdown {
+    type RequestBody = ();
+    type ResponseBody = ();
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmmShutdown");
+
+            let response = vmm
+                .vmm_shutdown()
+                .map_err(ApiError::VmmShutdown)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(true)
+        })
+    }
+
+    fn send(&self, api_evt: EventFd, api_sender: Sender<ApiRequest>, data: ()) -> ApiResult<()> {
+        get_response(self, api_evt, api_sender, data)?;
+
+        Ok(())
+    }
+}
+
+pub struct VmNmi;
+
+impl ApiAction for VmNmi {
+    type RequestBody = ();
+    type ResponseBody = Option<Body>;
+
+    fn request(&self, _: Self::RequestBody, response_sender: Sender<ApiResponse>) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmNmi");
+
+            let response = vmm
+                .vm_nmi()
+                .map_err(ApiError::VmNmi)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmSysRq;
+
+impl ApiAction for VmSysRq {
+    type RequestBody = VmSysRqData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        sysrq_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmSysRq {:?}", sysrq_data);
+
+            let response = vmm
+                .vm_sysrq(sysrq_data.key)
+                .map_err(ApiError::VmSysRq)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmScreenshot;
+
+impl ApiAction for VmScreenshot {
+    type RequestBody = VmScreenshotData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        screenshot_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmScreenshot {:?}", screenshot_data);
+
+            let response = vmm
+                .vm_screenshot(&screenshot_data.destination_url)
+                .map_err(ApiError::VmScreenshot)
+                .map(|_| ApiResponsePayload::Empty);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+pub struct VmDumpMemory;
+
+impl ApiAction for VmDumpMemory {
+    type RequestBody = VmDumpMemoryData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        dump_memory_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmDumpMemory {:?}", dump_memory_data);
+
+            let dump = match vmm.vm_dump_memory(dump_memory_data) {
+                Ok(dump) => dump,
+                Err(e) => {
+                    response_sender
+                        .send(Err(ApiError::VmDumpMemory(e)))
+                        .map_err(VmmError::ApiResponseSend)?;
+                    return Ok(false);
+                }
+            };
+
+            // The memory is written from a thread of its own, for the VMM
+            // thread not to wait for it.
+            let dump_sender = response_sender.clone();
+            if let Err(e) = thread::Builder::new()
+                .name("vmm_dump_memory".to_string())
+                .spawn(move || {
+                    let response = dump
+                        .write(&AtomicBool::new(false))
+                        .map_err(ApiError::VmDumpMemory)
+                        .map(|_| ApiResponsePayload::Empty);
+                    dump_sender.send(response).ok();
+                })
+            {
+                response_sender
+                    .send(Err(ApiError::VmDumpMemory(VmError::WriteMemoryDump(e))))
+                    .map_err(VmmError::ApiResponseSend)?;
+            }
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
+
+#[cfg(feature = "introspection")]
+pub struct VmIntrospect;
+
+#[cfg(feature = "introspection")]
+impl ApiAction for VmIntrospect {
+    type RequestBody = VmIntrospectData;
+    type ResponseBody = Option<Body>;
+
+    fn request(
+        &self,
+        introspect_data: Self::RequestBody,
+        response_sender: Sender<ApiResponse>,
+    ) -> ApiRequest {
+        Box::new(move |vmm| {
+            info!("API request event: VmIntrospect {:?}", introspect_data);
+
+            let response = vmm
+                .vm_introspect(introspect_data)
+                .map_err(ApiError::VmIntrospect)
+                .map(ApiResponsePayload::VmAction);
+
+            response_sender
+                .send(response)
+                .map_err(VmmError::ApiResponseSend)?;
+
+            Ok(false)
+        })
+    }
+
+    fn send(
+        &self,
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        data: Self::RequestBody,
+    ) -> ApiResult<Self::ResponseBody> {
+        get_response_body(self, api_evt, api_sender, data)
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the guest being booted.
+//!
+//! The vCPUs running doesn't tell whether the workload is up, hence the guest
+//! being considered booted on the first of: a marker printed on the serial
+//! port, the guest agent connecting to a vsock port, or the OSPM evaluating
+//! the status of the vCPUs while initializing the ACPI namespace.
+
+use crate::vm_config::{BootDetectConfig, VmConfig};
+use devices::legacy::OutputObserver;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootState {
+    Booting,
+    Booted,
+}
+
+// Finds a marker in a stream of bytes fed one at a time.
+struct MarkerMatcher {
+    marker: Vec<u8>,
+    matched: usize,
+}
+
+impl MarkerMatcher {
+    fn new(marker: &str) -> Self {
+        MarkerMatcher {
+            marker: marker.as_bytes().to_vec(),
+            matched: 0,
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> bool {
+        // Fall back onto the longest prefix of the marker which is still a
+        // suffix of the bytes received, the marker being short enough not to
+        // bother with precomputing the fallbacks.
+        let mut candidate = self.marker[..self.matched].to_vec();
+        candidate.push(byte);
+        self.matched = (1..=candidate.len().min(self.marker.len()))
+            .rev()
+            .find(|&len| candidate.ends_with(&self.marker[..len]))
+            .unwrap_or(0);
+
+        if self.matched == self.marker.len() {
+            self.matched = 0;
+            return true;
+        }
+
+        false
+    }
+}
+
+pub struct BootDetector {
+    booted: AtomicBool,
+    // Socket the guest agent connects to through the vsock device
+    listener: Mutex<Option<(UnixListener, PathBuf)>>,
+}
+
+impl BootDetector {
+    pub fn new(config: &BootDetectConfig, vm_config: &VmConfig) -> io::Result<Arc<Self>> {
+        let listener = match (config.vsock_port, &vm_config.vsock) {
+            (Some(port), Some(vsock)) => {
+                // Connections initiated by the guest to a host port are
+                // forwarded to "<socket>_<port>" by the vsock device.
+                let mut path = vsock.socket.clone().into_os_string();
+                path.push(format!("_{port}"));
+                let path = PathBuf::from(path);
+                let listener = UnixListener::bind(&path)?;
+                listener.set_nonblocking(true)?;
+                Some((listener, path))
+            }
+            _ => None,
+        };
+
+        Ok(Arc::new(BootDetector {
+            booted: AtomicBool::new(false),
+            listener: Mutex::new(listener),
+        }))
+    }
+
+    pub fn state(&self) -> BootState {
+        if self.booted.load(Ordering::Acquire) {
+            BootState::Booted
+        } else {
+            BootState::Booting
+        }
+    }
+
+    /// Records the guest as booted, `source` naming the detection method.
+    pub fn signal(&self, source: &str) {
+        if self.booted.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        info!("Guest booted, as detected through {}", source);
+        event!("vm", "guest-booted", "source", source);
+    }
+
+    pub fn console_observer(self: &Arc<Self>, marker: &str) -> OutputObserver {
+        let detector = Arc::clone(self);
+        let mut matcher = MarkerMatcher::new(marker);
+        Box::new(move |byte| {
+            if matcher.feed(byte) {
+                detector.signal("console");
+            }
+        })
+    }
+
+    pub fn listener_fd(&self) -> Option<RawFd> {
+        self.listener
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(listener, _)| listener.as_raw_fd())
+    }
+
+    /// Handles the guest agent connecting, the connection being closed right
+    /// away as its only purpose is to tell the guest is up.
+    pub fn accept(&self) {
+        let mut listener = self.listener.lock().unwrap();
+        let Some((l, _)) = listener.as_ref() else {
+            return;
+        };
+
+        match l.accept() {
+            Ok(_) => {
+                // Closing the listener removes it from the VMM epoll set
+                if let Some((_, path)) = listener.take() {
+                    fs::remove_file(path).ok();
+                }
+                drop(listener);
+                self.signal("vsock");
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => warn!("Error accepting guest agent connection: {}", e),
+        }
+    }
+}
+
+impl Drop for BootDetector {
+    fn drop(&mut self) {
+        if let Some((_, path)) = self.listener.lock().unwrap().take() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_matcher() {
+        let mut matcher = MarkerMatcher::new("login:");
+        assert!(!b"Welcome\r\nlogi".iter().any(|b| matcher.feed(*b)));
+        assert!(!matcher.feed(b'x'));
+        assert!(b"login:".iter().any(|b| matcher.feed(*b)));
+
+        // Overlapping prefix
+        let mut matcher = MarkerMatcher::new("aab");
+        assert!(b"aaab".iter().any(|b| matcher.feed(*b)));
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cleanup of the resources left behind by a crashed VMM.
+//!
+//! The UNIX sockets bound by the VMM outlive it, preventing a new instance
+//! from binding them again. The TAP interfaces created by the VMM aren't
+//! persistent and the image locks are OFD locks, both being released by the
+//! kernel along with the last file descriptor.
+
+use crate::vm_config::{ConsoleOutputMode, VhostMode, VmConfig};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use virtio_devices::vsock::DGRAM_SOCK_SUFFIX;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error opening pidfile {0}: {1}")]
+    PidFileOpen(PathBuf, #[source] io::Error),
+
+    #[error("Another instance (pid {1}) owns pidfile {0}")]
+    PidFileLocked(PathBuf, String),
+
+    #[error("Error writing pidfile {0}: {1}")]
+    PidFileWrite(PathBuf, #[source] io::Error),
+
+    #[error("Socket {0} is in use by another process")]
+    SocketInUse(PathBuf),
+
+    #[error("Error removing stale socket {0}: {1}")]
+    SocketRemove(PathBuf, #[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// File holding the pid of the VMM, locked for as long as the VMM runs so
+/// that a stale file can be told apart from the one of a running instance.
+pub struct PidFile {
+    path: PathBuf,
+    // Keeps the lock held
+    _file: File,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| Error::PidFileOpen(path.to_owned(), e))?;
+
+        // SAFETY: FFI call with a valid fd
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+                return Err(Error::PidFileLocked(path.to_owned(), pid.trim().to_owned()));
+            }
+            return Err(Error::PidFileOpen(path.to_owned(), e));
+        }
+
+        // Whatever is left comes from a previous instance which is gone
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| Error::PidFileWrite(path.to_owned(), e))?;
+
+        Ok(PidFile {
+            path: path.to_owned(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Error removing pidfile {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Tells whether a process is listening on the socket, trying a datagram
+// socket when the path isn't bound to a stream socket.
+fn socket_in_use(path: &Path) -> io::Result<bool> {
+    let e = match UnixStream::connect(path) {
+        Ok(_) => return Ok(true),
+        Err(e) => e,
+    };
+    if e.raw_os_error() != Some(libc::EPROTOTYPE) {
+        return if e.raw_os_error() == Some(libc::ECONNREFUSED) {
+            Ok(false)
+        } else {
+            Err(e)
+        };
+    }
+
+    match UnixDatagram::unbound()?.connect(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ECONNREFUSED) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the socket at `path` if no process listens on it anymore,
+/// returning whether it has been removed. Anything else than a socket is
+/// left untouched.
+pub fn remove_stale_socket(path: &Path) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(false),
+    }
+
+    match socket_in_use(path) {
+        Ok(false) => {}
+        Ok(true) => return Err(Error::SocketInUse(path.to_owned())),
+        Err(e) => return Err(Error::SocketRemove(path.to_owned(), e)),
+    }
+
+    fs::remove_file(path).map_err(|e| Error::SocketRemove(path.to_owned(), e))?;
+    info!("Removed stale socket {}", path.display());
+
+    Ok(true)
+}
+
+/// UNIX sockets the VMM binds for the VM described by `vm_config`.
+pub fn vm_sockets(vm_config: &VmConfig) -> Vec<PathBuf> {
+    let mut sockets = Vec::new();
+
+    for console in [&vm_config.serial, &vm_config.console] {
+        if console.mode == ConsoleOutputMode::Socket {
+            sockets.extend(console.socket.clone());
+        }
+    }
+
+    if let Some(vsock) = &vm_config.vsock {
+        sockets.push(vsock.socket.clone());
+        let mut dgram_socket = vsock.socket.clone().into_os_string();
+        dgram_socket.push(DGRAM_SOCK_SUFFIX);
+        sockets.push(dgram_socket.into());
+    }
+
+    #[cfg(feature = "vnc")]
+    sockets.extend(vm_config.vnc.as_ref().map(|vnc| vnc.socket.clone()));
+
+    if let Some(net) = &vm_config.net {
+        sockets.extend(
+            net.iter()
+                .filter(|n| n.vhost_user && n.vhost_mode == VhostMode::Server)
+                .filter_map(|n| n.vhost_socket.as_ref().map(PathBuf::from)),
+        );
+    }
+
+    sockets
+}
+
+/// Removes the stale sockets among `sockets`, failing if one of them is
+/// still in use.
+pub fn cleanup_sockets(sockets: &[PathBuf]) -> Result<()> {
+    for socket in sockets {
+        remove_stale_socket(socket)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("api.sock");
+
+        assert!(!remove_stale_socket(&path).unwrap());
+
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(matches!(
+            remove_stale_socket(&path),
+            Err(Error::SocketInUse(_))
+        ));
+
+        drop(listener);
+        assert!(remove_stale_socket(&path).unwrap());
+        assert!(!path.exists());
+
+        let dgram_path = dir.as_path().join("vsock.sock_dgram");
+        let dgram = UnixDatagram::bind(&dgram_path).unwrap();
+        assert!(remove_stale_socket(&dgram_path).is_err());
+        drop(dgram);
+        assert!(remove_stale_socket(&dgram_path).unwrap());
+
+        let file_path = dir.as_path().join("file");
+        File::create(&file_path).unwrap();
+        assert!(!remove_stale_socket(&file_path).unwrap());
+        assert!(file_path.exists());
+    }
+
+    #[test]
+    fn test_pidfile() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("vmm.pid");
+
+        // Stale content from a crashed instance
+        fs::write(&path, "999999\n").unwrap();
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        assert!(matches!(
+            PidFile::create(&path),
+            Err(Error::PidFileLocked(_, pid)) if pid == std::process::id().to_string()
+        ));
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -5,6 +5,7 @@
 
 pub use crate::vm_config::*;
 use clap::ArgMatches;
+use net_util::{MacAddr, MAC_ADDR_LEN};
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
 };
@@ -28,6 +29,16 @@ pub enum Error {
     ParseFsTagTooLong,
     /// Filesystem socket is missing
     ParseFsSockMissing,
+    /// Filesystem shared directory is missing
+    ParseFsSharedDirMissing,
+    /// Filesystem socket specified for a built-in device
+    ParseFsSockWithBuiltin,
+    /// Failed parsing virtio-9p parameters
+    ParseP9(OptionParserError),
+    /// virtio-9p mount tag is missing
+    ParseP9TagMissing,
+    /// virtio-9p shared directory is missing
+    ParseP9PathMissing,
     /// Missing persistent memory file parameter.
     ParsePmemFileMissing,
     /// Missing vsock socket path parameter.
@@ -50,8 +61,12 @@ pub enum Error {
     ParseRateLimiterGroup(OptionParserError),
     /// Error parsing disk options
     ParseDisk(OptionParserError),
+    /// Both disk path and overlay image specified
+    ParseDiskPathAndOverlay,
     /// Error parsing network options
     ParseNetwork(OptionParserError),
+    /// Both MAC address and MAC address pool specified
+    ParseNetworkMacAndMacPool,
     /// Error parsing RNG options
     ParseRng(OptionParserError),
     /// Error parsing balloon options
@@ -106,10 +121,34 @@ pub enum Error {
     ParseVdpa(OptionParserError),
     /// Missing path for vDPA device
     ParseVdpaPathMissing,
+    /// Failed parsing host USB device
+    ParseUsb(OptionParserError),
+    /// Missing path for host USB device
+    ParseUsbPathMissing,
     /// Failed parsing TPM device
     ParseTpm(OptionParserError),
     /// Missing path for TPM device
     ParseTpmPathMissing,
+    /// Failed parsing realtime profile
+    ParseRt(OptionParserError),
+    /// Failed parsing payload verification parameters
+    ParsePayloadVerification(OptionParserError),
+    /// Missing trusted certificates for payload verification
+    ParsePayloadVerificationCertsMissing,
+    /// Failed parsing restart policy
+    ParseRestartPolicy(OptionParserError),
+    /// More than one restart mode requested
+    ParseRestartPolicyConflictingModes,
+    /// Failed parsing boot detection parameters
+    ParseBootDetect(OptionParserError),
+    /// No boot detection method provided
+    ParseBootDetectMethodMissing,
+    #[cfg(feature = "vnc")]
+    /// Failed parsing VNC server parameters
+    ParseVnc(OptionParserError),
+    #[cfg(feature = "vnc")]
+    /// Missing socket for the VNC server
+    ParseVncSocketMissing,
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -122,11 +161,32 @@ pub enum ValidationError {
     ConsoleSocketPathMissing,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
+    /// SVE vector length not a multiple of 128 bits between 128 and 2048
+    InvalidSveVectorLength(u16),
+    /// SVE and PMU event filtering are only supported on AArch64
+    CpuOptionUnsupported(&'static str),
+    /// Enlightened VMCS is part of the HyperV emulation
+    #[cfg(target_arch = "x86_64")]
+    EvmcsWithoutKvmHyperv,
     /// Missing file value for debug-console
     #[cfg(target_arch = "x86_64")]
     DebugconFileMissing,
     /// Both socket and path specified
     DiskSocketAndPath,
+    /// Both file descriptor and path (or socket) specified
+    DiskFdAndPath,
+    /// Using reserved fd for a disk
+    DiskReservedFd,
+    /// Read-ahead relies on the host page cache
+    DiskReadAheadUnsupported,
+    /// Base image given without any overlay image path
+    DiskBaseWithoutOverlay,
+    /// Overlay to be created without any base image
+    DiskOverlayWithoutBase,
+    /// Ephemeral overlay of anything but the image at the disk path
+    DiskEphemeralUnsupported,
+    /// Secure erase only applies to ephemeral overlays
+    DiskSecureEraseWithoutEphemeral,
     /// Using vhost user requires shared memory
     VhostUserRequiresSharedMemory,
     /// No socket provided for vhost_use
@@ -150,10 +210,38 @@ pub enum ValidationError {
     VnetReservedFd,
     /// Hardware checksum offload is disabled.
     NoHardwareChecksumOffload,
+    /// Bridge attachment requires the VMM to create the TAP interface
+    NetBridgeRequiresTap,
+    /// VLAN specified without any bridge
+    NetVlanWithoutBridge,
+    /// Egress shaping requires the VMM to create the TAP interface
+    NetEgressShapingRequiresTap,
+    /// IPv6 autoconfiguration requires the VMM to create the TAP interface
+    NetIpv6RequiresTap,
+    /// Invalid IPv6 prefix length
+    InvalidIpv6PrefixLen(u8),
+    /// Router advertisements enabled without any IPv6 address
+    NetIpv6RaWithoutAddress,
+    /// Instance identifier given without connecting to a vhost-user backend
+    NetVhostInstanceIdRequiresClient,
+    /// Invalid vhost-user instance identifier
+    InvalidVhostInstanceId(String),
+    /// Invalid VLAN identifier
+    InvalidVlanId(u16),
+    /// Option not supported by the emulated e1000e NIC
+    E1000eUnsupportedOption(&'static str),
+    /// Invalid MAC address pool
+    InvalidMacPool(String),
+    /// No free MAC address left in the pool
+    MacPoolExhausted(String),
     /// Hugepages not turned on
     HugePageSizeWithoutHugePages,
     /// Huge page size is not power of 2
     InvalidHugePageSize(u64),
+    /// Locked memory can't be reclaimed through the balloon
+    LockedMemoryWithBalloon,
+    /// Locked memory can't be unplugged through virtio-mem
+    LockedMemoryWithVirtioMem,
     /// CPU Hotplug is not permitted with TDX
     #[cfg(feature = "tdx")]
     TdxNoCpuHotplug,
@@ -162,14 +250,30 @@ pub enum ValidationError {
     TdxFirmwareMissing,
     /// Insufficient vCPUs for queues
     TooManyQueues,
+    /// Built-in virtio-fs device without any shared directory
+    FsBuiltinSharedDirMissing,
+    /// Built-in virtio-fs device support is not compiled in
+    FsBuiltinNotSupported,
+    /// Read-only virtio-fs share without the built-in device
+    FsReadonlyNotBuiltin,
+    /// virtio-9p device support is not compiled in
+    P9NotSupported,
     /// Need shared memory for vfio-user
     UserDevicesRequireSharedMemory,
     /// VSOCK Context Identifier has a special meaning, unsuitable for a VM.
     VsockSpecialCid(u32),
     /// Memory zone is reused across NUMA nodes
     MemoryZoneReused(String, u32, u32),
+    /// Memory zone can't be shared with or from another VMM
+    InvalidMemoryZoneSharing(String),
     /// Invalid number of PCI segments
     InvalidNumPciSegments(u16),
+    /// Device tree overlays are only supported on AArch64
+    DtOverlayUnsupported,
+    /// Invalid size of the 32-bit MMIO aperture
+    InvalidMmio32Size(u64),
+    /// Invalid size of the 64-bit MMIO aperture
+    InvalidMmio64Size(u64),
     /// Invalid PCI segment id
     InvalidPciSegment(u16),
     /// Invalid PCI segment aperture weight
@@ -188,6 +292,8 @@ pub enum ValidationError {
     IommuNotSupported,
     /// Duplicated device path (device added twice)
     DuplicateDevicePath(String),
+    /// Duplicated host USB device path (device added twice)
+    DuplicateUsbDevicePath(String),
     /// Provided MTU is lower than what the VIRTIO specification expects
     InvalidMtu(u16),
     /// PCI segment is reused across NUMA nodes
@@ -201,6 +307,33 @@ pub enum ValidationError {
     InvalidIoPortHex(String),
     #[cfg(feature = "sev_snp")]
     InvalidHostData,
+    /// SCHED_FIFO priority out of range
+    InvalidRtPriority(u8),
+    /// Fewer host CPUs than vCPUs for the realtime profile
+    RtNotEnoughCpus(usize, u8),
+    /// Realtime profile combined with explicit vCPU affinity
+    RtWithCpuAffinity,
+    /// Serial output requested while the serial port is suppressed
+    SerialLegacyDeviceDisabled,
+    /// Payload verification not compiled in
+    PayloadVerificationUnsupported,
+    /// Payload verification only applies to direct kernel boot
+    PayloadVerificationWithoutKernel,
+    /// Payload to be loaded without its signature
+    PayloadSignatureMissing(&'static str),
+    /// Restart limit set to zero
+    InvalidRestartMax,
+    /// Boot detection on the serial port while it is disabled
+    BootDetectWithoutSerial,
+    /// Boot detection through vsock without a vsock device
+    BootDetectWithoutVsock,
+    #[cfg(feature = "vnc")]
+    /// VNC server without a framebuffer to display
+    VncWithoutRamfb,
+    /// Identity given to a device the VM doesn't have
+    IdentityDeviceNotFound(String),
+    /// Identity given to a device which can't take it
+    IdentityUnsupported(String),
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -213,9 +346,43 @@ impl fmt::Display for ValidationError {
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
             ConsoleSocketPathMissing => write!(f, "Path missing when using socket console mode"),
             CpusMaxLowerThanBoot => write!(f, "Max CPUs lower than boot CPUs"),
+            InvalidSveVectorLength(vl) => write!(
+                f,
+                "Invalid SVE vector length {vl}, must be a multiple of 128 between 128 and 2048"
+            ),
+            CpuOptionUnsupported(option) => {
+                write!(
+                    f,
+                    "The {option} option of --cpus is only supported on AArch64"
+                )
+            }
+            #[cfg(target_arch = "x86_64")]
+            EvmcsWithoutKvmHyperv => {
+                write!(f, "The evmcs CPU feature requires kvm_hyperv=on")
+            }
             #[cfg(target_arch = "x86_64")]
             DebugconFileMissing => write!(f, "Path missing when using file mode for debug console"),
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
+            DiskFdAndPath => write!(f, "Disk FD and path (or vhost socket) both provided"),
+            DiskReservedFd => write!(f, "Reserved fd number (<= 2) used for disk"),
+            DiskReadAheadUnsupported => write!(
+                f,
+                "Disk read-ahead is not supported with direct I/O or vhost-user"
+            ),
+            DiskBaseWithoutOverlay => {
+                write!(f, "Disk base image requires the path of an overlay image")
+            }
+            DiskOverlayWithoutBase => {
+                write!(f, "Disk overlay can't be created without a base image")
+            }
+            DiskEphemeralUnsupported => write!(
+                f,
+                "Ephemeral disk requires a path and is not supported with fd, readonly, \
+                base or vhost-user"
+            ),
+            DiskSecureEraseWithoutEphemeral => {
+                write!(f, "Disk secure erase requires ephemeral=on")
+            }
             VhostUserRequiresSharedMemory => {
                 write!(
                     f,
@@ -242,12 +409,59 @@ impl fmt::Display for ValidationError {
                 f,
                 "\"offload_tso\" and \"offload_ufo\" depend on \"offload_tso\""
             ),
+            NetBridgeRequiresTap => write!(
+                f,
+                "Bridge attachment is incompatible with \"fd\" and \"vhost_user\""
+            ),
+            NetVlanWithoutBridge => write!(f, "VLAN specified without any bridge"),
+            NetEgressShapingRequiresTap => write!(
+                f,
+                "Egress shaping is incompatible with \"fd\" and \"vhost_user\""
+            ),
+            NetIpv6RequiresTap => write!(
+                f,
+                "IPv6 autoconfiguration is incompatible with \"fd\", \"vhost_user\" and \"bridge\""
+            ),
+            InvalidIpv6PrefixLen(len) => {
+                write!(f, "Invalid IPv6 prefix length {len} (expected 1-128)")
+            }
+            NetIpv6RaWithoutAddress => {
+                write!(
+                    f,
+                    "Router advertisements enabled without any \"ipv6\" address"
+                )
+            }
+            NetVhostInstanceIdRequiresClient => write!(
+                f,
+                "\"vhost_instance_id\" requires \"vhost_user=on\" and \"vhost_mode=client\""
+            ),
+            InvalidVhostInstanceId(s) => write!(
+                f,
+                "Invalid vhost-user instance identifier \"{s}\" (expected 1-{} bytes)",
+                virtio_devices::vhost_user::INSTANCE_ID_MAX_LEN
+            ),
+            InvalidVlanId(vlan) => write!(f, "Invalid VLAN identifier {vlan} (expected 1-4094)"),
+            E1000eUnsupportedOption(o) => {
+                write!(f, "\"{o}\" is not supported by the e1000e network model")
+            }
+            InvalidMacPool(s) => write!(
+                f,
+                "Invalid MAC address pool {s} (expected a unicast prefix such as \
+                52:54:00:xx:xx:xx)"
+            ),
+            MacPoolExhausted(s) => write!(f, "No free MAC address left in pool {s}"),
             HugePageSizeWithoutHugePages => {
                 write!(f, "Huge page size specified but huge pages not enabled")
             }
             InvalidHugePageSize(s) => {
                 write!(f, "Huge page size is not power of 2: {s}")
             }
+            LockedMemoryWithBalloon => {
+                write!(f, "Locked memory is not compatible with the balloon device")
+            }
+            LockedMemoryWithVirtioMem => {
+                write!(f, "Locked memory is not compatible with virtio-mem hotplug")
+            }
             #[cfg(feature = "tdx")]
             TdxNoCpuHotplug => {
                 write!(f, "CPU hotplug is not permitted with TDX")
@@ -256,6 +470,20 @@ impl fmt::Display for ValidationError {
             TdxFirmwareMissing => {
                 write!(f, "No TDX firmware specified")
             }
+            FsBuiltinSharedDirMissing => {
+                write!(f, "Built-in virtio-fs device requires a shared directory")
+            }
+            FsBuiltinNotSupported => write!(
+                f,
+                "Built-in virtio-fs device requires the \"fs_builtin\" feature"
+            ),
+            FsReadonlyNotBuiltin => write!(
+                f,
+                "Read-only virtio-fs shares require the built-in device (builtin=on)"
+            ),
+            P9NotSupported => {
+                write!(f, "virtio-9p device requires the \"virtio_9p\" feature")
+            }
             TooManyQueues => {
                 write!(f, "Number of vCPUs is insufficient for number of queues")
             }
@@ -274,12 +502,37 @@ impl fmt::Display for ValidationError {
                     "Memory zone: {s} belongs to multiple NUMA nodes {u1} and {u2}"
                 )
             }
+            InvalidMemoryZoneSharing(s) => {
+                write!(
+                    f,
+                    "Memory zone: {s} shared with another VMM needs shared=on, shared from \
+                    another VMM needs shared=off, and neither can use a backing file or hotplug"
+                )
+            }
             InvalidNumPciSegments(n) => {
                 write!(
                     f,
                     "Number of PCI segments ({n}) not in range of 1 to {MAX_NUM_PCI_SEGMENTS}"
                 )
             }
+            DtOverlayUnsupported => {
+                write!(f, "Device tree overlays are only supported on AArch64")
+            }
+            InvalidMmio32Size(size) => {
+                write!(
+                    f,
+                    "Invalid 32-bit MMIO aperture size: {size}, it must be a non-zero multiple \
+                    of 4KiB of at most {}MiB",
+                    arch::layout::MEM_32BIT_DEVICES_MAX_SIZE >> 20
+                )
+            }
+            InvalidMmio64Size(size) => {
+                write!(
+                    f,
+                    "Invalid 64-bit MMIO aperture size: {size}, it must be a non-zero multiple \
+                    of 4GiB"
+                )
+            }
             InvalidPciSegment(pci_segment) => {
                 write!(f, "Invalid PCI segment id: {pci_segment}")
             }
@@ -314,6 +567,7 @@ impl fmt::Display for ValidationError {
                 write!(f, "Device does not support being placed behind IOMMU")
             }
             DuplicateDevicePath(p) => write!(f, "Duplicated device path: {p}"),
+            DuplicateUsbDevicePath(p) => write!(f, "Duplicated USB device path: {p}"),
             &InvalidMtu(mtu) => {
                 write!(
                     f,
@@ -343,6 +597,155 @@ impl fmt::Display for ValidationError {
             InvalidHostData => {
                 write!(f, "Invalid host data format")
             }
+            InvalidRtPriority(p) => {
+                write!(f, "Realtime priority {p} not in range of 1 to 99")
+            }
+            RtNotEnoughCpus(cpus, vcpus) => {
+                write!(
+                    f,
+                    "Not enough host CPUs ({cpus}) to pin the {vcpus} vCPUs onto for the realtime profile"
+                )
+            }
+            RtWithCpuAffinity => {
+                write!(f, "Realtime profile can't be combined with vCPU affinity")
+            }
+            SerialLegacyDeviceDisabled => {
+                write!(
+                    f,
+                    "Serial output requires the serial legacy device to be enabled"
+                )
+            }
+            PayloadVerificationUnsupported => {
+                write!(
+                    f,
+                    "Payload verification requires the \"payload_verification\" feature"
+                )
+            }
+            PayloadVerificationWithoutKernel => {
+                write!(f, "Payload verification requires booting a kernel directly")
+            }
+            PayloadSignatureMissing(p) => {
+                write!(f, "Payload verification requires a signature for the {p}")
+            }
+            InvalidRestartMax => {
+                write!(f, "Restart policy limit must be greater than zero")
+            }
+            BootDetectWithoutSerial => {
+                write!(
+                    f,
+                    "Boot detection through a console marker requires the serial port"
+                )
+            }
+            BootDetectWithoutVsock => {
+                write!(f, "Boot detection through vsock requires a vsock device")
+            }
+            #[cfg(feature = "vnc")]
+            VncWithoutRamfb => {
+                write!(f, "The VNC server requires the ramfb device")
+            }
+            IdentityDeviceNotFound(id) => {
+                write!(f, "No device {id} to give the identity of the VM to")
+            }
+            IdentityUnsupported(id) => {
+                write!(f, "Device {id} can't be given the identity of the VM")
+            }
+        }
+    }
+}
+
+impl ValidationError {
+    /// Returns the section of the VM configuration the error relates to,
+    /// or None when the error can come from several sections.
+    pub fn field(&self) -> Option<&'static str> {
+        use self::ValidationError::*;
+        match self {
+            KernelMissing | PayloadVerificationUnsupported | PayloadVerificationWithoutKernel => {
+                Some("payload")
+            }
+            #[cfg(feature = "sev_snp")]
+            InvalidHostData => Some("payload"),
+            #[cfg(feature = "tdx")]
+            TdxFirmwareMissing => Some("payload"),
+            PayloadSignatureMissing(_) => Some("payload_verification"),
+            ConsoleFileMissing | ConsoleSocketPathMissing => Some("console"),
+            SerialLegacyDeviceDisabled => Some("serial"),
+            #[cfg(target_arch = "x86_64")]
+            DebugconFileMissing | InvalidIoPortHex(_) => Some("debug_console"),
+            CpusMaxLowerThanBoot
+            | CpuTopologyCount
+            | CpuTopologyZeroPart
+            | InvalidSveVectorLength(_)
+            | CpuOptionUnsupported(_) => Some("cpus"),
+            #[cfg(target_arch = "aarch64")]
+            CpuTopologyDiesPerPackage => Some("cpus"),
+            #[cfg(target_arch = "x86_64")]
+            EvmcsWithoutKvmHyperv => Some("cpus"),
+            #[cfg(feature = "tdx")]
+            TdxNoCpuHotplug => Some("cpus"),
+            DiskSocketAndPath
+            | DiskFdAndPath
+            | DiskReservedFd
+            | DiskReadAheadUnsupported
+            | DiskBaseWithoutOverlay
+            | DiskOverlayWithoutBase
+            | DiskEphemeralUnsupported
+            | DiskSecureEraseWithoutEphemeral => Some("disks"),
+            VnetQueueLowerThan2
+            | VnetQueueFdMismatch
+            | VnetReservedFd
+            | NoHardwareChecksumOffload
+            | NetBridgeRequiresTap
+            | NetVlanWithoutBridge
+            | NetEgressShapingRequiresTap
+            | NetIpv6RequiresTap
+            | InvalidIpv6PrefixLen(_)
+            | NetIpv6RaWithoutAddress
+            | NetVhostInstanceIdRequiresClient
+            | InvalidVhostInstanceId(_)
+            | InvalidVlanId(_)
+            | E1000eUnsupportedOption(_)
+            | InvalidMacPool(_)
+            | MacPoolExhausted(_)
+            | InvalidMtu(_) => Some("net"),
+            VhostUserRequiresSharedMemory
+            | UserDevicesRequireSharedMemory
+            | HugePageSizeWithoutHugePages
+            | InvalidHugePageSize(_)
+            | LockedMemoryWithBalloon
+            | LockedMemoryWithVirtioMem
+            | InvalidMemoryZoneSharing(_) => Some("memory"),
+            FsBuiltinSharedDirMissing | FsBuiltinNotSupported | FsReadonlyNotBuiltin => Some("fs"),
+            P9NotSupported => Some("p9"),
+            VsockSpecialCid(_) => Some("vsock"),
+            BalloonLargerThanRam(..) => Some("balloon"),
+            MemoryZoneReused(..) | PciSegmentReused(..) | DefaultPciSegmentInvalidNode(_) => {
+                Some("numa")
+            }
+            InvalidNumPciSegments(_)
+            | DtOverlayUnsupported
+            | InvalidMmio32Size(_)
+            | InvalidMmio64Size(_) => Some("platform"),
+            InvalidPciSegmentApertureWeight(_) => Some("pci_segments"),
+            DuplicateDevicePath(_) => Some("devices"),
+            DuplicateUsbDevicePath(_) => Some("usb"),
+            InvalidRtPriority(_) | RtNotEnoughCpus(..) | RtWithCpuAffinity => Some("rt"),
+            InvalidRestartMax => Some("restart_policy"),
+            BootDetectWithoutSerial | BootDetectWithoutVsock => Some("boot_detect"),
+            #[cfg(feature = "vnc")]
+            VncWithoutRamfb => Some("vnc"),
+            VhostUserMissingSocket
+            | IommuUnsupported
+            | VfioUnsupported
+            | TooManyQueues
+            | InvalidPciSegment(_)
+            | OnIommuSegment(_)
+            | IommuNotSupportedOnSegment(_)
+            | IommuNotSupported
+            | IdentifierNotUnique(_)
+            | InvalidIdentifier(_)
+            | InvalidRateLimiterGroup
+            | IdentityDeviceNotFound(_)
+            | IdentityUnsupported(_) => None,
         }
     }
 }
@@ -363,7 +766,15 @@ impl fmt::Display for Error {
             ParseDevicePathMissing => write!(f, "Error parsing --device: path missing"),
             ParseFileSystem(o) => write!(f, "Error parsing --fs: {o}"),
             ParseFsSockMissing => write!(f, "Error parsing --fs: socket missing"),
+            ParseFsSharedDirMissing => write!(f, "Error parsing --fs: shared_dir missing"),
+            ParseFsSockWithBuiltin => write!(
+                f,
+                "Error parsing --fs: socket is incompatible with builtin=on"
+            ),
             ParseFsTagMissing => write!(f, "Error parsing --fs: tag missing"),
+            ParseP9(o) => write!(f, "Error parsing --p9: {o}"),
+            ParseP9TagMissing => write!(f, "Error parsing --p9: tag missing"),
+            ParseP9PathMissing => write!(f, "Error parsing --p9: path missing"),
             ParseFsTagTooLong => write!(
                 f,
                 "Error parsing --fs: max tag length is {}",
@@ -378,8 +789,17 @@ impl fmt::Display for Error {
             ParseMemoryZone(o) => write!(f, "Error parsing --memory-zone: {o}"),
             ParseMemoryZoneIdMissing => write!(f, "Error parsing --memory-zone: id missing"),
             ParseNetwork(o) => write!(f, "Error parsing --net: {o}"),
+            ParseNetworkMacAndMacPool => {
+                write!(
+                    f,
+                    "Error parsing --net: mac and mac_pool are mutually exclusive"
+                )
+            }
             ParseRateLimiterGroup(o) => write!(f, "Error parsing --rate-limit-group: {o}"),
             ParseDisk(o) => write!(f, "Error parsing --disk: {o}"),
+            ParseDiskPathAndOverlay => {
+                write!(f, "Error parsing --disk: path and overlay both provided")
+            }
             ParseRng(o) => write!(f, "Error parsing --rng: {o}"),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {o}"),
             ParseRestore(o) => write!(f, "Error parsing --restore: {o}"),
@@ -406,8 +826,27 @@ impl fmt::Display for Error {
             ParsePlatform(o) => write!(f, "Error parsing --platform: {o}"),
             ParseVdpa(o) => write!(f, "Error parsing --vdpa: {o}"),
             ParseVdpaPathMissing => write!(f, "Error parsing --vdpa: path missing"),
+            ParseUsb(o) => write!(f, "Error parsing --usb: {o}"),
+            ParseUsbPathMissing => write!(f, "Error parsing --usb: path missing"),
             ParseTpm(o) => write!(f, "Error parsing --tpm: {o}"),
             ParseTpmPathMissing => write!(f, "Error parsing --tpm: path missing"),
+            ParseRt(o) => write!(f, "Error parsing --rt: {o}"),
+            ParsePayloadVerification(o) => write!(f, "Error parsing --payload-verification: {o}"),
+            ParsePayloadVerificationCertsMissing => {
+                write!(f, "Error parsing --payload-verification: certs missing")
+            }
+            ParseRestartPolicy(o) => write!(f, "Error parsing --restart-policy: {o}"),
+            ParseRestartPolicyConflictingModes => {
+                write!(f, "Error parsing --restart-policy: conflicting modes")
+            }
+            ParseBootDetect(o) => write!(f, "Error parsing --boot-detect: {o}"),
+            ParseBootDetectMethodMissing => {
+                write!(f, "Error parsing --boot-detect: detection method missing")
+            }
+            #[cfg(feature = "vnc")]
+            ParseVnc(o) => write!(f, "Error parsing --vnc: {o}"),
+            #[cfg(feature = "vnc")]
+            ParseVncSocketMissing => write!(f, "Error parsing --vnc: socket missing"),
         }
     }
 }
@@ -436,6 +875,7 @@ pub struct VmParams<'a> {
     pub rng: &'a str,
     pub balloon: Option<&'a str>,
     pub fs: Option<Vec<&'a str>>,
+    pub p9: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
@@ -445,8 +885,12 @@ pub struct VmParams<'a> {
     pub user_devices: Option<Vec<&'a str>>,
     pub vdpa: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
+    pub xhci: bool,
+    pub usb: Option<Vec<&'a str>>,
     pub pvpanic: bool,
     #[cfg(target_arch = "x86_64")]
+    pub ramfb: bool,
+    #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
     pub numa: Option<Vec<&'a str>>,
     pub watchdog: bool,
@@ -455,6 +899,12 @@ pub struct VmParams<'a> {
     pub pci_segments: Option<Vec<&'a str>>,
     pub platform: Option<&'a str>,
     pub tpm: Option<&'a str>,
+    pub rt: Option<&'a str>,
+    pub payload_verification: Option<&'a str>,
+    pub restart_policy: Option<&'a str>,
+    pub boot_detect: Option<&'a str>,
+    #[cfg(feature = "vnc")]
+    pub vnc: Option<&'a str>,
     #[cfg(feature = "igvm")]
     pub igvm: Option<&'a str>,
     #[cfg(feature = "sev_snp")]
@@ -491,6 +941,9 @@ impl<'a> VmParams<'a> {
         let fs: Option<Vec<&str>> = args
             .get_many::<String>("fs")
             .map(|x| x.map(|y| y as &str).collect());
+        let p9: Option<Vec<&str>> = args
+            .get_many::<String>("p9")
+            .map(|x| x.map(|y| y as &str).collect());
         let pmem: Option<Vec<&str>> = args
             .get_many::<String>("pmem")
             .map(|x| x.map(|y| y as &str).collect());
@@ -504,8 +957,14 @@ impl<'a> VmParams<'a> {
             .get_many::<String>("vdpa")
             .map(|x| x.map(|y| y as &str).collect());
         let vsock: Option<&str> = args.get_one::<String>("vsock").map(|x| x as &str);
+        let xhci = args.get_flag("xhci");
+        let usb: Option<Vec<&str>> = args
+            .get_many::<String>("usb")
+            .map(|x| x.map(|y| y as &str).collect());
         let pvpanic = args.get_flag("pvpanic");
         #[cfg(target_arch = "x86_64")]
+        let ramfb = args.get_flag("ramfb");
+        #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args
             .get_many::<String>("sgx-epc")
             .map(|x| x.map(|y| y as &str).collect());
@@ -520,6 +979,14 @@ impl<'a> VmParams<'a> {
         #[cfg(feature = "guest_debug")]
         let gdb = args.contains_id("gdb");
         let tpm: Option<&str> = args.get_one::<String>("tpm").map(|x| x as &str);
+        let rt = args.get_one::<String>("rt").map(|x| x as &str);
+        let payload_verification = args
+            .get_one::<String>("payload-verification")
+            .map(|x| x as &str);
+        let restart_policy = args.get_one::<String>("restart-policy").map(|x| x as &str);
+        let boot_detect = args.get_one::<String>("boot-detect").map(|x| x as &str);
+        #[cfg(feature = "vnc")]
+        let vnc = args.get_one::<String>("vnc").map(|x| x as &str);
         #[cfg(feature = "igvm")]
         let igvm = args.get_one::<String>("igvm").map(|x| x as &str);
         #[cfg(feature = "sev_snp")]
@@ -538,6 +1005,7 @@ impl<'a> VmParams<'a> {
             rng,
             balloon,
             fs,
+            p9,
             pmem,
             serial,
             console,
@@ -547,8 +1015,12 @@ impl<'a> VmParams<'a> {
             user_devices,
             vdpa,
             vsock,
+            xhci,
+            usb,
             pvpanic,
             #[cfg(target_arch = "x86_64")]
+            ramfb,
+            #[cfg(target_arch = "x86_64")]
             sgx_epc,
             numa,
             watchdog,
@@ -557,6 +1029,12 @@ impl<'a> VmParams<'a> {
             pci_segments,
             platform,
             tpm,
+            rt,
+            payload_verification,
+            restart_policy,
+            boot_detect,
+            #[cfg(feature = "vnc")]
+            vnc,
             #[cfg(feature = "igvm")]
             igvm,
             #[cfg(feature = "sev_snp")]
@@ -565,6 +1043,78 @@ impl<'a> VmParams<'a> {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseResetPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for ResetPolicy {
+    type Err = ParseResetPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "restart" => Ok(ResetPolicy::Restart),
+            "shutdown" => Ok(ResetPolicy::Shutdown),
+            "pause" => Ok(ResetPolicy::Pause),
+            "event" => Ok(ResetPolicy::Event),
+            _ => Err(ParseResetPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseDeviceNumaPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for DeviceNumaPolicy {
+    type Err = ParseDeviceNumaPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(DeviceNumaPolicy::Off),
+            "preferred" => Ok(DeviceNumaPolicy::Preferred),
+            "bind" => Ok(DeviceNumaPolicy::Bind),
+            _ => Err(ParseDeviceNumaPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseLegacyDeviceError {
+    InvalidValue(String),
+}
+
+impl FromStr for LegacyDevice {
+    type Err = ParseLegacyDeviceError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "i8042" => Ok(LegacyDevice::I8042),
+            "serial" => Ok(LegacyDevice::Serial),
+            "rtc" => Ok(LegacyDevice::Rtc),
+            _ => Err(ParseLegacyDeviceError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseMemoryLockPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for MemoryLockPolicy {
+    type Err = ParseMemoryLockPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(MemoryLockPolicy::Strict),
+            "best-effort" => Ok(MemoryLockPolicy::BestEffort),
+            _ => Err(ParseMemoryLockPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseHotplugMethodError {
     InvalidValue(String),
@@ -625,7 +1175,9 @@ impl CpusConfig {
             .add("kvm_hyperv")
             .add("max_phys_bits")
             .add("affinity")
-            .add("features");
+            .add("features")
+            .add("sve_vl")
+            .add("pmu_events");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u8 = parser
@@ -675,10 +1227,33 @@ impl CpusConfig {
                     features.amx = true;
                     Ok(())
                 }
+                #[cfg(target_arch = "x86_64")]
+                "evmcs" => {
+                    features.evmcs = true;
+                    Ok(())
+                }
                 _ => Err(Error::InvalidCpuFeatures(s)),
             }?;
         }
 
+        let sve_vl = parser.convert("sve_vl").map_err(Error::ParseCpus)?;
+        let pmu_events = parser
+            .convert::<IntegerList>("pmu_events")
+            .map_err(Error::ParseCpus)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|event| {
+                        u16::try_from(*event).map_err(|_| {
+                            Error::ParseCpus(OptionParserError::Conversion(
+                                "pmu_events".to_owned(),
+                                event.to_string(),
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<u16>>>()
+            })
+            .transpose()?;
+
         Ok(CpusConfig {
             boot_vcpus,
             max_vcpus,
@@ -687,6 +1262,8 @@ impl CpusConfig {
             max_phys_bits,
             affinity,
             features,
+            sve_vl,
+            pmu_events,
         })
     }
 }
@@ -757,7 +1334,15 @@ impl PlatformConfig {
             .add("iommu_segments")
             .add("serial_number")
             .add("uuid")
-            .add("oem_strings");
+            .add("oem_strings")
+            .add("device_numa_policy")
+            .add("device_access_warn_us")
+            .add("legacy_devices")
+            .add("on_reset")
+            .add("dt_overlay")
+            .add("mmio32_size")
+            .add("mmio64_size")
+            .add("vmgenid");
         #[cfg(feature = "tdx")]
         parser.add("tdx");
         #[cfg(feature = "sev_snp")]
@@ -792,6 +1377,51 @@ impl PlatformConfig {
             .map_err(Error::ParsePlatform)?
             .unwrap_or(Toggle(false))
             .0;
+        let device_numa_policy = parser
+            .convert("device_numa_policy")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_default();
+        let device_access_warn_us = parser
+            .convert("device_access_warn_us")
+            .map_err(Error::ParsePlatform)?;
+        let legacy_devices = parser
+            .convert::<StringList>("legacy_devices")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| {
+                v.0.iter()
+                    .filter(|e| !e.is_empty())
+                    .map(|e| {
+                        e.parse::<LegacyDevice>().map_err(|_| {
+                            Error::ParsePlatform(OptionParserError::Conversion(
+                                "legacy_devices".to_owned(),
+                                e.to_owned(),
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<LegacyDevice>>>()
+            })
+            .transpose()?;
+        let on_reset = parser
+            .convert("on_reset")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_default();
+        let dt_overlay = parser
+            .convert::<StringList>("dt_overlay")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| v.0.into_iter().map(PathBuf::from).collect());
+        let mmio32_size = parser
+            .convert::<ByteSized>("mmio32_size")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| v.0);
+        let mmio64_size = parser
+            .convert::<ByteSized>("mmio64_size")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| v.0);
+        let vmgenid = parser
+            .convert::<Toggle>("vmgenid")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
         Ok(PlatformConfig {
             num_pci_segments,
             iommu_segments,
@@ -802,6 +1432,14 @@ impl PlatformConfig {
             tdx,
             #[cfg(feature = "sev_snp")]
             sev_snp,
+            device_numa_policy,
+            device_access_warn_us,
+            legacy_devices,
+            on_reset,
+            dt_overlay,
+            mmio32_size,
+            mmio64_size,
+            vmgenid,
         })
     }
 
@@ -820,10 +1458,46 @@ impl PlatformConfig {
             }
         }
 
+        #[cfg(not(target_arch = "aarch64"))]
+        if self.dt_overlay.is_some() {
+            return Err(ValidationError::DtOverlayUnsupported);
+        }
+
+        // The apertures are split between the PCI segments on 4KiB and 4GiB
+        // boundaries, the 32-bit one not growing beyond what the fixed ranges
+        // below 4GiB leave. Whether the RAM leaves room for it is only known
+        // once the RAM is laid out.
+        if let Some(size) = self.mmio32_size {
+            if size == 0 || size % (4 << 10) != 0 || size > arch::layout::MEM_32BIT_DEVICES_MAX_SIZE
+            {
+                return Err(ValidationError::InvalidMmio32Size(size));
+            }
+        }
+        if let Some(size) = self.mmio64_size {
+            if size == 0 || size % (4 << 30) != 0 {
+                return Err(ValidationError::InvalidMmio64Size(size));
+            }
+        }
+
         Ok(())
     }
 }
 
+// Besides the usual toggle values, "hugepages=try" enables hugepages while
+// allowing to fall back onto regular memory if the hugepage pool is short.
+fn parse_hugepages(parser: &OptionParser) -> result::Result<(bool, bool), OptionParserError> {
+    if parser.get("hugepages").as_deref() == Some("try") {
+        return Ok((true, true));
+    }
+
+    let hugepages = parser
+        .convert::<Toggle>("hugepages")?
+        .unwrap_or(Toggle(false))
+        .0;
+
+    Ok((hugepages, false))
+}
+
 impl MemoryConfig {
     pub fn parse(memory: &str, memory_zones: Option<Vec<&str>>) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -838,7 +1512,9 @@ impl MemoryConfig {
             .add("hugepages")
             .add("hugepage_size")
             .add("prefault")
-            .add("thp");
+            .add("thp")
+            .add("locked")
+            .add("lock_policy");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -868,11 +1544,8 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(false))
             .0;
-        let hugepages = parser
-            .convert::<Toggle>("hugepages")
-            .map_err(Error::ParseMemory)?
-            .unwrap_or(Toggle(false))
-            .0;
+        let (hugepages, hugepages_fallback) =
+            parse_hugepages(&parser).map_err(Error::ParseMemory)?;
         let hugepage_size = parser
             .convert::<ByteSized>("hugepage_size")
             .map_err(Error::ParseMemory)?
@@ -887,6 +1560,15 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(true))
             .0;
+        let locked = parser
+            .convert::<Toggle>("locked")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let lock_policy = parser
+            .convert("lock_policy")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or_default();
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -902,7 +1584,9 @@ impl MemoryConfig {
                     .add("host_numa_node")
                     .add("hotplug_size")
                     .add("hotplugged_size")
-                    .add("prefault");
+                    .add("prefault")
+                    .add("shared_with")
+                    .add("shared_from");
                 parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
 
                 let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
@@ -917,11 +1601,8 @@ impl MemoryConfig {
                     .map_err(Error::ParseMemoryZone)?
                     .unwrap_or(Toggle(false))
                     .0;
-                let hugepages = parser
-                    .convert::<Toggle>("hugepages")
-                    .map_err(Error::ParseMemoryZone)?
-                    .unwrap_or(Toggle(false))
-                    .0;
+                let (hugepages, hugepages_fallback) =
+                    parse_hugepages(&parser).map_err(Error::ParseMemoryZone)?;
                 let hugepage_size = parser
                     .convert::<ByteSized>("hugepage_size")
                     .map_err(Error::ParseMemoryZone)?
@@ -943,6 +1624,8 @@ impl MemoryConfig {
                     .map_err(Error::ParseMemoryZone)?
                     .unwrap_or(Toggle(false))
                     .0;
+                let shared_with = parser.get("shared_with").map(PathBuf::from);
+                let shared_from = parser.get("shared_from").map(PathBuf::from);
 
                 zones.push(MemoryZoneConfig {
                     id,
@@ -951,10 +1634,13 @@ impl MemoryConfig {
                     shared,
                     hugepages,
                     hugepage_size,
+                    hugepages_fallback,
                     host_numa_node,
                     hotplug_size,
                     hotplugged_size,
                     prefault,
+                    shared_with,
+                    shared_from,
                 });
             }
             Some(zones)
@@ -971,9 +1657,12 @@ impl MemoryConfig {
             shared,
             hugepages,
             hugepage_size,
+            hugepages_fallback,
             prefault,
             zones,
             thp,
+            locked,
+            lock_policy,
         })
     }
 
@@ -1085,8 +1774,9 @@ impl RateLimiterGroupConfig {
 
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
-         \"path=<disk_image_path>,readonly=on|off,direct=on|off,iommu=on|off,\
-         num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
+         \"path=<disk_image_path>,fd=<disk_image_fd>,readonly=on|off,direct=on|off,\
+         read_ahead=on|off,base=<base_image_path>,overlay=<overlay_image_path>,\
+         create_overlay=on|off,ephemeral=on|off,secure_erase=on|off,iommu=on|off,num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
          vhost_user=on|off,socket=<vhost_user_socket_path>,\
          bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
@@ -1097,8 +1787,15 @@ impl DiskConfig {
         let mut parser = OptionParser::new();
         parser
             .add("path")
+            .add("fd")
             .add("readonly")
             .add("direct")
+            .add("read_ahead")
+            .add("base")
+            .add("overlay")
+            .add("create_overlay")
+            .add("ephemeral")
+            .add("secure_erase")
             .add("iommu")
             .add("queue_size")
             .add("num_queues")
@@ -1119,7 +1816,12 @@ impl DiskConfig {
             .add("queue_affinity");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
-        let path = parser.get("path").map(PathBuf::from);
+        // The overlay of a base image is the image the disk is backed by
+        let path = match (parser.get("path"), parser.get("overlay")) {
+            (Some(_), Some(_)) => return Err(Error::ParseDiskPathAndOverlay),
+            (path, overlay) => path.or(overlay).map(PathBuf::from),
+        };
+        let fd = parser.convert("fd").map_err(Error::ParseDisk)?;
         let readonly = parser
             .convert::<Toggle>("readonly")
             .map_err(Error::ParseDisk)?
@@ -1130,6 +1832,27 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or(Toggle(false))
             .0;
+        let read_ahead = parser
+            .convert::<Toggle>("read_ahead")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let base = parser.get("base").map(PathBuf::from);
+        let create_overlay = parser
+            .convert::<Toggle>("create_overlay")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let ephemeral = parser
+            .convert::<Toggle>("ephemeral")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let secure_erase = parser
+            .convert::<Toggle>("secure_erase")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
         let iommu = parser
             .convert::<Toggle>("iommu")
             .map_err(Error::ParseDisk)?
@@ -1230,8 +1953,14 @@ impl DiskConfig {
 
         Ok(DiskConfig {
             path,
+            fd,
             readonly,
             direct,
+            read_ahead,
+            base,
+            create_overlay,
+            ephemeral,
+            secure_erase,
             iommu,
             num_queues,
             queue_size,
@@ -1257,6 +1986,16 @@ impl DiskConfig {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if let Some(fd) = self.fd {
+            if self.path.is_some() || self.vhost_user {
+                return Err(ValidationError::DiskFdAndPath);
+            }
+
+            if fd <= 2 {
+                return Err(ValidationError::DiskReservedFd);
+            }
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1273,6 +2012,32 @@ impl DiskConfig {
             return Err(ValidationError::InvalidRateLimiterGroup);
         }
 
+        if self.read_ahead && (self.direct || self.vhost_user) {
+            return Err(ValidationError::DiskReadAheadUnsupported);
+        }
+
+        if self.base.is_some() && (self.path.is_none() || self.fd.is_some() || self.vhost_user) {
+            return Err(ValidationError::DiskBaseWithoutOverlay);
+        }
+
+        if self.create_overlay && self.base.is_none() {
+            return Err(ValidationError::DiskOverlayWithoutBase);
+        }
+
+        if self.ephemeral
+            && (self.path.is_none()
+                || self.fd.is_some()
+                || self.readonly
+                || self.base.is_some()
+                || self.vhost_user)
+        {
+            return Err(ValidationError::DiskEphemeralUnsupported);
+        }
+
+        if self.secure_erase && !self.ephemeral {
+            return Err(ValidationError::DiskSecureEraseWithoutEphemeral);
+        }
+
         Ok(())
     }
 }
@@ -1294,14 +2059,34 @@ impl FromStr for VhostMode {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseNetModelError {
+    InvalidValue(String),
+}
+
+impl FromStr for NetModel {
+    type Err = ParseNetModelError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "virtio" => Ok(NetModel::Virtio),
+            "e1000e" => Ok(NetModel::E1000e),
+            _ => Err(ParseNetModelError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
-    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd1,fd2...>,iommu=on|off,\
+    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,mac_pool=<mac_prefix>,fd=<fd1,fd2...>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,id=<device_id>,\
     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,vhost_mode=client|server,\
+    vhost_instance_id=<instance_id>,\
     bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
     ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,pci_segment=<segment_id>\
-    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off\"";
+    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off,bridge=<bridge_name>,vlan=<vlan_id>,\
+    model=virtio|e1000e,egress_shaping=htb:<rate>|netem:<delay>[/<jitter>],\
+    ipv6=<ipv6_addr>,ipv6_prefix_len=<prefix_len>,ipv6_ra=on|off\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1311,6 +2096,7 @@ impl NetConfig {
             .add("ip")
             .add("mask")
             .add("mac")
+            .add("mac_pool")
             .add("host_mac")
             .add("offload_tso")
             .add("offload_ufo")
@@ -1322,6 +2108,7 @@ impl NetConfig {
             .add("vhost_user")
             .add("socket")
             .add("vhost_mode")
+            .add("vhost_instance_id")
             .add("id")
             .add("fd")
             .add("bw_size")
@@ -1330,14 +2117,22 @@ impl NetConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("bridge")
+            .add("vlan")
+            .add("model")
+            .add("egress_shaping")
+            .add("ipv6")
+            .add("ipv6_prefix_len")
+            .add("ipv6_ra");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
+        let ipv6 = parser.convert("ipv6").map_err(Error::ParseNetwork)?;
         let ip = parser
             .convert("ip")
             .map_err(Error::ParseNetwork)?
-            .unwrap_or_else(default_netconfig_ip);
+            .unwrap_or_else(|| default_netconfig_ip(ipv6));
         let mask = parser
             .convert("mask")
             .map_err(Error::ParseNetwork)?
@@ -1346,6 +2141,10 @@ impl NetConfig {
             .convert("mac")
             .map_err(Error::ParseNetwork)?
             .unwrap_or_else(default_netconfig_mac);
+        let mac_pool = parser.get("mac_pool");
+        if mac_pool.is_some() && parser.is_set("mac") {
+            return Err(Error::ParseNetworkMacAndMacPool);
+        }
         let host_mac = parser.convert("host_mac").map_err(Error::ParseNetwork)?;
         let offload_tso = parser
             .convert::<Toggle>("offload_tso")
@@ -1386,6 +2185,7 @@ impl NetConfig {
             .convert("vhost_mode")
             .map_err(Error::ParseNetwork)?
             .unwrap_or_default();
+        let vhost_instance_id = parser.get("vhost_instance_id");
         let id = parser.get("id");
         let fds = parser
             .convert::<IntegerList>("fd")
@@ -1445,12 +2245,31 @@ impl NetConfig {
         } else {
             None
         };
+        let bridge = parser.get("bridge");
+        let vlan = parser.convert("vlan").map_err(Error::ParseNetwork)?;
+        let model = parser
+            .convert("model")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let egress_shaping = parser
+            .convert("egress_shaping")
+            .map_err(Error::ParseNetwork)?;
+        let ipv6_prefix_len = parser
+            .convert("ipv6_prefix_len")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_ipv6_prefix_len);
+        let ipv6_ra = parser
+            .convert::<Toggle>("ipv6_ra")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         let config = NetConfig {
             tap,
-            ip,
+            ip: Some(ip),
             mask,
             mac,
+            mac_pool,
             host_mac,
             mtu,
             iommu,
@@ -1459,6 +2278,7 @@ impl NetConfig {
             vhost_user,
             vhost_socket,
             vhost_mode,
+            vhost_instance_id,
             id,
             fds,
             rate_limiter_config,
@@ -1466,6 +2286,13 @@ impl NetConfig {
             offload_tso,
             offload_ufo,
             offload_csum,
+            bridge,
+            vlan,
+            model,
+            egress_shaping,
+            ipv6,
+            ipv6_prefix_len,
+            ipv6_ra,
         };
         Ok(config)
     }
@@ -1517,12 +2344,118 @@ impl NetConfig {
             return Err(ValidationError::NoHardwareChecksumOffload);
         }
 
-        Ok(())
-    }
-}
+        if self.bridge.is_some() && (self.fds.is_some() || self.vhost_user) {
+            return Err(ValidationError::NetBridgeRequiresTap);
+        }
 
-impl RngConfig {
-    pub fn parse(rng: &str) -> Result<Self> {
+        if self.egress_shaping.is_some() && (self.fds.is_some() || self.vhost_user) {
+            return Err(ValidationError::NetEgressShapingRequiresTap);
+        }
+
+        if self.ipv6.is_some() && (self.fds.is_some() || self.vhost_user || self.bridge.is_some()) {
+            return Err(ValidationError::NetIpv6RequiresTap);
+        }
+
+        if !(1..=128).contains(&self.ipv6_prefix_len) {
+            return Err(ValidationError::InvalidIpv6PrefixLen(self.ipv6_prefix_len));
+        }
+
+        if self.ipv6_ra && self.ipv6.is_none() {
+            return Err(ValidationError::NetIpv6RaWithoutAddress);
+        }
+
+        if let Some(instance_id) = &self.vhost_instance_id {
+            if !self.vhost_user || self.vhost_mode != VhostMode::Client {
+                return Err(ValidationError::NetVhostInstanceIdRequiresClient);
+            }
+
+            if instance_id.is_empty()
+                || instance_id.len() > virtio_devices::vhost_user::INSTANCE_ID_MAX_LEN
+            {
+                return Err(ValidationError::InvalidVhostInstanceId(instance_id.clone()));
+            }
+        }
+
+        if let Some(vlan) = self.vlan {
+            if self.bridge.is_none() {
+                return Err(ValidationError::NetVlanWithoutBridge);
+            }
+
+            if !(1..=4094).contains(&vlan) {
+                return Err(ValidationError::InvalidVlanId(vlan));
+            }
+        }
+
+        if let Some(mac_pool) = &self.mac_pool {
+            parse_mac_pool(mac_pool)?;
+        }
+
+        if self.model == NetModel::E1000e {
+            // The e1000e NIC is a single queue pair device, doing its DMA
+            // directly into guest memory.
+            if self.vhost_user {
+                return Err(ValidationError::E1000eUnsupportedOption("vhost_user"));
+            }
+            if self.num_queues != DEFAULT_NET_NUM_QUEUES {
+                return Err(ValidationError::E1000eUnsupportedOption("num_queues"));
+            }
+            if self.iommu {
+                return Err(ValidationError::E1000eUnsupportedOption("iommu"));
+            }
+            if self.rate_limiter_config.is_some() {
+                return Err(ValidationError::E1000eUnsupportedOption("rate_limiter"));
+            }
+            if self.ipv6_ra {
+                return Err(ValidationError::E1000eUnsupportedOption("ipv6_ra"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a MAC address pool such as "52:54:00:xx:xx:xx" (or the shorter
+/// form "52:54:00:xx") and returns its fixed prefix. The remaining bytes of
+/// the MAC address are allocated from the pool.
+fn parse_mac_pool(mac_pool: &str) -> ValidationResult<Vec<u8>> {
+    let invalid = || ValidationError::InvalidMacPool(mac_pool.to_owned());
+
+    let mut prefix = Vec::new();
+    let mut free_bytes = 0;
+    for part in mac_pool.split(':') {
+        if part.eq_ignore_ascii_case("xx") {
+            free_bytes += 1;
+        } else if free_bytes == 0 && part.len() == 2 {
+            prefix.push(u8::from_str_radix(part, 16).map_err(|_| invalid())?);
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    if prefix.is_empty() || free_bytes == 0 || prefix.len() + free_bytes > MAC_ADDR_LEN {
+        return Err(invalid());
+    }
+
+    // The group bit would make every address of the pool a multicast one.
+    if prefix[0] & 1 != 0 {
+        return Err(invalid());
+    }
+
+    Ok(prefix)
+}
+
+// FNV-1a, used to derive MAC addresses that are stable across releases.
+fn fnv1a_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl RngConfig {
+    pub fn parse(rng: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser.add("src").add("iommu");
         parser.parse(rng).map_err(Error::ParseRng)?;
@@ -1583,7 +2516,8 @@ impl BalloonConfig {
 impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
     \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
-    queue_size=<size_of_each_queue>,id=<device_id>,pci_segment=<segment_id>\"";
+    queue_size=<size_of_each_queue>,id=<device_id>,pci_segment=<segment_id>,\
+    builtin=on|off,shared_dir=<shared_directory_path>,readonly=on|off\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1593,14 +2527,42 @@ impl FsConfig {
             .add("num_queues")
             .add("socket")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("builtin")
+            .add("shared_dir")
+            .add("readonly");
         parser.parse(fs).map_err(Error::ParseFileSystem)?;
 
         let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
         if tag.len() > virtio_devices::vhost_user::VIRTIO_FS_TAG_LEN {
             return Err(Error::ParseFsTagTooLong);
         }
-        let socket = PathBuf::from(parser.get("socket").ok_or(Error::ParseFsSockMissing)?);
+
+        let builtin = parser
+            .convert::<Toggle>("builtin")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or(Toggle(false))
+            .0;
+        // The built-in device serves the shared directory from the VMM process
+        // while the vhost-user one relies on an external backend.
+        let (socket, shared_dir) = if builtin {
+            if parser.is_set("socket") {
+                return Err(Error::ParseFsSockWithBuiltin);
+            }
+            let shared_dir = parser
+                .get("shared_dir")
+                .ok_or(Error::ParseFsSharedDirMissing)?;
+            (PathBuf::new(), Some(PathBuf::from(shared_dir)))
+        } else {
+            let socket = parser.get("socket").ok_or(Error::ParseFsSockMissing)?;
+            (PathBuf::from(socket), None)
+        };
+
+        let readonly = parser
+            .convert::<Toggle>("readonly")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         let queue_size = parser
             .convert("queue_size")
@@ -1625,6 +2587,9 @@ impl FsConfig {
             queue_size,
             id,
             pci_segment,
+            builtin,
+            shared_dir,
+            readonly,
         })
     }
 
@@ -1633,6 +2598,20 @@ impl FsConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        if self.builtin {
+            if !cfg!(feature = "fs_builtin") {
+                return Err(ValidationError::FsBuiltinNotSupported);
+            }
+
+            if self.shared_dir.is_none() {
+                return Err(ValidationError::FsBuiltinSharedDirMissing);
+            }
+        } else if self.readonly {
+            // Read-only access can only be enforced by the built-in device,
+            // an external backend is not under the VMM control.
+            return Err(ValidationError::FsReadonlyNotBuiltin);
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1651,6 +2630,67 @@ impl FsConfig {
     }
 }
 
+impl P9Config {
+    pub const SYNTAX: &'static str = "virtio-9p parameters \
+    \"tag=<tag_name>,path=<shared_directory_path>,iommu=on|off,\
+    id=<device_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(p9: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("tag")
+            .add("path")
+            .add("iommu")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(p9).map_err(Error::ParseP9)?;
+
+        let tag = parser.get("tag").ok_or(Error::ParseP9TagMissing)?;
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseP9PathMissing)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseP9)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseP9)?
+            .unwrap_or_default();
+
+        Ok(P9Config {
+            tag,
+            path,
+            iommu,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if !cfg!(feature = "virtio_9p") {
+            return Err(ValidationError::P9NotSupported);
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+
+            if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
+                if iommu_segments.contains(&self.pci_segment) && !self.iommu {
+                    return Err(ValidationError::OnIommuSegment(self.pci_segment));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
@@ -1823,7 +2863,7 @@ impl DebugConsoleConfig {
 
 impl DeviceConfig {
     pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,romfile=<option_rom_path>\"";
 
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1832,7 +2872,8 @@ impl DeviceConfig {
             .add("id")
             .add("iommu")
             .add("pci_segment")
-            .add("x_nv_gpudirect_clique");
+            .add("x_nv_gpudirect_clique")
+            .add("romfile");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1852,12 +2893,14 @@ impl DeviceConfig {
         let x_nv_gpudirect_clique = parser
             .convert::<u8>("x_nv_gpudirect_clique")
             .map_err(Error::ParseDevice)?;
+        let romfile = parser.get("romfile").map(PathBuf::from);
         Ok(DeviceConfig {
             path,
             iommu,
             id,
             pci_segment,
             x_nv_gpudirect_clique,
+            romfile,
         })
     }
 
@@ -1983,6 +3026,25 @@ impl VdpaConfig {
     }
 }
 
+impl UsbConfig {
+    pub const SYNTAX: &'static str = "Host USB device passed through an emulated xHCI \
+        controller \"path=<usbfs_device_path>,id=<device_id>\"";
+
+    pub fn parse(usb: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("id");
+        parser.parse(usb).map_err(Error::ParseUsb)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseUsbPathMissing)?;
+        let id = parser.get("id");
+
+        Ok(UsbConfig { path, id })
+    }
+}
+
 impl VsockConfig {
     pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
         \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
@@ -2135,17 +3197,30 @@ pub struct RestoreConfig {
     pub source_url: PathBuf,
     #[serde(default)]
     pub prefault: bool,
+    /// File descriptor the key of an encrypted snapshot is read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_fd: Option<i32>,
+    /// Leave the devices inactive until the VM is booted with its identity
+    #[serde(default)]
+    pub ready: bool,
 }
 
 impl RestoreConfig {
     pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
-        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off,key_fd=<fd>,ready=on|off\" \
+        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo), \
+        or a stream to read the snapshot from (fd:<fd>, http://<host>/<path> or https://<host>/<path>) \
+        \n`prefault` brings memory pages in when enabled (disabled by default) \
+        \n`key_fd` is the file descriptor the key of an encrypted snapshot is read from \
+        \n`ready` restores a ready VM, given its identity on boot (disabled by default)";
 
     pub fn parse(restore: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("key_fd")
+            .add("ready");
         parser.parse(restore).map_err(Error::ParseRestore)?;
 
         let source_url = parser
@@ -2157,10 +3232,20 @@ impl RestoreConfig {
             .map_err(Error::ParseRestore)?
             .unwrap_or(Toggle(false))
             .0;
+        let key_fd = parser
+            .convert::<i32>("key_fd")
+            .map_err(Error::ParseRestore)?;
+        let ready = parser
+            .convert::<Toggle>("ready")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         Ok(RestoreConfig {
             source_url,
             prefault,
+            key_fd,
+            ready,
         })
     }
 }
@@ -2181,6 +3266,244 @@ impl TpmConfig {
     }
 }
 
+impl RtConfig {
+    pub const SYNTAX: &'static str = "Realtime profile \
+        \"cpus=<list_of_isolated_host_cpus>,priority=<sched_fifo_priority>\"";
+
+    pub fn parse(rt: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("cpus").add("priority");
+        parser.parse(rt).map_err(Error::ParseRt)?;
+
+        let cpus = parser
+            .convert::<IntegerList>("cpus")
+            .map_err(Error::ParseRt)?
+            .map(|v| v.0.iter().map(|e| *e as usize).collect());
+        let priority = parser
+            .convert("priority")
+            .map_err(Error::ParseRt)?
+            .unwrap_or(DEFAULT_RT_PRIORITY);
+
+        Ok(RtConfig { cpus, priority })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if !(1..=99).contains(&self.priority) {
+            return Err(ValidationError::InvalidRtPriority(self.priority));
+        }
+
+        if let Some(cpus) = &self.cpus {
+            if cpus.len() < usize::from(vm_config.cpus.max_vcpus) {
+                return Err(ValidationError::RtNotEnoughCpus(
+                    cpus.len(),
+                    vm_config.cpus.max_vcpus,
+                ));
+            }
+        }
+
+        if vm_config.cpus.affinity.is_some() {
+            return Err(ValidationError::RtWithCpuAffinity);
+        }
+
+        Ok(())
+    }
+}
+
+impl PayloadVerificationConfig {
+    pub const SYNTAX: &'static str = "Payload signature verification \
+        \"certs=</path/to/trusted/certs.pem>,kernel_signature=</path/to/kernel.p7s>,\
+        initramfs_signature=</path/to/initramfs.p7s>\"";
+
+    pub fn parse(payload_verification: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("certs")
+            .add("kernel_signature")
+            .add("initramfs_signature");
+        parser
+            .parse(payload_verification)
+            .map_err(Error::ParsePayloadVerification)?;
+
+        let certs = parser
+            .get("certs")
+            .map(PathBuf::from)
+            .ok_or(Error::ParsePayloadVerificationCertsMissing)?;
+        let kernel_signature = parser.get("kernel_signature").map(PathBuf::from);
+        let initramfs_signature = parser.get("initramfs_signature").map(PathBuf::from);
+
+        Ok(PayloadVerificationConfig {
+            certs,
+            kernel_signature,
+            initramfs_signature,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if cfg!(not(feature = "payload_verification")) {
+            return Err(ValidationError::PayloadVerificationUnsupported);
+        }
+
+        let Some(payload) = vm_config
+            .payload
+            .as_ref()
+            .filter(|p| p.kernel.is_some() && p.firmware.is_none())
+        else {
+            return Err(ValidationError::PayloadVerificationWithoutKernel);
+        };
+
+        // Every payload being loaded must be covered by a signature, the
+        // kernel being otherwise expected to embed an Authenticode one
+        if payload.initramfs.is_some() && self.initramfs_signature.is_none() {
+            return Err(ValidationError::PayloadSignatureMissing("initramfs"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a duration such as "500ms", "5s" or "2m" into milliseconds, a
+/// value without unit being expressed in seconds.
+pub fn parse_duration_ms(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (value, factor) = if let Some(v) = s.strip_suffix("ms") {
+        (v, 1)
+    } else if let Some(v) = s.strip_suffix('s') {
+        (v, 1000)
+    } else if let Some(v) = s.strip_suffix('m') {
+        (v, 60 * 1000)
+    } else {
+        (s, 1000)
+    };
+
+    value.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+impl RestartPolicyConfig {
+    pub const SYNTAX: &'static str = "Restart the guest from within the VMM \
+        \"no|on-failure|always,max=<max_consecutive_restarts>,\
+        backoff=<initial_delay, e.g. 500ms, 5s>\"";
+
+    pub fn parse(restart_policy: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add_valueless("no")
+            .add_valueless("on-failure")
+            .add_valueless("always")
+            .add("max")
+            .add("backoff");
+        parser
+            .parse(restart_policy)
+            .map_err(Error::ParseRestartPolicy)?;
+
+        let modes: Vec<RestartMode> = [
+            ("no", RestartMode::No),
+            ("on-failure", RestartMode::OnFailure),
+            ("always", RestartMode::Always),
+        ]
+        .into_iter()
+        .filter(|(name, _)| parser.is_set(name))
+        .map(|(_, mode)| mode)
+        .collect();
+        let mode = match modes.as_slice() {
+            [] => RestartMode::OnFailure,
+            [mode] => *mode,
+            _ => return Err(Error::ParseRestartPolicyConflictingModes),
+        };
+        let max = parser.convert("max").map_err(Error::ParseRestartPolicy)?;
+        let backoff_ms = match parser.get("backoff") {
+            Some(backoff) => parse_duration_ms(&backoff).ok_or_else(|| {
+                Error::ParseRestartPolicy(OptionParserError::Conversion(
+                    "backoff".to_owned(),
+                    backoff.clone(),
+                ))
+            })?,
+            None => DEFAULT_RESTART_BACKOFF_MS,
+        };
+
+        Ok(RestartPolicyConfig {
+            mode,
+            max,
+            backoff_ms,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.max == Some(0) {
+            return Err(ValidationError::InvalidRestartMax);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "vnc")]
+impl VncConfig {
+    pub const SYNTAX: &'static str = "VNC server displaying the ramfb framebuffer \
+        \"socket=</path/to/a/file>,input=on|off\"";
+
+    pub fn parse(vnc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket").add("input");
+        parser.parse(vnc).map_err(Error::ParseVnc)?;
+        let socket = parser
+            .get("socket")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseVncSocketMissing)?;
+        let input = parser
+            .convert::<Toggle>("input")
+            .map_err(Error::ParseVnc)?
+            .unwrap_or(Toggle(default_vncconfig_input()))
+            .0;
+        Ok(VncConfig { socket, input })
+    }
+}
+
+impl BootDetectConfig {
+    pub const SYNTAX: &'static str = "Detection of the guest being booted \
+        \"console_marker=<string_printed_on_serial>,vsock_port=<guest_agent_port>,acpi=on|off\"";
+
+    pub fn parse(boot_detect: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("console_marker").add("vsock_port").add("acpi");
+        parser.parse(boot_detect).map_err(Error::ParseBootDetect)?;
+
+        let console_marker = parser.get("console_marker");
+        let vsock_port = parser
+            .convert("vsock_port")
+            .map_err(Error::ParseBootDetect)?;
+        let acpi = parser
+            .convert::<Toggle>("acpi")
+            .map_err(Error::ParseBootDetect)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        if console_marker.is_none() && vsock_port.is_none() && !acpi {
+            return Err(Error::ParseBootDetectMethodMissing);
+        }
+
+        Ok(BootDetectConfig {
+            console_marker,
+            vsock_port,
+            acpi,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.console_marker.is_some()
+            && (vm_config.serial.mode == ConsoleOutputMode::Off
+                || !vm_config.legacy_device_enabled(LegacyDevice::Serial))
+        {
+            return Err(ValidationError::BootDetectWithoutSerial);
+        }
+
+        if self.vsock_port.is_some() && vm_config.vsock.is_none() {
+            return Err(ValidationError::BootDetectWithoutVsock);
+        }
+
+        Ok(())
+    }
+}
+
 impl VmConfig {
     fn validate_identifier(
         id_list: &mut BTreeSet<String>,
@@ -2199,14 +3522,16 @@ impl VmConfig {
         Ok(())
     }
 
+    // Memory allowed to fall back onto regular pages is only guaranteed to be
+    // shared if explicitly requested.
     pub fn backed_by_shared_memory(&self) -> bool {
-        if self.memory.shared || self.memory.hugepages {
+        if self.memory.shared || (self.memory.hugepages && !self.memory.hugepages_fallback) {
             return true;
         }
 
         if self.memory.size == 0 {
             for zone in self.memory.zones.as_ref().unwrap() {
-                if !zone.shared && !zone.hugepages {
+                if !zone.shared && (!zone.hugepages || zone.hugepages_fallback) {
                     return false;
                 }
             }
@@ -2216,6 +3541,72 @@ impl VmConfig {
         }
     }
 
+    // Allocates the MAC address of every network device relying on a MAC
+    // address pool. The address is derived from the platform UUID (or serial
+    // number, or else the TAP interface name) and the index of the device so
+    // that it remains the same from one run to another, and it never collides
+    // with the address of another network device of the VM. Once allocated, the pool is dropped from the
+    // device configuration so that the address stays stable when devices are
+    // added or removed afterwards.
+    fn allocate_pool_mac_addresses(&mut self) -> ValidationResult<()> {
+        let nets = match self.net.as_mut() {
+            Some(nets) => nets,
+            None => return Ok(()),
+        };
+
+        let vm_identity = self
+            .platform
+            .as_ref()
+            .and_then(|p| p.uuid.clone().or_else(|| p.serial_number.clone()));
+
+        let mut used_macs: BTreeSet<[u8; MAC_ADDR_LEN]> = nets
+            .iter()
+            .filter(|net| net.mac_pool.is_none())
+            .map(|net| net.mac.get_bytes().try_into().unwrap())
+            .collect();
+
+        for (index, net) in nets.iter_mut().enumerate() {
+            let mac_pool = match net.mac_pool.as_ref() {
+                Some(mac_pool) => mac_pool,
+                None => continue,
+            };
+            let prefix = parse_mac_pool(mac_pool)?;
+            let pool_size = 1u64 << (8 * (MAC_ADDR_LEN - prefix.len()));
+
+            let mut bytes = [0u8; MAC_ADDR_LEN];
+            bytes[..prefix.len()].copy_from_slice(&prefix);
+            // Without any identity for the VM, the TAP interface name, unique
+            // on the host, stands in for it.
+            let identity = vm_identity
+                .as_ref()
+                .or(net.tap.as_ref())
+                .map(|identity| identity.as_bytes())
+                .unwrap_or_default();
+            let mut allocated = false;
+            for attempt in 0..pool_size.min(u16::MAX as u64) {
+                let mut data = identity.to_vec();
+                data.extend_from_slice(&(index as u64).to_le_bytes());
+                data.extend_from_slice(&attempt.to_le_bytes());
+                let seed = fnv1a_hash(&data);
+                for (i, b) in bytes[prefix.len()..].iter_mut().enumerate() {
+                    *b = (seed >> (8 * i)) as u8;
+                }
+                if used_macs.insert(bytes) {
+                    allocated = true;
+                    break;
+                }
+            }
+            if !allocated {
+                return Err(ValidationError::MacPoolExhausted(mac_pool.clone()));
+            }
+
+            net.mac = MacAddr::from_bytes_unchecked(&bytes);
+            net.mac_pool = None;
+        }
+
+        Ok(())
+    }
+
     // Also enables virtio-iommu if the config needs it
     // Returns the list of unique identifiers provided through the
     // configuration.
@@ -2281,10 +3672,36 @@ impl VmConfig {
             return Err(ValidationError::ConsoleFileMissing);
         }
 
+        if !matches!(
+            self.serial.mode,
+            ConsoleOutputMode::Off | ConsoleOutputMode::Null
+        ) && !self.legacy_device_enabled(LegacyDevice::Serial)
+        {
+            return Err(ValidationError::SerialLegacyDeviceDisabled);
+        }
+
         if self.cpus.max_vcpus < self.cpus.boot_vcpus {
             return Err(ValidationError::CpusMaxLowerThanBoot);
         }
 
+        if let Some(sve_vl) = self.cpus.sve_vl {
+            if !cfg!(target_arch = "aarch64") {
+                return Err(ValidationError::CpuOptionUnsupported("sve_vl"));
+            }
+            if sve_vl == 0 || sve_vl > 2048 || sve_vl % 128 != 0 {
+                return Err(ValidationError::InvalidSveVectorLength(sve_vl));
+            }
+        }
+
+        if self.cpus.pmu_events.is_some() && !cfg!(target_arch = "aarch64") {
+            return Err(ValidationError::CpuOptionUnsupported("pmu_events"));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if self.cpus.features.evmcs && !self.cpus.kvm_hyperv {
+            return Err(ValidationError::EvmcsWithoutKvmHyperv);
+        }
+
         if let Some(rate_limit_groups) = &self.rate_limit_groups {
             for rate_limit_group in rate_limit_groups {
                 rate_limit_group.validate(self)?;
@@ -2324,6 +3741,15 @@ impl VmConfig {
             }
         }
 
+        // The default IPv4 address of a network device depends on whether
+        // it has an IPv6 one, which serde can't account for.
+        for net in self.net.iter_mut().flatten() {
+            let ipv6 = net.ipv6;
+            net.ip.get_or_insert(default_netconfig_ip(ipv6));
+        }
+
+        self.allocate_pool_mac_addresses()?;
+
         if let Some(nets) = &self.net {
             for net in nets {
                 if net.vhost_user && !self.backed_by_shared_memory() {
@@ -2337,7 +3763,8 @@ impl VmConfig {
         }
 
         if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.backed_by_shared_memory() {
+            // Only vhost-user backends need to access the guest memory
+            if fses.iter().any(|fs| !fs.builtin) && !self.backed_by_shared_memory() {
                 return Err(ValidationError::VhostUserRequiresSharedMemory);
             }
             for fs in fses {
@@ -2347,7 +3774,16 @@ impl VmConfig {
             }
         }
 
-        if let Some(pmems) = &self.pmem {
+        if let Some(p9_devices) = &self.p9 {
+            for p9 in p9_devices {
+                p9.validate(self)?;
+                self.iommu |= p9.iommu;
+
+                Self::validate_identifier(&mut id_list, &p9.id)?;
+            }
+        }
+
+        if let Some(pmems) = &self.pmem {
             for pmem in pmems {
                 pmem.validate(self)?;
                 self.iommu |= pmem.iommu;
@@ -2391,6 +3827,53 @@ impl VmConfig {
             }
         }
 
+        if let Some(rt) = &self.rt {
+            rt.validate(self)?;
+        }
+
+        if let Some(payload_verification) = &self.payload_verification {
+            payload_verification.validate(self)?;
+        }
+
+        if let Some(restart_policy) = &self.restart_policy {
+            restart_policy.validate()?;
+        }
+
+        if let Some(boot_detect) = &self.boot_detect {
+            boot_detect.validate(self)?;
+        }
+
+        #[cfg(feature = "vnc")]
+        if self.vnc.is_some() {
+            // The ramfb framebuffer is the only one which can be displayed
+            #[cfg(target_arch = "x86_64")]
+            let ramfb = self.ramfb;
+            #[cfg(not(target_arch = "x86_64"))]
+            let ramfb = false;
+
+            if !ramfb {
+                return Err(ValidationError::VncWithoutRamfb);
+            }
+        }
+
+        if self.memory.locked {
+            if self.balloon.is_some() {
+                return Err(ValidationError::LockedMemoryWithBalloon);
+            }
+
+            let virtio_mem = (self.memory.hotplug_size.is_some()
+                && self.memory.hotplug_method == HotplugMethod::VirtioMem)
+                || self
+                    .memory
+                    .zones
+                    .iter()
+                    .flatten()
+                    .any(|zone| zone.hotplug_size.is_some());
+            if virtio_mem {
+                return Err(ValidationError::LockedMemoryWithVirtioMem);
+            }
+        }
+
         if let Some(user_devices) = &self.user_devices {
             if !user_devices.is_empty() && !self.backed_by_shared_memory() {
                 return Err(ValidationError::UserDevicesRequireSharedMemory);
@@ -2458,6 +3941,19 @@ impl VmConfig {
             Self::validate_identifier(&mut id_list, &vsock.id)?;
         }
 
+        if let Some(usb_devices) = &self.usb {
+            let mut usb_paths = BTreeSet::new();
+            for usb_device in usb_devices {
+                if !usb_paths.insert(usb_device.path.to_string_lossy()) {
+                    return Err(ValidationError::DuplicateUsbDevicePath(
+                        usb_device.path.to_string_lossy().to_string(),
+                    ));
+                }
+
+                Self::validate_identifier(&mut id_list, &usb_device.id)?;
+            }
+        }
+
         let num_pci_segments = match &self.platform {
             Some(platform_config) => platform_config.num_pci_segments,
             None => 1,
@@ -2509,6 +4005,19 @@ impl VmConfig {
             for zone in zones.iter() {
                 let id = zone.id.clone();
                 Self::validate_identifier(&mut id_list, &Some(id))?;
+
+                // The zone is entirely backed by the file shared with or
+                // from the other VMM, only writable by the former.
+                let shared_with = zone.shared_with.is_some();
+                let shared_from = zone.shared_from.is_some();
+                if (shared_with || shared_from)
+                    && ((shared_with && shared_from)
+                        || zone.shared != shared_with
+                        || zone.file.is_some()
+                        || zone.hotplug_size.is_some())
+                {
+                    return Err(ValidationError::InvalidMemoryZoneSharing(zone.id.clone()));
+                }
             }
         }
 
@@ -2583,6 +4092,15 @@ impl VmConfig {
             fs = Some(fs_config_list);
         }
 
+        let mut p9: Option<Vec<P9Config>> = None;
+        if let Some(p9_list) = &vm_params.p9 {
+            let mut p9_config_list = Vec::new();
+            for item in p9_list.iter() {
+                p9_config_list.push(P9Config::parse(item)?);
+            }
+            p9 = Some(p9_config_list);
+        }
+
         let mut pmem: Option<Vec<PmemConfig>> = None;
         if let Some(pmem_list) = &vm_params.pmem {
             let mut pmem_config_list = Vec::new();
@@ -2628,6 +4146,16 @@ impl VmConfig {
             vdpa = Some(vdpa_config_list);
         }
 
+        let mut usb: Option<Vec<UsbConfig>> = None;
+        if let Some(usb_list) = &vm_params.usb {
+            let mut usb_config_list = Vec::new();
+            for item in usb_list.iter() {
+                let usb_config = UsbConfig::parse(item)?;
+                usb_config_list.push(usb_config);
+            }
+            usb = Some(usb_config_list);
+        }
+
         let mut vsock: Option<VsockConfig> = None;
         if let Some(vs) = &vm_params.vsock {
             let vsock_config = VsockConfig::parse(vs)?;
@@ -2646,6 +4174,26 @@ impl VmConfig {
 
         let platform = vm_params.platform.map(PlatformConfig::parse).transpose()?;
 
+        let rt = vm_params.rt.map(RtConfig::parse).transpose()?;
+
+        let payload_verification = vm_params
+            .payload_verification
+            .map(PayloadVerificationConfig::parse)
+            .transpose()?;
+
+        let restart_policy = vm_params
+            .restart_policy
+            .map(RestartPolicyConfig::parse)
+            .transpose()?;
+
+        let boot_detect = vm_params
+            .boot_detect
+            .map(BootDetectConfig::parse)
+            .transpose()?;
+
+        #[cfg(feature = "vnc")]
+        let vnc = vm_params.vnc.map(VncConfig::parse).transpose()?;
+
         #[cfg(target_arch = "x86_64")]
         let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
         #[cfg(target_arch = "x86_64")]
@@ -2713,6 +4261,7 @@ impl VmConfig {
             rng,
             balloon,
             fs,
+            p9,
             pmem,
             serial,
             console,
@@ -2722,7 +4271,13 @@ impl VmConfig {
             user_devices,
             vdpa,
             vsock,
+            xhci: vm_params.xhci,
+            usb,
             pvpanic: vm_params.pvpanic,
+            #[cfg(target_arch = "x86_64")]
+            ramfb: vm_params.ramfb,
+            #[cfg(feature = "vnc")]
+            vnc,
             iommu: false, // updated in VmConfig::validate()
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
@@ -2733,6 +4288,10 @@ impl VmConfig {
             pci_segments,
             platform,
             tpm,
+            rt,
+            payload_verification,
+            restart_policy,
+            boot_detect,
             preserved_fds: None,
         };
         config.validate().map_err(Error::Validation)?;
@@ -2770,6 +4329,13 @@ impl VmConfig {
             removed |= fs.len() != len;
         }
 
+        // Remove if 9p device
+        if let Some(p9) = self.p9.as_mut() {
+            let len = p9.len();
+            p9.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= p9.len() != len;
+        }
+
         // Remove if net device
         if let Some(net) = self.net.as_mut() {
             let len = net.len();
@@ -2791,6 +4357,13 @@ impl VmConfig {
             removed |= vdpa.len() != len;
         }
 
+        // Remove if host USB device
+        if let Some(usb) = self.usb.as_mut() {
+            let len = usb.len();
+            usb.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= usb.len() != len;
+        }
+
         // Remove if vsock device
         if let Some(vsock) = self.vsock.as_ref() {
             if vsock.id.as_ref().map(|id| id.as_ref()) == Some(id) {
@@ -2802,6 +4375,65 @@ impl VmConfig {
         removed
     }
 
+    pub fn replace_device(&mut self, id: &str, replacement: ReplacementDeviceConfig) -> bool {
+        if !self.remove_device(id) {
+            return false;
+        }
+
+        match replacement {
+            ReplacementDeviceConfig::Disk(cfg) => add_to_config(&mut self.disks, cfg),
+            ReplacementDeviceConfig::Fs(cfg) => add_to_config(&mut self.fs, cfg),
+            ReplacementDeviceConfig::Net(cfg) => add_to_config(&mut self.net, cfg),
+            ReplacementDeviceConfig::Pmem(cfg) => add_to_config(&mut self.pmem, cfg),
+        }
+
+        true
+    }
+
+    /// Gives the VM the identity of an instance, as a ready VM gets on boot.
+    /// The disks are switched to overlays of the images they used.
+    pub fn set_identity(&mut self, identity: &VmIdentityConfig) -> ValidationResult<()> {
+        for net in &identity.net {
+            let net_cfg = self
+                .net
+                .iter_mut()
+                .flatten()
+                .find(|net_cfg| net_cfg.id.as_ref() == Some(&net.id))
+                .ok_or_else(|| ValidationError::IdentityDeviceNotFound(net.id.clone()))?;
+            if net_cfg.vhost_user {
+                return Err(ValidationError::IdentityUnsupported(net.id.clone()));
+            }
+            net_cfg.mac = net.mac;
+        }
+
+        for disk in &identity.disks {
+            let disk_cfg = self
+                .disks
+                .iter_mut()
+                .flatten()
+                .find(|disk_cfg| disk_cfg.id.as_ref() == Some(&disk.id))
+                .ok_or_else(|| ValidationError::IdentityDeviceNotFound(disk.id.clone()))?;
+            if disk_cfg.vhost_user || disk_cfg.ephemeral || disk_cfg.path.is_none() {
+                return Err(ValidationError::IdentityUnsupported(disk.id.clone()));
+            }
+            disk_cfg.base = disk_cfg.path.replace(disk.overlay.clone());
+            disk_cfg.create_overlay = true;
+        }
+
+        if let Some(cid) = identity.vsock_cid {
+            let vsock_cfg = self
+                .vsock
+                .as_mut()
+                .ok_or_else(|| ValidationError::IdentityDeviceNotFound("vsock".to_string()))?;
+            if [!0, 0, 1, 2].contains(&cid) {
+                return Err(ValidationError::VsockSpecialCid(cid));
+            }
+            vsock_cfg.cid = cid;
+        }
+
+        Ok(())
+    }
+
     /// # Safety
     /// To use this safely, the caller must guarantee that the input
     /// fds are all valid.
@@ -2817,6 +4449,14 @@ impl VmConfig {
         self.preserved_fds = Some(fds);
     }
 
+    pub fn legacy_device_enabled(&self, device: LegacyDevice) -> bool {
+        self.platform
+            .as_ref()
+            .and_then(|p| p.legacy_devices.as_ref())
+            .map(|devices| devices.contains(&device))
+            .unwrap_or(true)
+    }
+
     #[cfg(feature = "tdx")]
     pub fn is_tdx_enabled(&self) -> bool {
         self.platform.as_ref().map(|p| p.tdx).unwrap_or(false)
@@ -2840,6 +4480,7 @@ impl Clone for VmConfig {
             rng: self.rng.clone(),
             balloon: self.balloon.clone(),
             fs: self.fs.clone(),
+            p9: self.p9.clone(),
             pmem: self.pmem.clone(),
             serial: self.serial.clone(),
             console: self.console.clone(),
@@ -2849,12 +4490,19 @@ impl Clone for VmConfig {
             user_devices: self.user_devices.clone(),
             vdpa: self.vdpa.clone(),
             vsock: self.vsock.clone(),
+            usb: self.usb.clone(),
             #[cfg(target_arch = "x86_64")]
             sgx_epc: self.sgx_epc.clone(),
             numa: self.numa.clone(),
             pci_segments: self.pci_segments.clone(),
             platform: self.platform.clone(),
             tpm: self.tpm.clone(),
+            rt: self.rt.clone(),
+            payload_verification: self.payload_verification.clone(),
+            restart_policy: self.restart_policy.clone(),
+            boot_detect: self.boot_detect.clone(),
+            #[cfg(feature = "vnc")]
+            vnc: self.vnc.clone(),
             preserved_fds: self
                 .preserved_fds
                 .as_ref()
@@ -2879,7 +4527,7 @@ impl Drop for VmConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use net_util::MacAddr;
+    use net_util::{EgressShaping, MacAddr};
     use std::fs::File;
     use std::net::Ipv4Addr;
     use std::os::unix::io::AsRawFd;
@@ -2930,6 +4578,20 @@ mod tests {
                 ..Default::default()
             }
         );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            CpusConfig::parse("boot=1,kvm_hyperv=on,features=evmcs")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                kvm_hyperv: true,
+                features: CpuFeatures {
+                    evmcs: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        );
         assert_eq!(
             CpusConfig::parse("boot=2,affinity=[0@[0,2],1@[1,3]]")?,
             CpusConfig {
@@ -2948,6 +4610,17 @@ mod tests {
                 ..Default::default()
             },
         );
+        assert_eq!(
+            CpusConfig::parse("boot=2,sve_vl=512,pmu_events=[8,17-18]")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                sve_vl: Some(512),
+                pmu_events: Some(vec![8, 17, 18]),
+                ..Default::default()
+            },
+        );
+        assert!(CpusConfig::parse("pmu_events=[65536]").is_err());
 
         Ok(())
     }
@@ -3013,6 +4686,33 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("hugepages=try,size=1G", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                hugepages: true,
+                hugepages_fallback: true,
+                ..Default::default()
+            }
+        );
+        assert!(MemoryConfig::parse("hugepages=maybe", None).is_err());
+        assert_eq!(
+            MemoryConfig::parse("size=1G,locked=on", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                locked: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("locked=on,lock_policy=best-effort", None)?,
+            MemoryConfig {
+                locked: true,
+                lock_policy: MemoryLockPolicy::BestEffort,
+                ..Default::default()
+            }
+        );
+        assert!(MemoryConfig::parse("locked=on,lock_policy=lax", None).is_err());
         Ok(())
     }
 
@@ -3092,8 +4792,14 @@ mod tests {
     fn disk_fixture() -> DiskConfig {
         DiskConfig {
             path: Some(PathBuf::from("/path/to_file")),
+            fd: None,
             readonly: false,
             direct: false,
+            read_ahead: false,
+            base: None,
+            create_overlay: false,
+            ephemeral: false,
+            secure_erase: false,
             iommu: false,
             num_queues: 1,
             queue_size: 128,
@@ -3132,6 +4838,14 @@ mod tests {
                 ..disk_fixture()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("fd=5")?,
+            DiskConfig {
+                path: None,
+                fd: Some(5),
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,iommu=on")?,
             DiskConfig {
@@ -3163,6 +4877,33 @@ mod tests {
                 ..disk_fixture()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,read_ahead=on")?,
+            DiskConfig {
+                read_ahead: true,
+                ..disk_fixture()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("base=/path/base.raw,overlay=/path/to_file,create_overlay=on")?,
+            DiskConfig {
+                base: Some(PathBuf::from("/path/base.raw")),
+                create_overlay: true,
+                ..disk_fixture()
+            }
+        );
+        assert!(matches!(
+            DiskConfig::parse("path=/path/to_file,overlay=/path/to_file"),
+            Err(Error::ParseDiskPathAndOverlay)
+        ));
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,ephemeral=on,secure_erase=on")?,
+            DiskConfig {
+                ephemeral: true,
+                secure_erase: true,
+                ..disk_fixture()
+            }
+        );
         assert_eq!(
             DiskConfig::parse("path=/path/to_file,serial=test")?,
             DiskConfig {
@@ -3207,9 +4948,10 @@ mod tests {
     fn net_fixture() -> NetConfig {
         NetConfig {
             tap: None,
-            ip: Ipv4Addr::new(192, 168, 249, 1),
+            ip: Some(Ipv4Addr::new(192, 168, 249, 1)),
             mask: Ipv4Addr::new(255, 255, 255, 0),
             mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+            mac_pool: None,
             host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
             mtu: None,
             iommu: false,
@@ -3218,6 +4960,7 @@ mod tests {
             vhost_user: false,
             vhost_socket: None,
             vhost_mode: VhostMode::Client,
+            vhost_instance_id: None,
             id: None,
             fds: None,
             rate_limiter_config: None,
@@ -3225,6 +4968,13 @@ mod tests {
             offload_tso: true,
             offload_ufo: true,
             offload_csum: true,
+            bridge: None,
+            vlan: None,
+            model: NetModel::Virtio,
+            egress_shaping: None,
+            ipv6: None,
+            ipv6_prefix_len: 64,
+            ipv6_ra: false,
         }
     }
 
@@ -3250,7 +5000,7 @@ mod tests {
             )?,
             NetConfig {
                 tap: Some("tap0".to_owned()),
-                ip: "192.168.100.1".parse().unwrap(),
+                ip: Some("192.168.100.1".parse().unwrap()),
                 mask: "255.255.255.128".parse().unwrap(),
                 ..net_fixture()
             }
@@ -3267,6 +5017,18 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock,vhost_instance_id=vm42"
+            )?,
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                vhost_instance_id: Some("vm42".to_owned()),
+                ..net_fixture()
+            }
+        );
+
         assert_eq!(
             NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
             NetConfig {
@@ -3287,6 +5049,78 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse("mac_pool=52:54:00:xx,host_mac=12:34:de:ad:be:ef")?.mac_pool,
+            Some("52:54:00:xx".to_owned())
+        );
+        assert!(NetConfig::parse("mac=de:ad:be:ef:12:34,mac_pool=52:54:00:xx").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,bridge=br0,vlan=10"
+            )?,
+            NetConfig {
+                bridge: Some("br0".to_owned()),
+                vlan: Some(10),
+                ..net_fixture()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,model=e1000e")?,
+            NetConfig {
+                model: NetModel::E1000e,
+                ..net_fixture()
+            }
+        );
+        assert!(NetConfig::parse("model=rtl8139").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,egress_shaping=htb:100mbit"
+            )?,
+            NetConfig {
+                egress_shaping: Some(EgressShaping::Htb { rate: 100_000_000 }),
+                ..net_fixture()
+            }
+        );
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,egress_shaping=netem:20ms/5ms"
+            )?,
+            NetConfig {
+                egress_shaping: Some(EgressShaping::Netem {
+                    delay_us: 20_000,
+                    jitter_us: 5_000
+                }),
+                ..net_fixture()
+            }
+        );
+        assert!(NetConfig::parse("egress_shaping=htb:fast").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,ipv6=fd00::1,ipv6_prefix_len=48,ipv6_ra=on"
+            )?,
+            NetConfig {
+                ip: Some(Ipv4Addr::UNSPECIFIED),
+                ipv6: Some("fd00::1".parse().unwrap()),
+                ipv6_prefix_len: 48,
+                ipv6_ra: true,
+                ..net_fixture()
+            }
+        );
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,ip=192.168.249.1,ipv6=fd00::1"
+            )?,
+            NetConfig {
+                ipv6: Some("fd00::1".parse().unwrap()),
+                ..net_fixture()
+            }
+        );
+        assert!(NetConfig::parse("ipv6=192.168.249.1").is_err());
+
         Ok(())
     }
 
@@ -3325,6 +5159,9 @@ mod tests {
             queue_size: 1024,
             id: None,
             pci_segment: 0,
+            builtin: false,
+            shared_dir: None,
+            readonly: false,
         }
     }
 
@@ -3343,6 +5180,70 @@ mod tests {
                 ..fs_fixture()
             }
         );
+        // "shared_dir" replaces "socket" for the built-in device
+        assert_eq!(
+            FsConfig::parse("tag=mytag,builtin=on,shared_dir=/tmp/shared")?,
+            FsConfig {
+                socket: PathBuf::new(),
+                builtin: true,
+                shared_dir: Some(PathBuf::from("/tmp/shared")),
+                ..fs_fixture()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,builtin=on,shared_dir=/tmp/shared,readonly=on")?,
+            FsConfig {
+                socket: PathBuf::new(),
+                builtin: true,
+                shared_dir: Some(PathBuf::from("/tmp/shared")),
+                readonly: true,
+                ..fs_fixture()
+            }
+        );
+        assert!(matches!(
+            FsConfig::parse("tag=mytag,builtin=on"),
+            Err(Error::ParseFsSharedDirMissing)
+        ));
+        assert!(matches!(
+            FsConfig::parse("tag=mytag,builtin=on,socket=/tmp/sock,shared_dir=/tmp/shared"),
+            Err(Error::ParseFsSockWithBuiltin)
+        ));
+
+        Ok(())
+    }
+
+    fn p9_fixture() -> P9Config {
+        P9Config {
+            tag: "mytag".to_owned(),
+            path: PathBuf::from("/tmp/shared"),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_p9() -> Result<()> {
+        // "tag" and "path" must be supplied
+        assert!(P9Config::parse("").is_err());
+        assert!(matches!(
+            P9Config::parse("path=/tmp/shared"),
+            Err(Error::ParseP9TagMissing)
+        ));
+        assert!(matches!(
+            P9Config::parse("tag=mytag"),
+            Err(Error::ParseP9PathMissing)
+        ));
+        assert_eq!(P9Config::parse("tag=mytag,path=/tmp/shared")?, p9_fixture());
+        assert_eq!(
+            P9Config::parse("tag=mytag,path=/tmp/shared,iommu=on,id=my9p,pci_segment=1")?,
+            P9Config {
+                iommu: true,
+                id: Some("my9p".to_owned()),
+                pci_segment: 1,
+                ..p9_fixture()
+            }
+        );
 
         Ok(())
     }
@@ -3472,6 +5373,7 @@ mod tests {
             iommu: false,
             pci_segment: 0,
             x_nv_gpudirect_clique: None,
+            romfile: None,
         }
     }
 
@@ -3501,6 +5403,14 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,romfile=/path/to/vbios.rom")?,
+            DeviceConfig {
+                romfile: Some(PathBuf::from("/path/to/vbios.rom")),
+                ..device_fixture()
+            }
+        );
+
         Ok(())
     }
 
@@ -3530,6 +5440,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_usb_parsing() -> Result<()> {
+        // path is required
+        assert!(UsbConfig::parse("").is_err());
+        assert!(UsbConfig::parse("id=my_usb").is_err());
+        assert_eq!(
+            UsbConfig::parse("path=/dev/bus/usb/001/004")?,
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: None,
+            }
+        );
+        assert_eq!(
+            UsbConfig::parse("path=/dev/bus/usb/001/004,id=my_usb")?,
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: Some("my_usb".to_owned()),
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_tpm_parsing() -> Result<()> {
         // path is required
@@ -3543,6 +5475,54 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
+        // source_url is required
+        assert!(RestoreConfig::parse("prefault=on").is_err());
+        assert_eq!(
+            RestoreConfig::parse("source_url=file:///tmp/snapshot,key_fd=3")?,
+            RestoreConfig {
+                source_url: PathBuf::from("file:///tmp/snapshot"),
+                prefault: false,
+                key_fd: Some(3),
+                ready: false,
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=file:///tmp/snapshot,ready=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("file:///tmp/snapshot"),
+                prefault: false,
+                key_fd: None,
+                ready: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "vnc")]
+    fn test_vnc_parsing() -> Result<()> {
+        // socket is required, the clients not being authenticated
+        assert!(VncConfig::parse("").is_err());
+        assert!(VncConfig::parse("listen=127.0.0.1:5900").is_err());
+        assert_eq!(
+            VncConfig::parse("socket=/tmp/vnc.sock")?,
+            VncConfig {
+                socket: PathBuf::from("/tmp/vnc.sock"),
+                input: false,
+            }
+        );
+        assert_eq!(
+            VncConfig::parse("socket=/tmp/vnc.sock,input=on")?,
+            VncConfig {
+                socket: PathBuf::from("/tmp/vnc.sock"),
+                input: true,
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_vsock_parsing() -> Result<()> {
         // socket and cid is required
@@ -3570,17 +5550,202 @@ mod tests {
         Ok(())
     }
 
-    fn platform_fixture() -> PlatformConfig {
-        PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: None,
-            serial_number: None,
-            uuid: None,
-            oem_strings: None,
-            #[cfg(feature = "tdx")]
-            tdx: false,
-            #[cfg(feature = "sev_snp")]
+    #[test]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(
+            PlatformConfig::parse("num_pci_segments=2")?,
+            PlatformConfig {
+                num_pci_segments: 2,
+                ..platform_fixture()
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("num_pci_segments=2,device_numa_policy=bind")?,
+            PlatformConfig {
+                num_pci_segments: 2,
+                device_numa_policy: DeviceNumaPolicy::Bind,
+                ..platform_fixture()
+            }
+        );
+        assert!(PlatformConfig::parse("device_numa_policy=interleave").is_err());
+        assert_eq!(
+            PlatformConfig::parse("device_access_warn_us=500")?,
+            PlatformConfig {
+                device_access_warn_us: Some(500),
+                ..platform_fixture()
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("legacy_devices=[rtc,i8042]")?,
+            PlatformConfig {
+                legacy_devices: Some(vec![LegacyDevice::Rtc, LegacyDevice::I8042]),
+                ..platform_fixture()
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("legacy_devices=[]")?,
+            PlatformConfig {
+                legacy_devices: Some(vec![]),
+                ..platform_fixture()
+            }
+        );
+        assert!(PlatformConfig::parse("legacy_devices=[pit]").is_err());
+        assert_eq!(
+            PlatformConfig::parse("on_reset=shutdown")?,
+            PlatformConfig {
+                on_reset: ResetPolicy::Shutdown,
+                ..platform_fixture()
+            }
+        );
+        assert!(PlatformConfig::parse("on_reset=poweroff").is_err());
+        assert_eq!(
+            PlatformConfig::parse("dt_overlay=[/tmp/a.dtbo,/tmp/b.dtbo]")?,
+            PlatformConfig {
+                dt_overlay: Some(vec![
+                    PathBuf::from("/tmp/a.dtbo"),
+                    PathBuf::from("/tmp/b.dtbo")
+                ]),
+                ..platform_fixture()
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("mmio32_size=256M,mmio64_size=64G")?,
+            PlatformConfig {
+                mmio32_size: Some(256 << 20),
+                mmio64_size: Some(64 << 30),
+                ..platform_fixture()
+            }
+        );
+        assert_eq!(
+            PlatformConfig::parse("vmgenid=on")?,
+            PlatformConfig {
+                vmgenid: true,
+                ..platform_fixture()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rt_parsing() -> Result<()> {
+        assert_eq!(
+            RtConfig::parse("")?,
+            RtConfig {
+                cpus: None,
+                priority: DEFAULT_RT_PRIORITY,
+            }
+        );
+        assert_eq!(
+            RtConfig::parse("cpus=[2-5],priority=10")?,
+            RtConfig {
+                cpus: Some(vec![2, 3, 4, 5]),
+                priority: 10,
+            }
+        );
+        assert!(RtConfig::parse("priority=high").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_verification_parsing() -> Result<()> {
+        assert_eq!(
+            PayloadVerificationConfig::parse(
+                "certs=/path/to/certs.pem,kernel_signature=/path/to/kernel.p7s"
+            )?,
+            PayloadVerificationConfig {
+                certs: PathBuf::from("/path/to/certs.pem"),
+                kernel_signature: Some(PathBuf::from("/path/to/kernel.p7s")),
+                initramfs_signature: None,
+            }
+        );
+        assert!(PayloadVerificationConfig::parse("kernel_signature=/path/to/kernel.p7s").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_policy_parsing() -> Result<()> {
+        assert_eq!(
+            RestartPolicyConfig::parse("on-failure,max=3,backoff=5s")?,
+            RestartPolicyConfig {
+                mode: RestartMode::OnFailure,
+                max: Some(3),
+                backoff_ms: 5000,
+            }
+        );
+        assert_eq!(
+            RestartPolicyConfig::parse("max=2")?,
+            RestartPolicyConfig {
+                mode: RestartMode::OnFailure,
+                max: Some(2),
+                backoff_ms: DEFAULT_RESTART_BACKOFF_MS,
+            }
+        );
+        assert_eq!(
+            RestartPolicyConfig::parse("always,backoff=250ms")?.backoff_ms,
+            250
+        );
+        assert_eq!(
+            RestartPolicyConfig::parse("always,backoff=2m")?.backoff_ms,
+            120_000
+        );
+        assert_eq!(
+            RestartPolicyConfig::parse("always,backoff=3")?.backoff_ms,
+            3000
+        );
+        assert_eq!(RestartPolicyConfig::parse("no")?.mode, RestartMode::No);
+        assert!(RestartPolicyConfig::parse("no,always").is_err());
+        assert!(RestartPolicyConfig::parse("on-failure,backoff=soon").is_err());
+        assert!(RestartPolicyConfig::parse("on-failure,max=-1").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_detect_parsing() -> Result<()> {
+        assert_eq!(
+            BootDetectConfig::parse("console_marker=login:")?,
+            BootDetectConfig {
+                console_marker: Some("login:".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            BootDetectConfig::parse("vsock_port=1024,acpi=on")?,
+            BootDetectConfig {
+                vsock_port: Some(1024),
+                acpi: true,
+                ..Default::default()
+            }
+        );
+        assert!(BootDetectConfig::parse("").is_err());
+        assert!(BootDetectConfig::parse("acpi=off").is_err());
+        assert!(BootDetectConfig::parse("vsock_port=foo").is_err());
+
+        Ok(())
+    }
+
+    fn platform_fixture() -> PlatformConfig {
+        PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: None,
+            serial_number: None,
+            uuid: None,
+            oem_strings: None,
+            #[cfg(feature = "tdx")]
+            tdx: false,
+            #[cfg(feature = "sev_snp")]
             sev_snp: false,
+            device_numa_policy: DeviceNumaPolicy::Off,
+            device_access_warn_us: None,
+            legacy_devices: None,
+            on_reset: ResetPolicy::Restart,
+            dt_overlay: None,
+            mmio32_size: None,
+            mmio64_size: None,
+            vmgenid: false,
         }
     }
 
@@ -3613,9 +5778,12 @@ mod tests {
                 shared: false,
                 hugepages: false,
                 hugepage_size: None,
+                hugepages_fallback: false,
                 prefault: false,
                 zones: None,
                 thp: true,
+                locked: false,
+                lock_policy: MemoryLockPolicy::Strict,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -3638,6 +5806,7 @@ mod tests {
             },
             balloon: None,
             fs: None,
+            p9: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
@@ -3657,7 +5826,13 @@ mod tests {
             user_devices: None,
             vdpa: None,
             vsock: None,
+            xhci: false,
+            usb: None,
             pvpanic: false,
+            #[cfg(target_arch = "x86_64")]
+            ramfb: false,
+            #[cfg(feature = "vnc")]
+            vnc: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
@@ -3668,6 +5843,10 @@ mod tests {
             pci_segments: None,
             platform: None,
             tpm: None,
+            rt: None,
+            payload_verification: None,
+            restart_policy: None,
+            boot_detect: None,
             preserved_fds: None,
         };
 
@@ -3701,6 +5880,40 @@ mod tests {
             Err(ValidationError::CpusMaxLowerThanBoot)
         );
 
+        #[cfg(target_arch = "aarch64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.sve_vl = Some(192);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidSveVectorLength(192))
+            );
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.pmu_events = Some(vec![8]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::CpuOptionUnsupported("pmu_events"))
+            );
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.features.evmcs = true;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::EvmcsWithoutKvmHyperv)
+            );
+
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.features.evmcs = true;
+            still_valid_config.cpus.kvm_hyperv = true;
+            assert!(still_valid_config.validate().is_ok());
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
         invalid_config.cpus.boot_vcpus = 16;
@@ -3726,6 +5939,81 @@ mod tests {
             Err(ValidationError::DiskSocketAndPath)
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fd: Some(5),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskFdAndPath)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            fd: Some(2),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskReservedFd)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            direct: true,
+            read_ahead: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskReadAheadUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: None,
+            fd: Some(3),
+            base: Some(PathBuf::from("/path/base.raw")),
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskBaseWithoutOverlay)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            create_overlay: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskOverlayWithoutBase)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            readonly: true,
+            ephemeral: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskEphemeralUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            secure_erase: true,
+            ..disk_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DiskSecureEraseWithoutEphemeral)
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.memory.shared = true;
         invalid_config.disks = Some(vec![DiskConfig {
@@ -3760,6 +6048,274 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            mac_pool: Some("52:54:xx:00".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMacPool("52:54:xx:00".to_owned()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            mac_pool: Some("01:00:5e:xx".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMacPool("01:00:5e:xx".to_owned()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vlan: Some(10),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetVlanWithoutBridge)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            bridge: Some("br0".to_owned()),
+            vlan: Some(4095),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidVlanId(4095))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            bridge: Some("br0".to_owned()),
+            fds: Some(vec![3]),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetBridgeRequiresTap)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            fds: Some(vec![3]),
+            egress_shaping: Some(EgressShaping::Htb { rate: 1_000_000 }),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetEgressShapingRequiresTap)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            ipv6: Some("fd00::1".parse().unwrap()),
+            bridge: Some("br0".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetIpv6RequiresTap)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            ipv6: Some("fd00::1".parse().unwrap()),
+            ipv6_prefix_len: 129,
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIpv6PrefixLen(129))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            ipv6_ra: true,
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetIpv6RaWithoutAddress)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_instance_id: Some("vm42".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetVhostInstanceIdRequiresClient)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/tmp/sock".to_owned()),
+            vhost_mode: VhostMode::Server,
+            vhost_instance_id: Some("vm42".to_owned()),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NetVhostInstanceIdRequiresClient)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/tmp/sock".to_owned()),
+            vhost_instance_id: Some("x".repeat(256)),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidVhostInstanceId("x".repeat(256)))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            model: NetModel::E1000e,
+            rate_limiter_config: Some(RateLimiterConfig {
+                bandwidth: Some(TokenBucketConfig {
+                    size: 1000,
+                    one_time_burst: None,
+                    refill_time: 100,
+                }),
+                ops: None,
+            }),
+            ..net_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::E1000eUnsupportedOption("rate_limiter"))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            legacy_devices: Some(vec![LegacyDevice::Rtc]),
+            ..platform_fixture()
+        });
+        still_valid_config.validate().unwrap();
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tty;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::SerialLegacyDeviceDisabled)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.payload_verification = Some(PayloadVerificationConfig {
+            certs: PathBuf::from("/path/to/certs.pem"),
+            kernel_signature: None,
+            initramfs_signature: None,
+        });
+        #[cfg(feature = "payload_verification")]
+        {
+            invalid_config.validate().unwrap();
+            invalid_config.payload.as_mut().unwrap().initramfs =
+                Some(PathBuf::from("/path/to/initramfs"));
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::PayloadSignatureMissing("initramfs"))
+            );
+        }
+        #[cfg(not(feature = "payload_verification"))]
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::PayloadVerificationUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.restart_policy = Some(RestartPolicyConfig {
+            mode: RestartMode::OnFailure,
+            max: Some(0),
+            backoff_ms: DEFAULT_RESTART_BACKOFF_MS,
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidRestartMax)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.boot_detect = Some(BootDetectConfig {
+            vsock_port: Some(1024),
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::BootDetectWithoutVsock)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Off;
+        invalid_config.boot_detect = Some(BootDetectConfig {
+            console_marker: Some("login:".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::BootDetectWithoutSerial)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            uuid: Some("1e8aa28a-435d-4027-87f4-40dceff1fa0a".to_owned()),
+            ..platform_fixture()
+        });
+        still_valid_config.net = Some(vec![
+            NetConfig {
+                mac: MacAddr::parse_str("52:54:00:12:34:56").unwrap(),
+                ..net_fixture()
+            },
+            NetConfig {
+                mac_pool: Some("52:54:00:xx".to_owned()),
+                ..net_fixture()
+            },
+        ]);
+        let mut other_config = still_valid_config.clone();
+        assert!(still_valid_config.validate().is_ok());
+        assert!(other_config.validate().is_ok());
+        let nets = still_valid_config.net.as_ref().unwrap();
+        assert_eq!(nets[1].mac_pool, None);
+        assert_eq!(&nets[1].mac.get_bytes()[..3], &[0x52, 0x54, 0x00]);
+        assert_ne!(nets[1].mac, nets[0].mac);
+        // The allocation is deterministic
+        assert_eq!(nets[1].mac, other_config.net.as_ref().unwrap()[1].mac);
+
+        // Even without any identity for the VM
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            mac_pool: Some("52:54:00:xx".to_owned()),
+            ..net_fixture()
+        }]);
+        let mut other_config = still_valid_config.clone();
+        assert!(still_valid_config.validate().is_ok());
+        assert!(other_config.validate().is_ok());
+        assert_eq!(
+            still_valid_config.net.as_ref().unwrap()[0].mac,
+            other_config.net.as_ref().unwrap()[0].mac
+        );
+
+        // The default IPv4 address depends on the address family
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![
+            serde_json::from_str(r#"{"tap": "tap0"}"#).unwrap(),
+            serde_json::from_str(r#"{"tap": "tap1", "ipv6": "fd00::1"}"#).unwrap(),
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+        let nets = still_valid_config.net.as_ref().unwrap();
+        assert_eq!(nets[0].ip, Some(Ipv4Addr::new(192, 168, 249, 1)));
+        assert_eq!(nets[1].ip, Some(Ipv4Addr::UNSPECIFIED));
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -3819,6 +6375,79 @@ mod tests {
         still_valid_config.memory.hugepage_size = Some(2 << 20);
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![fs_fixture()]);
+        invalid_config.memory.hugepages = true;
+        invalid_config.memory.hugepages_fallback = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.locked = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.locked = true;
+        invalid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+        invalid_config.memory.hotplug_size = Some(1 << 30);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::LockedMemoryWithVirtioMem)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.locked = true;
+        invalid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: false,
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::LockedMemoryWithBalloon)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory = MemoryConfig::parse(
+            "size=0",
+            Some(vec![
+                "id=mem0,size=1G,shared=on,shared_with=/tmp/mem0.sock",
+                "id=mem1,size=1G,shared_from=/tmp/mem1.sock",
+            ]),
+        )
+        .unwrap();
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory = MemoryConfig::parse(
+            "size=0",
+            Some(vec!["id=mem0,size=1G,shared_with=/tmp/mem0.sock"]),
+        )
+        .unwrap();
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMemoryZoneSharing(
+                "mem0".to_string()
+            ))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory = MemoryConfig::parse(
+            "size=0",
+            Some(vec![
+                "id=mem0,size=1G,shared_from=/tmp/mem0.sock,file=/dev/shm/mem0",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMemoryZoneSharing(
+                "mem0".to_string()
+            ))
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.memory.hugepages = false;
         invalid_config.memory.hugepage_size = Some(2 << 20);
@@ -3868,6 +6497,44 @@ mod tests {
             Err(ValidationError::InvalidPciSegment(MAX_NUM_PCI_SEGMENTS + 1))
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            mmio32_size: Some(256 << 20),
+            mmio64_size: Some(64 << 30),
+            ..platform_fixture()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                mmio32_size: Some(2 << 30),
+                ..platform_fixture()
+            });
+            assert!(still_valid_config.validate().is_ok());
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            mmio32_size: Some(4 << 30),
+            ..platform_fixture()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMmio32Size(4 << 30))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            mmio64_size: Some(1 << 30),
+            ..platform_fixture()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMmio64Size(1 << 30))
+        );
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.platform = Some(PlatformConfig {
             iommu_segments: Some(vec![1, 2, 3]),
@@ -4039,6 +6706,24 @@ mod tests {
             Err(ValidationError::OnIommuSegment(1))
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.usb = Some(vec![
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: None,
+            },
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: None,
+            },
+        ]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicateUsbDevicePath(
+                "/dev/bus/usb/001/004".to_string()
+            ))
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.memory.shared = true;
         invalid_config.platform = Some(PlatformConfig {
@@ -4054,6 +6739,46 @@ mod tests {
             Err(ValidationError::IommuNotSupportedOnSegment(1))
         );
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![FsConfig {
+            builtin: true,
+            ..fs_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(if cfg!(feature = "fs_builtin") {
+                ValidationError::FsBuiltinSharedDirMissing
+            } else {
+                ValidationError::FsBuiltinNotSupported
+            })
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.fs = Some(vec![FsConfig {
+            readonly: true,
+            ..fs_fixture()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::FsReadonlyNotBuiltin)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        let p9 = P9Config {
+            id: Some("my9p".to_owned()),
+            ..p9_fixture()
+        };
+        invalid_config.p9 = Some(vec![p9.clone(), p9]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(if cfg!(feature = "virtio_9p") {
+                ValidationError::IdentifierNotUnique("my9p".to_owned())
+            } else {
+                ValidationError::P9NotSupported
+            })
+        );
+
         let mut invalid_config = valid_config.clone();
         invalid_config.platform = Some(PlatformConfig {
             num_pci_segments: 2,
@@ -4215,6 +6940,67 @@ mod tests {
             assert!(config_with_invalid_host_data.validate().is_err());
         }
 
+        let mut replaced_config = valid_config.clone();
+        replaced_config.disks = Some(vec![DiskConfig {
+            id: Some("disk0".to_owned()),
+            ..disk_fixture()
+        }]);
+        let replacement = PmemConfig {
+            id: Some("disk0".to_owned()),
+            ..pmem_fixture()
+        };
+        assert!(!replaced_config
+            .replace_device("disk1", ReplacementDeviceConfig::Pmem(replacement.clone())));
+        assert!(replaced_config
+            .replace_device("disk0", ReplacementDeviceConfig::Pmem(replacement.clone())));
+        assert_eq!(replaced_config.disks, Some(Vec::new()));
+        assert_eq!(replaced_config.pmem, Some(vec![replacement]));
+
+        let mut identity_config = valid_config.clone();
+        identity_config.disks = Some(vec![DiskConfig {
+            id: Some("disk0".to_owned()),
+            ..disk_fixture()
+        }]);
+        identity_config.vsock = Some(VsockConfig {
+            cid: 3,
+            socket: PathBuf::from("/tmp/sock"),
+            iommu: false,
+            id: None,
+            pci_segment: 0,
+        });
+        assert!(identity_config
+            .clone()
+            .set_identity(&VmIdentityConfig {
+                vsock_cid: Some(2),
+                ..Default::default()
+            })
+            .is_err());
+        assert!(identity_config
+            .clone()
+            .set_identity(&VmIdentityConfig {
+                net: vec![NetIdentityConfig {
+                    id: "net0".to_owned(),
+                    mac: MacAddr::local_random(),
+                }],
+                ..Default::default()
+            })
+            .is_err());
+        identity_config
+            .set_identity(&VmIdentityConfig {
+                disks: vec![DiskIdentityConfig {
+                    id: "disk0".to_owned(),
+                    overlay: PathBuf::from("/path/to/overlay"),
+                }],
+                vsock_cid: Some(4),
+                ..Default::default()
+            })
+            .unwrap();
+        let disk = &identity_config.disks.as_ref().unwrap()[0];
+        assert_eq!(disk.path, Some(PathBuf::from("/path/to/overlay")));
+        assert_eq!(disk.base, disk_fixture().path);
+        assert!(disk.create_overlay);
+        assert_eq!(identity_config.vsock.unwrap().cid, 4);
+
         let mut still_valid_config = valid_config;
         // SAFETY: Safe as the file was just opened
         let fd1 = unsafe { libc::dup(File::open("/dev/null").unwrap().as_raw_fd()) };
@@ -4226,4 +7012,17 @@ mod tests {
         }
         let _still_valid_config = still_valid_config.clone();
     }
+
+    #[test]
+    fn test_validation_error_field() {
+        assert_eq!(ValidationError::KernelMissing.field(), Some("payload"));
+        assert_eq!(ValidationError::InvalidVlanId(0).field(), Some("net"));
+        assert_eq!(ValidationError::VsockSpecialCid(2).field(), Some("vsock"));
+        assert_eq!(ValidationError::InvalidRtPriority(0).field(), Some("rt"));
+        // Shared by all the device types
+        assert_eq!(
+            ValidationError::IdentifierNotUnique("disk0".to_owned()).field(),
+            None
+        );
+    }
 }
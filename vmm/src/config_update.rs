@@ -0,0 +1,375 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Updates of the VM configuration given as a partial configuration, limited
+//! to the fields the devices can take into account while the VM is running.
+
+use crate::vm_config::{ConsoleConfig, ConsoleOutputMode, VmConfig};
+use serde_json::{map::Entry, Value};
+use std::path::PathBuf;
+use thiserror::Error;
+use virtio_devices::RateLimiterConfig;
+
+// Fields which can be updated, `[*]` standing for any element of a list.
+const LIVE_FIELDS: &[&str] = &[
+    "rng.src",
+    "console.file",
+    "serial.file",
+    "disks[*].rate_limiter_config",
+    "rate_limit_groups[*].rate_limiter_config",
+];
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("The VM configuration update is not an object")]
+    NotAnObject,
+    #[error("Error serializing the VM configuration: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("Invalid VM configuration update: {0}")]
+    Deserialize(#[source] serde_json::Error),
+    #[error("Fields of the VM configuration can't be updated: {}", .0.join(", "))]
+    ImmutableFields(Vec<String>),
+}
+
+/// Change to apply to the devices of the running VM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LiveConfigChange {
+    RngSource(PathBuf),
+    ConsoleFile(PathBuf),
+    SerialFile(PathBuf),
+    DiskRateLimiter {
+        id: String,
+        config: RateLimiterConfig,
+    },
+    RateLimitGroup {
+        id: String,
+        config: RateLimiterConfig,
+    },
+}
+
+// The elements of a list are matched by identifier when the patch gives one,
+// by position otherwise.
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                match target.entry(key) {
+                    Entry::Occupied(mut entry) => merge(entry.get_mut(), value),
+                    Entry::Vacant(entry) => {
+                        entry.insert(value);
+                    }
+                }
+            }
+        }
+        (Value::Array(target), Value::Array(patch)) => {
+            for (index, value) in patch.into_iter().enumerate() {
+                let position = match value.get("id") {
+                    Some(id) => target.iter().position(|t| t.get("id") == Some(id)),
+                    None => (index < target.len()).then_some(index),
+                };
+                match position {
+                    Some(position) => merge(&mut target[position], value),
+                    None => target.push(value),
+                }
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+fn changed_fields(field: &str, old: &Value, new: &Value, fields: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, new) in new {
+                let field = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{field}.{key}")
+                };
+                changed_fields(&field, old.get(key).unwrap_or(&Value::Null), new, fields);
+            }
+        }
+        (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+            for (index, (old, new)) in old.iter().zip(new).enumerate() {
+                let element = old
+                    .get("id")
+                    .and_then(Value::as_str)
+                    .map(String::from)
+                    .unwrap_or_else(|| index.to_string());
+                changed_fields(&format!("{field}[{element}]"), old, new, fields);
+            }
+        }
+        (old, new) => {
+            if old != new {
+                fields.push(field.to_string());
+            }
+        }
+    }
+}
+
+fn is_live(field: &str) -> bool {
+    let mut pattern = String::new();
+    let mut in_element = false;
+    for c in field.chars() {
+        match c {
+            '[' => {
+                in_element = true;
+                pattern.push_str("[*]");
+            }
+            ']' => in_element = false,
+            c if !in_element => pattern.push(c),
+            _ => {}
+        }
+    }
+
+    LIVE_FIELDS
+        .iter()
+        .any(|live| pattern == *live || pattern.starts_with(&format!("{live}.")))
+}
+
+fn console_file_change(
+    field: &str,
+    old: &ConsoleConfig,
+    new: &ConsoleConfig,
+    immutable: &mut Vec<String>,
+) -> Option<PathBuf> {
+    if new.file == old.file {
+        return None;
+    }
+    // Anything but the file the output is written to is handled at boot
+    match (&new.mode, &new.file) {
+        (ConsoleOutputMode::File, Some(file)) => Some(file.clone()),
+        _ => {
+            immutable.push(field.to_string());
+            None
+        }
+    }
+}
+
+// The rate limiters can be updated, but neither added nor removed.
+fn live_changes(
+    old: &VmConfig,
+    new: &VmConfig,
+    immutable: &mut Vec<String>,
+) -> Vec<LiveConfigChange> {
+    let mut changes = Vec::new();
+
+    if new.rng.src != old.rng.src {
+        changes.push(LiveConfigChange::RngSource(new.rng.src.clone()));
+    }
+    if let Some(file) = console_file_change("console.file", &old.console, &new.console, immutable) {
+        changes.push(LiveConfigChange::ConsoleFile(file));
+    }
+    if let Some(file) = console_file_change("serial.file", &old.serial, &new.serial, immutable) {
+        changes.push(LiveConfigChange::SerialFile(file));
+    }
+
+    for (old, new) in old.disks.iter().flatten().zip(new.disks.iter().flatten()) {
+        if new.rate_limiter_config == old.rate_limiter_config {
+            continue;
+        }
+        let id = new.id.clone().unwrap_or_default();
+        match (old.rate_limiter_config, new.rate_limiter_config) {
+            (Some(_), Some(config)) => {
+                changes.push(LiveConfigChange::DiskRateLimiter { id, config })
+            }
+            _ => immutable.push(format!("disks[{id}].rate_limiter_config")),
+        }
+    }
+
+    for (old, new) in old
+        .rate_limit_groups
+        .iter()
+        .flatten()
+        .zip(new.rate_limit_groups.iter().flatten())
+    {
+        if new.rate_limiter_config != old.rate_limiter_config {
+            changes.push(LiveConfigChange::RateLimitGroup {
+                id: new.id.clone(),
+                config: new.rate_limiter_config,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Applies `patch`, a partial VM configuration, to `config`. Returns the
+/// updated configuration, along with the changes to apply to the devices if
+/// the VM is `running`.
+pub fn update(
+    config: &VmConfig,
+    patch: Value,
+    running: bool,
+) -> Result<(VmConfig, Vec<LiveConfigChange>), Error> {
+    if !patch.is_object() {
+        return Err(Error::NotAnObject);
+    }
+
+    let current = serde_json::to_value(config).map_err(Error::Serialize)?;
+    let mut merged = current.clone();
+    merge(&mut merged, patch);
+
+    let mut fields = Vec::new();
+    changed_fields("", &current, &merged, &mut fields);
+    let mut immutable: Vec<String> = fields.into_iter().filter(|f| !is_live(f)).collect();
+    if !immutable.is_empty() {
+        return Err(Error::ImmutableFields(immutable));
+    }
+
+    let updated: VmConfig = serde_json::from_value(merged).map_err(Error::Deserialize)?;
+    let changes = if running {
+        live_changes(config, &updated, &mut immutable)
+    } else {
+        Vec::new()
+    };
+    if !immutable.is_empty() {
+        return Err(Error::ImmutableFields(immutable));
+    }
+
+    Ok((updated, changes))
+}
+
+/// Copies the fields which can be updated from `updated` to `config`, the
+/// other fields being left untouched.
+pub fn apply(config: &mut VmConfig, updated: &VmConfig) {
+    config.rng.src = updated.rng.src.clone();
+    config.console.file = updated.console.file.clone();
+    config.serial.file = updated.serial.file.clone();
+    for (disk, updated) in config
+        .disks
+        .iter_mut()
+        .flatten()
+        .zip(updated.disks.iter().flatten())
+    {
+        disk.rate_limiter_config = updated.rate_limiter_config;
+    }
+    for (group, updated) in config
+        .rate_limit_groups
+        .iter_mut()
+        .flatten()
+        .zip(updated.rate_limit_groups.iter().flatten())
+    {
+        group.rate_limiter_config = updated.rate_limiter_config;
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> VmConfig {
+        serde_json::from_value(json!({
+            "serial": { "mode": "File", "file": "/tmp/serial.log" },
+            "disks": [
+                {
+                    "path": "/tmp/disk0.raw",
+                    "id": "_disk0",
+                    "rate_limiter_config": {
+                        "bandwidth": { "size": 1000, "refill_time": 100 }
+                    }
+                },
+                { "path": "/tmp/disk1.raw", "id": "_disk1" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_update_live_fields() {
+        let config = config();
+        let rate_limiter_config = RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(virtio_devices::TokenBucketConfig {
+                size: 10,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+        };
+        let patch = json!({
+            "rng": { "src": "/dev/random" },
+            "serial": { "file": "/tmp/serial.1.log" },
+            "disks": [
+                {
+                    "id": "_disk0",
+                    "rate_limiter_config": { "bandwidth": null, "ops": { "size": 10, "refill_time": 100 } }
+                }
+            ]
+        });
+
+        let (updated, changes) = update(&config, patch, true).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                LiveConfigChange::RngSource(PathBuf::from("/dev/random")),
+                LiveConfigChange::SerialFile(PathBuf::from("/tmp/serial.1.log")),
+                LiveConfigChange::DiskRateLimiter {
+                    id: "_disk0".to_string(),
+                    config: rate_limiter_config,
+                },
+            ]
+        );
+
+        let mut config = config;
+        apply(&mut config, &updated);
+        assert_eq!(config.rng.src, PathBuf::from("/dev/random"));
+        assert_eq!(
+            config.disks.as_ref().unwrap()[0].rate_limiter_config,
+            Some(rate_limiter_config)
+        );
+    }
+
+    #[test]
+    fn test_update_immutable_fields() {
+        let config = config();
+
+        match update(
+            &config,
+            json!({ "cpus": { "boot_vcpus": 4 }, "disks": [{ "id": "_disk1", "readonly": true }] }),
+            false,
+        ) {
+            Err(Error::ImmutableFields(fields)) => {
+                assert_eq!(fields, vec!["cpus.boot_vcpus", "disks[_disk1].readonly"])
+            }
+            r => panic!("Unexpected result: {r:?}"),
+        }
+
+        // Adding a disk isn't an update
+        assert!(matches!(
+            update(
+                &config,
+                json!({ "disks": [{ "id": "_disk2", "path": "/tmp/disk2.raw" }] }),
+                false
+            ),
+            Err(Error::ImmutableFields(_))
+        ));
+
+        // Neither is adding a rate limiter to a running disk, nor writing the
+        // console to a file while it is written to the terminal
+        match update(
+            &config,
+            json!({
+                "console": { "file": "/tmp/console.log" },
+                "disks": [{ "id": "_disk1", "rate_limiter_config": { "ops": { "size": 10, "refill_time": 100 } } }]
+            }),
+            true,
+        ) {
+            Err(Error::ImmutableFields(fields)) => assert_eq!(
+                fields,
+                vec!["console.file", "disks[_disk1].rate_limiter_config"]
+            ),
+            r => panic!("Unexpected result: {r:?}"),
+        }
+
+        assert!(update(&config, json!({ "disks": [{}, {}] }), true)
+            .unwrap()
+            .1
+            .is_empty());
+        assert!(matches!(
+            update(&config, json!([]), true),
+            Err(Error::NotAnObject)
+        ));
+    }
+}
@@ -11,7 +11,8 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::CpusConfig;
+use crate::boot_detect::BootDetector;
+use crate::config::{CpusConfig, RtConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
     CpuElf64Writable, CpuSegment, CpuState as DumpCpusState, DumpState, Elf64Writable,
@@ -22,9 +23,9 @@ use crate::coredump::{
 use crate::gdb::{get_raw_tid, Debuggable, DebuggableError};
 #[cfg(target_arch = "x86_64")]
 use crate::memory_manager::MemoryManager;
+use crate::realtime;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-#[cfg(target_arch = "x86_64")]
-use crate::vm::physical_bits;
+use crate::vm::ShutdownReason;
 use crate::GuestMemoryMmap;
 use crate::CPU_MANAGER_SNAPSHOT_ID;
 use acpi_tables::{aml, sdt::Sdt, Aml};
@@ -50,6 +51,8 @@ use hypervisor::arch::x86::msr_index;
 use hypervisor::arch::x86::CpuIdEntry;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use hypervisor::arch::x86::MsrEntry;
+#[cfg(all(target_arch = "x86_64", feature = "igvm"))]
+use hypervisor::arch::x86::CPUID_FLAG_VALID_INDEX;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use hypervisor::arch::x86::{SpecialRegisters, StandardRegisters};
 #[cfg(target_arch = "aarch64")]
@@ -65,13 +68,14 @@ use libc::{c_void, siginfo_t};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use linux_loader::elf::Elf64_Nhdr;
 use seccompiler::{apply_filter, SeccompAction};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::io::Write;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::mem::size_of;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::{cmp, io, result, thread};
 use thiserror::Error;
@@ -192,6 +196,9 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     #[error("Failed to inject NMI")]
     NmiError(hypervisor::HypervisorCpuError),
+
+    #[error("Error reading the CPU time of vCPU {0}: {1}")]
+    VcpuCpuTime(u8, #[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -325,6 +332,9 @@ pub struct Vcpu {
     id: u8,
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
+    // Maximum SVE vector length in bits, SVE being disabled when None.
+    #[cfg(target_arch = "aarch64")]
+    sve_vl: Option<u16>,
     saved_state: Option<CpuState>,
     #[cfg(target_arch = "x86_64")]
     vendor: CpuVendor,
@@ -355,6 +365,8 @@ impl Vcpu {
             id,
             #[cfg(target_arch = "aarch64")]
             mpidr: 0,
+            #[cfg(target_arch = "aarch64")]
+            sve_vl: None,
             saved_state: None,
             #[cfg(target_arch = "x86_64")]
             vendor: cpu_vendor,
@@ -428,11 +440,22 @@ impl Vcpu {
         {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
         }
+        if self.sve_vl.is_some() {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_SVE;
+        }
         // Non-boot cpus are powered off initially.
         if self.id > 0 {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_POWER_OFF;
         }
-        self.vcpu.vcpu_init(&kvi).map_err(Error::VcpuArmInit)
+        self.vcpu.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+
+        // The vector lengths must be set before the vCPU is finalized, which
+        // only happens after vcpu_init().
+        if let Some(sve_vl) = self.sve_vl {
+            self.vcpu.init_sve(sve_vl).map_err(Error::VcpuArmInit)?;
+        }
+
+        Ok(())
     }
 
     /// Runs the VCPU until it exits, returning the reason.
@@ -482,6 +505,10 @@ pub struct CpuManager {
     vcpus_kill_signalled: Arc<AtomicBool>,
     vcpus_pause_signalled: Arc<AtomicBool>,
     vcpus_kick_signalled: Arc<AtomicBool>,
+    // Why a vCPU signalled the exit or reset event
+    shutdown_reason: Arc<Mutex<Option<ShutdownReason>>>,
+    // Told when the OSPM first evaluates the status of the vCPUs
+    boot_detector: Option<Arc<BootDetector>>,
     exit_evt: EventFd,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
@@ -496,6 +523,7 @@ pub struct CpuManager {
     acpi_address: Option<GuestAddress>,
     proximity_domain_per_cpu: BTreeMap<u8, u32>,
     affinity: BTreeMap<u8, Vec<usize>>,
+    rt_priority: Option<u8>,
     dynamic: bool,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     #[cfg(feature = "sev_snp")]
@@ -520,6 +548,9 @@ impl BusDevice for CpuManager {
                 data[0] = self.selected_cpu;
             }
             CPU_STATUS_OFFSET => {
+                if let Some(boot_detector) = &self.boot_detector {
+                    boot_detector.signal("acpi");
+                }
                 if self.selected_cpu < self.max_vcpus() {
                     let state = &self.vcpu_states[usize::from(self.selected_cpu)];
                     if state.active() {
@@ -585,12 +616,190 @@ impl BusDevice for CpuManager {
     }
 }
 
+/// CPU time consumed by one or several vCPU threads.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct VcpuCpuTime {
+    /// Time spent running on a host CPU, in nanoseconds
+    pub cpu_time_ns: u64,
+    /// Time spent in user mode, including the guest time, in microseconds
+    pub user_time_us: u64,
+    /// Time spent in kernel mode, in microseconds
+    pub system_time_us: u64,
+    /// Time spent running guest code, in microseconds
+    pub guest_time_us: u64,
+    /// Time spent in the VMM and the host kernel on behalf of the guest, in
+    /// microseconds
+    pub vmm_time_us: u64,
+}
+
+impl std::ops::AddAssign for VcpuCpuTime {
+    fn add_assign(&mut self, other: Self) {
+        self.cpu_time_ns += other.cpu_time_ns;
+        self.user_time_us += other.user_time_us;
+        self.system_time_us += other.system_time_us;
+        self.guest_time_us += other.guest_time_us;
+        self.vmm_time_us += other.vmm_time_us;
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VcpuStats {
+    pub id: u8,
+    /// Identifier of the host thread running the vCPU
+    pub tid: i32,
+    #[serde(flatten)]
+    pub cpu_time: VcpuCpuTime,
+    /// Number of exits handled by the VMM since the vCPU was created
+    #[serde(default)]
+    pub exits: u64,
+}
+
+/// CPU model the vCPUs can be exposed as.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CpuModel {
+    /// Name of the model, "host" being the host CPU model passed through
+    pub name: String,
+    /// Vendor identification, as reported through CPUID
+    #[serde(default)]
+    pub vendor: Option<String>,
+    /// Processor brand string, as reported through CPUID
+    #[serde(default)]
+    pub brand: Option<String>,
+    #[serde(default)]
+    pub family: Option<u32>,
+    #[serde(default)]
+    pub model: Option<u32>,
+    #[serde(default)]
+    pub stepping: Option<u32>,
+    /// Optional CPU features that can be enabled on top of the model
+    pub features: Vec<String>,
+}
+
+/// vCPU hotplug limits of the VM.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CpusCapabilities {
+    pub boot_vcpus: u8,
+    pub present_vcpus: u8,
+    pub max_vcpus: u8,
+    /// Number of vCPUs that can still be hotplugged
+    pub hotpluggable_vcpus: u8,
+    /// CPU models supported for the vCPUs
+    #[serde(default)]
+    pub models: Vec<CpuModel>,
+}
+
+// Describes the CPU model exposed through the CPUID entries of the vCPUs.
+#[cfg(target_arch = "x86_64")]
+fn cpuid_model(cpuid: &[CpuIdEntry]) -> CpuModel {
+    let leaf = |function| {
+        cpuid
+            .iter()
+            .find(|entry| entry.function == function && entry.index == 0)
+    };
+    let string = |registers: &[u32]| {
+        let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_le_bytes()).collect();
+        let string = String::from_utf8_lossy(&bytes)
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string();
+        (!string.is_empty()).then_some(string)
+    };
+
+    let vendor = leaf(0).and_then(|e| string(&[e.ebx, e.edx, e.ecx]));
+    let brand_registers = (0x8000_0002..=0x8000_0004)
+        .map(|function| leaf(function).map(|e| [e.eax, e.ebx, e.ecx, e.edx]))
+        .collect::<Option<Vec<_>>>()
+        .map(|leaves| leaves.concat());
+    let brand = brand_registers.and_then(|registers| string(&registers));
+
+    // Extended family and model only apply to some families
+    let signature = leaf(1).map(|e| e.eax);
+    let family = signature.map(|eax| {
+        let family = (eax >> 8) & 0xf;
+        if family == 0xf {
+            family + ((eax >> 20) & 0xff)
+        } else {
+            family
+        }
+    });
+    let model = signature.zip(family).map(|(eax, family)| {
+        let model = (eax >> 4) & 0xf;
+        if family == 0x6 || family >= 0xf {
+            model | (((eax >> 16) & 0xf) << 4)
+        } else {
+            model
+        }
+    });
+
+    CpuModel {
+        name: "host".to_string(),
+        vendor,
+        brand,
+        family,
+        model,
+        stepping: signature.map(|eax| eax & 0xf),
+        features: vec!["amx".to_string(), "evmcs".to_string()],
+    }
+}
+
+// Reads the CPU time consumed by a vCPU thread. The total CPU time comes from
+// the thread CPU clock, while the user, system and guest split is only exposed
+// by the kernel with a clock tick granularity.
+fn thread_cpu_time(handle: &thread::JoinHandle<()>, tid: i32) -> io::Result<VcpuCpuTime> {
+    let mut clock_id: libc::clockid_t = 0;
+    // SAFETY: FFI call with correct arguments
+    let ret = unsafe { libc::pthread_getcpuclockid(handle.as_pthread_t() as _, &mut clock_id) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    // SAFETY: all zeros is a valid pattern
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call with correct arguments
+    if unsafe { libc::clock_gettime(clock_id, &mut ts) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let stat = std::fs::read_to_string(format!("/proc/self/task/{tid}/stat"))?;
+    // The thread name can contain spaces, hence fields are split after it,
+    // starting from the third one.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, fields)| fields.split_whitespace().collect())
+        .unwrap_or_default();
+    let field = |index: usize| -> io::Result<u64> {
+        fields
+            .get(index - 3)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Missing field {index} in /proc/self/task/{tid}/stat"),
+                )
+            })
+    };
+    // SAFETY: FFI call, trivially safe
+    let us_per_tick = 1_000_000 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let user_time_us = field(14)? * us_per_tick;
+    let system_time_us = field(15)? * us_per_tick;
+    let guest_time_us = field(43)? * us_per_tick;
+
+    Ok(VcpuCpuTime {
+        cpu_time_ns: ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64,
+        user_time_us,
+        system_time_us,
+        guest_time_us,
+        vmm_time_us: (user_time_us + system_time_us).saturating_sub(guest_time_us),
+    })
+}
+
 #[derive(Default)]
 struct VcpuState {
     inserting: bool,
     removing: bool,
     pending_removal: Arc<AtomicBool>,
     handle: Option<thread::JoinHandle<()>>,
+    tid: Arc<AtomicI32>,
+    // Exits of the vCPU handled by the VMM
+    exits: Arc<AtomicU64>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
     paused: Arc<AtomicBool>,
@@ -648,6 +857,7 @@ impl CpuManager {
         vm_ops: Arc<dyn VmOps>,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         numa_nodes: &NumaNodes,
+        rt: Option<&RtConfig>,
         #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
     ) -> Result<Arc<Mutex<CpuManager>>> {
         if u32::from(config.max_vcpus) > hypervisor.get_max_vcpus() {
@@ -704,7 +914,13 @@ impl CpuManager {
         .into_iter()
         .collect();
 
-        let affinity = if let Some(cpu_affinity) = config.affinity.as_ref() {
+        // The realtime profile pins each vCPU onto its own isolated host CPU.
+        let affinity = if let Some(rt) = rt {
+            (0..config.max_vcpus)
+                .zip(rt.cpus.iter().flatten())
+                .map(|(vcpu, host_cpu)| (vcpu, vec![*host_cpu]))
+                .collect()
+        } else if let Some(cpu_affinity) = config.affinity.as_ref() {
             cpu_affinity
                 .iter()
                 .map(|a| (a.vcpu, a.host_cpus.clone()))
@@ -727,6 +943,8 @@ impl CpuManager {
             vcpus_kill_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_pause_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_kick_signalled: Arc::new(AtomicBool::new(false)),
+            shutdown_reason: Arc::new(Mutex::new(None)),
+            boot_detector: None,
             vcpu_states,
             exit_evt,
             reset_evt,
@@ -739,6 +957,7 @@ impl CpuManager {
             acpi_address: None,
             proximity_domain_per_cpu,
             affinity,
+            rt_priority: rt.map(|rt| rt.priority),
             dynamic,
             hypervisor: hypervisor.clone(),
             #[cfg(feature = "sev_snp")]
@@ -751,6 +970,7 @@ impl CpuManager {
         &mut self,
         memory_manager: &Arc<Mutex<MemoryManager>>,
         hypervisor: &Arc<dyn hypervisor::Hypervisor>,
+        phys_bits: u8,
         #[cfg(feature = "tdx")] tdx: bool,
     ) -> Result<()> {
         let sgx_epc_sections = memory_manager
@@ -761,7 +981,6 @@ impl CpuManager {
             .map(|sgx_epc_region| sgx_epc_region.epc_sections().values().cloned().collect());
 
         self.cpuid = {
-            let phys_bits = physical_bits(hypervisor, self.config.max_phys_bits);
             arch::generate_common_cpuid(
                 hypervisor,
                 &arch::CpuidConfig {
@@ -771,6 +990,7 @@ impl CpuManager {
                     #[cfg(feature = "tdx")]
                     tdx,
                     amx: self.config.features.amx,
+                    evmcs: self.config.features.evmcs,
                 },
             )
             .map_err(Error::CommonCpuId)?
@@ -797,6 +1017,10 @@ impl CpuManager {
             #[cfg(target_arch = "x86_64")]
             self.hypervisor.get_cpu_vendor(),
         )?;
+        #[cfg(target_arch = "aarch64")]
+        {
+            vcpu.sve_vl = self.config.sve_vl;
+        }
 
         if let Some(snapshot) = snapshot {
             // AArch64 vCPUs should be initialized after created.
@@ -907,12 +1131,20 @@ impl CpuManager {
             let cpu = cpu.lock().unwrap();
             // Check if PMU attr is available, if not, log the information.
             if cpu.vcpu.has_pmu_support() {
+                if let Some(pmu_events) = &self.config.pmu_events {
+                    cpu.vcpu
+                        .set_pmu_event_filter(pmu_events)
+                        .map_err(Error::InitPmu)?;
+                }
                 cpu.vcpu.init_pmu(irq).map_err(Error::InitPmu)?;
             } else {
                 debug!(
                     "PMU attribute is not supported in vCPU{}, skip PMU init!",
                     cpu.id
                 );
+                if self.config.pmu_events.is_some() {
+                    warn!("PMU not supported, ignoring the PMU event filter");
+                }
                 return Ok(false);
             }
         }
@@ -938,6 +1170,8 @@ impl CpuManager {
         #[cfg(feature = "guest_debug")]
         let vm_debug_evt = self.vm_debug_evt.try_clone().unwrap();
         let panic_exit_evt = self.exit_evt.try_clone().unwrap();
+        let shutdown_reason = self.shutdown_reason.clone();
+        let panic_shutdown_reason = self.shutdown_reason.clone();
         let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
         let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
         let vcpu_kick_signalled = self.vcpus_kick_signalled.clone();
@@ -948,6 +1182,11 @@ impl CpuManager {
             .clone();
         let panic_vcpu_run_interrupted = vcpu_run_interrupted.clone();
         let vcpu_paused = self.vcpu_states[usize::from(vcpu_id)].paused.clone();
+        let vcpu_tid = self.vcpu_states[usize::from(vcpu_id)].tid.clone();
+        vcpu_tid.store(0, Ordering::SeqCst);
+        let vcpu_exits = self.vcpu_states[usize::from(vcpu_id)].exits.clone();
+
+        let rt_priority = self.rt_priority;
 
         // Prepare the CPU set the current vCPU is expected to run onto.
         let cpuset = self.affinity.get(&vcpu_id).map(|host_cpus| {
@@ -979,6 +1218,11 @@ impl CpuManager {
             thread::Builder::new()
                 .name(format!("vcpu{vcpu_id}"))
                 .spawn(move || {
+                    // Record the thread id for the CPU time accounting
+                    // SAFETY: FFI call, trivially safe
+                    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+                    vcpu_tid.store(tid as i32, Ordering::SeqCst);
+
                     // Schedule the thread to run on the expected CPU set
                     if let Some(cpuset) = cpuset.as_ref() {
                         // SAFETY: FFI call with correct arguments
@@ -1000,6 +1244,17 @@ impl CpuManager {
                         }
                     }
 
+                    // The vCPU keeps running with the default policy rather
+                    // than leaving the other vCPUs waiting on the barrier.
+                    if let Some(priority) = rt_priority {
+                        if let Err(e) = realtime::set_fifo_priority(priority) {
+                            error!(
+                                "Failed scheduling the vCPU {} with realtime priority {}: {}",
+                                vcpu_id, priority, e
+                            );
+                        }
+                    }
+
                     // Apply seccomp filter for vcpu thread.
                     if !vcpu_seccomp_filter.is_empty() {
                         if let Err(e) =
@@ -1090,7 +1345,11 @@ impl CpuManager {
                             #[cfg(not(feature = "tdx"))]
                             let vcpu = vcpu.lock().unwrap();
                             // vcpu.run() returns false on a triple-fault so trigger a reset
-                            match vcpu.run() {
+                            let run = vcpu.run();
+                            if run.is_ok() {
+                                vcpu_exits.fetch_add(1, Ordering::Relaxed);
+                            }
+                            match run {
                                 Ok(run) => match run {
                                     #[cfg(feature = "kvm")]
                                     VmExit::Debug => {
@@ -1118,13 +1377,39 @@ impl CpuManager {
                                     VmExit::Reset => {
                                         info!("VmExit::Reset");
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        // On x86_64 the guest resets through
+                                        // I/O ports, a vCPU reset exit meaning
+                                        // the guest triple faulted.
+                                        #[cfg(target_arch = "x86_64")]
+                                        let reason = ShutdownReason::TripleFault;
+                                        #[cfg(target_arch = "aarch64")]
+                                        let reason = ShutdownReason::GuestReset;
+                                        shutdown_reason.lock().unwrap().replace(reason);
                                         reset_evt.write(1).unwrap();
                                         break;
                                     }
                                     VmExit::Shutdown => {
                                         info!("VmExit::Shutdown");
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
-                                        exit_evt.write(1).unwrap();
+                                        // On x86_64 the vCPU shuts down on a
+                                        // triple fault, which is handled as a
+                                        // reset for the reset policy to apply.
+                                        #[cfg(target_arch = "x86_64")]
+                                        {
+                                            shutdown_reason
+                                                .lock()
+                                                .unwrap()
+                                                .replace(ShutdownReason::TripleFault);
+                                            reset_evt.write(1).unwrap();
+                                        }
+                                        #[cfg(target_arch = "aarch64")]
+                                        {
+                                            shutdown_reason
+                                                .lock()
+                                                .unwrap()
+                                                .replace(ShutdownReason::GuestPoweroff);
+                                            exit_evt.write(1).unwrap();
+                                        }
                                         break;
                                     }
                                     #[cfg(feature = "tdx")]
@@ -1153,6 +1438,10 @@ impl CpuManager {
                                             Error::UnexpectedVmExit
                                         );
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        shutdown_reason
+                                            .lock()
+                                            .unwrap()
+                                            .replace(ShutdownReason::Error);
                                         exit_evt.write(1).unwrap();
                                         break;
                                     }
@@ -1161,6 +1450,10 @@ impl CpuManager {
                                 Err(e) => {
                                     error!("VCPU generated error: {:?}", Error::VcpuRun(e.into()));
                                     vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                    shutdown_reason
+                                        .lock()
+                                        .unwrap()
+                                        .replace(ShutdownReason::Error);
                                     exit_evt.write(1).unwrap();
                                     break;
                                 }
@@ -1178,6 +1471,9 @@ impl CpuManager {
                     .or_else(|_| {
                         panic_vcpu_run_interrupted.store(true, Ordering::SeqCst);
                         error!("vCPU thread panicked");
+                        if let Ok(mut reason) = panic_shutdown_reason.lock() {
+                            reason.replace(ShutdownReason::Error);
+                        }
                         panic_exit_evt.write(1)
                     })
                     .ok();
@@ -1357,6 +1653,31 @@ impl CpuManager {
         Ok(())
     }
 
+    /// Returns the CPU time consumed by each running vCPU.
+    pub fn vcpus_stats(&self) -> Result<Vec<VcpuStats>> {
+        let mut stats = Vec::new();
+        for (id, state) in self.vcpu_states.iter().enumerate() {
+            let tid = state.tid.load(Ordering::SeqCst);
+            let Some(handle) = state.handle.as_ref() else {
+                continue;
+            };
+            // The vCPU thread is not started yet
+            if tid == 0 {
+                continue;
+            }
+
+            let id = id as u8;
+            stats.push(VcpuStats {
+                id,
+                tid,
+                cpu_time: thread_cpu_time(handle, tid).map_err(|e| Error::VcpuCpuTime(id, e))?,
+                exits: state.exits.load(Ordering::Relaxed),
+            });
+        }
+
+        Ok(stats)
+    }
+
     pub fn boot_vcpus(&self) -> u8 {
         self.config.boot_vcpus
     }
@@ -1365,6 +1686,29 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
+    pub fn capabilities(&self) -> CpusCapabilities {
+        let present_vcpus = self.present_vcpus();
+        CpusCapabilities {
+            boot_vcpus: self.config.boot_vcpus,
+            present_vcpus,
+            max_vcpus: self.config.max_vcpus,
+            hotpluggable_vcpus: if self.dynamic {
+                self.config.max_vcpus.saturating_sub(present_vcpus)
+            } else {
+                0
+            },
+            // The host CPU model is always passed through, optional features
+            // being enabled on top of it
+            #[cfg(target_arch = "x86_64")]
+            models: vec![cpuid_model(&self.cpuid)],
+            #[cfg(target_arch = "aarch64")]
+            models: vec![CpuModel {
+                name: "host".to_string(),
+                ..Default::default()
+            }],
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn common_cpuid(&self) -> Vec<CpuIdEntry> {
         assert!(!self.cpuid.is_empty());
@@ -1845,6 +2189,15 @@ impl CpuManager {
         &self.vcpus_kill_signalled
     }
 
+    /// Why a vCPU signalled the exit or reset event, if it did.
+    pub(crate) fn take_shutdown_reason(&self) -> Option<ShutdownReason> {
+        self.shutdown_reason.lock().unwrap().take()
+    }
+
+    pub(crate) fn set_boot_detector(&mut self, boot_detector: Arc<BootDetector>) {
+        self.boot_detector = Some(boot_detector);
+    }
+
     #[cfg(feature = "igvm")]
     pub(crate) fn get_cpuid_leaf(
         &self,
@@ -1854,6 +2207,20 @@ impl CpuManager {
         xfem: u64,
         xss: u64,
     ) -> Result<[u32; 4]> {
+        // Only MSHV can be queried for the CPUID of a given vCPU, otherwise
+        // rely on the common CPUID exposed to all of them.
+        if !matches!(self.hypervisor.hypervisor_type(), HypervisorType::Mshv) {
+            return Ok(self
+                .cpuid
+                .iter()
+                .find(|entry| {
+                    entry.function == eax
+                        && (entry.flags & CPUID_FLAG_VALID_INDEX == 0 || entry.index == ecx)
+                })
+                .map(|entry| [entry.eax, entry.ebx, entry.ecx, entry.edx])
+                .unwrap_or_default());
+        }
+
         let leaf_info = self.vcpus[usize::from(cpu_id)]
             .lock()
             .unwrap()
@@ -2750,7 +3117,7 @@ mod tests {
     use arch::layout::ZERO_PAGE_START;
     use arch::x86_64::interrupts::*;
     use arch::x86_64::regs::*;
-    use hypervisor::arch::x86::{FpuState, LapicState, StandardRegisters};
+    use hypervisor::arch::x86::{CpuIdEntry, FpuState, LapicState, StandardRegisters};
     use linux_loader::loader::bootparam::setup_header;
 
     #[test]
@@ -2779,6 +3146,57 @@ mod tests {
         assert_eq!(lint1_mode_expected, lint1_mode_actual);
     }
 
+    #[test]
+    fn test_cpuid_model() {
+        let entry = |function, eax, ebx, ecx, edx| CpuIdEntry {
+            function,
+            eax,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        };
+        let brand = *b"Intel(R) Xeon(R) Platinum 8380 CPU @ 2.30GHz\0\0\0\0";
+        let brand: Vec<u32> = brand
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let mut cpuid = vec![
+            // "GenuineIntel"
+            entry(0, 0x1b, 0x756e_6547, 0x6c65_746e, 0x4965_6e69),
+            // Family 6, model 0x6a, stepping 6
+            entry(1, 0x0006_06a6, 0, 0, 0),
+        ];
+        for (i, registers) in brand.chunks(4).enumerate() {
+            cpuid.push(entry(
+                0x8000_0002 + i as u32,
+                registers[0],
+                registers[1],
+                registers[2],
+                registers[3],
+            ));
+        }
+
+        let model = super::cpuid_model(&cpuid);
+        assert_eq!(model.name, "host");
+        assert_eq!(model.vendor.as_deref(), Some("GenuineIntel"));
+        assert_eq!(
+            model.brand.as_deref(),
+            Some("Intel(R) Xeon(R) Platinum 8380 CPU @ 2.30GHz")
+        );
+        assert_eq!(model.family, Some(6));
+        assert_eq!(model.model, Some(0x6a));
+        assert_eq!(model.stepping, Some(6));
+
+        // Family 0x19, model 0x11, stepping 1
+        let model = super::cpuid_model(&[entry(1, 0x00a1_0f11, 0, 0, 0)]);
+        assert_eq!(model.vendor, None);
+        assert_eq!(model.brand, None);
+        assert_eq!(model.family, Some(0x19));
+        assert_eq!(model.model, Some(0x11));
+        assert_eq!(model.stepping, Some(1));
+    }
+
     #[test]
     fn test_setup_fpu() {
         let hv = hypervisor::new().unwrap();
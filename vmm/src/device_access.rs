@@ -0,0 +1,210 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Device emulation time monitoring.
+//!
+//! The time spent handling each MMIO/PIO exit is compared against a threshold
+//! so that guest visible latency spikes can be traced back to the emulated
+//! device responsible for them.
+
+use crate::device_tree::DeviceTree;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use vm_device::{Bus, PciBarType, Resource};
+
+// Slow accesses to a given device are reported at most once per interval, the
+// ones happening in between being accounted for in the next report.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BusKind {
+    Mmio,
+    #[cfg(target_arch = "x86_64")]
+    Pio,
+}
+
+impl fmt::Display for BusKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusKind::Mmio => write!(f, "mmio"),
+            #[cfg(target_arch = "x86_64")]
+            BusKind::Pio => write!(f, "pio"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SlowAccessStats {
+    count: u64,
+    max: Duration,
+    last_report: Option<Instant>,
+    unreported: u64,
+}
+
+pub struct DeviceAccessMonitor {
+    threshold: Duration,
+    // Set once the devices have been created, and only used to name the
+    // device once an access has been found slow.
+    device_tree: OnceLock<Arc<Mutex<DeviceTree>>>,
+    stats: Mutex<HashMap<(BusKind, u64), SlowAccessStats>>,
+}
+
+impl DeviceAccessMonitor {
+    pub fn new(threshold_us: u64) -> Self {
+        DeviceAccessMonitor {
+            threshold: Duration::from_micros(threshold_us),
+            device_tree: OnceLock::new(),
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_device_tree(&self, device_tree: Arc<Mutex<DeviceTree>>) {
+        let _ = self.device_tree.set(device_tree);
+    }
+
+    /// Runs the access to the given address of the bus, reporting it if the
+    /// device handler took longer than the threshold.
+    pub fn monitor<T>(
+        &self,
+        bus: &Bus,
+        kind: BusKind,
+        addr: u64,
+        write: bool,
+        access: impl FnOnce() -> T,
+    ) -> T {
+        let start = Instant::now();
+        let ret = access();
+        let elapsed = start.elapsed();
+        if elapsed > self.threshold {
+            // The device may have been removed by the access itself.
+            let base = bus.resolve(addr).map(|(base, _, _)| base).unwrap_or(addr);
+            self.report(kind, base, addr, write, elapsed);
+        }
+
+        ret
+    }
+
+    fn report(&self, kind: BusKind, base: u64, addr: u64, write: bool, elapsed: Duration) {
+        let (count, max, unreported) = {
+            let mut stats = self.stats.lock().unwrap();
+            let stats = stats.entry((kind, base)).or_default();
+            stats.count += 1;
+            stats.max = stats.max.max(elapsed);
+            stats.unreported += 1;
+
+            let now = Instant::now();
+            if stats
+                .last_report
+                .is_some_and(|last| now.duration_since(last) < REPORT_INTERVAL)
+            {
+                return;
+            }
+            stats.last_report = Some(now);
+            let unreported = stats.unreported;
+            stats.unreported = 0;
+            (stats.count, stats.max, unreported)
+        };
+
+        let device = self
+            .device_tree
+            .get()
+            .and_then(|device_tree| device_id(&device_tree.lock().unwrap(), kind, base))
+            .unwrap_or_else(|| "unknown".to_string());
+        let access = if write { "write" } else { "read" };
+
+        warn!(
+            "Slow {kind} {access} to 0x{addr:x} handled by device '{device}': {}us (threshold {}us, {unreported} slow access(es) since last report, {count} in total, max {}us)",
+            elapsed.as_micros(),
+            self.threshold.as_micros(),
+            max.as_micros()
+        );
+        event!(
+            "vm",
+            "slow-device-access",
+            "device",
+            device,
+            "bus",
+            kind.to_string(),
+            "access",
+            access,
+            "address",
+            format!("0x{addr:x}"),
+            "duration_us",
+            elapsed.as_micros().to_string(),
+            "count",
+            count.to_string()
+        );
+    }
+}
+
+// Finds the device owning the range starting at the given base address.
+fn device_id(device_tree: &DeviceTree, kind: BusKind, base: u64) -> Option<String> {
+    device_tree
+        .iter()
+        .find(|(_, node)| {
+            node.resources.iter().any(|resource| match resource {
+                Resource::MmioAddressRange { base: b, .. } => kind == BusKind::Mmio && *b == base,
+                #[cfg(target_arch = "x86_64")]
+                Resource::PioAddressRange { base: b, .. } => {
+                    kind == BusKind::Pio && u64::from(*b) == base
+                }
+                Resource::PciBar { base: b, type_, .. } => {
+                    (kind == BusKind::Mmio) == (*type_ != PciBarType::Io) && *b == base
+                }
+                _ => false,
+            })
+        })
+        .map(|(id, _)| id.clone())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::device_tree::DeviceNode;
+
+    #[test]
+    fn test_device_id() {
+        let mut device_tree = DeviceTree::new();
+        let mut node = DeviceNode::new("_disk0".to_string(), None);
+        node.resources.push(Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x100,
+        });
+        device_tree.insert("_disk0".to_string(), node);
+
+        assert_eq!(
+            device_id(&device_tree, BusKind::Mmio, 0x1000),
+            Some("_disk0".to_string())
+        );
+        assert_eq!(device_id(&device_tree, BusKind::Mmio, 0x2000), None);
+    }
+
+    #[test]
+    fn test_report_interval() {
+        let monitor = DeviceAccessMonitor::new(0);
+        monitor.report(
+            BusKind::Mmio,
+            0x1000,
+            0x1004,
+            true,
+            Duration::from_millis(2),
+        );
+        monitor.report(
+            BusKind::Mmio,
+            0x1000,
+            0x1004,
+            true,
+            Duration::from_millis(5),
+        );
+
+        let stats = monitor.stats.lock().unwrap();
+        let stats = stats.get(&(BusKind::Mmio, 0x1000)).unwrap();
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.max, Duration::from_millis(5));
+        // The second access happened within the report interval.
+        assert_eq!(stats.unreported, 1);
+    }
+}
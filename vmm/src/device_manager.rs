@@ -9,20 +9,28 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+#[cfg(feature = "virtio_9p")]
+use crate::config::P9Config;
 use crate::config::{
-    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UserDeviceConfig,
-    VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, LegacyDevice, NetConfig, NetModel,
+    PmemConfig, ReplacementDeviceConfig, UsbConfig, UserDeviceConfig, VdpaConfig, VhostMode,
+    VmConfig, VmIdentityConfig, VsockConfig,
 };
+use crate::config_update::LiveConfigChange;
 use crate::cpu::{CpuManager, CPU_MANAGER_ACPI_SIZE};
 use crate::device_tree::{DeviceNode, DeviceTree};
 use crate::interrupt::LegacyUserspaceInterruptManager;
 use crate::interrupt::MsiInterruptManager;
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager, MEMORY_MANAGER_ACPI_SIZE};
+use crate::migration::{MigrationBlocker, MigrationBlockers};
 use crate::pci_segment::PciSegment;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::serial_manager::{Error as SerialManagerError, SerialManager};
 use crate::sigwinch_listener::start_sigwinch_listener;
-use crate::vm_config::DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT;
+use crate::vm::ShutdownReason;
+use crate::vm_config::{
+    default_netconfig_ip, DeviceNumaPolicy, DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT,
+};
 use crate::GuestRegionMmap;
 use crate::PciDeviceInfo;
 use crate::{device_node, DEVICE_MANAGER_SNAPSHOT_ID};
@@ -48,6 +56,7 @@ use devices::debug_console::DebugConsole;
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
 use devices::ioapic;
+use devices::legacy::OutputObserver;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::Pl011;
 use devices::{
@@ -69,12 +78,14 @@ use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{read_link, File, OpenOptions};
 use std::io::{self, stdout, Seek, SeekFrom};
 use std::mem::zeroed;
+use std::net::Ipv4Addr;
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Instant;
 use tracer::trace_scoped;
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDeviceFd};
@@ -94,7 +105,6 @@ use vm_device::{Bus, BusDevice, Resource};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::GuestMemoryRegion;
 use vm_memory::{Address, GuestAddress, GuestUsize, MmapRegion};
-#[cfg(target_arch = "x86_64")]
 use vm_memory::{GuestAddressSpace, GuestMemory};
 use vm_migration::{
     protocol::MemoryRangeTable, snapshot_from_id, state_from_id, Migratable, MigratableError,
@@ -122,14 +132,22 @@ const IOMMU_DEVICE_NAME: &str = "__iommu";
 const BALLOON_DEVICE_NAME: &str = "__balloon";
 const CONSOLE_DEVICE_NAME: &str = "__console";
 const PVPANIC_DEVICE_NAME: &str = "__pvpanic";
+const XHCI_DEVICE_NAME: &str = "__xhci";
+#[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+const VNC_KEYBOARD_DEVICE_NAME: &str = "__vnc_keyboard";
+#[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+const VNC_TABLET_DEVICE_NAME: &str = "__vnc_tablet";
 
 // Devices that the user may name and for which we generate
 // identifiers if the user doesn't give one
 const DISK_DEVICE_NAME_PREFIX: &str = "_disk";
 const FS_DEVICE_NAME_PREFIX: &str = "_fs";
 const NET_DEVICE_NAME_PREFIX: &str = "_net";
+#[cfg(feature = "virtio_9p")]
+const P9_DEVICE_NAME_PREFIX: &str = "_p9";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
 const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
+const USB_DEVICE_NAME_PREFIX: &str = "_usb";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
 const WATCHDOG_DEVICE_NAME: &str = "__watchdog";
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
@@ -166,6 +184,18 @@ pub enum DeviceManagerError {
     /// Virtio-fs device was created without a socket.
     NoVirtioFsSock,
 
+    /// Cannot create built-in virtio-fs device
+    #[cfg(feature = "fs_builtin")]
+    CreateBuiltinVirtioFs(io::Error),
+
+    /// Built-in virtio-fs device was created without a shared directory.
+    #[cfg(feature = "fs_builtin")]
+    NoVirtioFsSharedDir,
+
+    /// Cannot create virtio-9p device
+    #[cfg(feature = "virtio_9p")]
+    CreateVirtioP9(io::Error),
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(virtio_devices::vhost_user::Error),
 
@@ -211,9 +241,53 @@ pub enum DeviceManagerError {
     /// Cannot spawn the serial manager thread
     SpawnSerialManager(SerialManagerError),
 
+    /// No serial port to send input to
+    NoSerialDevice,
+
+    /// Cannot queue input to the serial port
+    SerialInput(vmm_sys_util::errno::Error),
+
+    /// Cannot add a file to the fw_cfg device
+    #[cfg(target_arch = "x86_64")]
+    FwCfgAddFile(devices::legacy::FwCfgError),
+
+    /// No ramfb device to capture
+    NoRamfbDevice,
+
+    /// Cannot capture the ramfb framebuffer
+    #[cfg(target_arch = "x86_64")]
+    RamfbScreenshot(devices::legacy::RamfbError),
+
+    /// Cannot start the VNC server
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    CreateVncServer(crate::vnc::Error),
+
+    /// Cannot create the seccomp filters of the VNC server threads
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    VncSeccompFilter(seccompiler::Error),
+
+    /// Cannot create virtio-input device
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    CreateVirtioInput(io::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
+    /// Cannot create tap interface attached to a bridge
+    OpenBridgedTap(net_util::OpenTapError),
+
+    /// Cannot attach tap interface to a bridge
+    BridgeAttach(net_util::BridgeError),
+
+    /// Cannot install the egress shaping qdisc on the tap interface
+    EgressShaping(net_util::NetlinkError),
+
+    /// Cannot assign the IPv6 address to the tap interface
+    SetIpv6Address(net_util::NetlinkError),
+
+    /// Cannot set up the router advertisements on the tap interface
+    EnableRouterAdvertisement(virtio_devices::net::Error),
+
     /// Cannot allocate IRQ.
     AllocateIrq,
 
@@ -281,6 +355,9 @@ pub enum DeviceManagerError {
     /// Cannot create a VFIO PCI device
     VfioPciCreate(pci::VfioPciError),
 
+    /// Cannot read the option ROM of a VFIO device
+    VfioRomFile(PathBuf, io::Error),
+
     /// Failed to map VFIO MMIO region.
     VfioMapRegion(pci::VfioPciError),
 
@@ -426,6 +503,12 @@ pub enum DeviceManagerError {
     #[cfg(target_arch = "aarch64")]
     AArch64PowerButtonNotification(devices::legacy::GpioDeviceError),
 
+    /// Failed to do sleep button notification
+    SleepButtonNotification(io::Error),
+
+    /// Failed to notify the guest of a new VM generation ID
+    VmGenIdNotification(io::Error),
+
     /// Failed to set O_DIRECT flag to file descriptor
     SetDirectIo,
 
@@ -438,9 +521,27 @@ pub enum DeviceManagerError {
     /// Failed to create QcowDiskSync
     CreateQcowDiskSync(qcow::Error),
 
+    /// Failed to create the overlay of a base disk image
+    CreateDiskOverlay(qcow::Error),
+
+    /// Failed to read the header of an existing disk overlay
+    ReadDiskOverlay(qcow::Error),
+
+    /// An existing disk overlay isn't backed by the base image given
+    DiskOverlayBaseMismatch(PathBuf, Option<String>),
+
+    /// Failed to create the ephemeral overlay of a disk image
+    CreateEphemeralDisk(block::ephemeral::Error),
+
     /// Failed to create FixedVhdxDiskSync
     CreateFixedVhdxDiskSync(vhdx::VhdxError),
 
+    /// Failed to create ZstdSeekableDiskSync
+    CreateZstdSeekableDiskSync(block::zstd_seekable::ZstdSeekableError),
+
+    /// Compressed disk images can only be attached read-only
+    CompressedDiskNotReadOnly,
+
     /// Failed to add DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
@@ -492,14 +593,122 @@ pub enum DeviceManagerError {
     /// Cannot create a PvPanic device
     PvPanicCreate(devices::pvpanic::PvPanicError),
 
+    /// Cannot create an e1000e device
+    E1000eCreate(devices::e1000e::E1000eError),
+
+    /// Cannot start the e1000e device thread
+    E1000eStartThread(devices::e1000e::E1000eError),
+
+    /// Cannot create the seccomp filter of the e1000e device thread
+    E1000eSeccompFilter(seccompiler::Error),
+
+    /// Cannot open the TAP interface of an e1000e device
+    E1000eOpenTap(net_util::OpenTapError),
+
+    /// Cannot duplicate the TAP file descriptor of an e1000e device
+    E1000eDuplicateTapFd(io::Error),
+
+    /// e1000e devices can't be hotplugged
+    E1000eHotplugNotSupported,
+
+    /// Cannot create the xHCI controller
+    XhciCreate(devices::usb::XhciError),
+
+    /// Cannot start the xHCI controller thread
+    XhciStartThread(devices::usb::XhciError),
+
+    /// Cannot create the seccomp filter of the xHCI controller thread
+    XhciSeccompFilter(seccompiler::Error),
+
+    /// Cannot open the host USB device
+    UsbOpen(devices::usb::HostUsbError),
+
+    /// Cannot plug the host USB device into the xHCI controller
+    UsbAttach(devices::usb::XhciError),
+
+    /// USB devices can't be hotplugged without an xHCI controller
+    NoXhciDevice,
+
     /// Cannot create a RateLimiterGroup
     RateLimiterGroupCreate(rate_limiter::group::Error),
+
+    /// A replacement is already pending for this device.
+    ReplacementAlreadyPending(String),
+
+    /// The backend of the replacement device can't be used
+    ReplacementBackend(io::Error),
+
+    /// Failed setting the NUMA memory policy for a device activation.
+    SetDeviceNumaPolicy(io::Error),
+
+    /// Cannot replace the source of the virtio-rng device
+    UpdateRngSource(io::Error),
+
+    /// Cannot replace the console or serial output file
+    ReplaceOutputFile(io::Error),
+
+    /// No device to apply the configuration change to
+    NoDeviceToUpdate(String),
+
+    /// The overlay a disk is to be switched to already exists
+    DiskOverlayExists(PathBuf),
+
+    /// Cannot give a device the identity of the VM
+    SetIdentity(io::Error),
+
+    /// The MMIO aperture can't be split between the PCI segments
+    MmioApertureTooSmall(u64),
+
+    /// The 32-bit MMIO aperture overlaps the RAM below 4GiB
+    Mmio32ApertureOverlapsRam(u64),
+
+    /// The 64-bit MMIO aperture doesn't fit after the RAM in the guest
+    /// physical address space
+    Mmio64ApertureTooLarge(u64, u64),
 }
 
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 const DEVICE_MANAGER_ACPI_SIZE: usize = 0x10;
 
+// Memory policy modes from include/uapi/linux/mempolicy.h
+const MPOL_DEFAULT: libc::c_int = 0;
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_BIND: libc::c_int = 2;
+
+// Sets the memory policy of the calling thread. The policy is inherited by
+// the threads it creates.
+fn set_mempolicy(mode: libc::c_int, node: Option<u32>) -> io::Result<()> {
+    let mut nodemask: Vec<u64> = Vec::new();
+    let mut maxnode = 0;
+    if let Some(node) = node {
+        nodemask.resize((node as usize / 64) + 1, 0);
+        nodemask[(node / 64) as usize] |= 1u64 << (node % 64);
+        // Same as for mbind(), Linux cuts off the last node.
+        maxnode = node as u64 + 1 + 1;
+    }
+
+    // SAFETY: FFI call with correct arguments
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            mode,
+            if nodemask.is_empty() {
+                std::ptr::null()
+            } else {
+                nodemask.as_ptr()
+            },
+            maxnode,
+        )
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
 const TIOCSPTLCK: libc::c_int = 0x4004_5431;
 const TIOCGTPEER: libc::c_int = 0x5441;
 
@@ -767,6 +976,82 @@ impl DeviceRelocation for AddressManager {
 
         pci_dev.move_bar(old_base, new_base)
     }
+
+    fn resize_bar(
+        &self,
+        old_base: u64,
+        old_len: u64,
+        new_len: u64,
+        pci_dev: &mut dyn PciDevice,
+        region_type: PciBarRegionType,
+    ) -> std::result::Result<(), std::io::Error> {
+        let allocators = match region_type {
+            PciBarRegionType::Memory32BitRegion => &self.pci_mmio32_allocators,
+            PciBarRegionType::Memory64BitRegion => &self.pci_mmio64_allocators,
+            PciBarRegionType::IoRegion => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "I/O BARs can't be resized",
+                ))
+            }
+        };
+
+        // Find the specific allocator that this BAR was allocated from and
+        // allocate the range of the new size from it, anywhere in the window.
+        let allocator = allocators
+            .iter()
+            .find(|allocator| {
+                let allocator = allocator.lock().unwrap();
+                old_base >= allocator.base().0 && old_base <= allocator.end().0
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Couldn't find the allocator of BAR 0x{old_base:x}"),
+                )
+            })?;
+        let new_base = {
+            let mut allocator = allocator.lock().unwrap();
+            allocator.free(GuestAddress(old_base), old_len as GuestUsize);
+            match allocator.allocate(None, new_len as GuestUsize, Some(new_len)) {
+                Some(new_base) => new_base.raw_value(),
+                None => {
+                    // Keep the BAR where it is
+                    allocator.allocate(
+                        Some(GuestAddress(old_base)),
+                        old_len as GuestUsize,
+                        Some(old_len),
+                    );
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "failed allocating resized MMIO range",
+                    ));
+                }
+            }
+        };
+
+        // Update MMIO bus
+        self.mmio_bus
+            .update_range(old_base, old_len, new_base, new_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // Update the device_tree resources associated with the device
+        if let Some(id) = pci_dev.id() {
+            if let Some(node) = self.device_tree.lock().unwrap().get_mut(&id) {
+                for resource in node.resources.iter_mut() {
+                    if let Resource::PciBar { base, size, .. } = resource {
+                        if *base == old_base {
+                            *base = new_base;
+                            *size = new_len;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        pci_dev.resize_bar(old_base, new_base, new_len)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -797,6 +1082,37 @@ pub enum PciDeviceHandle {
     VfioUser(Arc<Mutex<VfioUserPciDevice>>),
 }
 
+/// Device slots left on a PCI segment, for hotplugging devices.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PciSegmentCapabilities {
+    pub id: u16,
+    pub free_slots: usize,
+}
+
+/// Device which can be removed from the running VM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RemovableDevice {
+    pub id: String,
+    /// Type of the virtio device, or `vfio`, `vfio-user` and `usb` for the
+    /// devices passed through
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub pci_segment: u16,
+    pub pci_bdf: PciBdf,
+}
+
+// Virtio devices which can be removed from the running VM.
+fn virtio_device_removable(device_type: VirtioDeviceType) -> bool {
+    matches!(
+        device_type,
+        VirtioDeviceType::Net
+            | VirtioDeviceType::Block
+            | VirtioDeviceType::Pmem
+            | VirtioDeviceType::Fs
+            | VirtioDeviceType::Vsock
+    )
+}
+
 #[derive(Clone)]
 struct MetaVirtioDevice {
     virtio_device: Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -836,6 +1152,12 @@ pub struct DeviceManager {
     // Serial Manager
     serial_manager: Option<Arc<SerialManager>>,
 
+    // Serial port, if enabled
+    #[cfg(target_arch = "x86_64")]
+    serial: Option<Arc<Mutex<Serial>>>,
+    #[cfg(target_arch = "aarch64")]
+    serial: Option<Arc<Mutex<Pl011>>>,
+
     // pty foreground status,
     console_resize_pipe: Option<Arc<File>>,
 
@@ -912,6 +1234,13 @@ pub struct DeviceManager {
     exit_evt: EventFd,
     reset_evt: EventFd,
 
+    // Set by the ACPI shutdown device when the guest powers off, by the
+    // watchdog when it resets the VM, and by the pvpanic device when the
+    // guest panics.
+    acpi_poweroff_signalled: Arc<AtomicBool>,
+    watchdog_triggered: Arc<AtomicBool>,
+    guest_panicked: Arc<AtomicBool>,
+
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MmioDeviceInfo>,
 
@@ -924,6 +1253,14 @@ pub struct DeviceManager {
     // Possible handle to the virtio-balloon device
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
 
+    // Possible handle to the virtio-rng device
+    rng: Option<Arc<Mutex<virtio_devices::Rng>>>,
+
+    // Descriptors of the files the console and serial output is written to,
+    // pointed to another file when the configuration is updated.
+    console_output_fd: Option<RawFd>,
+    serial_output_fd: Option<RawFd>,
+
     // Virtio Device activation EventFd to allow the VMM thread to trigger device
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
@@ -942,6 +1279,30 @@ pub struct DeviceManager {
     // pvpanic device
     pvpanic_device: Option<Arc<Mutex<devices::PvPanicDevice>>>,
 
+    #[cfg(target_arch = "x86_64")]
+    // ramfb boot framebuffer
+    ramfb: Option<Arc<Mutex<devices::legacy::Ramfb>>>,
+
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    // VNC server displaying the ramfb framebuffer
+    vnc_server: Option<crate::vnc::VncServer>,
+
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    // Input devices the VNC clients type and point through
+    vnc_input: Option<crate::vnc::VncInput>,
+
+    // TPM device
+    tpm_device: Option<Arc<Mutex<devices::tpm::Tpm>>>,
+
+    // xHCI controller
+    xhci_device: Option<Arc<Mutex<devices::XhciDevice>>>,
+
+    // Ports of the xHCI controller the host USB devices are plugged into
+    usb_ports: BTreeMap<String, u8>,
+
+    // VM generation ID device
+    vmgenid_device: Option<Arc<Mutex<devices::VmGenIdDevice>>>,
+
     // Flag to force setting the iommu on virtio devices
     force_iommu: bool,
 
@@ -960,6 +1321,13 @@ pub struct DeviceManager {
     // Pending activations
     pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
 
+    // Host NUMA node the virtio-pci devices allocate their memory from
+    device_numa_nodes: HashMap<String, u32>,
+
+    // Egress shaping installed on the TAP interface of the network devices,
+    // removed along with them
+    egress_shapings: HashMap<String, net_util::InstalledEgressShaping>,
+
     // Addresses for ACPI platform devices e.g. ACPI PM timer, sleep/reset registers
     acpi_platform_addresses: AcpiPlatformAddresses,
 
@@ -967,7 +1335,29 @@ pub struct DeviceManager {
 
     rate_limit_groups: HashMap<String, Arc<RateLimiterGroup>>,
 
+    // Rate limiters of the disks having their own, which go away along with
+    // the disk.
+    disk_rate_limit_groups: HashMap<String, Weak<RateLimiterGroup>>,
+
+    // Devices carrying the identity of the VM, which a ready VM gets on boot.
+    // They go away when unplugged.
+    virtio_block_devices: HashMap<String, Weak<Mutex<virtio_devices::Block>>>,
+    virtio_net_devices: HashMap<String, Weak<Mutex<virtio_devices::Net>>>,
+    virtio_vsock_device:
+        Option<Weak<Mutex<virtio_devices::Vsock<virtio_devices::vsock::VsockUnixBackend>>>>,
+
     mmio_regions: Arc<Mutex<Vec<MmioRegion>>>,
+
+    // Devices to plug once the guest has ejected the device they replace,
+    // indexed by the PCI b/d/f being freed.
+    pending_replacements: HashMap<PciBdf, ReplacementDeviceConfig>,
+
+    // PCI b/d/f the next hotplugged device must be assigned to
+    replacement_pci_bdf: Option<PciBdf>,
+
+    // Ephemeral overlays of the disks, indexed by disk id, which are erased
+    // when the disk is removed or the VM goes away.
+    ephemeral_disks: HashMap<String, block::ephemeral::EphemeralDisk>,
 }
 
 fn create_mmio_allocators(
@@ -998,6 +1388,155 @@ fn create_mmio_allocators(
     mmio_allocators
 }
 
+// Checks each PCI segment gets a share of the MMIO aperture, as split by
+// create_mmio_allocators().
+fn check_mmio_aperture_size(size: u64, weights: &[u32], alignment: u64) -> DeviceManagerResult<()> {
+    let total_weight: u64 = weights.iter().map(|weight| *weight as u64).sum();
+    if size / (alignment * total_weight) == 0 {
+        return Err(DeviceManagerError::MmioApertureTooSmall(size));
+    }
+
+    Ok(())
+}
+
+// The 32-bit aperture ends where the default one does, growing downwards
+// over the addresses the RAM below 4GiB leaves unused.
+fn mmio32_aperture_start(size: u64, guest_memory: &GuestMemoryMmap) -> DeviceManagerResult<u64> {
+    let end = layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE;
+    let start = end.saturating_sub(size);
+    if guest_memory
+        .iter()
+        .any(|region| region.start_addr().0 < end && region.last_addr().0 >= start)
+    {
+        return Err(DeviceManagerError::Mmio32ApertureOverlapsRam(size));
+    }
+
+    Ok(start)
+}
+
+// Checks the socket a vhost-user device connects to is there.
+fn check_vhost_user_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::metadata(path)?.file_type().is_socket() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket", path.display()),
+        ))
+    }
+}
+
+fn check_fd(fd: RawFd) -> io::Result<()> {
+    // SAFETY: FFI call with a caller provided file descriptor, only querying
+    // its flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Checks an overlay created on a previous boot is backed by the base image
+// given, the guest would be given the content of another disk otherwise.
+fn check_disk_overlay_base(path: &Path, base: &Path) -> DeviceManagerResult<()> {
+    let file = File::open(path).map_err(DeviceManagerError::Disk)?;
+    let mut file = qcow::RawFile::new(file, false);
+    let backing_file_path = qcow::QcowHeader::new(&mut file)
+        .map_err(DeviceManagerError::ReadDiskOverlay)?
+        .backing_file_path;
+
+    // Both paths may be relative to the current directory, they are compared
+    // once resolved.
+    let same_file =
+        |backing: &str| match (std::fs::canonicalize(backing), std::fs::canonicalize(base)) {
+            (Ok(backing), Ok(base)) => backing == base,
+            _ => Path::new(backing) == base,
+        };
+    if !backing_file_path.as_deref().is_some_and(same_file) {
+        return Err(DeviceManagerError::DiskOverlayBaseMismatch(
+            path.to_path_buf(),
+            backing_file_path,
+        ));
+    }
+
+    Ok(())
+}
+
+// Opens the backend of a replacement device before the replaced device gets
+// ejected, the guest being left without any device otherwise if the
+// replacement can't be created. Nothing is created along the way, the
+// overlays of the disk images included.
+fn check_replacement_backend(replacement: &ReplacementDeviceConfig) -> DeviceManagerResult<()> {
+    match replacement {
+        ReplacementDeviceConfig::Disk(cfg) if cfg.vhost_user => {
+            let socket = cfg.vhost_socket.as_ref().ok_or_else(|| {
+                DeviceManagerError::ReplacementBackend(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Missing vhost-user socket",
+                ))
+            })?;
+            check_vhost_user_socket(Path::new(socket))
+                .map_err(DeviceManagerError::ReplacementBackend)
+        }
+        ReplacementDeviceConfig::Disk(cfg) => {
+            if let Some(fd) = cfg.fd {
+                return check_fd(fd).map_err(DeviceManagerError::Disk);
+            }
+
+            let path = cfg.path.as_ref().ok_or(DeviceManagerError::NoDiskPath)?;
+            // Only the base is there yet when the overlay is to be created,
+            // and the guest doesn't write to the image of an ephemeral disk.
+            let (path, writable) = match &cfg.base {
+                Some(base) if cfg.create_overlay && !path.exists() => (base, false),
+                Some(base) if cfg.create_overlay => {
+                    check_disk_overlay_base(path, base)?;
+                    (path, !cfg.readonly && !cfg.ephemeral)
+                }
+                _ => (path, !cfg.readonly && !cfg.ephemeral),
+            };
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .open(path)
+                .map_err(DeviceManagerError::Disk)?;
+            detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+
+            Ok(())
+        }
+        ReplacementDeviceConfig::Fs(cfg) => {
+            check_vhost_user_socket(&cfg.socket).map_err(DeviceManagerError::ReplacementBackend)
+        }
+        ReplacementDeviceConfig::Net(cfg) => {
+            if cfg.vhost_user && cfg.vhost_mode == VhostMode::Client {
+                if let Some(socket) = &cfg.vhost_socket {
+                    check_vhost_user_socket(Path::new(socket))
+                        .map_err(DeviceManagerError::ReplacementBackend)?;
+                }
+            }
+            for fd in cfg.fds.iter().flatten() {
+                check_fd(*fd).map_err(DeviceManagerError::ReplacementBackend)?;
+            }
+            Ok(())
+        }
+        ReplacementDeviceConfig::Pmem(cfg) => {
+            if cfg.file.is_dir() {
+                if cfg.size.is_none() {
+                    return Err(DeviceManagerError::PmemWithDirectorySizeMissing);
+                }
+            } else {
+                OpenOptions::new()
+                    .read(true)
+                    .write(!cfg.discard_writes)
+                    .open(&cfg.file)
+                    .map_err(DeviceManagerError::PmemFileOpen)?;
+            }
+            Ok(())
+        }
+    }
+}
+
 impl DeviceManager {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -1049,8 +1588,22 @@ impl DeviceManager {
             }
         }
 
-        let start_of_mmio32_area = layout::MEM_32BIT_DEVICES_START.0;
+        let (mmio32_size, mmio64_size) = config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map(|platform| (platform.mmio32_size, platform.mmio64_size))
+            .unwrap_or_default();
+
         let end_of_mmio32_area = layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE;
+        let start_of_mmio32_area = if let Some(size) = mmio32_size {
+            check_mmio_aperture_size(size, &mmio32_aperture_weights, 4 << 10)?;
+            let guest_memory = memory_manager.lock().unwrap().guest_memory().memory();
+            mmio32_aperture_start(size, &guest_memory)?
+        } else {
+            layout::MEM_32BIT_DEVICES_START.0
+        };
         let pci_mmio32_allocators = create_mmio_allocators(
             start_of_mmio32_area,
             end_of_mmio32_area,
@@ -1071,7 +1624,19 @@ impl DeviceManager {
         }
 
         let start_of_mmio64_area = memory_manager.lock().unwrap().start_of_device_area().0;
-        let end_of_mmio64_area = memory_manager.lock().unwrap().end_of_device_area().0;
+        let mut end_of_mmio64_area = memory_manager.lock().unwrap().end_of_device_area().0;
+        if let Some(size) = mmio64_size {
+            // The device area starts after the RAM, including the hotplug
+            // area, and ends with the guest physical address space, which
+            // physical_bits() widened for the aperture as far as the host
+            // allows.
+            let available = end_of_mmio64_area - start_of_mmio64_area + 1;
+            if size > available {
+                return Err(DeviceManagerError::Mmio64ApertureTooLarge(size, available));
+            }
+            check_mmio_aperture_size(size, &mmio64_aperture_weights, 4 << 30)?;
+            end_of_mmio64_area = start_of_mmio64_area + size - 1;
+        }
         let pci_mmio64_allocators = create_mmio_allocators(
             start_of_mmio64_area,
             end_of_mmio64_area,
@@ -1204,11 +1769,17 @@ impl DeviceManager {
             device_tree,
             exit_evt,
             reset_evt,
+            acpi_poweroff_signalled: Arc::new(AtomicBool::new(false)),
+            watchdog_triggered: Arc::new(AtomicBool::new(false)),
+            guest_panicked: Arc::new(AtomicBool::new(false)),
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
             seccomp_action,
             numa_nodes,
             balloon: None,
+            rng: None,
+            console_output_fd: None,
+            serial_output_fd: None,
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
@@ -1216,24 +1787,44 @@ impl DeviceManager {
             selected_segment: 0,
             serial_pty: None,
             serial_manager: None,
+            serial: None,
             console_pty: None,
             debug_console_pty: None,
             console_resize_pipe: None,
             original_termios_opt: Arc::new(Mutex::new(None)),
             virtio_mem_devices: Vec::new(),
+            xhci_device: None,
+            usb_ports: BTreeMap::new(),
             #[cfg(target_arch = "aarch64")]
             gpio_device: None,
             pvpanic_device: None,
+            #[cfg(target_arch = "x86_64")]
+            ramfb: None,
+            #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+            vnc_server: None,
+            #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+            vnc_input: None,
+            tpm_device: None,
+            vmgenid_device: None,
             force_iommu,
             io_uring_supported: None,
             aio_supported: None,
             boot_id_list,
             timestamp,
             pending_activations: Arc::new(Mutex::new(Vec::default())),
+            device_numa_nodes: HashMap::new(),
+            egress_shapings: HashMap::new(),
             acpi_platform_addresses: AcpiPlatformAddresses::default(),
             snapshot,
             rate_limit_groups,
+            disk_rate_limit_groups: HashMap::new(),
+            virtio_block_devices: HashMap::new(),
+            virtio_net_devices: HashMap::new(),
+            virtio_vsock_device: None,
             mmio_regions: Arc::new(Mutex::new(Vec::new())),
+            pending_replacements: HashMap::new(),
+            replacement_pci_bdf: None,
+            ephemeral_disks: HashMap::new(),
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1348,14 +1939,33 @@ impl DeviceManager {
         if let Some(tpm) = self.config.clone().lock().unwrap().tpm.as_ref() {
             let tpm_dev = self.add_tpm_device(tpm.socket.clone())?;
             self.bus_devices
-                .push(Arc::clone(&tpm_dev) as Arc<Mutex<dyn BusDevice>>)
+                .push(Arc::clone(&tpm_dev) as Arc<Mutex<dyn BusDevice>>);
+            self.tpm_device = Some(tpm_dev);
+        }
+
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .is_some_and(|p| p.vmgenid)
+        {
+            self.vmgenid_device = Some(self.add_vmgenid_device()?);
         }
         self.legacy_interrupt_manager = Some(legacy_interrupt_manager);
 
         virtio_devices.append(&mut self.make_virtio_devices()?);
 
+        #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+        self.start_vnc_server()?;
+
         self.add_pci_devices(virtio_devices.clone())?;
 
+        self.add_e1000e_devices()?;
+
+        self.add_xhci_device()?;
+
         self.virtio_devices = virtio_devices;
 
         if self.config.clone().lock().unwrap().pvpanic {
@@ -1598,6 +2208,7 @@ impl DeviceManager {
             exit_evt,
             reset_evt,
             vcpus_kill_signalled,
+            self.acpi_poweroff_signalled.clone(),
         )));
 
         self.bus_devices
@@ -1702,20 +2313,31 @@ impl DeviceManager {
             .unwrap()
             .vcpus_kill_signalled()
             .clone();
-        // Add a shutdown device (i8042)
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
-            reset_evt.try_clone().unwrap(),
-            vcpus_kill_signalled.clone(),
-        )));
+        let (i8042_enabled, rtc_enabled) = {
+            let config = self.config.lock().unwrap();
+            (
+                config.legacy_device_enabled(LegacyDevice::I8042),
+                config.legacy_device_enabled(LegacyDevice::Rtc),
+            )
+        };
 
-        self.bus_devices
-            .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
+        if i8042_enabled {
+            // Add a shutdown device (i8042)
+            let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+                reset_evt.try_clone().unwrap(),
+                vcpus_kill_signalled.clone(),
+            )));
 
-        self.address_manager
-            .io_bus
-            .insert(i8042, 0x61, 0x4)
-            .map_err(DeviceManagerError::BusError)?;
-        {
+            self.bus_devices
+                .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
+
+            self.address_manager
+                .io_bus
+                .insert(i8042, 0x61, 0x4)
+                .map_err(DeviceManagerError::BusError)?;
+        }
+
+        if rtc_enabled {
             // Add a CMOS emulated device
             let mem_size = self
                 .memory_manager
@@ -1743,17 +2365,17 @@ impl DeviceManager {
                 .io_bus
                 .insert(cmos, 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
+        }
 
-            let fwdebug = Arc::new(Mutex::new(devices::legacy::FwDebugDevice::new()));
+        let fwdebug = Arc::new(Mutex::new(devices::legacy::FwDebugDevice::new()));
 
-            self.bus_devices
-                .push(Arc::clone(&fwdebug) as Arc<Mutex<dyn BusDevice>>);
+        self.bus_devices
+            .push(Arc::clone(&fwdebug) as Arc<Mutex<dyn BusDevice>>);
 
-            self.address_manager
-                .io_bus
-                .insert(fwdebug, 0x402, 0x1)
-                .map_err(DeviceManagerError::BusError)?;
-        }
+        self.address_manager
+            .io_bus
+            .insert(fwdebug, 0x402, 0x1)
+            .map_err(DeviceManagerError::BusError)?;
 
         // 0x80 debug port
         let debug_port = Arc::new(Mutex::new(devices::legacy::DebugPort::new(self.timestamp)));
@@ -1764,6 +2386,87 @@ impl DeviceManager {
             .insert(debug_port, 0x80, 0x1)
             .map_err(DeviceManagerError::BusError)?;
 
+        if self.config.lock().unwrap().ramfb {
+            self.add_ramfb_device()?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_ramfb_device(&mut self) -> DeviceManagerResult<()> {
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let ramfb = Arc::new(Mutex::new(devices::legacy::Ramfb::new(
+            guest_memory.clone(),
+        )));
+
+        // The firmware configures the framebuffer through the fw_cfg device
+        let mut fw_cfg = devices::legacy::FwCfg::new(guest_memory);
+        let on_write = {
+            let ramfb = ramfb.clone();
+            Box::new(move |data: &[u8]| ramfb.lock().unwrap().configure(data))
+        };
+        fw_cfg
+            .add_file(
+                devices::legacy::RAMFB_FILE_NAME,
+                vec![0; devices::legacy::RAMFB_CFG_SIZE],
+                Some(on_write),
+            )
+            .map_err(DeviceManagerError::FwCfgAddFile)?;
+
+        let fw_cfg = Arc::new(Mutex::new(fw_cfg));
+        self.bus_devices
+            .push(Arc::clone(&fw_cfg) as Arc<Mutex<dyn BusDevice>>);
+        self.address_manager
+            .io_bus
+            .insert(
+                fw_cfg,
+                devices::legacy::FW_CFG_PORT_BASE,
+                devices::legacy::FW_CFG_PORT_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+
+        self.ramfb = Some(ramfb);
+
+        Ok(())
+    }
+
+    // Displays the ramfb framebuffer, the virtio-input devices being created
+    // already.
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    fn start_vnc_server(&mut self) -> DeviceManagerResult<()> {
+        let (Some(vnc), Some(ramfb)) = (self.config.lock().unwrap().vnc.clone(), &self.ramfb)
+        else {
+            return Ok(());
+        };
+
+        let seccomp_filters = crate::vnc::VncSeccompFilters {
+            server: get_seccomp_filter(
+                &self.seccomp_action,
+                Thread::VncServer,
+                self.hypervisor_type,
+            )
+            .map_err(DeviceManagerError::VncSeccompFilter)?,
+            client: get_seccomp_filter(
+                &self.seccomp_action,
+                Thread::VncClient,
+                self.hypervisor_type,
+            )
+            .map_err(DeviceManagerError::VncSeccompFilter)?,
+        };
+        self.vnc_server = Some(
+            crate::vnc::VncServer::new(
+                &vnc.socket,
+                ramfb.clone(),
+                self.vnc_input.clone(),
+                seccomp_filters,
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::CreateVncServer)?,
+        );
+
         Ok(())
     }
 
@@ -2085,6 +2788,7 @@ impl DeviceManager {
             ConsoleOutputMode::File => {
                 let file = File::create(console_config.file.as_ref().unwrap())
                     .map_err(DeviceManagerError::ConsoleOutputFileOpen)?;
+                self.console_output_fd = Some(file.as_raw_fd());
                 Endpoint::File(file)
             }
             ConsoleOutputMode::Pty => {
@@ -2211,10 +2915,12 @@ impl DeviceManager {
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
-            ConsoleOutputMode::File => Some(Box::new(
-                File::create(serial_config.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::SerialOutputFileOpen)?,
-            )),
+            ConsoleOutputMode::File => {
+                let file = File::create(serial_config.file.as_ref().unwrap())
+                    .map_err(DeviceManagerError::SerialOutputFileOpen)?;
+                self.serial_output_fd = Some(file.as_raw_fd());
+                Some(Box::new(file))
+            }
             ConsoleOutputMode::Pty => {
                 if let Some(pty) = serial_pty.clone() {
                     self.config.lock().unwrap().serial.file = Some(pty.path.clone());
@@ -2236,8 +2942,15 @@ impl DeviceManager {
             }
             ConsoleOutputMode::Off | ConsoleOutputMode::Null | ConsoleOutputMode::Socket => None,
         };
-        if serial_config.mode != ConsoleOutputMode::Off {
+        if serial_config.mode != ConsoleOutputMode::Off
+            && self
+                .config
+                .lock()
+                .unwrap()
+                .legacy_device_enabled(LegacyDevice::Serial)
+        {
             let serial = self.add_serial_device(interrupt_manager, serial_writer)?;
+            self.serial = Some(Arc::clone(&serial));
             self.serial_manager = match serial_config.mode {
                 ConsoleOutputMode::Pty | ConsoleOutputMode::Tty | ConsoleOutputMode::Socket => {
                     let serial_manager = SerialManager::new(
@@ -2331,6 +3044,30 @@ impl DeviceManager {
         Ok(tpm)
     }
 
+    fn add_vmgenid_device(&mut self) -> DeviceManagerResult<Arc<Mutex<devices::VmGenIdDevice>>> {
+        let vmgenid_address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(None, devices::VMGENID_DEVICE_MMIO_SIZE, None)
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+        let vmgenid_device = Arc::new(Mutex::new(devices::VmGenIdDevice::new(vmgenid_address)));
+
+        self.address_manager
+            .mmio_bus
+            .insert(
+                vmgenid_device.clone(),
+                vmgenid_address.0,
+                devices::VMGENID_DEVICE_MMIO_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&vmgenid_device) as Arc<Mutex<dyn BusDevice>>);
+
+        Ok(vmgenid_device)
+    }
+
     fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices: Vec<MetaVirtioDevice> = Vec::new();
 
@@ -2342,6 +3079,10 @@ impl DeviceManager {
         // Add virtio-fs if required
         devices.append(&mut self.make_virtio_fs_devices()?);
 
+        // Add virtio-9p if required
+        #[cfg(feature = "virtio_9p")]
+        devices.append(&mut self.make_virtio_p9_devices()?);
+
         // Add virtio-pmem if required
         devices.append(&mut self.make_virtio_pmem_devices()?);
 
@@ -2356,6 +3097,10 @@ impl DeviceManager {
         // Add virtio-watchdog device
         devices.append(&mut self.make_virtio_watchdog_devices()?);
 
+        // Add the virtio-input devices of the VNC server if required
+        #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+        devices.append(&mut self.make_virtio_input_devices()?);
+
         // Add vDPA devices if required
         devices.append(&mut self.make_vdpa_devices()?);
 
@@ -2384,6 +3129,175 @@ impl DeviceManager {
         supported
     }
 
+    /// Opens the image of a disk, creating its overlay if needed, and picks
+    /// the backend it's accessed through.
+    fn open_disk_image(
+        &mut self,
+        id: &str,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(Box<dyn DiskFile>, PathBuf)> {
+        let (mut file, disk_path) = if let Some(fd) = disk_cfg.fd {
+            // Duplicate the file descriptor so that the original one
+            // stays open and the device can be created again on reboot.
+            // SAFETY: FFI call with a caller provided file descriptor
+            let dup_fd = unsafe { libc::dup(fd) };
+            if dup_fd < 0 {
+                return Err(DeviceManagerError::Disk(io::Error::last_os_error()));
+            }
+            // SAFETY: 'dup_fd' is a valid file descriptor we own
+            let file = unsafe { File::from_raw_fd(dup_fd) };
+            if disk_cfg.direct {
+                // SAFETY: FFI calls with a valid file descriptor
+                let ret = unsafe {
+                    let flags = libc::fcntl(dup_fd, libc::F_GETFL);
+                    libc::fcntl(dup_fd, libc::F_SETFL, flags | libc::O_DIRECT)
+                };
+                if ret < 0 {
+                    return Err(DeviceManagerError::Disk(io::Error::last_os_error()));
+                }
+            }
+
+            // SAFETY: 'fd' is valid because it was successfully duplicated
+            unsafe {
+                self.config.lock().unwrap().add_preserved_fds(vec![fd]);
+            }
+
+            (file, PathBuf::from(format!("/proc/self/fd/{fd}")))
+        } else {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            options.write(!disk_cfg.readonly);
+            if disk_cfg.direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            let disk_path = disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .clone();
+            if let Some(base) = disk_cfg.base.as_ref().filter(|_| disk_cfg.create_overlay) {
+                if disk_path.exists() {
+                    check_disk_overlay_base(&disk_path, base)?;
+                } else {
+                    info!(
+                        "Creating overlay {} of base image {}",
+                        disk_path.display(),
+                        base.display()
+                    );
+                    qcow::QcowFile::create_overlay(&disk_path, &base.to_string_lossy())
+                        .map_err(DeviceManagerError::CreateDiskOverlay)?;
+                }
+            }
+            // The guest writes to a temporary overlay of the disk image
+            let disk_path = if disk_cfg.ephemeral {
+                let overlay_path = std::env::temp_dir().join(format!(
+                    "cloud-hypervisor-{}-{}.qcow2",
+                    std::process::id(),
+                    id
+                ));
+                info!(
+                    "Creating ephemeral overlay {} of {}",
+                    overlay_path.display(),
+                    disk_path.display()
+                );
+                let ephemeral_disk = block::ephemeral::EphemeralDisk::create(
+                    overlay_path,
+                    &disk_path,
+                    disk_cfg.secure_erase,
+                )
+                .map_err(DeviceManagerError::CreateEphemeralDisk)?;
+                let overlay_path = ephemeral_disk.path().to_path_buf();
+                self.ephemeral_disks.insert(id.to_string(), ephemeral_disk);
+                overlay_path
+            } else {
+                disk_path
+            };
+            // Open block device path
+            let file: File = options.open(&disk_path).map_err(DeviceManagerError::Disk)?;
+
+            (file, disk_path)
+        };
+        let image_type =
+            detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+
+        let image = match image_type {
+            ImageType::FixedVhd => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if cfg!(feature = "io_uring")
+                    && !disk_cfg.disable_io_uring
+                    && self.io_uring_is_supported()
+                {
+                    info!("Using asynchronous fixed VHD disk file (io_uring)");
+
+                    #[cfg(not(feature = "io_uring"))]
+                    unreachable!("Checked in if statement above");
+                    #[cfg(feature = "io_uring")]
+                    {
+                        Box::new(
+                            FixedVhdDiskAsync::new(file)
+                                .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
+                        ) as Box<dyn DiskFile>
+                    }
+                } else {
+                    info!("Using synchronous fixed VHD disk file");
+                    Box::new(
+                        FixedVhdDiskSync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
+                    ) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Raw => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if cfg!(feature = "io_uring")
+                    && !disk_cfg.disable_io_uring
+                    && self.io_uring_is_supported()
+                {
+                    info!("Using asynchronous RAW disk file (io_uring)");
+
+                    #[cfg(not(feature = "io_uring"))]
+                    unreachable!("Checked in if statement above");
+                    #[cfg(feature = "io_uring")]
+                    {
+                        Box::new(RawFileDisk::new(file, disk_cfg.read_ahead)) as Box<dyn DiskFile>
+                    }
+                } else if !disk_cfg.disable_aio && self.aio_is_supported() {
+                    info!("Using asynchronous RAW disk file (aio)");
+                    Box::new(RawFileDiskAio::new(file, disk_cfg.read_ahead)) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous RAW disk file");
+                    Box::new(RawFileDiskSync::new(file, disk_cfg.read_ahead)) as Box<dyn DiskFile>
+                }
+            }
+            ImageType::Qcow2 => {
+                info!("Using synchronous QCOW disk file");
+                Box::new(
+                    QcowDiskSync::new(file, disk_cfg.direct)
+                        .map_err(DeviceManagerError::CreateQcowDiskSync)?,
+                ) as Box<dyn DiskFile>
+            }
+            ImageType::Vhdx => {
+                info!("Using synchronous VHDX disk file");
+                Box::new(
+                    VhdxDiskSync::new(file).map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
+                ) as Box<dyn DiskFile>
+            }
+            ImageType::ZstdSeekable => {
+                if !disk_cfg.readonly {
+                    return Err(DeviceManagerError::CompressedDiskNotReadOnly);
+                }
+                info!("Using synchronous zstd seekable disk file");
+                Box::new(
+                    block::zstd_seekable_sync::ZstdSeekableDiskSync::new(file)
+                        .map_err(DeviceManagerError::CreateZstdSeekableDiskSync)?,
+                ) as Box<dyn DiskFile>
+            }
+        };
+
+        Ok((image, disk_path))
+    }
+
     fn make_virtio_block_device(
         &mut self,
         disk_cfg: &mut DiskConfig,
@@ -2404,6 +3318,7 @@ impl DeviceManager {
                 socket,
                 num_queues: disk_cfg.num_queues,
                 queue_size: disk_cfg.queue_size,
+                instance_id: None,
             };
             let vhost_user_block = Arc::new(Mutex::new(
                 match virtio_devices::vhost_user::Blk::new(
@@ -2429,90 +3344,7 @@ impl DeviceManager {
                 vhost_user_block as Arc<Mutex<dyn Migratable>>,
             )
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
-
-            let image = match image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(
-                                FixedVhdDiskAsync::new(file)
-                                    .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                            ) as Box<dyn DiskFile>
-                        }
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous RAW disk file (io_uring)");
-
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                        }
-                    } else if !disk_cfg.disable_aio && self.aio_is_supported() {
-                        info!("Using asynchronous RAW disk file (aio)");
-                        Box::new(RawFileDiskAio::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW disk file");
-                    Box::new(
-                        QcowDiskSync::new(file, disk_cfg.direct)
-                            .map_err(DeviceManagerError::CreateQcowDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-                ImageType::Vhdx => {
-                    info!("Using synchronous VHDX disk file");
-                    Box::new(
-                        VhdxDiskSync::new(file)
-                            .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-            };
+            let (image, disk_path) = self.open_disk_image(&id, disk_cfg)?;
 
             let rate_limit_group =
                 if let Some(rate_limiter_cfg) = disk_cfg.rate_limiter_config.as_ref() {
@@ -2539,7 +3371,12 @@ impl DeviceManager {
                         )
                         .unwrap();
 
-                    Some(Arc::new(rate_limit_group))
+                    let rate_limit_group = Arc::new(rate_limit_group);
+                    self.disk_rate_limit_groups.insert(
+                        disk_cfg.id.clone().unwrap(),
+                        Arc::downgrade(&rate_limit_group),
+                    );
+                    Some(rate_limit_group)
                 } else if let Some(rate_limit_group) = disk_cfg.rate_limit_group.as_ref() {
                     self.rate_limit_groups.get(rate_limit_group).cloned()
                 } else {
@@ -2559,11 +3396,7 @@ impl DeviceManager {
                 virtio_devices::Block::new(
                     id.clone(),
                     image,
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
+                    disk_path,
                     disk_cfg.readonly,
                     self.force_iommu | disk_cfg.iommu,
                     disk_cfg.num_queues,
@@ -2580,6 +3413,8 @@ impl DeviceManager {
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
+            self.virtio_block_devices
+                .insert(id.clone(), Arc::downgrade(&virtio_block));
 
             (
                 Arc::clone(&virtio_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2637,6 +3472,7 @@ impl DeviceManager {
                 socket,
                 num_queues: net_cfg.num_queues,
                 queue_size: net_cfg.queue_size,
+                instance_id: net_cfg.vhost_instance_id.clone(),
             };
             let server = match net_cfg.vhost_mode {
                 VhostMode::Client => false,
@@ -2674,13 +3510,57 @@ impl DeviceManager {
         } else {
             let state = state_from_id(self.snapshot.as_ref(), id.as_str())
                 .map_err(DeviceManagerError::RestoreGetState)?;
-            let virtio_net = if let Some(ref tap_if_name) = net_cfg.tap {
+            let (ipv4_addr, ipv4_mask) = net_ipv4_config(net_cfg);
+            let virtio_net = if let Some(bridge) = &net_cfg.bridge {
+                // The TAP interface is only used as a bridge port, hence it
+                // is not given any IP configuration.
+                let taps = net_util::open_tap(
+                    net_cfg.tap.as_deref(),
+                    None,
+                    None,
+                    &mut net_cfg.host_mac,
+                    net_cfg.mtu,
+                    net_cfg.num_queues / 2,
+                    None,
+                )
+                .map_err(DeviceManagerError::OpenBridgedTap)?;
+
+                let if_name = taps[0].get_if_name();
+                let if_name = String::from_utf8_lossy(&if_name);
+                net_util::bridge_attach(
+                    if_name.trim_end_matches(char::from(0)),
+                    bridge,
+                    net_cfg.vlan,
+                )
+                .map_err(DeviceManagerError::BridgeAttach)?;
+
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_tap(
+                        id.clone(),
+                        taps,
+                        Some(net_cfg.mac),
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        net_cfg.rate_limiter_config,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                        state,
+                        net_cfg.offload_tso,
+                        net_cfg.offload_ufo,
+                        net_cfg.offload_csum,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
                         Some(tap_if_name),
-                        Some(net_cfg.ip),
-                        Some(net_cfg.mask),
+                        ipv4_addr,
+                        ipv4_mask,
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
                         net_cfg.mtu,
@@ -2730,8 +3610,8 @@ impl DeviceManager {
                     virtio_devices::Net::new(
                         id.clone(),
                         None,
-                        Some(net_cfg.ip),
-                        Some(net_cfg.mask),
+                        ipv4_addr,
+                        ipv4_mask,
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
                         net_cfg.mtu,
@@ -2752,6 +3632,26 @@ impl DeviceManager {
                 ))
             };
 
+            if let Some(shaping) = &net_cfg.egress_shaping {
+                let if_name = virtio_net.lock().unwrap().tap_if_name();
+                let installed = net_util::set_egress_shaping(&if_name, shaping)
+                    .map_err(DeviceManagerError::EgressShaping)?;
+                self.egress_shapings.insert(id.clone(), installed);
+            }
+
+            if let Some(ipv6) = net_cfg.ipv6 {
+                let mut net = virtio_net.lock().unwrap();
+                net_util::set_ipv6_addr(&net.tap_if_name(), ipv6, net_cfg.ipv6_prefix_len)
+                    .map_err(DeviceManagerError::SetIpv6Address)?;
+                if net_cfg.ipv6_ra {
+                    net.enable_router_advertisement(ipv6, net_cfg.ipv6_prefix_len)
+                        .map_err(DeviceManagerError::EnableRouterAdvertisement)?;
+                }
+            }
+
+            self.virtio_net_devices
+                .insert(id.clone(), Arc::downgrade(&virtio_net));
+
             (
                 Arc::clone(&virtio_net) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
                 virtio_net as Arc<Mutex<dyn Migratable>>,
@@ -2781,6 +3681,10 @@ impl DeviceManager {
         let mut net_devices = self.config.lock().unwrap().net.clone();
         if let Some(net_list_cfg) = &mut net_devices {
             for net_cfg in net_list_cfg.iter_mut() {
+                // Emulated NICs are added once the virtio devices are.
+                if net_cfg.model != NetModel::Virtio {
+                    continue;
+                }
                 devices.push(self.make_virtio_net_device(net_cfg)?);
             }
         }
@@ -2828,6 +3732,8 @@ impl DeviceManager {
                 .lock()
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_rng_device));
+
+            self.rng = Some(virtio_rng_device);
         }
 
         Ok(devices)
@@ -2849,6 +3755,42 @@ impl DeviceManager {
 
         let mut node = device_node!(id);
 
+        #[cfg(feature = "fs_builtin")]
+        if fs_cfg.builtin {
+            let shared_dir = fs_cfg
+                .shared_dir
+                .as_ref()
+                .ok_or(DeviceManagerError::NoVirtioFsSharedDir)?;
+            let virtio_fs_device = Arc::new(Mutex::new(
+                virtio_devices::fs::Fs::new(
+                    id.clone(),
+                    shared_dir,
+                    &fs_cfg.tag,
+                    fs_cfg.num_queues,
+                    fs_cfg.queue_size,
+                    fs_cfg.readonly,
+                    self.seccomp_action.clone(),
+                    self.exit_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                )
+                .map_err(DeviceManagerError::CreateBuiltinVirtioFs)?,
+            ));
+
+            // Update the device tree with the migratable device.
+            node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
+            self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+            return Ok(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_fs_device)
+                    as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+                iommu: false,
+                id,
+                pci_segment: fs_cfg.pci_segment,
+                dma_handler: None,
+            });
+        }
+
         if let Some(fs_socket) = fs_cfg.socket.to_str() {
             let virtio_fs_device = Arc::new(Mutex::new(
                 virtio_devices::vhost_user::Fs::new(
@@ -2900,6 +3842,66 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    #[cfg(feature = "virtio_9p")]
+    fn make_virtio_p9_device(
+        &mut self,
+        p9_cfg: &mut P9Config,
+    ) -> DeviceManagerResult<MetaVirtioDevice> {
+        let id = if let Some(id) = &p9_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(P9_DEVICE_NAME_PREFIX)?;
+            p9_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating virtio-9p device: {:?}", p9_cfg);
+
+        let mut node = device_node!(id);
+
+        let virtio_p9_device = Arc::new(Mutex::new(
+            virtio_devices::p9::P9::new(
+                id.clone(),
+                &p9_cfg.path,
+                &p9_cfg.tag,
+                self.force_iommu | p9_cfg.iommu,
+                self.seccomp_action.clone(),
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::CreateVirtioP9)?,
+        ));
+
+        // Update the device tree with the migratable device.
+        node.migratable = Some(Arc::clone(&virtio_p9_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+
+        Ok(MetaVirtioDevice {
+            virtio_device: Arc::clone(&virtio_p9_device)
+                as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+            iommu: p9_cfg.iommu,
+            id,
+            pci_segment: p9_cfg.pci_segment,
+            dma_handler: None,
+        })
+    }
+
+    #[cfg(feature = "virtio_9p")]
+    fn make_virtio_p9_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        let mut p9_devices = self.config.lock().unwrap().p9.clone();
+        if let Some(p9_list_cfg) = &mut p9_devices {
+            for p9_cfg in p9_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_p9_device(p9_cfg)?);
+            }
+        }
+        self.config.lock().unwrap().p9 = p9_devices;
+
+        Ok(devices)
+    }
+
     fn make_virtio_pmem_device(
         &mut self,
         pmem_cfg: &mut PmemConfig,
@@ -3124,6 +4126,7 @@ impl DeviceManager {
             )
             .map_err(DeviceManagerError::CreateVirtioVsock)?,
         ));
+        self.virtio_vsock_device = Some(Arc::downgrade(&vsock_device));
 
         // Fill the device tree with a new node. In case of restore, we
         // know there is nothing to do, so we can simply override the
@@ -3217,16 +4220,24 @@ impl DeviceManager {
     fn make_virtio_balloon_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
+        let rt = self.config.lock().unwrap().rt.is_some();
         if let Some(balloon_config) = &self.config.lock().unwrap().balloon {
             let id = String::from(BALLOON_DEVICE_NAME);
             info!("Creating virtio-balloon device: id = {}", id);
 
+            // The guest reports its free pages from a periodic worker, which
+            // the realtime profile can't tolerate.
+            let free_page_reporting = balloon_config.free_page_reporting && !rt;
+            if balloon_config.free_page_reporting && rt {
+                warn!("Free page reporting disabled by the realtime profile");
+            }
+
             let virtio_balloon_device = Arc::new(Mutex::new(
                 virtio_devices::Balloon::new(
                     id.clone(),
                     balloon_config.size,
                     balloon_config.deflate_on_oom,
-                    balloon_config.free_page_reporting,
+                    free_page_reporting,
                     self.seccomp_action.clone(),
                     self.exit_evt
                         .try_clone()
@@ -3271,6 +4282,7 @@ impl DeviceManager {
             virtio_devices::Watchdog::new(
                 id.clone(),
                 self.reset_evt.try_clone().unwrap(),
+                self.watchdog_triggered.clone(),
                 self.seccomp_action.clone(),
                 self.exit_evt
                     .try_clone()
@@ -3297,6 +4309,69 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    #[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+    fn make_virtio_input_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
+        let mut devices = Vec::new();
+
+        if !self
+            .config
+            .lock()
+            .unwrap()
+            .vnc
+            .as_ref()
+            .is_some_and(|vnc| vnc.input)
+        {
+            return Ok(devices);
+        }
+
+        let mut senders = Vec::new();
+        for (name, kind) in [
+            (
+                VNC_KEYBOARD_DEVICE_NAME,
+                virtio_devices::InputKind::Keyboard,
+            ),
+            (VNC_TABLET_DEVICE_NAME, virtio_devices::InputKind::Tablet),
+        ] {
+            let id = String::from(name);
+            info!("Creating virtio-input device: id = {}", id);
+
+            let virtio_input_device = Arc::new(Mutex::new(
+                virtio_devices::Input::new(
+                    id.clone(),
+                    kind,
+                    self.force_iommu,
+                    self.seccomp_action.clone(),
+                    self.exit_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                    state_from_id(self.snapshot.as_ref(), id.as_str())
+                        .map_err(DeviceManagerError::RestoreGetState)?,
+                )
+                .map_err(DeviceManagerError::CreateVirtioInput)?,
+            ));
+            senders.push(virtio_input_device.lock().unwrap().event_sender());
+            devices.push(MetaVirtioDevice {
+                virtio_device: Arc::clone(&virtio_input_device)
+                    as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+                iommu: false,
+                id: id.clone(),
+                pci_segment: 0,
+                dma_handler: None,
+            });
+
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, virtio_input_device));
+        }
+
+        let tablet = senders.pop().unwrap();
+        let keyboard = senders.pop().unwrap();
+        self.vnc_input = Some(crate::vnc::VncInput { keyboard, tablet });
+
+        Ok(devices)
+    }
+
     fn make_vdpa_device(
         &mut self,
         vdpa_cfg: &mut VdpaConfig,
@@ -3524,6 +4599,15 @@ impl DeviceManager {
                 None
             };
 
+        let rom = device_cfg
+            .romfile
+            .as_ref()
+            .map(|romfile| {
+                std::fs::read(romfile)
+                    .map_err(|e| DeviceManagerError::VfioRomFile(romfile.clone(), e))
+            })
+            .transpose()?;
+
         let memory_manager = self.memory_manager.clone();
 
         let vfio_pci_device = VfioPciDevice::new(
@@ -3538,6 +4622,7 @@ impl DeviceManager {
             Arc::new(move || memory_manager.lock().unwrap().allocate_memory_slot()),
             vm_migration::snapshot_from_id(self.snapshot.as_ref(), vfio_name.as_str()),
             device_cfg.x_nv_gpudirect_clique,
+            rom,
         )
         .map_err(DeviceManagerError::VfioPciCreate)?;
 
@@ -3790,6 +4875,12 @@ impl DeviceManager {
         let (pci_segment_id, pci_device_bdf, resources) =
             self.pci_resources(&id, pci_segment_id)?;
 
+        if self.device_numa_policy() != DeviceNumaPolicy::Off {
+            if let Some(node) = self.pci_segment_host_numa_node(pci_segment_id) {
+                self.device_numa_nodes.insert(id.clone(), node);
+            }
+        }
+
         // Update the existing virtio node by setting the parent.
         if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
             node.parent = Some(id.clone());
@@ -3922,8 +5013,9 @@ impl DeviceManager {
 
         let snapshot = snapshot_from_id(self.snapshot.as_ref(), id.as_str());
 
-        let pvpanic_device = devices::PvPanicDevice::new(id.clone(), snapshot)
-            .map_err(DeviceManagerError::PvPanicCreate)?;
+        let pvpanic_device =
+            devices::PvPanicDevice::new(id.clone(), self.guest_panicked.clone(), snapshot)
+                .map_err(DeviceManagerError::PvPanicCreate)?;
 
         let pvpanic_device = Arc::new(Mutex::new(pvpanic_device));
 
@@ -3946,12 +5038,319 @@ impl DeviceManager {
         Ok(Some(pvpanic_device))
     }
 
-    fn pci_resources(
-        &self,
-        id: &str,
-        pci_segment_id: u16,
-    ) -> DeviceManagerResult<(u16, PciBdf, Option<Vec<Resource>>)> {
-        // Look for the id in the device tree. If it can be found, that means
+    fn add_e1000e_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut net_devices = self.config.lock().unwrap().net.clone();
+        if let Some(net_list_cfg) = &mut net_devices {
+            for net_cfg in net_list_cfg.iter_mut() {
+                if net_cfg.model == NetModel::E1000e {
+                    self.add_e1000e_device(net_cfg)?;
+                }
+            }
+        }
+        self.config.lock().unwrap().net = net_devices;
+
+        Ok(())
+    }
+
+    fn add_e1000e_device(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &net_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(NET_DEVICE_NAME_PREFIX)?;
+            net_cfg.id = Some(id.clone());
+            id
+        };
+        info!("Creating e1000e device: {:?}", net_cfg);
+
+        let tap = if let Some(fds) = &net_cfg.fds {
+            // Duplicate so that it can survive reboots
+            // SAFETY: FFI call to dup. Trivially safe.
+            let fd = unsafe { libc::dup(fds[0]) };
+            if fd < 0 {
+                return Err(DeviceManagerError::E1000eDuplicateTapFd(
+                    io::Error::last_os_error(),
+                ));
+            }
+            let tap = net_util::Tap::from_tap_fd(fd, 1).map_err(DeviceManagerError::OpenTap)?;
+            if let Some(mtu) = net_cfg.mtu {
+                tap.set_mtu(mtu as i32)
+                    .map_err(DeviceManagerError::OpenTap)?;
+            }
+
+            // SAFETY: 'fds' are valid because TAP devices are created successfully
+            unsafe {
+                self.config.lock().unwrap().add_preserved_fds(fds.clone());
+            }
+
+            tap
+        } else if let Some(bridge) = &net_cfg.bridge {
+            // The TAP interface is only used as a bridge port, hence it
+            // is not given any IP configuration.
+            let mut taps = net_util::open_tap(
+                net_cfg.tap.as_deref(),
+                None,
+                None,
+                &mut net_cfg.host_mac,
+                net_cfg.mtu,
+                1,
+                None,
+            )
+            .map_err(DeviceManagerError::OpenBridgedTap)?;
+
+            let if_name = taps[0].get_if_name();
+            let if_name = String::from_utf8_lossy(&if_name);
+            net_util::bridge_attach(
+                if_name.trim_end_matches(char::from(0)),
+                bridge,
+                net_cfg.vlan,
+            )
+            .map_err(DeviceManagerError::BridgeAttach)?;
+
+            taps.remove(0)
+        } else {
+            let (ipv4_addr, ipv4_mask) = net_ipv4_config(net_cfg);
+            net_util::open_tap(
+                net_cfg.tap.as_deref(),
+                ipv4_addr,
+                ipv4_mask,
+                &mut net_cfg.host_mac,
+                net_cfg.mtu,
+                1,
+                None,
+            )
+            .map_err(DeviceManagerError::E1000eOpenTap)?
+            .remove(0)
+        };
+
+        if let Some(shaping) = &net_cfg.egress_shaping {
+            let if_name = tap.get_if_name();
+            let if_name = String::from_utf8_lossy(&if_name);
+            let installed =
+                net_util::set_egress_shaping(if_name.trim_end_matches(char::from(0)), shaping)
+                    .map_err(DeviceManagerError::EgressShaping)?;
+            self.egress_shapings.insert(id.clone(), installed);
+        }
+
+        if let Some(ipv6) = net_cfg.ipv6 {
+            let if_name = tap.get_if_name();
+            let if_name = String::from_utf8_lossy(&if_name);
+            net_util::set_ipv6_addr(
+                if_name.trim_end_matches(char::from(0)),
+                ipv6,
+                net_cfg.ipv6_prefix_len,
+            )
+            .map_err(DeviceManagerError::SetIpv6Address)?;
+        }
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, net_cfg.pci_segment)?;
+
+        let irq = self.pci_segments[pci_segment_id as usize].pci_irq_slots
+            [pci_device_bdf.device() as usize];
+        let interrupt_group = self
+            .legacy_interrupt_manager
+            .as_ref()
+            .ok_or(DeviceManagerError::AllocateIrq)?
+            .create_group(LegacyIrqGroupConfig {
+                irq: irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        let mut e1000e_device = devices::E1000eDevice::new(
+            id.clone(),
+            tap,
+            net_cfg.mac,
+            self.memory_manager.lock().unwrap().guest_memory(),
+            interrupt_group,
+            irq,
+            snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+        )
+        .map_err(DeviceManagerError::E1000eCreate)?;
+        let seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::E1000e, self.hypervisor_type)
+                .map_err(DeviceManagerError::E1000eSeccompFilter)?;
+        e1000e_device
+            .start_thread(
+                seccomp_filter,
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::E1000eStartThread)?;
+
+        let e1000e_device = Arc::new(Mutex::new(e1000e_device));
+
+        let new_resources = self.add_pci_device(
+            e1000e_device.clone(),
+            e1000e_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut node = device_node!(id, e1000e_device);
+
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    fn add_xhci_device(&mut self) -> DeviceManagerResult<()> {
+        let mut usb_devices = self.config.lock().unwrap().usb.clone();
+        if !self.config.lock().unwrap().xhci && usb_devices.is_none() {
+            return Ok(());
+        }
+
+        let id = String::from(XHCI_DEVICE_NAME);
+        info!("Creating xHCI controller {}", id);
+
+        let (pci_segment_id, pci_device_bdf, resources) = self.pci_resources(&id, 0)?;
+
+        let irq = self.pci_segments[pci_segment_id as usize].pci_irq_slots
+            [pci_device_bdf.device() as usize];
+        let interrupt_group = self
+            .legacy_interrupt_manager
+            .as_ref()
+            .ok_or(DeviceManagerError::AllocateIrq)?
+            .create_group(LegacyIrqGroupConfig {
+                irq: irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        let mut xhci_device = devices::XhciDevice::new(
+            id.clone(),
+            self.memory_manager.lock().unwrap().guest_memory(),
+            interrupt_group,
+            irq,
+            snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+        )
+        .map_err(DeviceManagerError::XhciCreate)?;
+        let seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::Xhci, self.hypervisor_type)
+                .map_err(DeviceManagerError::XhciSeccompFilter)?;
+        xhci_device
+            .start_thread(
+                seccomp_filter,
+                self.exit_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+            )
+            .map_err(DeviceManagerError::XhciStartThread)?;
+
+        let xhci_device = Arc::new(Mutex::new(xhci_device));
+
+        let new_resources = self.add_pci_device(
+            xhci_device.clone(),
+            xhci_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut node = device_node!(id, xhci_device);
+
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+        self.xhci_device = Some(xhci_device);
+
+        if let Some(usb_list_cfg) = &mut usb_devices {
+            for usb_cfg in usb_list_cfg.iter_mut() {
+                self.add_usb_device(usb_cfg)?;
+            }
+        }
+        self.config.lock().unwrap().usb = usb_devices;
+
+        Ok(())
+    }
+
+    fn add_usb_device(&mut self, usb_cfg: &mut UsbConfig) -> DeviceManagerResult<PciBdf> {
+        let xhci_device = self
+            .xhci_device
+            .clone()
+            .ok_or(DeviceManagerError::NoXhciDevice)?;
+
+        let id = if let Some(id) = &usb_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(USB_DEVICE_NAME_PREFIX)?;
+            usb_cfg.id = Some(id.clone());
+            id
+        };
+        info!("Creating host USB device: {:?}", usb_cfg);
+
+        let device =
+            devices::usb::HostUsbDevice::new(&usb_cfg.path).map_err(DeviceManagerError::UsbOpen)?;
+        let port = xhci_device
+            .lock()
+            .unwrap()
+            .attach(Box::new(device))
+            .map_err(DeviceManagerError::UsbAttach)?;
+        self.usb_ports.insert(id.clone(), port);
+
+        // The USB device is reached through the xHCI controller, which
+        // holds its state.
+        let mut device_tree = self.device_tree.lock().unwrap();
+        let xhci_node = device_tree
+            .get_mut(XHCI_DEVICE_NAME)
+            .ok_or(DeviceManagerError::MissingNode)?;
+        xhci_node.children.push(id.clone());
+        let pci_device_bdf = xhci_node
+            .pci_bdf
+            .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
+
+        let mut node = device_node!(id);
+        node.parent = Some(String::from(XHCI_DEVICE_NAME));
+        device_tree.insert(id, node);
+
+        Ok(pci_device_bdf)
+    }
+
+    fn remove_usb_device(&mut self, id: &str) -> DeviceManagerResult<()> {
+        let port = self
+            .usb_ports
+            .remove(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        let xhci_device = self
+            .xhci_device
+            .as_ref()
+            .ok_or(DeviceManagerError::NoXhciDevice)?;
+        xhci_device.lock().unwrap().detach(port);
+
+        let mut device_tree = self.device_tree.lock().unwrap();
+        device_tree.remove(id);
+        let xhci_node = device_tree
+            .get_mut(XHCI_DEVICE_NAME)
+            .ok_or(DeviceManagerError::MissingNode)?;
+        xhci_node.children.retain(|child| child != id);
+        let pci_device_bdf = xhci_node
+            .pci_bdf
+            .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
+
+        event!(
+            "vm",
+            "device-removed",
+            "id",
+            id,
+            "bdf",
+            pci_device_bdf.to_string()
+        );
+
+        Ok(())
+    }
+
+    fn pci_resources(
+        &mut self,
+        id: &str,
+        pci_segment_id: u16,
+    ) -> DeviceManagerResult<(u16, PciBdf, Option<Vec<Resource>>)> {
+        // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
         Ok(
             if let Some(node) = self.device_tree.lock().unwrap().get(id) {
@@ -3969,6 +5368,18 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::GetPciDeviceId)?;
 
                 (pci_segment_id, pci_device_bdf, Some(node.resources.clone()))
+            } else if let Some(pci_device_bdf) = self.replacement_pci_bdf.take() {
+                // Reuse the slot of the device being replaced.
+                let pci_segment_id = pci_device_bdf.segment();
+
+                self.pci_segments[pci_segment_id as usize]
+                    .pci_bus
+                    .lock()
+                    .unwrap()
+                    .get_device_id(pci_device_bdf.device() as usize)
+                    .map_err(DeviceManagerError::GetPciDeviceId)?;
+
+                (pci_segment_id, pci_device_bdf, None)
             } else {
                 let pci_device_bdf =
                     self.pci_segments[pci_segment_id as usize].next_device_bdf()?;
@@ -4005,6 +5416,10 @@ impl DeviceManager {
         &self.console
     }
 
+    pub fn tpm_device(&self) -> Option<&Arc<Mutex<devices::tpm::Tpm>>> {
+        self.tpm_device.as_ref()
+    }
+
     #[cfg(target_arch = "aarch64")]
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
@@ -4063,14 +5478,72 @@ impl DeviceManager {
     }
 
     pub fn activate_virtio_devices(&self) -> DeviceManagerResult<()> {
+        let mode = match self.device_numa_policy() {
+            DeviceNumaPolicy::Off => None,
+            DeviceNumaPolicy::Preferred => Some(MPOL_PREFERRED),
+            DeviceNumaPolicy::Bind => Some(MPOL_BIND),
+        };
+
         for mut activator in self.pending_activations.lock().unwrap().drain(..) {
-            activator
+            // The threads spawned by the device on activation inherit the
+            // memory policy, which covers the allocations made afterwards.
+            let numa_node = self.device_numa_nodes.get(activator.id()).copied();
+            if let (Some(mode), Some(node)) = (mode, numa_node) {
+                set_mempolicy(mode, Some(node)).map_err(DeviceManagerError::SetDeviceNumaPolicy)?;
+            }
+
+            let res = activator
                 .activate()
-                .map_err(DeviceManagerError::VirtioActivate)?;
+                .map_err(DeviceManagerError::VirtioActivate);
+
+            if mode.is_some() && numa_node.is_some() {
+                set_mempolicy(MPOL_DEFAULT, None)
+                    .map_err(DeviceManagerError::SetDeviceNumaPolicy)?;
+            }
+            res?;
         }
         Ok(())
     }
 
+    fn device_numa_policy(&self) -> DeviceNumaPolicy {
+        self.config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map(|platform| platform.device_numa_policy)
+            .unwrap_or_default()
+    }
+
+    // Finds the host NUMA node backing the guest NUMA node the PCI segment
+    // belongs to, from the memory zones of this guest NUMA node.
+    fn pci_segment_host_numa_node(&self, pci_segment_id: u16) -> Option<u32> {
+        let config = self.config.lock().unwrap();
+        let numa_nodes = config.numa.as_ref()?;
+        // PCI segments not assigned to a NUMA node belong to NUMA node 0.
+        let numa_node = numa_nodes
+            .iter()
+            .find(|numa_node| {
+                numa_node
+                    .pci_segments
+                    .as_ref()
+                    .is_some_and(|pci_segments| pci_segments.contains(&pci_segment_id))
+            })
+            .or_else(|| {
+                numa_nodes
+                    .iter()
+                    .find(|numa_node| numa_node.guest_numa_id == 0)
+            })?;
+        let zones = config.memory.zones.as_ref()?;
+
+        numa_node.memory_zones.as_ref()?.iter().find_map(|zone_id| {
+            zones
+                .iter()
+                .find(|zone| &zone.id == zone_id)?
+                .host_numa_node
+        })
+    }
+
     pub fn notify_hotplug(
         &self,
         _notification_type: AcpiNotificationFlags,
@@ -4124,14 +5597,75 @@ impl DeviceManager {
     }
 
     pub fn remove_device(&mut self, id: String) -> DeviceManagerResult<()> {
+        // USB devices are unplugged from their port right away, without
+        // going through the ejection by the guest.
+        if self.usb_ports.contains_key(&id) {
+            return self.remove_usb_device(&id);
+        }
+
+        self.unplug_device(id)?;
+        Ok(())
+    }
+
+    pub fn replace_device(
+        &mut self,
+        id: String,
+        replacement: &mut ReplacementDeviceConfig,
+    ) -> DeviceManagerResult<PciBdf> {
+        // The replacement inherits the identifier of the device it replaces
+        // unless a new one is provided.
+        let replacement_id = replacement.id_mut();
+        if replacement_id.as_ref() != Some(&id) {
+            if replacement_id.is_some() {
+                self.validate_identifier(replacement_id)?;
+            } else {
+                *replacement_id = Some(id.clone());
+            }
+        }
+
+        let pci_device_bdf = self.pci_device_bdf_for_removal(&id)?;
+        if self.pending_replacements.contains_key(&pci_device_bdf) {
+            return Err(DeviceManagerError::ReplacementAlreadyPending(id));
+        }
+
+        *replacement.pci_segment_mut() = pci_device_bdf.segment();
+        let iommu = match replacement {
+            ReplacementDeviceConfig::Disk(cfg) => cfg.iommu,
+            ReplacementDeviceConfig::Net(cfg) => cfg.iommu,
+            ReplacementDeviceConfig::Pmem(cfg) => cfg.iommu,
+            ReplacementDeviceConfig::Fs(_) => false,
+        };
+        if iommu && !self.is_iommu_segment(pci_device_bdf.segment()) {
+            return Err(DeviceManagerError::InvalidIommuHotplug);
+        }
+        check_replacement_backend(replacement)?;
+
+        self.unplug_device(id)?;
+        self.pending_replacements
+            .insert(pci_device_bdf, replacement.clone());
+
+        Ok(pci_device_bdf)
+    }
+
+    fn unplug_device(&mut self, id: String) -> DeviceManagerResult<PciBdf> {
+        let pci_device_bdf = self.pci_device_bdf_for_removal(&id)?;
+
+        // Update the PCID bitmap
+        self.pci_segments[pci_device_bdf.segment() as usize].pci_devices_down |=
+            1 << pci_device_bdf.device();
+
+        Ok(pci_device_bdf)
+    }
+
+    fn pci_device_bdf_for_removal(&self, id: &str) -> DeviceManagerResult<PciBdf> {
         // The node can be directly a PCI node in case the 'id' refers to a
         // VFIO device or a virtio-pci one.
         // In case the 'id' refers to a virtio device, we must find the PCI
         // node by looking at the parent.
         let device_tree = self.device_tree.lock().unwrap();
         let node = device_tree
-            .get(&id)
-            .ok_or(DeviceManagerError::UnknownDeviceId(id))?;
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
 
         let pci_device_node = if node.pci_bdf.is_some() && node.pci_device_handle.is_some() {
             node
@@ -4148,7 +5682,6 @@ impl DeviceManager {
         let pci_device_bdf: PciBdf = pci_device_node
             .pci_bdf
             .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
-        let pci_segment_id = pci_device_bdf.segment();
 
         let pci_device_handle = pci_device_node
             .pci_device_handle
@@ -4165,20 +5698,12 @@ impl DeviceManager {
                     .unwrap()
                     .device_type(),
             );
-            match device_type {
-                VirtioDeviceType::Net
-                | VirtioDeviceType::Block
-                | VirtioDeviceType::Pmem
-                | VirtioDeviceType::Fs
-                | VirtioDeviceType::Vsock => {}
-                _ => return Err(DeviceManagerError::RemovalNotAllowed(device_type)),
+            if !virtio_device_removable(device_type) {
+                return Err(DeviceManagerError::RemovalNotAllowed(device_type));
             }
         }
 
-        // Update the PCID bitmap
-        self.pci_segments[pci_segment_id as usize].pci_devices_down |= 1 << pci_device_bdf.device();
-
-        Ok(())
+        Ok(pci_device_bdf)
     }
 
     pub fn eject_device(&mut self, pci_segment_id: u16, device_id: u8) -> DeviceManagerResult<()> {
@@ -4204,6 +5729,8 @@ impl DeviceManager {
             .remove_node_by_pci_bdf(pci_device_bdf)
             .ok_or(DeviceManagerError::MissingPciDevice)?;
 
+        self.device_numa_nodes.remove(&pci_device_node.id);
+
         // For VFIO and vfio-user the PCI device id is the id.
         // For virtio we overwrite it later as we want the id of the
         // underlying device.
@@ -4222,6 +5749,7 @@ impl DeviceManager {
         for child in pci_device_node.children.iter() {
             device_tree.remove(child);
         }
+        self.egress_shapings.remove(&id);
 
         let mut iommu_attached = false;
         if let Some((_, iommu_attached_devices)) = &self.iommu_attached_devices {
@@ -4372,6 +5900,9 @@ impl DeviceManager {
                 .retain(|handler| !Arc::ptr_eq(&handler.virtio_device, &virtio_device));
         }
 
+        // Scrub the data the guest wrote to an ephemeral disk
+        self.ephemeral_disks.remove(&id);
+
         event!(
             "vm",
             "device-removed",
@@ -4381,6 +5912,13 @@ impl DeviceManager {
             pci_device_bdf.to_string()
         );
 
+        // Now that the slot is free, plug the device queued to replace the
+        // one which has just been ejected.
+        drop(device_tree);
+        if let Some(replacement) = self.pending_replacements.remove(&pci_device_bdf) {
+            self.plug_replacement(pci_device_bdf, replacement);
+        }
+
         // At this point, the device has been removed from all the list and
         // buses where it was stored. At the end of this function, after
         // any_device, bus_device and pci_device are released, the actual
@@ -4388,6 +5926,42 @@ impl DeviceManager {
         Ok(())
     }
 
+    fn plug_replacement(
+        &mut self,
+        pci_device_bdf: PciBdf,
+        mut replacement: ReplacementDeviceConfig,
+    ) {
+        let id = replacement.id().cloned().unwrap_or_default();
+
+        self.replacement_pci_bdf = Some(pci_device_bdf);
+        let result = match &mut replacement {
+            ReplacementDeviceConfig::Disk(cfg) => self.make_virtio_block_device(cfg),
+            ReplacementDeviceConfig::Fs(cfg) => self.make_virtio_fs_device(cfg),
+            ReplacementDeviceConfig::Net(cfg) => self.make_virtio_net_device(cfg),
+            ReplacementDeviceConfig::Pmem(cfg) => self.make_virtio_pmem_device(cfg),
+        }
+        .and_then(|device| self.hotplug_virtio_pci_device(device))
+        .and_then(|_| self.notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED));
+        self.replacement_pci_bdf = None;
+
+        match result {
+            Ok(()) => event!(
+                "vm",
+                "device-replaced",
+                "id",
+                &id,
+                "bdf",
+                pci_device_bdf.to_string()
+            ),
+            Err(e) => {
+                error!("Failed plugging replacement device {}: {:?}", id, e);
+                // The replacement could not be created, make sure it won't
+                // be created in case of a reboot either.
+                self.config.lock().unwrap().remove_device(&id);
+            }
+        }
+    }
+
     fn hotplug_virtio_pci_device(
         &mut self,
         handle: MetaVirtioDevice,
@@ -4465,6 +6039,10 @@ impl DeviceManager {
     pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&net_cfg.id)?;
 
+        if net_cfg.model != NetModel::Virtio {
+            return Err(DeviceManagerError::E1000eHotplugNotSupported);
+        }
+
         if net_cfg.iommu && !self.is_iommu_segment(net_cfg.pci_segment) {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
@@ -4484,6 +6062,17 @@ impl DeviceManager {
         self.hotplug_virtio_pci_device(device)
     }
 
+    pub fn add_usb(&mut self, usb_cfg: &mut UsbConfig) -> DeviceManagerResult<PciDeviceInfo> {
+        self.validate_identifier(&usb_cfg.id)?;
+
+        let bdf = self.add_usb_device(usb_cfg)?;
+
+        Ok(PciDeviceInfo {
+            id: usb_cfg.id.clone().unwrap_or_default(),
+            bdf,
+        })
+    }
+
     pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<PciDeviceInfo> {
         self.validate_identifier(&vsock_cfg.id)?;
 
@@ -4503,11 +6092,206 @@ impl DeviceManager {
             if let Some(device_counters) = virtio_device.counters() {
                 counters.insert(handle.id.clone(), device_counters.clone());
             }
+            if let Some(queue_counters) = virtio_device.queue_counters() {
+                for (index, queue_counters) in queue_counters.into_iter().enumerate() {
+                    counters.insert(format!("{}/queue{}", handle.id, index), queue_counters);
+                }
+            }
         }
 
         counters
     }
 
+    /// Forwards the bytes written by the guest to the serial port to
+    /// `observer`, returning false if there is no serial port.
+    pub fn set_serial_output_observer(&self, observer: OutputObserver) -> bool {
+        let Some(serial) = &self.serial else {
+            return false;
+        };
+        serial.lock().unwrap().set_output_observer(Some(observer));
+        true
+    }
+
+    /// Sends the magic SysRq `key` to the guest over the serial port, as a
+    /// BREAK followed by the key.
+    pub fn send_sysrq(&self, key: u8) -> DeviceManagerResult<()> {
+        let serial = self
+            .serial
+            .as_ref()
+            .ok_or(DeviceManagerError::NoSerialDevice)?;
+        let mut serial = serial.lock().unwrap();
+        serial
+            .queue_break()
+            .map_err(DeviceManagerError::SerialInput)?;
+        serial
+            .queue_input_bytes(&[key])
+            .map_err(DeviceManagerError::SerialInput)
+    }
+
+    /// Captures the ramfb framebuffer as a PNG image.
+    pub fn ramfb_screenshot(&self) -> DeviceManagerResult<Vec<u8>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(ramfb) = &self.ramfb {
+            return ramfb
+                .lock()
+                .unwrap()
+                .screenshot()
+                .map_err(DeviceManagerError::RamfbScreenshot);
+        }
+
+        Err(DeviceManagerError::NoRamfbDevice)
+    }
+
+    /// Why a device signalled the exit or reset event, if it did. A guest
+    /// panic takes precedence, the guest resetting or powering off
+    /// afterwards being a consequence of it.
+    pub fn take_shutdown_reason(&self) -> Option<ShutdownReason> {
+        if self.guest_panicked.swap(false, Ordering::SeqCst) {
+            self.watchdog_triggered.store(false, Ordering::SeqCst);
+            self.acpi_poweroff_signalled.store(false, Ordering::SeqCst);
+            Some(ShutdownReason::GuestPanic)
+        } else if self.watchdog_triggered.swap(false, Ordering::SeqCst) {
+            Some(ShutdownReason::Watchdog)
+        } else if self.acpi_poweroff_signalled.swap(false, Ordering::SeqCst) {
+            Some(ShutdownReason::GuestPoweroff)
+        } else {
+            None
+        }
+    }
+
+    pub fn pci_segments_capabilities(&self) -> Vec<PciSegmentCapabilities> {
+        self.pci_segments
+            .iter()
+            .map(|pci_segment| PciSegmentCapabilities {
+                id: pci_segment.id,
+                free_slots: pci_segment.pci_bus.lock().unwrap().free_device_ids(),
+            })
+            .collect()
+    }
+
+    /// Lists the devices `remove_device()` accepts, sorted by PCI address.
+    /// Name of the TAP interface backing the virtio-net device `id`, the
+    /// interface being created by the VMM unless given in the configuration.
+    pub fn net_tap_if_name(&self, id: &str) -> Option<String> {
+        self.virtio_net_devices
+            .get(id)
+            .and_then(Weak::upgrade)
+            .map(|net| net.lock().unwrap().tap_if_name())
+    }
+
+    pub fn removable_devices(&self) -> Vec<RemovableDevice> {
+        let device_tree = self.device_tree.lock().unwrap();
+        let mut devices: Vec<RemovableDevice> = device_tree
+            .pci_devices()
+            .into_iter()
+            .filter_map(|node| {
+                let pci_bdf = node.pci_bdf?;
+                let (id, device_type) = match node.pci_device_handle.as_ref()? {
+                    PciDeviceHandle::Vfio(_) => (node.id.clone(), "vfio".to_string()),
+                    PciDeviceHandle::VfioUser(_) => (node.id.clone(), "vfio-user".to_string()),
+                    PciDeviceHandle::Virtio(virtio_pci_device) => {
+                        let device_type = VirtioDeviceType::from(
+                            virtio_pci_device
+                                .lock()
+                                .unwrap()
+                                .virtio_device()
+                                .lock()
+                                .unwrap()
+                                .device_type(),
+                        );
+                        if !virtio_device_removable(device_type) {
+                            return None;
+                        }
+                        // The virtio device is identified by its own node,
+                        // the only child of the node of its transport
+                        let id = match node.children.as_slice() {
+                            [virtio_device_id] => virtio_device_id.clone(),
+                            _ => node.id.clone(),
+                        };
+                        (id, device_type.to_string())
+                    }
+                };
+                Some(RemovableDevice {
+                    id,
+                    device_type,
+                    pci_segment: pci_bdf.segment(),
+                    pci_bdf,
+                })
+            })
+            .collect();
+        // The USB devices are seen behind their controller.
+        if let Some(pci_bdf) = device_tree
+            .get(XHCI_DEVICE_NAME)
+            .and_then(|node| node.pci_bdf)
+        {
+            devices.extend(self.usb_ports.keys().map(|id| RemovableDevice {
+                id: id.clone(),
+                device_type: "usb".to_string(),
+                pci_segment: pci_bdf.segment(),
+                pci_bdf,
+            }));
+        }
+        devices.sort_by_key(|device| u32::from(device.pci_bdf));
+        devices
+    }
+
+    pub fn migration_blockers(&self) -> MigrationBlockers {
+        let mut blockers = MigrationBlockers::default();
+
+        for handle in &self.virtio_devices {
+            let virtio_device = handle.virtio_device.lock().unwrap();
+            if let Some(reason) = virtio_device.migration_blocker() {
+                blockers
+                    .migration
+                    .push(MigrationBlocker::new(&handle.id, &reason));
+            }
+            if let Some(reason) = virtio_device.snapshot_blocker() {
+                blockers
+                    .snapshot
+                    .push(MigrationBlocker::new(&handle.id, &reason));
+            }
+        }
+
+        // The state of passthrough devices lives in the hardware, or in the
+        // vfio-user server, out of reach of the VMM.
+        for (id, node) in self.device_tree.lock().unwrap().iter() {
+            let reason = match node.pci_device_handle {
+                Some(PciDeviceHandle::Vfio(_)) => "VFIO device state can't be saved",
+                Some(PciDeviceHandle::VfioUser(_)) => "vfio-user device state can't be saved",
+                _ => continue,
+            };
+            blockers.migration.push(MigrationBlocker::new(id, reason));
+            blockers.snapshot.push(MigrationBlocker::new(id, reason));
+        }
+        for id in self.usb_ports.keys() {
+            let reason = "Host USB device state can't be saved";
+            blockers.migration.push(MigrationBlocker::new(id, reason));
+            blockers.snapshot.push(MigrationBlocker::new(id, reason));
+        }
+        // The overlay is local to the VMM and scrubbed along with the VM,
+        // hence the restored disk missing whatever the guest wrote.
+        for id in self.ephemeral_disks.keys() {
+            let reason = "Ephemeral disk content isn't saved";
+            blockers.migration.push(MigrationBlocker::new(id, reason));
+            blockers.snapshot.push(MigrationBlocker::new(id, reason));
+        }
+        // The file a disk was handed over as can't be found again by the
+        // VMM restoring the VM, or receiving it.
+        for disk in self.config.lock().unwrap().disks.iter().flatten() {
+            if disk.fd.is_none() {
+                continue;
+            }
+            let Some(id) = &disk.id else {
+                continue;
+            };
+            let reason = "Disk backed by a file descriptor can't be reopened";
+            blockers.migration.push(MigrationBlocker::new(id, reason));
+            blockers.snapshot.push(MigrationBlocker::new(id, reason));
+        }
+
+        blockers
+    }
+
     pub fn resize_balloon(&mut self, size: u64) -> DeviceManagerResult<()> {
         if let Some(balloon) = &self.balloon {
             return balloon
@@ -4533,6 +6317,110 @@ impl DeviceManager {
         self.device_tree.clone()
     }
 
+    /// Applies a change of the VM configuration to the running devices.
+    pub fn update_config(&self, change: &LiveConfigChange) -> DeviceManagerResult<()> {
+        match change {
+            LiveConfigChange::RngSource(path) => self
+                .rng
+                .as_ref()
+                .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(RNG_DEVICE_NAME.to_string()))?
+                .lock()
+                .unwrap()
+                .set_source(&path.to_string_lossy())
+                .map_err(DeviceManagerError::UpdateRngSource),
+            LiveConfigChange::ConsoleFile(path) => {
+                let fd = self
+                    .console_output_fd
+                    .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate("console".to_string()))?;
+                let file = File::create(path).map_err(DeviceManagerError::ConsoleOutputFileOpen)?;
+                replace_output_file(fd, &file)
+            }
+            LiveConfigChange::SerialFile(path) => {
+                let fd = self
+                    .serial_output_fd
+                    .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate("serial".to_string()))?;
+                let file = File::create(path).map_err(DeviceManagerError::SerialOutputFileOpen)?;
+                replace_output_file(fd, &file)
+            }
+            LiveConfigChange::DiskRateLimiter { id, config } => {
+                let rate_limit_group = self
+                    .disk_rate_limit_groups
+                    .get(id)
+                    .and_then(Weak::upgrade)
+                    .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(id.clone()))?;
+                let (bytes, ops) = config.bucket_updates();
+                rate_limit_group.update_buckets(bytes, ops);
+                Ok(())
+            }
+            LiveConfigChange::RateLimitGroup { id, config } => {
+                let rate_limit_group = self
+                    .rate_limit_groups
+                    .get(id)
+                    .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(id.clone()))?;
+                let (bytes, ops) = config.bucket_updates();
+                rate_limit_group.update_buckets(bytes, ops);
+                Ok(())
+            }
+        }
+    }
+
+    /// Gives the devices of a ready or restored VM the identity of the
+    /// instance, `config` being the configuration of the VM with the identity
+    /// set. The disks can only be switched before the devices are activated.
+    pub fn set_identity(
+        &mut self,
+        identity: &VmIdentityConfig,
+        config: &VmConfig,
+    ) -> DeviceManagerResult<()> {
+        // The data of another instance is never picked up.
+        if let Some(disk) = identity.disks.iter().find(|disk| disk.overlay.exists()) {
+            return Err(DeviceManagerError::DiskOverlayExists(disk.overlay.clone()));
+        }
+
+        for net in &identity.net {
+            self.virtio_net_devices
+                .get(&net.id)
+                .and_then(Weak::upgrade)
+                .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(net.id.clone()))?
+                .lock()
+                .unwrap()
+                .set_mac(net.mac);
+        }
+
+        for disk in &identity.disks {
+            let device = self
+                .virtio_block_devices
+                .get(&disk.id)
+                .and_then(Weak::upgrade)
+                .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(disk.id.clone()))?;
+            let disk_cfg = config
+                .disks
+                .iter()
+                .flatten()
+                .find(|disk_cfg| disk_cfg.id.as_ref() == Some(&disk.id))
+                .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate(disk.id.clone()))?;
+            let (image, disk_path) = self.open_disk_image(&disk.id, disk_cfg)?;
+            device
+                .lock()
+                .unwrap()
+                .set_disk_image(image, disk_path)
+                .map_err(DeviceManagerError::SetIdentity)?;
+        }
+
+        if let Some(cid) = identity.vsock_cid {
+            self.virtio_vsock_device
+                .as_ref()
+                .and_then(Weak::upgrade)
+                .ok_or_else(|| DeviceManagerError::NoDeviceToUpdate("vsock".to_string()))?
+                .lock()
+                .unwrap()
+                .set_cid(cid)
+                .map_err(DeviceManagerError::SetIdentity)?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn notify_power_button(&self) -> DeviceManagerResult<()> {
         self.ged_notification_device
@@ -4569,6 +6457,33 @@ impl DeviceManager {
             .map_err(DeviceManagerError::PowerButtonNotification);
     }
 
+    pub fn notify_sleep_button(&self) -> DeviceManagerResult<()> {
+        self.ged_notification_device
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .notify(AcpiNotificationFlags::SLEEP_BUTTON_CHANGED)
+            .map_err(DeviceManagerError::SleepButtonNotification)
+    }
+
+    /// Gives the VM a new generation ID, notifying the guest for it to read
+    /// it. Nothing is done if the VM has no VM generation ID device.
+    pub fn regenerate_vmgenid(&self) -> DeviceManagerResult<()> {
+        let Some(vmgenid_device) = &self.vmgenid_device else {
+            return Ok(());
+        };
+        vmgenid_device.lock().unwrap().regenerate();
+
+        self.ged_notification_device
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .notify(AcpiNotificationFlags::VMGENID_CHANGED)
+            .map_err(DeviceManagerError::VmGenIdNotification)
+    }
+
     pub fn iommu_attached_devices(&self) -> &Option<(PciBdf, Vec<PciBdf>)> {
         &self.iommu_attached_devices
     }
@@ -4590,6 +6505,22 @@ impl DeviceManager {
     pub(crate) fn acpi_platform_addresses(&self) -> &AcpiPlatformAddresses {
         &self.acpi_platform_addresses
     }
+
+    pub(crate) fn legacy_device_enabled(&self, device: LegacyDevice) -> bool {
+        self.config.lock().unwrap().legacy_device_enabled(device)
+    }
+}
+
+// Points `fd` to `file`, the device holding `fd` writing to `file` from then on.
+fn replace_output_file(fd: RawFd, file: &File) -> DeviceManagerResult<()> {
+    // SAFETY: FFI call on valid descriptors
+    if unsafe { libc::dup3(file.as_raw_fd(), fd, libc::O_CLOEXEC) } < 0 {
+        return Err(DeviceManagerError::ReplaceOutputFile(
+            io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(())
 }
 
 fn numa_node_id_from_memory_zone_id(numa_nodes: &NumaNodes, memory_zone_id: &str) -> Option<u32> {
@@ -4612,6 +6543,19 @@ fn numa_node_id_from_pci_segment_id(numa_nodes: &NumaNodes, pci_segment_id: u16)
     0
 }
 
+// IPv4 address and netmask of the TAP interface, an unspecified address
+// meaning the interface is IPv6 only.
+fn net_ipv4_config(net_cfg: &NetConfig) -> (Option<Ipv4Addr>, Option<Ipv4Addr>) {
+    let ip = net_cfg
+        .ip
+        .unwrap_or_else(|| default_netconfig_ip(net_cfg.ipv6));
+    if ip.is_unspecified() {
+        (None, None)
+    } else {
+        (Some(ip), Some(net_cfg.mask))
+    }
+}
+
 struct TpmDevice {}
 
 impl Aml for TpmDevice {
@@ -4741,21 +6685,25 @@ impl Aml for DeviceManager {
         .to_aml_bytes(sink);
 
         // Serial device
+        let serial_enabled = {
+            let config = self.config.lock().unwrap();
+            config.serial.mode != ConsoleOutputMode::Off
+                && config.legacy_device_enabled(LegacyDevice::Serial)
+        };
         #[cfg(target_arch = "x86_64")]
         let serial_irq = 4;
         #[cfg(target_arch = "aarch64")]
-        let serial_irq =
-            if self.config.lock().unwrap().serial.clone().mode != ConsoleOutputMode::Off {
-                self.get_device_info()
-                    .clone()
-                    .get(&(DeviceType::Serial, DeviceType::Serial.to_string()))
-                    .unwrap()
-                    .irq()
-            } else {
-                // If serial is turned off, add a fake device with invalid irq.
-                31
-            };
-        if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
+        let serial_irq = if serial_enabled {
+            self.get_device_info()
+                .clone()
+                .get(&(DeviceType::Serial, DeviceType::Serial.to_string()))
+                .unwrap()
+                .irq()
+        } else {
+            // If serial is turned off, add a fake device with invalid irq.
+            31
+        };
+        if serial_enabled {
             aml::Device::new(
                 "_SB_.COM1".into(),
                 vec![
@@ -4787,6 +6735,9 @@ impl Aml for DeviceManager {
             .to_aml_bytes(sink);
         }
 
+        // There is no S3 sleep state, the VMM not taking care of resuming the
+        // vCPUs through the waking vector. The guest can only suspend to idle
+        // when the sleep button is pressed.
         aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes(sink);
 
         aml::Device::new(
@@ -4798,11 +6749,24 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes(sink);
 
+        aml::Device::new(
+            "_SB_.SLPB".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0E")),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+            ],
+        )
+        .to_aml_bytes(sink);
+
         if self.config.lock().unwrap().tpm.is_some() {
             // Add tpm device
             TpmDevice {}.to_aml_bytes(sink);
         }
 
+        if let Some(vmgenid_device) = &self.vmgenid_device {
+            vmgenid_device.lock().unwrap().to_aml_bytes(sink);
+        }
+
         self.ged_notification_device
             .as_ref()
             .unwrap()
@@ -5089,4 +7053,57 @@ mod tests {
             vm_memory::GuestAddress(0x3fffff)
         );
     }
+
+    #[test]
+    fn test_check_mmio_aperture_size() {
+        assert!(check_mmio_aperture_size(64 << 30, &[1, 1], 4 << 30).is_ok());
+        assert!(matches!(
+            check_mmio_aperture_size(4 << 30, &[1, 1], 4 << 30),
+            Err(DeviceManagerError::MmioApertureTooSmall(_))
+        ));
+        assert!(check_mmio_aperture_size(8 << 10, &[1], 4 << 10).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_mmio32_aperture_start() {
+        let end = layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE;
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 1 << 30)]).unwrap();
+
+        assert_eq!(
+            mmio32_aperture_start(layout::MEM_32BIT_DEVICES_SIZE, &guest_memory).unwrap(),
+            layout::MEM_32BIT_DEVICES_START.0
+        );
+        assert_eq!(
+            mmio32_aperture_start(end - (1 << 30), &guest_memory).unwrap(),
+            1 << 30
+        );
+        assert!(matches!(
+            mmio32_aperture_start(end - (1 << 30) + (4 << 10), &guest_memory),
+            Err(DeviceManagerError::Mmio32ApertureOverlapsRam(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_disk_overlay_base() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let base = dir.as_path().join("base.raw");
+        let other = dir.as_path().join("other.raw");
+        let overlay = dir.as_path().join("overlay.qcow2");
+        for image in [&base, &other] {
+            File::create(image).unwrap().set_len(1 << 20).unwrap();
+        }
+        qcow::QcowFile::create_overlay(&overlay, &base.to_string_lossy()).unwrap();
+
+        check_disk_overlay_base(&overlay, &base).unwrap();
+        assert!(matches!(
+            check_disk_overlay_base(&overlay, &other),
+            Err(DeviceManagerError::DiskOverlayBaseMismatch(_, Some(_)))
+        ));
+        // A RAW image can't be the overlay of the base
+        assert!(matches!(
+            check_disk_overlay_base(&other, &base),
+            Err(DeviceManagerError::ReadDiskOverlay(_))
+        ));
+    }
 }
@@ -50,6 +50,16 @@ macro_rules! device_node {
     };
 }
 
+/// Address a PCI device is seen at by the guest.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PciDeviceAddress {
+    /// Identifier of the device, as given in the VM configuration
+    pub id: String,
+    pub pci_segment: u16,
+    pub pci_bdf: PciBdf,
+    pub resources: Vec<Resource>,
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct DeviceTree(HashMap<String, DeviceNode>);
 
@@ -85,6 +95,31 @@ impl DeviceTree {
             .collect()
     }
 
+    /// Lists the addresses of the PCI devices, sorted by address. A virtio
+    /// device is identified by its own node, the only child of the node of
+    /// its virtio-pci transport which carries the PCI address.
+    pub fn pci_device_addresses(&self) -> Vec<PciDeviceAddress> {
+        let mut addresses: Vec<PciDeviceAddress> = self
+            .0
+            .values()
+            .filter_map(|node| {
+                let pci_bdf = node.pci_bdf?;
+                let id = match node.children.as_slice() {
+                    [virtio_device_id] => virtio_device_id.clone(),
+                    _ => node.id.clone(),
+                };
+                Some(PciDeviceAddress {
+                    id,
+                    pci_segment: pci_bdf.segment(),
+                    pci_bdf,
+                    resources: node.resources.clone(),
+                })
+            })
+            .collect();
+        addresses.sort_by_key(|address| u32::from(address.pci_bdf));
+        addresses
+    }
+
     pub fn remove_node_by_pci_bdf(&mut self, pci_bdf: PciBdf) -> Option<DeviceNode> {
         let mut id = None;
         for (k, v) in self.0.iter() {
@@ -152,6 +187,7 @@ impl<'a> DoubleEndedIterator for BftIter<'a> {
 #[cfg(test)]
 mod tests {
     use super::{DeviceNode, DeviceTree};
+    use pci::PciBdf;
 
     #[test]
     fn test_device_tree() {
@@ -268,4 +304,36 @@ mod tests {
         assert_eq!(iter_vec[1].id, child_2_id);
         assert_eq!(iter_vec[0].id, child_3_id);
     }
+
+    #[test]
+    fn test_pci_device_addresses() {
+        let mut device_tree = DeviceTree::new();
+        let disk_id = String::from("disk0");
+        let virtio_pci_id = String::from("_virtio-pci-disk0");
+        let vfio_id = String::from("vfio0");
+        let serial_id = String::from("serial");
+
+        let mut disk_node = device_node!(disk_id);
+        disk_node.parent = Some(virtio_pci_id.clone());
+        let mut virtio_pci_node = device_node!(virtio_pci_id);
+        virtio_pci_node.children = vec![disk_id.clone()];
+        virtio_pci_node.pci_bdf = Some(PciBdf::new(1, 0, 2, 0));
+        let mut vfio_node = device_node!(vfio_id);
+        vfio_node.pci_bdf = Some(PciBdf::new(0, 0, 4, 0));
+        device_tree.0.extend(vec![
+            (disk_id.clone(), disk_node),
+            (virtio_pci_id.clone(), virtio_pci_node),
+            (vfio_id.clone(), vfio_node),
+            (serial_id.clone(), device_node!(serial_id)),
+        ]);
+
+        let addresses = device_tree.pci_device_addresses();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].id, vfio_id);
+        assert_eq!(addresses[0].pci_segment, 0);
+        assert_eq!(addresses[0].pci_bdf.to_string(), "0000:00:04.0");
+        assert_eq!(addresses[1].id, disk_id);
+        assert_eq!(addresses[1].pci_segment, 1);
+        assert_eq!(addresses[1].pci_bdf.to_string(), "0001:00:02.0");
+    }
 }
@@ -0,0 +1,167 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Inventory of the file descriptors of the VMM process, telling whether the
+//! process can be checkpointed by CRIU.
+//!
+//! CRIU knows how to save and re-establish regular files, pipes, sockets and
+//! most anonymous inode descriptors such as the eventfds, but not the state
+//! held by the kernel behind the hypervisor, VFIO and vhost descriptors, nor
+//! the eventfds registered as irqfds and ioeventfds with the hypervisor. The
+//! VM is snapshotted and deleted before checkpointing the process, and
+//! restored from the snapshot once the process is restored, its devices
+//! registering new eventfds with the hypervisor. The hypervisor device stays
+//! open for the lifetime of the process, and must be handed to CRIU as an
+//! external file.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FdKind {
+    /// Hypervisor device, holding no state of its own
+    HypervisorDevice,
+    /// Hypervisor VM or vCPU
+    Hypervisor,
+    /// VFIO container, group or device, or IOMMU
+    Vfio,
+    /// vhost kernel backend
+    Vhost,
+    /// TAP interface
+    Tap,
+    EventFd,
+    Socket,
+    Pipe,
+    /// Other anonymous inode, e.g. epoll or timerfd
+    AnonInode,
+    File,
+    Other,
+}
+
+impl FdKind {
+    /// Whether CRIU can checkpoint and restore the descriptor.
+    pub fn checkpointable(self) -> bool {
+        !matches!(self, FdKind::Hypervisor | FdKind::Vfio | FdKind::Vhost)
+    }
+}
+
+/// VM snapshotted and deleted for the VMM process to be checkpointed, and
+/// restored once the process is restored.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub source_url: String,
+    /// Whether the VM was running, and is resumed once restored
+    pub resume: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FdInfo {
+    pub fd: i32,
+    pub kind: FdKind,
+    /// Target of the /proc/self/fd link
+    pub target: String,
+    pub checkpointable: bool,
+}
+
+fn fd_kind(target: &str) -> FdKind {
+    if let Some(inode) = target.strip_prefix("anon_inode:") {
+        let inode = inode.trim_start_matches('[').trim_end_matches(']');
+        if inode.starts_with("kvm-") || inode.starts_with("mshv") {
+            FdKind::Hypervisor
+        } else if inode.starts_with("vfio") {
+            FdKind::Vfio
+        } else if inode == "eventfd" {
+            FdKind::EventFd
+        } else {
+            FdKind::AnonInode
+        }
+    } else if target.starts_with("socket:") {
+        FdKind::Socket
+    } else if target.starts_with("pipe:") {
+        FdKind::Pipe
+    } else if matches!(target, "/dev/kvm" | "/dev/mshv") {
+        FdKind::HypervisorDevice
+    } else if target.starts_with("/dev/vfio/") || target == "/dev/iommu" {
+        FdKind::Vfio
+    } else if target.starts_with("/dev/vhost-") {
+        FdKind::Vhost
+    } else if target == "/dev/net/tun" {
+        FdKind::Tap
+    } else if target.starts_with('/') {
+        FdKind::File
+    } else {
+        FdKind::Other
+    }
+}
+
+/// Lists the file descriptors opened by the VMM process.
+pub fn list_fds() -> io::Result<Vec<FdInfo>> {
+    let mut fds = Vec::new();
+    for entry in fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        let Some(fd) = entry
+            .file_name()
+            .to_str()
+            .and_then(|fd| fd.parse::<i32>().ok())
+        else {
+            continue;
+        };
+
+        // The descriptor may have been closed since the directory was read,
+        // the one used for reading it being gone as well.
+        let target = match fs::read_link(entry.path()) {
+            Ok(target) => target.to_string_lossy().into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let kind = fd_kind(&target);
+
+        fds.push(FdInfo {
+            fd,
+            kind,
+            target,
+            checkpointable: kind.checkpointable(),
+        });
+    }
+    fds.sort_by_key(|fd| fd.fd);
+
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[test]
+    fn test_list_fds() {
+        let evt = EventFd::new(0).unwrap();
+
+        let fds = list_fds().unwrap();
+        let fd = fds.iter().find(|fd| fd.fd == evt.as_raw_fd()).unwrap();
+        assert_eq!(fd.kind, FdKind::EventFd);
+        assert!(fd.checkpointable);
+    }
+
+    #[test]
+    fn test_fd_kind() {
+        assert_eq!(fd_kind("anon_inode:kvm-vm"), FdKind::Hypervisor);
+        assert_eq!(fd_kind("anon_inode:kvm-vcpu:3"), FdKind::Hypervisor);
+        assert_eq!(fd_kind("/dev/kvm"), FdKind::HypervisorDevice);
+        assert_eq!(fd_kind("anon_inode:[vfio-device]"), FdKind::Vfio);
+        assert_eq!(fd_kind("/dev/vfio/12"), FdKind::Vfio);
+        assert_eq!(fd_kind("/dev/vhost-vsock"), FdKind::Vhost);
+        assert_eq!(fd_kind("/dev/net/tun"), FdKind::Tap);
+        assert_eq!(fd_kind("anon_inode:[eventfd]"), FdKind::EventFd);
+        assert_eq!(fd_kind("anon_inode:[eventpoll]"), FdKind::AnonInode);
+        assert_eq!(fd_kind("socket:[12345]"), FdKind::Socket);
+        assert_eq!(fd_kind("pipe:[12345]"), FdKind::Pipe);
+        assert_eq!(fd_kind("/var/lib/vm/disk.raw"), FdKind::File);
+        assert!(!FdKind::Hypervisor.checkpointable());
+        assert!(FdKind::Tap.checkpointable());
+    }
+}
@@ -0,0 +1,670 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Executables run at defined points of the VM lifecycle.
+//!
+//! Each hook gets a JSON description of the event on its standard input and
+//! is killed along with its process group if it doesn't complete in time.
+//! The hooks are spawned from a helper process forked before any thread is
+//! started, as the seccomp filters would be inherited by the hook processes
+//! otherwise. The VMM thread hands the events over to the hooks thread, which
+//! forwards them to the helper process, only waiting for the hooks aborting
+//! the operation on failure.
+
+use crate::config::parse_duration_ms;
+use crate::seccomp_violations;
+use arch::_NSIG;
+use libc::{c_char, c_int, pid_t};
+use option_parser::{OptionParser, OptionParserError};
+use seccompiler::BpfProgram;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::ptr::null;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub const DEFAULT_HOOK_TIMEOUT_MS: u64 = 10_000;
+
+// How often a running hook is checked for completion
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Bounds the events and the outcomes exchanged with the hooks process
+const HOOK_MESSAGE_MAX_SIZE: usize = 64 << 10;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error parsing --hook: {0}")]
+    Parse(OptionParserError),
+
+    #[error("Error parsing --hook: at least one hook required")]
+    NoHook,
+
+    #[error("Error spawning the hooks thread: {0}")]
+    ThreadSpawn(#[source] io::Error),
+
+    #[error("The hooks thread is gone")]
+    ThreadGone,
+
+    #[error("Error spawning the hooks process: {0}")]
+    RunnerSpawn(#[source] io::Error),
+
+    #[error("Error exchanging with the hooks process: {0}")]
+    RunnerIo(#[source] io::Error),
+
+    #[error("Error spawning hook {0}: {1}")]
+    Spawn(PathBuf, #[source] io::Error),
+
+    #[error("Error waiting for hook {0}: {1}")]
+    Wait(PathBuf, #[source] io::Error),
+
+    #[error("Hook {0} didn't complete within {1:?}")]
+    Timeout(PathBuf, Duration),
+
+    #[error("Hook {0} failed: {1}")]
+    Failed(PathBuf, ExitStatus),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookPoint {
+    PreBoot,
+    PostBoot,
+    PreShutdown,
+    PostShutdown,
+    PostReboot,
+    PostNetAdd,
+    PostDeviceRemove,
+}
+
+impl HookPoint {
+    pub const ALL: [HookPoint; 7] = [
+        HookPoint::PreBoot,
+        HookPoint::PostBoot,
+        HookPoint::PreShutdown,
+        HookPoint::PostShutdown,
+        HookPoint::PostReboot,
+        HookPoint::PostNetAdd,
+        HookPoint::PostDeviceRemove,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookPoint::PreBoot => "pre-boot",
+            HookPoint::PostBoot => "post-boot",
+            HookPoint::PreShutdown => "pre-shutdown",
+            HookPoint::PostShutdown => "post-shutdown",
+            HookPoint::PostReboot => "post-reboot",
+            HookPoint::PostNetAdd => "post-net-add",
+            HookPoint::PostDeviceRemove => "post-device-remove",
+        }
+    }
+
+    /// Whether the hook failing aborts the operation. A shutdown can be
+    /// initiated by the guest, hence the failure of any other hook being only
+    /// reported.
+    pub fn is_blocking(&self) -> bool {
+        matches!(self, HookPoint::PreBoot)
+    }
+}
+
+impl fmt::Display for HookPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HooksConfig {
+    pub hooks: BTreeMap<HookPoint, PathBuf>,
+    pub timeout: Duration,
+}
+
+impl HooksConfig {
+    pub const SYNTAX: &'static str = "Executables run at VM lifecycle points, \
+        with a JSON description of the event on stdin \
+        \"pre-boot=<path>,post-boot=<path>,pre-shutdown=<path>,post-shutdown=<path>,\
+        post-reboot=<path>,post-net-add=<path>,post-device-remove=<path>,\
+        timeout=<max_duration, e.g. 500ms, 5s>\"";
+
+    pub fn parse(hooks: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        for point in HookPoint::ALL {
+            parser.add(point.as_str());
+        }
+        parser.add("timeout");
+        parser.parse(hooks).map_err(Error::Parse)?;
+
+        let hooks: BTreeMap<HookPoint, PathBuf> = HookPoint::ALL
+            .iter()
+            .filter_map(|point| parser.get(point.as_str()).map(|p| (*point, p.into())))
+            .collect();
+        if hooks.is_empty() {
+            return Err(Error::NoHook);
+        }
+
+        let timeout_ms = match parser.get("timeout") {
+            Some(timeout) => parse_duration_ms(&timeout)
+                .filter(|ms| *ms > 0)
+                .ok_or_else(|| {
+                    Error::Parse(OptionParserError::Conversion(
+                        "timeout".to_string(),
+                        timeout.clone(),
+                    ))
+                })?,
+            None => DEFAULT_HOOK_TIMEOUT_MS,
+        };
+
+        Ok(HooksConfig {
+            hooks,
+            timeout: Duration::from_millis(timeout_ms),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct HookEvent {
+    hook: HookPoint,
+    timestamp: f64,
+    properties: BTreeMap<String, String>,
+}
+
+// Result of a hook as sent back by the hooks process
+#[derive(Debug, Deserialize, Serialize)]
+enum HookOutcome {
+    NotConfigured,
+    Success,
+    Spawn(PathBuf, i32),
+    Wait(PathBuf, i32),
+    Timeout(PathBuf, Duration),
+    Failed(PathBuf, i32),
+}
+
+impl HookOutcome {
+    fn spawn_error(path: &Path, e: io::Error) -> Self {
+        HookOutcome::Spawn(path.into(), e.raw_os_error().unwrap_or(libc::EINVAL))
+    }
+
+    // None when no hook is configured for the event
+    fn into_result(self) -> Option<Result<()>> {
+        let os_error = io::Error::from_raw_os_error;
+        Some(match self {
+            HookOutcome::NotConfigured => return None,
+            HookOutcome::Success => Ok(()),
+            HookOutcome::Spawn(path, errno) => Err(Error::Spawn(path, os_error(errno))),
+            HookOutcome::Wait(path, errno) => Err(Error::Wait(path, os_error(errno))),
+            HookOutcome::Timeout(path, timeout) => Err(Error::Timeout(path, timeout)),
+            HookOutcome::Failed(path, status) => {
+                Err(Error::Failed(path, ExitStatus::from_raw(status)))
+            }
+        })
+    }
+}
+
+// Makes a child which doesn't notify the parent with SIGCHLD, as the VMM
+// ignores that signal and a child reporting it would be reaped by the kernel
+// before its exit status could be collected.
+//
+// SAFETY: the child must only make async-signal-safe calls, unless the
+// parent has no other thread running.
+unsafe fn fork_quiet() -> io::Result<pid_t> {
+    match libc::syscall(libc::SYS_clone, 0, 0, 0, 0, 0) {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid as pid_t),
+    }
+}
+
+fn open_max() -> c_int {
+    // SAFETY: FFI call without side effects
+    match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 => n.min(c_int::MAX as libc::c_long) as c_int,
+        _ => 1024,
+    }
+}
+
+// Closes the file descriptors from `first` to `last` included, only the ones
+// below `open_max` being closed if close_range() isn't supported.
+//
+// SAFETY: the file descriptors in the range mustn't be owned by any object.
+unsafe fn close_fds(first: c_int, last: c_int, open_max: c_int) {
+    if first > last {
+        return;
+    }
+    if libc::syscall(libc::SYS_close_range, first, last, 0) == -1 {
+        for fd in first..last.saturating_add(1).min(open_max) {
+            libc::close(fd);
+        }
+    }
+}
+
+// SAFETY: called in the child right after forking, hence only making
+// async-signal-safe calls and never returning.
+unsafe fn exec_hook(path: &CString, stdin: c_int, open_max: c_int) -> ! {
+    // The hook gets its own process group so that whatever it spawns can be
+    // killed along with it.
+    libc::setpgid(0, 0);
+
+    // Signals ignored or blocked by the VMM would remain so through execve()
+    for signum in 1.._NSIG {
+        libc::signal(signum, libc::SIG_DFL);
+    }
+    let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+    libc::sigemptyset(set.as_mut_ptr());
+    libc::sigprocmask(libc::SIG_SETMASK, set.as_ptr(), std::ptr::null_mut());
+
+    if libc::dup2(stdin, libc::STDIN_FILENO) == -1 {
+        libc::_exit(127);
+    }
+    // The output of the hook goes to the VMM log
+    libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO);
+
+    // Don't leak the file descriptors of the hooks process to the hook
+    close_fds(3, c_int::MAX, open_max);
+
+    let argv: [*const c_char; 2] = [path.as_ptr(), null()];
+    libc::execv(path.as_ptr(), argv.as_ptr());
+    libc::_exit(127);
+}
+
+fn wait_hook(pid: pid_t, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        let mut status: c_int = 0;
+        // SAFETY: FFI call with valid arguments
+        let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG | libc::__WALL) };
+        match ret {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            0 if Instant::now() >= deadline => return Ok(None),
+            0 => thread::sleep(HOOK_POLL_INTERVAL),
+            _ => return Ok(Some(ExitStatus::from_raw(status))),
+        }
+    }
+}
+
+fn run_hook(path: &Path, input: &[u8], timeout: Duration) -> HookOutcome {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return HookOutcome::spawn_error(path, io::Error::from_raw_os_error(libc::EINVAL));
+    };
+
+    let mut pipe = [-1; 2];
+    // SAFETY: FFI call with valid arguments
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return HookOutcome::spawn_error(path, io::Error::last_os_error());
+    }
+    // SAFETY: pipe[0] is valid
+    let rx = unsafe { File::from_raw_fd(pipe[0]) };
+    // SAFETY: pipe[1] is valid
+    let mut tx = unsafe { File::from_raw_fd(pipe[1]) };
+
+    let open_max = open_max();
+    let deadline = Instant::now() + timeout;
+    // SAFETY: the child only runs exec_hook()
+    let pid = match unsafe { fork_quiet() } {
+        // SAFETY: in the child, which never returns from exec_hook()
+        Ok(0) => unsafe { exec_hook(&c_path, pipe[0], open_max) },
+        Ok(pid) => pid,
+        Err(e) => return HookOutcome::spawn_error(path, e),
+    };
+    drop(rx);
+
+    // A hook not interested in the event may exit without reading it
+    tx.write_all(input).ok();
+    drop(tx);
+
+    match wait_hook(pid, deadline) {
+        Ok(Some(status)) if status.success() => HookOutcome::Success,
+        Ok(Some(status)) => HookOutcome::Failed(path.into(), status.into_raw()),
+        Ok(None) => {
+            // SAFETY: FFI call, the hook being the leader of its process group
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+            let mut status: c_int = 0;
+            // SAFETY: FFI call with valid arguments
+            unsafe { libc::waitpid(pid, &mut status, libc::__WALL) };
+            HookOutcome::Timeout(path.into(), timeout)
+        }
+        Err(e) => HookOutcome::Wait(path.into(), e.raw_os_error().unwrap_or(libc::EIO)),
+    }
+}
+
+fn handle_event(config: &HooksConfig, input: &[u8]) -> HookOutcome {
+    let path = serde_json::from_slice::<HookEvent>(input)
+        .ok()
+        .and_then(|event| config.hooks.get(&event.hook));
+    match path {
+        Some(path) => run_hook(path, input, config.timeout),
+        None => HookOutcome::NotConfigured,
+    }
+}
+
+// Runs the hooks of the events received on `socket`, until the VMM closes it.
+fn serve_hooks(config: &HooksConfig, mut socket: UnixStream) {
+    let mut buf = vec![0; HOOK_MESSAGE_MAX_SIZE];
+    loop {
+        let len = match socket.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        };
+        let outcome = handle_event(config, &buf[..len]);
+        if socket
+            .write_all(&serde_json::to_vec(&outcome).unwrap())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// Each message, either an event or the outcome of its hook, is read at once.
+fn socket_pair() -> io::Result<(UnixStream, UnixStream)> {
+    let mut fds = [-1; 2];
+    // SAFETY: FFI call with valid arguments
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fds[0] and fds[1] are valid
+    Ok(unsafe {
+        (
+            UnixStream::from_raw_fd(fds[0]),
+            UnixStream::from_raw_fd(fds[1]),
+        )
+    })
+}
+
+// SAFETY: called in the hooks process right after forking, while the VMM had
+// no other thread running. Never returns.
+unsafe fn run_hooks_process(config: HooksConfig, socket: UnixStream, vmm_pid: pid_t) -> ! {
+    // The hooks process doesn't outlive the VMM
+    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL as libc::c_ulong);
+    if libc::getppid() != vmm_pid {
+        libc::_exit(0);
+    }
+    libc::prctl(libc::PR_SET_NAME, b"vmm_hooks\0".as_ptr());
+
+    // Neither the hooks process nor the hooks keep the files of the VMM open,
+    // the log file included, hence nothing being logged from here.
+    let fd = socket.as_raw_fd();
+    let open_max = open_max();
+    close_fds(3, fd - 1, open_max);
+    close_fds(fd + 1, c_int::MAX, open_max);
+
+    serve_hooks(&config, socket);
+    libc::_exit(0);
+}
+
+/// Process running the hooks on behalf of the hooks thread, which owns their
+/// configuration.
+pub struct HooksRunner {
+    socket: UnixStream,
+}
+
+impl HooksRunner {
+    /// Forks the process running the hooks of `config`. This must be done
+    /// before any thread is started, so that the process doesn't inherit any
+    /// seccomp filter and nothing is left locked by another thread.
+    pub fn new(config: HooksConfig) -> Result<Self> {
+        let (socket, peer) = socket_pair().map_err(Error::RunnerSpawn)?;
+
+        // SAFETY: FFI call without side effects
+        let vmm_pid = unsafe { libc::getpid() };
+        // SAFETY: no other thread is running
+        match unsafe { fork_quiet() } {
+            Ok(0) => {
+                drop(socket);
+                // SAFETY: in the hooks process, which never returns
+                unsafe { run_hooks_process(config, peer, vmm_pid) }
+            }
+            Ok(_) => Ok(HooksRunner { socket }),
+            Err(e) => Err(Error::RunnerSpawn(e)),
+        }
+    }
+
+    // Has the hook of the event run, None being returned when no hook is
+    // configured for it.
+    fn run(&mut self, input: &[u8]) -> Result<Option<Result<()>>> {
+        self.socket.write_all(input).map_err(Error::RunnerIo)?;
+
+        let mut buf = vec![0; HOOK_MESSAGE_MAX_SIZE];
+        let len = loop {
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Err(Error::RunnerIo(io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => break len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::RunnerIo(e)),
+            }
+        };
+        let outcome: HookOutcome = serde_json::from_slice(&buf[..len])
+            .map_err(|e| Error::RunnerIo(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        Ok(outcome.into_result())
+    }
+}
+
+struct HookRequest {
+    point: HookPoint,
+    input: Vec<u8>,
+    // Set when the VMM thread waits for the hook to complete
+    response: Option<Sender<Result<()>>>,
+}
+
+/// Hands the events over to the hooks thread on behalf of the VMM thread.
+pub struct Hooks {
+    sender: Sender<HookRequest>,
+}
+
+impl Hooks {
+    /// Starts the hooks thread, forwarding the events to `runner` under
+    /// `seccomp_filter`.
+    pub fn new(mut runner: HooksRunner, seccomp_filter: BpfProgram) -> Result<Self> {
+        let (sender, receiver) = channel::<HookRequest>();
+
+        thread::Builder::new()
+            .name("vmm_hooks".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = seccomp_violations::apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+
+                for request in receiver.iter() {
+                    let r = match runner.run(&request.input) {
+                        Ok(None) => Ok(()),
+                        Ok(Some(r)) => {
+                            event!(
+                                "vmm",
+                                "hook",
+                                "point",
+                                request.point.as_str(),
+                                "success",
+                                r.is_ok().to_string()
+                            );
+                            r
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    match request.response {
+                        Some(response) => {
+                            response.send(r).ok();
+                        }
+                        None => {
+                            if let Err(e) = r {
+                                warn!("Error running the {} hook: {}", request.point, e);
+                            }
+                        }
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(Hooks { sender })
+    }
+
+    /// Runs the hook configured for `point` if any, only waiting for it to
+    /// complete when its failure aborts the operation. The failure of any
+    /// other hook is logged by the hooks thread.
+    pub fn run(&self, point: HookPoint, properties: &[(&str, String)]) -> Result<()> {
+        let event = HookEvent {
+            hook: point,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            properties: properties
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        };
+        let mut input = serde_json::to_vec(&event).unwrap();
+        input.push(b'\n');
+
+        let (response, receiver) = if point.is_blocking() {
+            let (response, receiver) = channel();
+            (Some(response), Some(receiver))
+        } else {
+            (None, None)
+        };
+        self.sender
+            .send(HookRequest {
+                point,
+                input,
+                response,
+            })
+            .map_err(|_| Error::ThreadGone)?;
+
+        match receiver {
+            Some(receiver) => receiver.recv().map_err(|_| Error::ThreadGone)?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_script(dir: &vmm_sys_util::tempdir::TempDir, name: &str, body: &str) -> PathBuf {
+        let path = dir.as_path().join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_hooks_parsing() {
+        let config = HooksConfig::parse("pre-boot=/a,post-net-add=/b").unwrap();
+        assert_eq!(config.hooks.len(), 2);
+        assert_eq!(config.hooks[&HookPoint::PreBoot], PathBuf::from("/a"));
+        assert_eq!(config.hooks[&HookPoint::PostNetAdd], PathBuf::from("/b"));
+        assert_eq!(
+            config.timeout,
+            Duration::from_millis(DEFAULT_HOOK_TIMEOUT_MS)
+        );
+
+        let config = HooksConfig::parse("post-boot=/a,timeout=500ms").unwrap();
+        assert_eq!(config.timeout, Duration::from_millis(500));
+
+        assert!(matches!(
+            HooksConfig::parse("timeout=5s"),
+            Err(Error::NoHook)
+        ));
+        assert!(HooksConfig::parse("post-boot=/a,timeout=0").is_err());
+        assert!(HooksConfig::parse("post-boot=/a,timeout=soon").is_err());
+        assert!(HooksConfig::parse("pre-pause=/a").is_err());
+    }
+
+    // Serves the hooks from a thread, a test not being able to fork safely
+    fn start_hooks(config: HooksConfig) -> Hooks {
+        let (socket, peer) = socket_pair().unwrap();
+        thread::spawn(move || serve_hooks(&config, peer));
+        Hooks::new(HooksRunner { socket }, vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_hooks_run() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let output = dir.as_path().join("event.json");
+        let record = write_script(&dir, "record", &format!("cat > {}", output.display()));
+        let fail = write_script(&dir, "fail", "exit 3");
+        let hang = write_script(&dir, "hang", "sleep 10");
+
+        let hooks = start_hooks(HooksConfig {
+            hooks: BTreeMap::from([
+                (HookPoint::PostNetAdd, record),
+                (HookPoint::PreBoot, fail),
+                (HookPoint::PostShutdown, hang.clone()),
+            ]),
+            timeout: Duration::from_millis(500),
+        });
+
+        // Only the hooks aborting the operation are waited for
+        let start = Instant::now();
+        hooks.run(HookPoint::PostShutdown, &[]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        hooks
+            .run(HookPoint::PostNetAdd, &[("id", "_net2".to_string())])
+            .unwrap();
+        // The hooks run in order, the previous ones being done by then
+        assert!(matches!(
+            hooks.run(HookPoint::PreBoot, &[]),
+            Err(Error::Failed(_, status)) if status.code() == Some(3)
+        ));
+        let event: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(event["hook"], "post-net-add");
+        assert_eq!(event["properties"]["id"], "_net2");
+
+        // Nothing configured
+        hooks.run(HookPoint::PostBoot, &[]).unwrap();
+
+        let hooks = start_hooks(HooksConfig {
+            hooks: BTreeMap::from([(HookPoint::PreBoot, hang)]),
+            timeout: Duration::from_millis(500),
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            hooks.run(HookPoint::PreBoot, &[]),
+            Err(Error::Timeout(..))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let hooks = start_hooks(HooksConfig {
+            hooks: BTreeMap::from([(HookPoint::PreBoot, dir.as_path().join("missing"))]),
+            timeout: Duration::from_millis(500),
+        });
+        assert!(matches!(
+            hooks.run(HookPoint::PreBoot, &[]),
+            Err(Error::Failed(_, status)) if status.code() == Some(127)
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host dependency checks.
+//!
+//! The host resources a VM configuration relies on are checked when the VM is
+//! created, so that a misconfigured host is reported along with the way to fix
+//! it, instead of through a failure deep in the device creation at boot.
+//! Anything which can't be inspected is left for the boot to report.
+
+use crate::vm_config::{MemoryLockPolicy, VmConfig};
+use hypervisor::HypervisorType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[cfg(feature = "kvm")]
+const KVM_DEVICE: &str = "/dev/kvm";
+const TUN_DEVICE: &str = "/dev/net/tun";
+const VFIO_DEVICE: &str = "/dev/vfio/vfio";
+const VFIO_PCI_DRIVER: &str = "/sys/bus/pci/drivers/vfio-pci";
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+const CAP_IPC_LOCK: u32 = 14;
+
+/// Change to the host needed to run the VM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Remediation {
+    /// Grant the capability to the VMM
+    Capability { name: String },
+    /// Raise the resource limit of the VMM to at least `value`
+    Rlimit { resource: String, value: u64 },
+    /// Set the sysctl to at least `value`
+    Sysctl { name: String, value: u64 },
+    /// Write at least `value` to the sysfs file
+    Sysfs { path: PathBuf, value: u64 },
+    /// Load the kernel module
+    Module { name: String },
+    /// Give the VMM read and write access to the file
+    Permission { path: PathBuf },
+    /// Bind the device to the driver
+    BindDriver { device: PathBuf, driver: String },
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0} doesn't exist, is the {1} kernel module loaded?")]
+    MissingDeviceNode(PathBuf, &'static str),
+
+    #[error("No read and write access to {0}")]
+    DeviceNodeAccess(PathBuf),
+
+    #[error(
+        "RLIMIT_MEMLOCK ({limit} bytes) is lower than the {required} bytes of guest RAM to lock"
+    )]
+    MemlockLimit { limit: u64, required: u64 },
+
+    #[error(
+        "VFIO device {device} is bound to {} instead of vfio-pci",
+        .driver.as_deref().unwrap_or("no driver")
+    )]
+    VfioDriver {
+        device: PathBuf,
+        driver: Option<String>,
+    },
+
+    #[error("Not enough free huge pages of {size} bytes: {free} free, {required} needed")]
+    HugePages { size: u64, free: u64, required: u64 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Returns the changes to make to the host, in order.
+    pub fn remediation(&self) -> Vec<Remediation> {
+        match self {
+            Error::MissingDeviceNode(_, module) => vec![Remediation::Module {
+                name: module.to_string(),
+            }],
+            Error::DeviceNodeAccess(path) => vec![Remediation::Permission { path: path.clone() }],
+            Error::MemlockLimit { required, .. } => vec![
+                Remediation::Rlimit {
+                    resource: "memlock".to_string(),
+                    value: *required,
+                },
+                Remediation::Capability {
+                    name: "CAP_IPC_LOCK".to_string(),
+                },
+            ],
+            Error::VfioDriver { device, .. } => {
+                let mut remediation = Vec::new();
+                if !Path::new(VFIO_PCI_DRIVER).exists() {
+                    remediation.push(Remediation::Module {
+                        name: "vfio_pci".to_string(),
+                    });
+                }
+                remediation.push(Remediation::BindDriver {
+                    device: device.clone(),
+                    driver: "vfio-pci".to_string(),
+                });
+                remediation
+            }
+            Error::HugePages {
+                size,
+                free,
+                required,
+            } => {
+                let total = hugepages_count(*size, "nr_hugepages").unwrap_or(*free);
+                let value = total + required - free;
+                if default_hugepage_size() == Some(*size) {
+                    vec![Remediation::Sysctl {
+                        name: "vm.nr_hugepages".to_string(),
+                        value,
+                    }]
+                } else {
+                    vec![Remediation::Sysfs {
+                        path: hugepages_dir(*size).join("nr_hugepages"),
+                        value,
+                    }]
+                }
+            }
+        }
+    }
+}
+
+fn check_device_node(path: &Path, module: &'static str) -> Result<()> {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return Ok(());
+    };
+    // SAFETY: FFI call with a valid C string
+    let ret = unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::R_OK | libc::W_OK,
+            libc::AT_EACCESS,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    match io::Error::last_os_error().raw_os_error() {
+        Some(libc::ENOENT) => Err(Error::MissingDeviceNode(path.to_owned(), module)),
+        Some(libc::EACCES) | Some(libc::EPERM) => Err(Error::DeviceNodeAccess(path.to_owned())),
+        _ => Ok(()),
+    }
+}
+
+// Parses the effective capabilities out of the content of /proc/self/status.
+fn parse_effective_caps(status: &str) -> Option<u64> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+fn has_capability(cap: u32) -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_effective_caps(&status))
+        .is_some_and(|caps| caps & (1 << cap) != 0)
+}
+
+fn check_memlock(required: u64) -> Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: FFI call with a valid rlimit structure
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Ok(());
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY
+        || limit.rlim_cur >= required
+        || has_capability(CAP_IPC_LOCK)
+    {
+        return Ok(());
+    }
+
+    Err(Error::MemlockLimit {
+        limit: limit.rlim_cur,
+        required,
+    })
+}
+
+fn check_vfio_device(device: &Path) -> Result<()> {
+    let driver = fs::read_link(device.join("driver"))
+        .ok()
+        .and_then(|driver| Some(driver.file_name()?.to_string_lossy().into_owned()));
+    // Vendor specific variants of vfio-pci are accepted as well.
+    if !driver.as_deref().is_some_and(|d| d.contains("vfio")) {
+        return Err(Error::VfioDriver {
+            device: device.to_owned(),
+            driver,
+        });
+    }
+
+    if let Some(group) = fs::read_link(device.join("iommu_group"))
+        .ok()
+        .and_then(|group| group.file_name().map(|g| g.to_owned()))
+    {
+        check_device_node(&Path::new("/dev/vfio").join(group), "vfio")?;
+    }
+
+    Ok(())
+}
+
+fn hugepages_dir(size: u64) -> PathBuf {
+    Path::new(HUGEPAGES_DIR).join(format!("hugepages-{}kB", size >> 10))
+}
+
+fn hugepages_count(size: u64, file: &str) -> Option<u64> {
+    fs::read_to_string(hugepages_dir(size).join(file))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Parses the default huge page size out of the content of /proc/meminfo.
+fn parse_default_hugepage_size(meminfo: &str) -> Option<u64> {
+    let size = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))?;
+    let kb: u64 = size.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb << 10)
+}
+
+fn default_hugepage_size() -> Option<u64> {
+    parse_default_hugepage_size(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+// Returns the amount of memory to back with huge pages, per page size, when
+// no fallback onto regular pages has been allowed.
+fn hugepages_requirements(config: &VmConfig) -> BTreeMap<Option<u64>, u64> {
+    let mut requirements = BTreeMap::new();
+    let memory = &config.memory;
+    if memory.hugepages && !memory.hugepages_fallback && memory.size > 0 {
+        *requirements.entry(memory.hugepage_size).or_default() += memory.size;
+    }
+    for zone in memory.zones.iter().flatten() {
+        if zone.hugepages && !zone.hugepages_fallback {
+            *requirements.entry(zone.hugepage_size).or_default() += zone.size;
+        }
+    }
+
+    requirements
+}
+
+fn check_hugepages(config: &VmConfig) -> Result<()> {
+    for (size, bytes) in hugepages_requirements(config) {
+        let Some(size) = size.or_else(default_hugepage_size) else {
+            continue;
+        };
+        let Some(free) = hugepages_count(size, "free_hugepages") else {
+            continue;
+        };
+        let required = bytes.div_ceil(size);
+        if free < required {
+            return Err(Error::HugePages {
+                size,
+                free,
+                required,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the host provides what the VM configuration relies on.
+pub fn check_host(config: &VmConfig, hypervisor_type: HypervisorType) -> Result<()> {
+    #[cfg(feature = "kvm")]
+    if matches!(hypervisor_type, HypervisorType::Kvm) {
+        check_device_node(Path::new(KVM_DEVICE), "kvm")?;
+    }
+    #[cfg(not(feature = "kvm"))]
+    let _ = hypervisor_type;
+
+    if config
+        .net
+        .iter()
+        .flatten()
+        .any(|net| net.fds.is_none() && !net.vhost_user)
+    {
+        check_device_node(Path::new(TUN_DEVICE), "tun")?;
+    }
+
+    for vdpa in config.vdpa.iter().flatten() {
+        check_device_node(&vdpa.path, "vhost_vdpa")?;
+    }
+
+    let devices = config.devices.as_deref().unwrap_or_default();
+    if !devices.is_empty() {
+        check_device_node(Path::new(VFIO_DEVICE), "vfio")?;
+        for device in devices {
+            check_vfio_device(&device.path)?;
+        }
+    }
+
+    // VFIO pins the whole guest RAM, just like locking it does.
+    let locked = config.memory.locked && config.memory.lock_policy == MemoryLockPolicy::Strict;
+    if locked || !devices.is_empty() {
+        check_memlock(config.memory.total_size())?;
+    }
+
+    check_hugepages(config)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_effective_caps() {
+        let status =
+            "Name:\tcloud-hypervisor\nCapInh:\t0000000000000000\nCapEff:\t0000000000004000\n";
+        let caps = parse_effective_caps(status).unwrap();
+        assert_ne!(caps & (1 << CAP_IPC_LOCK), 0);
+        assert_eq!(parse_effective_caps("Name:\tvmm\n"), None);
+    }
+
+    #[test]
+    fn test_parse_default_hugepage_size() {
+        let meminfo = "MemTotal:       32768000 kB\nHugepagesize:       2048 kB\n";
+        assert_eq!(parse_default_hugepage_size(meminfo), Some(2 << 20));
+        assert_eq!(parse_default_hugepage_size("MemTotal: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn test_remediation_serialization() {
+        let error = Error::MemlockLimit {
+            limit: 8 << 20,
+            required: 1 << 30,
+        };
+        assert_eq!(
+            serde_json::to_string(&error.remediation()).unwrap(),
+            r#"[{"kind":"rlimit","resource":"memlock","value":1073741824},{"kind":"capability","name":"CAP_IPC_LOCK"}]"#
+        );
+    }
+}
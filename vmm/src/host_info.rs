@@ -0,0 +1,244 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host topology and capabilities.
+//!
+//! The host resources a VM configuration can refer to are reported as the
+//! configuration expects them, so that provisioning tools can generate a
+//! valid configuration without parsing sysfs themselves. Anything which can't
+//! be read, such as the IOMMU groups of a host without IOMMU, is left out.
+
+use crate::realtime::parse_cpu_list;
+use hypervisor::Hypervisor;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const NUMA_NODES_DIR: &str = "/sys/devices/system/node";
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+const IOMMU_GROUPS_DIR: &str = "/sys/kernel/iommu_groups";
+const CPUINFO: &str = "/proc/cpuinfo";
+
+/// Huge pages of a given size.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HugePagePool {
+    pub size: u64,
+    pub total: u64,
+    pub free: u64,
+}
+
+/// NUMA node of the host, as referred to by the `host_numa_node` of a memory
+/// zone.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HostNumaNode {
+    pub id: u32,
+    pub cpus: Vec<usize>,
+    pub memory_size: u64,
+    /// Distances to the nodes, in the order of their identifiers
+    pub distances: Vec<u32>,
+    pub hugepages: Vec<HugePagePool>,
+}
+
+/// IOMMU group, the devices of which can only be passed through together.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IommuGroup {
+    pub id: u32,
+    /// PCI addresses of the devices
+    pub devices: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HypervisorInfo {
+    #[serde(rename = "type")]
+    pub hypervisor_type: String,
+    pub max_vcpus: u32,
+    /// Whether the hypervisor provides the extensions the VMM requires
+    pub required_extensions: bool,
+    /// Maximum IPA size, on AArch64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_ipa_limit: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HostInfo {
+    pub hypervisor: HypervisorInfo,
+    /// Features of the host CPU, as listed in /proc/cpuinfo
+    pub cpu_features: Vec<String>,
+    pub numa_nodes: Vec<HostNumaNode>,
+    pub hugepages: Vec<HugePagePool>,
+    pub iommu_groups: Vec<IommuGroup>,
+}
+
+// Lists the entries of a directory named `prefix` followed by an identifier,
+// sorted by identifier.
+fn numbered_entries(dir: &Path, prefix: &str) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut ids: Vec<u32> = entries
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(prefix)?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// Parses the page size out of the name of a huge pages directory, e.g.
+// "hugepages-2048kB".
+fn parse_hugepages_dir_name(name: &str) -> Option<u64> {
+    let kb: u64 = name
+        .strip_prefix("hugepages-")?
+        .strip_suffix("kB")?
+        .parse()
+        .ok()?;
+    Some(kb << 10)
+}
+
+fn hugepage_pools(dir: &Path) -> Vec<HugePagePool> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut pools: Vec<HugePagePool> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let size = parse_hugepages_dir_name(entry.file_name().to_str()?)?;
+            Some(HugePagePool {
+                size,
+                total: read_u64(&entry.path().join("nr_hugepages"))?,
+                free: read_u64(&entry.path().join("free_hugepages"))?,
+            })
+        })
+        .collect();
+    pools.sort_by_key(|pool| pool.size);
+    pools
+}
+
+// Parses the total memory of a node out of the content of its meminfo file,
+// e.g. "Node 0 MemTotal:       32768000 kB".
+fn parse_node_memory_size(meminfo: &str) -> Option<u64> {
+    let size = meminfo
+        .lines()
+        .find_map(|line| Some(line.split_once("MemTotal:")?.1))?;
+    let kb: u64 = size.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb << 10)
+}
+
+fn numa_nodes() -> Vec<HostNumaNode> {
+    numbered_entries(Path::new(NUMA_NODES_DIR), "node")
+        .into_iter()
+        .map(|id| {
+            let dir = Path::new(NUMA_NODES_DIR).join(format!("node{id}"));
+            let read = |file: &str| fs::read_to_string(dir.join(file)).unwrap_or_default();
+            HostNumaNode {
+                id,
+                cpus: parse_cpu_list(&read("cpulist")).unwrap_or_default(),
+                memory_size: parse_node_memory_size(&read("meminfo")).unwrap_or_default(),
+                distances: read("distance")
+                    .split_whitespace()
+                    .filter_map(|distance| distance.parse().ok())
+                    .collect(),
+                hugepages: hugepage_pools(&dir.join("hugepages")),
+            }
+        })
+        .collect()
+}
+
+fn iommu_groups() -> Vec<IommuGroup> {
+    numbered_entries(Path::new(IOMMU_GROUPS_DIR), "")
+        .into_iter()
+        .map(|id| {
+            let dir = Path::new(IOMMU_GROUPS_DIR).join(format!("{id}/devices"));
+            let mut devices: Vec<String> = fs::read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .flatten()
+                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                        .collect()
+                })
+                .unwrap_or_default();
+            devices.sort();
+            IommuGroup { id, devices }
+        })
+        .collect()
+}
+
+// Parses the features of the first CPU out of the content of /proc/cpuinfo,
+// listed as "flags" on x86_64 and as "Features" on AArch64.
+fn parse_cpu_features(cpuinfo: &str) -> Vec<String> {
+    let Some(features) = cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        matches!(key.trim(), "flags" | "Features").then_some(value)
+    }) else {
+        return Vec::new();
+    };
+
+    let mut features: Vec<String> = features.split_whitespace().map(String::from).collect();
+    features.sort();
+    features
+}
+
+fn hypervisor_info(hypervisor: &dyn Hypervisor) -> HypervisorInfo {
+    HypervisorInfo {
+        hypervisor_type: format!("{:?}", hypervisor.hypervisor_type()).to_lowercase(),
+        max_vcpus: hypervisor.get_max_vcpus(),
+        required_extensions: hypervisor.check_required_extensions().is_ok(),
+        #[cfg(target_arch = "aarch64")]
+        host_ipa_limit: Some(hypervisor.get_host_ipa_limit()),
+        #[cfg(not(target_arch = "aarch64"))]
+        host_ipa_limit: None,
+    }
+}
+
+/// Gathers the topology and the capabilities of the host.
+pub fn host_info(hypervisor: &dyn Hypervisor) -> HostInfo {
+    HostInfo {
+        hypervisor: hypervisor_info(hypervisor),
+        cpu_features: parse_cpu_features(&fs::read_to_string(CPUINFO).unwrap_or_default()),
+        numa_nodes: numa_nodes(),
+        hugepages: hugepage_pools(Path::new(HUGEPAGES_DIR)),
+        iommu_groups: iommu_groups(),
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hugepages_dir_name() {
+        assert_eq!(parse_hugepages_dir_name("hugepages-2048kB"), Some(2 << 20));
+        assert_eq!(
+            parse_hugepages_dir_name("hugepages-1048576kB"),
+            Some(1 << 30)
+        );
+        assert_eq!(parse_hugepages_dir_name("hugepages"), None);
+    }
+
+    #[test]
+    fn test_parse_node_memory_size() {
+        let meminfo = "Node 1 MemTotal:       32768000 kB\nNode 1 MemFree:        1024 kB\n";
+        assert_eq!(parse_node_memory_size(meminfo), Some(32768000 << 10));
+        assert_eq!(parse_node_memory_size(""), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_features() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: GenuineIntel\nflags\t\t: sse2 fpu avx\n\n\
+                       processor\t: 1\nflags\t\t: fpu\n";
+        assert_eq!(parse_cpu_features(cpuinfo), vec!["avx", "fpu", "sse2"]);
+        let cpuinfo = "processor\t: 0\nBogoMIPS\t: 50.00\nFeatures\t: fp asimd sve\n";
+        assert_eq!(parse_cpu_features(cpuinfo), vec!["asimd", "fp", "sve"]);
+    }
+}
@@ -7,10 +7,15 @@ use zerocopy::AsBytes;
 
 use crate::igvm::loader::Loader;
 use crate::igvm::IgvmLoadedInfo;
-use crate::igvm::{BootPageAcceptance, StartupMemoryType, HV_PAGE_SIZE};
+use crate::igvm::{
+    BootPageAcceptance, StartupMemoryType, HV_ISOLATED_PAGE_SIZE_4KB, HV_ISOLATED_PAGE_TYPE_CPUID,
+    HV_ISOLATED_PAGE_TYPE_NORMAL, HV_ISOLATED_PAGE_TYPE_SECRETS, HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+    HV_ISOLATED_PAGE_TYPE_VMSA, HV_PAGE_SIZE,
+};
 use crate::memory_manager::MemoryManager;
 use igvm_defs::IgvmPageDataType;
 use igvm_defs::IgvmPlatformType;
+use igvm_defs::Vtl;
 use igvm_parser::IgvmDirectiveHeader;
 use igvm_parser::IgvmFile;
 use igvm_parser::IgvmPlatformHeader;
@@ -19,8 +24,8 @@ use igvm_parser::IsolationType;
 use igvm_defs::IGVM_VHS_PARAMETER;
 use igvm_defs::IGVM_VHS_PARAMETER_INSERT;
 
+use igvm_parser::registers::X86Register;
 use igvm_parser::snp_defs::SevVmsa;
-pub use mshv_bindings::*;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::io::Read;
@@ -43,6 +48,10 @@ pub enum Error {
     Igvm(#[source] std::io::Error),
     #[error("invalid igvm file")]
     InvalidIgvmFile(#[source] igvm_parser::Error),
+    #[error("no platform supported by the igvm file")]
+    NoSupportedPlatform,
+    #[error("missing RIP in the VP context")]
+    MissingVpContextRip,
     #[error("invalid guest memory map")]
     InvalidGuestMemmap(#[source] arch::Error),
     #[error("loader error")]
@@ -57,6 +66,33 @@ pub enum Error {
     FailedToDecodeHostData(#[source] hex::FromHexError),
 }
 
+// CPUID page of SNP isolated guests, as defined by the SEV-SNP firmware ABI
+const SNP_CPUID_LEAF_COUNT_MAX: usize = 64;
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct SnpCpuidLeaf {
+    eax_in: u32,
+    ecx_in: u32,
+    xfem_in: u64,
+    xss_in: u64,
+    eax_out: u32,
+    ebx_out: u32,
+    ecx_out: u32,
+    edx_out: u32,
+    reserved: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct SnpCpuidPage {
+    count: u32,
+    reserved1: u32,
+    reserved2: u64,
+    cpuid_leaf_info: [SnpCpuidLeaf; SNP_CPUID_LEAF_COUNT_MAX],
+}
+
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 struct GpaPages {
@@ -135,8 +171,9 @@ fn import_parameter(
 ///
 /// Load the given IGVM file to guest memory.
 /// Right now it only supports SNP based isolation.
-/// We can boot legacy VM with an igvm file without
-/// any isolation.
+/// Non isolated VMs can be booted from an igvm file on
+/// any hypervisor, using either a non isolated platform
+/// of the file or its SNP platform as a fallback.
 ///
 pub fn load_igvm(
     mut file: &std::fs::File,
@@ -151,6 +188,10 @@ pub fn load_igvm(
     let memory = memory_manager.lock().as_ref().unwrap().guest_memory();
     let mut gpas: Vec<GpaPages> = Vec::new();
     let proc_count = cpu_manager.lock().unwrap().vcpus().len() as u32;
+    #[cfg(feature = "sev_snp")]
+    let sev_snp_enabled = cpu_manager.lock().unwrap().sev_snp_enabled();
+    #[cfg(not(feature = "sev_snp"))]
+    let sev_snp_enabled = false;
 
     #[cfg(feature = "sev_snp")]
     let mut host_data_contents = [0; 32];
@@ -163,22 +204,38 @@ pub fn load_igvm(
     file.seek(SeekFrom::Start(0)).map_err(Error::Igvm)?;
     file.read_to_end(&mut file_contents).map_err(Error::Igvm)?;
 
-    let igvm_file = IgvmFile::new_from_binary(&file_contents, Some(IsolationType::Snp))
-        .map_err(Error::InvalidIgvmFile)?;
-
-    let mask = match &igvm_file.platforms()[0] {
-        IgvmPlatformHeader::SupportedPlatform(info) => {
-            debug_assert!(info.platform_type == IgvmPlatformType::SEV_SNP);
-            info.compatibility_mask
-        }
+    let isolation = if sev_snp_enabled {
+        Some(IsolationType::Snp)
+    } else {
+        None
     };
+    let igvm_file =
+        IgvmFile::new_from_binary(&file_contents, isolation).map_err(Error::InvalidIgvmFile)?;
+
+    // Prefer the platform matching the isolation of the VM, falling back on
+    // whatever the file provides for a non isolated VM.
+    let platforms: Vec<_> = igvm_file
+        .platforms()
+        .iter()
+        .map(|platform| match platform {
+            IgvmPlatformHeader::SupportedPlatform(info) => info,
+        })
+        .collect();
+    let mask = platforms
+        .iter()
+        .find(|info| (info.platform_type == IgvmPlatformType::SEV_SNP) == sev_snp_enabled)
+        .or_else(|| platforms.first())
+        .ok_or(Error::NoSupportedPlatform)?
+        .compatibility_mask;
 
     let mut loader = Loader::new(memory);
 
     let mut parameter_areas: HashMap<u32, ParameterAreaState> = HashMap::new();
 
     for header in igvm_file.directives() {
-        debug_assert!(header.compatibility_mask().unwrap_or(mask) & mask == mask);
+        if header.compatibility_mask().unwrap_or(mask) & mask == 0 {
+            continue;
+        }
 
         match header {
             IgvmDirectiveHeader::PageData {
@@ -198,15 +255,15 @@ pub fn load_igvm(
                         if flags.unmeasured() {
                             gpas.push(GpaPages {
                                 gpa: *gpa,
-                                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
-                                page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                                page_type: HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+                                page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                             });
                             BootPageAcceptance::ExclusiveUnmeasured
                         } else {
                             gpas.push(GpaPages {
                                 gpa: *gpa,
-                                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
-                                page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                                page_type: HV_ISOLATED_PAGE_TYPE_NORMAL,
+                                page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                             });
                             BootPageAcceptance::Exclusive
                         }
@@ -214,17 +271,17 @@ pub fn load_igvm(
                     IgvmPageDataType::SECRETS => {
                         gpas.push(GpaPages {
                             gpa: *gpa,
-                            page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS,
-                            page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                            page_type: HV_ISOLATED_PAGE_TYPE_SECRETS,
+                            page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                         });
                         BootPageAcceptance::SecretsPage
                     }
                     IgvmPageDataType::CPUID_DATA => {
                         // SAFETY: CPUID is readonly
                         unsafe {
-                            let cpuid_page_p: *mut hv_psp_cpuid_page =
-                                data.as_ptr() as *mut hv_psp_cpuid_page; // as *mut hv_psp_cpuid_page;
-                            let cpuid_page: &mut hv_psp_cpuid_page = &mut *cpuid_page_p;
+                            let cpuid_page_p: *mut SnpCpuidPage =
+                                data.as_ptr() as *mut SnpCpuidPage;
+                            let cpuid_page: &mut SnpCpuidPage = &mut *cpuid_page_p;
                             for i in 0..cpuid_page.count {
                                 let leaf = cpuid_page.cpuid_leaf_info[i as usize];
                                 let mut in_leaf = cpu_manager
@@ -249,8 +306,8 @@ pub fn load_igvm(
                         }
                         gpas.push(GpaPages {
                             gpa: *gpa,
-                            page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID,
-                            page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                            page_type: HV_ISOLATED_PAGE_TYPE_CPUID,
+                            page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                         });
                         BootPageAcceptance::CpuidPage
                     }
@@ -335,6 +392,7 @@ pub fn load_igvm(
                 loaded_info.vmsa = **vmsa;
                 // Only supported for index zero
                 if *vp_index == 0 {
+                    loaded_info.bsp_rip = vmsa.rip;
                     data[..len].copy_from_slice(vmsa.as_bytes());
                     loader
                         .import_pages(gpa / HV_PAGE_SIZE, 1, BootPageAcceptance::VpContext, &data)
@@ -343,8 +401,8 @@ pub fn load_igvm(
 
                 gpas.push(GpaPages {
                     gpa: *gpa,
-                    page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
-                    page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                    page_type: HV_ISOLATED_PAGE_TYPE_VMSA,
+                    page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                 });
             }
             IgvmDirectiveHeader::SnpIdBlock {
@@ -379,11 +437,22 @@ pub fn load_igvm(
                 loaded_info.snp_id_block.author_public_key = **author_public_key;
             }
             IgvmDirectiveHeader::X64VbsVpContext {
-                vtl: _,
-                registers: _,
+                vtl,
+                registers,
                 compatibility_mask: _,
             } => {
-                todo!("VbsVpContext not supported");
+                // Only the context of VTL0 is relevant without VSM, and the
+                // boot processor starts at its RIP with the same initial
+                // state as any other payload.
+                if *vtl == Vtl::Vtl0 {
+                    loaded_info.bsp_rip = registers
+                        .iter()
+                        .find_map(|register| match register {
+                            X86Register::Rip(rip) => Some(*rip),
+                            _ => None,
+                        })
+                        .ok_or(Error::MissingVpContextRip)?;
+                }
             }
             IgvmDirectiveHeader::VbsMeasurement { .. } => {
                 todo!("VbsMeasurement not supported")
@@ -412,8 +481,8 @@ pub fn load_igvm(
                 *area = ParameterAreaState::Inserted;
                 gpas.push(GpaPages {
                     gpa: *gpa,
-                    page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
-                    page_size: hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+                    page_type: HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+                    page_size: HV_ISOLATED_PAGE_SIZE_4KB,
                 });
             }
             IgvmDirectiveHeader::ErrorRange { .. } => {
@@ -426,7 +495,7 @@ pub fn load_igvm(
     }
 
     #[cfg(feature = "sev_snp")]
-    {
+    if sev_snp_enabled {
         use std::time::Instant;
 
         let mut now = Instant::now();
@@ -457,19 +526,12 @@ pub fn load_igvm(
             );
             // Convert the gpa into PFN as MSHV hypercall takes an array
             // of PFN for importing the isolated pages
-            let pfns: Vec<u64> = group
-                .iter()
-                .map(|gpa| gpa.gpa >> HV_HYP_PAGE_SHIFT)
-                .collect();
+            let pfns: Vec<u64> = group.iter().map(|gpa| gpa.gpa / HV_PAGE_SIZE).collect();
             memory_manager
                 .lock()
                 .unwrap()
                 .vm
-                .import_isolated_pages(
-                    group[0].page_type,
-                    hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
-                    &pfns,
-                )
+                .import_isolated_pages(group[0].page_type, HV_ISOLATED_PAGE_SIZE_4KB, &pfns)
                 .map_err(Error::ImportIsolatedPages)?;
         }
 
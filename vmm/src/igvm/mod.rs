@@ -21,8 +21,8 @@
  *  https://github.com/microsoft/igvm
  *
  *  This module takes the IGVM file, parses it, and loads it to the
- *  guest memory. Currently igvm only supported on Microsoft Hypervisor, as
- *  booting a legacy VM, as well as SNP based isolated VM.
+ *  guest memory. Non isolated VMs can be booted from an IGVM file on any
+ *  hypervisor, while SNP based isolated VMs require Microsoft Hypervisor.
  */
 
 pub mod igvm_loader;
@@ -37,6 +37,8 @@ pub struct IgvmLoadedInfo {
     pub vmsa_gpa: u64,
     pub snp_id_block: IGVM_VHS_SNP_ID_BLOCK,
     pub vmsa: SevVmsa,
+    /// RIP of the boot processor taken from its VP context
+    pub bsp_rip: u64,
 }
 
 impl Default for IgvmLoadedInfo {
@@ -46,12 +48,22 @@ impl Default for IgvmLoadedInfo {
             vmsa_gpa: 0,
             snp_id_block: IGVM_VHS_SNP_ID_BLOCK::new_zeroed(),
             vmsa: SevVmsa::new_zeroed(),
+            bsp_rip: 0,
         }
     }
 }
 
 pub const HV_PAGE_SIZE: u64 = 4096;
 
+// Isolated page types and size the pages of SNP isolated guests are imported
+// with, as defined by the Microsoft Hypervisor ABI.
+pub const HV_ISOLATED_PAGE_TYPE_NORMAL: u32 = 0;
+pub const HV_ISOLATED_PAGE_TYPE_VMSA: u32 = 1;
+pub const HV_ISOLATED_PAGE_TYPE_UNMEASURED: u32 = 3;
+pub const HV_ISOLATED_PAGE_TYPE_SECRETS: u32 = 4;
+pub const HV_ISOLATED_PAGE_TYPE_CPUID: u32 = 5;
+pub const HV_ISOLATED_PAGE_SIZE_4KB: u32 = 0;
+
 /// The page acceptance used for importing pages into the initial launch context of the guest.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum BootPageAcceptance {
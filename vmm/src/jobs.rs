@@ -0,0 +1,246 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Long-running operations requested through the API without waiting for
+//! their completion, whose state can be queried in the meantime.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+// Completed jobs kept around for the clients to query their outcome
+const MAX_COMPLETED_JOBS: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unknown job {0}")]
+    NotFound(u64),
+    #[error("Job {0} has already completed")]
+    AlreadyCompleted(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn completed(self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub action: String,
+    pub state: JobState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Jobs queued, running or recently completed, the oldest completed ones
+/// being forgotten first.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, JobInfo>,
+    // Raised to ask the running jobs to stop
+    cancel_flags: BTreeMap<u64, Arc<AtomicBool>>,
+}
+
+impl Jobs {
+    /// Queues a job running `action`.
+    pub fn create(&mut self, action: &str) -> JobInfo {
+        self.next_id += 1;
+        let job = JobInfo {
+            id: self.next_id,
+            action: action.to_string(),
+            state: JobState::Queued,
+            error: None,
+        };
+        self.jobs.insert(job.id, job.clone());
+        self.prune();
+
+        job
+    }
+
+    pub fn get(&self, id: u64) -> Option<&JobInfo> {
+        self.jobs.get(&id)
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        self.jobs.values().cloned().collect()
+    }
+
+    /// Cancels a job which hasn't started yet, or asks a running one to
+    /// stop, the job then being cancelled once it has.
+    pub fn cancel(&mut self, id: u64) -> Result<JobInfo, Error> {
+        let job = self.jobs.get_mut(&id).ok_or(Error::NotFound(id))?;
+        match job.state {
+            JobState::Queued => job.state = JobState::Cancelled,
+            JobState::Running => {
+                if let Some(flag) = self.cancel_flags.get(&id) {
+                    flag.store(true, Ordering::SeqCst);
+                }
+            }
+            JobState::Cancelled => {}
+            JobState::Succeeded | JobState::Failed => return Err(Error::AlreadyCompleted(id)),
+        }
+        let job = job.clone();
+        self.prune();
+
+        Ok(job)
+    }
+
+    /// Number of jobs queued or running.
+    pub fn pending(&self) -> usize {
+        self.jobs
+            .values()
+            .filter(|job| !job.state.completed())
+            .count()
+    }
+
+    /// Marks the job as running, unless it got cancelled, returning the flag
+    /// raised when the job is asked to stop.
+    pub fn start(&mut self, id: u64) -> Option<Arc<AtomicBool>> {
+        match self.jobs.get_mut(&id) {
+            Some(job) if job.state == JobState::Queued => {
+                job.state = JobState::Running;
+                let flag = Arc::new(AtomicBool::new(false));
+                self.cancel_flags.insert(id, flag.clone());
+                Some(flag)
+            }
+            _ => None,
+        }
+    }
+
+    /// Records the outcome of a job, a job which failed after being asked
+    /// to stop being cancelled.
+    pub fn complete(&mut self, id: u64, result: Result<(), String>) {
+        let cancelled = self
+            .cancel_flags
+            .remove(&id)
+            .is_some_and(|flag| flag.load(Ordering::SeqCst));
+        if let Some(job) = self.jobs.get_mut(&id) {
+            match result {
+                Ok(()) => job.state = JobState::Succeeded,
+                Err(e) if cancelled => {
+                    job.state = JobState::Cancelled;
+                    job.error = Some(e);
+                }
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e);
+                }
+            }
+        }
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let completed = self
+            .jobs
+            .values()
+            .filter(|job| job.state.completed())
+            .count();
+        let stale: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|job| job.state.completed())
+            .take(completed.saturating_sub(MAX_COMPLETED_JOBS))
+            .map(|job| job.id)
+            .collect();
+        for id in stale {
+            self.jobs.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs() {
+        let mut jobs = Jobs::default();
+
+        let snapshot = jobs.create("vm.snapshot");
+        let migration = jobs.create("vm.send-migration");
+        assert_eq!(snapshot.id, 1);
+        assert_eq!(snapshot.state, JobState::Queued);
+        assert_eq!(migration.id, 2);
+
+        assert!(jobs.start(snapshot.id).is_some());
+        jobs.complete(snapshot.id, Err("No space left on device".to_string()));
+        let snapshot = jobs.get(snapshot.id).unwrap();
+        assert_eq!(snapshot.state, JobState::Failed);
+        assert_eq!(snapshot.error.as_deref(), Some("No space left on device"));
+        assert!(matches!(
+            jobs.cancel(snapshot.id),
+            Err(Error::AlreadyCompleted(1))
+        ));
+
+        assert_eq!(
+            jobs.cancel(migration.id).unwrap().state,
+            JobState::Cancelled
+        );
+        assert!(jobs.start(migration.id).is_none());
+        assert!(matches!(jobs.cancel(3), Err(Error::NotFound(3))));
+
+        let coredump = jobs.create("vm.coredump");
+        assert!(jobs.start(coredump.id).is_some());
+        jobs.complete(coredump.id, Ok(()));
+        assert_eq!(jobs.get(coredump.id).unwrap().state, JobState::Succeeded);
+        assert_eq!(jobs.list().len(), 3);
+    }
+
+    #[test]
+    fn test_jobs_cancel_running() {
+        let mut jobs = Jobs::default();
+
+        let dump = jobs.create("vm.dump-memory");
+        let flag = jobs.start(dump.id).unwrap();
+        assert_eq!(jobs.cancel(dump.id).unwrap().state, JobState::Running);
+        assert!(flag.load(Ordering::SeqCst));
+        jobs.complete(dump.id, Err("Memory dump cancelled".to_string()));
+        assert_eq!(jobs.get(dump.id).unwrap().state, JobState::Cancelled);
+
+        // Completing anyway, the job reports its outcome
+        let migration = jobs.create("vm.send-migration");
+        jobs.start(migration.id).unwrap();
+        jobs.cancel(migration.id).unwrap();
+        jobs.complete(migration.id, Ok(()));
+        assert_eq!(jobs.get(migration.id).unwrap().state, JobState::Succeeded);
+    }
+
+    #[test]
+    fn test_jobs_prune() {
+        let mut jobs = Jobs::default();
+
+        let running = jobs.create("vm.send-migration");
+        assert!(jobs.start(running.id).is_some());
+        for _ in 0..MAX_COMPLETED_JOBS + 1 {
+            let job = jobs.create("vm.snapshot");
+            jobs.cancel(job.id).unwrap();
+        }
+
+        // The oldest completed job is gone, not the running one
+        assert_eq!(jobs.list().len(), MAX_COMPLETED_JOBS + 1);
+        assert!(jobs.get(running.id).is_some());
+        assert!(jobs.get(2).is_none());
+        assert!(jobs.get(3).is_some());
+    }
+}
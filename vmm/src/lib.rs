@@ -9,21 +9,33 @@ extern crate event_monitor;
 extern crate log;
 
 use crate::api::{
-    ApiRequest, ApiResponse, RequestHandler, VmInfoResponse, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiRequest, ApiResponse, JobRequest, RequestHandler, StagedConfigChange, VmDumpMemoryData,
+    VmInfoResponse, VmReceiveMigrationData, VmRekeyData, VmSendMigrationData, VmSetBootParamsData,
+    VmSnapshotConfig, VmVcpuStatsResponse, VmmCapabilitiesResponse, VmmFdsResponse,
+    VmmPingResponse, VmmThreadsResponse,
 };
+#[cfg(feature = "pprof")]
+use crate::api::{PprofKind, VmmPprofData};
+#[cfg(feature = "introspection")]
+use crate::api::{VmIntrospectData, VmIntrospectResponse};
 use crate::config::{
-    add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PayloadConfig, PmemConfig,
+    ReplacementDeviceConfig, ResetPolicy, RestartMode, RestoreConfig, UsbConfig, UserDeviceConfig,
+    VdpaConfig, VmConfig, VmIdentityConfig, VsockConfig,
 };
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggable;
+use crate::cpu::VcpuCpuTime;
+use crate::hooks::{HookPoint, Hooks, HooksRunner};
 use crate::memory_manager::MemoryManager;
+use crate::metrics::{MetricType, MetricsWriter};
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 use crate::migration::get_vm_snapshot;
-use crate::migration::{recv_vm_config, recv_vm_state};
+use crate::migration::{recv_vm_config, recv_vm_state, verify_snapshot, SnapshotSource};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
-use crate::vm::{Error as VmError, Vm, VmState};
+use crate::snapshot_encryption::SnapshotKey;
+use crate::snapshot_stream::SnapshotWriter;
+use crate::vm::{Error as VmError, MemoryDump, ShutdownReason, Vm, VmState};
 use anyhow::anyhow;
 #[cfg(feature = "dbus_api")]
 use api::dbus::{DBusApiOptions, DBusApiShutdownChannels};
@@ -35,7 +47,7 @@ use seccompiler::{apply_filter, SeccompAction};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 use signal_hook::iterator::{Handle, Signals};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::{stdout, Read, Write};
@@ -45,42 +57,65 @@ use std::os::unix::net::UnixStream;
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{result, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
 use vm_memory::bitmap::AtomicBitmap;
 use vm_memory::{ReadVolatile, WriteVolatile};
 use vm_migration::{protocol::*, Migratable};
-use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::unblock_signal;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+use vmm_sys_util::timerfd::TimerFd;
 
 mod acpi;
 pub mod api;
+pub mod boot_detect;
+pub mod cleanup;
 mod clone3;
 pub mod config;
+pub mod config_update;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 mod coredump;
 pub mod cpu;
+mod device_access;
 pub mod device_manager;
 pub mod device_tree;
+pub mod fds;
 #[cfg(feature = "guest_debug")]
 mod gdb;
+pub mod hooks;
+pub mod host_checks;
+pub mod host_info;
 #[cfg(feature = "igvm")]
 mod igvm;
 pub mod interrupt;
+pub mod jobs;
 pub mod memory_manager;
+mod memory_share;
+pub mod metrics;
 pub mod migration;
+mod payload_verification;
 mod pci_segment;
+pub mod profile;
+mod realtime;
 pub mod seccomp_filters;
+pub mod seccomp_violations;
 mod serial_manager;
 mod sigwinch_listener;
+pub mod snapshot_compression;
+pub mod snapshot_encryption;
+pub mod snapshot_stream;
+pub mod threads;
 pub mod vm;
 pub mod vm_config;
+#[cfg(all(feature = "vnc", target_arch = "x86_64"))]
+mod vnc;
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
@@ -112,6 +147,10 @@ pub enum Error {
     #[error("Error reading from EventFd: {0}")]
     EventFdRead(#[source] io::Error),
 
+    /// Cannot write to EventFd.
+    #[error("Error writing to EventFd: {0}")]
+    EventFdWrite(#[source] io::Error),
+
     /// Cannot create epoll context.
     #[error("Error creating epoll context: {0}")]
     Epoll(#[source] io::Error),
@@ -130,10 +169,19 @@ pub enum Error {
     #[error("Error starting D-Bus session: {0}")]
     CreateDBusSession(#[source] zbus::Error),
 
+    /// Cannot bind the D-Bus peer-to-peer socket
+    #[cfg(feature = "dbus_api")]
+    #[error("Error binding D-Bus socket: {0}")]
+    CreateDBusSocket(#[source] io::Error),
+
     /// Cannot create `event-monitor` thread
     #[error("Error spawning `event-monitor` thread: {0}")]
     EventMonitorThreadSpawn(#[source] io::Error),
 
+    /// Cannot start the hooks thread
+    #[error("Error starting the hooks thread: {0}")]
+    HooksThread(#[source] hooks::Error),
+
     /// Cannot handle the VM STDIN stream
     #[error("Error handling VM stdin: {0:?}")]
     Stdin(VmError),
@@ -174,6 +222,15 @@ pub enum Error {
     #[error("Error creation API server's socket {0:?}")]
     CreateApiServerSocket(#[source] io::Error),
 
+    /// Error configuring TLS for the API server
+    #[cfg(feature = "http_tls")]
+    #[error("Error configuring TLS for the API server: {0}")]
+    HttpTls(#[source] api::http::tls::Error),
+
+    /// Error loading the bearer token of the API server
+    #[error("Error loading the bearer token of the API server: {0}")]
+    HttpApiToken(#[source] api::http::auth::Error),
+
     #[cfg(feature = "guest_debug")]
     #[error("Failed to start the GDB thread: {0}")]
     GdbThreadSpawn(io::Error),
@@ -193,6 +250,10 @@ pub enum Error {
 
     #[error("Failed to join on threads: {0:?}")]
     ThreadCleanup(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
+
+    /// Cannot create or arm the restart timer
+    #[error("Error handling the restart timer: {0}")]
+    RestartTimer(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -204,6 +265,9 @@ pub enum EpollDispatch {
     Api = 2,
     ActivateVirtioDevices = 3,
     Debug = 4,
+    Restart = 5,
+    BootDetect = 6,
+    Job = 7,
     Unknown,
 }
 
@@ -216,6 +280,9 @@ impl From<u64> for EpollDispatch {
             2 => Api,
             3 => ActivateVirtioDevices,
             4 => Debug,
+            5 => Restart,
+            6 => BootDetect,
+            7 => Job,
             _ => Unknown,
         }
     }
@@ -250,6 +317,20 @@ impl EpollContext {
         Ok(())
     }
 
+    pub fn remove_event<T>(&mut self, fd: &T) -> result::Result<(), io::Error>
+    where
+        T: AsRawFd,
+    {
+        epoll::ctl(
+            self.epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            fd.as_raw_fd(),
+            epoll::Event::new(epoll::Events::empty(), 0),
+        )?;
+
+        Ok(())
+    }
+
     #[cfg(fuzzing)]
     pub fn add_event_custom<T>(
         &mut self,
@@ -303,22 +384,40 @@ pub fn feature_list() -> Vec<String> {
         "dbus_api".to_string(),
         #[cfg(feature = "dhat-heap")]
         "dhat-heap".to_string(),
+        #[cfg(feature = "fs_builtin")]
+        "fs_builtin".to_string(),
         #[cfg(feature = "guest_debug")]
         "guest_debug".to_string(),
+        #[cfg(feature = "http_tls")]
+        "http_tls".to_string(),
         #[cfg(feature = "igvm")]
         "igvm".to_string(),
+        #[cfg(feature = "introspection")]
+        "introspection".to_string(),
         #[cfg(feature = "io_uring")]
         "io_uring".to_string(),
         #[cfg(feature = "kvm")]
         "kvm".to_string(),
         #[cfg(feature = "mshv")]
         "mshv".to_string(),
+        #[cfg(feature = "payload_verification")]
+        "payload_verification".to_string(),
+        #[cfg(feature = "pprof")]
+        "pprof".to_string(),
         #[cfg(feature = "sev_snp")]
         "sev_snp".to_string(),
+        #[cfg(feature = "snapshot_compression")]
+        "snapshot_compression".to_string(),
+        #[cfg(feature = "snapshot_encryption")]
+        "snapshot_encryption".to_string(),
         #[cfg(feature = "tdx")]
         "tdx".to_string(),
         #[cfg(feature = "tracing")]
         "tracing".to_string(),
+        #[cfg(feature = "virtio_9p")]
+        "virtio_9p".to_string(),
+        #[cfg(feature = "vnc")]
+        "vnc".to_string(),
     ]
 }
 
@@ -337,7 +436,7 @@ pub fn start_event_monitor_thread(
         .spawn(move || {
             // Apply seccomp filter
             if !seccomp_filter.is_empty() {
-                apply_filter(&seccomp_filter)
+                seccomp_violations::apply_filter(&seccomp_filter)
                     .map_err(Error::ApplySeccompFilter)
                     .map_err(|e| {
                         error!("Error applying seccomp filter: {:?}", e);
@@ -377,6 +476,10 @@ pub fn start_vmm_thread(
     vmm_version: VmmVersionInfo,
     http_path: &Option<String>,
     http_fd: Option<RawFd>,
+    #[cfg(feature = "http_tls")] http_tls: Option<api::HttpTlsConfig>,
+    http_vsock: Option<api::HttpVsockConfig>,
+    http_token_path: Option<PathBuf>,
+    http_events: Option<flume::Receiver<Arc<String>>>,
     #[cfg(feature = "dbus_api")] dbus_options: Option<DBusApiOptions>,
     api_event: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -387,6 +490,7 @@ pub fn start_vmm_thread(
     exit_event: EventFd,
     seccomp_action: &SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    hooks: Option<HooksRunner>,
 ) -> Result<VmmThreadHandle> {
     #[cfg(feature = "guest_debug")]
     let gdb_hw_breakpoints = hypervisor.get_guest_debug_hw_bps();
@@ -404,7 +508,17 @@ pub fn start_vmm_thread(
     let vmm_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::Vmm, hypervisor_type)
         .map_err(Error::CreateSeccompFilter)?;
 
+    let hooks = hooks
+        .map(|runner| {
+            let seccomp_filter = get_seccomp_filter(seccomp_action, Thread::Hooks, hypervisor_type)
+                .map_err(Error::CreateSeccompFilter)?;
+            Hooks::new(runner, seccomp_filter).map_err(Error::HooksThread)
+        })
+        .transpose()?;
+
     let vmm_seccomp_action = seccomp_action.clone();
+    let jobs = Arc::new(Mutex::new(jobs::Jobs::default()));
+    let vmm_jobs = jobs.clone();
     let thread = {
         let exit_event = exit_event.try_clone().map_err(Error::EventFdClone)?;
         thread::Builder::new()
@@ -412,7 +526,8 @@ pub fn start_vmm_thread(
             .spawn(move || {
                 // Apply seccomp filter for VMM thread.
                 if !vmm_seccomp_filter.is_empty() {
-                    apply_filter(&vmm_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
+                    seccomp_violations::apply_filter(&vmm_seccomp_filter)
+                        .map_err(Error::ApplySeccompFilter)?;
                 }
 
                 let mut vmm = Vmm::new(
@@ -425,6 +540,8 @@ pub fn start_vmm_thread(
                     vmm_seccomp_action,
                     hypervisor,
                     exit_event,
+                    hooks,
+                    vmm_jobs,
                 )?;
 
                 vmm.setup_signal_handler()?;
@@ -456,6 +573,39 @@ pub fn start_vmm_thread(
         None => None,
     };
 
+    // The token is required before the first request gets served
+    if let Some(http_token_path) = &http_token_path {
+        api::http::auth::set_token_from_file(http_token_path).map_err(Error::HttpApiToken)?;
+    }
+
+    api::http::jobs::set_jobs(jobs);
+
+    // Clients can ask for the events as soon as the HTTP API is served
+    if let Some(http_events) = http_events {
+        api::http::events::start_http_events_thread(
+            http_events,
+            seccomp_action,
+            exit_event.try_clone().map_err(Error::EventFdClone)?,
+            hypervisor_type,
+        )?;
+    }
+
+    // Only one of the HTTP API sockets is provided
+    #[cfg(feature = "http_tls")]
+    let http_tls_handle = match &http_tls {
+        Some(http_tls) => Some(api::start_http_tls_thread(
+            http_tls,
+            api_event_clone.try_clone().map_err(Error::EventFdClone)?,
+            api_sender.clone(),
+            seccomp_action,
+            exit_event.try_clone().map_err(Error::EventFdClone)?,
+            hypervisor_type,
+        )?),
+        None => None,
+    };
+    #[cfg(not(feature = "http_tls"))]
+    let http_tls_handle = None;
+
     let http_api_handle = if let Some(http_path) = http_path {
         Some(api::start_http_path_thread(
             http_path,
@@ -474,8 +624,17 @@ pub fn start_vmm_thread(
             exit_event,
             hypervisor_type,
         )?)
+    } else if let Some(http_vsock) = http_vsock {
+        Some(api::start_http_vsock_thread(
+            &http_vsock,
+            api_event_clone,
+            api_sender,
+            seccomp_action,
+            exit_event,
+            hypervisor_type,
+        )?)
     } else {
-        None
+        http_tls_handle
     };
 
     #[cfg(feature = "guest_debug")]
@@ -508,6 +667,51 @@ struct VmMigrationConfig {
     memory_manager_data: MemoryManagerSnapshotData,
 }
 
+fn apply_config_change(config: &mut VmConfig, change: &StagedConfigChange) {
+    match change {
+        StagedConfigChange::BootParams(boot_params) => {
+            let payload = config.payload.get_or_insert(PayloadConfig {
+                firmware: None,
+                kernel: None,
+                cmdline: None,
+                initramfs: None,
+                #[cfg(feature = "igvm")]
+                igvm: None,
+                #[cfg(feature = "sev_snp")]
+                host_data: None,
+            });
+            if let Some(firmware) = &boot_params.firmware {
+                payload.firmware = Some(firmware.clone());
+            }
+            if let Some(kernel) = &boot_params.kernel {
+                payload.kernel = Some(kernel.clone());
+            }
+            if let Some(cmdline) = &boot_params.cmdline {
+                payload.cmdline = Some(cmdline.clone());
+            }
+            if let Some(initramfs) = &boot_params.initramfs {
+                payload.initramfs = Some(initramfs.clone());
+            }
+        }
+        StagedConfigChange::Resize(resize) => {
+            if let Some(desired_vcpus) = resize.desired_vcpus {
+                // Contrary to a hotplug, a new boot can go beyond the
+                // maximum number of vCPUs.
+                config.cpus.boot_vcpus = desired_vcpus;
+                config.cpus.max_vcpus = config.cpus.max_vcpus.max(desired_vcpus);
+            }
+            if let Some(desired_ram) = resize.desired_ram {
+                config.memory.size = desired_ram;
+            }
+            if let Some(desired_balloon) = resize.desired_balloon {
+                if let Some(balloon_config) = &mut config.balloon {
+                    balloon_config.size = desired_balloon;
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VmmVersionInfo {
     pub build_version: String,
@@ -524,7 +728,7 @@ impl VmmVersionInfo {
 }
 
 pub struct VmmThreadHandle {
-    pub thread_handle: thread::JoinHandle<Result<()>>,
+    pub thread_handle: thread::JoinHandle<Result<Option<ShutdownReason>>>,
     #[cfg(feature = "dbus_api")]
     pub dbus_shutdown_chs: Option<DBusApiShutdownChannels>,
     pub http_api_handle: Option<HttpApiHandle>,
@@ -548,6 +752,51 @@ pub struct Vmm {
     signals: Option<Handle>,
     threads: Vec<thread::JoinHandle<()>>,
     original_termios_opt: Arc<Mutex<Option<termios>>>,
+    staged_changes: Vec<StagedConfigChange>,
+    // Set by the VMM itself before signalling the exit event
+    pending_shutdown_reason: Arc<Mutex<Option<ShutdownReason>>>,
+    // Why the VM was last shut down or rebooted
+    shutdown_reason: Option<ShutdownReason>,
+    // Fires when the restart policy brings the VM back up
+    restart_timer: TimerFd,
+    // Consecutive restarts performed by the restart policy
+    restarts: u32,
+    // VM to restore once the process is restored by CRIU
+    checkpoint: Option<fds::Checkpoint>,
+    // When the restart policy last brought the VM back up
+    last_restart: Option<Instant>,
+    hooks: Option<Hooks>,
+    // Shared with the HTTP API, which answers the queries about the jobs
+    // without involving the VMM thread
+    jobs: Arc<Mutex<jobs::Jobs>>,
+    // Signalled by the job thread once the job has completed
+    job_evt: EventFd,
+    // Job running on its own thread, possibly with the VM lent to it
+    running_job: Option<RunningJob>,
+    // Jobs waiting for the running one to complete
+    queued_jobs: VecDeque<(u64, JobRequest)>,
+    // Requests waiting for the VM to be handed back by the running job
+    deferred_requests: VecDeque<ApiRequest>,
+    // Events about the VM lent to the running job, raised again once the
+    // VM is handed back
+    deferred_events: Vec<EpollDispatch>,
+}
+
+// Work of a job, run on its own thread
+enum JobWork {
+    // Needs the VM, lent to the job thread until the job completes
+    Vm(Box<dyn FnOnce(&mut Vm, &AtomicBool) -> result::Result<(), String> + Send>),
+    // Got all it needs, the VM staying available in the meantime
+    Detached(Box<dyn FnOnce(&AtomicBool) -> result::Result<(), String> + Send>),
+}
+
+struct RunningJob {
+    id: u64,
+    // Hands the lent VM back along with the outcome of the job
+    thread: thread::JoinHandle<(Option<Vm>, result::Result<(), String>)>,
+    vm_lent: bool,
+    // Raised to ask the job to stop
+    cancel: Arc<AtomicBool>,
 }
 
 impl Vmm {
@@ -556,6 +805,7 @@ impl Vmm {
     fn signal_handler(
         mut signals: Signals,
         original_termios_opt: Arc<Mutex<Option<termios>>>,
+        shutdown_reason: &Mutex<Option<ShutdownReason>>,
         exit_evt: &EventFd,
     ) {
         for sig in &Self::HANDLED_SIGNALS {
@@ -565,6 +815,9 @@ impl Vmm {
         for signal in signals.forever() {
             match signal {
                 SIGTERM | SIGINT => {
+                    if let Ok(mut reason) = shutdown_reason.lock() {
+                        reason.replace(ShutdownReason::Signal);
+                    }
                     if exit_evt.write(1).is_err() {
                         // Resetting the terminal is usually done as the VMM exits
                         if let Ok(lock) = original_termios_opt.lock() {
@@ -593,6 +846,7 @@ impl Vmm {
                 self.signals = Some(signals.handle());
                 let exit_evt = self.exit_evt.try_clone().map_err(Error::EventFdClone)?;
                 let original_termios_opt = Arc::clone(&self.original_termios_opt);
+                let shutdown_reason = Arc::clone(&self.pending_shutdown_reason);
 
                 let signal_handler_seccomp_filter = get_seccomp_filter(
                     &self.seccomp_action,
@@ -614,7 +868,12 @@ impl Vmm {
                                 }
                             }
                             std::panic::catch_unwind(AssertUnwindSafe(|| {
-                                Vmm::signal_handler(signals, original_termios_opt, &exit_evt);
+                                Vmm::signal_handler(
+                                    signals,
+                                    original_termios_opt,
+                                    &shutdown_reason,
+                                    &exit_evt,
+                                );
                             }))
                             .map_err(|_| {
                                 error!("vmm signal_handler thread panicked");
@@ -638,10 +897,14 @@ impl Vmm {
         seccomp_action: SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         exit_evt: EventFd,
+        hooks: Option<Hooks>,
+        jobs: Arc<Mutex<jobs::Jobs>>,
     ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let restart_timer = TimerFd::new().map_err(Error::RestartTimer)?;
+        let job_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         epoll
             .add_event(&exit_evt, EpollDispatch::Exit)
@@ -659,6 +922,14 @@ impl Vmm {
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&restart_timer, EpollDispatch::Restart)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&job_evt, EpollDispatch::Job)
+            .map_err(Error::Epoll)?;
+
         #[cfg(feature = "guest_debug")]
         epoll
             .add_event(&debug_evt, EpollDispatch::Debug)
@@ -682,9 +953,715 @@ impl Vmm {
             signals: None,
             threads: vec![],
             original_termios_opt: Arc::new(Mutex::new(None)),
+            staged_changes: Vec::new(),
+            pending_shutdown_reason: Arc::new(Mutex::new(None)),
+            shutdown_reason: None,
+            restart_timer,
+            restarts: 0,
+            checkpoint: None,
+            last_restart: None,
+            hooks,
+            jobs,
+            job_evt,
+            running_job: None,
+            queued_jobs: VecDeque::new(),
+            deferred_requests: VecDeque::new(),
+            deferred_events: Vec::new(),
+        })
+    }
+
+    // Find out what signalled the exit or reset event, falling back onto
+    // `default` when nothing recorded it.
+    fn take_shutdown_reason(&mut self, default: ShutdownReason) -> ShutdownReason {
+        let pending = self.pending_shutdown_reason.lock().unwrap().take();
+        let vm_reason = self.vm.as_ref().and_then(|vm| vm.take_shutdown_reason());
+        pending.or(vm_reason).unwrap_or(default)
+    }
+
+    // Opens where the snapshot goes and checks nothing prevents it, returning
+    // what takes the snapshot of the VM.
+    fn snapshot_job(
+        &self,
+        config: VmSnapshotConfig,
+    ) -> result::Result<
+        impl FnOnce(&mut Vm, &AtomicBool) -> result::Result<(), VmError> + Send,
+        VmError,
+    > {
+        let VmSnapshotConfig {
+            destination_url,
+            key_fd,
+            compress,
+        } = config;
+
+        // The key is read first for its fd to be closed whatever happens, as
+        // is the stream opened.
+        let key = key_fd
+            .map(SnapshotKey::from_fd)
+            .transpose()
+            .map_err(VmError::SnapshotKey)?;
+        let stream = snapshot_stream::is_stream_url(&destination_url)
+            .then(|| SnapshotWriter::open(&destination_url))
+            .transpose()
+            .map_err(VmError::SnapshotStream)?;
+        if key.is_some() && stream.is_some() {
+            return Err(VmError::SnapshotSend(MigratableError::MigrateSend(
+                anyhow!("Encrypted snapshots can't be streamed"),
+            )));
+        }
+        if compress && stream.is_some() {
+            return Err(VmError::SnapshotSend(MigratableError::MigrateSend(
+                anyhow!("Compressed snapshots can't be streamed"),
+            )));
+        }
+        if compress && !snapshot_compression::is_supported() {
+            return Err(VmError::SnapshotSend(MigratableError::MigrateSend(
+                snapshot_compression::Error::NotSupported.into(),
+            )));
+        }
+
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        // Fail early rather than leaving a partial snapshot behind.
+        if let Some(blocker) = vm.migration_blockers().snapshot.first() {
+            return Err(VmError::Snapshot(MigratableError::Snapshot(anyhow!(
+                "Snapshot prevented by {}",
+                blocker
+            ))));
+        }
+
+        Ok(move |vm: &mut Vm, cancel: &AtomicBool| {
+            let snapshot = vm.snapshot().map_err(VmError::Snapshot)?;
+            if cancel.load(Ordering::SeqCst) {
+                return Err(VmError::SnapshotSend(MigratableError::MigrateSend(
+                    anyhow!("Snapshot cancelled"),
+                )));
+            }
+            match stream {
+                Some(stream) => vm.stream_snapshot(&snapshot, stream),
+                None => vm.send_snapshot(&snapshot, &destination_url, key.as_ref(), compress),
+            }
+            .map_err(VmError::SnapshotSend)
+        })
+    }
+
+    // Checks the profile can be gathered, returning what gathers it and
+    // writes it to the destination.
+    #[cfg(feature = "pprof")]
+    fn pprof_job(
+        &self,
+        pprof_data: VmmPprofData,
+    ) -> result::Result<impl FnOnce(&AtomicBool) -> result::Result<(), VmError> + Send, VmError>
+    {
+        let path = pprof_data
+            .destination_url
+            .strip_prefix("file://")
+            .ok_or_else(|| VmError::InvalidPprofUrl(pprof_data.destination_url.clone()))?
+            .to_string();
+        if pprof_data.kind == PprofKind::Cpu
+            && !matches!(
+                self.seccomp_action,
+                SeccompAction::Allow | SeccompAction::Log
+            )
+        {
+            return Err(VmError::Pprof(profile::PprofError::SeccompEnabled));
+        }
+
+        Ok(move |cancel: &AtomicBool| {
+            let profile = match pprof_data.kind {
+                PprofKind::Cpu => profile::cpu_pprof(
+                    Duration::from_secs(
+                        pprof_data
+                            .duration_s
+                            .unwrap_or(profile::PPROF_DEFAULT_DURATION_S),
+                    ),
+                    pprof_data
+                        .frequency
+                        .unwrap_or(profile::PPROF_DEFAULT_FREQUENCY),
+                    cancel,
+                ),
+                PprofKind::Heap => profile::heap_pprof(),
+            }
+            .map_err(VmError::Pprof)?;
+
+            std::fs::write(&path, profile).map_err(VmError::Profile)
+        })
+    }
+
+    // Checks the VM can be migrated, returning what sends it to the
+    // destination and shuts it down once migrated.
+    fn send_migration_job(
+        &self,
+        send_data_migration: VmSendMigrationData,
+    ) -> result::Result<
+        impl FnOnce(&mut Vm, &AtomicBool) -> result::Result<(), MigratableError> + Send,
+        MigratableError,
+    > {
+        info!(
+            "Sending migration: destination_url = {}, local = {}",
+            send_data_migration.destination_url, send_data_migration.local
+        );
+
+        let vm = self
+            .vm
+            .as_ref()
+            .ok_or_else(|| MigratableError::MigrateSend(anyhow!("VM is not running")))?;
+
+        if !vm.get_config().lock().unwrap().backed_by_shared_memory() && send_data_migration.local {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Local migration requires shared memory or hugepages enabled"
+            )));
+        }
+
+        // Fail before anything is sent to the destination.
+        if let Some(blocker) = vm.migration_blockers().migration.first() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Live migration prevented by {}",
+                blocker
+            )));
+        }
+
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        let hypervisor = self.hypervisor.clone();
+        let pending_shutdown_reason = self.pending_shutdown_reason.clone();
+        let exit_evt = self.exit_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error cloning the exit event: {:?}", e))
+        })?;
+
+        Ok(move |vm: &mut Vm, cancel: &AtomicBool| {
+            Self::send_migration(
+                vm,
+                #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+                hypervisor,
+                send_data_migration,
+                cancel,
+            )
+            .map_err(|migration_err| {
+                error!("Migration failed: {:?}", migration_err);
+
+                // Stop logging dirty pages
+                if let Err(e) = vm.stop_dirty_log() {
+                    return e;
+                }
+
+                if vm.get_state().unwrap() == VmState::Paused {
+                    if let Err(e) = vm.resume() {
+                        return e;
+                    }
+                }
+
+                migration_err
+            })?;
+
+            // Shutdown the VM after the migration succeeded
+            pending_shutdown_reason
+                .lock()
+                .unwrap()
+                .replace(ShutdownReason::Migration);
+            exit_evt.write(1).map_err(|e| {
+                MigratableError::MigrateSend(anyhow!(
+                    "Failed shutting down the VM after migration: {:?}",
+                    e
+                ))
+            })
         })
     }
 
+    // Checks the request can be run as a job, returning what runs it once
+    // the VM is lent to the job thread.
+    fn job_work(&self, request: JobRequest) -> result::Result<JobWork, String> {
+        match request {
+            JobRequest::Snapshot(config) => {
+                let snapshot = self.snapshot_job(config).map_err(|e| e.to_string())?;
+                Ok(JobWork::Vm(Box::new(move |vm, cancel| {
+                    snapshot(vm, cancel).map_err(|e| e.to_string())
+                })))
+            }
+            JobRequest::SendMigration(data) => {
+                let migrate = self.send_migration_job(data).map_err(|e| e.to_string())?;
+                Ok(JobWork::Vm(Box::new(move |vm, cancel| {
+                    migrate(vm, cancel).map_err(|e| e.to_string())
+                })))
+            }
+            JobRequest::DumpMemory(data) => {
+                let dump = self
+                    .vm
+                    .as_ref()
+                    .ok_or(VmError::VmNotRunning)
+                    .and_then(|vm| vm.memory_dump(&data.destination_url, data.offset, data.length))
+                    .map_err(|e| e.to_string())?;
+                Ok(JobWork::Detached(Box::new(move |cancel| {
+                    dump.write(cancel).map_err(|e| e.to_string())
+                })))
+            }
+            #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+            JobRequest::Coredump(data) => {
+                self.vm
+                    .as_ref()
+                    .ok_or_else(|| VmError::VmNotRunning.to_string())?;
+                Ok(JobWork::Vm(Box::new(move |vm, _| {
+                    vm.coredump(&data.destination_url)
+                        .map_err(|e| VmError::Coredump(e).to_string())
+                })))
+            }
+            #[cfg(feature = "pprof")]
+            JobRequest::Pprof(data) => {
+                let pprof = self.pprof_job(data).map_err(|e| e.to_string())?;
+                Ok(JobWork::Detached(Box::new(move |cancel| {
+                    pprof(cancel).map_err(|e| e.to_string())
+                })))
+            }
+        }
+    }
+
+    // Runs the job on its own thread, lending it the VM until it completes
+    // when the job needs it.
+    fn start_job(
+        &mut self,
+        id: u64,
+        work: JobWork,
+        cancel: Arc<AtomicBool>,
+    ) -> result::Result<(), String> {
+        let job_evt = self.job_evt.try_clone().map_err(|e| e.to_string())?;
+        let job_cancel = cancel.clone();
+        let vm_lent = matches!(work, JobWork::Vm(_));
+        let (vm_sender, vm_receiver) = channel();
+        let thread = thread::Builder::new()
+            .name("vmm_job".to_string())
+            .spawn(move || {
+                let (vm, result) = match work {
+                    JobWork::Vm(work) => {
+                        // The VM is only handed over once the thread is up,
+                        // staying with the VMM otherwise.
+                        match vm_receiver.recv() {
+                            Ok(mut vm) => {
+                                let result = work(&mut vm, &job_cancel);
+                                (Some(vm), result)
+                            }
+                            Err(e) => (None, Err(e.to_string())),
+                        }
+                    }
+                    JobWork::Detached(work) => (None, work(&job_cancel)),
+                };
+                job_evt.write(1).ok();
+                (vm, result)
+            })
+            .map_err(|e| format!("Error spawning the job thread: {}", e))?;
+
+        if vm_lent {
+            let vm = self
+                .vm
+                .take()
+                .ok_or_else(|| VmError::VmNotRunning.to_string())?;
+            let boot_listener = vm.boot_listener_fd();
+            if let Err(SendError(vm)) = vm_sender.send(vm) {
+                self.vm = Some(vm);
+                return Err("The job thread exited early".to_string());
+            }
+            // Polled again once the VM is handed back
+            if let Some(fd) = boot_listener {
+                self.epoll.remove_event(&fd).ok();
+            }
+        }
+
+        self.running_job = Some(RunningJob {
+            id,
+            thread,
+            vm_lent,
+            cancel,
+        });
+
+        Ok(())
+    }
+
+    // Whether the VM is lent to the running job, the events about the VM and
+    // the requests then waiting for the VM to be handed back.
+    fn vm_lent(&self) -> bool {
+        self.running_job.as_ref().is_some_and(|job| job.vm_lent)
+    }
+
+    // Takes the VM back from the job which completed, then goes through the
+    // jobs, events and requests which waited for it. Returns whether the VMM
+    // must exit.
+    fn end_job(&mut self) -> Result<bool> {
+        let Some(job) = self.running_job.take() else {
+            return Ok(false);
+        };
+        let (vm, result) = job.thread.join().map_err(Error::ThreadCleanup)?;
+        if job.vm_lent {
+            self.vm = vm;
+            if let Err(e) = self.watch_boot_listener() {
+                warn!("Error polling the boot listener: {:?}", e);
+            }
+        }
+        self.job_complete(job.id, result);
+
+        for event in self.deferred_events.drain(..) {
+            match event {
+                EpollDispatch::Exit => self.exit_evt.write(1),
+                EpollDispatch::Reset => self.reset_evt.write(1),
+                EpollDispatch::ActivateVirtioDevices => self.activate_evt.write(1),
+                #[cfg(feature = "guest_debug")]
+                EpollDispatch::Debug => self.debug_evt.write(1),
+                _ => Ok(()),
+            }
+            .map_err(Error::EventFdWrite)?;
+        }
+
+        while self.running_job.is_none() {
+            let Some((id, request)) = self.queued_jobs.pop_front() else {
+                break;
+            };
+            self.job_run(id, request);
+        }
+
+        // A request may start another job, the following ones waiting again
+        while !self.vm_lent() {
+            let Some(api_request) = self.deferred_requests.pop_front() else {
+                break;
+            };
+            if api_request(self)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Keeps an event about the VM lent to the running job for when the VM is
+    // handed back, the VMM exiting asking the job to stop.
+    fn defer_event(&mut self, event: EpollDispatch) {
+        if let Some(job) = &self.running_job {
+            if event == EpollDispatch::Exit {
+                job.cancel.store(true, Ordering::SeqCst);
+            }
+        }
+        self.deferred_events.push(event);
+    }
+
+    fn job_complete(&mut self, id: u64, result: result::Result<(), String>) {
+        let succeeded = result.is_ok();
+        self.jobs.lock().unwrap().complete(id, result);
+        event!(
+            "vmm",
+            "job-completed",
+            "id",
+            id.to_string(),
+            "succeeded",
+            succeeded.to_string()
+        );
+    }
+
+    // Arm the restart timer if the restart policy of the VM asks for the
+    // guest to be brought back up, returning whether it has been armed.
+    fn schedule_restart(&mut self, reason: ShutdownReason) -> Result<bool> {
+        let Some(policy) = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().restart_policy.clone())
+        else {
+            return Ok(false);
+        };
+
+        let restart = match policy.mode {
+            RestartMode::No => false,
+            RestartMode::OnFailure => reason.is_failure(),
+            RestartMode::Always => !matches!(
+                reason,
+                ShutdownReason::Api | ShutdownReason::Signal | ShutdownReason::Migration
+            ),
+        };
+        if !restart {
+            return Ok(false);
+        }
+
+        // A guest which stayed up long enough since its last restart isn't
+        // crashing in a loop, the restarts count starting over.
+        if self
+            .last_restart
+            .is_some_and(|restarted| restarted.elapsed() >= RESTARTS_RESET_UPTIME)
+        {
+            self.restarts = 0;
+        }
+
+        if policy.max.is_some_and(|max| self.restarts >= max) {
+            warn!("Restart limit reached, not restarting the VM");
+            event!(
+                "vm",
+                "restart-limit-reached",
+                "restarts",
+                self.restarts.to_string()
+            );
+            return Ok(false);
+        }
+
+        self.restarts += 1;
+        // Double the delay on each restart so that a guest crashing right
+        // after booting does not keep the host busy.
+        let delay_ms = policy
+            .backoff_ms
+            .saturating_mul(1 << (self.restarts - 1).min(16))
+            .max(1);
+        self.restart_timer
+            .reset(Duration::from_millis(delay_ms), None)
+            .map_err(Error::RestartTimer)?;
+
+        info!(
+            "Restarting the VM in {} ms after {} (attempt {})",
+            delay_ms, reason, self.restarts
+        );
+        event!(
+            "vm",
+            "restart-scheduled",
+            "reason",
+            reason.to_string(),
+            "attempt",
+            self.restarts.to_string(),
+            "delay_ms",
+            delay_ms.to_string()
+        );
+
+        Ok(true)
+    }
+
+    // Poll the socket the guest agent connects to once booted, if any. It is
+    // closed, hence removed from the epoll set, along with the VM.
+    fn watch_boot_listener(&mut self) -> result::Result<(), VmError> {
+        let Some(fd) = self.vm.as_ref().and_then(|vm| vm.boot_listener_fd()) else {
+            return Ok(());
+        };
+
+        match self.epoll.add_event(&fd, EpollDispatch::BootDetect) {
+            // Already polled, vm.boot resuming a paused VM
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            r => r.map_err(VmError::BootDetect),
+        }
+    }
+
+    // Apply the reset policy of the VM, returning whether the VMM must exit.
+    fn handle_reset(&mut self, reason: ShutdownReason) -> Result<bool> {
+        let Some(vm) = self.vm.as_mut() else {
+            warn!("Reset event received while no VM is running. Ignoring.");
+            return Ok(false);
+        };
+
+        // A crashed guest is left to the restart policy, if any, so that the
+        // restarts are rate limited.
+        let supervised = vm
+            .get_config()
+            .lock()
+            .unwrap()
+            .restart_policy
+            .as_ref()
+            .is_some_and(|policy| policy.mode != RestartMode::No);
+        if supervised && reason.is_failure() {
+            self.shutdown_vm(reason).map_err(Error::VmmShutdown)?;
+            if self.schedule_restart(reason)? {
+                return Ok(false);
+            }
+            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+            return Ok(true);
+        }
+
+        let on_reset = vm
+            .get_config()
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map(|platform| platform.on_reset)
+            .unwrap_or_default();
+
+        match on_reset {
+            ResetPolicy::Restart => self.reboot_vm(reason).map_err(Error::VmReboot)?,
+            ResetPolicy::Shutdown => {
+                self.shutdown_vm(reason).map_err(Error::VmmShutdown)?;
+                self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+                return Ok(true);
+            }
+            ResetPolicy::Pause => {
+                // A guest resetting through several devices at once signals
+                // the event more than once.
+                if vm.get_state().map_err(Error::VmReboot)? != VmState::Running {
+                    warn!("Spurious reset event received. Ignoring.");
+                    return Ok(false);
+                }
+                vm.halt().map_err(Error::VmReboot)?;
+                self.shutdown_reason = Some(reason);
+                event!("vm", "reset", "reason", reason.to_string());
+            }
+            ResetPolicy::Event => {
+                self.shutdown_vm(reason).map_err(Error::VmReboot)?;
+                event!("vm", "reset", "reason", reason.to_string());
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn shutdown_vm(&mut self, reason: ShutdownReason) -> result::Result<(), VmError> {
+        if self.vm.is_none() {
+            return Err(VmError::VmNotRunning);
+        }
+
+        let properties = [("reason", reason.to_string())];
+        self.run_hook(HookPoint::PreShutdown, &properties)?;
+
+        let r = self.vm.take().unwrap().shutdown();
+
+        if r.is_ok() {
+            self.shutdown_reason = Some(reason);
+            event!("vm", "shutdown", "reason", reason.to_string());
+            self.run_hook(HookPoint::PostShutdown, &properties)?;
+        }
+
+        r
+    }
+
+    // Runs the hook configured for `point`, whose failure is only reported
+    // unless the hook is meant to abort the operation.
+    fn run_hook(
+        &self,
+        point: HookPoint,
+        properties: &[(&str, String)],
+    ) -> result::Result<(), VmError> {
+        let Some(hooks) = &self.hooks else {
+            return Ok(());
+        };
+
+        match hooks.run(point, properties) {
+            Ok(()) => Ok(()),
+            Err(e) if point.is_blocking() => Err(VmError::Hook(e)),
+            Err(e) => {
+                warn!("Error running the {} hook: {}", point, e);
+                Ok(())
+            }
+        }
+    }
+
+    // Identifiers of the devices of the VM, used to associate the threads of
+    // the VMM with the device they serve.
+    fn device_ids(&self) -> Vec<String> {
+        self.vm
+            .as_ref()
+            .map(|vm| {
+                vm.device_tree()
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(id, _)| id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn reboot_vm(&mut self, reason: ShutdownReason) -> result::Result<(), VmError> {
+        event!("vm", "rebooting", "reason", reason.to_string());
+
+        // First we stop the current VM
+        let (config, serial_pty, console_pty, debug_console_pty, console_resize_pipe) =
+            if let Some(mut vm) = self.vm.take() {
+                let config = vm.get_config();
+                let serial_pty = vm.serial_pty();
+                let console_pty = vm.console_pty();
+                let debug_console_pty = vm.debug_console_pty();
+                let console_resize_pipe = vm
+                    .console_resize_pipe()
+                    .as_ref()
+                    .map(|pipe| pipe.try_clone().unwrap());
+                vm.shutdown()?;
+                (
+                    config,
+                    serial_pty,
+                    console_pty,
+                    debug_console_pty,
+                    console_resize_pipe,
+                )
+            } else {
+                return Err(VmError::VmNotCreated);
+            };
+
+        self.shutdown_reason = Some(reason);
+
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        #[cfg(feature = "guest_debug")]
+        let debug_evt = self
+            .vm_debug_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let activate_evt = self
+            .activate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+
+        // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
+        // an event sitting in the shared reset_evt. Without doing this we get very early reboots
+        // during the boot process.
+        if self.reset_evt.read().is_ok() {
+            warn!("Spurious second reset event received. Ignoring.");
+        }
+
+        // Apply the changes staged until the next boot, the VM being rebooted
+        // with its current configuration if they can't be applied.
+        if let Err(e) = self.apply_staged_changes(&config) {
+            error!("Error applying the staged configuration changes: {}", e);
+        }
+
+        // Then we create the new VM
+        let mut vm = Vm::new(
+            config,
+            exit_evt,
+            reset_evt,
+            #[cfg(feature = "guest_debug")]
+            debug_evt,
+            &self.seccomp_action,
+            self.hypervisor.clone(),
+            activate_evt,
+            serial_pty,
+            console_pty,
+            debug_console_pty,
+            console_resize_pipe,
+            Arc::clone(&self.original_termios_opt),
+            None,
+            None,
+            None,
+            None,
+        )?;
+
+        // And we boot it
+        vm.boot()?;
+
+        self.vm = Some(vm);
+        self.watch_boot_listener()?;
+
+        event!("vm", "rebooted");
+        self.run_hook(HookPoint::PostReboot, &[("reason", reason.to_string())])?;
+
+        Ok(())
+    }
+
+    // Atomically apply the changes staged for the next boot: either all of
+    // them make it into the configuration, or none.
+    fn apply_staged_changes(
+        &mut self,
+        vm_config: &Arc<Mutex<VmConfig>>,
+    ) -> result::Result<(), VmError> {
+        if self.staged_changes.is_empty() {
+            return Ok(());
+        }
+
+        // The changes are kept staged if the resulting configuration is invalid
+        let mut config = vm_config.lock().unwrap().clone();
+        for change in self.staged_changes.iter() {
+            info!("Applying staged configuration change: {:?}", change);
+            apply_config_change(&mut config, change);
+        }
+        config.validate().map_err(VmError::ConfigValidation)?;
+        *vm_config.lock().unwrap() = config;
+        self.staged_changes.clear();
+
+        Ok(())
+    }
+
     fn vm_receive_config<T>(
         &mut self,
         req: &Request,
@@ -729,8 +1706,7 @@ impl Vmm {
             ))
         })?;
 
-        let phys_bits =
-            vm::physical_bits(&self.hypervisor, config.lock().unwrap().cpus.max_phys_bits);
+        let phys_bits = vm::physical_bits(&self.hypervisor, &config.lock().unwrap());
 
         let memory_manager = MemoryManager::new(
             vm,
@@ -890,12 +1866,27 @@ impl Vmm {
         Ok(true)
     }
 
+    // Gives up on the migration when asked to stop.
+    fn check_migration_cancelled(
+        cancel: &AtomicBool,
+        socket: &mut UnixStream,
+    ) -> result::Result<(), MigratableError> {
+        if !cancel.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        Request::abandon().write_to(socket)?;
+        Response::read_from(socket).ok();
+        Err(MigratableError::MigrateSend(anyhow!("Migration cancelled")))
+    }
+
     fn send_migration(
         vm: &mut Vm,
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))] hypervisor: Arc<
             dyn hypervisor::Hypervisor,
         >,
         send_data_migration: VmSendMigrationData,
+        cancel: &AtomicBool,
     ) -> result::Result<(), MigratableError> {
         let path = Self::socket_url_to_path(&send_data_migration.destination_url)?;
         let mut socket = UnixStream::connect(path).map_err(|e| {
@@ -926,8 +1917,7 @@ impl Vmm {
             };
 
             let amx = vm_config.lock().unwrap().cpus.features.amx;
-            let phys_bits =
-                vm::physical_bits(&hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
+            let phys_bits = vm::physical_bits(&hypervisor, &vm_config.lock().unwrap());
             arch::generate_common_cpuid(
                 &hypervisor,
                 &arch::CpuidConfig {
@@ -937,6 +1927,7 @@ impl Vmm {
                     #[cfg(feature = "tdx")]
                     tdx: false,
                     amx,
+                    evmcs: vm_config.lock().unwrap().cpus.features.evmcs,
                 },
             )
             .map_err(|e| {
@@ -971,6 +1962,7 @@ impl Vmm {
 
         // Let every Migratable object know about the migration being started.
         vm.start_migration()?;
+        Self::check_migration_cancelled(cancel, &mut socket)?;
 
         if send_data_migration.local {
             // Now pause VM
@@ -1000,6 +1992,7 @@ impl Vmm {
             // Try at most 5 passes of dirty memory sending
             const MAX_DIRTY_MIGRATIONS: usize = 5;
             for i in 0..MAX_DIRTY_MIGRATIONS {
+                Self::check_migration_cancelled(cancel, &mut socket)?;
                 info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
                 if !Self::vm_maybe_send_dirty_pages(vm, &mut socket)? {
                     break;
@@ -1067,7 +2060,7 @@ impl Vmm {
         let dest_cpuid = &{
             let vm_config = &src_vm_config.lock().unwrap();
 
-            let phys_bits = vm::physical_bits(&self.hypervisor, vm_config.cpus.max_phys_bits);
+            let phys_bits = vm::physical_bits(&self.hypervisor, vm_config);
             arch::generate_common_cpuid(
                 &self.hypervisor.clone(),
                 &arch::CpuidConfig {
@@ -1077,6 +2070,7 @@ impl Vmm {
                     #[cfg(feature = "tdx")]
                     tdx: false,
                     amx: vm_config.cpus.features.amx,
+                    evmcs: vm_config.cpus.features.evmcs,
                 },
             )
             .map_err(|e| {
@@ -1095,7 +2089,7 @@ impl Vmm {
         &mut self,
         api_receiver: Rc<Receiver<ApiRequest>>,
         #[cfg(feature = "guest_debug")] gdb_receiver: Rc<Receiver<gdb::GdbRequest>>,
-    ) -> Result<()> {
+    ) -> Result<Option<ShutdownReason>> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -1126,19 +2120,74 @@ impl Vmm {
                         let event = event.data;
                         warn!("Unknown VMM loop event: {}", event);
                     }
+                    EpollDispatch::Exit if self.vm_lent() => {
+                        // Consume the event.
+                        self.exit_evt.read().map_err(Error::EventFdRead)?;
+                        self.defer_event(EpollDispatch::Exit);
+                    }
                     EpollDispatch::Exit => {
                         info!("VM exit event");
                         // Consume the event.
                         self.exit_evt.read().map_err(Error::EventFdRead)?;
+                        let reason = self.take_shutdown_reason(ShutdownReason::Error);
+                        if self.vm.is_some() {
+                            self.shutdown_vm(reason).map_err(Error::VmmShutdown)?;
+                            if self.schedule_restart(reason)? {
+                                continue;
+                            }
+                        } else {
+                            self.shutdown_reason = Some(reason);
+                        }
                         self.vmm_shutdown().map_err(Error::VmmShutdown)?;
 
                         break 'outer;
                     }
+                    EpollDispatch::Reset if self.vm_lent() => {
+                        // Consume the event.
+                        self.reset_evt.read().map_err(Error::EventFdRead)?;
+                        self.defer_event(EpollDispatch::Reset);
+                    }
                     EpollDispatch::Reset => {
                         info!("VM reset event");
                         // Consume the event.
                         self.reset_evt.read().map_err(Error::EventFdRead)?;
-                        self.vm_reboot().map_err(Error::VmReboot)?;
+                        let reason = self.take_shutdown_reason(ShutdownReason::GuestReset);
+                        if self.handle_reset(reason)? {
+                            break 'outer;
+                        }
+                    }
+                    EpollDispatch::Restart => {
+                        // Consume the event.
+                        self.restart_timer.wait().map_err(Error::RestartTimer)?;
+                        // The VM may have been deleted or booted again through
+                        // the API in the meantime.
+                        if self.vm_config.is_none() || self.vm.is_some() || self.vm_lent() {
+                            continue;
+                        }
+                        match self.vm_boot(None) {
+                            Ok(()) => {
+                                self.last_restart = Some(Instant::now());
+                                event!("vm", "restarted", "attempt", self.restarts.to_string())
+                            }
+                            Err(e) => {
+                                error!("Error restarting the VM: {:?}", e);
+                                if !self.schedule_restart(ShutdownReason::Error)? {
+                                    self.shutdown_reason = Some(ShutdownReason::Error);
+                                    self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+                                    break 'outer;
+                                }
+                            }
+                        }
+                    }
+                    EpollDispatch::BootDetect => {
+                        if let Some(ref vm) = self.vm {
+                            vm.accept_boot_connection();
+                        }
+                    }
+                    EpollDispatch::ActivateVirtioDevices if self.vm_lent() => {
+                        // Consume the event.
+                        self.activate_evt.read().map_err(Error::EventFdRead)?;
+                        self.defer_event(EpollDispatch::ActivateVirtioDevices);
                     }
                     EpollDispatch::ActivateVirtioDevices => {
                         if let Some(ref vm) = self.vm {
@@ -1157,11 +2206,32 @@ impl Vmm {
                             // Read from the API receiver channel
                             let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
 
+                            // The request waits for the VM to be handed back
+                            // by the running job.
+                            if self.vm_lent() {
+                                self.deferred_requests.push_back(api_request);
+                                continue;
+                            }
+
                             if api_request(self)? {
                                 break 'outer;
                             }
                         }
                     }
+                    EpollDispatch::Job => {
+                        // Consume the event.
+                        self.job_evt.read().map_err(Error::EventFdRead)?;
+                        if self.end_job()? {
+                            break 'outer;
+                        }
+                    }
+                    #[cfg(feature = "guest_debug")]
+                    EpollDispatch::Debug if self.vm_lent() => {
+                        // Consume the events.
+                        for _ in 0..self.debug_evt.read().map_err(Error::EventFdRead)? {
+                            self.defer_event(EpollDispatch::Debug);
+                        }
+                    }
                     #[cfg(feature = "guest_debug")]
                     EpollDispatch::Debug => {
                         // Consume the events.
@@ -1193,13 +2263,83 @@ impl Vmm {
             signals.close();
         }
 
+        // Stop the job which may still be running
+        if let Some(job) = self.running_job.take() {
+            job.cancel.store(true, Ordering::SeqCst);
+            job.thread.join().map_err(Error::ThreadCleanup)?;
+        }
+
         // Wait for all the threads to finish
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
         }
 
-        Ok(())
+        Ok(self.shutdown_reason)
+    }
+}
+
+// Closes the files handed over along with a snapshot request which won't be
+// run.
+fn close_snapshot_fds(config: &VmSnapshotConfig) {
+    let url_fd = config
+        .destination_url
+        .strip_prefix("fd:")
+        .and_then(|fd| fd.parse::<RawFd>().ok());
+    for fd in config.key_fd.into_iter().chain(url_fd) {
+        // SAFETY: the file descriptors were handed over along with the
+        // request, and nothing else refers to them.
+        drop(unsafe { File::from_raw_fd(fd) });
+    }
+}
+
+// Closes the file handed over along with a disk which failed to be added,
+// nothing else owning it.
+fn close_disk_fd(vm_config: Option<&Arc<Mutex<VmConfig>>>, fd: Option<RawFd>) {
+    let Some(fd) = fd else {
+        return;
+    };
+    // The disk may have failed once opened, its file being preserved along
+    // with the configuration by then.
+    if let Some(config) = vm_config {
+        if let Some(fds) = config.lock().unwrap().preserved_fds.as_mut() {
+            fds.retain(|preserved_fd| *preserved_fd != fd);
+        }
+    }
+    // SAFETY: the file descriptor was handed over along with the request,
+    // and nothing else refers to it.
+    drop(unsafe { File::from_raw_fd(fd) });
+}
+
+// Split of the CPU time of vCPU threads, in microseconds, labelled by mode.
+fn cpu_time_modes(cpu_time: &VcpuCpuTime) -> [(&'static str, u64); 4] {
+    [
+        ("user", cpu_time.user_time_us),
+        ("system", cpu_time.system_time_us),
+        ("guest", cpu_time.guest_time_us),
+        ("vmm", cpu_time.vmm_time_us),
+    ]
+}
+
+// CPU time consumed by the vCPUs, along with the split of the CPU time of the
+// VMM process between running the guest and running the VMM.
+fn vcpu_stats(vm: &Vm) -> result::Result<VmVcpuStatsResponse, VmError> {
+    let vcpus = vm.vcpus_stats()?;
+    let mut total = VcpuCpuTime::default();
+    for vcpu in vcpus.iter() {
+        total += vcpu.cpu_time;
     }
+    let process = profile::process_cpu_time().map_err(VmError::Profile)?;
+    // The guest time of unplugged vCPUs is lost, hence the VMM time being
+    // overestimated once vCPUs got removed.
+    let vmm_time_us =
+        (process.user_time_us + process.system_time_us).saturating_sub(total.guest_time_us);
+
+    Ok(VmVcpuStatsResponse {
+        vcpus,
+        total,
+        process,
+        vmm_time_us,
+    })
 }
 
 impl RequestHandler for Vmm {
@@ -1207,14 +2347,20 @@ impl RequestHandler for Vmm {
         // We only store the passed VM config.
         // The VM will be created when being asked to boot it.
         if self.vm_config.is_none() {
+            host_checks::check_host(&config.lock().unwrap(), self.hypervisor.hypervisor_type())
+                .map_err(VmError::HostCheck)?;
             self.vm_config = Some(config);
+            self.shutdown_reason = None;
+            self.restarts = 0;
+            self.last_restart = None;
+            event!("vm", "created");
             Ok(())
         } else {
             Err(VmError::VmAlreadyCreated)
         }
     }
 
-    fn vm_boot(&mut self) -> result::Result<(), VmError> {
+    fn vm_boot(&mut self, identity: Option<VmIdentityConfig>) -> result::Result<(), VmError> {
         tracer::start();
         info!("Booting VM");
         event!("vm", "booting");
@@ -1225,6 +2371,8 @@ impl RequestHandler for Vmm {
                 return Err(VmError::VmMissingConfig);
             };
 
+            self.run_hook(HookPoint::PreBoot, &[])?;
+
             // Create a new VM if we don't have one yet.
             if self.vm.is_none() {
                 let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
@@ -1239,9 +2387,10 @@ impl RequestHandler for Vmm {
                     .try_clone()
                     .map_err(VmError::EventFdClone)?;
 
-                if let Some(ref vm_config) = self.vm_config {
+                if let Some(vm_config) = self.vm_config.clone() {
+                    self.apply_staged_changes(&vm_config)?;
                     let vm = Vm::new(
-                        Arc::clone(vm_config),
+                        vm_config,
                         exit_evt,
                         reset_evt,
                         #[cfg(feature = "guest_debug")]
@@ -1257,15 +2406,23 @@ impl RequestHandler for Vmm {
                         None,
                         None,
                         None,
+                        None,
                     )?;
 
                     self.vm = Some(vm);
                 }
             }
 
-            // Now we can boot the VM.
+            // Now we can boot the VM, a ready VM getting its identity.
             if let Some(ref mut vm) = self.vm {
-                vm.boot()
+                let r = if vm.is_ready() {
+                    vm.boot_ready(&identity.unwrap_or_default())
+                } else if identity.is_some() {
+                    Err(VmError::VmNotReady)
+                } else {
+                    vm.boot()
+                };
+                r.and_then(|_| self.watch_boot_listener())
             } else {
                 Err(VmError::VmNotCreated)
             }
@@ -1273,6 +2430,8 @@ impl RequestHandler for Vmm {
         tracer::end();
         if r.is_ok() {
             event!("vm", "booted");
+            seccomp_violations::enforce();
+            self.run_hook(HookPoint::PostBoot, &[])?;
         }
         r
     }
@@ -1287,41 +2446,58 @@ impl RequestHandler for Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            if vm.is_ready() {
+                return Err(VmError::VmReady);
+            }
             vm.resume().map_err(VmError::Resume)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
-        if let Some(ref mut vm) = self.vm {
-            vm.snapshot()
-                .map_err(VmError::Snapshot)
-                .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
-                        .map_err(VmError::SnapshotSend)
-                })
-        } else {
-            Err(VmError::VmNotRunning)
-        }
+    fn vm_snapshot(
+        &mut self,
+        destination_url: &str,
+        key_fd: Option<RawFd>,
+        compress: bool,
+    ) -> result::Result<(), VmError> {
+        let snapshot = self.snapshot_job(VmSnapshotConfig {
+            destination_url: destination_url.to_string(),
+            key_fd,
+            compress,
+        })?;
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        snapshot(vm, &AtomicBool::new(false))
     }
 
     fn vm_restore(&mut self, restore_cfg: RestoreConfig) -> result::Result<(), VmError> {
-        if self.vm.is_some() || self.vm_config.is_some() {
-            return Err(VmError::VmAlreadyCreated);
-        }
+        let key = restore_cfg
+            .key_fd
+            .map(SnapshotKey::from_fd)
+            .transpose()
+            .map_err(VmError::SnapshotKey)?;
 
         let source_url = restore_cfg.source_url.as_path().to_str();
         if source_url.is_none() {
             return Err(VmError::InvalidRestoreSourceUrl);
         }
-        // Safe to unwrap as we checked it was Some(&str).
-        let source_url = source_url.unwrap();
+        // Safe to unwrap as we checked it was Some(&str). Like the key, the
+        // stream is opened first for its fd to be closed whatever happens.
+        let mut source = SnapshotSource::open(source_url.unwrap()).map_err(VmError::Restore)?;
+
+        if self.vm.is_some() || self.vm_config.is_some() {
+            return Err(VmError::VmAlreadyCreated);
+        }
+
+        // Nothing gets read out of a snapshot which doesn't match its manifest.
+        if let Some(key) = &key {
+            verify_snapshot(&source, key).map_err(VmError::Restore)?;
+        }
 
         let vm_config = Arc::new(Mutex::new(
-            recv_vm_config(source_url).map_err(VmError::Restore)?,
+            recv_vm_config(&mut source, key.as_ref()).map_err(VmError::Restore)?,
         ));
-        let snapshot = recv_vm_state(source_url).map_err(VmError::Restore)?;
+        let snapshot = recv_vm_state(&mut source, key.as_ref()).map_err(VmError::Restore)?;
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
@@ -1358,14 +2534,19 @@ impl RequestHandler for Vmm {
             None,
             Arc::clone(&self.original_termios_opt),
             Some(snapshot),
-            Some(source_url),
+            Some(&mut source),
             Some(restore_cfg.prefault),
+            key.as_ref(),
         )?;
         self.vm = Some(vm);
 
         // Now we can restore the rest of the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.restore()
+            if restore_cfg.ready {
+                vm.restore_ready()
+            } else {
+                vm.restore()
+            }
         } else {
             Err(VmError::VmNotCreated)
         }
@@ -1381,92 +2562,11 @@ impl RequestHandler for Vmm {
     }
 
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
-        let r = if let Some(ref mut vm) = self.vm.take() {
-            vm.shutdown()
-        } else {
-            Err(VmError::VmNotRunning)
-        };
-
-        if r.is_ok() {
-            event!("vm", "shutdown");
-        }
-
-        r
+        self.shutdown_vm(ShutdownReason::Api)
     }
 
     fn vm_reboot(&mut self) -> result::Result<(), VmError> {
-        event!("vm", "rebooting");
-
-        // First we stop the current VM
-        let (config, serial_pty, console_pty, debug_console_pty, console_resize_pipe) =
-            if let Some(mut vm) = self.vm.take() {
-                let config = vm.get_config();
-                let serial_pty = vm.serial_pty();
-                let console_pty = vm.console_pty();
-                let debug_console_pty = vm.debug_console_pty();
-                let console_resize_pipe = vm
-                    .console_resize_pipe()
-                    .as_ref()
-                    .map(|pipe| pipe.try_clone().unwrap());
-                vm.shutdown()?;
-                (
-                    config,
-                    serial_pty,
-                    console_pty,
-                    debug_console_pty,
-                    console_resize_pipe,
-                )
-            } else {
-                return Err(VmError::VmNotCreated);
-            };
-
-        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
-        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
-        #[cfg(feature = "guest_debug")]
-        let debug_evt = self
-            .vm_debug_evt
-            .try_clone()
-            .map_err(VmError::EventFdClone)?;
-        let activate_evt = self
-            .activate_evt
-            .try_clone()
-            .map_err(VmError::EventFdClone)?;
-
-        // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
-        // an event sitting in the shared reset_evt. Without doing this we get very early reboots
-        // during the boot process.
-        if self.reset_evt.read().is_ok() {
-            warn!("Spurious second reset event received. Ignoring.");
-        }
-
-        // Then we create the new VM
-        let mut vm = Vm::new(
-            config,
-            exit_evt,
-            reset_evt,
-            #[cfg(feature = "guest_debug")]
-            debug_evt,
-            &self.seccomp_action,
-            self.hypervisor.clone(),
-            activate_evt,
-            serial_pty,
-            console_pty,
-            debug_console_pty,
-            console_resize_pipe,
-            Arc::clone(&self.original_termios_opt),
-            None,
-            None,
-            None,
-        )?;
-
-        // And we boot it
-        vm.boot()?;
-
-        self.vm = Some(vm);
-
-        event!("vm", "rebooted");
-
-        Ok(())
+        self.reboot_vm(ShutdownReason::Api)
     }
 
     fn vm_info(&self) -> result::Result<VmInfoResponse, VmError> {
@@ -1484,13 +2584,20 @@ impl RequestHandler for Vmm {
                     memory_actual_size -= vm.balloon_size();
                 }
 
+                let memory_backing = self.vm.as_ref().map(|vm| vm.memory_backing());
                 let device_tree = self.vm.as_ref().map(|vm| vm.device_tree());
+                let payload_verification =
+                    self.vm.as_ref().and_then(|vm| vm.payload_verification());
 
                 Ok(VmInfoResponse {
                     config,
                     state,
                     memory_actual_size,
+                    memory_backing,
                     device_tree,
+                    payload_verification,
+                    shutdown_reason: self.shutdown_reason,
+                    boot_state: self.vm.as_ref().and_then(|vm| vm.boot_state()),
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -1511,6 +2618,268 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vmm_threads(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        let threads = threads::list_threads(&self.device_ids()).map_err(VmError::ListThreads)?;
+
+        serde_json::to_vec(&VmmThreadsResponse { threads })
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vmm_host_info(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        serde_json::to_vec(&host_info::host_info(self.hypervisor.as_ref()))
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    fn vmm_capabilities(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        serde_json::to_vec(&VmmCapabilitiesResponse {
+            api_version: "v1".to_string(),
+            hypervisor: format!("{:?}", self.hypervisor.hypervisor_type()).to_lowercase(),
+            features: feature_list(),
+            endpoints: api::http::endpoints(),
+        })
+        .map(Some)
+        .map_err(VmError::SerializeJson)
+    }
+
+    fn vmm_fds(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        let fds = fds::list_fds().map_err(VmError::ListFds)?;
+        let checkpointable = fds.iter().all(|fd| fd.checkpointable);
+
+        serde_json::to_vec(&VmmFdsResponse {
+            fds,
+            checkpointable,
+        })
+        .map(Some)
+        .map_err(VmError::SerializeJson)
+    }
+
+    fn vmm_checkpoint(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+        // The VM is read back from the same URL once the process is restored.
+        if !destination_url.starts_with("file://") {
+            return Err(VmError::InvalidCheckpointUrl(destination_url.to_owned()));
+        }
+        if self.checkpoint.is_some() {
+            return Err(VmError::CheckpointPending);
+        }
+
+        let resume = match self.vm {
+            Some(ref mut vm) => {
+                let running = vm.get_state()? == VmState::Running;
+                if running {
+                    vm.pause().map_err(VmError::Pause)?;
+                }
+                running
+            }
+            None => return Err(VmError::VmNotRunning),
+        };
+        if let Err(e) = self.vm_snapshot(destination_url, None, false) {
+            if resume {
+                self.vm_resume()?;
+            }
+            return Err(e);
+        }
+
+        // Deleting the VM closes the descriptors CRIU can't save, along with
+        // the eventfds registered with the hypervisor.
+        self.vm_delete()?;
+        self.checkpoint = Some(fds::Checkpoint {
+            source_url: destination_url.to_owned(),
+            resume,
+        });
+        event!("vmm", "checkpointed");
+
+        // Whatever is left open by the VM, e.g. a VFIO container still
+        // referenced, would make CRIU fail to dump the process. The VM can
+        // still be brought back through vmm.post-restore.
+        let fds = fds::list_fds().map_err(VmError::ListFds)?;
+        if let Some(fd) = fds.iter().find(|fd| !fd.checkpointable) {
+            return Err(VmError::NotCheckpointable(fd.target.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn vmm_post_restore(&mut self) -> result::Result<(), VmError> {
+        let checkpoint = self.checkpoint.as_ref().ok_or(VmError::NoCheckpoint)?;
+        let source_url = PathBuf::from(&checkpoint.source_url);
+        let resume = checkpoint.resume;
+
+        // The VM being created anew, its devices register new eventfds as
+        // irqfds and ioeventfds with the hypervisor, the ones of the VMM
+        // itself having been restored by CRIU.
+        self.vm_restore(RestoreConfig {
+            source_url,
+            prefault: false,
+            key_fd: None,
+            ready: false,
+        })?;
+        // The checkpoint is only consumed once the VM is back, so that a
+        // failed restore can be retried.
+        self.checkpoint = None;
+        if resume {
+            self.vm_resume()?;
+        }
+        event!("vmm", "post-restored");
+
+        Ok(())
+    }
+
+    fn vmm_metrics(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        let mut writer = MetricsWriter::default();
+
+        let memory = profile::process_memory().map_err(VmError::Profile)?;
+        for (name, help, value) in [
+            (
+                "vmm_resident_memory_bytes",
+                "Resident memory of the VMM process",
+                memory.resident_bytes,
+            ),
+            (
+                "vmm_virtual_memory_bytes",
+                "Virtual memory of the VMM process",
+                memory.virtual_bytes,
+            ),
+            (
+                "vmm_anonymous_memory_bytes",
+                "Anonymous resident memory of the VMM process",
+                memory.anonymous_bytes,
+            ),
+        ] {
+            writer.family(name, MetricType::Gauge, help);
+            writer.sample(name, &[], value);
+        }
+
+        if let (Some(vm), Some(config)) = (&self.vm, &self.vm_config) {
+            let guest_memory = config.lock().unwrap().memory.total_size() - vm.balloon_size();
+            writer.family(
+                "guest_memory_bytes",
+                MetricType::Gauge,
+                "Memory available to the guest",
+            );
+            writer.sample("guest_memory_bytes", &[], guest_memory);
+
+            let stats = vcpu_stats(vm)?;
+            let vcpus = &stats.vcpus;
+            writer.family(
+                "vcpu_cpu_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by the vCPU threads",
+            );
+            for vcpu in vcpus.iter() {
+                writer.sample(
+                    "vcpu_cpu_seconds_total",
+                    &[("vcpu", &vcpu.id.to_string())],
+                    vcpu.cpu_time.cpu_time_ns as f64 / 1e9,
+                );
+            }
+            writer.family(
+                "vcpu_mode_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by the vCPU threads in each mode",
+            );
+            for vcpu in vcpus.iter() {
+                let id = vcpu.id.to_string();
+                for (mode, time_us) in cpu_time_modes(&vcpu.cpu_time) {
+                    writer.sample(
+                        "vcpu_mode_seconds_total",
+                        &[("vcpu", &id), ("mode", mode)],
+                        time_us as f64 / 1e6,
+                    );
+                }
+            }
+            writer.family(
+                "vcpus_cpu_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by all the vCPU threads",
+            );
+            writer.sample(
+                "vcpus_cpu_seconds_total",
+                &[],
+                stats.total.cpu_time_ns as f64 / 1e9,
+            );
+            writer.family(
+                "vcpus_mode_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by all the vCPU threads in each mode",
+            );
+            for (mode, time_us) in cpu_time_modes(&stats.total) {
+                writer.sample(
+                    "vcpus_mode_seconds_total",
+                    &[("mode", mode)],
+                    time_us as f64 / 1e6,
+                );
+            }
+            writer.family(
+                "vmm_cpu_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by the VMM process in each mode",
+            );
+            for (mode, time_us) in [
+                ("user", stats.process.user_time_us),
+                ("system", stats.process.system_time_us),
+            ] {
+                writer.sample(
+                    "vmm_cpu_seconds_total",
+                    &[("mode", mode)],
+                    time_us as f64 / 1e6,
+                );
+            }
+            writer.family(
+                "vmm_overhead_seconds_total",
+                MetricType::Counter,
+                "CPU time consumed by the VMM process outside of the guest",
+            );
+            writer.sample(
+                "vmm_overhead_seconds_total",
+                &[],
+                stats.vmm_time_us as f64 / 1e6,
+            );
+            writer.family(
+                "vcpu_exits_total",
+                MetricType::Counter,
+                "vCPU exits handled by the VMM",
+            );
+            for vcpu in vcpus.iter() {
+                writer.sample(
+                    "vcpu_exits_total",
+                    &[("vcpu", &vcpu.id.to_string())],
+                    vcpu.exits,
+                );
+            }
+
+            let counters: BTreeMap<String, BTreeMap<String, u64>> = vm
+                .counters()?
+                .into_iter()
+                .map(|(device, counters)| {
+                    let counters = counters
+                        .into_iter()
+                        .map(|(counter, value)| (counter.to_string(), value.0))
+                        .collect();
+                    (device, counters)
+                })
+                .collect();
+            writer.device_counters(&counters);
+        }
+
+        Ok(Some(writer.finish().into_bytes()))
+    }
+
+    fn vmm_profile(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        let profile = profile::vmm_profile(&self.device_ids()).map_err(VmError::Profile)?;
+
+        serde_json::to_vec(&profile)
+            .map(Some)
+            .map_err(VmError::SerializeJson)
+    }
+
+    #[cfg(feature = "pprof")]
+    fn vmm_pprof(&self, pprof_data: VmmPprofData) -> result::Result<(), VmError> {
+        let pprof = self.pprof_job(pprof_data)?;
+        pprof(&AtomicBool::new(false))
+    }
+
     fn vm_delete(&mut self) -> result::Result<(), VmError> {
         if self.vm_config.is_none() {
             return Ok(());
@@ -1522,6 +2891,7 @@ impl RequestHandler for Vmm {
         }
 
         self.vm_config = None;
+        self.staged_changes.clear();
 
         event!("vm", "deleted");
 
@@ -1589,9 +2959,76 @@ impl RequestHandler for Vmm {
                 }
             }
 
-            error!("Could not find the memory zone {} for the resize", id);
-            Err(VmError::ResizeZone)
+            error!("Could not find the memory zone {} for the resize", id);
+            Err(VmError::ResizeZone)
+        }
+    }
+
+    fn vm_set_boot_params(
+        &mut self,
+        boot_params: VmSetBootParamsData,
+    ) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        // The payload is only loaded when booting the VM.
+        if self.vm.is_some() {
+            return Err(VmError::VmAlreadyBooted);
+        }
+
+        // Validate the configuration change in a cloned configuration
+        let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+        apply_config_change(&mut config, &StagedConfigChange::BootParams(boot_params));
+        config.validate().map_err(VmError::ConfigValidation)?;
+
+        self.vm_config.as_ref().unwrap().lock().unwrap().payload = config.payload;
+
+        Ok(())
+    }
+
+    fn vm_rekey(&mut self, rekey_data: VmRekeyData) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.rekey(rekey_data.vsock_cid, rekey_data.uuid)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_stage_config_change(
+        &mut self,
+        change: StagedConfigChange,
+    ) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        // Validate the configuration resulting from all the staged changes
+        let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+        for staged_change in self.staged_changes.iter().chain(std::iter::once(&change)) {
+            apply_config_change(&mut config, staged_change);
+        }
+        config.validate().map_err(VmError::ConfigValidation)?;
+
+        self.staged_changes.push(change);
+
+        Ok(())
+    }
+
+    fn vm_update_config(&mut self, patch: serde_json::Value) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        // Validate the configuration change in a cloned configuration
+        let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+        let (updated, changes) = config_update::update(&config, patch, self.vm.is_some())
+            .map_err(VmError::UpdateConfig)?;
+        config_update::apply(&mut config, &updated);
+        config.validate().map_err(VmError::ConfigValidation)?;
+
+        if let Some(ref vm) = self.vm {
+            vm.update_config(&changes)?;
         }
+
+        let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+        config_update::apply(&mut config, &updated);
+
+        Ok(())
     }
 
     fn vm_add_device(
@@ -1654,11 +3091,11 @@ impl RequestHandler for Vmm {
 
     fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            if let Err(e) = vm.remove_device(id) {
+            if let Err(e) = vm.remove_device(id.clone()) {
                 error!("Error when removing device from the VM: {:?}", e);
                 Err(e)
             } else {
-                Ok(())
+                self.run_hook(HookPoint::PostDeviceRemove, &[("id", id)])
             }
         } else if let Some(ref config) = self.vm_config {
             let mut config = config.lock().unwrap();
@@ -1672,19 +3109,67 @@ impl RequestHandler for Vmm {
         }
     }
 
-    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+    fn vm_replace_device(
+        &mut self,
+        id: String,
+        mut replacement: ReplacementDeviceConfig,
+    ) -> result::Result<(), VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
+        let replacement_id = replacement.id_mut();
+        if replacement_id.is_none() {
+            *replacement_id = Some(id.clone());
+        }
+
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
-            add_to_config(&mut config.disks, disk_cfg.clone());
+            if !config.replace_device(&id, replacement.clone()) {
+                return Err(VmError::NoDeviceToRemove(id));
+            }
             config.validate().map_err(VmError::ConfigValidation)?;
+
+            // Keep the MAC address which might have been allocated from a
+            // pool during the validation.
+            if let ReplacementDeviceConfig::Net(net_cfg) = &mut replacement {
+                *net_cfg = config.net.as_mut().unwrap().pop().unwrap();
+            }
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            vm.replace_device(id, replacement).map_err(|e| {
+                error!("Error when replacing device of the VM: {:?}", e);
+                e
+            })
+        } else {
+            // Update VmConfig by swapping the devices.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            config.replace_device(&id, replacement);
+            Ok(())
+        }
+    }
+
+    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        let disk_fd = disk_cfg.fd;
+        let Some(vm_config) = self.vm_config.as_ref() else {
+            close_disk_fd(None, disk_fd);
+            return Err(VmError::VmNotCreated);
+        };
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = vm_config.lock().unwrap().clone();
+            add_to_config(&mut config.disks, disk_cfg.clone());
+            if let Err(e) = config.validate() {
+                close_disk_fd(Some(vm_config), disk_fd);
+                return Err(VmError::ConfigValidation(e));
+            }
         }
 
         if let Some(ref mut vm) = self.vm {
             let info = vm.add_disk(disk_cfg).map_err(|e| {
                 error!("Error when adding new disk to the VM: {:?}", e);
+                close_disk_fd(self.vm_config.as_ref(), disk_fd);
                 e
             })?;
             serde_json::to_vec(&info)
@@ -1753,18 +3238,32 @@ impl RequestHandler for Vmm {
     fn vm_add_net(&mut self, net_cfg: NetConfig) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
-        {
+        let net_cfg = {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
-            add_to_config(&mut config.net, net_cfg.clone());
+            add_to_config(&mut config.net, net_cfg);
             config.validate().map_err(VmError::ConfigValidation)?;
-        }
+
+            // Validation allocates the MAC address from the pool if needed,
+            // hence the device configuration must be taken from there.
+            config.net.as_mut().unwrap().pop().unwrap()
+        };
 
         if let Some(ref mut vm) = self.vm {
+            let mac = net_cfg.mac.to_string();
             let info = vm.add_net(net_cfg).map_err(|e| {
                 error!("Error when adding new network device to the VM: {:?}", e);
                 e
             })?;
+
+            let mut properties = vec![
+                ("id", info.id.clone()),
+                ("bdf", info.bdf.to_string()),
+                ("mac", mac),
+            ];
+            properties.extend(vm.net_tap_if_name(&info.id).map(|tap| ("tap", tap)));
+            self.run_hook(HookPoint::PostNetAdd, &properties)?;
+
             serde_json::to_vec(&info)
                 .map(Some)
                 .map_err(VmError::SerializeJson)
@@ -1802,6 +3301,32 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_add_usb(&mut self, usb_cfg: UsbConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.usb, usb_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.add_usb(usb_cfg).map_err(|e| {
+                error!("Error when adding new USB device to the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.usb, usb_cfg);
+            Ok(None)
+        }
+    }
+
     fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
@@ -1847,6 +3372,60 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_migration_blockers(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.migration_blockers())
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_capabilities(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.capabilities())
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_device_tree(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.device_tree_info())
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_devices(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.removable_devices())
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_vcpu_stats(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let stats = vcpu_stats(vm).map_err(|e| {
+                error!("Error when getting vCPUs statistics from the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&stats)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_power_button(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.power_button()
@@ -1855,6 +3434,14 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_suspend_to_ram(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.suspend_to_ram()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_nmi(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.nmi()
@@ -1863,6 +3450,89 @@ impl RequestHandler for Vmm {
         }
     }
 
+    fn vm_sysrq(&mut self, key: char) -> result::Result<(), VmError> {
+        if !key.is_ascii_graphic() {
+            return Err(VmError::InvalidSysRqKey(key));
+        }
+
+        if let Some(ref vm) = self.vm {
+            vm.sysrq(key as u8)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_screenshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.screenshot(destination_url)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_dump_memory(
+        &mut self,
+        dump_memory_data: VmDumpMemoryData,
+    ) -> result::Result<MemoryDump, VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.memory_dump(
+                &dump_memory_data.destination_url,
+                dump_memory_data.offset,
+                dump_memory_data.length,
+            )
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn job_run(&mut self, id: u64, request: JobRequest) {
+        // The jobs run one at a time
+        if self.running_job.is_some() {
+            self.queued_jobs.push_back((id, request));
+            return;
+        }
+
+        let Some(cancel) = self.jobs.lock().unwrap().start(id) else {
+            // The files passed along with the request are closed all the same
+            if let JobRequest::Snapshot(config) = request {
+                close_snapshot_fds(&config);
+            }
+            return;
+        };
+        event!("vmm", "job-started", "id", id.to_string());
+
+        if let Err(e) = self
+            .job_work(request)
+            .and_then(|work| self.start_job(id, work, cancel))
+        {
+            self.job_complete(id, Err(e));
+        }
+    }
+
+    #[cfg(feature = "introspection")]
+    fn vm_introspect(
+        &mut self,
+        introspect_data: VmIntrospectData,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let (matches, truncated) = vm
+                .introspect(
+                    introspect_data.gpa,
+                    introspect_data.size,
+                    &introspect_data.pattern,
+                )
+                .map_err(|e| {
+                    error!("Error when introspecting the VM: {:?}", e);
+                    e
+                })?;
+            serde_json::to_vec(&VmIntrospectResponse { matches, truncated })
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_receive_migration(
         &mut self,
         receive_data_migration: VmReceiveMigrationData,
@@ -1996,59 +3666,12 @@ impl RequestHandler for Vmm {
         &mut self,
         send_data_migration: VmSendMigrationData,
     ) -> result::Result<(), MigratableError> {
-        info!(
-            "Sending migration: destination_url = {}, local = {}",
-            send_data_migration.destination_url, send_data_migration.local
-        );
-
-        if !self
-            .vm_config
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .backed_by_shared_memory()
-            && send_data_migration.local
-        {
-            return Err(MigratableError::MigrateSend(anyhow!(
-                "Local migration requires shared memory or hugepages enabled"
-            )));
-        }
-
-        if let Some(vm) = self.vm.as_mut() {
-            Self::send_migration(
-                vm,
-                #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-                self.hypervisor.clone(),
-                send_data_migration,
-            )
-            .map_err(|migration_err| {
-                error!("Migration failed: {:?}", migration_err);
-
-                // Stop logging dirty pages
-                if let Err(e) = vm.stop_dirty_log() {
-                    return e;
-                }
-
-                if vm.get_state().unwrap() == VmState::Paused {
-                    if let Err(e) = vm.resume() {
-                        return e;
-                    }
-                }
-
-                migration_err
-            })?;
-
-            // Shutdown the VM after the migration succeeded
-            self.exit_evt.write(1).map_err(|e| {
-                MigratableError::MigrateSend(anyhow!(
-                    "Failed shutting down the VM after migration: {:?}",
-                    e
-                ))
-            })
-        } else {
-            Err(MigratableError::MigrateSend(anyhow!("VM is not running")))
-        }
+        let migrate = self.send_migration_job(send_data_migration)?;
+        let vm = self
+            .vm
+            .as_mut()
+            .ok_or_else(|| MigratableError::MigrateSend(anyhow!("VM is not running")))?;
+        migrate(vm, &AtomicBool::new(false))
     }
 }
 
@@ -2056,14 +3679,19 @@ const CPU_MANAGER_SNAPSHOT_ID: &str = "cpu-manager";
 const MEMORY_MANAGER_SNAPSHOT_ID: &str = "memory-manager";
 const DEVICE_MANAGER_SNAPSHOT_ID: &str = "device-manager";
 
+// Uptime after which a restarted guest is considered up again, the restart
+// policy limit only applying to consecutive restarts.
+const RESTARTS_RESET_UPTIME: Duration = Duration::from_secs(300);
+
 #[cfg(test)]
 mod unit_tests {
     use super::*;
+    use crate::api::VmResizeData;
     #[cfg(target_arch = "x86_64")]
     use crate::config::DebugConsoleConfig;
     use config::{
-        ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig, PayloadConfig,
-        RngConfig,
+        ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig,
+        MemoryLockPolicy, PayloadConfig, RngConfig, ValidationError,
     };
 
     fn create_dummy_vmm() -> Vmm {
@@ -2077,6 +3705,8 @@ mod unit_tests {
             SeccompAction::Allow,
             hypervisor::new().unwrap(),
             EventFd::new(EFD_NONBLOCK).unwrap(),
+            None,
+            Arc::default(),
         )
         .unwrap()
     }
@@ -2091,6 +3721,8 @@ mod unit_tests {
                 max_phys_bits: 46,
                 affinity: None,
                 features: config::CpuFeatures::default(),
+                sve_vl: None,
+                pmu_events: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -2101,9 +3733,12 @@ mod unit_tests {
                 shared: true,
                 hugepages: false,
                 hugepage_size: None,
+                hugepages_fallback: false,
                 prefault: false,
                 zones: None,
                 thp: true,
+                locked: false,
+                lock_policy: MemoryLockPolicy::Strict,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -2124,6 +3759,7 @@ mod unit_tests {
             },
             balloon: None,
             fs: None,
+            p9: None,
             pmem: None,
             serial: ConsoleConfig {
                 file: None,
@@ -2143,7 +3779,13 @@ mod unit_tests {
             user_devices: None,
             vdpa: None,
             vsock: None,
+            xhci: false,
+            usb: None,
             pvpanic: false,
+            #[cfg(target_arch = "x86_64")]
+            ramfb: false,
+            #[cfg(feature = "vnc")]
+            vnc: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
@@ -2154,6 +3796,10 @@ mod unit_tests {
             pci_segments: None,
             platform: None,
             tpm: None,
+            rt: None,
+            payload_verification: None,
+            restart_policy: None,
+            boot_detect: None,
             preserved_fds: None,
         }))
     }
@@ -2170,6 +3816,83 @@ mod unit_tests {
         ));
     }
 
+    #[test]
+    fn test_vmm_vm_set_boot_params() {
+        let mut vmm = create_dummy_vmm();
+        let boot_params = VmSetBootParamsData {
+            cmdline: Some("console=ttyS0 debug".to_string()),
+            initramfs: Some(PathBuf::from("/path/to/initramfs")),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            vmm.vm_set_boot_params(boot_params.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(vmm.vm_set_boot_params(boot_params).is_ok());
+
+        let payload = vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .payload
+            .clone()
+            .unwrap();
+        assert_eq!(payload.kernel, Some(PathBuf::from("/path/to/kernel")));
+        assert_eq!(payload.cmdline, Some("console=ttyS0 debug".to_string()));
+        assert_eq!(payload.initramfs, Some(PathBuf::from("/path/to/initramfs")));
+    }
+
+    #[test]
+    fn test_vmm_vm_stage_config_change() {
+        let mut vmm = create_dummy_vmm();
+        let change = StagedConfigChange::Resize(VmResizeData {
+            desired_vcpus: Some(4),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            vmm.vm_stage_config_change(change.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(vmm.vm_stage_config_change(change).is_ok());
+        assert!(vmm
+            .vm_stage_config_change(StagedConfigChange::BootParams(VmSetBootParamsData {
+                cmdline: Some("console=ttyS0 debug".to_string()),
+                ..Default::default()
+            }))
+            .is_ok());
+
+        // Nothing changes until the next boot
+        let vm_config = vmm.vm_config.clone().unwrap();
+        assert_eq!(vm_config.lock().unwrap().cpus.boot_vcpus, 1);
+        assert_eq!(vmm.staged_changes.len(), 2);
+
+        // Invalid changes are kept staged, the configuration being left as is
+        let serial = vm_config.lock().unwrap().serial.clone();
+        vm_config.lock().unwrap().serial.mode = ConsoleOutputMode::File;
+        assert!(vmm.apply_staged_changes(&vm_config).is_err());
+        assert_eq!(vmm.staged_changes.len(), 2);
+        assert_eq!(vm_config.lock().unwrap().cpus.boot_vcpus, 1);
+        vm_config.lock().unwrap().serial = serial;
+
+        assert!(vmm.apply_staged_changes(&vm_config).is_ok());
+        assert!(vmm.staged_changes.is_empty());
+        let config = vm_config.lock().unwrap();
+        assert_eq!(config.cpus.boot_vcpus, 4);
+        assert_eq!(config.cpus.max_vcpus, 4);
+        assert_eq!(
+            config.payload.as_ref().unwrap().cmdline,
+            Some("console=ttyS0 debug".to_string())
+        );
+    }
+
     #[test]
     fn test_vmm_vm_cold_add_device() {
         let mut vmm = create_dummy_vmm();
@@ -2503,6 +4226,50 @@ mod unit_tests {
         );
     }
 
+    #[test]
+    fn test_vmm_vm_cold_add_usb() {
+        let mut vmm = create_dummy_vmm();
+        let usb_config = UsbConfig::parse("path=/dev/bus/usb/001/004").unwrap();
+
+        assert!(matches!(
+            vmm.vm_add_usb(usb_config.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .usb
+            .is_none());
+
+        let result = vmm.vm_add_usb(usb_config.clone());
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .usb
+                .clone()
+                .unwrap(),
+            vec![usb_config.clone()]
+        );
+
+        // The same host device can't be passed through twice
+        assert!(matches!(
+            vmm.vm_add_usb(usb_config),
+            Err(VmError::ConfigValidation(
+                ValidationError::DuplicateUsbDevicePath(_)
+            ))
+        ));
+    }
+
     #[test]
     fn test_vmm_vm_cold_add_vsock() {
         let mut vmm = create_dummy_vmm();
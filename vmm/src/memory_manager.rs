@@ -4,12 +4,16 @@
 //
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
-use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
+use crate::config::{HotplugMethod, MemoryConfig, MemoryLockPolicy, MemoryZoneConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
     CoredumpMemoryRegion, CoredumpMemoryRegions, DumpState, GuestDebuggableError,
 };
-use crate::migration::url_to_path;
+use crate::memory_share::{self, MemoryZoneExport};
+use crate::migration::{url_to_path, SnapshotSource};
+use crate::snapshot_compression;
+use crate::snapshot_encryption::{self, EncryptedReader, EncryptedWriter, SnapshotKey};
+use crate::snapshot_stream;
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use acpi_tables::{aml, Aml};
@@ -24,18 +28,21 @@ use hypervisor::HypervisorVmError;
 use libc::_SC_NPROCESSORS_ONLN;
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use seccompiler::BpfProgram;
 use serde::{Deserialize, Serialize};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{self};
-use std::ops::{BitAnd, Deref, Not, Sub};
+use std::io::{self, Read, Write};
+use std::ops::{BitAnd, Deref, Not, Range, Sub};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::os::fd::AsFd;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
+#[cfg(feature = "guard_pages")]
+use std::sync::Weak;
 use std::sync::{Arc, Barrier, Mutex};
 use std::{ffi, thread};
 use tracer::trace_scoped;
@@ -46,8 +53,10 @@ use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::BusDevice;
 use vm_memory::bitmap::AtomicBitmap;
 use vm_memory::guest_memory::FileOffset;
+#[cfg(feature = "guard_pages")]
+use vm_memory::mmap::{MmapRegionBuilder, NewBitmap};
 use vm_memory::{
-    mmap::MmapRegionError, Address, Error as MmapError, GuestAddress, GuestAddressSpace,
+    mmap::MmapRegionError, Address, Bytes, Error as MmapError, GuestAddress, GuestAddressSpace,
     GuestMemory, GuestMemoryAtomic, GuestMemoryError, GuestMemoryRegion, GuestUsize, MmapRegion,
     ReadVolatile,
 };
@@ -60,11 +69,45 @@ pub const MEMORY_MANAGER_ACPI_SIZE: usize = 0x18;
 
 const DEFAULT_MEMORY_ZONE: &str = "mem0";
 
-const SNAPSHOT_FILENAME: &str = "memory-ranges";
+pub const SNAPSHOT_FILENAME: &str = "memory-ranges";
+pub const SNAPSHOT_COMPRESSED_FILENAME: &str = "memory-ranges.zst";
+
+// Guest memory copied at once from or to an encrypted or streamed snapshot
+const SNAPSHOT_COPY_SIZE: usize = 1 << 20;
 
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
+// Address space reserved around a guarded region, unmapped when dropped.
+#[cfg(feature = "guard_pages")]
+struct GuardReservation {
+    addr: usize,
+    size: usize,
+}
+
+#[cfg(feature = "guard_pages")]
+impl Drop for GuardReservation {
+    fn drop(&mut self) {
+        // SAFETY: FFI call unmapping the range reserved when building the
+        // guarded region, which isn't referred to anymore.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.size) };
+    }
+}
+
+// The guarded regions don't own their mapping, which is only released once
+// the last reference to the region is gone, devices and vCPUs included.
+#[cfg(feature = "guard_pages")]
+static GUARDED_MAPPINGS: Mutex<Vec<(Weak<GuestRegionMmap>, GuardReservation)>> =
+    Mutex::new(Vec::new());
+
+#[cfg(feature = "guard_pages")]
+fn release_guarded_mappings() {
+    GUARDED_MAPPINGS
+        .lock()
+        .unwrap()
+        .retain(|(region, _)| region.strong_count() > 0);
+}
+
 #[cfg(target_arch = "x86_64")]
 const SGX_PAGE_SIZE: u64 = 1 << 12;
 
@@ -125,6 +168,8 @@ impl VirtioMemZone {
 pub struct MemoryZone {
     regions: Vec<Arc<GuestRegionMmap>>,
     virtio_mem_zone: Option<VirtioMemZone>,
+    // Hands the zone out to another VMM until the zone goes away
+    export: Option<Arc<MemoryZoneExport>>,
 }
 
 impl MemoryZone {
@@ -197,6 +242,46 @@ pub struct MemoryManager {
     pub acpi_address: Option<GuestAddress>,
     #[cfg(target_arch = "aarch64")]
     uefi_flash: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+
+    memory_backing: Vec<MemoryZoneBacking>,
+    locked: bool,
+    lock_policy: MemoryLockPolicy,
+}
+
+/// Pages actually backing a memory zone.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemoryZoneBacking {
+    pub id: String,
+    pub page_size: u64,
+    /// The zone is backed by hugetlbfs pages
+    pub hugepages: bool,
+    /// Hugepages were requested but the pool was short, hence the zone is
+    /// backed by regular pages, possibly THP.
+    pub fallback: bool,
+    /// The zone is locked in memory
+    pub locked: bool,
+}
+
+/// Hotplug limits of a virtio-mem backed memory zone.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemoryZoneCapabilities {
+    pub id: String,
+    pub hotplug_size: u64,
+    pub hotplugged_size: u64,
+}
+
+/// Memory hotplug limits of the VM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemoryCapabilities {
+    pub hotplug_method: HotplugMethod,
+    pub boot_size: u64,
+    pub current_size: u64,
+    /// Memory that can still be hotplugged through `vm.resize`
+    pub hotpluggable_size: u64,
+    /// ACPI hotplug slots left, each memory hotplug consuming one
+    pub free_hotplug_slots: Option<usize>,
+    /// Memory zones resizable through virtio-mem
+    pub zones: Vec<MemoryZoneCapabilities>,
 }
 
 #[derive(Debug)]
@@ -216,6 +301,10 @@ pub enum Error {
     /// Error from region creation
     GuestMemoryRegion(MmapRegionError),
 
+    /// Failed to map the guard pages surrounding a region
+    #[cfg(feature = "guard_pages")]
+    GuardPages(io::Error),
+
     /// No ACPI slot available
     NoSlotAvailable,
 
@@ -324,6 +413,21 @@ pub enum Error {
     // Error copying snapshot into region
     SnapshotCopy(GuestMemoryError),
 
+    /// Error setting up the decryption of the snapshot file
+    SnapshotEncryption(snapshot_encryption::Error),
+
+    /// Error decrypting the snapshot file
+    SnapshotDecrypt(io::Error),
+
+    /// Error reading the snapshot stream
+    SnapshotStream(snapshot_stream::Error),
+
+    /// Error reading the snapshot file out of the stream
+    SnapshotRead(io::Error),
+
+    /// Error decompressing the snapshot file
+    SnapshotCompression(snapshot_compression::Error),
+
     /// Failed to allocate MMIO address
     AllocateMmioAddress,
 
@@ -337,8 +441,31 @@ pub enum Error {
     /// Failed to stat filesystem
     GetFileSystemBlockSize(io::Error),
 
+    /// Failed to share the memory zone with or from another VMM
+    MemoryZoneSharing(memory_share::Error),
+
     /// Memory size is misaligned with default page size or its hugepage size
     MisalignedMemorySize,
+
+    /// Not enough hugepages available to back the memory
+    InsufficientHugepages {
+        page_size: u64,
+        host_numa_node: Option<u32>,
+        required: u64,
+        available: u64,
+    },
+
+    /// Failed to read the state of the hugepage pool
+    ReadHugepagesPool(io::Error),
+
+    /// Failed to lock the guest memory
+    LockMemory(io::Error),
+
+    /// The guest memory is larger than RLIMIT_MEMLOCK
+    MemlockLimit {
+        required: u64,
+        limit: u64,
+    },
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -417,6 +544,79 @@ fn memory_zone_get_align_size(zone: &MemoryZoneConfig) -> Result<u64, Error> {
     Ok(align_size)
 }
 
+// Number of hugepages of the given size which can still be allocated, from
+// the pool of the host NUMA node if any, from the global pool otherwise.
+fn hugepages_available(page_size: u64, host_numa_node: Option<u32>) -> Result<u64, Error> {
+    let pool_dir = match host_numa_node {
+        Some(node) => format!(
+            "/sys/devices/system/node/node{}/hugepages/hugepages-{}kB",
+            node,
+            page_size >> 10
+        ),
+        None => format!("/sys/kernel/mm/hugepages/hugepages-{}kB", page_size >> 10),
+    };
+    let read_counter = |name: &str| -> Result<u64, Error> {
+        match std::fs::read_to_string(format!("{pool_dir}/{name}")) {
+            Ok(value) => value.trim().parse().map_err(|e| {
+                Error::ReadHugepagesPool(io::Error::new(io::ErrorKind::InvalidData, e))
+            }),
+            // There is no pool for this page size on the host.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(Error::ReadHugepagesPool(e)),
+        }
+    };
+
+    let mut available = read_counter("free_hugepages")?;
+    // Reservations and overcommit are only accounted for the global pool.
+    if host_numa_node.is_none() {
+        available = available.saturating_sub(read_counter("resv_hugepages")?);
+        available += read_counter("nr_overcommit_hugepages")?
+            .saturating_sub(read_counter("surplus_hugepages")?);
+    }
+
+    Ok(available)
+}
+
+// Whether the process can lock memory regardless of RLIMIT_MEMLOCK.
+fn has_cap_ipc_lock() -> bool {
+    const CAP_IPC_LOCK: u32 = 14;
+
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & (1 << CAP_IPC_LOCK) != 0)
+}
+
+fn check_memlock_limit(size: u64) -> Result<(), Error> {
+    if has_cap_ipc_lock() {
+        return Ok(());
+    }
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: FFI call with a valid rlimit
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) };
+    if ret != 0 {
+        return Err(Error::LockMemory(io::Error::last_os_error()));
+    }
+
+    if limit.rlim_cur != libc::RLIM_INFINITY && limit.rlim_cur < size {
+        return Err(Error::MemlockLimit {
+            required: size,
+            limit: limit.rlim_cur,
+        });
+    }
+
+    Ok(())
+}
+
 #[inline]
 fn align_down<T>(val: T, align: T) -> T
 where
@@ -536,6 +736,7 @@ impl MemoryManager {
         let mut zone = zone_iter.next().ok_or(Error::MissingMemoryZones)?;
         let mut zone_align_size = memory_zone_get_align_size(zone)?;
         let mut zone_offset = 0u64;
+        let mut zone_file = None;
         let mut memory_zones = HashMap::new();
 
         if !is_aligned(zone.size, zone_align_size) {
@@ -587,6 +788,17 @@ impl MemoryManager {
                     region_start.raw_value(),
                     region_size
                 );
+                // A zone shared with or from another VMM is backed by a single
+                // file, all its regions mapping the part they cover.
+                if file_offset == 0 {
+                    zone_file = Self::shared_zone_file(zone, memory_zones.get_mut(&zone.id))?;
+                }
+                let existing_memory_file = zone_file
+                    .as_ref()
+                    .map(|f| f.try_clone())
+                    .transpose()
+                    .map_err(Error::SharedFileCreate)?;
+
                 let region = MemoryManager::create_ram_region(
                     &zone.file,
                     file_offset,
@@ -597,7 +809,7 @@ impl MemoryManager {
                     zone.hugepages,
                     zone.hugepage_size,
                     zone.host_numa_node,
-                    None,
+                    existing_memory_file,
                     thp,
                 )?;
 
@@ -650,6 +862,51 @@ impl MemoryManager {
         Ok((mem_regions, memory_zones))
     }
 
+    /// Starts handing out the memory zones shared with another VMM, the
+    /// serving threads applying `seccomp_filter`.
+    pub fn start_memory_zone_exports(&self, seccomp_filter: &BpfProgram) -> Result<(), Error> {
+        for export in self
+            .memory_zones
+            .values()
+            .filter_map(|zone| zone.export.as_ref())
+        {
+            export
+                .start(seccomp_filter.clone())
+                .map_err(Error::MemoryZoneSharing)?;
+        }
+        Ok(())
+    }
+
+    // Gets the file backing the whole zone when it is shared with another VMM,
+    // which gets exported, or shared from another VMM, which gets imported.
+    fn shared_zone_file(
+        zone: &MemoryZoneConfig,
+        memory_zone: Option<&mut MemoryZone>,
+    ) -> Result<Option<File>, Error> {
+        if let Some(path) = &zone.shared_with {
+            let file = Self::create_anonymous_file(
+                zone.size as usize,
+                zone.hugepages,
+                zone.hugepage_size,
+            )?
+            .file()
+            .try_clone()
+            .map_err(Error::SharedFileCreate)?;
+            let export = MemoryZoneExport::new(path, &file).map_err(Error::MemoryZoneSharing)?;
+            if let Some(memory_zone) = memory_zone {
+                memory_zone.export = Some(Arc::new(export));
+            }
+            info!("Sharing memory zone {} on {:?}", zone.id, path);
+            Ok(Some(file))
+        } else if let Some(path) = &zone.shared_from {
+            let file = memory_share::import(path, zone.size).map_err(Error::MemoryZoneSharing)?;
+            info!("Mapping memory zone {} shared on {:?}", zone.id, path);
+            Ok(Some(file))
+        } else {
+            Ok(None)
+        }
+    }
+
     // Restore both GuestMemory regions along with MemoryZone zones.
     fn restore_memory_regions_and_zones(
         guest_ram_mappings: &[GuestRamMapping],
@@ -712,19 +969,41 @@ impl MemoryManager {
 
     fn fill_saved_regions(
         &mut self,
-        file_path: PathBuf,
+        source: &mut SnapshotSource,
         saved_regions: MemoryRangeTable,
+        key: Option<&SnapshotKey>,
     ) -> Result<(), Error> {
         if saved_regions.is_empty() {
             return Ok(());
         }
 
+        let source_url = match source {
+            SnapshotSource::Url(source_url) => source_url,
+            SnapshotSource::Stream(stream) => {
+                let memory_file = stream
+                    .file(SNAPSHOT_FILENAME)
+                    .map_err(Error::SnapshotStream)?;
+                return self.fill_saved_regions_from(memory_file, &saved_regions, key);
+            }
+        };
+        let source_path = url_to_path(source_url).map_err(Error::Restore)?;
+        let file_path = source_path.join(SNAPSHOT_COMPRESSED_FILENAME);
+        if file_path.exists() {
+            let memory_file = File::open(file_path).map_err(Error::SnapshotOpen)?;
+            return self.fill_saved_regions_compressed(memory_file, &saved_regions, key);
+        }
+        let file_path = source_path.join(SNAPSHOT_FILENAME);
+
         // Open (read only) the snapshot file.
         let mut memory_file = OpenOptions::new()
             .read(true)
             .open(file_path)
             .map_err(Error::SnapshotOpen)?;
 
+        if key.is_some() {
+            return self.fill_saved_regions_from(memory_file, &saved_regions, key);
+        }
+
         let guest_memory = self.guest_memory.memory();
         for range in saved_regions.regions() {
             let mut offset: u64 = 0;
@@ -752,6 +1031,191 @@ impl MemoryManager {
         Ok(())
     }
 
+    // The content goes through a buffer, as it is read out of a stream or
+    // decrypted, the guest memory being only written once authenticated.
+    fn fill_saved_regions_from<R: Read>(
+        &mut self,
+        memory_file: R,
+        saved_regions: &MemoryRangeTable,
+        key: Option<&SnapshotKey>,
+    ) -> Result<(), Error> {
+        let (mut reader, read_error): (Box<dyn Read + '_>, fn(io::Error) -> Error) = match key {
+            Some(key) => (
+                Box::new(
+                    EncryptedReader::new(memory_file, key, SNAPSHOT_FILENAME)
+                        .map_err(Error::SnapshotEncryption)?,
+                ),
+                Error::SnapshotDecrypt,
+            ),
+            None => (Box::new(memory_file), Error::SnapshotRead),
+        };
+        let mut buffer = vec![0u8; SNAPSHOT_COPY_SIZE];
+
+        let guest_memory = self.guest_memory.memory();
+        for range in saved_regions.regions() {
+            let mut offset: u64 = 0;
+            while offset < range.length {
+                let len = std::cmp::min(range.length - offset, buffer.len() as u64) as usize;
+                reader.read_exact(&mut buffer[..len]).map_err(read_error)?;
+                guest_memory
+                    .write_slice(&buffer[..len], GuestAddress(range.gpa + offset))
+                    .map_err(Error::SnapshotCopy)?;
+                offset += len as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The frames of the compressed file are decompressed in parallel, unless
+    // the file is encrypted, in which case it can only be read sequentially.
+    fn fill_saved_regions_compressed(
+        &mut self,
+        memory_file: File,
+        saved_regions: &MemoryRangeTable,
+        key: Option<&SnapshotKey>,
+    ) -> Result<(), Error> {
+        let guest_memory = self.guest_memory.memory();
+        let guest_memory = &*guest_memory;
+        let len = saved_regions.regions().iter().map(|r| r.length).sum();
+        let write = |offset, data: &[u8]| {
+            for_each_snapshot_range(saved_regions, offset, data.len(), |addr, range| {
+                guest_memory.write_slice(&data[range], addr)
+            })
+            .map_err(io::Error::other)
+        };
+
+        match key {
+            Some(key) => snapshot_compression::decompress(
+                EncryptedReader::new(memory_file, key, SNAPSHOT_COMPRESSED_FILENAME)
+                    .map_err(Error::SnapshotEncryption)?,
+                len,
+                write,
+            ),
+            None => snapshot_compression::decompress_file(&memory_file, len, write),
+        }
+        .map_err(Error::SnapshotCompression)
+    }
+
+    // Because the guest RAM is mapped with MAP_NORESERVE, a short hugepage
+    // pool would only be noticed when the guest faults the missing pages in.
+    // Check every pool can back the zones relying on it before allocating
+    // anything, and switch the zones allowed to fall back onto regular pages
+    // if it can't. Returns the identifiers of the zones which fell back.
+    fn check_hugepages_pools(zones: &mut [MemoryZoneConfig]) -> Result<Vec<String>, Error> {
+        // Zones relying on each pool, identified by page size and host NUMA node.
+        let mut pools: BTreeMap<(u64, Option<u32>), Vec<usize>> = BTreeMap::new();
+        for (index, zone) in zones.iter().enumerate() {
+            if zone.hugepages && zone.file.is_none() {
+                let page_size = memory_zone_get_align_size(zone)?;
+                pools
+                    .entry((page_size, zone.host_numa_node))
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        let mut fallback_zones = Vec::new();
+        for ((page_size, host_numa_node), indexes) in pools {
+            let zone_pages = |index: &usize| {
+                let zone = &zones[*index];
+                (zone.size + zone.hotplugged_size.unwrap_or(0)).div_ceil(page_size)
+            };
+            let required: u64 = indexes.iter().map(zone_pages).sum();
+            let available = hugepages_available(page_size, host_numa_node)?;
+            if required <= available {
+                continue;
+            }
+
+            // The zones which can't fall back must still fit in the pool.
+            let strict_required: u64 = indexes
+                .iter()
+                .filter(|index| !zones[**index].hugepages_fallback)
+                .map(zone_pages)
+                .sum();
+            if strict_required > available {
+                return Err(Error::InsufficientHugepages {
+                    page_size,
+                    host_numa_node,
+                    required,
+                    available,
+                });
+            }
+
+            for index in indexes {
+                let zone = &mut zones[index];
+                if zone.hugepages_fallback {
+                    warn!(
+                        "Not enough hugepages of size {} to back memory zone '{}' \
+                        ({} required, {} available), falling back onto regular pages",
+                        page_size, zone.id, required, available
+                    );
+                    zone.hugepages = false;
+                    zone.hugepage_size = None;
+                    fallback_zones.push(zone.id.clone());
+                }
+            }
+        }
+
+        Ok(fallback_zones)
+    }
+
+    // Locks a guest RAM region so that it can't be swapped out. Returns
+    // whether the region could be locked, as the best-effort policy leaves
+    // the regions which can't be locked as they are.
+    fn lock_region(region: &GuestRegionMmap, policy: MemoryLockPolicy) -> Result<bool, Error> {
+        // SAFETY: FFI call with a valid mapping
+        let ret = unsafe {
+            libc::mlock(
+                region.as_ptr() as *const libc::c_void,
+                region.len() as usize,
+            )
+        };
+        if ret == 0 {
+            return Ok(true);
+        }
+
+        let e = io::Error::last_os_error();
+        match policy {
+            MemoryLockPolicy::Strict => Err(Error::LockMemory(e)),
+            MemoryLockPolicy::BestEffort => {
+                warn!(
+                    "Failed to lock guest memory region at 0x{:x}: {}",
+                    region.start_addr().raw_value(),
+                    e
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    fn lock_memory_zones(&mut self) -> Result<(), Error> {
+        let size = self
+            .memory_zones
+            .values()
+            .flat_map(|zone| zone.regions())
+            .map(|region| region.len())
+            .sum();
+        if let Err(e) = check_memlock_limit(size) {
+            if self.lock_policy == MemoryLockPolicy::Strict {
+                return Err(e);
+            }
+            warn!("{:?}, locking as much guest memory as possible", e);
+        }
+
+        for backing in self.memory_backing.iter_mut() {
+            let Some(memory_zone) = self.memory_zones.get(&backing.id) else {
+                continue;
+            };
+            backing.locked = true;
+            for region in memory_zone.regions() {
+                backing.locked &= Self::lock_region(region, self.lock_policy)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn validate_memory_config(
         config: &MemoryConfig,
         user_provided_zones: bool,
@@ -806,10 +1270,13 @@ impl MemoryManager {
                 shared: config.shared,
                 hugepages: config.hugepages,
                 hugepage_size: config.hugepage_size,
+                hugepages_fallback: config.hugepages_fallback,
                 host_numa_node: None,
                 hotplug_size: config.hotplug_size,
                 hotplugged_size: config.hotplugged_size,
                 prefault: config.prefault,
+                shared_with: None,
+                shared_from: None,
             }];
 
             Ok((config.size, zones, allow_mem_hotplug))
@@ -999,9 +1466,39 @@ impl MemoryManager {
             GuestAddress(mmio_address_space_size - PLATFORM_DEVICE_AREA_SIZE);
         let end_of_device_area = start_of_platform_device_area.unchecked_sub(1);
 
-        let (ram_size, zones, allow_mem_hotplug) =
+        let (ram_size, mut zones, allow_mem_hotplug) =
             Self::validate_memory_config(config, user_provided_zones)?;
 
+        // The memory received from a local migration is already allocated.
+        let fallback_zones = if existing_memory_files.is_none() {
+            Self::check_hugepages_pools(&mut zones)?
+        } else {
+            Vec::new()
+        };
+        // SAFETY: FFI call. Trivially safe.
+        let system_page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+        let memory_backing = zones
+            .iter()
+            .map(|zone| {
+                let page_size = memory_zone_get_align_size(zone)?;
+                Ok(MemoryZoneBacking {
+                    id: zone.id.clone(),
+                    page_size,
+                    hugepages: zone.hugepages || page_size > system_page_size,
+                    fallback: fallback_zones.contains(&zone.id),
+                    locked: false,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // Hotplugged ACPI memory is allocated the same way as the default
+        // zone, including when it fell back onto regular pages.
+        let (hugepages, hugepage_size) = if user_provided_zones {
+            (config.hugepages, config.hugepage_size)
+        } else {
+            (zones[0].hugepages, zones[0].hugepage_size)
+        };
+
         let (
             start_of_device_area,
             boot_ram,
@@ -1215,8 +1712,8 @@ impl MemoryManager {
             current_ram,
             next_hotplug_slot,
             shared: config.shared,
-            hugepages: config.hugepages,
-            hugepage_size: config.hugepage_size,
+            hugepages,
+            hugepage_size,
             prefault: config.prefault,
             #[cfg(target_arch = "x86_64")]
             sgx_epc_region: None,
@@ -1232,8 +1729,15 @@ impl MemoryManager {
             #[cfg(target_arch = "aarch64")]
             uefi_flash: None,
             thp: config.thp,
+            memory_backing,
+            locked: config.locked,
+            lock_policy: config.lock_policy,
         };
 
+        if config.locked {
+            memory_manager.lock_memory_zones()?;
+        }
+
         #[cfg(target_arch = "aarch64")]
         {
             // For Aarch64 we cannot lazily allocate the address space like we
@@ -1258,14 +1762,12 @@ impl MemoryManager {
         snapshot: &Snapshot,
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
-        source_url: Option<&str>,
+        source: Option<&mut SnapshotSource>,
         prefault: bool,
         phys_bits: u8,
+        key: Option<&SnapshotKey>,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        if let Some(source_url) = source_url {
-            let mut memory_file_path = url_to_path(source_url).map_err(Error::Restore)?;
-            memory_file_path.push(String::from(SNAPSHOT_FILENAME));
-
+        if let Some(source) = source {
             let mem_snapshot: MemoryManagerSnapshotData =
                 snapshot.to_state().map_err(Error::Restore)?;
 
@@ -1284,7 +1786,7 @@ impl MemoryManager {
 
             mm.lock()
                 .unwrap()
-                .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?;
+                .fill_saved_regions(source, mem_snapshot.memory_ranges, key)?;
 
             Ok(mm)
         } else {
@@ -1370,6 +1872,12 @@ impl MemoryManager {
         Ok(FileOffset::new(f, 0))
     }
 
+    fn is_read_only(file: &File) -> bool {
+        // SAFETY: FFI call with a valid fd
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        flags >= 0 && flags & libc::O_ACCMODE == libc::O_RDONLY
+    }
+
     fn open_backing_file(backing_file: &PathBuf, file_offset: u64) -> Result<FileOffset, Error> {
         if backing_file.is_dir() {
             Err(Error::DirectoryAsBackingFileForMemory)
@@ -1403,8 +1911,13 @@ impl MemoryManager {
         // The duplication of mmap_flags ORing here is unfortunate but it also makes
         // the complexity of the handling clear.
         let fo = if let Some(f) = existing_memory_file {
-            // It must be MAP_SHARED as we wouldn't already have an FD
-            mmap_flags |= libc::MAP_SHARED;
+            // It must be MAP_SHARED as we wouldn't already have an FD, unless
+            // it's a read-only one, the guest writes staying private then.
+            if Self::is_read_only(&f) {
+                mmap_flags |= libc::MAP_PRIVATE;
+            } else {
+                mmap_flags |= libc::MAP_SHARED;
+            }
             Some(FileOffset::new(f, file_offset))
         } else if let Some(backing_file) = backing_file {
             if shared {
@@ -1424,12 +1937,20 @@ impl MemoryManager {
             None
         };
 
-        let region = GuestRegionMmap::new(
+        #[cfg(not(feature = "guard_pages"))]
+        let mmap_region =
             MmapRegion::build(fo, size, libc::PROT_READ | libc::PROT_WRITE, mmap_flags)
-                .map_err(Error::GuestMemoryRegion)?,
-            start_addr,
-        )
-        .map_err(Error::GuestMemory)?;
+                .map_err(Error::GuestMemoryRegion)?;
+        #[cfg(feature = "guard_pages")]
+        let (mmap_region, reservation) = Self::build_guarded_region(
+            fo,
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            mmap_flags,
+            Self::get_prefault_align_size(backing_file, hugepages, hugepage_size)? as usize,
+        )?;
+
+        let region = GuestRegionMmap::new(mmap_region, start_addr).map_err(Error::GuestMemory)?;
 
         // Apply NUMA policy if needed.
         if let Some(node) = host_numa_node {
@@ -1522,7 +2043,83 @@ impl MemoryManager {
             }
         }
 
-        Ok(Arc::new(region))
+        let region = Arc::new(region);
+        #[cfg(feature = "guard_pages")]
+        {
+            release_guarded_mappings();
+            GUARDED_MAPPINGS
+                .lock()
+                .unwrap()
+                .push((Arc::downgrade(&region), reservation));
+        }
+        Ok(region)
+    }
+
+    // Maps the region in between two inaccessible guard pages, so that an
+    // access overflowing the region faults instead of silently reaching an
+    // adjacent mapping. The mapping isn't owned by the returned region but by
+    // the returned reservation, which must outlive the region.
+    #[cfg(feature = "guard_pages")]
+    fn build_guarded_region(
+        fo: Option<FileOffset>,
+        size: usize,
+        prot: i32,
+        flags: i32,
+        page_size: usize,
+    ) -> Result<(MmapRegion<AtomicBitmap>, GuardReservation), Error> {
+        // Reserve one more page so that the region can be aligned on the
+        // backing page size, as required for hugepages.
+        let reserved_size = size + 3 * page_size;
+        // SAFETY: FFI call with valid arguments, only reserving address space
+        let reserved = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                reserved_size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if reserved == libc::MAP_FAILED {
+            return Err(Error::GuardPages(io::Error::last_os_error()));
+        }
+        let reservation = GuardReservation {
+            addr: reserved as usize,
+            size: reserved_size,
+        };
+
+        let addr = align_down(reserved as usize + 2 * page_size - 1, page_size);
+        let (fd, offset) = fo.as_ref().map_or((-1, 0), |fo| {
+            (fo.file().as_raw_fd(), fo.start() as libc::off_t)
+        });
+        // SAFETY: FFI call with valid arguments, mapping the region over the
+        // address space reserved above
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                size,
+                prot,
+                flags | libc::MAP_FIXED,
+                fd,
+                offset,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(Error::GuardPages(io::Error::last_os_error()));
+        }
+
+        let mut builder = MmapRegionBuilder::new_with_bitmap(size, AtomicBitmap::with_len(size))
+            .with_mmap_prot(prot)
+            .with_mmap_flags(flags);
+        // SAFETY: the pointer is valid for size bytes, as mapped above
+        builder = unsafe { builder.with_raw_mmap_pointer(addr as *mut u8) };
+        if let Some(fo) = fo {
+            builder = builder.with_file_offset(fo);
+        }
+
+        let region = builder.build().map_err(Error::GuestMemoryRegion)?;
+        Ok((region, reservation))
     }
 
     // Duplicate of `memory_zone_get_align_size` that does not require a `zone`
@@ -1634,6 +2231,16 @@ impl MemoryManager {
             self.thp,
         )?;
 
+        if self.locked && !Self::lock_region(&region, self.lock_policy)? {
+            if let Some(backing) = self
+                .memory_backing
+                .iter_mut()
+                .find(|backing| backing.id == DEFAULT_MEMORY_ZONE)
+            {
+                backing.locked = false;
+            }
+        }
+
         // Map it into the guest
         let slot = self.create_userspace_mapping(
             region.start_addr().0,
@@ -2063,6 +2670,65 @@ impl MemoryManager {
         &mut self.memory_zones
     }
 
+    pub fn memory_backing(&self) -> &[MemoryZoneBacking] {
+        &self.memory_backing
+    }
+
+    pub fn capabilities(&self) -> MemoryCapabilities {
+        let mut zones: Vec<MemoryZoneCapabilities> = self
+            .memory_zones
+            .iter()
+            .filter_map(|(id, zone)| {
+                zone.virtio_mem_zone
+                    .as_ref()
+                    .map(|virtio_mem_zone| MemoryZoneCapabilities {
+                        id: id.clone(),
+                        hotplug_size: virtio_mem_zone.region.len(),
+                        hotplugged_size: virtio_mem_zone.hotplugged_size,
+                    })
+            })
+            .collect();
+        zones.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let free_hotplug_slots = match self.hotplug_method {
+            HotplugMethod::Acpi => Some(HOTPLUG_COUNT - self.next_hotplug_slot),
+            HotplugMethod::VirtioMem => None,
+        };
+
+        // Memory backed by user defined zones can only be resized per zone.
+        let hotpluggable_size = if !self.dynamic || self.user_provided_zones {
+            0
+        } else {
+            match self.hotplug_method {
+                HotplugMethod::Acpi if self.next_hotplug_slot < HOTPLUG_COUNT => {
+                    // Hotplugged DIMMs must have a size that is a multiple of
+                    // 128MiB, and end before the end of the RAM area.
+                    MemoryManager::start_addr(self.guest_memory.memory().last_addr(), true)
+                        .map(|start_addr| {
+                            self.end_of_ram_area.0.saturating_sub(start_addr.0 + 1)
+                                & !((128 << 20) - 1)
+                        })
+                        .unwrap_or(0)
+                }
+                HotplugMethod::Acpi => 0,
+                HotplugMethod::VirtioMem => zones
+                    .iter()
+                    .find(|zone| zone.id == DEFAULT_MEMORY_ZONE)
+                    .map(|zone| zone.hotplug_size - zone.hotplugged_size)
+                    .unwrap_or(0),
+            }
+        };
+
+        MemoryCapabilities {
+            hotplug_method: self.hotplug_method,
+            boot_size: self.boot_ram,
+            current_size: self.current_ram,
+            hotpluggable_size,
+            free_hotplug_slots,
+            zones,
+        }
+    }
+
     pub fn memory_range_table(
         &self,
         snapshot: bool,
@@ -2624,6 +3290,18 @@ impl Aml for MemoryManager {
     }
 }
 
+#[cfg(feature = "guard_pages")]
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        // Releases the regions nothing else refers to, the others being
+        // released once the last device or vCPU referring to them is gone.
+        self.memory_zones.clear();
+        self.boot_guest_memory = GuestMemoryMmap::default();
+        self.guest_memory = GuestMemoryAtomic::new(GuestMemoryMmap::default());
+        release_guarded_mappings();
+    }
+}
+
 impl Pausable for MemoryManager {}
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -2664,18 +3342,51 @@ impl Snapshottable for MemoryManager {
     }
 }
 
-impl Transportable for MemoryManager {
-    fn send(
+// Calls `f` with the guest address and the part of the `len` bytes found at
+// `offset` in the concatenated content of `ranges` of every range they span.
+fn for_each_snapshot_range(
+    ranges: &MemoryRangeTable,
+    offset: u64,
+    len: usize,
+    mut f: impl FnMut(GuestAddress, Range<usize>) -> result::Result<(), GuestMemoryError>,
+) -> result::Result<(), GuestMemoryError> {
+    let end = offset + len as u64;
+    let mut range_offset = 0;
+    for range in ranges.regions() {
+        let start = offset.max(range_offset);
+        let range_end = end.min(range_offset + range.length);
+        if start < range_end {
+            f(
+                GuestAddress(range.gpa + start - range_offset),
+                (start - offset) as usize..(range_end - offset) as usize,
+            )?;
+        }
+        range_offset += range.length;
+    }
+
+    Ok(())
+}
+
+impl MemoryManager {
+    /// Writes the guest memory to the directory `destination_url`, encrypted
+    /// when `key` is given and compressed when `compress` is set.
+    pub fn send_snapshot(
         &self,
         _snapshot: &Snapshot,
         destination_url: &str,
+        key: Option<&SnapshotKey>,
+        compress: bool,
     ) -> result::Result<(), MigratableError> {
         if self.snapshot_memory_ranges.is_empty() {
             return Ok(());
         }
 
         let mut memory_file_path = url_to_path(destination_url)?;
-        memory_file_path.push(String::from(SNAPSHOT_FILENAME));
+        memory_file_path.push(String::from(if compress {
+            SNAPSHOT_COMPRESSED_FILENAME
+        } else {
+            SNAPSHOT_FILENAME
+        }));
 
         // Create the snapshot file for the entire memory
         let mut memory_file = OpenOptions::new()
@@ -2685,6 +3396,10 @@ impl Transportable for MemoryManager {
             .open(memory_file_path)
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
+        if key.is_some() || compress {
+            return self.write_snapshot_memory(memory_file, key, compress);
+        }
+
         let guest_memory = self.guest_memory.memory();
 
         for range in self.snapshot_memory_ranges.regions() {
@@ -2711,6 +3426,105 @@ impl Transportable for MemoryManager {
         }
         Ok(())
     }
+
+    /// Size of the guest memory saved by the snapshot, if any.
+    pub fn snapshot_memory_len(&self) -> Option<u64> {
+        if self.snapshot_memory_ranges.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.snapshot_memory_ranges
+                .regions()
+                .iter()
+                .map(|r| r.length)
+                .sum(),
+        )
+    }
+
+    /// Writes the guest memory saved by the snapshot into `writer`, encrypted
+    /// with `key` when given and compressed when `compress` is set.
+    pub fn write_snapshot_memory<W: Write>(
+        &self,
+        writer: W,
+        key: Option<&SnapshotKey>,
+        compress: bool,
+    ) -> result::Result<(), MigratableError> {
+        match key {
+            Some(key) => {
+                let name = if compress {
+                    SNAPSHOT_COMPRESSED_FILENAME
+                } else {
+                    SNAPSHOT_FILENAME
+                };
+                let mut writer = EncryptedWriter::new(writer, key, name)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                self.copy_snapshot_memory(&mut writer, compress)?;
+                writer
+                    .finish()
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            }
+            None => {
+                let mut writer = writer;
+                self.copy_snapshot_memory(&mut writer, compress)?;
+                writer
+                    .flush()
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The guest memory goes through a buffer, so that nothing but the
+    // encrypted content reaches the file when the snapshot is encrypted.
+    fn copy_snapshot_memory(
+        &self,
+        writer: &mut impl Write,
+        compress: bool,
+    ) -> result::Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+        if compress {
+            let guest_memory = &*guest_memory;
+            let ranges = &self.snapshot_memory_ranges;
+            let len = ranges.regions().iter().map(|r| r.length).sum();
+            return snapshot_compression::compress(writer, len, |offset, buf| {
+                for_each_snapshot_range(ranges, offset, buf.len(), |addr, range| {
+                    guest_memory.read_slice(&mut buf[range], addr)
+                })
+                .map_err(io::Error::other)
+            })
+            .map(|_| ())
+            .map_err(|e| MigratableError::MigrateSend(e.into()));
+        }
+
+        let mut buffer = vec![0u8; SNAPSHOT_COPY_SIZE];
+        for range in self.snapshot_memory_ranges.regions() {
+            let mut offset: u64 = 0;
+            while offset < range.length {
+                let len = std::cmp::min(range.length - offset, buffer.len() as u64) as usize;
+                guest_memory
+                    .read_slice(&mut buffer[..len], GuestAddress(range.gpa + offset))
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                writer
+                    .write_all(&buffer[..len])
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                offset += len as u64;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Transportable for MemoryManager {
+    fn send(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+    ) -> result::Result<(), MigratableError> {
+        self.send_snapshot(snapshot, destination_url, None, false)
+    }
 }
 
 impl Migratable for MemoryManager {
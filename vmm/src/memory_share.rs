@@ -0,0 +1,297 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Memory zones shared between two VMMs: the producer hands out a read-only
+//! file descriptor of the zone backing memory to whoever connects to its
+//! socket, the consumer mapping it into its own guest.
+
+use crate::seccomp_violations;
+use seccompiler::BpfProgram;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+const LISTENER_TOKEN: u64 = 0;
+const STOP_TOKEN: u64 = 1;
+const EPOLL_EVENTS_LEN: usize = 2;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error reopening the memory zone file read-only: {0}")]
+    Reopen(#[source] io::Error),
+    #[error("Error binding the memory zone socket {0}: {1}")]
+    Bind(PathBuf, #[source] io::Error),
+    #[error("Memory zone socket {0} is in use")]
+    InUse(PathBuf),
+    #[error("Error setting up the memory zone sharing thread: {0}")]
+    ThreadSetup(#[source] io::Error),
+    #[error("Error spawning the memory zone sharing thread: {0}")]
+    ThreadSpawn(#[source] io::Error),
+    #[error("Error connecting to the memory zone socket {0}: {1}")]
+    Connect(PathBuf, #[source] io::Error),
+    #[error("Error receiving the memory zone file: {0}")]
+    Receive(#[source] vmm_sys_util::errno::Error),
+    #[error("No memory zone file was received")]
+    MissingFile,
+    #[error("Memory zone file of {0} bytes is smaller than the zone size {1}")]
+    TooSmall(u64, u64),
+    #[error("Error getting the memory zone file size: {0}")]
+    Metadata(#[source] io::Error),
+}
+
+// Removes the socket left behind by a producer which is gone, refusing to
+// take over the socket of a running one.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                fs::remove_file(path).map_err(|e| Error::Bind(path.to_owned(), e))
+            }
+            _ => Err(Error::InUse(path.to_owned())),
+        },
+        // Anything else is left for bind() to fail on
+        _ => Ok(()),
+    }
+}
+
+// Only the user running the VMM is handed the zone, regardless of the socket
+// permissions at the time of the connection.
+fn peer_allowed(stream: &UnixStream) -> io::Result<bool> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: FFI call with a valid credentials structure and its size
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: FFI call without side effects
+    Ok(cred.uid == unsafe { libc::geteuid() })
+}
+
+fn serve_clients(listener: &UnixListener, file: &File, stop_evt: &EventFd) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // SAFETY: epoll_fd is valid
+    let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    for (fd, token) in [
+        (listener.as_raw_fd(), LISTENER_TOKEN),
+        (stop_evt.as_raw_fd(), STOP_TOKEN),
+    ] {
+        epoll::ctl(
+            epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, token),
+        )?;
+    }
+
+    let mut events = [epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+    loop {
+        let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            Ok(res) => res,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            if event.data == STOP_TOKEN {
+                return Ok(());
+            }
+
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    warn!("Error accepting memory zone client: {}", e);
+                    continue;
+                }
+            };
+            match peer_allowed(&stream) {
+                Ok(true) => {
+                    if let Err(e) = stream.send_with_fd(&[0u8][..], file.as_raw_fd()) {
+                        warn!("Error sending the memory zone file: {}", e);
+                    }
+                }
+                Ok(false) => warn!("Memory zone client of another user rejected"),
+                Err(e) => warn!("Error getting the memory zone client credentials: {}", e),
+            }
+        }
+    }
+}
+
+/// Hands out a read-only file descriptor of the memory zone to each client of
+/// the socket, for as long as it is alive.
+pub struct MemoryZoneExport {
+    path: PathBuf,
+    // Identifies the socket this process created, as opposed to a socket
+    // another process could have bound at the same path in the meantime.
+    socket_id: (u64, u64),
+    stop_evt: EventFd,
+    // Taken by the thread once started
+    pending: Mutex<Option<(UnixListener, File)>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MemoryZoneExport {
+    /// Creates the socket of the zone, which only the user running the VMM
+    /// can connect to. The clients are only served once `start()` is called.
+    pub fn new(path: &Path, file: &File) -> Result<Self, Error> {
+        // The consumer must not be able to write to the zone, which it would
+        // be allowed to if given the producer's own descriptor.
+        let file = OpenOptions::new()
+            .read(true)
+            .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .map_err(Error::Reopen)?;
+
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path).map_err(|e| Error::Bind(path.to_owned(), e))?;
+        let metadata = fs::symlink_metadata(path).map_err(|e| Error::Bind(path.to_owned(), e))?;
+        let socket_id = (metadata.dev(), metadata.ino());
+        let export = MemoryZoneExport {
+            path: path.to_owned(),
+            socket_id,
+            stop_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::ThreadSetup)?,
+            pending: Mutex::new(None),
+            thread: Mutex::new(None),
+        };
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| Error::Bind(path.to_owned(), e))?;
+        listener.set_nonblocking(true).map_err(Error::ThreadSetup)?;
+        *export.pending.lock().unwrap() = Some((listener, file));
+
+        Ok(export)
+    }
+
+    /// Starts serving the clients from a thread applying `seccomp_filter`.
+    pub fn start(&self, seccomp_filter: BpfProgram) -> Result<(), Error> {
+        let Some((listener, file)) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let stop_evt = self.stop_evt.try_clone().map_err(Error::ThreadSetup)?;
+
+        let thread = thread::Builder::new()
+            .name("memory-share".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = seccomp_violations::apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+                if let Err(e) = serve_clients(&listener, &file, &stop_evt) {
+                    error!("Error serving the memory zone clients: {}", e);
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+        *self.thread.lock().unwrap() = Some(thread);
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryZoneExport {
+    fn drop(&mut self) {
+        self.stop_evt.write(1).ok();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().ok();
+        }
+        let created = fs::symlink_metadata(&self.path)
+            .map(|metadata| (metadata.dev(), metadata.ino()) == self.socket_id)
+            .unwrap_or(false);
+        if created {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Gets the read-only file of the memory zone shared by the producer
+/// listening on `path`, which must be able to back `size` bytes.
+pub fn import(path: &Path, size: u64) -> Result<File, Error> {
+    let stream = UnixStream::connect(path).map_err(|e| Error::Connect(path.to_owned(), e))?;
+
+    let mut buf = [0u8; 1];
+    let (_, file) = stream.recv_with_fd(&mut buf).map_err(Error::Receive)?;
+    let file = file.ok_or(Error::MissingFile)?;
+
+    let len = file.metadata().map_err(Error::Metadata)?.len();
+    if len < size {
+        return Err(Error::TooSmall(len, size));
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_memory_zone_sharing() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("zone.sock");
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.write_all(b"shared").unwrap();
+        file.set_len(0x1000).unwrap();
+
+        let export = MemoryZoneExport::new(&path, &file).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // Nothing is served until started, the socket being taken meanwhile
+        assert!(matches!(
+            MemoryZoneExport::new(&path, &file),
+            Err(Error::InUse(_))
+        ));
+        export.start(vec![]).unwrap();
+
+        let mut imported = import(&path, 0x1000).unwrap();
+        let mut buf = [0u8; 6];
+        imported.seek(SeekFrom::Start(0)).unwrap();
+        imported.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"shared");
+        assert!(imported.write_all(b"consumer").is_err());
+
+        assert!(matches!(
+            import(&path, 0x2000),
+            Err(Error::TooSmall(0x1000, 0x2000))
+        ));
+
+        drop(export);
+        assert!(!path.exists());
+
+        // A stale socket is replaced, but only the socket created by the
+        // export is removed along with it.
+        drop(UnixListener::bind(&path).unwrap());
+        let export = MemoryZoneExport::new(&path, &file).unwrap();
+        fs::remove_file(&path).unwrap();
+        let other = UnixListener::bind(&path).unwrap();
+        drop(export);
+        assert!(path.exists());
+        drop(other);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Metrics of the VMM in the Prometheus text exposition format.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+
+pub const METRICS_PREFIX: &str = "cloud_hypervisor_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Summary,
+    Untyped,
+}
+
+impl Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Summary => "summary",
+            MetricType::Untyped => "untyped",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// Metric names may only contain letters, digits, underscores and colons.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Builds the text of the metrics, all the samples of a metric family
+/// following its description.
+#[derive(Default)]
+pub struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    /// Starts a metric family, named after `name` once prefixed.
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) {
+        let name = sanitize_name(name);
+        writeln!(self.text, "# HELP {METRICS_PREFIX}{name} {help}").unwrap();
+        writeln!(self.text, "# TYPE {METRICS_PREFIX}{name} {metric_type}").unwrap();
+    }
+
+    /// Adds a sample to the current metric family, `name` possibly carrying
+    /// a suffix such as `_count` or `_sum` for a summary.
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let name = sanitize_name(name);
+        write!(self.text, "{METRICS_PREFIX}{name}").unwrap();
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label_value(value)))
+                .collect();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {value}").unwrap();
+    }
+
+    /// Adds the device counters, one family per counter name.
+    pub fn device_counters(&mut self, counters: &BTreeMap<String, BTreeMap<String, u64>>) {
+        let mut families: BTreeMap<&str, Vec<(&str, u64)>> = BTreeMap::new();
+        for (device, device_counters) in counters {
+            for (counter, value) in device_counters {
+                families
+                    .entry(counter.as_str())
+                    .or_default()
+                    .push((device.as_str(), *value));
+            }
+        }
+
+        // Latencies are reported along with the counters, hence the type of
+        // the families being left unspecified.
+        for (counter, samples) in families {
+            let name = format!("device_{counter}");
+            self.family(&name, MetricType::Untyped, &format!("Device {counter}"));
+            for (device, value) in samples {
+                self.sample(&name, &[("device", device)], value);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.text
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_writer() {
+        let mut writer = MetricsWriter::default();
+        writer.family("vcpu_exits_total", MetricType::Counter, "vCPU exits");
+        writer.sample("vcpu_exits_total", &[("vcpu", "0")], 42);
+        writer.family("guest_memory_bytes", MetricType::Gauge, "Guest memory");
+        writer.sample("guest_memory_bytes", &[], 1u64 << 30);
+        writer.sample("weird-name", &[("path", "a\"b\\c\n")], 1.5);
+
+        assert_eq!(
+            writer.finish(),
+            "# HELP cloud_hypervisor_vcpu_exits_total vCPU exits\n\
+             # TYPE cloud_hypervisor_vcpu_exits_total counter\n\
+             cloud_hypervisor_vcpu_exits_total{vcpu=\"0\"} 42\n\
+             # HELP cloud_hypervisor_guest_memory_bytes Guest memory\n\
+             # TYPE cloud_hypervisor_guest_memory_bytes gauge\n\
+             cloud_hypervisor_guest_memory_bytes 1073741824\n\
+             cloud_hypervisor_weird_name{path=\"a\\\"b\\\\c\\n\"} 1.5\n"
+        );
+    }
+
+    #[test]
+    fn test_device_counters() {
+        let mut counters = BTreeMap::new();
+        counters.insert(
+            "_disk0".to_string(),
+            BTreeMap::from([
+                ("read_bytes".to_string(), 512),
+                ("write_bytes".to_string(), 0),
+            ]),
+        );
+        counters.insert(
+            "_disk1".to_string(),
+            BTreeMap::from([("read_bytes".to_string(), 1024)]),
+        );
+
+        let mut writer = MetricsWriter::default();
+        writer.device_counters(&counters);
+        assert_eq!(
+            writer.finish(),
+            "# HELP cloud_hypervisor_device_read_bytes Device read_bytes\n\
+             # TYPE cloud_hypervisor_device_read_bytes untyped\n\
+             cloud_hypervisor_device_read_bytes{device=\"_disk0\"} 512\n\
+             cloud_hypervisor_device_read_bytes{device=\"_disk1\"} 1024\n\
+             # HELP cloud_hypervisor_device_write_bytes Device write_bytes\n\
+             # TYPE cloud_hypervisor_device_write_bytes untyped\n\
+             cloud_hypervisor_device_write_bytes{device=\"_disk0\"} 0\n"
+        );
+    }
+}
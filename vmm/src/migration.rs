@@ -4,8 +4,12 @@
 
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggableError;
+use crate::snapshot_encryption::{self, SnapshotKey};
+use crate::snapshot_stream::{self, SnapshotReader};
 use crate::{config::VmConfig, vm::VmSnapshot};
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
@@ -13,6 +17,47 @@ use vm_migration::{MigratableError, Snapshot};
 
 pub const SNAPSHOT_STATE_FILE: &str = "state.json";
 pub const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+/// Files a snapshot is made of, the guest memory one being left out when
+/// there is no memory to save, and compressed or not.
+pub const SNAPSHOT_FILES: [&str; 4] = [
+    SNAPSHOT_CONFIG_FILE,
+    SNAPSHOT_STATE_FILE,
+    crate::memory_manager::SNAPSHOT_FILENAME,
+    crate::memory_manager::SNAPSHOT_COMPRESSED_FILENAME,
+];
+
+/// A device or a feature preventing the VM from being live migrated or
+/// snapshotted.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MigrationBlocker {
+    /// Identifier of the device, or name of the feature
+    pub id: String,
+    /// Why the operation is not possible
+    pub reason: String,
+}
+
+impl MigrationBlocker {
+    pub fn new(id: &str, reason: &str) -> Self {
+        MigrationBlocker {
+            id: id.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for MigrationBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.reason)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MigrationBlockers {
+    /// Devices and features preventing live migration
+    pub migration: Vec<MigrationBlocker>,
+    /// Devices and features preventing snapshot
+    pub snapshot: Vec<MigrationBlocker>,
+}
 
 pub fn url_to_path(url: &str) -> std::result::Result<PathBuf, MigratableError> {
     let path: PathBuf = url
@@ -43,38 +88,94 @@ pub fn url_to_file(url: &str) -> std::result::Result<PathBuf, GuestDebuggableErr
     Ok(file)
 }
 
-pub fn recv_vm_config(source_url: &str) -> std::result::Result<VmConfig, MigratableError> {
-    let mut vm_config_path = url_to_path(source_url)?;
+/// Where the snapshot being restored is read from.
+pub enum SnapshotSource {
+    /// URL of the directory holding the snapshot files
+    Url(String),
+    /// Stream the snapshot files are read out of, one after the other
+    Stream(SnapshotReader),
+}
+
+impl SnapshotSource {
+    pub fn open(source_url: &str) -> std::result::Result<Self, MigratableError> {
+        if !snapshot_stream::is_stream_url(source_url) {
+            return Ok(SnapshotSource::Url(source_url.to_string()));
+        }
+
+        SnapshotReader::open(source_url)
+            .map(SnapshotSource::Stream)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))
+    }
+}
 
-    vm_config_path.push(SNAPSHOT_CONFIG_FILE);
+// Reads the snapshot file `name`, decrypting it when `key` is given.
+fn recv_snapshot_file(
+    source: &mut SnapshotSource,
+    name: &str,
+    key: Option<&SnapshotKey>,
+) -> std::result::Result<Vec<u8>, MigratableError> {
+    let source_url = match source {
+        SnapshotSource::Url(source_url) => source_url,
+        SnapshotSource::Stream(stream) => {
+            return stream
+                .read_file(name)
+                .map_err(|e| MigratableError::MigrateReceive(e.into()));
+        }
+    };
+    let mut path = url_to_path(source_url)?;
+
+    path.push(name);
+
+    if let Some(key) = key {
+        return snapshot_encryption::read_file(&path, key)
+            .map_err(|e| MigratableError::MigrateReceive(e.into()));
+    }
 
     // Try opening the snapshot file
-    let mut vm_config_file =
-        File::open(vm_config_path).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    let mut file = File::open(path).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
     let mut bytes = Vec::new();
-    vm_config_file
-        .read_to_end(&mut bytes)
+    file.read_to_end(&mut bytes)
         .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
 
-    serde_json::from_slice(&bytes).map_err(|e| MigratableError::MigrateReceive(e.into()))
+    Ok(bytes)
 }
 
-pub fn recv_vm_state(source_url: &str) -> std::result::Result<Snapshot, MigratableError> {
-    let mut vm_state_path = url_to_path(source_url)?;
+pub fn recv_vm_config(
+    source: &mut SnapshotSource,
+    key: Option<&SnapshotKey>,
+) -> std::result::Result<VmConfig, MigratableError> {
+    let bytes = recv_snapshot_file(source, SNAPSHOT_CONFIG_FILE, key)?;
 
-    vm_state_path.push(SNAPSHOT_STATE_FILE);
+    serde_json::from_slice(&bytes).map_err(|e| MigratableError::MigrateReceive(e.into()))
+}
 
-    // Try opening the snapshot file
-    let mut vm_state_file =
-        File::open(vm_state_path).map_err(|e| MigratableError::MigrateReceive(e.into()))?;
-    let mut bytes = Vec::new();
-    vm_state_file
-        .read_to_end(&mut bytes)
-        .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+pub fn recv_vm_state(
+    source: &mut SnapshotSource,
+    key: Option<&SnapshotKey>,
+) -> std::result::Result<Snapshot, MigratableError> {
+    let bytes = recv_snapshot_file(source, SNAPSHOT_STATE_FILE, key)?;
 
     serde_json::from_slice(&bytes).map_err(|e| MigratableError::MigrateReceive(e.into()))
 }
 
+/// Checks the files of the snapshot match its manifest, signed with `key`.
+/// Streamed snapshots can't be encrypted, their manifest being only known
+/// once the whole stream is read.
+pub fn verify_snapshot(
+    source: &SnapshotSource,
+    key: &SnapshotKey,
+) -> std::result::Result<(), MigratableError> {
+    match source {
+        SnapshotSource::Url(source_url) => {
+            snapshot_encryption::verify_manifest(&url_to_path(source_url)?, &SNAPSHOT_FILES, key)
+                .map_err(|e| MigratableError::MigrateReceive(e.into()))
+        }
+        SnapshotSource::Stream(_) => Err(MigratableError::MigrateReceive(anyhow!(
+            "Encrypted snapshots can't be streamed"
+        ))),
+    }
+}
+
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
     if let Some(snapshot_data) = snapshot.snapshot_data.as_ref() {
         return snapshot_data.to_state();
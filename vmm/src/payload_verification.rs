@@ -0,0 +1,475 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the payloads directly loaded into the guest, through
+//! detached PKCS#7 signatures, or the Authenticode signature embedded in EFI
+//! stub kernels, checked against a set of trusted certificates before
+//! anything gets loaded.
+
+use serde::{Deserialize, Serialize};
+
+/// Outcome of the verification of a payload, reported through `vm.info`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PayloadVerification {
+    /// Verified payload, either "kernel" or "initramfs"
+    pub payload: String,
+    /// SHA-256 digest of the verified payload
+    pub sha256: String,
+    /// Common name of the certificate the payload is signed with
+    pub signer: String,
+}
+
+#[cfg(feature = "payload_verification")]
+pub use self::verification::{verify_payloads, Error};
+
+#[cfg(feature = "payload_verification")]
+mod verification {
+    use super::PayloadVerification;
+    use crate::vm_config::{PayloadConfig, PayloadVerificationConfig};
+    use openssl::error::ErrorStack;
+    use openssl::hash::{hash, Hasher, MessageDigest};
+    use openssl::nid::Nid;
+    use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+    use openssl::stack::Stack;
+    use openssl::x509::store::{X509Store, X509StoreBuilder};
+    use openssl::x509::{X509PurposeId, X509};
+    use std::fs::{self, File};
+    use std::io::{self, Read, Seek};
+    use std::ops::Range;
+    use std::path::{Path, PathBuf};
+    use thiserror::Error;
+
+    // Offset of the offset of the PE header in the MS-DOS stub
+    const PE_HEADER_OFFSET: usize = 0x3c;
+    const PE_SIGNATURE: &[u8] = b"PE\0\0";
+    const COFF_HEADER_SIZE: usize = 20;
+    const PE32_MAGIC: u16 = 0x10b;
+    const PE32_PLUS_MAGIC: u16 = 0x20b;
+    // Offset of the checksum in the optional header
+    const CHECKSUM_OFFSET: usize = 64;
+    const DATA_DIRECTORY_ENTRY_SIZE: usize = 8;
+    const CERTIFICATE_TABLE_INDEX: usize = 4;
+    const WIN_CERTIFICATE_HEADER_SIZE: usize = 8;
+    const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+    const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+    // DER encoded content of the OIDs of the SpcIndirectDataContent, and of
+    // the digest algorithms Authenticode signatures are accepted with
+    const SPC_INDIRECT_DATA_OID: &[u8] =
+        &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+    const SHA256_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+    const SHA384_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+    const SHA512_OID: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+    const DER_OCTET_STRING: u8 = 0x04;
+    const DER_OID: u8 = 0x06;
+
+    #[derive(Debug, Error)]
+    pub enum Error {
+        #[error("Cannot read {0:?}: {1}")]
+        Read(PathBuf, #[source] io::Error),
+        #[error("Invalid trusted certificates: {0}")]
+        InvalidCerts(#[source] ErrorStack),
+        #[error("Invalid signature {0:?}: {1}")]
+        InvalidSignature(PathBuf, #[source] ErrorStack),
+        #[error("Signature of the {0} does not verify: {1}")]
+        Verification(&'static str, #[source] ErrorStack),
+        #[error("No signer for the {0}")]
+        MissingSigner(&'static str),
+        #[error("The {0} is not a PE image carrying an Authenticode signature")]
+        MissingAuthenticode(&'static str),
+        #[error("Invalid Authenticode signature of the {0}")]
+        InvalidAuthenticode(&'static str),
+        #[error("Authenticode digest of the {0} does not match its content")]
+        AuthenticodeDigestMismatch(&'static str),
+    }
+
+    fn read(path: &Path) -> Result<Vec<u8>, Error> {
+        fs::read(path).map_err(|e| Error::Read(path.to_path_buf(), e))
+    }
+
+    // Reads the payload through the file the VM loads it from, leaving the
+    // file offset untouched for the loader.
+    fn read_payload(path: &Path, mut file: &File) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        file.rewind()
+            .and_then(|_| file.read_to_end(&mut data))
+            .and_then(|_| file.rewind())
+            .map_err(|e| Error::Read(path.to_path_buf(), e))?;
+
+        Ok(data)
+    }
+
+    fn trusted_store(certs: &Path) -> Result<X509Store, Error> {
+        let mut builder = X509StoreBuilder::new().map_err(Error::InvalidCerts)?;
+        for cert in X509::stack_from_pem(&read(certs)?).map_err(Error::InvalidCerts)? {
+            builder.add_cert(cert).map_err(Error::InvalidCerts)?;
+        }
+        // Payloads are usually signed with code signing certificates, which
+        // the default S/MIME purpose would reject.
+        builder
+            .set_purpose(X509PurposeId::ANY)
+            .map_err(Error::InvalidCerts)?;
+
+        Ok(builder.build())
+    }
+
+    // Common name of the certificate the payload is signed with
+    fn signer(name: &'static str, pkcs7: &Pkcs7) -> Result<String, Error> {
+        let certs = Stack::new().map_err(Error::InvalidCerts)?;
+        let signers = pkcs7
+            .signers(&certs, Pkcs7Flags::empty())
+            .map_err(|e| Error::Verification(name, e))?;
+        let signer = signers
+            .iter()
+            .next()
+            .ok_or(Error::MissingSigner(name))?
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|e| e.data().as_utf8().ok())
+            .map(|cn| cn.to_string())
+            .unwrap_or_default();
+
+        Ok(signer)
+    }
+
+    fn verification(
+        name: &'static str,
+        data: &[u8],
+        pkcs7: &Pkcs7,
+    ) -> Result<PayloadVerification, Error> {
+        let sha256 = hash(MessageDigest::sha256(), data)
+            .map_err(|e| Error::Verification(name, e))?
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        Ok(PayloadVerification {
+            payload: name.to_string(),
+            sha256,
+            signer: signer(name, pkcs7)?,
+        })
+    }
+
+    fn verify_payload(
+        name: &'static str,
+        payload: &Path,
+        file: &File,
+        signature: &Path,
+        store: &X509Store,
+    ) -> Result<PayloadVerification, Error> {
+        let data = read_payload(payload, file)?;
+        let signature_data = read(signature)?;
+        // Accept both DER and PEM encoded signatures
+        let pkcs7 = Pkcs7::from_der(&signature_data)
+            .or_else(|_| Pkcs7::from_pem(&signature_data))
+            .map_err(|e| Error::InvalidSignature(signature.to_path_buf(), e))?;
+
+        // Signer certificates are expected to be embedded in the signature
+        let certs = Stack::new().map_err(Error::InvalidCerts)?;
+        pkcs7
+            .verify(&certs, store, Some(&data), None, Pkcs7Flags::BINARY)
+            .map_err(|e| Error::Verification(name, e))?;
+
+        verification(name, &data, &pkcs7)
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> Option<usize> {
+        Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize)
+    }
+
+    // Location of the fields of a PE image the Authenticode digest leaves
+    // out: the checksum, the certificate table entry of the data directories,
+    // and the certificate table it points to.
+    #[derive(Debug, PartialEq, Eq)]
+    struct PeSignature {
+        checksum: usize,
+        certificate_entry: usize,
+        certificate_table: Range<usize>,
+    }
+
+    fn pe_signature(image: &[u8]) -> Option<PeSignature> {
+        if image.get(..2)? != b"MZ" {
+            return None;
+        }
+        let pe_header = u32_at(image, PE_HEADER_OFFSET)?;
+        if image.get(pe_header..pe_header.checked_add(PE_SIGNATURE.len())?)? != PE_SIGNATURE {
+            return None;
+        }
+
+        let optional_header = pe_header + PE_SIGNATURE.len() + COFF_HEADER_SIZE;
+        let (directories_count, directories) = match u16_at(image, optional_header)? {
+            PE32_MAGIC => (optional_header + 92, optional_header + 96),
+            PE32_PLUS_MAGIC => (optional_header + 108, optional_header + 112),
+            _ => return None,
+        };
+        if u32_at(image, directories_count)? <= CERTIFICATE_TABLE_INDEX {
+            return None;
+        }
+
+        let certificate_entry = directories + CERTIFICATE_TABLE_INDEX * DATA_DIRECTORY_ENTRY_SIZE;
+        // Unlike the other entries, the certificate table one holds a file
+        // offset.
+        let offset = u32_at(image, certificate_entry)?;
+        let size = u32_at(image, certificate_entry + 4)?;
+        let end = offset.checked_add(size)?;
+        if size == 0 || offset < certificate_entry + DATA_DIRECTORY_ENTRY_SIZE || end > image.len()
+        {
+            return None;
+        }
+
+        Some(PeSignature {
+            checksum: optional_header + CHECKSUM_OFFSET,
+            certificate_entry,
+            certificate_table: offset..end,
+        })
+    }
+
+    // First PKCS#7 signature of the certificate table.
+    fn pe_pkcs7_signature(table: &[u8]) -> Option<&[u8]> {
+        let mut offset = 0;
+        while offset + WIN_CERTIFICATE_HEADER_SIZE <= table.len() {
+            let length = u32_at(table, offset)?;
+            let certificate = table.get(offset + WIN_CERTIFICATE_HEADER_SIZE..offset + length)?;
+            if u16_at(table, offset + 4)? == WIN_CERT_REVISION_2_0
+                && u16_at(table, offset + 6)? == WIN_CERT_TYPE_PKCS_SIGNED_DATA
+            {
+                return Some(certificate);
+            }
+            // The entries are 8 bytes aligned
+            offset += length.checked_next_multiple_of(8)?.max(8);
+        }
+
+        None
+    }
+
+    // Authenticode digest of a PE image, covering the whole image but the
+    // fields describing its signature.
+    fn pe_digest(
+        image: &[u8],
+        signature: &PeSignature,
+        digest: MessageDigest,
+    ) -> Result<Vec<u8>, ErrorStack> {
+        let mut hasher = Hasher::new(digest)?;
+        for range in [
+            0..signature.checksum,
+            signature.checksum + 4..signature.certificate_entry,
+            signature.certificate_entry + DATA_DIRECTORY_ENTRY_SIZE
+                ..signature.certificate_table.start,
+            signature.certificate_table.end..image.len(),
+        ] {
+            hasher.update(&image[range])?;
+        }
+
+        Ok(hasher.finish()?.to_vec())
+    }
+
+    // Splits a DER element off `der`, returning its tag, its content and
+    // what follows it.
+    fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, der) = der.split_first()?;
+        let (&len, der) = der.split_first()?;
+        let (len, der) = if len & 0x80 == 0 {
+            (len as usize, der)
+        } else {
+            let count = (len & 0x7f) as usize;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let len = der
+                .get(..count)?
+                .iter()
+                .fold(0, |len, byte| len << 8 | *byte as usize);
+            (len, &der[count..])
+        };
+
+        Some((tag, der.get(..len)?, &der[len..]))
+    }
+
+    // Content of the SpcIndirectDataContent an Authenticode signature signs,
+    // along with the digest algorithm and the digest of the image it holds.
+    fn spc_indirect_data(signature: &[u8]) -> Option<(&[u8], MessageDigest, &[u8])> {
+        let (_, content_info, _) = der_element(signature)?;
+        let (_, _, content_info) = der_element(content_info)?;
+        let (_, signed_data, _) = der_element(content_info)?;
+        let (_, signed_data, _) = der_element(signed_data)?;
+        let (_, _, signed_data) = der_element(signed_data)?;
+        let (_, _, signed_data) = der_element(signed_data)?;
+        let (_, encapsulated, _) = der_element(signed_data)?;
+        let (tag, content_type, encapsulated) = der_element(encapsulated)?;
+        if tag != DER_OID || content_type != SPC_INDIRECT_DATA_OID {
+            return None;
+        }
+        let (_, content, _) = der_element(encapsulated)?;
+        let (_, indirect_data, _) = der_element(content)?;
+
+        let (_, _, message_digest) = der_element(indirect_data)?;
+        let (_, digest_info, _) = der_element(message_digest)?;
+        let (_, algorithm, digest_info) = der_element(digest_info)?;
+        let (tag, algorithm, _) = der_element(algorithm)?;
+        if tag != DER_OID {
+            return None;
+        }
+        let algorithm = match algorithm {
+            SHA256_OID => MessageDigest::sha256(),
+            SHA384_OID => MessageDigest::sha384(),
+            SHA512_OID => MessageDigest::sha512(),
+            _ => return None,
+        };
+        let (tag, digest, _) = der_element(digest_info)?;
+        if tag != DER_OCTET_STRING {
+            return None;
+        }
+
+        Some((indirect_data, algorithm, digest))
+    }
+
+    fn verify_authenticode(
+        name: &'static str,
+        payload: &Path,
+        file: &File,
+        store: &X509Store,
+    ) -> Result<PayloadVerification, Error> {
+        let image = read_payload(payload, file)?;
+        let pe = pe_signature(&image).ok_or(Error::MissingAuthenticode(name))?;
+        let signature = pe_pkcs7_signature(&image[pe.certificate_table.clone()])
+            .ok_or(Error::MissingAuthenticode(name))?;
+        let (indirect_data, algorithm, digest) =
+            spc_indirect_data(signature).ok_or(Error::InvalidAuthenticode(name))?;
+
+        if pe_digest(&image, &pe, algorithm).map_err(|e| Error::Verification(name, e))? != digest {
+            return Err(Error::AuthenticodeDigestMismatch(name));
+        }
+
+        // What is signed is the SpcIndirectDataContent, without its DER
+        // header, which holds the digest of the image.
+        let pkcs7 = Pkcs7::from_der(signature).map_err(|e| Error::Verification(name, e))?;
+        let certs = Stack::new().map_err(Error::InvalidCerts)?;
+        pkcs7
+            .verify(&certs, store, Some(indirect_data), None, Pkcs7Flags::BINARY)
+            .map_err(|e| Error::Verification(name, e))?;
+
+        verification(name, &image, &pkcs7)
+    }
+
+    /// Verify the signatures of the kernel and initramfs, failing as soon as
+    /// one of them does not verify. Without a detached signature, the kernel
+    /// must be a PE image carrying an Authenticode signature. The payloads
+    /// are read from the files they are loaded from, so that they can't be
+    /// swapped once verified.
+    pub fn verify_payloads(
+        config: &PayloadVerificationConfig,
+        payload: &PayloadConfig,
+        kernel: Option<&File>,
+        initramfs: Option<&File>,
+    ) -> Result<Vec<PayloadVerification>, Error> {
+        let store = trusted_store(&config.certs)?;
+
+        let mut verifications = Vec::new();
+        if let (Some(path), Some(file)) = (&payload.kernel, kernel) {
+            verifications.push(match &config.kernel_signature {
+                Some(signature) => verify_payload("kernel", path, file, signature, &store)?,
+                None => verify_authenticode("kernel", path, file, &store)?,
+            });
+        }
+        if let (Some(path), Some(file), Some(signature)) =
+            (&payload.initramfs, initramfs, &config.initramfs_signature)
+        {
+            verifications.push(verify_payload("initramfs", path, file, signature, &store)?);
+        }
+
+        Ok(verifications)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // PE32+ image made of its headers, followed by a certificate table
+        // holding `certificate`.
+        fn pe_image(certificate: &[u8]) -> Vec<u8> {
+            let pe_header = 0x80;
+            let optional_header = pe_header + 4 + COFF_HEADER_SIZE;
+            let directories = optional_header + 112;
+            let mut image = vec![0u8; directories + 16 * DATA_DIRECTORY_ENTRY_SIZE];
+            image[..2].copy_from_slice(b"MZ");
+            image[PE_HEADER_OFFSET..PE_HEADER_OFFSET + 4]
+                .copy_from_slice(&(pe_header as u32).to_le_bytes());
+            image[pe_header..pe_header + 4].copy_from_slice(PE_SIGNATURE);
+            image[optional_header..optional_header + 2]
+                .copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+            image[optional_header + 108..optional_header + 112]
+                .copy_from_slice(&16u32.to_le_bytes());
+
+            let table = image.len();
+            let length = WIN_CERTIFICATE_HEADER_SIZE + certificate.len();
+            let entry = directories + CERTIFICATE_TABLE_INDEX * DATA_DIRECTORY_ENTRY_SIZE;
+            image[entry..entry + 4].copy_from_slice(&(table as u32).to_le_bytes());
+            image[entry + 4..entry + 8].copy_from_slice(&(length as u32).to_le_bytes());
+            image.extend_from_slice(&(length as u32).to_le_bytes());
+            image.extend_from_slice(&WIN_CERT_REVISION_2_0.to_le_bytes());
+            image.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+            image.extend_from_slice(certificate);
+            image
+        }
+
+        #[test]
+        fn test_pe_signature() {
+            let image = pe_image(b"signature");
+            let pe = pe_signature(&image).unwrap();
+            let optional_header = 0x80 + 4 + COFF_HEADER_SIZE;
+            assert_eq!(
+                pe,
+                PeSignature {
+                    checksum: optional_header + CHECKSUM_OFFSET,
+                    certificate_entry: optional_header + 112 + 32,
+                    certificate_table: image.len() - 17..image.len(),
+                }
+            );
+            assert_eq!(
+                pe_pkcs7_signature(&image[pe.certificate_table.clone()]),
+                Some(&b"signature"[..])
+            );
+
+            // The digest doesn't depend on the fields describing the
+            // signature
+            let mut other = pe_image(b"other signature");
+            other[pe.checksum] = 0xff;
+            let other_pe = pe_signature(&other).unwrap();
+            assert_eq!(
+                pe_digest(&image, &pe, MessageDigest::sha256()).unwrap(),
+                pe_digest(&other, &other_pe, MessageDigest::sha256()).unwrap()
+            );
+            other[0x40] = 0xff;
+            assert_ne!(
+                pe_digest(&image, &pe, MessageDigest::sha256()).unwrap(),
+                pe_digest(&other, &other_pe, MessageDigest::sha256()).unwrap()
+            );
+
+            // Not a signed PE image
+            assert_eq!(pe_signature(b"\x7fELF"), None);
+            let mut unsigned = image.clone();
+            unsigned.truncate(pe.certificate_table.start);
+            assert_eq!(pe_signature(&unsigned), None);
+        }
+
+        #[test]
+        fn test_der_element() {
+            assert_eq!(
+                der_element(&[0x30, 0x02, 0x05, 0x00, 0xff]),
+                Some((0x30, &[0x05, 0x00][..], &[0xff][..]))
+            );
+            let mut long = vec![0x04, 0x81, 0x80];
+            long.extend_from_slice(&[0xaa; 0x80]);
+            assert_eq!(der_element(&long), Some((0x04, &long[3..], &[][..])));
+            // Truncated content
+            assert_eq!(der_element(&[0x04, 0x03, 0x00]), None);
+        }
+    }
+}
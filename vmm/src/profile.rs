@@ -0,0 +1,337 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::threads::{list_threads, ThreadInfo};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+#[cfg(feature = "pprof")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "pprof")]
+use std::thread;
+#[cfg(feature = "pprof")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "pprof")]
+use thiserror::Error;
+
+/// Statistics of the heap allocator, as reported by glibc.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AllocatorStats {
+    /// Bytes allocated from the main arena and the thread arenas
+    pub arena_bytes: u64,
+    /// Bytes of the large allocations directly mapped
+    pub mmap_bytes: u64,
+    /// Number of large allocations directly mapped
+    pub mmap_count: u64,
+    /// Bytes in use by the application
+    pub in_use_bytes: u64,
+    /// Bytes free within the arenas, not returned to the system yet
+    pub free_bytes: u64,
+    /// Bytes which could be released at the top of the main arena
+    pub releasable_bytes: u64,
+}
+
+/// Memory footprint of the VMM process.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ProcessMemory {
+    pub virtual_bytes: u64,
+    pub resident_bytes: u64,
+    /// Highest resident set size reached since the VMM started
+    pub peak_resident_bytes: u64,
+    /// Part of the resident set made of anonymous memory, which includes the
+    /// heap but also the guest RAM when it isn't backed by a file
+    pub anonymous_bytes: u64,
+}
+
+/// CPU time consumed by all the threads of the VMM process, including the
+/// ones which exited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct ProcessCpuTime {
+    /// Time spent in user mode, including the guest time, in microseconds
+    pub user_time_us: u64,
+    /// Time spent in kernel mode, in microseconds
+    pub system_time_us: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThreadProfile {
+    #[serde(flatten)]
+    pub info: ThreadInfo,
+    /// CPU time spent in user space (including running the guest for a
+    /// vCPU thread) since the thread started
+    pub user_time_us: u64,
+    /// CPU time spent in the kernel since the thread started
+    pub system_time_us: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VmmProfile {
+    /// Only available when the VMM allocates from the glibc heap, which is
+    /// not the case when built with the `pprof` feature
+    pub allocator: Option<AllocatorStats>,
+    pub memory: ProcessMemory,
+    pub threads: Vec<ThreadProfile>,
+}
+
+#[cfg(all(target_env = "gnu", not(feature = "pprof")))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    // SAFETY: FFI call, trivially safe
+    let info = unsafe { libc::mallinfo2() };
+
+    Some(AllocatorStats {
+        arena_bytes: info.arena as u64,
+        mmap_bytes: info.hblkhd as u64,
+        mmap_count: info.hblks as u64,
+        in_use_bytes: info.uordblks as u64,
+        free_bytes: info.fordblks as u64,
+        releasable_bytes: info.keepcost as u64,
+    })
+}
+
+#[cfg(any(not(target_env = "gnu"), feature = "pprof"))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+fn parse_process_memory(status: &str) -> ProcessMemory {
+    let field = |name: &str| -> u64 {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.trim().strip_suffix("kB"))
+            .and_then(|kb| kb.trim().parse::<u64>().ok())
+            .map(|kb| kb << 10)
+            .unwrap_or_default()
+    };
+
+    ProcessMemory {
+        virtual_bytes: field("VmSize"),
+        resident_bytes: field("VmRSS"),
+        peak_resident_bytes: field("VmHWM"),
+        anonymous_bytes: field("RssAnon"),
+    }
+}
+
+// Parses the user and system times (fields 14 and 15, in clock ticks) out of
+// the content of /proc/<pid>/task/<tid>/stat.
+fn parse_thread_times(stat: &str) -> Option<(u64, u64)> {
+    // The thread name can contain spaces, hence fields are split after it,
+    // starting from the third one.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(14 - 3);
+    let user = fields.next()?.parse().ok()?;
+    let system = fields.next()?.parse().ok()?;
+
+    Some((user, system))
+}
+
+/// Gathers the memory usage of the VMM process.
+pub fn process_memory() -> io::Result<ProcessMemory> {
+    Ok(parse_process_memory(&fs::read_to_string(
+        "/proc/self/status",
+    )?))
+}
+
+/// Gathers the CPU time consumed by the VMM process, from its resource usage.
+pub fn process_cpu_time() -> io::Result<ProcessCpuTime> {
+    // SAFETY: all zeros is a valid pattern
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call with correct arguments
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let us = |time: libc::timeval| time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64;
+
+    Ok(ProcessCpuTime {
+        user_time_us: us(usage.ru_utime),
+        system_time_us: us(usage.ru_stime),
+    })
+}
+
+/// Gathers the memory and CPU usage of the VMM process and of each of its
+/// threads. The device identifiers are used to associate the device threads
+/// with the device they serve.
+pub fn vmm_profile(device_ids: &[String]) -> io::Result<VmmProfile> {
+    // SAFETY: FFI call, trivially safe
+    let us_per_tick = 1_000_000 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+
+    let mut threads = Vec::new();
+    for info in list_threads(device_ids)? {
+        // The thread may have exited since it was listed.
+        let stat = match fs::read_to_string(format!("/proc/self/task/{}/stat", info.tid)) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let (user, system) = parse_thread_times(&stat).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid /proc/self/task/{}/stat", info.tid),
+            )
+        })?;
+
+        threads.push(ThreadProfile {
+            info,
+            user_time_us: user * us_per_tick,
+            system_time_us: system * us_per_tick,
+        });
+    }
+
+    Ok(VmmProfile {
+        allocator: allocator_stats(),
+        memory: process_memory()?,
+        threads,
+    })
+}
+
+/// Sampling duration of a CPU profile when not given.
+#[cfg(feature = "pprof")]
+pub const PPROF_DEFAULT_DURATION_S: u64 = 10;
+/// Sampling frequency of a CPU profile when not given, which is not a
+/// multiple of the usual timer frequencies so that the samples don't end up
+/// in lockstep with the periodic work.
+#[cfg(feature = "pprof")]
+pub const PPROF_DEFAULT_FREQUENCY: u32 = 99;
+
+#[cfg(feature = "pprof")]
+#[derive(Debug, Error)]
+pub enum PprofError {
+    #[error("Error sampling the CPU usage: {0}")]
+    Cpu(#[source] pprof::Error),
+
+    #[error("Invalid CPU sampling frequency {0}")]
+    InvalidFrequency(u32),
+
+    #[error("CPU profiling needs seccomp to be disabled or in log mode")]
+    SeccompEnabled,
+
+    #[error("The CPU profile was cancelled")]
+    Cancelled,
+
+    #[error("Heap profiling is not enabled in the allocator")]
+    HeapProfilingDisabled,
+
+    #[error("Error dumping the heap profile: {0}")]
+    Heap(#[source] anyhow::Error),
+}
+
+/// Samples the call stacks of all the threads of the VMM for the given
+/// duration, returning the profile encoded in the pprof format. The sampling
+/// is stopped early when cancelled.
+#[cfg(feature = "pprof")]
+pub fn cpu_pprof(
+    duration: Duration,
+    frequency: u32,
+    cancel: &AtomicBool,
+) -> Result<Vec<u8>, PprofError> {
+    use pprof::protos::Message;
+
+    let frequency: libc::c_int = frequency
+        .try_into()
+        .ok()
+        .filter(|frequency| *frequency > 0)
+        .ok_or(PprofError::InvalidFrequency(frequency))?;
+    // Unwinding through these libraries isn't reliable, the samples taken in
+    // them being dropped.
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(PprofError::Cpu)?;
+
+    let deadline = Instant::now() + duration;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(PprofError::Cancelled);
+        }
+        thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(PprofError::Cpu)?;
+
+    Ok(profile.encode_to_vec())
+}
+
+/// Dumps the call stacks the sampled live heap allocations were made from,
+/// returning the profile encoded in the gzipped pprof format. Only available
+/// when the VMM runs on jemalloc with profiling enabled.
+#[cfg(feature = "pprof")]
+pub fn heap_pprof() -> Result<Vec<u8>, PprofError> {
+    let mut prof_ctl = jemalloc_pprof::PROF_CTL
+        .as_ref()
+        .ok_or(PprofError::HeapProfilingDisabled)?
+        .blocking_lock();
+    if !prof_ctl.activated() {
+        return Err(PprofError::HeapProfilingDisabled);
+    }
+
+    prof_ctl.dump_pprof().map_err(PprofError::Heap)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_thread_times() {
+        let stat = "4242 (vcpu 0) S 1 4242 4242 0 -1 4194560 91 0 0 0 1234 56 0 0 20 0 1 0";
+        assert_eq!(parse_thread_times(stat), Some((1234, 56)));
+        assert_eq!(parse_thread_times("4242 (vmm) S 1"), None);
+    }
+
+    #[test]
+    fn test_parse_process_memory() {
+        let status = "Name:\tcloud-hypervisor\nVmSize:\t  2048 kB\nVmHWM:\t  1024 kB\nVmRSS:\t   512 kB\nRssAnon:\t   256 kB\n";
+        let memory = parse_process_memory(status);
+        assert_eq!(memory.virtual_bytes, 2048 << 10);
+        assert_eq!(memory.peak_resident_bytes, 1024 << 10);
+        assert_eq!(memory.resident_bytes, 512 << 10);
+        assert_eq!(memory.anonymous_bytes, 256 << 10);
+    }
+
+    #[test]
+    fn test_vmm_profile() {
+        let profile = vmm_profile(&[]).unwrap();
+        assert!(profile.memory.resident_bytes > 0);
+        // SAFETY: FFI call, trivially safe
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        assert!(profile.threads.iter().any(|thread| thread.info.tid == tid));
+    }
+
+    #[test]
+    fn test_process_cpu_time() {
+        let before = process_cpu_time().unwrap();
+        let after = process_cpu_time().unwrap();
+        assert!(after.user_time_us >= before.user_time_us);
+        assert!(after.system_time_us >= before.system_time_us);
+        assert!(after.user_time_us + after.system_time_us > 0);
+    }
+
+    #[cfg(feature = "pprof")]
+    #[test]
+    fn test_pprof() {
+        let cancel = AtomicBool::new(true);
+        assert!(matches!(
+            cpu_pprof(Duration::from_secs(1), 0, &cancel),
+            Err(PprofError::InvalidFrequency(0))
+        ));
+        assert!(matches!(
+            cpu_pprof(Duration::from_secs(1), PPROF_DEFAULT_FREQUENCY, &cancel),
+            Err(PprofError::Cancelled)
+        ));
+
+        let cancel = AtomicBool::new(false);
+        let profile = cpu_pprof(Duration::from_millis(200), 999, &cancel).unwrap();
+        assert!(!profile.is_empty());
+
+        // The tests don't run on jemalloc with profiling enabled.
+        assert!(matches!(
+            heap_pprof(),
+            Err(PprofError::HeapProfilingDisabled)
+        ));
+    }
+}
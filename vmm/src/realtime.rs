@@ -0,0 +1,215 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Realtime profile.
+//!
+//! The vCPUs are pinned 1:1 onto isolated host CPUs and scheduled with
+//! SCHED_FIFO, while every other VMM thread is kept on the housekeeping CPUs.
+
+use crate::threads::{list_threads, ThreadRole};
+use crate::vm_config::VmConfig;
+use option_parser::IntegerList;
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::str::FromStr;
+
+const ISOLATED_CPUS: &str = "/sys/devices/system/cpu/isolated";
+const NOHZ_FULL_CPUS: &str = "/sys/devices/system/cpu/nohz_full";
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+const IRQ_DEFAULT_AFFINITY: &str = "/proc/irq/default_smp_affinity";
+const SCHED_RT_RUNTIME: &str = "/proc/sys/kernel/sched_rt_runtime_us";
+
+// Parses a list of CPUs as formatted by the kernel, e.g. "2-5,8".
+pub(crate) fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let list = list.trim();
+    // Empty lists are reported as "(null)" by some of the sysfs files.
+    if list.is_empty() || list == "(null)" {
+        return Some(Vec::new());
+    }
+
+    IntegerList::from_str(list)
+        .ok()
+        .map(|cpus| cpus.0.iter().map(|cpu| *cpu as usize).collect())
+}
+
+// Parses a hexadecimal CPU mask as formatted by the kernel, e.g. "ff,ffffffff".
+fn parse_cpu_mask(mask: &str) -> Vec<usize> {
+    mask.trim()
+        .chars()
+        .filter(|c| *c != ',')
+        .rev()
+        .enumerate()
+        .flat_map(|(index, c)| {
+            let bits = c.to_digit(16).unwrap_or(0);
+            (0..4)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| index * 4 + bit)
+        })
+        .collect()
+}
+
+fn read_cpu_list(path: &str) -> io::Result<Vec<usize>> {
+    let list = fs::read_to_string(path)?;
+    parse_cpu_list(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid CPU list in {path}: {list}"),
+        )
+    })
+}
+
+/// Host CPUs isolated from the scheduler through the kernel command line.
+pub fn isolated_cpus() -> io::Result<Vec<usize>> {
+    read_cpu_list(ISOLATED_CPUS)
+}
+
+/// Online host CPUs which are not dedicated to the vCPUs.
+pub fn housekeeping_cpus(rt_cpus: &[usize]) -> io::Result<Vec<usize>> {
+    let cpus: Vec<usize> = read_cpu_list(ONLINE_CPUS)?
+        .into_iter()
+        .filter(|cpu| !rt_cpus.contains(cpu))
+        .collect();
+    if cpus.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No host CPU left for the VMM threads",
+        ));
+    }
+
+    Ok(cpus)
+}
+
+fn set_thread_affinity(tid: i32, cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        // SAFETY: FFI call, trivially safe
+        unsafe { libc::CPU_SET(*cpu, &mut cpuset) };
+    }
+
+    // SAFETY: FFI call with correct arguments
+    let ret = unsafe { libc::sched_setaffinity(tid, size_of::<libc::cpu_set_t>(), &cpuset) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Moves all the VMM threads but the vCPU ones onto the given host CPUs. The
+/// threads created afterwards inherit the affinity of their parent.
+pub fn move_threads(cpus: &[usize]) -> io::Result<()> {
+    for thread in list_threads(&[])? {
+        if thread.role == ThreadRole::Vcpu {
+            continue;
+        }
+
+        match set_thread_affinity(thread.tid, cpus) {
+            // The thread may have exited since it was listed.
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Schedules the calling thread with the SCHED_FIFO policy.
+pub fn set_fifo_priority(priority: u8) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: i32::from(priority),
+    };
+    // SAFETY: FFI call with a valid sched_param
+    let ret = unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Lists what may still interrupt the vCPUs running on the given host CPUs,
+/// either because of the host setup or because of the VM configuration.
+pub fn jitter_sources(rt_cpus: &[usize], config: &VmConfig) -> Vec<String> {
+    let mut sources = Vec::new();
+
+    let isolated_cpus = read_cpu_list(ISOLATED_CPUS).unwrap_or_default();
+    let nohz_full_cpus = read_cpu_list(NOHZ_FULL_CPUS).unwrap_or_default();
+    for cpu in rt_cpus {
+        if !isolated_cpus.contains(cpu) {
+            sources.push(format!(
+                "host CPU {cpu} is not isolated from the scheduler (isolcpus)"
+            ));
+        }
+        if !nohz_full_cpus.contains(cpu) {
+            sources.push(format!(
+                "host CPU {cpu} still receives the scheduler tick (nohz_full)"
+            ));
+        }
+        let governor_path = format!("/sys/devices/system/cpu/cpu{cpu}/cpufreq/scaling_governor");
+        if let Ok(governor) = fs::read_to_string(governor_path) {
+            if governor.trim() != "performance" {
+                sources.push(format!(
+                    "host CPU {cpu} frequency is scaled by the '{}' governor",
+                    governor.trim()
+                ));
+            }
+        }
+    }
+
+    if let Ok(mask) = fs::read_to_string(IRQ_DEFAULT_AFFINITY) {
+        let irq_cpus: Vec<usize> = parse_cpu_mask(&mask)
+            .into_iter()
+            .filter(|cpu| rt_cpus.contains(cpu))
+            .collect();
+        if !irq_cpus.is_empty() {
+            sources.push(format!(
+                "new interrupts may be routed to host CPUs {irq_cpus:?} (default_smp_affinity)"
+            ));
+        }
+    }
+
+    if let Ok(runtime) = fs::read_to_string(SCHED_RT_RUNTIME) {
+        if runtime.trim() != "-1" {
+            sources.push(format!(
+                "realtime threads are throttled (kernel.sched_rt_runtime_us={})",
+                runtime.trim()
+            ));
+        }
+    }
+
+    if !config.memory.locked {
+        sources.push("guest memory may be swapped out (locked=off)".to_string());
+    }
+    if config.memory.mergeable {
+        sources.push("guest memory is scanned by KSM (mergeable=on)".to_string());
+    }
+    if config.memory.thp && !config.memory.hugepages {
+        sources.push("guest memory is scanned by khugepaged (thp=on)".to_string());
+    }
+
+    sources
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("(null)\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("3\n"), Some(vec![3]));
+        assert_eq!(parse_cpu_list("2-5,8"), Some(vec![2, 3, 4, 5, 8]));
+        assert_eq!(parse_cpu_list("2-"), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_mask() {
+        assert_eq!(parse_cpu_mask("0\n"), Vec::<usize>::new());
+        assert_eq!(parse_cpu_mask("5"), vec![0, 2]);
+        assert_eq!(parse_cpu_mask("1,00000010"), vec![4, 32]);
+    }
+}
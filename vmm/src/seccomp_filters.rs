@@ -14,11 +14,20 @@ pub enum Thread {
     HttpApi,
     #[cfg(feature = "dbus_api")]
     DBusApi,
+    E1000e,
     EventMonitor,
+    Hooks,
+    MemoryShare,
     SignalHandler,
     Vcpu,
     Vmm,
     PtyForeground,
+    #[cfg(feature = "vnc")]
+    VncServer,
+    #[cfg(feature = "vnc")]
+    VncClient,
+    Xhci,
+    SeccompNotifier,
 }
 
 /// Shorthand for chaining `SeccompCondition`s with the `and` operator  in a `SeccompRule`.
@@ -114,6 +123,24 @@ const VHOST_VDPA_GET_IOVA_RANGE: u64 = 0x8010af78;
 const VHOST_VDPA_GET_CONFIG_SIZE: u64 = 0x8004af79;
 const VHOST_VDPA_SUSPEND: u64 = 0xaf7d;
 
+// See include/uapi/linux/usbdevice_fs.h in the kernel code.
+const USBDEVFS_CONTROL: u64 = 0xc018_5500;
+const USBDEVFS_SETINTERFACE: u64 = 0x8008_5504;
+const USBDEVFS_SETCONFIGURATION: u64 = 0x8004_5505;
+const USBDEVFS_SUBMITURB: u64 = 0x8038_550a;
+const USBDEVFS_DISCARDURB: u64 = 0x550b;
+const USBDEVFS_REAPURBNDELAY: u64 = 0x4008_550d;
+const USBDEVFS_RELEASEINTERFACE: u64 = 0x8004_5510;
+const USBDEVFS_IOCTL: u64 = 0xc010_5512;
+const USBDEVFS_RESET: u64 = 0x5514;
+const USBDEVFS_CLEAR_HALT: u64 = 0x8004_5515;
+const USBDEVFS_DISCONNECT_CLAIM: u64 = 0x8108_551b;
+const USBDEVFS_GET_SPEED: u64 = 0x551f;
+
+// See include/uapi/linux/seccomp.h in the kernel code.
+const SECCOMP_IOCTL_NOTIF_RECV: u64 = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: u64 = 0xc018_2101;
+
 // See include/uapi/linux/kvm.h in the kernel code.
 #[cfg(feature = "kvm")]
 mod kvm {
@@ -379,6 +406,12 @@ fn create_vmm_ioctl_seccomp_rule_common(
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_IOVA_RANGE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_GET_CONFIG_SIZE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, VHOST_VDPA_SUSPEND)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CONTROL)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCARDURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RELEASEINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_IOCTL)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_GET_SPEED)?],
     ];
 
     let hypervisor_rules = create_vmm_ioctl_seccomp_rule_hypervisor(hypervisor_type)?;
@@ -566,6 +599,7 @@ fn vmm_thread_rules(
         (libc::SYS_close_range, vec![]),
         (libc::SYS_connect, vec![]),
         (libc::SYS_dup, vec![]),
+        (libc::SYS_dup3, vec![]),
         (libc::SYS_epoll_create1, vec![]),
         (libc::SYS_epoll_ctl, vec![]),
         (libc::SYS_epoll_pwait, vec![]),
@@ -582,6 +616,8 @@ fn vmm_thread_rules(
         (libc::SYS_ftruncate, vec![]),
         #[cfg(target_arch = "aarch64")]
         (libc::SYS_faccessat, vec![]),
+        // Checking the device nodes against the effective IDs
+        (libc::SYS_faccessat2, vec![]),
         #[cfg(target_arch = "aarch64")]
         (libc::SYS_newfstatat, vec![]),
         (libc::SYS_futex, vec![]),
@@ -591,6 +627,7 @@ fn vmm_thread_rules(
         (libc::SYS_getpgrp, vec![]),
         (libc::SYS_getpid, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getrusage, vec![]),
         (libc::SYS_gettid, vec![]),
         (libc::SYS_gettimeofday, vec![]),
         (libc::SYS_getuid, vec![]),
@@ -612,6 +649,7 @@ fn vmm_thread_rules(
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mbind, vec![]),
         (libc::SYS_memfd_create, vec![]),
+        (libc::SYS_mlock, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_mremap, vec![]),
@@ -654,8 +692,11 @@ fn vmm_thread_rules(
         (libc::SYS_sched_setaffinity, vec![]),
         (libc::SYS_sched_yield, vec![]),
         (libc::SYS_seccomp, vec![]),
+        // Resolving the host of a snapshot stream
+        (libc::SYS_sendmmsg, vec![]),
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_sendto, vec![]),
+        (libc::SYS_set_mempolicy, vec![]),
         (libc::SYS_set_robust_list, vec![]),
         (libc::SYS_setsid, vec![]),
         (libc::SYS_setsockopt, vec![]),
@@ -666,6 +707,8 @@ fn vmm_thread_rules(
             or![
                 and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET6 as u64)?],
+                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_NETLINK as u64)?],
             ],
         ),
         (libc::SYS_socketpair, vec![]),
@@ -844,14 +887,19 @@ fn http_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_fcntl, vec![]),
         (libc::SYS_futex, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getsockopt, vec![]),
         (libc::SYS_ioctl, create_api_ioctl_seccomp_rule()?),
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
         (libc::SYS_recvfrom, vec![]),
         (libc::SYS_recvmsg, vec![]),
         (libc::SYS_sched_yield, vec![]),
+        #[cfg(feature = "http_tls")]
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_setsockopt, vec![]),
         (libc::SYS_sigaltstack, vec![]),
         (libc::SYS_write, vec![]),
         (libc::SYS_rt_sigprocmask, vec![]),
@@ -863,21 +911,34 @@ fn http_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
 #[cfg(feature = "dbus_api")]
 fn dbus_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
     Ok(vec![
+        (libc::SYS_accept4, vec![]),
         (libc::SYS_brk, vec![]),
         (libc::SYS_clock_gettime, vec![]),
+        (libc::SYS_clock_nanosleep, vec![]),
         (libc::SYS_clone, vec![]),
         (libc::SYS_clone3, vec![]),
         (libc::SYS_close, vec![]),
+        (libc::SYS_connect, vec![]),
         (libc::SYS_dup, vec![]),
         (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
         (libc::SYS_exit, vec![]),
+        (libc::SYS_fcntl, vec![]),
         (libc::SYS_futex, vec![]),
+        (libc::SYS_geteuid, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getsockopt, vec![]),
+        (libc::SYS_getuid, vec![]),
+        (libc::SYS_ioctl, create_api_ioctl_seccomp_rule()?),
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
+        (libc::SYS_nanosleep, vec![]),
         (libc::SYS_prctl, vec![]),
+        (libc::SYS_read, vec![]),
         (libc::SYS_recvmsg, vec![]),
         // musl is missing this constant
         // (libc::SYS_rseq, vec![]),
@@ -891,6 +952,75 @@ fn dbus_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_set_robust_list, vec![]),
         (libc::SYS_sigaltstack, vec![]),
+        (
+            libc::SYS_socket,
+            or![and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_UNIX as u64)?]],
+        ),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+// The xHCI thread submits the transfers of the guest to the host USB
+// devices and reaps their completions.
+fn create_xhci_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, BackendError> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SETCONFIGURATION)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_SUBMITURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCARDURB)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_REAPURBNDELAY)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RELEASEINTERFACE)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_RESET)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_CLEAR_HALT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
+    ])
+}
+
+fn xhci_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_ioctl, create_xhci_ioctl_seccomp_rule()?),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_mprotect, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+// The e1000e thread reads the frames from the TAP interface into the guest
+// memory, and triggers the device interrupt through its irqfd.
+fn e1000e_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_mprotect, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
         (libc::SYS_write, vec![]),
     ])
 }
@@ -906,6 +1036,141 @@ fn event_monitor_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendE
     ])
 }
 
+// The hooks themselves are run by a process forked before any filter is
+// applied, this thread only exchanging with it.
+fn hooks_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_gettime, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+fn memory_share_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_accept4, vec![]),
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_create1, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_geteuid, vec![]),
+        (libc::SYS_getsockopt, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sendmsg, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+// The filter of the VNC server thread, inherited by the client threads it
+// spawns, which then apply their own filter on top of it.
+#[cfg(feature = "vnc")]
+fn vnc_server_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    let mut rules = vec![
+        (libc::SYS_accept4, vec![]),
+        (libc::SYS_clone, vec![]),
+        (libc::SYS_clone3, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_mprotect, vec![]),
+        (libc::SYS_prctl, vec![]),
+        // musl is missing this constant
+        // (libc::SYS_rseq, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (334, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (293, vec![]),
+        (libc::SYS_seccomp, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+    ];
+    rules.append(&mut vnc_client_thread_rules()?);
+    Ok(rules)
+}
+
+#[cfg(feature = "vnc")]
+fn vnc_client_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_gettime, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+fn create_seccomp_notifier_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, BackendError> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::Dword, Eq, SECCOMP_IOCTL_NOTIF_RECV)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, SECCOMP_IOCTL_NOTIF_SEND)?],
+    ])
+}
+
+// The notifier thread reports the violations of the audited filters, and
+// kills the VMM once they are enforced.
+fn seccomp_notifier_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_gettime, vec![]),
+        (libc::SYS_clock_nanosleep, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_getpid, vec![]),
+        (libc::SYS_gettid, vec![]),
+        (
+            libc::SYS_ioctl,
+            create_seccomp_notifier_ioctl_seccomp_rule()?,
+        ),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_nanosleep, vec![]),
+        (libc::SYS_openat, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_rt_sigaction, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_statx, vec![]),
+        (libc::SYS_tgkill, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
 fn get_seccomp_rules(
     thread_type: Thread,
     hypervisor_type: HypervisorType,
@@ -914,11 +1179,20 @@ fn get_seccomp_rules(
         Thread::HttpApi => Ok(http_api_thread_rules()?),
         #[cfg(feature = "dbus_api")]
         Thread::DBusApi => Ok(dbus_api_thread_rules()?),
+        Thread::E1000e => Ok(e1000e_thread_rules()?),
         Thread::EventMonitor => Ok(event_monitor_thread_rules()?),
+        Thread::Hooks => Ok(hooks_thread_rules()?),
+        Thread::MemoryShare => Ok(memory_share_thread_rules()?),
         Thread::SignalHandler => Ok(signal_handler_thread_rules()?),
         Thread::Vcpu => Ok(vcpu_thread_rules(hypervisor_type)?),
         Thread::Vmm => Ok(vmm_thread_rules(hypervisor_type)?),
         Thread::PtyForeground => Ok(pty_foreground_thread_rules()?),
+        #[cfg(feature = "vnc")]
+        Thread::VncServer => Ok(vnc_server_thread_rules()?),
+        #[cfg(feature = "vnc")]
+        Thread::VncClient => Ok(vnc_client_thread_rules()?),
+        Thread::Xhci => Ok(xhci_thread_rules()?),
+        Thread::SeccompNotifier => Ok(seccomp_notifier_thread_rules()?),
     }
 }
 
@@ -0,0 +1,325 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Auditing of the seccomp filters, the violations being reported through the
+//! logs and the event monitor rather than the VMM being killed without a hint.
+//!
+//! While auditing, the filters hand the denied syscalls over to a listener
+//! (`SECCOMP_RET_USER_NOTIF`) instead of trapping them. The notifier thread
+//! reports each violation and lets the syscall proceed, as if the thread
+//! wasn't filtered, until the filters are enforced once the VM is booted or
+//! restored. From then on, the next violation kills the VMM.
+//!
+//! The kernel allows a single listener along the filters of a thread, so
+//! only the threads started by the main thread get one. The threads they
+//! start in turn, such as the vCPU and the device threads, have their filters
+//! enforced from the start.
+
+use once_cell::sync::OnceCell;
+use seccompiler::BpfProgram;
+use signal_hook::consts::SIGSYS;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::eventfd::EventFd;
+
+static AUDITING: AtomicBool = AtomicBool::new(false);
+static ENFORCING: AtomicBool = AtomicBool::new(false);
+// Listeners of the filters applied since the notifier thread last woke up
+static LISTENERS: Mutex<Vec<File>> = Mutex::new(Vec::new());
+static LISTENERS_EVT: OnceCell<EventFd> = OnceCell::new();
+
+// Gives the event monitor a chance to deliver the event before the VMM dies
+const EVENT_DELIVERY_DELAY: Duration = Duration::from_millis(100);
+const LISTENERS_EVT_TOKEN: u64 = u64::MAX;
+const EPOLL_EVENTS_LEN: usize = 8;
+
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_ulong = 1 << 3;
+const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+const SECCOMP_IOCTL_NOTIF_RECV: u64 = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: u64 = 0xc018_2101;
+
+// Notification and response structures, which the libc crate doesn't expose.
+#[repr(C)]
+#[derive(Default)]
+struct SeccompData {
+    nr: libc::c_int,
+    arch: u32,
+    instruction_pointer: u64,
+    args: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: SeccompData,
+}
+
+#[repr(C)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+// Has the denied syscalls notified to the listener rather than trapped.
+fn notify_violations(filter: &BpfProgram) -> BpfProgram {
+    let mut program = filter.clone();
+    for insn in program.iter_mut() {
+        if insn.code == BPF_RET_K && insn.k & SECCOMP_RET_ACTION_FULL == SECCOMP_RET_TRAP {
+            insn.k = SECCOMP_RET_USER_NOTIF;
+        }
+    }
+    program
+}
+
+fn apply_filter_with_listener(filter: &BpfProgram) -> io::Result<File> {
+    let program = notify_violations(filter);
+    let prog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+
+    // SAFETY: FFI call, trivially safe
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: FFI call with a valid program, outliving the call
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just created
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Applies `filter` on the calling thread. While auditing, the violations
+/// are reported to the notifier thread, unless the thread inherited a filter
+/// already having a listener, in which case `filter` is enforced right away.
+pub fn apply_filter(filter: &BpfProgram) -> Result<(), seccompiler::Error> {
+    if AUDITING.load(Ordering::Acquire) {
+        match apply_filter_with_listener(filter) {
+            Ok(listener) => {
+                LISTENERS.lock().unwrap().push(listener);
+                if let Some(evt) = LISTENERS_EVT.get() {
+                    evt.write(1).ok();
+                }
+                return Ok(());
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+            Err(e) => warn!("Error auditing the seccomp filter: {e}"),
+        }
+    }
+    seccompiler::apply_filter(filter)
+}
+
+fn report_violation(listener: &File) {
+    let mut notif = SeccompNotif::default();
+    // SAFETY: FFI call with a valid notification structure
+    if unsafe {
+        libc::ioctl(
+            listener.as_raw_fd(),
+            SECCOMP_IOCTL_NOTIF_RECV as _,
+            &mut notif as *mut SeccompNotif,
+        )
+    } < 0
+    {
+        // The thread may have been killed in the meantime
+        return;
+    }
+
+    // The thread is still around, waiting for the response
+    let syscall = notif.data.nr;
+    let tid = notif.pid;
+    let thread = fs::read_to_string(format!("/proc/self/task/{tid}/comm"))
+        .map(|name| name.trim_end().to_string())
+        .unwrap_or_default();
+    let enforcing = ENFORCING.load(Ordering::Acquire);
+    let action = if enforcing { "trap" } else { "log" };
+
+    error!(
+        "Seccomp violation: syscall {} from thread {} ({}), action: {}",
+        syscall, thread, tid, action
+    );
+    event!(
+        "seccomp",
+        "violation",
+        "syscall",
+        syscall.to_string(),
+        "thread",
+        thread,
+        "tid",
+        tid.to_string(),
+        "action",
+        action
+    );
+
+    if enforcing {
+        thread::sleep(EVENT_DELIVERY_DELAY);
+        eprint!(
+            "\n==== Possible seccomp violation ====\n\
+            Try running with `strace -ff` to identify the cause and open an issue: \
+            https://github.com/cloud-hypervisor/cloud-hypervisor/issues/new\n"
+        );
+        signal_hook::low_level::emulate_default_handler(SIGSYS).ok();
+    }
+
+    let resp = SeccompNotifResp {
+        id: notif.id,
+        val: 0,
+        error: 0,
+        flags: SECCOMP_USER_NOTIF_FLAG_CONTINUE,
+    };
+    // SAFETY: FFI call with a valid response structure
+    unsafe {
+        libc::ioctl(
+            listener.as_raw_fd(),
+            SECCOMP_IOCTL_NOTIF_SEND as _,
+            &resp as *const SeccompNotifResp,
+        )
+    };
+}
+
+fn serve_listeners(epoll_file: File, evt: &EventFd) -> io::Result<()> {
+    let epoll_fd = epoll_file.as_raw_fd();
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        evt.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, LISTENERS_EVT_TOKEN),
+    )?;
+
+    let mut listeners: BTreeMap<RawFd, File> = BTreeMap::new();
+    let mut events = [epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(res) => res,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            let token = event.data;
+            if token == LISTENERS_EVT_TOKEN {
+                evt.read().ok();
+                for listener in LISTENERS.lock().unwrap().drain(..) {
+                    let fd = listener.as_raw_fd();
+                    epoll::ctl(
+                        epoll_fd,
+                        epoll::ControlOptions::EPOLL_CTL_ADD,
+                        fd,
+                        epoll::Event::new(epoll::Events::EPOLLIN, fd as u64),
+                    )?;
+                    listeners.insert(fd, listener);
+                }
+                continue;
+            }
+
+            let fd = token as RawFd;
+            let event_set = epoll::Events::from_bits_truncate(event.events);
+            if event_set.contains(epoll::Events::EPOLLIN) {
+                if let Some(listener) = listeners.get(&fd) {
+                    report_violation(listener);
+                }
+            } else if event_set.contains(epoll::Events::EPOLLHUP) {
+                // All the threads the filter applies to are gone
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                    fd,
+                    epoll::Event::new(epoll::Events::empty(), 0),
+                )
+                .ok();
+                listeners.remove(&fd);
+            }
+        }
+    }
+}
+
+/// Starts the thread the violations are notified to, which applies `filter`
+/// on itself. The filters applied from then on through `apply_filter()` are
+/// audited until `enforce()` is called.
+pub fn start(filter: BpfProgram) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    // SAFETY: epoll_fd is valid
+    let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    let evt = LISTENERS_EVT.get_or_try_init(|| EventFd::new(libc::EFD_NONBLOCK))?;
+
+    thread::Builder::new()
+        .name("seccomp-notify".to_string())
+        .spawn(move || {
+            if !filter.is_empty() {
+                if let Err(e) = seccompiler::apply_filter(&filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+            }
+            if let Err(e) = serve_listeners(epoll_file, evt) {
+                error!("Error serving the seccomp listeners: {e}");
+            }
+        })?;
+
+    AUDITING.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops letting the denied syscalls through, the VMM being killed on the
+/// next violation.
+pub fn enforce() {
+    if AUDITING.load(Ordering::Acquire) && !ENFORCING.swap(true, Ordering::AcqRel) {
+        info!("Enforcing the seccomp filters");
+        event!("seccomp", "enforced");
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use seccompiler::{SeccompAction, SeccompFilter};
+
+    #[test]
+    fn test_notify_violations() {
+        let filter: BpfProgram = SeccompFilter::new(
+            vec![(libc::SYS_read, vec![])].into_iter().collect(),
+            SeccompAction::Trap,
+            SeccompAction::Allow,
+            std::env::consts::ARCH.try_into().unwrap(),
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let returns = |program: &BpfProgram, action: u32| {
+            program
+                .iter()
+                .any(|insn| insn.code == BPF_RET_K && insn.k & SECCOMP_RET_ACTION_FULL == action)
+        };
+
+        assert!(returns(&filter, SECCOMP_RET_TRAP));
+        let program = notify_violations(&filter);
+        assert_eq!(program.len(), filter.len());
+        assert!(!returns(&program, SECCOMP_RET_TRAP));
+        assert!(returns(&program, SECCOMP_RET_USER_NOTIF));
+    }
+}
@@ -0,0 +1,448 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compression of the guest memory of the snapshots.
+//!
+//! The guest memory is compressed with zstd, following the zstd seekable
+//! format: the content is split into frames compressed independently of each
+//! other, a seek table listing the compressed and decompressed size of every
+//! frame being stored in a skippable frame at the end of the file. The frames
+//! are compressed by several threads at once, and the seek table lets them be
+//! decompressed in parallel on restore. The file remains a regular zstd file,
+//! which `zstd -d` decompresses into the content of an uncompressed snapshot.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use thiserror::Error;
+
+/// Content compressed into each frame, the last frame being shorter.
+pub const FRAME_SIZE: usize = 4 << 20;
+
+const MAX_THREADS: usize = 8;
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const SEEK_TABLE_ENTRY_SIZE: u64 = 8;
+// Seek table descriptor flag of the entries holding a checksum
+const SEEK_TABLE_CHECKSUM: u8 = 1 << 7;
+// Seek table descriptor bits which must be left clear
+const SEEK_TABLE_RESERVED: u8 = 0x7c;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Snapshot compression requires the snapshot_compression feature")]
+    NotSupported,
+    #[error("Cannot compress the guest memory: {0}")]
+    Compress(#[source] io::Error),
+    #[error("Cannot decompress the guest memory: {0}")]
+    Decompress(#[source] io::Error),
+    #[error("Cannot read the guest memory: {0}")]
+    ReadMemory(#[source] io::Error),
+    #[error("Cannot write the guest memory: {0}")]
+    WriteMemory(#[source] io::Error),
+    #[error("Cannot write the compressed snapshot file: {0}")]
+    Write(#[source] io::Error),
+    #[error("Cannot read the compressed snapshot file: {0}")]
+    Read(#[source] io::Error),
+    #[error("Invalid seek table in the compressed snapshot file")]
+    InvalidSeekTable,
+    #[error("The compressed snapshot file does not match the size of the guest memory")]
+    SizeMismatch,
+}
+
+#[cfg(feature = "snapshot_compression")]
+mod codec {
+    use super::Error;
+    use std::io::{self, Read};
+
+    pub fn compress_frame(data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut compressor = zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+            .map_err(Error::Compress)?;
+        compressor.include_checksum(true).map_err(Error::Compress)?;
+        compressor.compress(data).map_err(Error::Compress)
+    }
+
+    pub fn decompress_frame(frame: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        let len = zstd::bulk::Decompressor::new()
+            .and_then(|mut decompressor| decompressor.decompress_to_buffer(frame, data))
+            .map_err(Error::Decompress)?;
+        if len != data.len() {
+            return Err(Error::Decompress(io::Error::from(
+                io::ErrorKind::UnexpectedEof,
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn decoder<'a, R: Read + 'a>(reader: R) -> Result<impl Read + 'a, Error> {
+        zstd::stream::read::Decoder::new(reader).map_err(Error::Decompress)
+    }
+}
+
+#[cfg(not(feature = "snapshot_compression"))]
+mod codec {
+    use super::Error;
+    use std::io::Read;
+
+    pub fn compress_frame(_data: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    pub fn decompress_frame(_frame: &[u8], _data: &mut [u8]) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    pub fn decoder<'a, R: Read + 'a>(_reader: R) -> Result<impl Read + 'a, Error> {
+        Err::<std::io::Empty, _>(Error::NotSupported)
+    }
+}
+
+/// Whether the guest memory can be compressed.
+pub fn is_supported() -> bool {
+    cfg!(feature = "snapshot_compression")
+}
+
+// A frame of the compressed file, as listed by the seek table.
+struct Frame {
+    offset: u64,
+    size: usize,
+    content_offset: u64,
+    content_size: usize,
+}
+
+fn threads(frames: usize) -> usize {
+    thread::available_parallelism()
+        .map_or(1, |threads| threads.get())
+        .clamp(1, MAX_THREADS)
+        .min(frames)
+}
+
+fn join<T>(handle: thread::ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// Compresses the `len` bytes of content `read` fills the buffers it is
+/// given with, from the offset it is given, into `writer`. Returns `writer`
+/// once the seek table is written.
+pub fn compress<W, F>(mut writer: W, len: u64, read: F) -> Result<W, Error>
+where
+    W: Write,
+    F: Fn(u64, &mut [u8]) -> io::Result<()> + Sync,
+{
+    let frames = len.div_ceil(FRAME_SIZE as u64) as usize;
+    let threads = threads(frames);
+    let mut sizes = Vec::with_capacity(frames);
+
+    // The frames are compressed by batches, one frame per thread, so that
+    // only a few of them are held in memory at once.
+    for batch in (0..frames).step_by(threads.max(1)) {
+        let compressed = thread::scope(|s| {
+            let handles = (batch..frames.min(batch + threads))
+                .map(|frame| {
+                    let read = &read;
+                    s.spawn(move || {
+                        let offset = (frame * FRAME_SIZE) as u64;
+                        let mut data = vec![0u8; (len - offset).min(FRAME_SIZE as u64) as usize];
+                        read(offset, &mut data).map_err(Error::ReadMemory)?;
+                        codec::compress_frame(&data).map(|frame| (frame, data.len()))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().map(join).collect::<Result<Vec<_>, _>>()
+        })?;
+
+        for (frame, content_size) in compressed {
+            writer.write_all(&frame).map_err(Error::Write)?;
+            sizes.push((frame.len() as u32, content_size as u32));
+        }
+    }
+
+    writer
+        .write_all(&seek_table(&sizes))
+        .map_err(Error::Write)?;
+    Ok(writer)
+}
+
+// The seek table, as a skippable frame, of the frames whose compressed and
+// decompressed sizes are `sizes`.
+fn seek_table(sizes: &[(u32, u32)]) -> Vec<u8> {
+    let frame_size = sizes.len() as u64 * SEEK_TABLE_ENTRY_SIZE + SEEK_TABLE_FOOTER_SIZE;
+    let mut table = Vec::with_capacity((SKIPPABLE_HEADER_SIZE + frame_size) as usize);
+    table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    table.extend_from_slice(&(frame_size as u32).to_le_bytes());
+    for (size, content_size) in sizes {
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&content_size.to_le_bytes());
+    }
+    table.extend_from_slice(&(sizes.len() as u32).to_le_bytes());
+    table.push(0);
+    table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    table
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().unwrap())
+}
+
+// Reads the seek table at the end of `file`, checking the frames it lists
+// span the rest of the file.
+fn read_seek_table(file: &File) -> Result<Vec<Frame>, Error> {
+    let file_size = file.metadata().map_err(Error::Read)?.len();
+    if file_size < SKIPPABLE_HEADER_SIZE + SEEK_TABLE_FOOTER_SIZE {
+        return Err(Error::InvalidSeekTable);
+    }
+
+    let mut footer = [0u8; SEEK_TABLE_FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, file_size - SEEK_TABLE_FOOTER_SIZE)
+        .map_err(Error::Read)?;
+    let descriptor = footer[4];
+    if read_u32(&footer[5..]) != SEEKABLE_MAGIC || descriptor & SEEK_TABLE_RESERVED != 0 {
+        return Err(Error::InvalidSeekTable);
+    }
+    let entry_size = if descriptor & SEEK_TABLE_CHECKSUM != 0 {
+        SEEK_TABLE_ENTRY_SIZE + 4
+    } else {
+        SEEK_TABLE_ENTRY_SIZE
+    };
+    let frame_size = u64::from(read_u32(&footer)) * entry_size + SEEK_TABLE_FOOTER_SIZE;
+    let table_offset = file_size
+        .checked_sub(SKIPPABLE_HEADER_SIZE + frame_size)
+        .ok_or(Error::InvalidSeekTable)?;
+
+    let mut table = vec![0u8; (SKIPPABLE_HEADER_SIZE + frame_size) as usize];
+    file.read_exact_at(&mut table, table_offset)
+        .map_err(Error::Read)?;
+    if read_u32(&table) != SKIPPABLE_MAGIC || u64::from(read_u32(&table[4..])) != frame_size {
+        return Err(Error::InvalidSeekTable);
+    }
+
+    let mut frames = Vec::new();
+    let (mut offset, mut content_offset) = (0, 0);
+    for entry in table[SKIPPABLE_HEADER_SIZE as usize..(table.len() - footer.len())]
+        .chunks(entry_size as usize)
+    {
+        let frame = Frame {
+            offset,
+            size: read_u32(entry) as usize,
+            content_offset,
+            content_size: read_u32(&entry[4..]) as usize,
+        };
+        // The content of a frame is decompressed in a buffer of that size,
+        // which the file isn't to choose.
+        if frame.content_size > FRAME_SIZE {
+            return Err(Error::InvalidSeekTable);
+        }
+        offset += frame.size as u64;
+        content_offset += frame.content_size as u64;
+        frames.push(frame);
+    }
+    if offset != table_offset {
+        return Err(Error::InvalidSeekTable);
+    }
+
+    Ok(frames)
+}
+
+/// Decompresses the `len` bytes of content of `file`, several frames at once,
+/// `write` being given the offset and the content of every frame.
+pub fn decompress_file<F>(file: &File, len: u64, write: F) -> Result<(), Error>
+where
+    F: Fn(u64, &[u8]) -> io::Result<()> + Sync,
+{
+    let frames = read_seek_table(file)?;
+    if frames
+        .iter()
+        .map(|frame| frame.content_size as u64)
+        .sum::<u64>()
+        != len
+    {
+        return Err(Error::SizeMismatch);
+    }
+
+    let next = AtomicUsize::new(0);
+    thread::scope(|s| {
+        let handles = (0..threads(frames.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let (mut frame_data, mut data) = (Vec::new(), Vec::new());
+                    while let Some(frame) = frames.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        frame_data.resize(frame.size, 0);
+                        data.resize(frame.content_size, 0);
+                        let result = file
+                            .read_exact_at(&mut frame_data, frame.offset)
+                            .map_err(Error::Read)
+                            .and_then(|_| codec::decompress_frame(&frame_data, &mut data))
+                            .and_then(|_| {
+                                write(frame.content_offset, &data).map_err(Error::WriteMemory)
+                            });
+                        if result.is_err() {
+                            // Stop the other threads too.
+                            next.store(frames.len(), Ordering::Relaxed);
+                            return result;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().try_for_each(join)
+    })
+}
+
+/// Decompresses the `len` bytes of content read out of `reader` one frame
+/// after the other, `write` being given the offset and the content of every
+/// chunk. Meant for the files which can only be read sequentially, such as
+/// the encrypted ones.
+pub fn decompress<R, F>(reader: R, len: u64, mut write: F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(u64, &[u8]) -> io::Result<()>,
+{
+    let mut decoder = codec::decoder(reader)?;
+    let mut data = vec![0u8; FRAME_SIZE];
+
+    let mut offset = 0;
+    while offset < len {
+        let size = (len - offset).min(FRAME_SIZE as u64) as usize;
+        decoder
+            .read_exact(&mut data[..size])
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::SizeMismatch,
+                _ => Error::Decompress(e),
+            })?;
+        write(offset, &data[..size]).map_err(Error::WriteMemory)?;
+        offset += size as u64;
+    }
+
+    // Reading up to the end of the file goes through the seek table, and
+    // makes sure nothing follows the content.
+    if decoder.read(&mut data[..1]).map_err(Error::Decompress)? != 0 {
+        return Err(Error::SizeMismatch);
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "snapshot_compression"))]
+mod unit_tests {
+    use super::*;
+    use std::io::{Cursor, Seek, SeekFrom};
+    use std::sync::Mutex;
+
+    fn content(len: usize) -> Vec<u8> {
+        // Compressible, but not only zeros
+        (0..len).map(|i| (i / 4096) as u8).collect()
+    }
+
+    fn compress_content(data: &[u8]) -> Vec<u8> {
+        compress(Vec::new(), data.len() as u64, |offset, buf| {
+            let offset = offset as usize;
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    fn compressed_file(compressed: &[u8]) -> File {
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        file.write_all(compressed).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    fn decompress_content(file: &File, len: usize) -> Result<Vec<u8>, Error> {
+        let data = Mutex::new(vec![0u8; len]);
+        decompress_file(file, len as u64, |offset, buf| {
+            let offset = offset as usize;
+            data.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        })?;
+        Ok(data.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        for len in [0, 1, FRAME_SIZE - 1, FRAME_SIZE, 3 * FRAME_SIZE + 5] {
+            let data = content(len);
+            let compressed = compress_content(&data);
+            assert!(len < FRAME_SIZE || compressed.len() < len / 3);
+
+            let file = compressed_file(&compressed);
+            assert_eq!(
+                read_seek_table(&file).unwrap().len(),
+                len.div_ceil(FRAME_SIZE)
+            );
+            assert_eq!(decompress_content(&file, len).unwrap(), data);
+
+            let mut decompressed = vec![0u8; len];
+            decompress(Cursor::new(&compressed), len as u64, |offset, buf| {
+                let offset = offset as usize;
+                decompressed[offset..offset + buf.len()].copy_from_slice(buf);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(decompressed, data);
+
+            // A regular zstd file
+            assert_eq!(
+                zstd::stream::decode_all(Cursor::new(&compressed)).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_compression_invalid() {
+        let len = 2 * FRAME_SIZE;
+        let compressed = compress_content(&content(len));
+
+        let file = compressed_file(&compressed);
+        assert!(matches!(
+            decompress_content(&file, len - 1),
+            Err(Error::SizeMismatch)
+        ));
+        assert!(matches!(
+            decompress(Cursor::new(&compressed), len as u64 + 1, |_, _| Ok(())),
+            Err(Error::SizeMismatch)
+        ));
+        assert!(matches!(
+            decompress(Cursor::new(&compressed), len as u64 - 1, |_, _| Ok(())),
+            Err(Error::SizeMismatch)
+        ));
+
+        // Truncated seek table
+        let file = compressed_file(&compressed[..compressed.len() - 1]);
+        assert!(matches!(
+            decompress_content(&file, len),
+            Err(Error::InvalidSeekTable)
+        ));
+
+        // Frame content larger than the frames written
+        let mut oversized = compressed.clone();
+        let entries =
+            oversized.len() - SEEK_TABLE_FOOTER_SIZE as usize - 2 * SEEK_TABLE_ENTRY_SIZE as usize;
+        oversized[entries + 4..entries + 8].copy_from_slice(&(2 * FRAME_SIZE as u32).to_le_bytes());
+        oversized[entries + 12..entries + 16].copy_from_slice(&0u32.to_le_bytes());
+        let file = compressed_file(&oversized);
+        assert!(matches!(
+            decompress_content(&file, len),
+            Err(Error::InvalidSeekTable)
+        ));
+
+        // Corrupted frame
+        let mut corrupted = compressed.clone();
+        corrupted[16] ^= 0xff;
+        let file = compressed_file(&corrupted);
+        assert!(matches!(
+            decompress_content(&file, len),
+            Err(Error::Decompress(_))
+        ));
+    }
+}
@@ -0,0 +1,571 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption and integrity protection of the snapshot files.
+//!
+//! The 256-bit key the snapshot is protected with is read from a file
+//! descriptor and never used as is: an encryption key and a manifest key are
+//! derived from it with HMAC-SHA256. Every file gets its own AES-256-GCM key,
+//! derived from the encryption key, the name of the file and a random salt
+//! stored in its header. The content is sealed in chunks, each chunk being
+//! authenticated along with its index and whether it is the last one, so that
+//! chunks can't be reordered nor the file truncated.
+//!
+//! The manifest lists the size and the SHA-256 digest of the files of the
+//! snapshot, as stored, and is signed with an HMAC-SHA256 of the manifest
+//! key. It is checked before anything is read from the snapshot on restore,
+//! which catches the files being mixed with those of another snapshot
+//! encrypted with the same key.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use thiserror::Error;
+
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.json";
+
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const MAGIC: &[u8; 8] = b"CHSNAPE1";
+// Plaintext sealed at once, the last chunk of a file being shorter
+const CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Snapshot encryption requires the snapshot_encryption feature")]
+    NotSupported,
+    #[error("Cannot read the snapshot key: {0}")]
+    ReadKey(#[source] io::Error),
+    #[error("The snapshot key must be 32 bytes long")]
+    InvalidKeySize,
+    #[cfg(feature = "snapshot_encryption")]
+    #[error("Cryptographic operation failed: {0}")]
+    Crypto(#[source] openssl::error::ErrorStack),
+    #[error("Cannot access the snapshot file {0:?}: {1}")]
+    File(String, #[source] io::Error),
+    #[error("The snapshot file {0:?} is not encrypted")]
+    NotEncrypted(String),
+    #[error("Invalid snapshot manifest: {0}")]
+    InvalidManifest(#[source] serde_json::Error),
+    #[error("The snapshot manifest signature does not verify")]
+    ManifestSignature,
+    #[error("The snapshot file {0:?} is not listed in the manifest")]
+    UnlistedFile(String),
+    #[error("The snapshot file {0:?} does not match the manifest")]
+    FileMismatch(String),
+}
+
+#[cfg(feature = "snapshot_encryption")]
+mod cipher {
+    use super::{Error, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::sign::Signer;
+    use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+    pub fn hmac(key: &[u8], data: &[&[u8]]) -> Result<[u8; KEY_SIZE], Error> {
+        let key = PKey::hmac(key).map_err(Error::Crypto)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(Error::Crypto)?;
+        for data in data {
+            signer.update(data).map_err(Error::Crypto)?;
+        }
+        let mut mac = [0u8; KEY_SIZE];
+        signer.sign(&mut mac).map_err(Error::Crypto)?;
+        Ok(mac)
+    }
+
+    pub fn hmac_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && openssl::memcmp::eq(a, b)
+    }
+
+    pub fn random(buf: &mut [u8]) -> Result<(), Error> {
+        openssl::rand::rand_bytes(buf).map_err(Error::Crypto)
+    }
+
+    pub fn seal(
+        key: &[u8; KEY_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<(Vec<u8>, [u8; TAG_SIZE]), Error> {
+        let mut tag = [0u8; TAG_SIZE];
+        let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, data, &mut tag)
+            .map_err(Error::Crypto)?;
+        Ok((ciphertext, tag))
+    }
+
+    pub fn open(
+        key: &[u8; KEY_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        data: &[u8],
+        tag: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, data, tag).map_err(Error::Crypto)
+    }
+}
+
+#[cfg(not(feature = "snapshot_encryption"))]
+mod cipher {
+    use super::{Error, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+
+    pub fn hmac(_key: &[u8], _data: &[&[u8]]) -> Result<[u8; KEY_SIZE], Error> {
+        Err(Error::NotSupported)
+    }
+
+    pub fn hmac_eq(_a: &[u8], _b: &[u8]) -> bool {
+        false
+    }
+
+    pub fn random(_buf: &mut [u8]) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    pub fn seal(
+        _key: &[u8; KEY_SIZE],
+        _nonce: &[u8; NONCE_SIZE],
+        _aad: &[u8],
+        _data: &[u8],
+    ) -> Result<(Vec<u8>, [u8; TAG_SIZE]), Error> {
+        Err(Error::NotSupported)
+    }
+
+    pub fn open(
+        _key: &[u8; KEY_SIZE],
+        _nonce: &[u8; NONCE_SIZE],
+        _aad: &[u8],
+        _data: &[u8],
+        _tag: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        Err(Error::NotSupported)
+    }
+}
+
+/// Key the snapshot files are encrypted and signed with.
+pub struct SnapshotKey {
+    encryption: [u8; KEY_SIZE],
+    manifest: [u8; KEY_SIZE],
+}
+
+impl fmt::Debug for SnapshotKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SnapshotKey")
+    }
+}
+
+impl SnapshotKey {
+    fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != KEY_SIZE {
+            return Err(Error::InvalidKeySize);
+        }
+
+        Ok(SnapshotKey {
+            encryption: cipher::hmac(key, &[b"encryption"])?,
+            manifest: cipher::hmac(key, &[b"manifest"])?,
+        })
+    }
+
+    /// Reads the raw key out of `fd`, which gets closed.
+    pub fn from_fd(fd: RawFd) -> Result<Self, Error> {
+        // SAFETY: the fd is handed over along with the request, and nothing
+        // else refers to it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut key = Vec::with_capacity(KEY_SIZE + 1);
+        file.take(KEY_SIZE as u64 + 1)
+            .read_to_end(&mut key)
+            .map_err(Error::ReadKey)?;
+        let snapshot_key = Self::new(&key);
+        key.fill(0);
+        snapshot_key
+    }
+
+    fn file_key(&self, name: &str, salt: &[u8]) -> Result<[u8; KEY_SIZE], Error> {
+        cipher::hmac(&self.encryption, &[name.as_bytes(), salt])
+    }
+}
+
+impl Drop for SnapshotKey {
+    fn drop(&mut self) {
+        self.encryption.fill(0);
+        self.manifest.fill(0);
+    }
+}
+
+fn nonce(index: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
+fn aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&index.to_le_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+fn invalid_data(e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Encrypts what is written to it into `writer`. The last chunk is only
+/// sealed by `finish()`, without which the file can't be decrypted.
+pub struct EncryptedWriter<W: Write> {
+    writer: W,
+    key: [u8; KEY_SIZE],
+    index: u64,
+    chunk: Vec<u8>,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    /// Starts encrypting the file `name` of the snapshot.
+    pub fn new(mut writer: W, key: &SnapshotKey, name: &str) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_SIZE];
+        cipher::random(&mut salt)?;
+        writer
+            .write_all(MAGIC)
+            .and_then(|_| writer.write_all(&salt))
+            .map_err(|e| Error::File(name.to_string(), e))?;
+
+        Ok(EncryptedWriter {
+            writer,
+            key: key.file_key(name, &salt)?,
+            index: 0,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let (ciphertext, tag) = cipher::seal(
+            &self.key,
+            &nonce(self.index),
+            &aad(self.index, last),
+            &self.chunk,
+        )
+        .map_err(io::Error::other)?;
+        self.writer
+            .write_all(&(self.chunk.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&ciphertext)?;
+        self.writer.write_all(&tag)?;
+        self.chunk.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Seals the last chunk, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..len]);
+        // Only the last chunk is shorter, hence an empty last chunk when
+        // the content ends on a chunk boundary.
+        if self.chunk.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts the content of the file `name` of the snapshot out of `reader`,
+/// failing as soon as a chunk does not authenticate.
+pub struct EncryptedReader<R: Read> {
+    reader: R,
+    key: [u8; KEY_SIZE],
+    index: u64,
+    chunk: Vec<u8>,
+    position: usize,
+    last: bool,
+}
+
+impl<R: Read> EncryptedReader<R> {
+    pub fn new(mut reader: R, key: &SnapshotKey, name: &str) -> Result<Self, Error> {
+        let mut header = [0u8; MAGIC.len() + SALT_SIZE];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::NotEncrypted(name.to_string()),
+            _ => Error::File(name.to_string(), e),
+        })?;
+        let (magic, salt) = header.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(Error::NotEncrypted(name.to_string()));
+        }
+
+        Ok(EncryptedReader {
+            reader,
+            key: key.file_key(name, salt)?,
+            index: 0,
+            chunk: Vec::new(),
+            position: 0,
+            last: false,
+        })
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > CHUNK_SIZE {
+            return Err(invalid_data("Invalid snapshot chunk size"));
+        }
+
+        let last = len < CHUNK_SIZE;
+        let mut sealed = vec![0u8; len + TAG_SIZE];
+        self.reader.read_exact(&mut sealed)?;
+        let (ciphertext, tag) = sealed.split_at(len);
+        self.chunk = cipher::open(
+            &self.key,
+            &nonce(self.index),
+            &aad(self.index, last),
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| invalid_data("Snapshot chunk does not authenticate"))?;
+        self.position = 0;
+        self.index += 1;
+        self.last = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for EncryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.last {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Writes `data` encrypted to the new file `path`.
+pub fn write_file(path: &Path, data: &[u8], key: &SnapshotKey) -> Result<(), Error> {
+    let name = file_name(path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| Error::File(name.clone(), e))?;
+
+    let mut writer = EncryptedWriter::new(file, key, &name)?;
+    writer
+        .write_all(data)
+        .and_then(|_| writer.finish())
+        .map_err(|e| Error::File(name, e))?;
+
+    Ok(())
+}
+
+/// Reads and decrypts the file `path`.
+pub fn read_file(path: &Path, key: &SnapshotKey) -> Result<Vec<u8>, Error> {
+    let name = file_name(path);
+    let file = File::open(path).map_err(|e| Error::File(name.clone(), e))?;
+
+    let mut data = Vec::new();
+    EncryptedReader::new(file, key, &name)?
+        .read_to_end(&mut data)
+        .map_err(|e| Error::File(name, e))?;
+
+    Ok(data)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct ManifestEntry {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    files: Vec<ManifestEntry>,
+    /// HMAC-SHA256 of the serialized list of files
+    hmac: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn manifest_entry(dir: &Path, name: &str) -> Result<ManifestEntry, Error> {
+    let mut file = File::open(dir.join(name)).map_err(|e| Error::File(name.to_string(), e))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher).map_err(|e| Error::File(name.to_string(), e))?;
+
+    Ok(ManifestEntry {
+        name: name.to_string(),
+        size,
+        sha256: hex(&hasher.finalize()),
+    })
+}
+
+fn manifest_hmac(files: &[ManifestEntry], key: &SnapshotKey) -> Result<[u8; KEY_SIZE], Error> {
+    let files = serde_json::to_vec(files).map_err(Error::InvalidManifest)?;
+    cipher::hmac(&key.manifest, &[&files])
+}
+
+/// Writes the signed manifest of the files `names` of the snapshot in `dir`,
+/// skipping those the snapshot doesn't have.
+pub fn write_manifest(dir: &Path, names: &[&str], key: &SnapshotKey) -> Result<(), Error> {
+    let files = names
+        .iter()
+        .filter(|name| dir.join(name).exists())
+        .map(|name| manifest_entry(dir, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let manifest = Manifest {
+        hmac: hex(&manifest_hmac(&files, key)?),
+        files,
+    };
+
+    let path = dir.join(SNAPSHOT_MANIFEST_FILE);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| Error::File(SNAPSHOT_MANIFEST_FILE.to_string(), e))?;
+    file.write_all(&serde_json::to_vec(&manifest).map_err(Error::InvalidManifest)?)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| Error::File(SNAPSHOT_MANIFEST_FILE.to_string(), e))
+}
+
+/// Checks the manifest of the snapshot in `dir` is signed with `key`, and
+/// the files `names` the snapshot has are listed in it and match it.
+pub fn verify_manifest(dir: &Path, names: &[&str], key: &SnapshotKey) -> Result<(), Error> {
+    let manifest = std::fs::read(dir.join(SNAPSHOT_MANIFEST_FILE))
+        .map_err(|e| Error::File(SNAPSHOT_MANIFEST_FILE.to_string(), e))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest).map_err(Error::InvalidManifest)?;
+
+    if !cipher::hmac_eq(
+        hex(&manifest_hmac(&manifest.files, key)?).as_bytes(),
+        manifest.hmac.as_bytes(),
+    ) {
+        return Err(Error::ManifestSignature);
+    }
+
+    for name in names {
+        match manifest.files.iter().find(|entry| entry.name == *name) {
+            Some(entry) if manifest_entry(dir, name)? != *entry => {
+                return Err(Error::FileMismatch(name.to_string()))
+            }
+            None if dir.join(name).exists() => return Err(Error::UnlistedFile(name.to_string())),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "snapshot_encryption"))]
+mod unit_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn key(byte: u8) -> SnapshotKey {
+        SnapshotKey::new(&[byte; KEY_SIZE]).unwrap()
+    }
+
+    fn encrypt(data: &[u8], key: &SnapshotKey, name: &str) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(Vec::new(), key, name).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(data: &[u8], key: &SnapshotKey, name: &str) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        EncryptedReader::new(Cursor::new(data), key, name)
+            .map_err(io::Error::other)?
+            .read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_encryption_round_trip() {
+        for size in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 3] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let encrypted = encrypt(&data, &key(1), "state.json");
+            assert_ne!(&encrypted[MAGIC.len() + SALT_SIZE..], &data[..]);
+            assert_eq!(decrypt(&encrypted, &key(1), "state.json").unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_encryption_tampering() {
+        let data = vec![0x5a; CHUNK_SIZE + 10];
+        let encrypted = encrypt(&data, &key(1), "state.json");
+
+        // Wrong key or file name
+        assert!(decrypt(&encrypted, &key(2), "state.json").is_err());
+        assert!(decrypt(&encrypted, &key(1), "config.json").is_err());
+        // Modified content
+        let mut modified = encrypted.clone();
+        modified[MAGIC.len() + SALT_SIZE + 10] ^= 1;
+        assert!(decrypt(&modified, &key(1), "state.json").is_err());
+        // Truncated after the first chunk
+        let first_chunk = MAGIC.len() + SALT_SIZE + 4 + CHUNK_SIZE + TAG_SIZE;
+        assert!(decrypt(&encrypted[..first_chunk], &key(1), "state.json").is_err());
+        // Not encrypted
+        assert!(matches!(
+            EncryptedReader::new(Cursor::new(b"{}"), &key(1), "state.json"),
+            Err(Error::NotEncrypted(_))
+        ));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let dir = dir.as_path();
+        write_file(&dir.join("config.json"), b"{}", &key(1)).unwrap();
+        write_file(&dir.join("state.json"), b"{}", &key(1)).unwrap();
+        let names = ["config.json", "state.json", "memory-ranges"];
+
+        write_manifest(dir, &names, &key(1)).unwrap();
+        verify_manifest(dir, &names, &key(1)).unwrap();
+        assert!(matches!(
+            verify_manifest(dir, &names, &key(2)),
+            Err(Error::ManifestSignature)
+        ));
+
+        // A file swapped with one encrypted with the same key
+        std::fs::remove_file(dir.join("state.json")).unwrap();
+        write_file(&dir.join("state.json"), b"{}", &key(1)).unwrap();
+        assert!(matches!(
+            verify_manifest(dir, &names, &key(1)),
+            Err(Error::FileMismatch(_))
+        ));
+
+        // A file added to the snapshot
+        write_file(&dir.join("memory-ranges"), b"", &key(1)).unwrap();
+        assert!(matches!(
+            verify_manifest(dir, &["memory-ranges"], &key(1)),
+            Err(Error::UnlistedFile(_))
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots streamed to, or restored from, a file descriptor (`fd:<n>`) or an
+//! HTTP(S) URL rather than a directory, so that they can be handed over to the
+//! storage without going through a local copy.
+//!
+//! The stream starts with a magic number, followed by the files of the
+//! snapshot one after the other, in the order they are read on restore. Each
+//! file is preceded by the length of its name, its name and its size. The size
+//! of the whole stream being known upfront, snapshots are uploaded with a
+//! single `PUT` request carrying a `Content-Length`, as expected by the
+//! pre-signed URLs of S3-compatible object storages.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::time::Duration;
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"CHSNAPS1";
+const STREAM_URL_PREFIXES: [&str; 3] = ["fd:", "http://", "https://"];
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest the server may stall the transfer before the snapshot fails
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid snapshot URL: {0}")]
+    InvalidUrl(String),
+    #[error("Error connecting to {0}: {1}")]
+    Connect(String, #[source] io::Error),
+    #[error("Snapshots can only be streamed over HTTPS with the http_tls feature")]
+    HttpsNotSupported,
+    #[cfg(feature = "http_tls")]
+    #[error("Error reading the CA certificates: {0}")]
+    CaCertificates(#[source] io::Error),
+    #[cfg(feature = "http_tls")]
+    #[error("Error setting up TLS: {0}")]
+    Tls(#[source] rustls::Error),
+    #[error("Invalid HTTP response")]
+    InvalidResponse,
+    #[error("HTTP request failed: {0}")]
+    HttpStatus(String),
+    #[error("Chunked HTTP responses are not supported")]
+    ChunkedResponse,
+    #[error("Error writing the snapshot stream: {0}")]
+    Write(#[source] io::Error),
+    #[error("Error reading the snapshot stream: {0}")]
+    Read(#[source] io::Error),
+    #[error("Not a snapshot stream")]
+    NotSnapshotStream,
+    #[error("Expected {0} in the snapshot stream, found {1}")]
+    UnexpectedFile(String, String),
+    #[error("Snapshot file {0} is not of the size announced")]
+    FileSize(String),
+}
+
+/// Whether the snapshot is streamed rather than stored in a directory.
+pub fn is_stream_url(url: &str) -> bool {
+    STREAM_URL_PREFIXES
+        .iter()
+        .any(|prefix| url.starts_with(prefix))
+}
+
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+struct HttpUrl<'a> {
+    tls: bool,
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+fn parse_http_url(url: &str) -> Result<HttpUrl<'_>, Error> {
+    let invalid = || Error::InvalidUrl(url.to_string());
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        (false, url.strip_prefix("http://").ok_or_else(invalid)?)
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    // The port follows the closing bracket of IPv6 addresses.
+    let port_index = authority
+        .rfind(':')
+        .filter(|index| !authority[*index..].contains(']'));
+    let (host, port) = match port_index {
+        Some(index) => (
+            &authority[..index],
+            authority[index + 1..].parse().map_err(|_| invalid())?,
+        ),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(HttpUrl {
+        tls,
+        authority,
+        host,
+        port,
+        path,
+    })
+}
+
+fn fd_file(url: &str) -> Result<File, Error> {
+    let fd: RawFd = url
+        .strip_prefix("fd:")
+        .and_then(|fd| fd.parse().ok())
+        .filter(|fd| *fd >= 0)
+        .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+
+    // SAFETY: the file descriptor was handed over for the snapshot, which
+    // owns it from now on.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(feature = "http_tls")]
+fn tls_connect(host: &str, stream: TcpStream) -> Result<Box<dyn Connection>, Error> {
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::sync::Arc;
+
+    // The CA certificates of the host, unless SSL_CERT_FILE points to others
+    let certs = rustls_native_certs::load_native_certs().map_err(Error::CaCertificates)?;
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(certs);
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(Error::Tls)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name =
+        ServerName::try_from(host.to_string()).map_err(|_| Error::InvalidUrl(host.to_string()))?;
+    let connection = ClientConnection::new(Arc::new(config), name).map_err(Error::Tls)?;
+
+    Ok(Box::new(StreamOwned::new(connection, stream)))
+}
+
+#[cfg(not(feature = "http_tls"))]
+fn tls_connect(_host: &str, _stream: TcpStream) -> Result<Box<dyn Connection>, Error> {
+    Err(Error::HttpsNotSupported)
+}
+
+// Connects to the server of the URL, returning the connection along with the
+// request line and the host header of the request to send.
+fn http_connect(url: &str, method: &str) -> Result<(Box<dyn Connection>, String), Error> {
+    let http_url = parse_http_url(url)?;
+    let connect_error = |e| Error::Connect(http_url.authority.to_string(), e);
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No address found");
+    let mut stream = None;
+    for addr in (http_url.host, http_url.port)
+        .to_socket_addrs()
+        .map_err(connect_error)?
+    {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let stream = stream.ok_or_else(|| connect_error(last_error))?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(connect_error)?;
+    let connection: Box<dyn Connection> = if http_url.tls {
+        tls_connect(http_url.host, stream)?
+    } else {
+        Box::new(stream)
+    };
+
+    let request = format!(
+        "{method} {} HTTP/1.1\r\nHost: {}\r\n",
+        http_url.path, http_url.authority
+    );
+
+    Ok((connection, request))
+}
+
+// Reads the status line and the headers of the response, failing unless the
+// request succeeded. Returns whether the body is chunked.
+fn http_response(reader: &mut impl BufRead) -> Result<bool, Error> {
+    let mut read_line = || -> Result<String, Error> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(Error::Read)? == 0 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(line.trim_end().to_string())
+    };
+
+    let status_line = read_line()?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or(Error::InvalidResponse)?;
+    if !status.starts_with('2') {
+        return Err(Error::HttpStatus(status_line));
+    }
+
+    let mut chunked = false;
+    loop {
+        let header = read_line()?;
+        if header.is_empty() {
+            return Ok(chunked);
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            chunked |= name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked");
+        }
+    }
+}
+
+fn file_header(name: &str, size: u64) -> Vec<u8> {
+    let mut header = vec![name.len() as u8];
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header
+}
+
+/// Writes the files of a snapshot into a stream, in the order they were
+/// announced.
+pub struct SnapshotWriter {
+    writer: BufWriter<Box<dyn Connection>>,
+    // Start of the upload request, sent once the size is known
+    http_request: Option<String>,
+    files: Vec<(String, u64)>,
+    // Bytes left to write of the current file
+    remaining: u64,
+}
+
+impl SnapshotWriter {
+    /// Opens the stream `url`, nothing being written to it until the files
+    /// are announced.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        let (connection, http_request) = if url.starts_with("fd:") {
+            (Box::new(fd_file(url)?) as Box<dyn Connection>, None)
+        } else {
+            let (connection, request) = http_connect(url, "PUT")?;
+            (connection, Some(request))
+        };
+
+        Ok(SnapshotWriter {
+            writer: BufWriter::new(connection),
+            http_request,
+            files: Vec::new(),
+            remaining: 0,
+        })
+    }
+
+    /// Starts the stream, made of the `files` given by name and size.
+    pub fn start(&mut self, files: &[(&str, u64)]) -> Result<(), Error> {
+        if let Some(request) = &self.http_request {
+            let len = MAGIC.len() as u64
+                + files
+                    .iter()
+                    .map(|(name, size)| file_header(name, *size).len() as u64 + size)
+                    .sum::<u64>();
+            write!(
+                self.writer,
+                "{request}Content-Type: application/octet-stream\r\nContent-Length: {len}\r\nConnection: close\r\n\r\n"
+            )
+            .map_err(Error::Write)?;
+        }
+        self.writer.write_all(MAGIC).map_err(Error::Write)?;
+
+        // Popped from the end
+        self.files = files
+            .iter()
+            .rev()
+            .map(|(name, size)| (name.to_string(), *size))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Starts writing the file `name`, expected to be the next one.
+    pub fn file(&mut self, name: &str) -> Result<&mut Self, Error> {
+        let size = match self.files.last() {
+            Some((next, size)) if next == name => *size,
+            next => {
+                let next = next.map(|(next, _)| next.clone()).unwrap_or_default();
+                return Err(Error::UnexpectedFile(next, name.to_string()));
+            }
+        };
+        self.files.pop();
+
+        self.writer
+            .write_all(&file_header(name, size))
+            .map_err(Error::Write)?;
+        self.remaining = size;
+
+        Ok(self)
+    }
+
+    /// Completes the stream, waiting for the upload to be acknowledged.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.remaining != 0 || !self.files.is_empty() {
+            return Err(Error::Write(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.writer.flush().map_err(Error::Write)?;
+
+        if self.http_request.is_some() {
+            let connection = self
+                .writer
+                .into_inner()
+                .map_err(|e| Error::Write(e.into_error()))?;
+            http_response(&mut BufReader::new(connection))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for SnapshotWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Snapshot file larger than announced",
+            ));
+        }
+
+        let len = self.writer.write(buf)?;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the files of a snapshot out of a stream.
+pub struct SnapshotReader {
+    reader: Box<dyn Read>,
+}
+
+impl SnapshotReader {
+    /// Opens the stream `url`.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        let mut reader: Box<dyn Read> = if url.starts_with("fd:") {
+            Box::new(BufReader::new(fd_file(url)?))
+        } else {
+            let (mut connection, request) = http_connect(url, "GET")?;
+            write!(connection, "{request}Connection: close\r\n\r\n").map_err(Error::Write)?;
+            let mut reader = BufReader::new(connection);
+            if http_response(&mut reader)? {
+                return Err(Error::ChunkedResponse);
+            }
+            Box::new(reader)
+        };
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(Error::Read)?;
+        if &magic != MAGIC {
+            return Err(Error::NotSnapshotStream);
+        }
+
+        Ok(SnapshotReader { reader })
+    }
+
+    /// Content of the file `name`, expected to be the next one.
+    pub fn file(&mut self, name: &str) -> Result<io::Take<&mut Box<dyn Read>>, Error> {
+        let mut len = [0u8; 1];
+        self.reader.read_exact(&mut len).map_err(Error::Read)?;
+        let mut next = vec![0u8; len[0] as usize];
+        self.reader.read_exact(&mut next).map_err(Error::Read)?;
+        let next = String::from_utf8_lossy(&next);
+        if next != name {
+            return Err(Error::UnexpectedFile(name.to_string(), next.into_owned()));
+        }
+
+        let mut size = [0u8; 8];
+        self.reader.read_exact(&mut size).map_err(Error::Read)?;
+
+        Ok((&mut self.reader).take(u64::from_le_bytes(size)))
+    }
+
+    /// Reads the whole content of the file `name`.
+    pub fn read_file(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        let mut file = self.file(name)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(Error::Read)?;
+        // Falling short of the size is only noticed as the end of the file.
+        if file.limit() != 0 {
+            return Err(Error::FileSize(name.to_string()));
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_parse_http_url() {
+        let url = parse_http_url("http://storage:9000/bucket/snapshot?X-Amz-Signature=0").unwrap();
+        assert!(!url.tls);
+        assert_eq!(url.authority, "storage:9000");
+        assert_eq!(url.host, "storage");
+        assert_eq!(url.port, 9000);
+        assert_eq!(url.path, "/bucket/snapshot?X-Amz-Signature=0");
+
+        let url = parse_http_url("https://[fd00::1]").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.host, "fd00::1");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/");
+
+        let url = parse_http_url("http://[fd00::1]:8080/snapshot").unwrap();
+        assert_eq!(url.host, "fd00::1");
+        assert_eq!(url.port, 8080);
+
+        assert!(parse_http_url("http://storage:port/snapshot").is_err());
+        assert!(parse_http_url("http:///snapshot").is_err());
+        assert!(parse_http_url("file:///snapshot").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_stream() {
+        let temp_file = TempFile::new().unwrap();
+        let stream_url = || {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .open(temp_file.as_path())
+                .unwrap();
+            format!("fd:{}", file.into_raw_fd())
+        };
+
+        let files = [("config.json", 4), ("memory-ranges", 0)];
+        let mut writer = SnapshotWriter::open(&stream_url()).unwrap();
+        writer.start(&files).unwrap();
+        assert!(writer.file("memory-ranges").is_err());
+        writer
+            .file("config.json")
+            .unwrap()
+            .write_all(b"{}")
+            .unwrap();
+        assert!(writer.write_all(b"{}{}").is_err());
+        writer.write_all(b"{}").unwrap();
+        writer.file("memory-ranges").unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = SnapshotReader::open(&stream_url()).unwrap();
+        assert_eq!(reader.read_file("config.json").unwrap(), b"{}{}");
+        assert_eq!(reader.read_file("memory-ranges").unwrap(), b"");
+
+        let mut reader = SnapshotReader::open(&stream_url()).unwrap();
+        assert!(matches!(
+            reader.read_file("state.json"),
+            Err(Error::UnexpectedFile(_, _))
+        ));
+
+        let mut writer = SnapshotWriter::open(&stream_url()).unwrap();
+        writer.start(&files).unwrap();
+        writer.file("config.json").unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
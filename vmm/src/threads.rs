@@ -0,0 +1,221 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::mem::size_of;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThreadRole {
+    /// Initial thread of the process
+    Main,
+    /// Thread handling the VMM control loop and the API requests
+    Vmm,
+    /// Thread running a vCPU
+    Vcpu,
+    /// Thread serving a device
+    Device,
+    /// Thread running the HTTP or D-Bus API server
+    Api,
+    EventMonitor,
+    SignalHandler,
+    SerialManager,
+    GdbStub,
+    PayloadLoader,
+    /// Thread whose role could not be identified
+    Other,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThreadInfo {
+    /// Name of the thread, truncated to 15 characters by the kernel
+    pub name: String,
+    pub tid: i32,
+    pub role: ThreadRole,
+    /// Identifier of the device served by a device thread
+    pub device_id: Option<String>,
+    /// Identifier of the vCPU run by a vCPU thread
+    pub vcpu_id: Option<u8>,
+    /// Host CPUs the thread is allowed to run on
+    pub affinity: Vec<usize>,
+}
+
+// Kernel limit of the thread name length, without the NUL terminator.
+const TASK_COMM_LEN: usize = 15;
+
+fn thread_affinity(tid: i32) -> io::Result<Vec<usize>> {
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call with correct arguments
+    let ret = unsafe { libc::sched_getaffinity(tid, size_of::<libc::cpu_set_t>(), &mut cpuset) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((0..libc::CPU_SETSIZE as usize)
+        // SAFETY: FFI call, trivially safe
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &cpuset) })
+        .collect())
+}
+
+// Threads serving a device are named after the device identifier, which
+// allows finding out the full identifier from the possibly truncated name.
+fn thread_role(name: &str, tid: i32, device_ids: &[String]) -> (ThreadRole, Option<String>) {
+    // SAFETY: FFI call, trivially safe
+    if tid == unsafe { libc::getpid() } {
+        return (ThreadRole::Main, None);
+    }
+
+    let role = match name {
+        "vmm" => ThreadRole::Vmm,
+        "http-server" | "dbus-thread" => ThreadRole::Api,
+        "event-monitor" => ThreadRole::EventMonitor,
+        "vmm_signal_hand" => ThreadRole::SignalHandler,
+        "serial-manager" => ThreadRole::SerialManager,
+        "gdb" => ThreadRole::GdbStub,
+        "payload_loader" => ThreadRole::PayloadLoader,
+        _ if vcpu_id(name).is_some() => ThreadRole::Vcpu,
+        _ => {
+            let device_id = device_ids
+                .iter()
+                .find(|id| device_thread_name_matches(name, id));
+            return match device_id {
+                Some(id) => (ThreadRole::Device, Some(id.clone())),
+                None => (ThreadRole::Other, None),
+            };
+        }
+    };
+
+    (role, None)
+}
+
+// Device threads are either named after the device, or after the device and
+// what they handle, e.g. "<id>_q<index>", "<id>_qp<index>" or "<id>_ctrl".
+fn device_thread_name_matches(name: &str, id: &str) -> bool {
+    let truncated = name.len() == TASK_COMM_LEN;
+    match name.strip_prefix(id) {
+        Some("") => true,
+        Some(suffix) => {
+            let queue = suffix
+                .strip_prefix("_qp")
+                .or_else(|| suffix.strip_prefix("_q"))
+                .map(|index| {
+                    (truncated || !index.is_empty()) && index.chars().all(|c| c.is_ascii_digit())
+                });
+            queue.unwrap_or(suffix == "_ctrl" || (truncated && "_ctrl".starts_with(suffix)))
+        }
+        None => truncated && id.starts_with(name),
+    }
+}
+
+fn vcpu_id(name: &str) -> Option<u8> {
+    name.strip_prefix("vcpu")?.parse().ok()
+}
+
+/// Lists the threads of the VMM process. The device identifiers are used to
+/// associate the device threads with the device they serve.
+pub fn list_threads(device_ids: &[String]) -> io::Result<Vec<ThreadInfo>> {
+    let mut threads = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let Some(tid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|tid| tid.parse::<i32>().ok())
+        else {
+            continue;
+        };
+
+        // The thread may have exited since the directory was read.
+        let name = match fs::read_to_string(entry.path().join("comm")) {
+            Ok(name) => name.trim_end().to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let affinity = match thread_affinity(tid) {
+            Ok(affinity) => affinity,
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => continue,
+            Err(e) => return Err(e),
+        };
+        let (role, device_id) = thread_role(&name, tid, device_ids);
+
+        threads.push(ThreadInfo {
+            vcpu_id: if role == ThreadRole::Vcpu {
+                vcpu_id(&name)
+            } else {
+                None
+            },
+            name,
+            tid,
+            role,
+            device_id,
+            affinity,
+        });
+    }
+    threads.sort_by_key(|thread| thread.tid);
+
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_list_threads() {
+        let (tid_sender, tid_receiver) = channel();
+        let (exit_sender, exit_receiver) = channel::<()>();
+        let handle = thread::Builder::new()
+            .name("_disk0".to_string())
+            .spawn(move || {
+                // SAFETY: FFI call, trivially safe
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+                tid_sender.send(tid).unwrap();
+                exit_receiver.recv().ok();
+            })
+            .unwrap();
+        let tid = tid_receiver.recv().unwrap();
+
+        let threads = list_threads(&["_disk0".to_string()]).unwrap();
+        let thread = threads.iter().find(|thread| thread.tid == tid).unwrap();
+        assert_eq!(thread.name, "_disk0");
+        assert_eq!(thread.role, ThreadRole::Device);
+        assert_eq!(thread.device_id, Some("_disk0".to_string()));
+        assert!(!thread.affinity.is_empty());
+
+        exit_sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_thread_role() {
+        let device_ids = vec!["_net1".to_string(), "my-very-long-disk-id".to_string()];
+
+        assert_eq!(
+            thread_role("vcpu12", 0, &device_ids),
+            (ThreadRole::Vcpu, None)
+        );
+        assert_eq!(thread_role("vmm", 0, &device_ids), (ThreadRole::Vmm, None));
+        assert_eq!(
+            thread_role("_net1", 0, &device_ids),
+            (ThreadRole::Device, Some("_net1".to_string()))
+        );
+        assert_eq!(
+            thread_role("_net1_q1", 0, &device_ids),
+            (ThreadRole::Device, Some("_net1".to_string()))
+        );
+        assert_eq!(
+            thread_role("my-very-long-di", 0, &device_ids),
+            (ThreadRole::Device, Some("my-very-long-disk-id".to_string()))
+        );
+        assert_eq!(
+            thread_role("_net", 0, &device_ids),
+            (ThreadRole::Other, None)
+        );
+    }
+}
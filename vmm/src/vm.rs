@@ -11,30 +11,45 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::boot_detect::{BootDetector, BootState};
 use crate::config::{
     add_to_config, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
-    UserDeviceConfig, ValidationError, VdpaConfig, VmConfig, VsockConfig,
+    ReplacementDeviceConfig, UsbConfig, UserDeviceConfig, ValidationError, VdpaConfig, VmConfig,
+    VmIdentityConfig, VsockConfig,
 };
-use crate::config::{NumaConfig, PayloadConfig};
+use crate::config::{NumaConfig, PayloadConfig, RtConfig};
+use crate::config_update::LiveConfigChange;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
     CpuElf64Writable, DumpState, Elf64Writable, GuestDebuggable, GuestDebuggableError, NoteDescType,
 };
 use crate::cpu;
-use crate::device_manager::{DeviceManager, DeviceManagerError, PtyPair};
-use crate::device_tree::DeviceTree;
+use crate::device_access::{BusKind, DeviceAccessMonitor};
+use crate::device_manager::{
+    DeviceManager, DeviceManagerError, PciSegmentCapabilities, PtyPair, RemovableDevice,
+};
+use crate::device_tree::{DeviceTree, PciDeviceAddress};
 #[cfg(feature = "guest_debug")]
 use crate::gdb::{Debuggable, DebuggableError, GdbRequestPayload, GdbResponsePayload};
 #[cfg(feature = "igvm")]
 use crate::igvm::igvm_loader;
 use crate::memory_manager::{
-    Error as MemoryManagerError, MemoryManager, MemoryManagerSnapshotData,
+    Error as MemoryManagerError, MemoryCapabilities, MemoryManager, MemoryManagerSnapshotData,
+    MemoryZoneBacking, SNAPSHOT_FILENAME,
 };
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 use crate::migration::get_vm_snapshot;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::migration::url_to_file;
-use crate::migration::{url_to_path, SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE};
+use crate::migration::{
+    url_to_path, MigrationBlocker, MigrationBlockers, SnapshotSource, SNAPSHOT_CONFIG_FILE,
+    SNAPSHOT_FILES, SNAPSHOT_STATE_FILE,
+};
+use crate::payload_verification::PayloadVerification;
+use crate::realtime;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::snapshot_encryption::{self, SnapshotKey};
+use crate::snapshot_stream::{self, SnapshotWriter};
 use crate::GuestMemoryMmap;
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
@@ -70,16 +85,20 @@ use linux_loader::loader::pe::Error::InvalidImageMagicNumber;
 use linux_loader::loader::KernelLoader;
 use seccompiler::SeccompAction;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Seek, SeekFrom, Write};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
+use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use std::{result, str, thread};
@@ -99,6 +118,27 @@ use vm_migration::{
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
+// PCRs the payload is measured into with direct kernel boot, following the
+// ones used by bootloaders and the Linux EFI stub.
+const PCR_KERNEL: u32 = 4;
+const PCR_CMDLINE: u32 = 8;
+const PCR_INITRAMFS: u32 = 9;
+
+// Amount of guest memory read at once when introspecting.
+#[cfg(feature = "introspection")]
+const INTROSPECT_CHUNK_SIZE: usize = 1 << 20;
+
+// Maximum number of matches returned by a single introspection request.
+#[cfg(feature = "introspection")]
+const INTROSPECT_MAX_MATCHES: usize = 4096;
+
+// Maximum size of the pattern looked for when introspecting.
+#[cfg(feature = "introspection")]
+const INTROSPECT_MAX_PATTERN_SIZE: usize = 4096;
+
+// Amount of guest memory read at once when dumping it.
+const DUMP_MEMORY_CHUNK_SIZE: usize = 1 << 20;
+
 /// Errors associated with VM management
 #[derive(Debug, Error)]
 pub enum Error {
@@ -158,9 +198,24 @@ pub enum Error {
     #[error("VM is already created")]
     VmAlreadyCreated,
 
+    #[error("VM is already booted")]
+    VmAlreadyBooted,
+
     #[error("VM is not running")]
     VmNotRunning,
 
+    #[error("VM is a ready VM, which must be booted")]
+    VmReady,
+
+    #[error("VM is not a ready VM, whose identity can be set on boot")]
+    VmNotReady,
+
+    #[error("VM is not paused")]
+    VmNotPaused,
+
+    #[error("Invalid SMBIOS UUID: {0}")]
+    InvalidUuid(#[source] uuid::Error),
+
     #[error("Cannot clone EventFd: {0}")]
     EventFdClone(#[source] io::Error),
 
@@ -206,6 +261,12 @@ pub enum Error {
     #[error("Invalid restore source URL")]
     InvalidRestoreSourceUrl,
 
+    #[error("Invalid snapshot key: {0}")]
+    SnapshotKey(#[source] snapshot_encryption::Error),
+
+    #[error("Error opening the snapshot stream: {0}")]
+    SnapshotStream(#[source] snapshot_stream::Error),
+
     #[error("Failed to validate config: {0}")]
     ConfigValidation(#[source] ValidationError),
 
@@ -233,6 +294,9 @@ pub enum Error {
     #[error("Error triggering power button: {0:?}")]
     PowerButton(DeviceManagerError),
 
+    #[error("Error triggering sleep button: {0:?}")]
+    SleepButton(DeviceManagerError),
+
     #[error("Kernel lacks PVH header")]
     KernelMissingPvhHeader,
 
@@ -319,6 +383,95 @@ pub enum Error {
 
     #[error("Error injecting NMI")]
     ErrorNmi,
+
+    #[error("Invalid SysRq key {0:?}, must be a printable ASCII character")]
+    InvalidSysRqKey(char),
+
+    #[error("Invalid screenshot destination {0}, must be a file:// URL")]
+    InvalidScreenshotUrl(String),
+
+    #[error("Error writing the screenshot: {0}")]
+    WriteScreenshot(#[source] io::Error),
+
+    #[error("Invalid memory dump destination {0}, must be a file:// URL")]
+    InvalidMemoryDumpUrl(String),
+
+    #[error("Invalid memory dump range 0x{0:x} + 0x{1:x}, must be non-empty and end within the guest RAM")]
+    InvalidMemoryDumpRange(u64, u64),
+
+    #[error("Error reading the guest memory to dump: {0}")]
+    ReadMemoryDump(#[source] vm_memory::GuestMemoryError),
+
+    #[error("Error writing the memory dump: {0}")]
+    WriteMemoryDump(#[source] io::Error),
+
+    #[error("Memory dump cancelled")]
+    MemoryDumpCancelled,
+
+    #[error("Error listing the VMM threads: {0}")]
+    ListThreads(#[source] io::Error),
+
+    #[error("Error listing the VMM file descriptors: {0}")]
+    ListFds(#[source] io::Error),
+
+    #[error("Invalid checkpoint URL, a file:// URL is expected: {0}")]
+    InvalidCheckpointUrl(String),
+
+    #[error("The VM is already checkpointed")]
+    CheckpointPending,
+
+    #[error("No VM checkpointed to restore")]
+    NoCheckpoint,
+
+    #[error("The VMM can't be checkpointed by CRIU, {0} being left open")]
+    NotCheckpointable(String),
+
+    #[error("Error updating the VM configuration: {0}")]
+    UpdateConfig(#[source] crate::config_update::Error),
+
+    #[error("Error profiling the VMM: {0}")]
+    Profile(#[source] io::Error),
+
+    #[cfg(feature = "pprof")]
+    #[error("Invalid pprof profile destination {0}, must be a file:// URL")]
+    InvalidPprofUrl(String),
+
+    #[cfg(feature = "pprof")]
+    #[error("Error gathering the pprof profile: {0}")]
+    Pprof(#[source] crate::profile::PprofError),
+
+    #[error("Error applying the realtime profile: {0}")]
+    RealtimeProfile(#[source] io::Error),
+
+    #[error("Not enough isolated host CPUs ({0}) to pin the {1} vCPUs onto")]
+    RealtimeNotEnoughCpus(usize, u8),
+
+    #[error("Error setting up the boot detection: {0}")]
+    BootDetect(#[source] io::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    #[error("Cannot read the device tree overlay {0}: {1}")]
+    DtOverlayFile(std::path::PathBuf, #[source] io::Error),
+
+    #[error("Error running the hook: {0}")]
+    Hook(#[source] crate::hooks::Error),
+
+    #[error("Host dependency missing: {0}")]
+    HostCheck(#[source] crate::host_checks::Error),
+
+    #[cfg(feature = "introspection")]
+    #[error("Error introspecting guest memory: {0}")]
+    Introspect(#[source] anyhow::Error),
+
+    #[cfg(feature = "payload_verification")]
+    #[error("Error verifying the payload: {0}")]
+    PayloadVerification(#[source] crate::payload_verification::Error),
+
+    #[error("Cannot read the payload to measure: {0}")]
+    PayloadMeasurementRead(#[source] io::Error),
+
+    #[error("Error measuring the payload into the TPM: {0}")]
+    PayloadMeasurement(#[source] devices::tpm::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -369,11 +522,117 @@ impl VmState {
     }
 }
 
+/// What triggered the last shutdown or reboot of the VM.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShutdownReason {
+    /// The guest powered off, through ACPI or PSCI
+    GuestPoweroff,
+    /// The guest requested a reset
+    GuestReset,
+    /// The guest triggered a triple fault, or an unrecoverable exception
+    TripleFault,
+    /// The guest stopped pinging the virtio-watchdog device
+    Watchdog,
+    /// The VM was shut down or rebooted through the API
+    Api,
+    /// The VMM received SIGTERM or SIGINT
+    Signal,
+    /// The VM was live migrated to another host
+    Migration,
+    /// A vCPU or a device thread failed
+    Error,
+    /// The guest reported a panic through the pvpanic device before
+    /// resetting or powering off
+    GuestPanic,
+}
+
+impl ShutdownReason {
+    /// Whether the guest stopped because of a crash rather than on purpose.
+    pub fn is_failure(self) -> bool {
+        matches!(
+            self,
+            ShutdownReason::TripleFault
+                | ShutdownReason::Watchdog
+                | ShutdownReason::Error
+                | ShutdownReason::GuestPanic
+        )
+    }
+
+    /// Exit status of the VMM process when it terminates because of the
+    /// VM shutting down. Status 1 is left for the VMM failing by itself.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ShutdownReason::GuestPoweroff
+            | ShutdownReason::GuestReset
+            | ShutdownReason::Api
+            | ShutdownReason::Signal
+            | ShutdownReason::Migration => 0,
+            ShutdownReason::TripleFault => 2,
+            ShutdownReason::Watchdog => 3,
+            ShutdownReason::Error => 4,
+            ShutdownReason::GuestPanic => 5,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ShutdownReason::GuestPoweroff => "guest-poweroff",
+            ShutdownReason::GuestReset => "guest-reset",
+            ShutdownReason::TripleFault => "triple-fault",
+            ShutdownReason::Watchdog => "watchdog",
+            ShutdownReason::Api => "api",
+            ShutdownReason::Signal => "signal",
+            ShutdownReason::Migration => "migration",
+            ShutdownReason::Error => "error",
+            ShutdownReason::GuestPanic => "guest-panic",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// Limits on what can still be hotplugged into the VM, along with the
+/// features it supports.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VmCapabilities {
+    pub cpus: cpu::CpusCapabilities,
+    pub memory: MemoryCapabilities,
+    pub pci_segments: Vec<PciSegmentCapabilities>,
+    pub features: Vec<String>,
+}
+
+/// Devices of the VM, along with the addresses the guest sees the PCI ones
+/// at.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDeviceTree {
+    pub pci_devices: Vec<PciDeviceAddress>,
+    pub devices: DeviceTree,
+}
+
 struct VmOpsHandler {
     memory: GuestMemoryAtomic<GuestMemoryMmap>,
     #[cfg(target_arch = "x86_64")]
     io_bus: Arc<Bus>,
     mmio_bus: Arc<Bus>,
+    device_access: Option<Arc<DeviceAccessMonitor>>,
+}
+
+impl VmOpsHandler {
+    fn access<T>(
+        &self,
+        bus: &Bus,
+        kind: BusKind,
+        addr: u64,
+        write: bool,
+        access: impl FnOnce() -> T,
+    ) -> T {
+        match &self.device_access {
+            Some(device_access) => device_access.monitor(bus, kind, addr, write, access),
+            None => access(),
+        }
+    }
 }
 
 impl VmOps for VmOpsHandler {
@@ -392,14 +651,20 @@ impl VmOps for VmOpsHandler {
     }
 
     fn mmio_read(&self, gpa: u64, data: &mut [u8]) -> result::Result<(), HypervisorVmError> {
-        if let Err(vm_device::BusError::MissingAddressRange) = self.mmio_bus.read(gpa, data) {
+        if let Err(vm_device::BusError::MissingAddressRange) =
+            self.access(&self.mmio_bus, BusKind::Mmio, gpa, false, || {
+                self.mmio_bus.read(gpa, data)
+            })
+        {
             info!("Guest MMIO read to unregistered address 0x{:x}", gpa);
         }
         Ok(())
     }
 
     fn mmio_write(&self, gpa: u64, data: &[u8]) -> result::Result<(), HypervisorVmError> {
-        match self.mmio_bus.write(gpa, data) {
+        match self.access(&self.mmio_bus, BusKind::Mmio, gpa, true, || {
+            self.mmio_bus.write(gpa, data)
+        }) {
             Err(vm_device::BusError::MissingAddressRange) => {
                 info!("Guest MMIO write to unregistered address 0x{:x}", gpa);
             }
@@ -415,7 +680,11 @@ impl VmOps for VmOpsHandler {
 
     #[cfg(target_arch = "x86_64")]
     fn pio_read(&self, port: u64, data: &mut [u8]) -> result::Result<(), HypervisorVmError> {
-        if let Err(vm_device::BusError::MissingAddressRange) = self.io_bus.read(port, data) {
+        if let Err(vm_device::BusError::MissingAddressRange) =
+            self.access(&self.io_bus, BusKind::Pio, port, false, || {
+                self.io_bus.read(port, data)
+            })
+        {
             info!("Guest PIO read to unregistered address 0x{:x}", port);
         }
         Ok(())
@@ -423,7 +692,9 @@ impl VmOps for VmOpsHandler {
 
     #[cfg(target_arch = "x86_64")]
     fn pio_write(&self, port: u64, data: &[u8]) -> result::Result<(), HypervisorVmError> {
-        match self.io_bus.write(port, data) {
+        match self.access(&self.io_bus, BusKind::Pio, port, true, || {
+            self.io_bus.write(port, data)
+        }) {
             Err(vm_device::BusError::MissingAddressRange) => {
                 info!("Guest PIO write to unregistered address 0x{:x}", port);
             }
@@ -438,14 +709,95 @@ impl VmOps for VmOpsHandler {
     }
 }
 
-pub fn physical_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>, max_phys_bits: u8) -> u8 {
+pub fn physical_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>, config: &VmConfig) -> u8 {
     let host_phys_bits = get_host_cpu_phys_bits(hypervisor);
 
-    cmp::min(host_phys_bits, max_phys_bits)
+    cmp::min(
+        host_phys_bits,
+        cmp::max(config.cpus.max_phys_bits, mmio64_phys_bits(config)),
+    )
+}
+
+// Physical address bits needed for the 64-bit MMIO aperture sized with
+// --platform to fit after the RAM, the hotplug areas and the SGX EPC, up to
+// the 128MiB alignment of each, and before the platform devices.
+fn mmio64_phys_bits(config: &VmConfig) -> u8 {
+    let Some(mmio64_size) = config
+        .platform
+        .as_ref()
+        .and_then(|platform| platform.mmio64_size)
+    else {
+        return 0;
+    };
+
+    let memory = &config.memory;
+    let mut end = arch::layout::RAM_64BIT_START
+        .0
+        .saturating_add(memory.size)
+        .saturating_add(memory.hotplug_size.unwrap_or(0))
+        .saturating_add(virtio_devices::VIRTIO_MEM_ALIGN_SIZE);
+    for zone in memory.zones.iter().flatten() {
+        end = end
+            .saturating_add(zone.size)
+            .saturating_add(zone.hotplug_size.unwrap_or(0))
+            .saturating_add(virtio_devices::VIRTIO_MEM_ALIGN_SIZE);
+    }
+    #[cfg(target_arch = "x86_64")]
+    for sgx_epc in config.sgx_epc.iter().flatten() {
+        end = end
+            .saturating_add(sgx_epc.size)
+            .saturating_add(virtio_devices::VIRTIO_MEM_ALIGN_SIZE);
+    }
+    // The platform devices take 1MiB, below the last 64KiB.
+    let end = end.saturating_add(mmio64_size).saturating_add(2 << 20);
+
+    (u64::BITS - (end - 1).leading_zeros()) as u8
+}
+
+/// Guest physical range to dump, along with the file it gets written to.
+pub struct MemoryDump {
+    guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    file: File,
+    offset: u64,
+    end: u64,
+}
+
+impl MemoryDump {
+    /// Writes the content of the range to the file, byte `n` of the file
+    /// holding the byte at `offset + n`. The parts of the range not backed by
+    /// RAM are left as holes in the file. The guest is not paused, meaning the
+    /// memory can be modified while being dumped. The dump stops as soon as
+    /// `cancel` is raised.
+    pub fn write(self, cancel: &AtomicBool) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        use vm_memory::MemoryRegionAddress;
+
+        let mem = self.guest_memory.memory();
+        let mut chunk = vec![0u8; DUMP_MEMORY_CHUNK_SIZE];
+        for region in mem.iter() {
+            let region_start = region.start_addr().raw_value();
+            let range_end = self.end.min(region_start + region.len());
+            let mut addr = self.offset.max(region_start);
+            while addr < range_end {
+                if cancel.load(Ordering::SeqCst) {
+                    return Err(Error::MemoryDumpCancelled);
+                }
+                let len = (range_end - addr).min(chunk.len() as u64) as usize;
+                region
+                    .read_slice(&mut chunk[..len], MemoryRegionAddress(addr - region_start))
+                    .map_err(Error::ReadMemoryDump)?;
+                self.file
+                    .write_all_at(&chunk[..len], addr - self.offset)
+                    .map_err(Error::WriteMemoryDump)?;
+                addr += len as u64;
+            }
+        }
+
+        self.file.sync_all().map_err(Error::WriteMemoryDump)
+    }
 }
 
 pub struct Vm {
-    #[cfg(feature = "tdx")]
     kernel: Option<File>,
     initramfs: Option<File>,
     threads: Vec<thread::JoinHandle<()>>,
@@ -464,6 +816,25 @@ pub struct Vm {
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     stop_on_boot: bool,
     load_payload_handle: Option<thread::JoinHandle<Result<EntryPoint>>>,
+    rt: Option<RtConfig>,
+    payload_verification: Option<Vec<PayloadVerification>>,
+    // The vCPUs were stopped after a guest reset, preventing any resume
+    halted: bool,
+    boot_detector: Option<Arc<BootDetector>>,
+    // Restored with its devices left inactive, waiting for its identity
+    ready: bool,
+}
+
+// Reports a device added to the running VM.
+fn device_added_event(pci_device_info: &PciDeviceInfo) {
+    event!(
+        "vm",
+        "device-added",
+        "id",
+        &pci_device_info.id,
+        "bdf",
+        pci_device_info.bdf.to_string()
+    );
 }
 
 impl Vm {
@@ -496,15 +867,60 @@ impl Vm {
             .validate()
             .map_err(Error::ConfigValidation)?;
 
+        // The payload files are opened once, so that the content being
+        // verified is the one loaded into the guest.
+        let kernel = if snapshot.is_none() {
+            config
+                .lock()
+                .unwrap()
+                .payload
+                .as_ref()
+                .and_then(|p| p.kernel.as_ref())
+                .map(File::open)
+                .transpose()
+                .map_err(Error::KernelFile)?
+        } else {
+            None
+        };
+
+        let initramfs = config
+            .lock()
+            .unwrap()
+            .payload
+            .as_ref()
+            .map(|p| p.initramfs.as_ref().map(File::open))
+            .unwrap_or_default()
+            .transpose()
+            .map_err(Error::InitramfsFile)?;
+
+        // Signatures are checked before loading anything into the guest
+        let payload_verification = if snapshot.is_none() {
+            Self::verify_payload(&config.lock().unwrap(), kernel.as_ref(), initramfs.as_ref())?
+        } else {
+            None
+        };
+
         #[cfg(not(feature = "igvm"))]
         let load_payload_handle = if snapshot.is_none() {
-            Self::load_payload_async(&memory_manager, &config)?
+            Self::load_payload_async(&memory_manager, &config, kernel.as_ref())?
         } else {
             None
         };
 
         info!("Booting VM from config: {:?}", &config);
 
+        let memory_share_filter = get_seccomp_filter(
+            seccomp_action,
+            Thread::MemoryShare,
+            hypervisor.hypervisor_type(),
+        )
+        .map_err(Error::CreateSeccompFilter)?;
+        memory_manager
+            .lock()
+            .unwrap()
+            .start_memory_zone_exports(&memory_share_filter)
+            .map_err(Error::MemoryManager)?;
+
         // Create NUMA nodes based on NumaConfig.
         let numa_nodes =
             Self::create_numa_nodes(config.lock().unwrap().numa.clone(), &memory_manager)?;
@@ -530,14 +946,24 @@ impl Vm {
         let io_bus = Arc::new(Bus::new());
         let mmio_bus = Arc::new(Bus::new());
 
+        let device_access = config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|platform| platform.device_access_warn_us)
+            .map(|threshold_us| Arc::new(DeviceAccessMonitor::new(threshold_us)));
+
         let vm_ops: Arc<dyn VmOps> = Arc::new(VmOpsHandler {
             memory,
             #[cfg(target_arch = "x86_64")]
             io_bus: io_bus.clone(),
             mmio_bus: mmio_bus.clone(),
+            device_access: device_access.clone(),
         });
 
         let cpus_config = { &config.lock().unwrap().cpus.clone() };
+        let rt = Self::resolve_rt_config(&config.lock().unwrap())?;
         let cpu_manager = cpu::CpuManager::new(
             cpus_config,
             vm.clone(),
@@ -551,6 +977,7 @@ impl Vm {
             #[cfg(feature = "tdx")]
             tdx_enabled,
             &numa_nodes,
+            rt.as_ref(),
             #[cfg(feature = "sev_snp")]
             sev_snp_enabled,
         )
@@ -563,6 +990,7 @@ impl Vm {
             .populate_cpuid(
                 &memory_manager,
                 &hypervisor,
+                physical_bits(&hypervisor, &config.lock().unwrap()),
                 #[cfg(feature = "tdx")]
                 tdx_enabled,
             )
@@ -580,6 +1008,7 @@ impl Vm {
             Self::load_payload_async(
                 &memory_manager,
                 &config,
+                kernel.as_ref(),
                 &cpu_manager,
                 #[cfg(feature = "sev_snp")]
                 sev_snp_enabled,
@@ -650,26 +1079,9 @@ impl Vm {
             )
             .map_err(Error::DeviceManager)?;
 
-        #[cfg(feature = "tdx")]
-        let kernel = config
-            .lock()
-            .unwrap()
-            .payload
-            .as_ref()
-            .map(|p| p.kernel.as_ref().map(File::open))
-            .unwrap_or_default()
-            .transpose()
-            .map_err(Error::KernelFile)?;
-
-        let initramfs = config
-            .lock()
-            .unwrap()
-            .payload
-            .as_ref()
-            .map(|p| p.initramfs.as_ref().map(File::open))
-            .unwrap_or_default()
-            .transpose()
-            .map_err(Error::InitramfsFile)?;
+        if let Some(device_access) = &device_access {
+            device_access.set_device_tree(device_manager.lock().unwrap().device_tree());
+        }
 
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let saved_clock = if let Some(snapshot) = snapshot.as_ref() {
@@ -686,7 +1098,6 @@ impl Vm {
         };
 
         Ok(Vm {
-            #[cfg(feature = "tdx")]
             kernel,
             initramfs,
             device_manager,
@@ -702,9 +1113,141 @@ impl Vm {
             hypervisor,
             stop_on_boot,
             load_payload_handle,
+            rt,
+            payload_verification,
+            halted: false,
+            boot_detector: None,
+            ready: false,
         })
     }
 
+    #[cfg(feature = "payload_verification")]
+    fn verify_payload(
+        config: &VmConfig,
+        kernel: Option<&File>,
+        initramfs: Option<&File>,
+    ) -> Result<Option<Vec<PayloadVerification>>> {
+        let (Some(verification_config), Some(payload)) =
+            (&config.payload_verification, &config.payload)
+        else {
+            return Ok(None);
+        };
+
+        let verifications = crate::payload_verification::verify_payloads(
+            verification_config,
+            payload,
+            kernel,
+            initramfs,
+        )
+        .map_err(|e| {
+            event!("vm", "payload-verification-failed", "error", e.to_string());
+            Error::PayloadVerification(e)
+        })?;
+
+        for verification in &verifications {
+            info!(
+                "Verified {} signed by {:?} (sha256 {})",
+                verification.payload, verification.signer, verification.sha256
+            );
+            event!(
+                "vm",
+                "payload-verified",
+                "payload",
+                verification.payload.clone(),
+                "signer",
+                verification.signer.clone(),
+                "sha256",
+                verification.sha256.clone()
+            );
+        }
+
+        Ok(Some(verifications))
+    }
+
+    // Payload verification is rejected at validation time when not compiled in
+    #[cfg(not(feature = "payload_verification"))]
+    fn verify_payload(
+        _config: &VmConfig,
+        _kernel: Option<&File>,
+        _initramfs: Option<&File>,
+    ) -> Result<Option<Vec<PayloadVerification>>> {
+        Ok(None)
+    }
+
+    // Picks the host CPUs isolated through the kernel command line when the
+    // realtime profile doesn't provide the ones to pin the vCPUs onto.
+    fn resolve_rt_config(config: &VmConfig) -> Result<Option<RtConfig>> {
+        let Some(mut rt) = config.rt.clone() else {
+            return Ok(None);
+        };
+
+        let cpus = match rt.cpus.take() {
+            Some(cpus) => cpus,
+            None => realtime::isolated_cpus().map_err(Error::RealtimeProfile)?,
+        };
+        if cpus.len() < usize::from(config.cpus.max_vcpus) {
+            return Err(Error::RealtimeNotEnoughCpus(
+                cpus.len(),
+                config.cpus.max_vcpus,
+            ));
+        }
+        rt.cpus = Some(cpus);
+
+        Ok(Some(rt))
+    }
+
+    // Only a VM booted from scratch gets its boot detected, a restored one
+    // being already up.
+    fn setup_boot_detection(&mut self) -> Result<()> {
+        let config = self.config.lock().unwrap();
+        let Some(boot_detect) = &config.boot_detect else {
+            return Ok(());
+        };
+
+        let boot_detector = BootDetector::new(boot_detect, &config).map_err(Error::BootDetect)?;
+        if let Some(marker) = &boot_detect.console_marker {
+            let observer = boot_detector.console_observer(marker);
+            if !self
+                .device_manager
+                .lock()
+                .unwrap()
+                .set_serial_output_observer(observer)
+            {
+                warn!("No serial port to detect the boot marker on");
+            }
+        }
+        if boot_detect.acpi {
+            self.cpu_manager
+                .lock()
+                .unwrap()
+                .set_boot_detector(Arc::clone(&boot_detector));
+        }
+        drop(config);
+
+        self.boot_detector = Some(boot_detector);
+        Ok(())
+    }
+
+    // Keeps the host CPUs of the realtime profile for the vCPUs only, by
+    // moving every other VMM thread onto the housekeeping CPUs, and reports
+    // what may still interrupt the vCPUs.
+    fn apply_rt_profile(&self) -> Result<()> {
+        let Some(rt) = &self.rt else {
+            return Ok(());
+        };
+
+        let rt_cpus = rt.cpus.as_deref().unwrap_or_default();
+        let housekeeping_cpus =
+            realtime::housekeeping_cpus(rt_cpus).map_err(Error::RealtimeProfile)?;
+        realtime::move_threads(&housekeeping_cpus).map_err(Error::RealtimeProfile)?;
+
+        for source in realtime::jitter_sources(rt_cpus, &self.config.lock().unwrap()) {
+            warn!("Realtime profile: possible source of jitter: {}", source);
+        }
+
+        Ok(())
+    }
+
     fn create_numa_nodes(
         configs: Option<Vec<NumaConfig>>,
         memory_manager: &Arc<Mutex<MemoryManager>>,
@@ -804,8 +1347,9 @@ impl Vm {
         console_resize_pipe: Option<File>,
         original_termios: Arc<Mutex<Option<termios>>>,
         snapshot: Option<Snapshot>,
-        source_url: Option<&str>,
+        snapshot_source: Option<&mut SnapshotSource>,
         prefault: Option<bool>,
+        snapshot_key: Option<&SnapshotKey>,
     ) -> Result<Self> {
         trace_scoped!("Vm::new");
 
@@ -833,7 +1377,7 @@ impl Vm {
             sev_snp_enabled,
         )?;
 
-        let phys_bits = physical_bits(&hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
+        let phys_bits = physical_bits(&hypervisor, &vm_config.lock().unwrap());
 
         let memory_manager = if let Some(snapshot) =
             snapshot_from_id(snapshot.as_ref(), MEMORY_MANAGER_SNAPSHOT_ID)
@@ -842,9 +1386,10 @@ impl Vm {
                 &snapshot,
                 vm.clone(),
                 &vm_config.lock().unwrap().memory.clone(),
-                source_url,
+                snapshot_source,
                 prefault.unwrap(),
                 phys_bits,
+                snapshot_key,
             )
             .map_err(Error::MemoryManager)?
         } else {
@@ -1032,10 +1577,10 @@ impl Vm {
                 let entry_point = if cpu_manager.lock().unwrap().sev_snp_enabled() {
                     EntryPoint { entry_addr: vm_memory::GuestAddress(res.vmsa_gpa), setup_header: None }
                 } else {
-                    EntryPoint {entry_addr: vm_memory::GuestAddress(res.vmsa.rip), setup_header: None }
+                    EntryPoint {entry_addr: vm_memory::GuestAddress(res.bsp_rip), setup_header: None }
                 };
             } else {
-               let entry_point = EntryPoint { entry_addr: vm_memory::GuestAddress(res.vmsa.rip), setup_header: None };
+               let entry_point = EntryPoint { entry_addr: vm_memory::GuestAddress(res.bsp_rip), setup_header: None };
             }
         };
         Ok(entry_point)
@@ -1102,6 +1647,7 @@ impl Vm {
     #[cfg(target_arch = "x86_64")]
     fn load_payload(
         payload: &PayloadConfig,
+        kernel: Option<File>,
         memory_manager: Arc<Mutex<MemoryManager>>,
         #[cfg(feature = "igvm")] cpu_manager: Arc<Mutex<cpu::CpuManager>>,
         #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
@@ -1115,13 +1661,18 @@ impl Vm {
                 if sev_snp_enabled {
                     return Self::load_igvm(igvm, memory_manager, cpu_manager, &payload.host_data);
                 }
-                #[cfg(not(feature = "sev_snp"))]
-                return Self::load_igvm(igvm, memory_manager, cpu_manager);
+                return Self::load_igvm(
+                    igvm,
+                    memory_manager,
+                    cpu_manager,
+                    #[cfg(feature = "sev_snp")]
+                    &None,
+                );
             }
         }
         match (
             &payload.firmware,
-            &payload.kernel,
+            kernel,
             &payload.initramfs,
             &payload.cmdline,
         ) {
@@ -1130,7 +1681,6 @@ impl Vm {
                 Self::load_kernel(firmware, None, memory_manager)
             }
             (None, Some(kernel), _, _) => {
-                let kernel = File::open(kernel).map_err(Error::KernelFile)?;
                 let cmdline = Self::generate_cmdline(payload)?;
                 Self::load_kernel(kernel, Some(cmdline), memory_manager)
             }
@@ -1141,17 +1691,15 @@ impl Vm {
     #[cfg(target_arch = "aarch64")]
     fn load_payload(
         payload: &PayloadConfig,
+        kernel: Option<File>,
         memory_manager: Arc<Mutex<MemoryManager>>,
     ) -> Result<EntryPoint> {
-        match (&payload.firmware, &payload.kernel) {
+        match (&payload.firmware, kernel) {
             (Some(firmware), None) => {
                 let firmware = File::open(firmware).map_err(Error::FirmwareFile)?;
                 Self::load_kernel(Some(firmware), None, memory_manager)
             }
-            (None, Some(kernel)) => {
-                let kernel = File::open(kernel).map_err(Error::KernelFile)?;
-                Self::load_kernel(None, Some(kernel), memory_manager)
-            }
+            (None, Some(kernel)) => Self::load_kernel(None, Some(kernel), memory_manager),
             _ => Err(Error::InvalidPayload),
         }
     }
@@ -1159,6 +1707,7 @@ impl Vm {
     fn load_payload_async(
         memory_manager: &Arc<Mutex<MemoryManager>>,
         config: &Arc<Mutex<VmConfig>>,
+        kernel: Option<&File>,
         #[cfg(feature = "igvm")] cpu_manager: &Arc<Mutex<cpu::CpuManager>>,
         #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
     ) -> Result<Option<thread::JoinHandle<Result<EntryPoint>>>> {
@@ -1176,6 +1725,10 @@ impl Vm {
             .map(|payload| {
                 let memory_manager = memory_manager.clone();
                 let payload = payload.clone();
+                let kernel = kernel
+                    .map(File::try_clone)
+                    .transpose()
+                    .map_err(Error::KernelFile)?;
                 #[cfg(feature = "igvm")]
                 let cpu_manager = cpu_manager.clone();
 
@@ -1184,6 +1737,7 @@ impl Vm {
                     .spawn(move || {
                         Self::load_payload(
                             &payload,
+                            kernel,
                             memory_manager,
                             #[cfg(feature = "igvm")]
                             cpu_manager,
@@ -1299,6 +1853,10 @@ impl Vm {
                 pci_device_space_size: pci_segment.end_of_mem64_area
                     - pci_segment.start_of_mem64_area
                     + 1,
+                pci_device_space_start_32bit: pci_segment.start_of_mem32_area,
+                pci_device_space_size_32bit: pci_segment.end_of_mem32_area
+                    - pci_segment.start_of_mem32_area
+                    + 1,
             };
             pci_space_info.push(pci_space);
         }
@@ -1338,6 +1896,18 @@ impl Vm {
                 ))
             })?;
 
+        let dt_overlays = self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|platform| platform.dt_overlay.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|path| std::fs::read(&path).map_err(|e| Error::DtOverlayFile(path, e)))
+            .collect::<Result<Vec<_>>>()?;
+
         arch::configure_system(
             &mem,
             cmdline.as_cstring().unwrap().to_str().unwrap(),
@@ -1350,6 +1920,7 @@ impl Vm {
             &vgic,
             &self.numa_nodes,
             pmu_supported,
+            &dt_overlays,
         )
         .map_err(Error::ConfigureSystem)?;
 
@@ -1400,6 +1971,34 @@ impl Vm {
         Ok(())
     }
 
+    /// Stop the vCPUs after a guest reset, leaving the VM paused with its
+    /// memory and devices untouched so that it can be inspected. Contrary to
+    /// a regular pause, the guest can't be resumed, only shut down or
+    /// rebooted.
+    pub fn halt(&mut self) -> Result<()> {
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        let new_state = VmState::Paused;
+
+        state.valid_transition(new_state)?;
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .shutdown()
+            .map_err(Error::CpuManager)?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .pause()
+            .map_err(Error::Pause)?;
+
+        self.halted = true;
+        *state = new_state;
+
+        event!("vm", "halted");
+        Ok(())
+    }
+
     pub fn resize(
         &mut self,
         desired_vcpus: Option<u8>,
@@ -1545,6 +2144,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1569,6 +2170,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1591,6 +2194,31 @@ impl Vm {
         Ok(())
     }
 
+    pub fn replace_device(
+        &mut self,
+        id: String,
+        mut replacement: ReplacementDeviceConfig,
+    ) -> Result<()> {
+        // The replacement is only plugged once the guest has ejected the
+        // device, see DeviceManager::eject_device().
+        self.device_manager
+            .lock()
+            .unwrap()
+            .replace_device(id.clone(), &mut replacement)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by swapping the devices. This is important to
+        // ensure the replacement would be created in case of a reboot.
+        self.config.lock().unwrap().replace_device(&id, replacement);
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+        Ok(())
+    }
+
     pub fn add_disk(&mut self, mut disk_cfg: DiskConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
@@ -1612,6 +2240,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1636,6 +2266,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1660,6 +2292,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1684,6 +2318,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1708,6 +2344,8 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
@@ -1732,16 +2370,163 @@ impl Vm {
             .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
             .map_err(Error::DeviceManager)?;
 
+        device_added_event(&pci_device_info);
+
         Ok(pci_device_info)
     }
 
-    pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
-    }
+    pub fn add_usb(&mut self, mut usb_cfg: UsbConfig) -> Result<PciDeviceInfo> {
+        let pci_device_info = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .add_usb(&mut usb_cfg)
+            .map_err(Error::DeviceManager)?;
 
-    #[cfg(feature = "tdx")]
-    fn extract_tdvf_sections(&mut self) -> Result<(Vec<TdvfSection>, bool)> {
-        use arch::x86_64::tdx::*;
+        // Update VmConfig by adding the new device. This is important to
+        // ensure the device would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            add_to_config(&mut config.usb, usb_cfg);
+        }
+
+        // The guest learns about the new device from the xHCI controller
+        // port it is plugged into, the PCI topology is unchanged.
+        device_added_event(&pci_device_info);
+
+        Ok(pci_device_info)
+    }
+
+    pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
+        Ok(self.device_manager.lock().unwrap().counters())
+    }
+
+    /// List the devices and features preventing the VM from being live
+    /// migrated or snapshotted.
+    pub fn migration_blockers(&self) -> MigrationBlockers {
+        let mut blockers = self.device_manager.lock().unwrap().migration_blockers();
+
+        let mut features: Vec<(&str, &str)> = Vec::new();
+        let shared_zones = self
+            .config
+            .lock()
+            .unwrap()
+            .memory
+            .zones
+            .iter()
+            .flatten()
+            .any(|zone| zone.shared_with.is_some() || zone.shared_from.is_some());
+        if shared_zones {
+            features.push((
+                "memory_zone_sharing",
+                "Memory zones shared between VMMs can't be restored",
+            ));
+        }
+        if self.ready {
+            features.push(("ready", "The ready VM must be booted first"));
+        }
+        #[cfg(feature = "tdx")]
+        if self.config.lock().unwrap().is_tdx_enabled() {
+            features.push(("tdx", "TDX guest state can't be saved"));
+        }
+        #[cfg(feature = "sev_snp")]
+        if self.config.lock().unwrap().is_sev_snp_enabled() {
+            features.push(("sev_snp", "SEV-SNP guest memory is encrypted"));
+        }
+        #[cfg(target_arch = "x86_64")]
+        if self.config.lock().unwrap().sgx_epc.is_some() {
+            features.push(("sgx_epc", "SGX enclave page cache can't be saved"));
+        }
+        for (id, reason) in features {
+            blockers.migration.push(MigrationBlocker::new(id, reason));
+            blockers.snapshot.push(MigrationBlocker::new(id, reason));
+        }
+
+        blockers.migration.sort_by(|a, b| a.id.cmp(&b.id));
+        blockers.snapshot.sort_by(|a, b| a.id.cmp(&b.id));
+        blockers
+    }
+
+    pub fn capabilities(&self) -> VmCapabilities {
+        let cpus = self.cpu_manager.lock().unwrap().capabilities();
+        let memory = self.memory_manager.lock().unwrap().capabilities();
+        let pci_segments = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .pci_segments_capabilities();
+
+        let mut features = Vec::new();
+        if cpus.hotpluggable_vcpus > 0 || cpus.present_vcpus > cpus.boot_vcpus {
+            features.push("cpu_hotplug".to_string());
+        }
+        if memory.hotpluggable_size > 0 {
+            features.push("memory_hotplug".to_string());
+        }
+        if !memory.zones.is_empty() {
+            features.push("virtio_mem".to_string());
+        }
+        if self.config.lock().unwrap().balloon.is_some() {
+            features.push("balloon".to_string());
+        }
+        #[cfg(target_arch = "aarch64")]
+        if self
+            .device_manager
+            .lock()
+            .unwrap()
+            .get_interrupt_controller()
+            .and_then(|gic| gic.lock().unwrap().get_vgic().ok())
+            .is_some_and(|vgic| vgic.lock().unwrap().direct_injection())
+        {
+            features.push("gicv4_direct_injection".to_string());
+        }
+
+        VmCapabilities {
+            cpus,
+            memory,
+            pci_segments,
+            features,
+        }
+    }
+
+    /// Why the exit or reset event was signalled, when a vCPU or a device
+    /// recorded it. Reading it clears it.
+    pub fn take_shutdown_reason(&self) -> Option<ShutdownReason> {
+        let cpu_reason = self.cpu_manager.lock().unwrap().take_shutdown_reason();
+        let device_reason = self.device_manager.lock().unwrap().take_shutdown_reason();
+        cpu_reason.or(device_reason)
+    }
+
+    /// Whether the guest is booted, `None` if its boot isn't being detected.
+    pub fn boot_state(&self) -> Option<BootState> {
+        self.boot_detector.as_ref().map(|detector| detector.state())
+    }
+
+    /// Socket the guest agent connects to once booted, to be polled by the
+    /// VMM.
+    pub fn boot_listener_fd(&self) -> Option<RawFd> {
+        self.boot_detector
+            .as_ref()
+            .and_then(|detector| detector.listener_fd())
+    }
+
+    pub fn accept_boot_connection(&self) {
+        if let Some(detector) = &self.boot_detector {
+            detector.accept();
+        }
+    }
+
+    pub fn vcpus_stats(&self) -> Result<Vec<cpu::VcpuStats>> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .vcpus_stats()
+            .map_err(Error::CpuManager)
+    }
+
+    #[cfg(feature = "tdx")]
+    fn extract_tdvf_sections(&mut self) -> Result<(Vec<TdvfSection>, bool)> {
+        use arch::x86_64::tdx::*;
 
         let firmware_path = self
             .config
@@ -1960,12 +2745,22 @@ impl Vm {
                 .map_err(Error::PopulateHob)?;
         }
 
-        // MMIO regions
+        // MMIO regions, the 32-bit one starting with the MMIO aperture of
+        // the first PCI segment, which may have been grown below the default
+        // one.
+        let start_of_mmio32_area = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .pci_segments()
+            .iter()
+            .map(|pci_segment| pci_segment.start_of_mem32_area)
+            .min()
+            .unwrap_or(arch::layout::MEM_32BIT_DEVICES_START.raw_value());
         hob.add_mmio_resource(
             &mem,
-            arch::layout::MEM_32BIT_DEVICES_START.raw_value(),
-            arch::layout::APIC_START.raw_value()
-                - arch::layout::MEM_32BIT_DEVICES_START.raw_value(),
+            start_of_mmio32_area,
+            arch::layout::APIC_START.raw_value() - start_of_mmio32_area,
         )
         .map_err(Error::PopulateHob)?;
         let start_of_device_area = self
@@ -2144,6 +2939,8 @@ impl Vm {
             })
             .transpose()?;
 
+        self.measure_payload()?;
+
         #[cfg(target_arch = "x86_64")]
         // Note: For x86, always call this function before invoking start boot vcpus.
         // Otherwise guest would fail to boot because we haven't created the
@@ -2173,6 +2970,10 @@ impl Vm {
             self.vm.tdx_finalize().map_err(Error::FinalizeTdx)?;
         }
 
+        self.apply_rt_profile()?;
+
+        self.setup_boot_detection()?;
+
         self.cpu_manager
             .lock()
             .unwrap()
@@ -2184,9 +2985,90 @@ impl Vm {
         Ok(())
     }
 
+    // Firmware measures the components it loads into the TPM, but it is
+    // bypassed by a direct kernel boot, hence the VMM measuring the payload
+    // itself for the guest to be attested.
+    fn measure_payload(&self) -> Result<()> {
+        let payload = match &self.config.lock().unwrap().payload {
+            Some(payload) if payload.kernel.is_some() && payload.firmware.is_none() => {
+                payload.clone()
+            }
+            _ => return Ok(()),
+        };
+        let Some(tpm) = self.device_manager.lock().unwrap().tpm_device().cloned() else {
+            return Ok(());
+        };
+
+        let mut measurements = Vec::new();
+
+        // The payload is hashed through the files it was loaded from, so that
+        // the measurements match the content of the guest.
+        if let Some(mut kernel) = self.kernel.as_ref() {
+            let mut hasher = Sha256::new();
+            kernel.rewind().map_err(Error::PayloadMeasurementRead)?;
+            io::copy(&mut kernel, &mut hasher).map_err(Error::PayloadMeasurementRead)?;
+            kernel.rewind().map_err(Error::PayloadMeasurementRead)?;
+            measurements.push(("kernel", PCR_KERNEL, hasher.finalize()));
+        }
+
+        let cmdline = Self::generate_cmdline(
+            &payload,
+            #[cfg(target_arch = "aarch64")]
+            &self.device_manager,
+        )?
+        .as_cstring()
+        .map_err(Error::CmdLineCreate)?;
+        measurements.push(("cmdline", PCR_CMDLINE, Sha256::digest(cmdline.as_bytes())));
+
+        if let Some(mut initramfs) = self.initramfs.as_ref() {
+            let mut hasher = Sha256::new();
+            initramfs.rewind().map_err(Error::PayloadMeasurementRead)?;
+            io::copy(&mut initramfs, &mut hasher).map_err(Error::PayloadMeasurementRead)?;
+            initramfs.rewind().map_err(Error::PayloadMeasurementRead)?;
+            measurements.push(("initramfs", PCR_INITRAMFS, hasher.finalize()));
+        }
+
+        let mut tpm = tpm.lock().unwrap();
+        tpm.startup().map_err(Error::PayloadMeasurement)?;
+        for (name, pcr, digest) in measurements {
+            tpm.extend_pcr(pcr, &digest.into())
+                .map_err(Error::PayloadMeasurement)?;
+
+            let sha256: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            info!("Measured {} into PCR {}: sha256 {}", name, pcr, sha256);
+            event!(
+                "vm",
+                "payload-measured",
+                "payload",
+                name,
+                "pcr",
+                pcr.to_string(),
+                "sha256",
+                sha256
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn restore(&mut self) -> Result<()> {
         event!("vm", "restoring");
 
+        self.activate_virtio_devices()?;
+        self.start_restored()
+    }
+
+    /// Restores the VM as a ready VM: everything is restored up to resuming
+    /// the vCPUs, apart from activating the devices, for the identity of the
+    /// instance to be set on [`Vm::boot_ready`].
+    pub fn restore_ready(&mut self) -> Result<()> {
+        event!("vm", "restoring");
+
+        self.ready = true;
+        self.start_restored()
+    }
+
+    fn start_restored(&mut self) -> Result<()> {
         #[cfg(target_arch = "x86_64")]
         // Note: For x86, always call this function before invoking start boot vcpus.
         // Otherwise guest would fail to boot because we haven't created the
@@ -2199,6 +3081,8 @@ impl Vm {
             .allocate_address_space()
             .map_err(Error::MemoryManager)?;
 
+        self.apply_rt_profile()?;
+
         // Now we can start all vCPUs from here.
         self.cpu_manager
             .lock()
@@ -2207,6 +3091,93 @@ impl Vm {
             .map_err(Error::CpuManager)?;
 
         event!("vm", "restored");
+        crate::seccomp_violations::enforce();
+        Ok(())
+    }
+
+    /// Whether the VM is a ready VM waiting to be booted with its identity.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Boots a ready VM, giving it the identity of the instance before
+    /// activating its devices and resuming it.
+    pub fn boot_ready(&mut self, identity: &VmIdentityConfig) -> Result<()> {
+        if !self.ready {
+            return Err(Error::VmNotReady);
+        }
+
+        self.set_identity(identity)?;
+        self.activate_virtio_devices()?;
+        self.ready = false;
+        self.resume().map_err(Error::Resume)
+    }
+
+    fn set_identity(&mut self, identity: &VmIdentityConfig) -> Result<()> {
+        // The identity is checked against a copy of the configuration first,
+        // for the configuration to be left untouched if it can't be set.
+        let mut config = self.config.lock().unwrap().clone();
+        config
+            .set_identity(identity)
+            .map_err(Error::ConfigValidation)?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_identity(identity, &config)
+            .map_err(Error::DeviceManager)?;
+        self.config
+            .lock()
+            .unwrap()
+            .set_identity(identity)
+            .map_err(Error::ConfigValidation)
+    }
+
+    /// Gives a paused VM, restored from the snapshot of another VM, an
+    /// identity of its own before it's resumed. The SMBIOS UUID gets a random
+    /// one when the VM has one, and the VM generation ID is regenerated. The
+    /// MAC addresses are left alone, the guest having no way of being told to
+    /// read them again.
+    pub fn rekey(&mut self, vsock_cid: Option<u32>, uuid: Option<String>) -> Result<()> {
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+
+        let uuid = match uuid {
+            Some(uuid) => Some(
+                uuid::Uuid::parse_str(&uuid)
+                    .map_err(Error::InvalidUuid)?
+                    .to_string(),
+            ),
+            None => self
+                .config
+                .lock()
+                .unwrap()
+                .platform
+                .as_ref()
+                .and_then(|p| p.uuid.as_ref())
+                .map(|_| uuid::Uuid::new_v4().to_string()),
+        };
+
+        self.set_identity(&VmIdentityConfig {
+            net: Vec::new(),
+            disks: Vec::new(),
+            vsock_cid,
+        })?;
+
+        // The guest only reads the SMBIOS tables when it boots, those of the
+        // next reboot being generated from the configuration.
+        if let Some(uuid) = uuid {
+            let mut config = self.config.lock().unwrap();
+            config.platform.get_or_insert_with(Default::default).uuid = Some(uuid);
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .regenerate_vmgenid()
+            .map_err(Error::DeviceManager)?;
+
+        event!("vm", "rekeyed");
         Ok(())
     }
 
@@ -2223,11 +3194,36 @@ impl Vm {
             .map(|state| *state)
     }
 
+    /// Applies changes of the configuration to the running devices.
+    pub fn update_config(&self, changes: &[LiveConfigChange]) -> Result<()> {
+        let device_manager = self.device_manager.lock().unwrap();
+        for change in changes {
+            info!("Applying configuration change: {:?}", change);
+            device_manager
+                .update_config(change)
+                .map_err(Error::DeviceManager)?;
+        }
+
+        Ok(())
+    }
+
     /// Gets the actual size of the balloon.
     pub fn balloon_size(&self) -> u64 {
         self.device_manager.lock().unwrap().balloon_size()
     }
 
+    pub fn payload_verification(&self) -> Option<Vec<PayloadVerification>> {
+        self.payload_verification.clone()
+    }
+
+    pub fn memory_backing(&self) -> Vec<MemoryZoneBacking> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .memory_backing()
+            .to_vec()
+    }
+
     pub fn send_memory_fds(
         &mut self,
         socket: &mut UnixStream,
@@ -2317,6 +3313,23 @@ impl Vm {
         self.device_manager.lock().unwrap().device_tree()
     }
 
+    pub fn device_tree_info(&self) -> VmDeviceTree {
+        let device_tree = self.device_tree();
+        let device_tree = device_tree.lock().unwrap();
+        VmDeviceTree {
+            pci_devices: device_tree.pci_device_addresses(),
+            devices: device_tree.clone(),
+        }
+    }
+
+    pub fn removable_devices(&self) -> Vec<RemovableDevice> {
+        self.device_manager.lock().unwrap().removable_devices()
+    }
+
+    pub fn net_tap_if_name(&self, id: &str) -> Option<String> {
+        self.device_manager.lock().unwrap().net_tap_if_name(id)
+    }
+
     pub fn activate_virtio_devices(&self) -> Result<()> {
         self.device_manager
             .lock()
@@ -2344,6 +3357,14 @@ impl Vm {
             .map_err(Error::PowerButton)
     }
 
+    pub fn suspend_to_ram(&self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_sleep_button()
+            .map_err(Error::SleepButton)
+    }
+
     pub fn memory_manager_data(&self) -> MemoryManagerSnapshotData {
         self.memory_manager.lock().unwrap().snapshot_data()
     }
@@ -2453,6 +3474,130 @@ impl Vm {
             .nmi()
             .map_err(|_| Error::ErrorNmi);
     }
+
+    pub fn sysrq(&self, key: u8) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .send_sysrq(key)
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Writes the content of the ramfb framebuffer as a PNG image.
+    pub fn screenshot(&self, destination_url: &str) -> Result<()> {
+        let path = destination_url
+            .strip_prefix("file://")
+            .ok_or_else(|| Error::InvalidScreenshotUrl(destination_url.to_string()))?;
+
+        let png = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .ramfb_screenshot()
+            .map_err(Error::DeviceManager)?;
+
+        std::fs::write(path, png).map_err(Error::WriteScreenshot)
+    }
+
+    /// Checks the `[offset, offset + length)` guest physical range ends
+    /// within the guest RAM and creates the file it gets dumped to, returning
+    /// the dump, written without involving the VM.
+    pub fn memory_dump(
+        &self,
+        destination_url: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<MemoryDump> {
+        let path = destination_url
+            .strip_prefix("file://")
+            .ok_or_else(|| Error::InvalidMemoryDumpUrl(destination_url.to_string()))?;
+
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let last_addr = guest_memory.memory().last_addr().raw_value();
+        let end = offset
+            .checked_add(length)
+            .filter(|end| length != 0 && end - 1 <= last_addr)
+            .ok_or(Error::InvalidMemoryDumpRange(offset, length))?;
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(Error::WriteMemoryDump)?;
+        file.set_len(length).map_err(Error::WriteMemoryDump)?;
+
+        Ok(MemoryDump {
+            guest_memory,
+            file,
+            offset,
+            end,
+        })
+    }
+
+    /// Returns the guest physical addresses where `pattern` is found within
+    /// the `[gpa, gpa + size)` range, and whether the list was truncated to
+    /// `INTROSPECT_MAX_MATCHES` addresses. The guest is not paused, meaning
+    /// the memory can be modified while being scanned.
+    #[cfg(feature = "introspection")]
+    pub fn introspect(&self, gpa: u64, size: u64, pattern: &[u8]) -> Result<(Vec<u64>, bool)> {
+        use vm_memory::{Address, GuestMemoryRegion, MemoryRegionAddress};
+
+        if pattern.is_empty() {
+            return Err(Error::Introspect(anyhow!("Empty pattern")));
+        }
+        if pattern.len() > INTROSPECT_MAX_PATTERN_SIZE {
+            return Err(Error::Introspect(anyhow!(
+                "Pattern longer than {} bytes",
+                INTROSPECT_MAX_PATTERN_SIZE
+            )));
+        }
+
+        match self.get_state().unwrap() {
+            VmState::Running | VmState::Paused => {}
+            _ => {
+                return Err(Error::Introspect(anyhow!(
+                    "Trying to introspect while VM is not running or paused"
+                )));
+            }
+        }
+
+        let end = gpa.checked_add(size).ok_or_else(|| {
+            Error::Introspect(anyhow!("Invalid range 0x{:x} + 0x{:x}", gpa, size))
+        })?;
+
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mem = guest_memory.memory();
+
+        // Consecutive chunks overlap so that a pattern crossing a chunk
+        // boundary is not missed.
+        let mut chunk = vec![0u8; INTROSPECT_CHUNK_SIZE + pattern.len() - 1];
+        let mut matches = Vec::new();
+        for region in mem.iter() {
+            let region_start = region.start_addr().raw_value();
+            let range_end = end.min(region_start + region.len());
+            let mut addr = gpa.max(region_start);
+            while range_end.saturating_sub(addr) >= pattern.len() as u64 {
+                let len = (range_end - addr).min(chunk.len() as u64) as usize;
+                region
+                    .read_slice(&mut chunk[..len], MemoryRegionAddress(addr - region_start))
+                    .map_err(|e| Error::Introspect(e.into()))?;
+
+                for (offset, window) in chunk[..len].windows(pattern.len()).enumerate() {
+                    if window == pattern {
+                        if matches.len() == INTROSPECT_MAX_MATCHES {
+                            return Ok((matches, true));
+                        }
+                        matches.push(addr + offset as u64);
+                    }
+                }
+
+                addr += (len - pattern.len() + 1) as u64;
+            }
+        }
+
+        Ok((matches, false))
+    }
 }
 
 impl Pausable for Vm {
@@ -2505,6 +3650,12 @@ impl Pausable for Vm {
             .valid_transition(new_state)
             .map_err(|e| MigratableError::Resume(anyhow!("Invalid transition: {:?}", e)))?;
 
+        if self.halted {
+            return Err(MigratableError::Resume(anyhow!(
+                "The vCPUs were stopped after a guest reset"
+            )));
+        }
+
         self.cpu_manager.lock().unwrap().resume()?;
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         {
@@ -2559,10 +3710,7 @@ impl Snapshottable for Vm {
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let common_cpuid = {
             let amx = self.config.lock().unwrap().cpus.features.amx;
-            let phys_bits = physical_bits(
-                &self.hypervisor,
-                self.config.lock().unwrap().cpus.max_phys_bits,
-            );
+            let phys_bits = physical_bits(&self.hypervisor, &self.config.lock().unwrap());
             arch::generate_common_cpuid(
                 &self.hypervisor,
                 &arch::CpuidConfig {
@@ -2572,6 +3720,7 @@ impl Snapshottable for Vm {
                     #[cfg(feature = "tdx")]
                     tdx: false,
                     amx,
+                    evmcs: self.config.lock().unwrap().cpus.features.evmcs,
                 },
             )
             .map_err(|e| {
@@ -2609,64 +3758,134 @@ impl Snapshottable for Vm {
     }
 }
 
-impl Transportable for Vm {
-    fn send(
+impl Vm {
+    /// Writes the snapshot to the directory `destination_url`, encrypted and
+    /// along with a signed manifest when `key` is given, the guest memory
+    /// being compressed when `compress` is set.
+    pub fn send_snapshot(
         &self,
         snapshot: &Snapshot,
         destination_url: &str,
+        key: Option<&SnapshotKey>,
+        compress: bool,
     ) -> std::result::Result<(), MigratableError> {
-        let mut snapshot_config_path = url_to_path(destination_url)?;
-        snapshot_config_path.push(SNAPSHOT_CONFIG_FILE);
+        let write_file = |name: &str, data: &[u8]| -> std::result::Result<(), MigratableError> {
+            let mut path = url_to_path(destination_url)?;
+            path.push(name);
 
-        // Create the snapshot config file
-        let mut snapshot_config_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(snapshot_config_path)
-            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-        // Serialize and write the snapshot config
-        let vm_config = serde_json::to_string(self.config.lock().unwrap().deref())
-            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            if let Some(key) = key {
+                return snapshot_encryption::write_file(&path, data, key)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()));
+            }
 
-        snapshot_config_file
-            .write(vm_config.as_bytes())
-            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            // Create the snapshot file
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-        let mut snapshot_state_path = url_to_path(destination_url)?;
-        snapshot_state_path.push(SNAPSHOT_STATE_FILE);
+            file.write_all(data)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))
+        };
 
-        // Create the snapshot state file
-        let mut snapshot_state_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create_new(true)
-            .open(snapshot_state_path)
+        // Serialize and write the snapshot config
+        let vm_config = serde_json::to_string(self.config.lock().unwrap().deref())
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        write_file(SNAPSHOT_CONFIG_FILE, vm_config.as_bytes())?;
 
         // Serialize and write the snapshot state
         let vm_state =
             serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
-
-        snapshot_state_file
-            .write(&vm_state)
-            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        write_file(SNAPSHOT_STATE_FILE, &vm_state)?;
 
         // Tell the memory manager to also send/write its own snapshot.
         if let Some(memory_manager_snapshot) = snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID) {
-            self.memory_manager
-                .lock()
-                .unwrap()
-                .send(&memory_manager_snapshot.clone(), destination_url)?;
+            self.memory_manager.lock().unwrap().send_snapshot(
+                &memory_manager_snapshot.clone(),
+                destination_url,
+                key,
+                compress,
+            )?;
         } else {
             return Err(MigratableError::Restore(anyhow!(
                 "Missing memory manager snapshot"
             )));
         }
 
+        if let Some(key) = key {
+            snapshot_encryption::write_manifest(
+                &url_to_path(destination_url)?,
+                &SNAPSHOT_FILES,
+                key,
+            )
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        }
+
         Ok(())
     }
+
+    /// Writes the snapshot files one after the other into `stream`, the
+    /// guest memory being read straight out of the guest RAM.
+    pub fn stream_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        mut stream: SnapshotWriter,
+    ) -> std::result::Result<(), MigratableError> {
+        if !snapshot.snapshots.contains_key(MEMORY_MANAGER_SNAPSHOT_ID) {
+            return Err(MigratableError::Restore(anyhow!(
+                "Missing memory manager snapshot"
+            )));
+        }
+
+        let vm_config = serde_json::to_vec(self.config.lock().unwrap().deref())
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        let vm_state =
+            serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        let memory_manager = self.memory_manager.lock().unwrap();
+
+        let mut files = vec![
+            (SNAPSHOT_CONFIG_FILE, vm_config.len() as u64),
+            (SNAPSHOT_STATE_FILE, vm_state.len() as u64),
+        ];
+        let memory_len = memory_manager.snapshot_memory_len();
+        if let Some(memory_len) = memory_len {
+            files.push((SNAPSHOT_FILENAME, memory_len));
+        }
+
+        let send_error = |e: snapshot_stream::Error| MigratableError::MigrateSend(e.into());
+        stream.start(&files).map_err(send_error)?;
+        for (name, data) in [
+            (SNAPSHOT_CONFIG_FILE, &vm_config),
+            (SNAPSHOT_STATE_FILE, &vm_state),
+        ] {
+            stream
+                .file(name)
+                .map_err(send_error)?
+                .write_all(data)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        }
+        if memory_len.is_some() {
+            memory_manager.write_snapshot_memory(
+                stream.file(SNAPSHOT_FILENAME).map_err(send_error)?,
+                None,
+                false,
+            )?;
+        }
+
+        stream.finish().map_err(send_error)
+    }
+}
+
+impl Transportable for Vm {
+    fn send(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+    ) -> std::result::Result<(), MigratableError> {
+        self.send_snapshot(snapshot, destination_url, None, false)
+    }
 }
 
 impl Migratable for Vm {
@@ -2917,6 +4136,33 @@ mod tests {
         test_vm_state_transitions(VmState::Paused);
     }
 
+    #[test]
+    fn test_shutdown_reason_exit_code() {
+        // Expected shutdowns exit successfully, guest crashes do not, and
+        // none of them can be mistaken for the VMM failing by itself.
+        for reason in [
+            ShutdownReason::GuestPoweroff,
+            ShutdownReason::GuestReset,
+            ShutdownReason::Api,
+            ShutdownReason::Signal,
+            ShutdownReason::Migration,
+        ] {
+            assert_eq!(reason.exit_code(), 0);
+        }
+        assert_eq!(ShutdownReason::TripleFault.exit_code(), 2);
+        assert_eq!(ShutdownReason::Watchdog.exit_code(), 3);
+        assert_eq!(ShutdownReason::Error.exit_code(), 4);
+        assert_eq!(ShutdownReason::GuestPanic.exit_code(), 5);
+        assert!(ShutdownReason::GuestPanic.is_failure());
+        assert!(!ShutdownReason::GuestPoweroff.is_failure());
+
+        assert_eq!(ShutdownReason::GuestPoweroff.to_string(), "guest-poweroff");
+        assert_eq!(
+            serde_json::to_string(&ShutdownReason::TripleFault).unwrap(),
+            "\"triple-fault\""
+        );
+    }
+
     #[cfg(feature = "tdx")]
     #[test]
     fn test_hob_memory_resources() {
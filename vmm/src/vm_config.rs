@@ -2,9 +2,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
-use net_util::MacAddr;
+use net_util::{EgressShaping, MacAddr};
 use serde::{Deserialize, Serialize};
-use std::{net::Ipv4Addr, path::PathBuf};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+};
 use virtio_devices::RateLimiterConfig;
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -18,6 +21,9 @@ pub struct CpuFeatures {
     #[cfg(target_arch = "x86_64")]
     #[serde(default)]
     pub amx: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub evmcs: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -51,6 +57,10 @@ pub struct CpusConfig {
     pub affinity: Option<Vec<CpuAffinity>>,
     #[serde(default)]
     pub features: CpuFeatures,
+    #[serde(default)]
+    pub sve_vl: Option<u16>,
+    #[serde(default)]
+    pub pmu_events: Option<Vec<u16>>,
 }
 
 pub const DEFAULT_VCPUS: u8 = 1;
@@ -65,6 +75,8 @@ impl Default for CpusConfig {
             max_phys_bits: DEFAULT_MAX_PHYS_BITS,
             affinity: None,
             features: CpuFeatures::default(),
+            sve_vl: None,
+            pmu_events: None,
         }
     }
 }
@@ -92,6 +104,95 @@ pub struct PlatformConfig {
     #[cfg(feature = "sev_snp")]
     #[serde(default)]
     pub sev_snp: bool,
+    #[serde(default)]
+    pub device_numa_policy: DeviceNumaPolicy,
+    #[serde(default)]
+    pub device_access_warn_us: Option<u64>,
+    #[serde(default)]
+    pub legacy_devices: Option<Vec<LegacyDevice>>,
+    #[serde(default)]
+    pub on_reset: ResetPolicy,
+    #[serde(default)]
+    pub dt_overlay: Option<Vec<PathBuf>>,
+    /// Size of the 32-bit MMIO aperture shared by the PCI segments, growing
+    /// downwards from the end of the default one, over the addresses below
+    /// 4GiB the RAM leaves unused
+    #[serde(default)]
+    pub mmio32_size: Option<u64>,
+    /// Size of the 64-bit MMIO aperture shared by the PCI segments, placed
+    /// after the RAM. It spans the rest of the guest physical address space
+    /// by default, and widens it beyond `max_phys_bits` when given.
+    #[serde(default)]
+    pub mmio64_size: Option<u64>,
+    /// Expose a VM generation ID device, whose identifier changes when the
+    /// VM is rekeyed after being cloned
+    #[serde(default)]
+    pub vmgenid: bool,
+}
+
+impl Default for PlatformConfig {
+    fn default() -> Self {
+        PlatformConfig {
+            num_pci_segments: DEFAULT_NUM_PCI_SEGMENTS,
+            iommu_segments: None,
+            serial_number: None,
+            uuid: None,
+            oem_strings: None,
+            #[cfg(feature = "tdx")]
+            tdx: false,
+            #[cfg(feature = "sev_snp")]
+            sev_snp: false,
+            device_numa_policy: DeviceNumaPolicy::default(),
+            device_access_warn_us: None,
+            legacy_devices: None,
+            on_reset: ResetPolicy::default(),
+            dt_overlay: None,
+            mmio32_size: None,
+            mmio64_size: None,
+            vmgenid: false,
+        }
+    }
+}
+
+/// How the VMM responds to a reset requested by the guest, or caused by a
+/// triple fault or the watchdog expiring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResetPolicy {
+    /// Reboot the VM within the VMM
+    #[default]
+    Restart,
+    /// Shut the VM down and terminate the VMM
+    Shutdown,
+    /// Stop the vCPUs and leave the VM paused, for inspection
+    Pause,
+    /// Shut the VM down, leaving it to the management layer to boot it again
+    Event,
+}
+
+/// Legacy platform devices which can be individually left out of the guest,
+/// all of them being exposed when none is explicitly selected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LegacyDevice {
+    /// i8042 controller, only used for guest initiated resets
+    I8042,
+    /// Serial COM1 port
+    Serial,
+    /// CMOS RTC
+    Rtc,
+}
+
+/// Memory policy applied when activating a device, so that its queues and
+/// buffers are allocated on the host NUMA node backing its PCI segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceNumaPolicy {
+    #[default]
+    Off,
+    /// Allocate on the host NUMA node if possible, fall back on other nodes
+    Preferred,
+    /// Only allocate on the host NUMA node
+    Bind,
 }
 
 pub const DEFAULT_PCI_SEGMENT_APERTURE_WEIGHT: u32 = 1;
@@ -123,6 +224,8 @@ pub struct MemoryZoneConfig {
     #[serde(default)]
     pub hugepage_size: Option<u64>,
     #[serde(default)]
+    pub hugepages_fallback: bool,
+    #[serde(default)]
     pub host_numa_node: Option<u32>,
     #[serde(default)]
     pub hotplug_size: Option<u64>,
@@ -130,6 +233,12 @@ pub struct MemoryZoneConfig {
     pub hotplugged_size: Option<u64>,
     #[serde(default)]
     pub prefault: bool,
+    /// Socket through which the zone is shared read-only with another VMM
+    #[serde(default)]
+    pub shared_with: Option<PathBuf>,
+    /// Socket of the VMM sharing the zone, mapped instead of allocated
+    #[serde(default)]
+    pub shared_from: Option<PathBuf>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -139,6 +248,15 @@ pub enum HotplugMethod {
     VirtioMem,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub enum MemoryLockPolicy {
+    /// Fail if any part of the guest RAM can't be locked
+    #[default]
+    Strict,
+    /// Keep going with whatever part of the guest RAM could be locked
+    BestEffort,
+}
+
 fn default_memoryconfig_thp() -> bool {
     true
 }
@@ -161,11 +279,17 @@ pub struct MemoryConfig {
     #[serde(default)]
     pub hugepage_size: Option<u64>,
     #[serde(default)]
+    pub hugepages_fallback: bool,
+    #[serde(default)]
     pub prefault: bool,
     #[serde(default)]
     pub zones: Option<Vec<MemoryZoneConfig>>,
     #[serde(default = "default_memoryconfig_thp")]
     pub thp: bool,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub lock_policy: MemoryLockPolicy,
 }
 
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -181,9 +305,12 @@ impl Default for MemoryConfig {
             shared: false,
             hugepages: false,
             hugepage_size: None,
+            hugepages_fallback: false,
             prefault: false,
             zones: None,
             thp: true,
+            locked: false,
+            lock_policy: MemoryLockPolicy::Strict,
         }
     }
 }
@@ -195,6 +322,14 @@ pub enum VhostMode {
     Server,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NetModel {
+    #[default]
+    Virtio,
+    E1000e,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RateLimiterGroupConfig {
     #[serde(default)]
@@ -212,11 +347,27 @@ pub struct VirtQueueAffinity {
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
+    #[serde(
+        default,
+        serialize_with = "serialize_diskconfig_fd",
+        deserialize_with = "deserialize_diskconfig_fd"
+    )]
+    pub fd: Option<i32>,
     #[serde(default)]
     pub readonly: bool,
     #[serde(default)]
     pub direct: bool,
     #[serde(default)]
+    pub read_ahead: bool,
+    #[serde(default)]
+    pub base: Option<PathBuf>,
+    #[serde(default)]
+    pub create_overlay: bool,
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub secure_erase: bool,
+    #[serde(default)]
     pub iommu: bool,
     #[serde(default = "default_diskconfig_num_queues")]
     pub num_queues: usize,
@@ -257,17 +408,47 @@ pub fn default_diskconfig_queue_size() -> u16 {
     DEFAULT_DISK_QUEUE_SIZE
 }
 
+fn serialize_diskconfig_fd<S>(x: &Option<i32>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    if x.is_some() {
+        warn!("'DiskConfig' contains a FD that can't be serialized correctly. Serializing it as an invalid FD.");
+        s.serialize_some(&-1)
+    } else {
+        s.serialize_none()
+    }
+}
+
+fn deserialize_diskconfig_fd<'de, D>(d: D) -> Result<Option<i32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let invalid_fd: Option<i32> = Option::deserialize(d)?;
+    if invalid_fd.is_some() {
+        warn!("'DiskConfig' contains a FD that can't be deserialized correctly. Deserializing it as an invalid FD.");
+        Ok(Some(-1))
+    } else {
+        Ok(None)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct NetConfig {
     #[serde(default = "default_netconfig_tap")]
     pub tap: Option<String>,
-    #[serde(default = "default_netconfig_ip")]
-    pub ip: Ipv4Addr,
+    /// IPv4 address of the TAP interface, which depends on the address
+    /// family when not given, see `default_netconfig_ip()`. Validating the
+    /// configuration fills it in.
+    #[serde(default)]
+    pub ip: Option<Ipv4Addr>,
     #[serde(default = "default_netconfig_mask")]
     pub mask: Ipv4Addr,
     #[serde(default = "default_netconfig_mac")]
     pub mac: MacAddr,
     #[serde(default)]
+    pub mac_pool: Option<String>,
+    #[serde(default)]
     pub host_mac: Option<MacAddr>,
     #[serde(default)]
     pub mtu: Option<u16>,
@@ -283,6 +464,8 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_mode: VhostMode,
     #[serde(default)]
+    pub vhost_instance_id: Option<String>,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(
         default,
@@ -300,6 +483,20 @@ pub struct NetConfig {
     pub offload_ufo: bool,
     #[serde(default = "default_netconfig_true")]
     pub offload_csum: bool,
+    #[serde(default)]
+    pub bridge: Option<String>,
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    #[serde(default)]
+    pub model: NetModel,
+    #[serde(default)]
+    pub egress_shaping: Option<EgressShaping>,
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(default = "default_netconfig_ipv6_prefix_len")]
+    pub ipv6_prefix_len: u8,
+    #[serde(default)]
+    pub ipv6_ra: bool,
 }
 
 pub fn default_netconfig_true() -> bool {
@@ -310,14 +507,24 @@ pub fn default_netconfig_tap() -> Option<String> {
     None
 }
 
-pub fn default_netconfig_ip() -> Ipv4Addr {
-    Ipv4Addr::new(192, 168, 249, 1)
+// The default IPv4 address is only assigned to the TAP interface when no
+// IPv6 address is given, so that it can be IPv6 only.
+pub fn default_netconfig_ip(ipv6: Option<Ipv6Addr>) -> Ipv4Addr {
+    if ipv6.is_some() {
+        Ipv4Addr::UNSPECIFIED
+    } else {
+        Ipv4Addr::new(192, 168, 249, 1)
+    }
 }
 
 pub fn default_netconfig_mask() -> Ipv4Addr {
     Ipv4Addr::new(255, 255, 255, 0)
 }
 
+pub fn default_netconfig_ipv6_prefix_len() -> u8 {
+    64
+}
+
 pub fn default_netconfig_mac() -> MacAddr {
     MacAddr::local_random()
 }
@@ -392,6 +599,7 @@ pub struct BalloonConfig {
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct FsConfig {
     pub tag: String,
+    #[serde(default)]
     pub socket: PathBuf,
     #[serde(default = "default_fsconfig_num_queues")]
     pub num_queues: usize,
@@ -401,6 +609,12 @@ pub struct FsConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub builtin: bool,
+    #[serde(default)]
+    pub shared_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub readonly: bool,
 }
 
 pub fn default_fsconfig_num_queues() -> usize {
@@ -411,6 +625,18 @@ pub fn default_fsconfig_queue_size() -> u16 {
     1024
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct P9Config {
+    pub tag: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PmemConfig {
     pub file: PathBuf,
@@ -426,6 +652,46 @@ pub struct PmemConfig {
     pub pci_segment: u16,
 }
 
+/// Configuration of a device plugged in place of an existing one, reusing
+/// the guest PCI slot the replaced device was occupying.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplacementDeviceConfig {
+    Disk(DiskConfig),
+    Fs(FsConfig),
+    Net(NetConfig),
+    Pmem(PmemConfig),
+}
+
+impl ReplacementDeviceConfig {
+    pub fn id(&self) -> Option<&String> {
+        match self {
+            ReplacementDeviceConfig::Disk(cfg) => cfg.id.as_ref(),
+            ReplacementDeviceConfig::Fs(cfg) => cfg.id.as_ref(),
+            ReplacementDeviceConfig::Net(cfg) => cfg.id.as_ref(),
+            ReplacementDeviceConfig::Pmem(cfg) => cfg.id.as_ref(),
+        }
+    }
+
+    pub fn id_mut(&mut self) -> &mut Option<String> {
+        match self {
+            ReplacementDeviceConfig::Disk(cfg) => &mut cfg.id,
+            ReplacementDeviceConfig::Fs(cfg) => &mut cfg.id,
+            ReplacementDeviceConfig::Net(cfg) => &mut cfg.id,
+            ReplacementDeviceConfig::Pmem(cfg) => &mut cfg.id,
+        }
+    }
+
+    pub fn pci_segment_mut(&mut self) -> &mut u16 {
+        match self {
+            ReplacementDeviceConfig::Disk(cfg) => &mut cfg.pci_segment,
+            ReplacementDeviceConfig::Fs(cfg) => &mut cfg.pci_segment,
+            ReplacementDeviceConfig::Net(cfg) => &mut cfg.pci_segment,
+            ReplacementDeviceConfig::Pmem(cfg) => &mut cfg.pci_segment,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ConsoleOutputMode {
     Off,
@@ -482,6 +748,8 @@ pub struct DeviceConfig {
     pub pci_segment: u16,
     #[serde(default)]
     pub x_nv_gpudirect_clique: Option<u8>,
+    #[serde(default)]
+    pub romfile: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -510,6 +778,13 @@ pub fn default_vdpaconfig_num_queues() -> usize {
     1
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UsbConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct VsockConfig {
     pub cid: u32,
@@ -522,6 +797,32 @@ pub struct VsockConfig {
     pub pci_segment: u16,
 }
 
+/// Identity of an instance of a VM restored from a snapshot shared with
+/// other instances, given on the boot of a ready VM.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VmIdentityConfig {
+    #[serde(default)]
+    pub net: Vec<NetIdentityConfig>,
+    #[serde(default)]
+    pub disks: Vec<DiskIdentityConfig>,
+    #[serde(default)]
+    pub vsock_cid: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NetIdentityConfig {
+    pub id: String,
+    pub mac: MacAddr,
+}
+
+/// The disk is switched to an overlay created at `overlay`, backed by the
+/// image the snapshot was taken with.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DiskIdentityConfig {
+    pub id: String,
+    pub overlay: PathBuf,
+}
+
 #[cfg(target_arch = "x86_64")]
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct SgxEpcConfig {
@@ -598,6 +899,99 @@ pub struct TpmConfig {
     pub socket: PathBuf,
 }
 
+pub const DEFAULT_RT_PRIORITY: u8 = 1;
+
+fn default_rtconfig_priority() -> u8 {
+    DEFAULT_RT_PRIORITY
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RtConfig {
+    /// Isolated host CPUs the vCPUs are pinned onto, the ones isolated
+    /// through the kernel command line if not provided
+    #[serde(default)]
+    pub cpus: Option<Vec<usize>>,
+    /// SCHED_FIFO priority of the vCPU threads
+    #[serde(default = "default_rtconfig_priority")]
+    pub priority: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PayloadVerificationConfig {
+    /// PEM bundle of the certificates trusted for signing the payloads
+    pub certs: PathBuf,
+    /// Detached PKCS#7 signature of the kernel, the Authenticode signature
+    /// embedded in the kernel being verified when not provided
+    #[serde(default)]
+    pub kernel_signature: Option<PathBuf>,
+    /// Detached PKCS#7 signature of the initramfs
+    #[serde(default)]
+    pub initramfs_signature: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    /// Never restart the guest, the VMM exits once the guest stops
+    #[default]
+    No,
+    /// Restart the guest after a triple fault, a watchdog expiry or a
+    /// fatal error of the VMM
+    OnFailure,
+    /// Restart the guest whatever the reason it stopped, unless the
+    /// shutdown has been requested through the API or a signal
+    Always,
+}
+
+pub const DEFAULT_RESTART_BACKOFF_MS: u64 = 1000;
+
+fn default_restart_backoff_ms() -> u64 {
+    DEFAULT_RESTART_BACKOFF_MS
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RestartPolicyConfig {
+    #[serde(default)]
+    pub mode: RestartMode,
+    /// Maximum number of consecutive restarts of the VM, unlimited if not
+    /// provided
+    #[serde(default)]
+    pub max: Option<u32>,
+    /// Delay before the first restart, doubled on each subsequent one
+    #[serde(default = "default_restart_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BootDetectConfig {
+    /// String the guest prints on the serial port once booted
+    #[serde(default)]
+    pub console_marker: Option<String>,
+    /// Vsock port the guest agent connects to on the host once booted
+    #[serde(default)]
+    pub vsock_port: Option<u32>,
+    /// Consider the guest booted once its OSPM evaluates the vCPUs status
+    #[serde(default)]
+    pub acpi: bool,
+}
+
+#[cfg(feature = "vnc")]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VncConfig {
+    /// UNIX socket the VNC server listens on, the server offering no
+    /// authentication
+    pub socket: PathBuf,
+    /// Inject the keyboard and pointer events of the clients through a
+    /// virtio-input keyboard and tablet
+    #[serde(default = "default_vncconfig_input")]
+    pub input: bool,
+}
+
+#[cfg(feature = "vnc")]
+pub fn default_vncconfig_input() -> bool {
+    false
+}
+
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct VmConfig {
     #[serde(default)]
@@ -612,6 +1006,7 @@ pub struct VmConfig {
     pub rng: RngConfig,
     pub balloon: Option<BalloonConfig>,
     pub fs: Option<Vec<FsConfig>>,
+    pub p9: Option<Vec<P9Config>>,
     pub pmem: Option<Vec<PmemConfig>>,
     #[serde(default = "default_serial")]
     pub serial: ConsoleConfig,
@@ -625,7 +1020,16 @@ pub struct VmConfig {
     pub vdpa: Option<Vec<VdpaConfig>>,
     pub vsock: Option<VsockConfig>,
     #[serde(default)]
+    pub xhci: bool,
+    pub usb: Option<Vec<UsbConfig>>,
+    #[serde(default)]
     pub pvpanic: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub ramfb: bool,
+    #[cfg(feature = "vnc")]
+    #[serde(default)]
+    pub vnc: Option<VncConfig>,
     #[serde(default)]
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
@@ -639,6 +1043,13 @@ pub struct VmConfig {
     pub pci_segments: Option<Vec<PciSegmentConfig>>,
     pub platform: Option<PlatformConfig>,
     pub tpm: Option<TpmConfig>,
+    pub rt: Option<RtConfig>,
+    #[serde(default)]
+    pub payload_verification: Option<PayloadVerificationConfig>,
+    #[serde(default)]
+    pub restart_policy: Option<RestartPolicyConfig>,
+    #[serde(default)]
+    pub boot_detect: Option<BootDetectConfig>,
     // Preserved FDs are the ones that share the same life-time as its holding
     // VmConfig instance, such as FDs for creating TAP devices.
     // Preserved FDs will stay open as long as the holding VmConfig instance is
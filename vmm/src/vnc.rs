@@ -0,0 +1,1069 @@
+// Copyright © 2024 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Minimal VNC server displaying the ramfb framebuffer.
+//!
+//! The server implements the RFB protocol (RFC 6143) without authentication
+//! and with the raw encoding only, hence listening on a UNIX socket only
+//! accessible by the user running the VMM, as well as the `DesktopSize`
+//! pseudo-encoding so that clients follow the mode changes of the guest.
+//! Keyboard and pointer events are injected into the guest through a
+//! virtio-input keyboard and tablet, the keysyms being translated as typed on
+//! a US keyboard.
+
+use devices::legacy::{Ramfb, RamfbError, RamfbFrame};
+use libc::EFD_NONBLOCK;
+use seccompiler::{apply_filter, BpfProgram};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{result, thread};
+use thiserror::Error;
+use virtio_devices::input::{
+    InputEvent, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_ABS, EV_KEY, EV_REL, REL_WHEEL,
+    TABLET_ABS_MAX,
+};
+use virtio_devices::InputEventSender;
+use vmm_sys_util::eventfd::EventFd;
+
+const RFB_VERSION_3_3: &[u8; 12] = b"RFB 003.003\n";
+const RFB_VERSION_3_7: &[u8; 12] = b"RFB 003.007\n";
+const RFB_VERSION_3_8: &[u8; 12] = b"RFB 003.008\n";
+
+const SECURITY_TYPE_NONE: u8 = 1;
+const SECURITY_RESULT_OK: u32 = 0;
+
+// Client to server messages
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages
+const FRAMEBUFFER_UPDATE: u8 = 0;
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const DESKTOP_NAME: &[u8] = b"Cloud Hypervisor";
+
+// Size of the blank screen displayed until the guest configures the
+// framebuffer
+const BLANK_WIDTH: u32 = 640;
+const BLANK_HEIGHT: u32 = 480;
+
+// How often the framebuffer is checked for changes to send
+const UPDATE_INTERVAL: Duration = Duration::from_millis(40);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Cannot bind the listening socket.
+    #[error("Error binding the VNC server to {0:?}: {1}")]
+    Bind(PathBuf, #[source] io::Error),
+
+    /// Cannot create epoll context.
+    #[error("Error creating epoll context: {0}")]
+    Epoll(#[source] io::Error),
+
+    /// Cannot create EventFd.
+    #[error("Error creating EventFd: {0}")]
+    EventFd(#[source] io::Error),
+
+    /// Cannot spawn the VNC server thread.
+    #[error("Error spawning the VNC server thread: {0}")]
+    SpawnThread(#[source] io::Error),
+
+    /// Cannot communicate with the client.
+    #[error("Error communicating with the VNC client: {0}")]
+    Client(#[source] io::Error),
+
+    /// The client doesn't speak a supported protocol version.
+    #[error("Unsupported RFB protocol version: {0:?}")]
+    UnsupportedVersion(String),
+
+    /// The client didn't pick the only security type offered.
+    #[error("Unsupported security type: {0}")]
+    UnsupportedSecurityType(u8),
+
+    /// The client asked for a pixel format which can't be produced.
+    #[error("Unsupported pixel format, expecting true colour with 8, 16 or 32 bits per pixel")]
+    UnsupportedPixelFormat,
+
+    /// The client sent a message which isn't part of the protocol.
+    #[error("Unknown client message type: {0}")]
+    UnknownMessage(u8),
+
+    /// Cannot apply the seccomp filter.
+    #[error("Error applying seccomp filter: {0}")]
+    ApplySeccompFilter(#[source] seccompiler::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+enum EpollDispatch {
+    Kill = 0,
+    Listener = 1,
+    Unknown,
+}
+
+impl From<u64> for EpollDispatch {
+    fn from(v: u64) -> Self {
+        use EpollDispatch::*;
+        match v {
+            0 => Kill,
+            1 => Listener,
+            _ => Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    big_endian: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    // Layout of the ramfb pixels, i.e. XRGB8888 stored as little endian
+    const NATIVE: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        big_endian: false,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        let be_u16 = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+
+        let true_colour = data[3] != 0;
+        if !true_colour || !matches!(data[0], 8 | 16 | 32) {
+            return Err(Error::UnsupportedPixelFormat);
+        }
+
+        Ok(PixelFormat {
+            bits_per_pixel: data[0],
+            big_endian: data[2] != 0,
+            red_max: be_u16(4),
+            green_max: be_u16(6),
+            blue_max: be_u16(8),
+            red_shift: data[10],
+            green_shift: data[11],
+            blue_shift: data[12],
+        })
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let depth = [self.red_max, self.green_max, self.blue_max]
+            .iter()
+            .map(|max| u16::BITS - max.leading_zeros())
+            .sum::<u32>() as u8;
+        let red_max = self.red_max.to_be_bytes();
+        let green_max = self.green_max.to_be_bytes();
+        let blue_max = self.blue_max.to_be_bytes();
+
+        [
+            self.bits_per_pixel,
+            depth,
+            u8::from(self.big_endian),
+            1,
+            red_max[0],
+            red_max[1],
+            green_max[0],
+            green_max[1],
+            blue_max[0],
+            blue_max[1],
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    // Appends a ramfb pixel, converted to this format.
+    fn encode(&self, pixel: &[u8], out: &mut Vec<u8>) {
+        let component = |value: u8, max: u16, shift: u8| {
+            (u32::from(value) * u32::from(max) / 255)
+                .checked_shl(u32::from(shift))
+                .unwrap_or(0)
+        };
+        let value = component(pixel[2], self.red_max, self.red_shift)
+            | component(pixel[1], self.green_max, self.green_shift)
+            | component(pixel[0], self.blue_max, self.blue_shift);
+
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(value as u8),
+            (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+/// Input devices the events of the clients are injected into.
+#[derive(Clone)]
+pub struct VncInput {
+    pub keyboard: InputEventSender,
+    pub tablet: InputEventSender,
+}
+
+// Linux key code of the key typing the keysym on a US keyboard, the modifiers
+// being pressed separately by the client.
+fn keysym_to_keycode(keysym: u32) -> Option<u16> {
+    const ROWS: [(&[u8], u16); 4] = [
+        (b"1234567890-=", 2),
+        (b"qwertyuiop[]", 16),
+        (b"asdfghjkl;'`", 30),
+        (b"zxcvbnm,./", 44),
+    ];
+    const SHIFTED_ROWS: [(&[u8], u16); 4] = [
+        (b"!@#$%^&*()_+", 2),
+        (b"QWERTYUIOP{}", 16),
+        (b"ASDFGHJKL:\"~", 30),
+        (b"ZXCVBNM<>?", 44),
+    ];
+
+    let code = match keysym {
+        0x20 => 57,
+        0x5c | 0x7c => 43,
+        0x21..=0x7e => {
+            let c = keysym as u8;
+            return ROWS
+                .iter()
+                .chain(SHIFTED_ROWS.iter())
+                .find_map(|(row, first)| {
+                    row.iter()
+                        .position(|key| *key == c)
+                        .map(|i| first + i as u16)
+                });
+        }
+        0xfe03 => 100,
+        0xfe20 => 15,
+        0xff08 => 14,
+        0xff09 => 15,
+        0xff0d => 28,
+        0xff13 => 119,
+        0xff14 => 70,
+        0xff15 | 0xff61 => 99,
+        0xff1b => 1,
+        0xff50 => 102,
+        0xff51 => 105,
+        0xff52 => 103,
+        0xff53 => 106,
+        0xff54 => 108,
+        0xff55 => 104,
+        0xff56 => 109,
+        0xff57 => 107,
+        0xff63 => 110,
+        0xff67 => 127,
+        0xff7f => 69,
+        0xff8d => 96,
+        0xffaa => 55,
+        0xffab => 78,
+        0xffad => 74,
+        0xffac | 0xffae => 83,
+        0xffaf => 98,
+        0xffb0 => 82,
+        0xffb1..=0xffb3 => 79 + (keysym - 0xffb1) as u16,
+        0xffb4..=0xffb6 => 75 + (keysym - 0xffb4) as u16,
+        0xffb7..=0xffb9 => 71 + (keysym - 0xffb7) as u16,
+        0xffbe..=0xffc7 => 59 + (keysym - 0xffbe) as u16,
+        0xffc8 => 87,
+        0xffc9 => 88,
+        0xffe1 => 42,
+        0xffe2 => 54,
+        0xffe3 => 29,
+        0xffe4 => 97,
+        0xffe5 => 58,
+        0xffe7 | 0xffeb => 125,
+        0xffe8 | 0xffec => 126,
+        0xffe9 => 56,
+        0xffea => 100,
+        0xffff => 111,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+// Pointer buttons as numbered in the button mask, the next two bits being the
+// wheel moving up and down
+const POINTER_BUTTONS: [u16; 3] = [BTN_LEFT, BTN_MIDDLE, BTN_RIGHT];
+const WHEEL_UP: u8 = 1 << 3;
+const WHEEL_DOWN: u8 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct UpdateRequest {
+    incremental: bool,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+fn frame_pixel(frame: &RamfbFrame, x: u32, y: u32) -> &[u8] {
+    if x < frame.width && y < frame.height {
+        let offset = ((y * frame.width + x) * 4) as usize;
+        &frame.pixels[offset..offset + 4]
+    } else {
+        &[0; 4]
+    }
+}
+
+struct VncClient<S: Read + Write> {
+    stream: S,
+    ramfb: Arc<Mutex<Ramfb>>,
+    input: Option<VncInput>,
+    // Keys and buttons pressed, released when the client disconnects
+    pressed_keys: BTreeSet<u16>,
+    button_mask: u8,
+    pixel_format: PixelFormat,
+    desktop_size: bool,
+    width: u32,
+    height: u32,
+    // Framebuffer content as last sent to the client
+    client_pixels: Vec<u8>,
+    pending_update: Option<UpdateRequest>,
+    last_update: Instant,
+    buffer: Vec<u8>,
+}
+
+impl<S: Read + Write> VncClient<S> {
+    fn new(stream: S, ramfb: Arc<Mutex<Ramfb>>, input: Option<VncInput>) -> Self {
+        VncClient {
+            stream,
+            ramfb,
+            input,
+            pressed_keys: BTreeSet::new(),
+            button_mask: 0,
+            pixel_format: PixelFormat::NATIVE,
+            desktop_size: false,
+            width: 0,
+            height: 0,
+            client_pixels: Vec::new(),
+            pending_update: None,
+            last_update: Instant::now(),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn current_frame(&self) -> RamfbFrame {
+        match self.ramfb.lock().unwrap().frame() {
+            Ok(frame) => frame,
+            Err(e) => {
+                if !matches!(e, RamfbError::NotConfigured) {
+                    warn!("Error reading the framebuffer: {e}");
+                }
+                RamfbFrame {
+                    width: BLANK_WIDTH,
+                    height: BLANK_HEIGHT,
+                    pixels: vec![0; (BLANK_WIDTH * BLANK_HEIGHT * 4) as usize],
+                }
+            }
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.client_pixels = vec![0; (width * height * 4) as usize];
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data).map_err(Error::Client)
+    }
+
+    fn read_exact(&mut self, data: &mut [u8]) -> Result<()> {
+        self.stream.read_exact(data).map_err(Error::Client)
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.write(RFB_VERSION_3_8)?;
+        let mut version = [0u8; 12];
+        self.read_exact(&mut version)?;
+
+        if &version == RFB_VERSION_3_3 {
+            // The server decides of the security type
+            self.write(&u32::from(SECURITY_TYPE_NONE).to_be_bytes())?;
+        } else if &version == RFB_VERSION_3_7 || &version == RFB_VERSION_3_8 {
+            self.write(&[1, SECURITY_TYPE_NONE])?;
+            let mut security_type = [0u8];
+            self.read_exact(&mut security_type)?;
+            if security_type[0] != SECURITY_TYPE_NONE {
+                return Err(Error::UnsupportedSecurityType(security_type[0]));
+            }
+            if &version == RFB_VERSION_3_8 {
+                self.write(&SECURITY_RESULT_OK.to_be_bytes())?;
+            }
+        } else {
+            return Err(Error::UnsupportedVersion(
+                String::from_utf8_lossy(&version).trim_end().to_string(),
+            ));
+        }
+
+        // The shared flag is ignored, as several clients can always be
+        // connected at the same time.
+        let mut client_init = [0u8];
+        self.read_exact(&mut client_init)?;
+
+        let frame = self.current_frame();
+        self.resize(frame.width, frame.height);
+
+        let mut server_init = Vec::new();
+        server_init.extend_from_slice(&(self.width as u16).to_be_bytes());
+        server_init.extend_from_slice(&(self.height as u16).to_be_bytes());
+        server_init.extend_from_slice(&self.pixel_format.to_bytes());
+        server_init.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        server_init.extend_from_slice(DESKTOP_NAME);
+        self.write(&server_init)
+    }
+
+    // Handles the first message of the buffer, returning its size if it has
+    // been fully received.
+    fn handle_message(&mut self) -> Result<Option<usize>> {
+        let Some(&message_type) = self.buffer.first() else {
+            return Ok(None);
+        };
+        let be_u16 = |data: &[u8], offset: usize| {
+            u32::from(u16::from_be_bytes([data[offset], data[offset + 1]]))
+        };
+
+        let size = match message_type {
+            SET_PIXEL_FORMAT => 20,
+            SET_ENCODINGS if self.buffer.len() >= 4 => 4 + 4 * be_u16(&self.buffer, 2) as usize,
+            FRAMEBUFFER_UPDATE_REQUEST => 10,
+            KEY_EVENT => 8,
+            POINTER_EVENT => 6,
+            CLIENT_CUT_TEXT if self.buffer.len() >= 8 => {
+                8 + u32::from_be_bytes(self.buffer[4..8].try_into().unwrap()) as usize
+            }
+            SET_ENCODINGS | CLIENT_CUT_TEXT => return Ok(None),
+            _ => return Err(Error::UnknownMessage(message_type)),
+        };
+        if self.buffer.len() < size {
+            return Ok(None);
+        }
+
+        let message = self.buffer[..size].to_vec();
+        match message_type {
+            SET_PIXEL_FORMAT => {
+                self.pixel_format = PixelFormat::parse(&message[4..])?;
+            }
+            SET_ENCODINGS => {
+                self.desktop_size = message[4..]
+                    .chunks_exact(4)
+                    .any(|e| i32::from_be_bytes(e.try_into().unwrap()) == ENCODING_DESKTOP_SIZE);
+            }
+            FRAMEBUFFER_UPDATE_REQUEST => {
+                let request = UpdateRequest {
+                    incremental: message[1] != 0,
+                    x: be_u16(&message, 2),
+                    y: be_u16(&message, 4),
+                    width: be_u16(&message, 6),
+                    height: be_u16(&message, 8),
+                };
+                if request.incremental {
+                    self.pending_update = Some(request);
+                } else {
+                    self.pending_update = None;
+                    self.send_update(request)?;
+                }
+            }
+            KEY_EVENT => {
+                let keysym = u32::from_be_bytes(message[4..8].try_into().unwrap());
+                let events = self.key_events(keysym, message[1] != 0);
+                if let Some(input) = &self.input {
+                    input.keyboard.send(&events);
+                }
+            }
+            POINTER_EVENT => {
+                let events =
+                    self.pointer_events(message[1], be_u16(&message, 2), be_u16(&message, 4));
+                if let Some(input) = &self.input {
+                    input.tablet.send(&events);
+                }
+            }
+            // Clipboard updates are ignored
+            _ => {}
+        }
+
+        Ok(Some(size))
+    }
+
+    fn key_events(&mut self, keysym: u32, down: bool) -> Vec<InputEvent> {
+        let Some(code) = keysym_to_keycode(keysym) else {
+            debug!("Ignoring VNC key event for keysym {keysym:#x}");
+            return Vec::new();
+        };
+        let changed = if down {
+            self.pressed_keys.insert(code)
+        } else {
+            self.pressed_keys.remove(&code)
+        };
+
+        // The guest repeats the keys held down itself
+        if !changed && down {
+            return Vec::new();
+        }
+        vec![
+            InputEvent::new(EV_KEY, code, u32::from(down)),
+            InputEvent::sync(),
+        ]
+    }
+
+    fn pointer_events(&mut self, button_mask: u8, x: u32, y: u32) -> Vec<InputEvent> {
+        let scale = |value: u32, size: u32| value.min(size) * TABLET_ABS_MAX / size.max(1);
+        let mut events = vec![
+            InputEvent::new(EV_ABS, ABS_X, scale(x, self.width.saturating_sub(1))),
+            InputEvent::new(EV_ABS, ABS_Y, scale(y, self.height.saturating_sub(1))),
+        ];
+
+        for (bit, button) in POINTER_BUTTONS.iter().enumerate() {
+            let pressed = button_mask & (1 << bit) != 0;
+            if pressed != (self.button_mask & (1 << bit) != 0) {
+                events.push(InputEvent::new(EV_KEY, *button, u32::from(pressed)));
+            }
+        }
+        // The wheel is moved as the bits are set
+        let pressed = button_mask & !self.button_mask;
+        if pressed & WHEEL_UP != 0 {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, 1));
+        }
+        if pressed & WHEEL_DOWN != 0 {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32));
+        }
+        self.button_mask = button_mask;
+
+        events.push(InputEvent::sync());
+        events
+    }
+
+    // Releases what the client left pressed, for the guest not to see keys
+    // stuck down.
+    fn release_input(&mut self) {
+        let Some(input) = &self.input else {
+            return;
+        };
+
+        let mut events: Vec<InputEvent> = std::mem::take(&mut self.pressed_keys)
+            .into_iter()
+            .map(|code| InputEvent::new(EV_KEY, code, 0))
+            .collect();
+        if !events.is_empty() {
+            events.push(InputEvent::sync());
+            input.keyboard.send(&events);
+        }
+
+        let mut events: Vec<InputEvent> = POINTER_BUTTONS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.button_mask & (1 << bit) != 0)
+            .map(|(_, button)| InputEvent::new(EV_KEY, *button, 0))
+            .collect();
+        self.button_mask = 0;
+        if !events.is_empty() {
+            events.push(InputEvent::sync());
+            input.tablet.send(&events);
+        }
+    }
+
+    // Sends the requested area of the framebuffer, only the lines which
+    // changed since the last update being sent for incremental requests.
+    // Returns whether an update has been sent.
+    fn send_update(&mut self, request: UpdateRequest) -> Result<bool> {
+        let frame = self.current_frame();
+        self.last_update = Instant::now();
+
+        let mut message = vec![FRAMEBUFFER_UPDATE, 0];
+        let (x0, y0, x1, y1) =
+            if self.desktop_size && (frame.width, frame.height) != (self.width, self.height) {
+                // The whole framebuffer is resent after a mode change
+                self.resize(frame.width, frame.height);
+                message.extend_from_slice(&2u16.to_be_bytes());
+                Self::rect_header(
+                    &mut message,
+                    (0, 0, self.width, self.height),
+                    ENCODING_DESKTOP_SIZE,
+                );
+                (0, 0, self.width, self.height)
+            } else {
+                let x0 = request.x.min(self.width);
+                let x1 = (request.x + request.width).min(self.width);
+                let mut y0 = request.y.min(self.height);
+                let mut y1 = (request.y + request.height).min(self.height);
+
+                if request.incremental {
+                    let mut changed = (y0..y1).filter(|y| self.line_changed(&frame, *y, x0, x1));
+                    let Some(first) = changed.next() else {
+                        return Ok(false);
+                    };
+                    let last = changed.last().unwrap_or(first);
+                    (y0, y1) = (first, last + 1);
+                }
+
+                message.extend_from_slice(&1u16.to_be_bytes());
+                (x0, y0, x1, y1)
+            };
+
+        Self::rect_header(&mut message, (x0, y0, x1 - x0, y1 - y0), ENCODING_RAW);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let pixel = frame_pixel(&frame, x, y);
+                let offset = ((y * self.width + x) * 4) as usize;
+                self.client_pixels[offset..offset + 4].copy_from_slice(pixel);
+                self.pixel_format.encode(pixel, &mut message);
+            }
+        }
+        self.write(&message)?;
+
+        Ok(true)
+    }
+
+    fn line_changed(&self, frame: &RamfbFrame, y: u32, x0: u32, x1: u32) -> bool {
+        (x0..x1).any(|x| {
+            let offset = ((y * self.width + x) * 4) as usize;
+            frame_pixel(frame, x, y) != &self.client_pixels[offset..offset + 4]
+        })
+    }
+
+    fn rect_header(message: &mut Vec<u8>, rect: (u32, u32, u32, u32), encoding: i32) {
+        for value in [rect.0, rect.1, rect.2, rect.3] {
+            message.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        message.extend_from_slice(&encoding.to_be_bytes());
+    }
+
+    // Handles the messages received so far, and sends the pending update if
+    // the framebuffer changed.
+    fn process(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+        while let Some(size) = self.handle_message()? {
+            self.buffer.drain(..size);
+        }
+
+        if let Some(request) = self.pending_update {
+            if self.last_update.elapsed() >= UPDATE_INTERVAL && self.send_update(request)? {
+                self.pending_update = None;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl VncClient<UnixStream> {
+    fn run(&mut self, stop: &AtomicBool) -> Result<()> {
+        let result = self.serve(stop);
+        self.release_input();
+        result
+    }
+
+    fn serve(&mut self, stop: &AtomicBool) -> Result<()> {
+        self.stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .map_err(Error::Client)?;
+        self.handshake()?;
+
+        // The read timeout paces the checks for framebuffer changes
+        self.stream
+            .set_read_timeout(Some(UPDATE_INTERVAL))
+            .map_err(Error::Client)?;
+        let mut data = [0u8; 4096];
+        while !stop.load(Ordering::Acquire) {
+            let size = match self.stream.read(&mut data) {
+                Ok(0) => return Ok(()),
+                Ok(size) => size,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    0
+                }
+                Err(e) => return Err(Error::Client(e)),
+            };
+            self.process(&data[..size])?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct VncServer {
+    socket: PathBuf,
+    kill_evt: EventFd,
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+/// Seccomp filters of the server and client threads, the latter being
+/// applied on top of the former, inherited from the server thread.
+pub struct VncSeccompFilters {
+    pub server: BpfProgram,
+    pub client: BpfProgram,
+}
+
+impl VncServer {
+    pub fn new(
+        socket: &Path,
+        ramfb: Arc<Mutex<Ramfb>>,
+        input: Option<VncInput>,
+        seccomp_filters: VncSeccompFilters,
+        exit_evt: EventFd,
+    ) -> Result<Self> {
+        let listener = UnixListener::bind(socket).map_err(|e| Error::Bind(socket.to_owned(), e))?;
+        // The clients aren't authenticated, the socket being their only
+        // access control
+        fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+            .map_err(|e| Error::Bind(socket.to_owned(), e))?;
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+
+        let epoll_fd = epoll::create(true).map_err(Error::Epoll)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        // SAFETY: epoll_fd is valid
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, EpollDispatch::Kill as u64),
+        )
+        .map_err(Error::Epoll)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            listener.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, EpollDispatch::Listener as u64),
+        )
+        .map_err(Error::Epoll)?;
+
+        info!("VNC server listening on {:?}", socket);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::Builder::new()
+            .name("vnc-server".to_string())
+            .spawn(move || {
+                // Apply seccomp filter
+                if !seccomp_filters.server.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filters.server) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        return;
+                    }
+                }
+
+                let exit_evt = Arc::new(exit_evt);
+                let client = ClientContext {
+                    ramfb,
+                    input,
+                    seccomp_filter: Arc::new(seccomp_filters.client),
+                    exit_evt: exit_evt.clone(),
+                    stop: thread_stop,
+                };
+                std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    Self::accept_loop(&epoll_file, &listener, &client)
+                }))
+                .map_err(|_| {
+                    error!("vnc-server thread panicked");
+                    exit_evt.write(1).ok();
+                })
+                .ok();
+            })
+            .map_err(Error::SpawnThread)?;
+
+        Ok(VncServer {
+            socket: socket.to_owned(),
+            kill_evt,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    fn accept_loop(epoll_file: &File, listener: &UnixListener, client: &ClientContext) {
+        // 2 for Kill and Listener
+        const EPOLL_EVENTS_LEN: usize = 2;
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        loop {
+            let num_events = match epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Error waiting for VNC connections: {e}");
+                    return;
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                match EpollDispatch::from(event.data) {
+                    EpollDispatch::Kill => return,
+                    EpollDispatch::Listener => {
+                        let stream = match listener.accept() {
+                            Ok((stream, _)) => stream,
+                            Err(e) => {
+                                warn!("Error accepting VNC connection: {e}");
+                                continue;
+                            }
+                        };
+                        info!("VNC client connected");
+
+                        let client = client.clone();
+                        if let Err(e) =
+                            thread::Builder::new()
+                                .name("vnc-client".to_string())
+                                .spawn(move || {
+                                    if let Err(e) = client.serve(stream) {
+                                        warn!("VNC client disconnected: {e}");
+                                    }
+                                })
+                        {
+                            warn!("Error spawning VNC client thread: {e}");
+                        }
+                    }
+                    EpollDispatch::Unknown => {
+                        warn!("Unknown VNC server loop event: {}", event.data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// What the client threads are given by the server thread
+#[derive(Clone)]
+struct ClientContext {
+    ramfb: Arc<Mutex<Ramfb>>,
+    input: Option<VncInput>,
+    seccomp_filter: Arc<BpfProgram>,
+    exit_evt: Arc<EventFd>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ClientContext {
+    fn serve(self, stream: UnixStream) -> Result<()> {
+        if !self.seccomp_filter.is_empty() {
+            apply_filter(&self.seccomp_filter)
+                .map_err(Error::ApplySeccompFilter)
+                .map_err(|e| {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    self.exit_evt.write(1).ok();
+                    e
+                })?;
+        }
+
+        VncClient::new(stream, self.ramfb, self.input).run(&self.stop)
+    }
+}
+
+impl Drop for VncServer {
+    fn drop(&mut self) {
+        // The client threads notice it within an update interval
+        self.stop.store(true, Ordering::Release);
+        self.kill_evt.write(1).ok();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+        fs::remove_file(&self.socket).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use vm_memory::{bitmap::AtomicBitmap, GuestAddress, GuestMemoryAtomic};
+
+    type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+    // Client input replayed from a buffer, the output being captured
+    struct TestStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        // B, G, R, X
+        let pixel = [0x10, 0x80, 0xff, 0];
+
+        let mut out = Vec::new();
+        PixelFormat::NATIVE.encode(&pixel, &mut out);
+        assert_eq!(out, pixel);
+
+        // RGB565 as big endian
+        let rgb565 =
+            PixelFormat::parse(&[16, 16, 1, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0]).unwrap();
+        let mut out = Vec::new();
+        rgb565.encode(&pixel, &mut out);
+        assert_eq!(out, ((31u16 << 11) | (31 << 5) | 1).to_be_bytes());
+
+        assert_eq!(
+            PixelFormat::parse(&PixelFormat::NATIVE.to_bytes()).unwrap(),
+            PixelFormat::NATIVE
+        );
+        // Colour maps aren't supported
+        assert!(PixelFormat::parse(&[8, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_vnc_session() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let ramfb = Arc::new(Mutex::new(Ramfb::new(GuestMemoryAtomic::new(mem))));
+
+        let mut input = RFB_VERSION_3_8.to_vec();
+        input.extend_from_slice(&[SECURITY_TYPE_NONE, 1]);
+        let mut client = VncClient::new(
+            TestStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            },
+            ramfb,
+            None,
+        );
+        client.handshake().unwrap();
+
+        let output = &client.stream.output;
+        assert_eq!(&output[0..12], RFB_VERSION_3_8);
+        assert_eq!(&output[12..14], &[1, SECURITY_TYPE_NONE]);
+        assert_eq!(&output[14..18], &SECURITY_RESULT_OK.to_be_bytes());
+        // The blank screen is displayed until the framebuffer is configured
+        assert_eq!(&output[18..22], &[2, 128, 1, 224]);
+        assert_eq!(&output[22..38], &PixelFormat::NATIVE.to_bytes());
+        assert_eq!(&output[42..], DESKTOP_NAME);
+        client.stream.output.clear();
+
+        // Nothing changed, the incremental update is deferred
+        client
+            .process(&[FRAMEBUFFER_UPDATE_REQUEST, 1, 0, 0, 0, 0, 2, 128, 1, 224])
+            .unwrap();
+        assert!(client.stream.output.is_empty());
+        assert!(client.pending_update.is_some());
+
+        // A 2x1 area is fully sent, as 8 bits per pixel
+        let mut messages = vec![SET_PIXEL_FORMAT, 0, 0, 0];
+        messages.extend_from_slice(&[8, 8, 0, 1, 0, 7, 0, 7, 0, 3, 0, 3, 6, 0, 0, 0]);
+        messages.extend_from_slice(&[FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 1, 0, 2, 0, 2, 0, 1]);
+        // Split in the middle of the next message
+        messages.extend_from_slice(&[POINTER_EVENT, 0, 0]);
+        client.process(&messages).unwrap();
+        assert_eq!(
+            client.stream.output,
+            [0, 0, 0, 1, 0, 1, 0, 2, 0, 2, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(client.buffer, [POINTER_EVENT, 0, 0]);
+
+        assert!(matches!(
+            client.process(&[0, 0, 0, 42]),
+            Err(Error::UnknownMessage(42))
+        ));
+    }
+
+    #[test]
+    fn test_keysym_to_keycode() {
+        // Both cases of a letter are typed with the same key
+        assert_eq!(keysym_to_keycode(u32::from(b'a')), Some(30));
+        assert_eq!(keysym_to_keycode(u32::from(b'A')), Some(30));
+        assert_eq!(keysym_to_keycode(u32::from(b'!')), Some(2));
+        assert_eq!(keysym_to_keycode(u32::from(b'0')), Some(11));
+        assert_eq!(keysym_to_keycode(u32::from(b'/')), Some(53));
+        assert_eq!(keysym_to_keycode(u32::from(b'|')), Some(43));
+        assert_eq!(keysym_to_keycode(u32::from(b'~')), Some(41));
+        assert_eq!(keysym_to_keycode(0xff0d), Some(28));
+        assert_eq!(keysym_to_keycode(0xffb1), Some(79));
+        assert_eq!(keysym_to_keycode(0xffc9), Some(88));
+        assert_eq!(keysym_to_keycode(0xe9), None);
+    }
+
+    #[test]
+    fn test_vnc_input_events() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let ramfb = Arc::new(Mutex::new(Ramfb::new(GuestMemoryAtomic::new(mem))));
+        let mut client = VncClient::new(
+            TestStream {
+                input: Cursor::new(Vec::new()),
+                output: Vec::new(),
+            },
+            ramfb,
+            None,
+        );
+        client.resize(641, 481);
+
+        assert_eq!(
+            client.key_events(u32::from(b'q'), true),
+            [InputEvent::new(EV_KEY, 16, 1), InputEvent::sync()]
+        );
+        // Keys held down are repeated by the guest
+        assert!(client.key_events(u32::from(b'q'), true).is_empty());
+        assert_eq!(
+            client.key_events(u32::from(b'q'), false),
+            [InputEvent::new(EV_KEY, 16, 0), InputEvent::sync()]
+        );
+
+        // The coordinates are scaled to those of the tablet
+        assert_eq!(
+            client.pointer_events(1 | WHEEL_DOWN, 640, 120),
+            [
+                InputEvent::new(EV_ABS, ABS_X, TABLET_ABS_MAX),
+                InputEvent::new(EV_ABS, ABS_Y, TABLET_ABS_MAX / 4),
+                InputEvent::new(EV_KEY, BTN_LEFT, 1),
+                InputEvent::new(EV_REL, REL_WHEEL, -1i32 as u32),
+                InputEvent::sync(),
+            ]
+        );
+        assert_eq!(
+            client.pointer_events(0, 0, 0),
+            [
+                InputEvent::new(EV_ABS, ABS_X, 0),
+                InputEvent::new(EV_ABS, ABS_Y, 0),
+                InputEvent::new(EV_KEY, BTN_LEFT, 0),
+                InputEvent::sync(),
+            ]
+        );
+    }
+}